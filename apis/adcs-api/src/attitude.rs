@@ -0,0 +1,361 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Attitude telemetry types shared between ADCS drivers and the telemetry pipeline
+//!
+//! Each quantity is stored in a single fixed unit, which is part of its field name, and
+//! the constructors reject non-finite values. Vector quantities which can be expressed in
+//! more than one frame carry that frame with them, so they can't be mixed up by accident.
+
+use crate::frames::FrameConverter;
+use crate::{AdcsError, AdcsResult};
+#[cfg(feature = "graphql")]
+use juniper::{GraphQLEnum, GraphQLObject};
+use serde_derive::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Reference frames which attitude data may be expressed in
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLEnum))]
+pub enum Frame {
+    /// Spacecraft body frame
+    Body,
+    /// Earth-centered inertial frame
+    Eci,
+    /// Local-vertical local-horizontal frame.
+    /// Z points at nadir, Y along the negative orbit normal and X completes the
+    /// right-handed set (along the velocity vector for circular orbits)
+    Lvlh,
+}
+
+/// Three dimensional vector
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct Vector3 {
+    /// X component
+    pub x: f64,
+    /// Y component
+    pub y: f64,
+    /// Z component
+    pub z: f64,
+}
+
+impl Vector3 {
+    /// Creates a new vector
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Vector3 { x, y, z }
+    }
+
+    /// Dot product
+    pub fn dot(&self, other: &Vector3) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    /// Cross product
+    pub fn cross(&self, other: &Vector3) -> Vector3 {
+        Vector3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    /// Euclidean length
+    pub fn norm(&self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    /// Multiplies each component by the given value
+    pub fn scale(&self, factor: f64) -> Vector3 {
+        Vector3::new(self.x * factor, self.y * factor, self.z * factor)
+    }
+
+    /// Component-wise addition
+    pub fn add(&self, other: &Vector3) -> Vector3 {
+        Vector3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+
+    /// Returns the unit vector pointing in the same direction.
+    /// Fails if the vector has no direction
+    pub fn normalize(&self) -> AdcsResult<Vector3> {
+        let norm = self.norm();
+        if !norm.is_normal() {
+            return Err(AdcsError::InvalidValue);
+        }
+        Ok(self.scale(1.0 / norm))
+    }
+
+    /// Whether all components are finite
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    fn check_finite(self) -> AdcsResult<Vector3> {
+        if self.is_finite() {
+            Ok(self)
+        } else {
+            Err(AdcsError::InvalidValue)
+        }
+    }
+}
+
+/// Unit quaternion, scalar first.
+///
+/// A quaternion `q_b_a` transforms vectors expressed in frame A into frame B,
+/// and `q_c_b.multiply(&q_b_a)` gives `q_c_a`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct Quaternion {
+    /// Scalar component
+    pub w: f64,
+    /// X vector component
+    pub x: f64,
+    /// Y vector component
+    pub y: f64,
+    /// Z vector component
+    pub z: f64,
+}
+
+impl Default for Quaternion {
+    fn default() -> Self {
+        Quaternion::identity()
+    }
+}
+
+impl Quaternion {
+    /// Creates a new quaternion, normalizing it to unit length
+    pub fn new(w: f64, x: f64, y: f64, z: f64) -> AdcsResult<Self> {
+        let norm = (w * w + x * x + y * y + z * z).sqrt();
+        if !norm.is_normal() {
+            return Err(AdcsError::InvalidValue);
+        }
+        Ok(Quaternion {
+            w: w / norm,
+            x: x / norm,
+            y: y / norm,
+            z: z / norm,
+        })
+    }
+
+    /// The quaternion which performs no rotation
+    pub fn identity() -> Self {
+        Quaternion {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    /// Creates the quaternion which rotates by `angle` radians about `axis`
+    pub fn from_axis_angle(axis: &Vector3, angle: f64) -> AdcsResult<Self> {
+        let axis = axis.normalize()?;
+        let half = angle / 2.0;
+        let sin = half.sin();
+        Quaternion::new(half.cos(), axis.x * sin, axis.y * sin, axis.z * sin)
+    }
+
+    /// Creates a quaternion from a direction cosine matrix, given as rows.
+    /// The result transforms vectors the same way as `dcm * v`
+    pub fn from_dcm(dcm: &[[f64; 3]; 3]) -> AdcsResult<Self> {
+        let m = dcm;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quaternion::new(
+                0.25 * s,
+                (m[2][1] - m[1][2]) / s,
+                (m[0][2] - m[2][0]) / s,
+                (m[1][0] - m[0][1]) / s,
+            )
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+            Quaternion::new(
+                (m[2][1] - m[1][2]) / s,
+                0.25 * s,
+                (m[0][1] + m[1][0]) / s,
+                (m[0][2] + m[2][0]) / s,
+            )
+        } else if m[1][1] > m[2][2] {
+            let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+            Quaternion::new(
+                (m[0][2] - m[2][0]) / s,
+                (m[0][1] + m[1][0]) / s,
+                0.25 * s,
+                (m[1][2] + m[2][1]) / s,
+            )
+        } else {
+            let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+            Quaternion::new(
+                (m[1][0] - m[0][1]) / s,
+                (m[0][2] + m[2][0]) / s,
+                (m[1][2] + m[2][1]) / s,
+                0.25 * s,
+            )
+        }
+    }
+
+    /// The inverse rotation
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    /// Hamilton product `self * other`, ie. apply `other` and then `self`
+    pub fn multiply(&self, other: &Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w * other.w - self.x * other.x - self.y * other.y - self.z * other.z,
+            x: self.w * other.x + self.x * other.w + self.y * other.z - self.z * other.y,
+            y: self.w * other.y - self.x * other.z + self.y * other.w + self.z * other.x,
+            z: self.w * other.z + self.x * other.y - self.y * other.x + self.z * other.w,
+        }
+    }
+
+    /// Transforms a vector by this rotation
+    pub fn rotate(&self, v: &Vector3) -> Vector3 {
+        let q = Vector3::new(self.x, self.y, self.z);
+        let t = q.cross(v).scale(2.0);
+        v.add(&t.scale(self.w)).add(&q.cross(&t))
+    }
+}
+
+/// Spacecraft attitude relative to a reference frame
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct Attitude {
+    /// Quaternion transforming vectors from the reference frame into the body frame
+    pub quaternion: Quaternion,
+    /// Frame the attitude is relative to. Never `Frame::Body`
+    pub reference: Frame,
+}
+
+impl Attitude {
+    /// Creates a new attitude relative to the given reference frame
+    pub fn new(quaternion: Quaternion, reference: Frame) -> AdcsResult<Self> {
+        if reference == Frame::Body {
+            return Err(AdcsError::InvalidValue);
+        }
+        Ok(Attitude {
+            quaternion,
+            reference,
+        })
+    }
+
+    /// Expresses the same attitude relative to a different reference frame.
+    /// Any attitude already set in `converter` is ignored
+    pub fn relative_to(&self, converter: &FrameConverter, reference: Frame) -> AdcsResult<Self> {
+        let to_body = converter
+            .clone()
+            .attitude(*self)
+            .rotation(reference, Frame::Body)?;
+        Attitude::new(to_body, reference)
+    }
+}
+
+/// Body angular rates, always expressed in the body frame
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct BodyRates {
+    /// Angular rate about each body axis, in radians per second
+    pub radians_per_sec: Vector3,
+}
+
+impl BodyRates {
+    /// Creates body rates from values in radians per second
+    pub fn from_radians_per_sec(x: f64, y: f64, z: f64) -> AdcsResult<Self> {
+        Ok(BodyRates {
+            radians_per_sec: Vector3::new(x, y, z).check_finite()?,
+        })
+    }
+
+    /// Creates body rates from values in degrees per second
+    pub fn from_degrees_per_sec(x: f64, y: f64, z: f64) -> AdcsResult<Self> {
+        let factor = PI / 180.0;
+        BodyRates::from_radians_per_sec(x * factor, y * factor, z * factor)
+    }
+
+    /// Returns the rates in degrees per second
+    pub fn degrees_per_sec(&self) -> Vector3 {
+        self.radians_per_sec.scale(180.0 / PI)
+    }
+}
+
+/// Magnetic field vector
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct MagneticField {
+    /// Field strength along each axis, in nanotesla
+    pub nanotesla: Vector3,
+    /// Frame the vector is expressed in
+    pub frame: Frame,
+}
+
+impl MagneticField {
+    /// Creates a magnetic field vector from values in nanotesla
+    pub fn from_nanotesla(x: f64, y: f64, z: f64, frame: Frame) -> AdcsResult<Self> {
+        Ok(MagneticField {
+            nanotesla: Vector3::new(x, y, z).check_finite()?,
+            frame,
+        })
+    }
+
+    /// Creates a magnetic field vector from values in gauss
+    pub fn from_gauss(x: f64, y: f64, z: f64, frame: Frame) -> AdcsResult<Self> {
+        let factor = 100_000.0;
+        MagneticField::from_nanotesla(x * factor, y * factor, z * factor, frame)
+    }
+
+    /// Expresses the field in a different frame
+    pub fn in_frame(&self, converter: &FrameConverter, frame: Frame) -> AdcsResult<Self> {
+        Ok(MagneticField {
+            nanotesla: converter.convert(&self.nanotesla, self.frame, frame)?,
+            frame,
+        })
+    }
+}
+
+/// Unit vector pointing at the sun
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct SunVector {
+    /// Direction of the sun, normalized to unit length
+    pub unit: Vector3,
+    /// Frame the vector is expressed in
+    pub frame: Frame,
+}
+
+impl SunVector {
+    /// Creates a sun vector from any non-zero vector pointing at the sun
+    pub fn new(x: f64, y: f64, z: f64, frame: Frame) -> AdcsResult<Self> {
+        Ok(SunVector {
+            unit: Vector3::new(x, y, z).normalize()?,
+            frame,
+        })
+    }
+
+    /// Expresses the sun vector in a different frame
+    pub fn in_frame(&self, converter: &FrameConverter, frame: Frame) -> AdcsResult<Self> {
+        Ok(SunVector {
+            unit: converter.convert(&self.unit, self.frame, frame)?,
+            frame,
+        })
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Conversions between the body, ECI and LVLH frames
+//!
+//! Converting to or from the body frame requires the spacecraft's attitude, and converting
+//! to or from LVLH requires its orbital state. A [`FrameConverter`] is built with whichever
+//! of these are known and reports [`AdcsError::Frame`] if asked for a conversion it doesn't
+//! have the data for.
+//!
+//! # Example
+//!
+//! ```
+//! use adcs_api::*;
+//!
+//! # fn main() { func().unwrap(); }
+//! # fn func() -> AdcsResult<()> {
+//! let attitude = Attitude::new(Quaternion::new(0.9, 0.1, 0.3, 0.0)?, Frame::Eci)?;
+//! let orbit = OrbitState {
+//!     position_m: Vector3::new(6_778_000.0, 0.0, 0.0),
+//!     velocity_m_per_sec: Vector3::new(0.0, 7_668.0, 0.0),
+//! };
+//! let converter = FrameConverter::new().attitude(attitude).orbit(orbit);
+//!
+//! let sun = SunVector::new(0.2, 0.5, 0.8, Frame::Body)?;
+//! let sun_lvlh = sun.in_frame(&converter, Frame::Lvlh)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::attitude::{Attitude, Frame, Quaternion, Vector3};
+use crate::{AdcsError, AdcsResult};
+#[cfg(feature = "graphql")]
+use juniper::GraphQLObject;
+use serde_derive::{Deserialize, Serialize};
+
+/// Spacecraft position and velocity in the ECI frame
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct OrbitState {
+    /// Position, in meters
+    pub position_m: Vector3,
+    /// Velocity, in meters per second
+    pub velocity_m_per_sec: Vector3,
+}
+
+impl OrbitState {
+    /// Quaternion transforming ECI vectors into the LVLH frame
+    pub fn eci_to_lvlh(&self) -> AdcsResult<Quaternion> {
+        let z = self.position_m.scale(-1.0).normalize()?;
+        let y = self
+            .position_m
+            .cross(&self.velocity_m_per_sec)
+            .scale(-1.0)
+            .normalize()?;
+        let x = y.cross(&z);
+
+        Quaternion::from_dcm(&[[x.x, x.y, x.z], [y.x, y.y, y.z], [z.x, z.y, z.z]])
+    }
+}
+
+/// Converts vectors and attitudes between frames
+#[derive(Clone, Debug, Default)]
+pub struct FrameConverter {
+    attitude: Option<Attitude>,
+    orbit: Option<OrbitState>,
+}
+
+impl FrameConverter {
+    /// Creates a converter which can't convert between any frames yet
+    pub fn new() -> Self {
+        FrameConverter::default()
+    }
+
+    /// Sets the spacecraft attitude, enabling conversions to and from the body frame
+    pub fn attitude(mut self, attitude: Attitude) -> Self {
+        self.attitude = Some(attitude);
+        self
+    }
+
+    /// Sets the orbital state, enabling conversions to and from the LVLH frame
+    pub fn orbit(mut self, orbit: OrbitState) -> Self {
+        self.orbit = Some(orbit);
+        self
+    }
+
+    // Quaternion transforming ECI vectors into the given frame
+    fn eci_to(&self, frame: Frame) -> AdcsResult<Quaternion> {
+        match frame {
+            Frame::Eci => Ok(Quaternion::identity()),
+            Frame::Lvlh => self.orbit.ok_or(AdcsError::Frame)?.eci_to_lvlh(),
+            Frame::Body => {
+                let attitude = self.attitude.ok_or(AdcsError::Frame)?;
+                if attitude.reference == Frame::Body {
+                    return Err(AdcsError::InvalidValue);
+                }
+                let reference = self.eci_to(attitude.reference)?;
+                Ok(attitude.quaternion.multiply(&reference))
+            }
+        }
+    }
+
+    /// Quaternion transforming vectors in frame `from` into frame `to`
+    pub fn rotation(&self, from: Frame, to: Frame) -> AdcsResult<Quaternion> {
+        if from == to {
+            return Ok(Quaternion::identity());
+        }
+
+        let to = self.eci_to(to)?;
+        let from = self.eci_to(from)?;
+        Ok(to.multiply(&from.conjugate()))
+    }
+
+    /// Transforms a vector in frame `from` into frame `to`
+    pub fn convert(&self, vector: &Vector3, from: Frame, to: Frame) -> AdcsResult<Vector3> {
+        Ok(self.rotation(from, to)?.rotate(vector))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attitude::SunVector;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn assert_close(expected: Vector3, actual: Vector3) {
+        let diff = expected.add(&actual.scale(-1.0)).norm();
+        assert!(diff < 1e-9, "expected {:?}, got {:?}", expected, actual);
+    }
+
+    fn orbit() -> OrbitState {
+        OrbitState {
+            position_m: Vector3::new(7_000_000.0, 0.0, 0.0),
+            velocity_m_per_sec: Vector3::new(0.0, 7_500.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn quaternion_rotate() {
+        let q = Quaternion::from_axis_angle(&Vector3::new(0.0, 0.0, 1.0), FRAC_PI_2).unwrap();
+        assert_close(
+            Vector3::new(0.0, 1.0, 0.0),
+            q.rotate(&Vector3::new(1.0, 0.0, 0.0)),
+        );
+    }
+
+    #[test]
+    fn quaternion_from_dcm() {
+        let q = Quaternion::from_axis_angle(&Vector3::new(1.0, 2.0, 3.0), 2.5).unwrap();
+        let cols = [
+            q.rotate(&Vector3::new(1.0, 0.0, 0.0)),
+            q.rotate(&Vector3::new(0.0, 1.0, 0.0)),
+            q.rotate(&Vector3::new(0.0, 0.0, 1.0)),
+        ];
+        let dcm = [
+            [cols[0].x, cols[1].x, cols[2].x],
+            [cols[0].y, cols[1].y, cols[2].y],
+            [cols[0].z, cols[1].z, cols[2].z],
+        ];
+
+        let v = Vector3::new(-4.0, 0.5, 2.0);
+        assert_close(q.rotate(&v), Quaternion::from_dcm(&dcm).unwrap().rotate(&v));
+    }
+
+    #[test]
+    fn eci_to_lvlh() {
+        let converter = FrameConverter::new().orbit(orbit());
+
+        // Zenith is -Z
+        assert_close(
+            Vector3::new(0.0, 0.0, -1.0),
+            converter
+                .convert(&Vector3::new(1.0, 0.0, 0.0), Frame::Eci, Frame::Lvlh)
+                .unwrap(),
+        );
+        // Velocity is +X
+        assert_close(
+            Vector3::new(1.0, 0.0, 0.0),
+            converter
+                .convert(&Vector3::new(0.0, 1.0, 0.0), Frame::Eci, Frame::Lvlh)
+                .unwrap(),
+        );
+        // Orbit normal is -Y
+        assert_close(
+            Vector3::new(0.0, -1.0, 0.0),
+            converter
+                .convert(&Vector3::new(0.0, 0.0, 1.0), Frame::Eci, Frame::Lvlh)
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn body_round_trip() {
+        let q = Quaternion::new(0.9, 0.1, 0.3, -0.2).unwrap();
+        let converter = FrameConverter::new()
+            .attitude(Attitude::new(q, Frame::Lvlh).unwrap())
+            .orbit(orbit());
+
+        let sun = SunVector::new(0.2, 0.5, 0.8, Frame::Body).unwrap();
+        let eci = sun.in_frame(&converter, Frame::Eci).unwrap();
+        let back = eci.in_frame(&converter, Frame::Body).unwrap();
+
+        assert_eq!(Frame::Eci, eci.frame);
+        assert_close(sun.unit, back.unit);
+
+        // Going via ECI should match the attitude's own LVLH to body rotation
+        let lvlh = sun.in_frame(&converter, Frame::Lvlh).unwrap();
+        assert_close(sun.unit, q.rotate(&lvlh.unit));
+    }
+
+    #[test]
+    fn attitude_relative_to() {
+        let q = Quaternion::from_axis_angle(&Vector3::new(0.0, 0.0, 1.0), FRAC_PI_2).unwrap();
+        let converter = FrameConverter::new().orbit(orbit());
+
+        let lvlh = Attitude::new(q, Frame::Eci)
+            .unwrap()
+            .relative_to(&converter, Frame::Lvlh)
+            .unwrap();
+        assert_eq!(Frame::Lvlh, lvlh.reference);
+
+        let eci = lvlh.relative_to(&converter, Frame::Eci).unwrap();
+        let v = Vector3::new(1.0, 2.0, 3.0);
+        assert_close(q.rotate(&v), eci.quaternion.rotate(&v));
+    }
+
+    #[test]
+    fn missing_data() {
+        let converter = FrameConverter::new().orbit(orbit());
+        assert_eq!(
+            Err(AdcsError::Frame),
+            converter.convert(&Vector3::default(), Frame::Body, Frame::Eci)
+        );
+
+        let converter = FrameConverter::new();
+        assert_eq!(
+            Err(AdcsError::Frame),
+            converter.rotation(Frame::Eci, Frame::Lvlh)
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(
+            Err(AdcsError::InvalidValue),
+            SunVector::new(0.0, 0.0, 0.0, Frame::Body)
+        );
+        assert_eq!(
+            Err(AdcsError::InvalidValue),
+            Quaternion::new(0.0, 0.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            Err(AdcsError::InvalidValue),
+            Attitude::new(Quaternion::identity(), Frame::Body)
+        );
+    }
+}
@@ -20,6 +20,12 @@
 
 use failure::Fail;
 
+mod attitude;
+mod frames;
+
+pub use crate::attitude::{Attitude, BodyRates, Frame, MagneticField, Quaternion, SunVector, Vector3};
+pub use crate::frames::{FrameConverter, OrbitState};
+
 /// Errors for ADCS devices
 #[derive(Fail, Debug, PartialEq)]
 pub enum AdcsError {
@@ -41,6 +47,27 @@ pub enum AdcsError {
     /// Requested function has not been implemented
     #[fail(display = "Requested function has not been implemented")]
     NotImplemented,
+    /// The subsystem rejected the command
+    #[fail(display = "The subsystem rejected the command")]
+    Rejected,
+    /// The subsystem did not recognize the command
+    #[fail(display = "Invalid command")]
+    InvalidCommand,
+    /// A required command parameter was missing
+    #[fail(display = "Command parameter missing")]
+    MissingParameter,
+    /// A command parameter was invalid
+    #[fail(display = "Command parameter invalid")]
+    InvalidParameter,
+    /// The command is not available in the subsystem's current mode
+    #[fail(display = "Command unavailable in current mode")]
+    WrongMode,
+    /// A value was out of range or non-finite
+    #[fail(display = "Invalid value")]
+    InvalidValue,
+    /// The data needed to convert between two frames is not available
+    #[fail(display = "Insufficient data for frame conversion")]
+    Frame,
 }
 
 /// ADCS specific result type
@@ -0,0 +1,567 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Typed iMTQ command definitions
+//!
+//! Each iMTQ command is a command code followed by little-endian parameters. Each response
+//! begins with the echoed command code and a status byte, followed by a fixed-length body.
+//! Implementing the [`Command`] trait describes all of this once, after which
+//! [`Imtq::execute`] takes care of packing the request, retrying transient failures,
+//! checking the status byte and parsing the body.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate adcs_api;
+//! extern crate isis_imtq_api;
+//! use adcs_api::*;
+//! use isis_imtq_api::*;
+//!
+//! /// Get the commanded actuation dipole
+//! struct GetDipole;
+//!
+//! impl Command for GetDipole {
+//!     const CODE: u8 = 0x46;
+//!     const RESPONSE_LEN: usize = 6;
+//!     type Response = (i16, i16, i16);
+//!
+//!     fn parse(body: &mut ResponseReader) -> AdcsResult<Self::Response> {
+//!         Ok((body.i16()?, body.i16()?, body.i16()?))
+//!     }
+//! }
+//!
+//! # fn main() { func(); }
+//!
+//! # fn func() -> AdcsResult<()> {
+//! let imtq = Imtq::imtq("/dev/i2c-0", 0x40, 60)?;
+//! let (x, y, z) = imtq.execute(&GetDipole)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::ffi::{ImtqFFI, KADCSStatus};
+use crate::imtq::Imtq;
+use adcs_api::*;
+use std::thread;
+use std::time::Duration;
+
+/// Length of the header (echoed command code and status byte) which starts every response
+pub const RESPONSE_HEADER_LEN: usize = 2;
+
+// Value of the command code byte when the iMTQ has no response ready
+const NO_RESPONSE: u8 = 0xFF;
+
+// Status byte flags
+const STATUS_NEW: u8 = 0x80;
+const STATUS_IVA_X: u8 = 0x40;
+const STATUS_IVA_Y: u8 = 0x20;
+const STATUS_IVA_Z: u8 = 0x10;
+const STATUS_CODE_MASK: u8 = 0x0F;
+
+// There must be at least a 1ms delay between sending a command and reading its response
+const MIN_DELAY: Duration = Duration::from_nanos(1_000_001);
+
+/// Definition of a single iMTQ command and the layout of its response
+pub trait Command {
+    /// Command code sent as the first byte of the request
+    const CODE: u8;
+    /// Length of the response body, excluding the two-byte response header
+    const RESPONSE_LEN: usize;
+    /// Type the response body is parsed into
+    type Response;
+
+    /// Parameters sent after the command code
+    fn params(&self, _params: &mut ParamWriter) {}
+
+    /// Time to wait between sending the command and reading the response
+    fn delay(&self) -> Duration {
+        MIN_DELAY
+    }
+
+    /// Parses the response body (everything after the response header)
+    fn parse(body: &mut ResponseReader) -> AdcsResult<Self::Response>;
+}
+
+/// Packs command parameters in the iMTQ's little-endian byte order
+#[derive(Debug, Default)]
+pub struct ParamWriter {
+    pub(crate) buffer: Vec<u8>,
+}
+
+impl ParamWriter {
+    /// Appends an `i8` parameter
+    pub fn i8(&mut self, value: i8) -> &mut Self {
+        self.u8(value as u8)
+    }
+
+    /// Appends a `u8` parameter
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buffer.push(value);
+        self
+    }
+
+    /// Appends an `i16` parameter
+    pub fn i16(&mut self, value: i16) -> &mut Self {
+        self.u16(value as u16)
+    }
+
+    /// Appends a `u16` parameter
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Appends an `i32` parameter
+    pub fn i32(&mut self, value: i32) -> &mut Self {
+        self.u32(value as u32)
+    }
+
+    /// Appends a `u32` parameter
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Appends an `f32` parameter
+    pub fn f32(&mut self, value: f32) -> &mut Self {
+        self.u32(value.to_bits())
+    }
+
+    /// Appends an `i64` parameter
+    pub fn i64(&mut self, value: i64) -> &mut Self {
+        self.u64(value as u64)
+    }
+
+    /// Appends a `u64` parameter
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Appends an `f64` parameter
+    pub fn f64(&mut self, value: f64) -> &mut Self {
+        self.u64(value.to_bits())
+    }
+}
+
+/// Reads little-endian fields out of a response body
+#[derive(Debug)]
+pub struct ResponseReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ResponseReader<'a> {
+    /// Creates a reader over the given response body
+    pub fn new(data: &'a [u8]) -> Self {
+        ResponseReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> AdcsResult<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(AdcsError::Generic);
+        }
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Reads an `i8` field
+    pub fn i8(&mut self) -> AdcsResult<i8> {
+        Ok(self.u8()? as i8)
+    }
+
+    /// Reads a `u8` field
+    pub fn u8(&mut self) -> AdcsResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads an `i16` field
+    pub fn i16(&mut self) -> AdcsResult<i16> {
+        Ok(self.u16()? as i16)
+    }
+
+    /// Reads a `u16` field
+    pub fn u16(&mut self) -> AdcsResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from(bytes[0]) | u16::from(bytes[1]) << 8)
+    }
+
+    /// Reads an `i32` field
+    pub fn i32(&mut self) -> AdcsResult<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    /// Reads a `u32` field
+    pub fn u32(&mut self) -> AdcsResult<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from(bytes[0])
+            | u32::from(bytes[1]) << 8
+            | u32::from(bytes[2]) << 16
+            | u32::from(bytes[3]) << 24)
+    }
+
+    /// Reads an `f32` field
+    pub fn f32(&mut self) -> AdcsResult<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// Reads an `i64` field
+    pub fn i64(&mut self) -> AdcsResult<i64> {
+        Ok(self.u64()? as i64)
+    }
+
+    /// Reads a `u64` field
+    pub fn u64(&mut self) -> AdcsResult<u64> {
+        let low = u64::from(self.u32()?);
+        let high = u64::from(self.u32()?);
+        Ok(low | high << 32)
+    }
+
+    /// Reads an `f64` field
+    pub fn f64(&mut self) -> AdcsResult<f64> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    /// Returns the bytes which haven't been read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+}
+
+/// Decoded response status byte
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    /// This is the first time the response has been read
+    pub new: bool,
+    /// The X-axis measurement might be invalid
+    pub invalid_x: bool,
+    /// The Y-axis measurement might be invalid
+    pub invalid_y: bool,
+    /// The Z-axis measurement might be invalid
+    pub invalid_z: bool,
+    /// Return code of the command
+    pub code: u8,
+}
+
+impl Status {
+    /// Decodes a raw status byte
+    pub fn parse(byte: u8) -> Self {
+        Status {
+            new: byte & STATUS_NEW != 0,
+            invalid_x: byte & STATUS_IVA_X != 0,
+            invalid_y: byte & STATUS_IVA_Y != 0,
+            invalid_z: byte & STATUS_IVA_Z != 0,
+            code: byte & STATUS_CODE_MASK,
+        }
+    }
+
+    /// Converts the return code into a result
+    pub fn check(&self) -> AdcsResult<()> {
+        match self.code {
+            0x00 => Ok(()),
+            0x01 => Err(AdcsError::Rejected),
+            0x02 => Err(AdcsError::InvalidCommand),
+            0x03 => Err(AdcsError::MissingParameter),
+            0x04 => Err(AdcsError::InvalidParameter),
+            0x05 => Err(AdcsError::WrongMode),
+            0x07 => Err(AdcsError::Internal),
+            _ => Err(AdcsError::Generic),
+        }
+    }
+}
+
+/// Controls how transient failures are retried by [`Imtq::execute_with_retry`]
+///
+/// Failed I2C transfers (eg. the iMTQ NAKing its address while busy) and responses which
+/// weren't ready yet are retried. Errors reported in the status byte are not, since the
+/// iMTQ has already processed the command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of times to retry after the initial attempt
+    pub retries: u32,
+    /// Time to wait between attempts
+    pub interval: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            interval: Duration::from_millis(10),
+        }
+    }
+}
+
+// Check the response header and parse the body
+fn parse_response<C: Command>(rx: &[u8]) -> AdcsResult<C::Response> {
+    if rx.len() < RESPONSE_HEADER_LEN + C::RESPONSE_LEN {
+        return Err(AdcsError::Generic);
+    }
+
+    if rx[0] == NO_RESPONSE {
+        return Err(AdcsError::NoResponse);
+    }
+
+    if rx[0] != C::CODE {
+        return Err(AdcsError::Generic);
+    }
+
+    Status::parse(rx[1]).check()?;
+
+    C::parse(&mut ResponseReader::new(&rx[RESPONSE_HEADER_LEN..]))
+}
+
+impl<T: ImtqFFI> Imtq<T> {
+    /// Executes a typed command, retrying transient failures with the default [`RetryPolicy`]
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to execute
+    pub fn execute<C: Command>(&self, command: &C) -> AdcsResult<C::Response> {
+        self.execute_with_retry(command, &RetryPolicy::default())
+    }
+
+    /// Executes a typed command, retrying transient failures according to the given policy
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to execute
+    /// * `retry` - How many times, and how often, to retry transient failures
+    pub fn execute_with_retry<C: Command>(
+        &self,
+        command: &C,
+        retry: &RetryPolicy,
+    ) -> AdcsResult<C::Response> {
+        let mut params = ParamWriter::default();
+        params.u8(C::CODE);
+        command.params(&mut params);
+
+        let rx_len = (RESPONSE_HEADER_LEN + C::RESPONSE_LEN) as i32;
+        let delay = command.delay();
+
+        let mut attempt = 0;
+        loop {
+            let (status, rx) = self.transfer(
+                &params.buffer,
+                rx_len,
+                delay.as_secs() as i32,
+                i64::from(delay.subsec_nanos()),
+            );
+
+            let result = match status {
+                // The transfer itself worked. Errors in the status byte are also reported
+                // as internal errors, so decode the header to find out what actually happened
+                KADCSStatus::Ok | KADCSStatus::ErrorInternal => parse_response::<C>(&rx),
+                KADCSStatus::Error => Err(AdcsError::Generic),
+                KADCSStatus::ErrorConfig => Err(AdcsError::Config),
+                KADCSStatus::ErrorNoResponse => Err(AdcsError::NoResponse),
+                KADCSStatus::ErrorMutex => Err(AdcsError::Mutex),
+                KADCSStatus::ErrorNotImplemented => Err(AdcsError::NotImplemented),
+            };
+
+            match result {
+                Err(AdcsError::Generic) | Err(AdcsError::NoResponse)
+                    if attempt < retry.retries =>
+                {
+                    attempt += 1;
+                    thread::sleep(retry.interval);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// No-operation. Useful for checking that the iMTQ is responsive
+pub struct NoOp;
+
+impl Command for NoOp {
+    const CODE: u8 = 0x02;
+    const RESPONSE_LEN: usize = 0;
+    type Response = ();
+
+    fn parse(_body: &mut ResponseReader) -> AdcsResult<()> {
+        Ok(())
+    }
+}
+
+/// Cancel the current operation and return to idle mode
+pub struct CancelOp;
+
+impl Command for CancelOp {
+    const CODE: u8 = 0x03;
+    const RESPONSE_LEN: usize = 0;
+    type Response = ();
+
+    fn parse(_body: &mut ResponseReader) -> AdcsResult<()> {
+        Ok(())
+    }
+}
+
+/// Start a magnetometer measurement
+pub struct StartMeasurement;
+
+impl Command for StartMeasurement {
+    const CODE: u8 = 0x04;
+    const RESPONSE_LEN: usize = 0;
+    type Response = ();
+
+    fn parse(_body: &mut ResponseReader) -> AdcsResult<()> {
+        Ok(())
+    }
+}
+
+/// Actuate the coils with the given dipole, in 10<sup>-4</sup> Am<sup>2</sup>
+pub struct StartActuationDipole {
+    /// X-axis dipole
+    pub x: i16,
+    /// Y-axis dipole
+    pub y: i16,
+    /// Z-axis dipole
+    pub z: i16,
+    /// Actuation duration in milliseconds. 0 means indefinitely
+    pub duration: u16,
+}
+
+impl Command for StartActuationDipole {
+    const CODE: u8 = 0x06;
+    const RESPONSE_LEN: usize = 0;
+    type Response = ();
+
+    fn params(&self, params: &mut ParamWriter) {
+        params.i16(self.x).i16(self.y).i16(self.z).u16(self.duration);
+    }
+
+    fn parse(_body: &mut ResponseReader) -> AdcsResult<()> {
+        Ok(())
+    }
+}
+
+/// Current iMTQ system state, as returned by [`GetSystemState`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SystemState {
+    /// Current system mode
+    pub mode: u8,
+    /// Error encountered during the previous iteration
+    pub error: u8,
+    /// Whether any parameters have been updated since system startup
+    pub configured: bool,
+    /// System uptime in seconds
+    pub uptime: u32,
+}
+
+/// Get the current iMTQ system state
+pub struct GetSystemState;
+
+impl Command for GetSystemState {
+    const CODE: u8 = 0x41;
+    const RESPONSE_LEN: usize = 7;
+    type Response = SystemState;
+
+    fn parse(body: &mut ResponseReader) -> AdcsResult<SystemState> {
+        Ok(SystemState {
+            mode: body.u8()?,
+            error: body.u8()?,
+            configured: body.u8()? != 0,
+            uptime: body.u32()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_parse() {
+        assert_eq!(
+            Status {
+                new: true,
+                invalid_x: true,
+                invalid_y: false,
+                invalid_z: true,
+                code: 0x04,
+            },
+            Status::parse(0xD4)
+        );
+    }
+
+    #[test]
+    fn test_status_errors() {
+        assert_eq!(Ok(()), Status::parse(0x80).check());
+        assert_eq!(Err(AdcsError::Rejected), Status::parse(0x01).check());
+        assert_eq!(Err(AdcsError::InvalidCommand), Status::parse(0x02).check());
+        assert_eq!(Err(AdcsError::MissingParameter), Status::parse(0x03).check());
+        assert_eq!(Err(AdcsError::InvalidParameter), Status::parse(0x04).check());
+        assert_eq!(Err(AdcsError::WrongMode), Status::parse(0x05).check());
+        assert_eq!(Err(AdcsError::Generic), Status::parse(0x06).check());
+        assert_eq!(Err(AdcsError::Internal), Status::parse(0x07).check());
+    }
+
+    #[test]
+    fn test_param_writer() {
+        let mut params = ParamWriter::default();
+        StartActuationDipole {
+            x: 1,
+            y: -1,
+            z: 0x1234,
+            duration: 500,
+        }
+        .params(&mut params);
+
+        assert_eq!(
+            vec![0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xF4, 0x01],
+            params.buffer
+        );
+    }
+
+    #[test]
+    fn test_response_reader_short() {
+        let data = [1, 2, 3];
+        let mut reader = ResponseReader::new(&data);
+        assert_eq!(Ok(0x0201), reader.u16());
+        assert_eq!(Err(AdcsError::Generic), reader.u16());
+        assert_eq!(&[3], reader.remaining());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let rx = [0x41, 0x80, 0x02, 0x00, 0x01, 0x10, 0x0E, 0x00, 0x00];
+        assert_eq!(
+            Ok(SystemState {
+                mode: 2,
+                error: 0,
+                configured: true,
+                uptime: 3600,
+            }),
+            parse_response::<GetSystemState>(&rx)
+        );
+    }
+
+    #[test]
+    fn test_parse_response_not_ready() {
+        let rx = [0xFF, 0xFF];
+        assert_eq!(Err(AdcsError::NoResponse), parse_response::<NoOp>(&rx));
+    }
+
+    #[test]
+    fn test_parse_response_mismatch() {
+        let rx = [0x03, 0x00];
+        assert_eq!(Err(AdcsError::Generic), parse_response::<NoOp>(&rx));
+    }
+}
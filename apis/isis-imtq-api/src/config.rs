@@ -0,0 +1,653 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Named iMTQ configuration parameters
+//!
+//! Each iMTQ configuration parameter has a 16-bit ID, the top four bits of which give the type
+//! of its value. [`PARAMS`] lists every parameter by name, along with its units and, where the
+//! iMTQ user manual limits it further than its type does, its valid range. Values are checked
+//! against the table before they're sent, so a mistyped or out of range value is caught on the
+//! ground side of the API rather than being stored by the iMTQ.
+//!
+//! [`Imtq::dump_params`] reads back every parameter in the table, for telemetry snapshots of
+//! the iMTQ's configuration.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate adcs_api;
+//! extern crate isis_imtq_api;
+//! use adcs_api::*;
+//! use isis_imtq_api::*;
+//!
+//! # fn main() { func(); }
+//!
+//! # fn func() -> AdcsResult<()> {
+//! let imtq = Imtq::imtq("/dev/i2c-0", 0x40, 60)?;
+//! imtq.set_param("detumble_frequency", 4.0)?;
+//! let gain = imtq.get_param("bdot_gain")?;
+//! for (param, value) in imtq.dump_params() {
+//!     println!("{} = {:?} {}", param.name, value, param.units);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::command::{Command, ParamWriter, ResponseReader};
+use crate::ffi::ImtqFFI;
+use crate::imtq::Imtq;
+use adcs_api::*;
+
+// Command codes for reading, writing and resetting a parameter
+const GET_PARAM: u8 = 0x81;
+const SET_PARAM: u8 = 0x82;
+const RESET_PARAM: u8 = 0x83;
+
+// Size of the value field in parameter commands and responses, whatever the parameter's type
+const VALUE_LEN: usize = 8;
+
+/// Type of a parameter's value
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParamType {
+    /// Signed byte
+    I8,
+    /// Unsigned byte
+    U8,
+    /// Signed 16-bit integer
+    I16,
+    /// Unsigned 16-bit integer
+    U16,
+    /// Signed 32-bit integer
+    I32,
+    /// Unsigned 32-bit integer
+    U32,
+    /// Single-precision float
+    F32,
+    /// Signed 64-bit integer
+    I64,
+    /// Unsigned 64-bit integer
+    U64,
+    /// Double-precision float
+    F64,
+}
+
+impl ParamType {
+    /// Gets the type of a parameter's value from the top four bits of its ID
+    pub fn from_id(id: u16) -> Option<Self> {
+        match id >> 12 {
+            0x1 => Some(ParamType::I8),
+            0x2 => Some(ParamType::U8),
+            0x3 => Some(ParamType::I16),
+            0x4 => Some(ParamType::U16),
+            0x5 => Some(ParamType::I32),
+            0x6 => Some(ParamType::U32),
+            0x7 => Some(ParamType::F32),
+            0x8 => Some(ParamType::I64),
+            0x9 => Some(ParamType::U64),
+            0xA => Some(ParamType::F64),
+            _ => None,
+        }
+    }
+
+    // Smallest and largest values of the type
+    fn limits(self) -> (f64, f64) {
+        match self {
+            ParamType::I8 => (f64::from(i8::min_value()), f64::from(i8::max_value())),
+            ParamType::U8 => (0.0, f64::from(u8::max_value())),
+            ParamType::I16 => (f64::from(i16::min_value()), f64::from(i16::max_value())),
+            ParamType::U16 => (0.0, f64::from(u16::max_value())),
+            ParamType::I32 => (f64::from(i32::min_value()), f64::from(i32::max_value())),
+            ParamType::U32 => (0.0, f64::from(u32::max_value())),
+            ParamType::F32 => (f64::from(std::f32::MIN), f64::from(std::f32::MAX)),
+            ParamType::I64 => (i64::min_value() as f64, i64::max_value() as f64),
+            ParamType::U64 => (0.0, u64::max_value() as f64),
+            ParamType::F64 => (std::f64::MIN, std::f64::MAX),
+        }
+    }
+}
+
+/// Value of a parameter, in the parameter's own type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamValue {
+    /// Signed byte
+    I8(i8),
+    /// Unsigned byte
+    U8(u8),
+    /// Signed 16-bit integer
+    I16(i16),
+    /// Unsigned 16-bit integer
+    U16(u16),
+    /// Signed 32-bit integer
+    I32(i32),
+    /// Unsigned 32-bit integer
+    U32(u32),
+    /// Single-precision float
+    F32(f32),
+    /// Signed 64-bit integer
+    I64(i64),
+    /// Unsigned 64-bit integer
+    U64(u64),
+    /// Double-precision float
+    F64(f64),
+}
+
+impl ParamValue {
+    /// Converts a number to the given type. The number must be finite, within the type's
+    /// limits and, for integer types, a whole number.
+    pub fn from_f64(param_type: ParamType, value: f64) -> AdcsResult<Self> {
+        let (min, max) = param_type.limits();
+        let float = param_type == ParamType::F32 || param_type == ParamType::F64;
+        if !value.is_finite() || value < min || value > max || (!float && value.fract() != 0.0) {
+            return Err(AdcsError::InvalidValue);
+        }
+
+        Ok(match param_type {
+            ParamType::I8 => ParamValue::I8(value as i8),
+            ParamType::U8 => ParamValue::U8(value as u8),
+            ParamType::I16 => ParamValue::I16(value as i16),
+            ParamType::U16 => ParamValue::U16(value as u16),
+            ParamType::I32 => ParamValue::I32(value as i32),
+            ParamType::U32 => ParamValue::U32(value as u32),
+            ParamType::F32 => ParamValue::F32(value as f32),
+            ParamType::I64 => ParamValue::I64(value as i64),
+            ParamType::U64 => ParamValue::U64(value as u64),
+            ParamType::F64 => ParamValue::F64(value),
+        })
+    }
+
+    /// Converts the value to a double, for reporting in telemetry
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            ParamValue::I8(value) => f64::from(value),
+            ParamValue::U8(value) => f64::from(value),
+            ParamValue::I16(value) => f64::from(value),
+            ParamValue::U16(value) => f64::from(value),
+            ParamValue::I32(value) => f64::from(value),
+            ParamValue::U32(value) => f64::from(value),
+            ParamValue::F32(value) => f64::from(value),
+            ParamValue::I64(value) => value as f64,
+            ParamValue::U64(value) => value as f64,
+            ParamValue::F64(value) => value,
+        }
+    }
+
+    // Writes the value, zero-padded to the full width of the value field
+    fn write(&self, params: &mut ParamWriter) {
+        let len = match *self {
+            ParamValue::I8(value) => {
+                params.i8(value);
+                1
+            }
+            ParamValue::U8(value) => {
+                params.u8(value);
+                1
+            }
+            ParamValue::I16(value) => {
+                params.i16(value);
+                2
+            }
+            ParamValue::U16(value) => {
+                params.u16(value);
+                2
+            }
+            ParamValue::I32(value) => {
+                params.i32(value);
+                4
+            }
+            ParamValue::U32(value) => {
+                params.u32(value);
+                4
+            }
+            ParamValue::F32(value) => {
+                params.f32(value);
+                4
+            }
+            ParamValue::I64(value) => {
+                params.i64(value);
+                8
+            }
+            ParamValue::U64(value) => {
+                params.u64(value);
+                8
+            }
+            ParamValue::F64(value) => {
+                params.f64(value);
+                8
+            }
+        };
+        for _ in len..VALUE_LEN {
+            params.u8(0);
+        }
+    }
+
+    // Reads a value of the given type from the start of the value field
+    fn read(param_type: ParamType, body: &mut ResponseReader) -> AdcsResult<Self> {
+        Ok(match param_type {
+            ParamType::I8 => ParamValue::I8(body.i8()?),
+            ParamType::U8 => ParamValue::U8(body.u8()?),
+            ParamType::I16 => ParamValue::I16(body.i16()?),
+            ParamType::U16 => ParamValue::U16(body.u16()?),
+            ParamType::I32 => ParamValue::I32(body.i32()?),
+            ParamType::U32 => ParamValue::U32(body.u32()?),
+            ParamType::F32 => ParamValue::F32(body.f32()?),
+            ParamType::I64 => ParamValue::I64(body.i64()?),
+            ParamType::U64 => ParamValue::U64(body.u64()?),
+            ParamType::F64 => ParamValue::F64(body.f64()?),
+        })
+    }
+}
+
+/// A named iMTQ configuration parameter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    /// Name of the parameter, as used by [`Imtq::get_param`] and friends
+    pub name: &'static str,
+    /// ID the iMTQ knows the parameter by
+    pub id: u16,
+    /// Units of the parameter's value. Empty for unitless values and raw settings
+    pub units: &'static str,
+    /// Smallest and largest values accepted, if the user manual limits the parameter further
+    /// than its type does
+    pub range: Option<(f64, f64)>,
+    /// Whether the parameter may be set or reset. Parameters which identify the iMTQ can
+    /// only be read
+    pub writable: bool,
+}
+
+impl Param {
+    /// Looks up a parameter by name
+    pub fn find(name: &str) -> AdcsResult<&'static Param> {
+        PARAMS
+            .iter()
+            .find(|param| param.name == name)
+            .ok_or(AdcsError::Config)
+    }
+
+    /// Type of the parameter's value
+    pub fn value_type(&self) -> ParamType {
+        // Every ID in the table has a valid type, which the tests check
+        ParamType::from_id(self.id).unwrap_or(ParamType::U8)
+    }
+
+    /// Checks that a value can be written to the parameter, converting it to the parameter's
+    /// type. Returns `AdcsError::Config` if the parameter is read-only and
+    /// `AdcsError::InvalidValue` if the value is out of range or doesn't fit the type.
+    pub fn validate(&self, value: f64) -> AdcsResult<ParamValue> {
+        if !self.writable {
+            return Err(AdcsError::Config);
+        }
+        if let Some((min, max)) = self.range {
+            if value < min || value > max {
+                return Err(AdcsError::InvalidValue);
+            }
+        }
+        ParamValue::from_f64(self.value_type(), value)
+    }
+}
+
+const fn rw(name: &'static str, id: u16, units: &'static str, range: Option<(f64, f64)>) -> Param {
+    Param {
+        name,
+        id,
+        units,
+        range,
+        writable: true,
+    }
+}
+
+const fn ro(name: &'static str, id: u16, units: &'static str, range: Option<(f64, f64)>) -> Param {
+    Param {
+        name,
+        id,
+        units,
+        range,
+        writable: false,
+    }
+}
+
+/// Every iMTQ configuration parameter. Refer to the iMTQ user manual for their meanings.
+pub static PARAMS: &[Param] = &[
+    rw("mtm_select", 0x2002, "", Some((0.0, 1.0))),
+    rw("mtm_internal_time", 0x2003, "", Some((0.0, 7.0))),
+    rw("mtm_external_time", 0x2004, "", Some((0.0, 7.0))),
+    rw("mtm_internal_map_x", 0x2005, "", None),
+    rw("mtm_internal_map_y", 0x2006, "", None),
+    rw("mtm_internal_map_z", 0x2007, "", None),
+    rw("mtm_external_map_x", 0x2008, "", None),
+    rw("mtm_external_map_y", 0x2009, "", None),
+    rw("mtm_external_map_z", 0x200A, "", None),
+    rw("mtm_matrix_r1_c1", 0xA001, "", None),
+    rw("mtm_matrix_r1_c2", 0xA002, "", None),
+    rw("mtm_matrix_r1_c3", 0xA003, "", None),
+    rw("mtm_matrix_r2_c1", 0xA004, "", None),
+    rw("mtm_matrix_r2_c2", 0xA005, "", None),
+    rw("mtm_matrix_r2_c3", 0xA006, "", None),
+    rw("mtm_matrix_r3_c1", 0xA007, "", None),
+    rw("mtm_matrix_r3_c2", 0xA008, "", None),
+    rw("mtm_matrix_r3_c3", 0xA009, "", None),
+    rw("mtm_bias_x", 0xA00A, "T", None),
+    rw("mtm_bias_y", 0xA00B, "T", None),
+    rw("mtm_bias_z", 0xA00C, "T", None),
+    rw("adc_coil_current_bias_x", 0x301C, "", None),
+    rw("adc_coil_current_bias_y", 0x301D, "", None),
+    rw("adc_coil_current_bias_z", 0x301E, "", None),
+    rw("adc_coil_current_mult_x", 0x301F, "", None),
+    rw("adc_coil_current_mult_y", 0x3020, "", None),
+    rw("adc_coil_current_mult_z", 0x3021, "", None),
+    rw("adc_coil_current_div_x", 0x3022, "", None),
+    rw("adc_coil_current_div_y", 0x3023, "", None),
+    rw("adc_coil_current_div_z", 0x3024, "", None),
+    rw("adc_coil_temp_bias_x", 0x3025, "", None),
+    rw("adc_coil_temp_bias_y", 0x3026, "", None),
+    rw("adc_coil_temp_bias_z", 0x3027, "", None),
+    rw("adc_coil_temp_mult_x", 0x3028, "", None),
+    rw("adc_coil_temp_mult_y", 0x3029, "", None),
+    rw("adc_coil_temp_mult_z", 0x302A, "", None),
+    rw("adc_coil_temp_div_x", 0x302B, "", None),
+    rw("adc_coil_temp_div_y", 0x302C, "", None),
+    rw("adc_coil_temp_div_z", 0x302D, "", None),
+    rw("detumble_frequency", 0x2000, "", Some((1.0, 8.0))),
+    rw("bdot_gain", 0xA000, "", Some((std::f64::MIN, 0.0))),
+    rw("mtm_filter_sensitivity", 0xA00D, "", None),
+    rw("mtm_filter_weight", 0xA00E, "", None),
+    rw("coil_area_x", 0xA00F, "m^2", None),
+    rw("coil_area_y", 0xA010, "m^2", None),
+    rw("coil_area_z", 0xA011, "m^2", None),
+    rw("coil_current_limit", 0x4000, "mA", None),
+    rw("current_feedback_enable", 0x2001, "", Some((0.0, 1.0))),
+    rw("current_feedback_gain_x", 0x5000, "", None),
+    rw("current_feedback_gain_y", 0x5001, "", None),
+    rw("current_feedback_gain_z", 0x5002, "", None),
+    rw("current_map_temp_t1", 0x3000, "degC", None),
+    rw("current_map_temp_t2", 0x3001, "degC", None),
+    rw("current_map_temp_t3", 0x3002, "degC", None),
+    rw("current_map_temp_t4", 0x3003, "degC", None),
+    rw("current_map_temp_t5", 0x3004, "degC", None),
+    rw("current_map_temp_t6", 0x3005, "degC", None),
+    rw("current_map_temp_t7", 0x3006, "degC", None),
+    rw("current_max_x_t1", 0x3007, "mA", None),
+    rw("current_max_x_t2", 0x3008, "mA", None),
+    rw("current_max_x_t3", 0x3009, "mA", None),
+    rw("current_max_x_t4", 0x300A, "mA", None),
+    rw("current_max_x_t5", 0x300B, "mA", None),
+    rw("current_max_x_t6", 0x300C, "mA", None),
+    rw("current_max_x_t7", 0x300D, "mA", None),
+    rw("current_max_y_t1", 0x300E, "mA", None),
+    rw("current_max_y_t2", 0x300F, "mA", None),
+    rw("current_max_y_t3", 0x3010, "mA", None),
+    rw("current_max_y_t4", 0x3011, "mA", None),
+    rw("current_max_y_t5", 0x3012, "mA", None),
+    rw("current_max_y_t6", 0x3013, "mA", None),
+    rw("current_max_y_t7", 0x3014, "mA", None),
+    rw("current_max_z_t1", 0x3015, "mA", None),
+    rw("current_max_z_t2", 0x3016, "mA", None),
+    rw("current_max_z_t3", 0x3017, "mA", None),
+    rw("current_max_z_t4", 0x3018, "mA", None),
+    rw("current_max_z_t5", 0x3019, "mA", None),
+    rw("current_max_z_t6", 0x301A, "mA", None),
+    rw("current_max_z_t7", 0x301B, "mA", None),
+    rw("hw_config", 0x2800, "", Some((0.0, 1.0))),
+    rw("watchdog_timeout", 0x2801, "s", None),
+    ro("slave_address", 0x4800, "", None),
+    ro("software_version", 0x6800, "", None),
+];
+
+/// Read a parameter's current value. Answered with the echoed parameter ID and its value
+pub struct GetParam {
+    /// ID of the parameter
+    pub id: u16,
+}
+
+impl Command for GetParam {
+    const CODE: u8 = GET_PARAM;
+    const RESPONSE_LEN: usize = 2 + VALUE_LEN;
+    type Response = (u16, ParamValue);
+
+    fn params(&self, params: &mut ParamWriter) {
+        params.u16(self.id);
+    }
+
+    fn parse(body: &mut ResponseReader) -> AdcsResult<(u16, ParamValue)> {
+        parse_param(body)
+    }
+}
+
+/// Set a parameter. Answered with the echoed parameter ID and the value the iMTQ stored
+pub struct SetParam {
+    /// ID of the parameter
+    pub id: u16,
+    /// New value, which must be of the parameter's type
+    pub value: ParamValue,
+}
+
+impl Command for SetParam {
+    const CODE: u8 = SET_PARAM;
+    const RESPONSE_LEN: usize = 2 + VALUE_LEN;
+    type Response = (u16, ParamValue);
+
+    fn params(&self, params: &mut ParamWriter) {
+        params.u16(self.id);
+        self.value.write(params);
+    }
+
+    fn parse(body: &mut ResponseReader) -> AdcsResult<(u16, ParamValue)> {
+        parse_param(body)
+    }
+}
+
+/// Reset a parameter to its default. Answered with the echoed parameter ID and the default value
+pub struct ResetParam {
+    /// ID of the parameter
+    pub id: u16,
+}
+
+impl Command for ResetParam {
+    const CODE: u8 = RESET_PARAM;
+    const RESPONSE_LEN: usize = 2 + VALUE_LEN;
+    type Response = (u16, ParamValue);
+
+    fn params(&self, params: &mut ParamWriter) {
+        params.u16(self.id);
+    }
+
+    fn parse(body: &mut ResponseReader) -> AdcsResult<(u16, ParamValue)> {
+        parse_param(body)
+    }
+}
+
+// The type of the value is given by the echoed parameter ID
+fn parse_param(body: &mut ResponseReader) -> AdcsResult<(u16, ParamValue)> {
+    let id = body.u16()?;
+    let param_type = ParamType::from_id(id).ok_or(AdcsError::Generic)?;
+    Ok((id, ParamValue::read(param_type, body)?))
+}
+
+// Checks that the iMTQ answered about the parameter which was asked about
+fn check_echo(param: &Param, (id, value): (u16, ParamValue)) -> AdcsResult<ParamValue> {
+    if id != param.id {
+        return Err(AdcsError::Generic);
+    }
+    Ok(value)
+}
+
+impl<T: ImtqFFI> Imtq<T> {
+    /// Reads the current value of a parameter
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the parameter, from [`PARAMS`]
+    pub fn get_param(&self, name: &str) -> AdcsResult<ParamValue> {
+        let param = Param::find(name)?;
+        check_echo(param, self.execute(&GetParam { id: param.id })?)
+    }
+
+    /// Checks a value against the parameter's type and range, then sets the parameter to it.
+    /// Returns the value the iMTQ stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the parameter, from [`PARAMS`]
+    /// * `value` - New value, which is converted to the parameter's type
+    pub fn set_param(&self, name: &str, value: f64) -> AdcsResult<ParamValue> {
+        let param = Param::find(name)?;
+        let value = param.validate(value)?;
+        check_echo(
+            param,
+            self.execute(&SetParam {
+                id: param.id,
+                value,
+            })?,
+        )
+    }
+
+    /// Resets a parameter to its default value, returning the default
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the parameter, from [`PARAMS`]
+    pub fn reset_param(&self, name: &str) -> AdcsResult<ParamValue> {
+        let param = Param::find(name)?;
+        if !param.writable {
+            return Err(AdcsError::Config);
+        }
+        check_echo(param, self.execute(&ResetParam { id: param.id })?)
+    }
+
+    /// Resets every writable parameter to its default value.
+    /// Stops at the first parameter which fails to reset.
+    pub fn reset_all_params(&self) -> AdcsResult<()> {
+        for param in PARAMS.iter().filter(|param| param.writable) {
+            check_echo(param, self.execute(&ResetParam { id: param.id })?)?;
+        }
+        Ok(())
+    }
+
+    /// Reads every parameter, for a snapshot of the iMTQ's configuration. A parameter which
+    /// fails to read doesn't stop the rest from being read.
+    pub fn dump_params(&self) -> Vec<(&'static Param, AdcsResult<ParamValue>)> {
+        PARAMS
+            .iter()
+            .map(|param| {
+                let value = self
+                    .execute(&GetParam { id: param.id })
+                    .and_then(|response| check_echo(param, response));
+                (param, value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Builds a parameter response, header included
+    fn response(code: u8, id: u16, value: &[u8]) -> Vec<u8> {
+        let mut rx = vec![code, 0x80];
+        rx.extend_from_slice(&id.to_le_bytes());
+        rx.extend_from_slice(value);
+        rx.resize(2 + GetParam::RESPONSE_LEN, 0);
+        rx
+    }
+
+    fn parse(rx: &[u8]) -> AdcsResult<(u16, ParamValue)> {
+        GetParam::parse(&mut ResponseReader::new(&rx[2..]))
+    }
+
+    #[test]
+    fn test_table() {
+        let mut names = HashSet::new();
+        let mut ids = HashSet::new();
+        for param in PARAMS {
+            assert!(names.insert(param.name), "Duplicate name {}", param.name);
+            assert!(ids.insert(param.id), "Duplicate ID {:x}", param.id);
+            assert!(ParamType::from_id(param.id).is_some());
+        }
+        assert_eq!(
+            ParamType::F64,
+            Param::find("bdot_gain").unwrap().value_type()
+        );
+        assert_eq!(Err(AdcsError::Config), Param::find("bdot"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let frequency = Param::find("detumble_frequency").unwrap();
+        assert_eq!(Ok(ParamValue::U8(4)), frequency.validate(4.0));
+        assert_eq!(Err(AdcsError::InvalidValue), frequency.validate(9.0));
+        assert_eq!(Err(AdcsError::InvalidValue), frequency.validate(2.5));
+
+        let gain = Param::find("bdot_gain").unwrap();
+        assert_eq!(Ok(ParamValue::F64(-0.5)), gain.validate(-0.5));
+        assert_eq!(Err(AdcsError::InvalidValue), gain.validate(0.5));
+        assert_eq!(Err(AdcsError::InvalidValue), gain.validate(std::f64::NAN));
+
+        // Parameters without their own range are limited by their type
+        let bias = Param::find("adc_coil_current_bias_x").unwrap();
+        assert_eq!(Ok(ParamValue::I16(-300)), bias.validate(-300.0));
+        assert_eq!(Err(AdcsError::InvalidValue), bias.validate(40000.0));
+
+        let version = Param::find("software_version").unwrap();
+        assert_eq!(Err(AdcsError::Config), version.validate(1.0));
+    }
+
+    #[test]
+    fn test_set_params() {
+        let mut params = ParamWriter::default();
+        SetParam {
+            id: 0x2000,
+            value: ParamValue::U8(4),
+        }
+        .params(&mut params);
+
+        let mut expected = ParamWriter::default();
+        expected.u16(0x2000).u8(4);
+        for _ in 1..VALUE_LEN {
+            expected.u8(0);
+        }
+        assert_eq!(expected.buffer, params.buffer);
+    }
+
+    #[test]
+    fn test_parse_param() {
+        let rx = response(GET_PARAM, 0xA000, &(-1.5f64).to_le_bytes());
+        assert_eq!(Ok((0xA000, ParamValue::F64(-1.5))), parse(&rx));
+
+        let rx = response(GET_PARAM, 0x301C, &(-300i16).to_le_bytes());
+        assert_eq!(Ok((0x301C, ParamValue::I16(-300))), parse(&rx));
+
+        let rx = response(GET_PARAM, 0x6800, &0x0001_0203u32.to_le_bytes());
+        assert_eq!(Ok((0x6800, ParamValue::U32(0x0001_0203))), parse(&rx));
+
+        // IDs without a valid type can't be decoded
+        let rx = response(GET_PARAM, 0x0001, &[]);
+        assert_eq!(Err(AdcsError::Generic), parse(&rx));
+    }
+
+    #[test]
+    fn test_check_echo() {
+        let param = Param::find("mtm_select").unwrap();
+        assert_eq!(
+            Ok(ParamValue::U8(1)),
+            check_echo(param, (0x2002, ParamValue::U8(1)))
+        );
+        assert_eq!(
+            Err(AdcsError::Generic),
+            check_echo(param, (0x2003, ParamValue::U8(1)))
+        );
+    }
+}
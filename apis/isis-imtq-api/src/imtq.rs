@@ -100,21 +100,37 @@ impl<T: ImtqFFI> Imtq<T> {
         delay_secs: i32,
         delay_nsecs: i64,
     ) -> AdcsResult<Vec<u8>> {
+        let (status, rx_buffer) = self.transfer(command, rx_len, delay_secs, delay_nsecs);
+
+        adcs_status_to_err(&status)?;
+
+        Ok(rx_buffer)
+    }
+
+    /// Sends a command and returns the raw transfer status along with whatever was read back,
+    /// so that callers can inspect the response header even when the transfer failed
+    pub(crate) fn transfer(
+        &self,
+        command: &[u8],
+        rx_len: i32,
+        delay_secs: i32,
+        delay_nsecs: i64,
+    ) -> (KADCSStatus, Vec<u8>) {
         let mut rx_buffer = vec![0; rx_len as usize];
         let tspec = timespec {
             tv_sec: delay_secs,
             tv_nsec: delay_nsecs,
         };
 
-        adcs_status_to_err(&self.handle.k_adcs_passthrough(
+        let status = self.handle.k_adcs_passthrough(
             command.as_ptr(),
             command.len() as i32,
             rx_buffer.as_mut_ptr(),
             rx_len,
             &tspec,
-        ))?;
+        );
 
-        Ok(rx_buffer)
+        (status, rx_buffer)
     }
 
     /// Reboots the iMTQ.
@@ -157,7 +173,12 @@ impl<T: ImtqFFI> Drop for Imtq<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::command::{self, RetryPolicy};
+    use crate::config::ParamValue;
     use double::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     mock_trait!(
         MockImtq,
@@ -242,6 +263,175 @@ mod tests {
         assert_eq!(mock_result, result.unwrap());
     }
 
+    #[test]
+    fn test_execute() {
+        let mock = MockImtq::default();
+        mock.k_adcs_passthrough.use_closure(Box::new(
+            |(tx, tx_len, rx, rx_len, _delay): (
+                *const u8,
+                i32,
+                *mut u8,
+                i32,
+                *const timespec,
+            )| {
+                assert_eq!(1, tx_len);
+                assert_eq!(9, rx_len);
+                let response = [0x41, 0x80, 0x01, 0x00, 0x00, 0x3C, 0x00, 0x00, 0x00];
+                unsafe {
+                    assert_eq!(0x41, *tx);
+                    std::ptr::copy_nonoverlapping(response.as_ptr(), rx, response.len());
+                }
+                KADCSStatus::Ok
+            },
+        ));
+        let imtq = Imtq::new(&mock, "/dev/i2c-0", 0x40, 60).unwrap();
+
+        let state = imtq.execute(&command::GetSystemState).unwrap();
+        assert_eq!(1, state.mode);
+        assert_eq!(60, state.uptime);
+    }
+
+    #[test]
+    fn test_execute_params() {
+        let mock = MockImtq::default();
+        mock.k_adcs_passthrough.use_closure(Box::new(
+            |(tx, tx_len, rx, _rx_len, _delay): (
+                *const u8,
+                i32,
+                *mut u8,
+                i32,
+                *const timespec,
+            )| {
+                let sent = unsafe { std::slice::from_raw_parts(tx, tx_len as usize) };
+                assert_eq!(&[0x06, 0x0A, 0x00, 0x00, 0x00, 0xF6, 0xFF, 0xE8, 0x03], sent);
+                unsafe {
+                    *rx = 0x06;
+                    *rx.offset(1) = 0x80;
+                }
+                KADCSStatus::Ok
+            },
+        ));
+        let imtq = Imtq::new(&mock, "/dev/i2c-0", 0x40, 60).unwrap();
+
+        let cmd = command::StartActuationDipole {
+            x: 10,
+            y: 0,
+            z: -10,
+            duration: 1000,
+        };
+        assert_eq!(Ok(()), imtq.execute(&cmd));
+    }
+
+    #[test]
+    fn test_execute_status_error() {
+        let mock = MockImtq::default();
+        mock.k_adcs_passthrough.use_closure(Box::new(
+            |(_tx, _tx_len, rx, _rx_len, _delay): (
+                *const u8,
+                i32,
+                *mut u8,
+                i32,
+                *const timespec,
+            )| {
+                unsafe {
+                    *rx = 0x02;
+                    *rx.offset(1) = 0x85;
+                }
+                KADCSStatus::ErrorInternal
+            },
+        ));
+        let imtq = Imtq::new(&mock, "/dev/i2c-0", 0x40, 60).unwrap();
+
+        assert_eq!(Err(AdcsError::WrongMode), imtq.execute(&command::NoOp));
+        // Errors reported by the iMTQ aren't retried
+        assert_eq!(1, mock.k_adcs_passthrough.num_calls());
+    }
+
+    #[test]
+    fn test_execute_retry() {
+        let mock = MockImtq::default();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_c = attempts.clone();
+        mock.k_adcs_passthrough.use_closure(Box::new(
+            move |(_tx, _tx_len, rx, _rx_len, _delay): (
+                *const u8,
+                i32,
+                *mut u8,
+                i32,
+                *const timespec,
+            )| {
+                // NAK the first two transfers
+                if attempts_c.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return KADCSStatus::Error;
+                }
+                unsafe {
+                    *rx = 0x02;
+                    *rx.offset(1) = 0x80;
+                }
+                KADCSStatus::Ok
+            },
+        ));
+        let imtq = Imtq::new(&mock, "/dev/i2c-0", 0x40, 60).unwrap();
+
+        assert_eq!(Ok(()), imtq.execute(&command::NoOp));
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_execute_retry_exhausted() {
+        let mock = MockImtq::default();
+        mock.k_adcs_passthrough
+            .return_value(KADCSStatus::ErrorNoResponse);
+        let imtq = Imtq::new(&mock, "/dev/i2c-0", 0x40, 60).unwrap();
+
+        let retry = RetryPolicy {
+            retries: 2,
+            interval: Duration::from_millis(1),
+        };
+        assert_eq!(
+            Err(AdcsError::NoResponse),
+            imtq.execute_with_retry(&command::NoOp, &retry)
+        );
+        assert_eq!(3, mock.k_adcs_passthrough.num_calls());
+    }
+
+    #[test]
+    fn test_set_param() {
+        let mock = MockImtq::default();
+        mock.k_adcs_passthrough.use_closure(Box::new(
+            |(tx, tx_len, rx, rx_len, _delay): (
+                *const u8,
+                i32,
+                *mut u8,
+                i32,
+                *const timespec,
+            )| {
+                let sent = unsafe { std::slice::from_raw_parts(tx, tx_len as usize) };
+                assert_eq!(&[0x82, 0x00, 0x20, 0x04, 0, 0, 0, 0, 0, 0, 0], sent);
+                assert_eq!(12, rx_len);
+                let response = [0x82, 0x80, 0x00, 0x20, 0x04];
+                unsafe {
+                    std::ptr::copy_nonoverlapping(response.as_ptr(), rx, response.len());
+                }
+                KADCSStatus::Ok
+            },
+        ));
+        let imtq = Imtq::new(&mock, "/dev/i2c-0", 0x40, 60).unwrap();
+
+        assert_eq!(
+            Ok(ParamValue::U8(4)),
+            imtq.set_param("detumble_frequency", 4.0)
+        );
+
+        // Invalid values are never sent
+        assert_eq!(
+            Err(AdcsError::InvalidValue),
+            imtq.set_param("detumble_frequency", 16.0)
+        );
+        assert_eq!(Err(AdcsError::Config), imtq.set_param("unknown", 1.0));
+        assert_eq!(1, mock.k_adcs_passthrough.num_calls());
+    }
+
     #[test]
     fn test_reset() {
         let mock = MockImtq::default();
@@ -19,7 +19,16 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
+pub mod command;
+pub mod config;
 mod ffi;
 mod imtq;
+pub mod selftest;
 
+pub use crate::command::{Command, ParamWriter, ResponseReader, RetryPolicy, Status};
+pub use crate::config::{Param, ParamType, ParamValue, PARAMS};
 pub use crate::imtq::Imtq;
+pub use crate::selftest::{
+    AxisValues, SelfTestCheck, SelfTestLimits, SelfTestReport, SelfTestResults, StepResult,
+    TestAxis, TestErrors, TestStep,
+};
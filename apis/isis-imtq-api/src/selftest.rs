@@ -0,0 +1,702 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! iMTQ self-test
+//!
+//! A self-test actuates each requested coil direction in turn, measuring the magnetic field,
+//! coil currents and coil temperatures before, during and after each actuation. The iMTQ
+//! returns one result for each of these steps: three for a single-axis test and eight for an
+//! all-axes test. Each step result is a full response of its own (command code and status
+//! byte included), and the results are read back together with a single command.
+//!
+//! The iMTQ sets error flags for any step where something was obviously wrong, but its own
+//! range checks are coarse. [`SelfTestResults::evaluate`] compares the measurements against
+//! [`SelfTestLimits`] as well, to give a pass/fail breakdown which can be reported to the
+//! ground without having to interpret raw numbers.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate adcs_api;
+//! extern crate isis_imtq_api;
+//! use adcs_api::*;
+//! use isis_imtq_api::*;
+//!
+//! # fn main() { func(); }
+//!
+//! # fn func() -> AdcsResult<()> {
+//! let imtq = Imtq::imtq("/dev/i2c-0", 0x40, 60)?;
+//! let results = imtq.run_self_test(TestAxis::All)?;
+//! let report = results.evaluate(&SelfTestLimits::default());
+//! for check in report.checks.iter().filter(|check| !check.passed) {
+//!     println!("{:?} {}: {}", check.step, check.name, check.detail);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::command::{Command, ParamWriter, ResponseReader, Status, RESPONSE_HEADER_LEN};
+use crate::ffi::ImtqFFI;
+use crate::imtq::Imtq;
+use adcs_api::*;
+#[cfg(feature = "graphql")]
+use juniper::{GraphQLEnum, GraphQLObject};
+use std::thread;
+use std::time::Duration;
+
+// Command code for reading back the self-test results
+const GET_TEST: u8 = 0x47;
+
+// Length of a single step result, excluding its response header
+const STEP_LEN: usize = 38;
+
+// Time the iMTQ needs to finish a self-test before its results can be read
+const TEST_DURATION: Duration = Duration::from_millis(1300);
+
+// Self-test error byte flags
+const TEST_ERROR_I2C: u8 = 0x01;
+const TEST_ERROR_SPI: u8 = 0x02;
+const TEST_ERROR_ADC: u8 = 0x04;
+const TEST_ERROR_PWM: u8 = 0x08;
+const TEST_ERROR_TC: u8 = 0x10;
+const TEST_ERROR_MTM: u8 = 0x20;
+const TEST_ERROR_COIL: u8 = 0x40;
+
+/// Coil direction(s) to actuate during a self-test
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLEnum))]
+pub enum TestAxis {
+    /// Every direction of every axis, one after the other
+    All,
+    /// Positive X-axis
+    XPos,
+    /// Negative X-axis
+    XNeg,
+    /// Positive Y-axis
+    YPos,
+    /// Negative Y-axis
+    YNeg,
+    /// Positive Z-axis
+    ZPos,
+    /// Negative Z-axis
+    ZNeg,
+}
+
+impl TestAxis {
+    fn code(self) -> u8 {
+        match self {
+            TestAxis::All => 0x00,
+            TestAxis::XPos => 0x01,
+            TestAxis::XNeg => 0x02,
+            TestAxis::YPos => 0x03,
+            TestAxis::YNeg => 0x04,
+            TestAxis::ZPos => 0x05,
+            TestAxis::ZNeg => 0x06,
+        }
+    }
+}
+
+/// Stage of a self-test which a step result was measured during
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLEnum))]
+pub enum TestStep {
+    /// Before any coil was actuated
+    Init,
+    /// While actuating the positive X-axis
+    XPos,
+    /// While actuating the negative X-axis
+    XNeg,
+    /// While actuating the positive Y-axis
+    YPos,
+    /// While actuating the negative Y-axis
+    YNeg,
+    /// While actuating the positive Z-axis
+    ZPos,
+    /// While actuating the negative Z-axis
+    ZNeg,
+    /// After all of the actuations had finished
+    Final,
+}
+
+impl TestStep {
+    fn parse(byte: u8) -> AdcsResult<Self> {
+        match byte {
+            0x00 => Ok(TestStep::Init),
+            0x01 => Ok(TestStep::XPos),
+            0x02 => Ok(TestStep::XNeg),
+            0x03 => Ok(TestStep::YPos),
+            0x04 => Ok(TestStep::YNeg),
+            0x05 => Ok(TestStep::ZPos),
+            0x06 => Ok(TestStep::ZNeg),
+            0x07 => Ok(TestStep::Final),
+            _ => Err(AdcsError::Generic),
+        }
+    }
+
+    /// The axis (0 = X, 1 = Y, 2 = Z) actuated during this step, and whether it was
+    /// actuated in the positive direction. `None` for the steps without any actuation
+    pub fn actuation(self) -> Option<(usize, bool)> {
+        match self {
+            TestStep::XPos => Some((0, true)),
+            TestStep::XNeg => Some((0, false)),
+            TestStep::YPos => Some((1, true)),
+            TestStep::YNeg => Some((1, false)),
+            TestStep::ZPos => Some((2, true)),
+            TestStep::ZNeg => Some((2, false)),
+            TestStep::Init | TestStep::Final => None,
+        }
+    }
+}
+
+/// Error flags the iMTQ raised for a self-test step
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct TestErrors {
+    /// I<sup>2</sup>C failure
+    pub i2c: bool,
+    /// SPI failure (magnetometer connectivity)
+    pub spi: bool,
+    /// ADC failure (current or temperature measurement)
+    pub adc: bool,
+    /// PWM failure (coil actuation)
+    pub pwm: bool,
+    /// System failure
+    pub system: bool,
+    /// Magnetometer values outside of the expected range
+    pub mtm: bool,
+    /// Coil currents outside of the expected range
+    pub coil: bool,
+}
+
+impl TestErrors {
+    /// Decodes a raw error byte
+    pub fn parse(byte: u8) -> Self {
+        TestErrors {
+            i2c: byte & TEST_ERROR_I2C != 0,
+            spi: byte & TEST_ERROR_SPI != 0,
+            adc: byte & TEST_ERROR_ADC != 0,
+            pwm: byte & TEST_ERROR_PWM != 0,
+            system: byte & TEST_ERROR_TC != 0,
+            mtm: byte & TEST_ERROR_MTM != 0,
+            coil: byte & TEST_ERROR_COIL != 0,
+        }
+    }
+
+    /// Names of the flags which are set
+    pub fn names(&self) -> Vec<&'static str> {
+        let flags = [
+            (self.i2c, "I2C"),
+            (self.spi, "SPI"),
+            (self.adc, "ADC"),
+            (self.pwm, "PWM"),
+            (self.system, "system"),
+            (self.mtm, "MTM range"),
+            (self.coil, "coil current range"),
+        ];
+        flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Whether any of the flags are set
+    pub fn any(&self) -> bool {
+        !self.names().is_empty()
+    }
+}
+
+/// A measurement for each axis
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct AxisValues {
+    /// X-axis
+    pub x: i32,
+    /// Y-axis
+    pub y: i32,
+    /// Z-axis
+    pub z: i32,
+}
+
+impl AxisValues {
+    /// Value for the given axis (0 = X, 1 = Y, 2 = Z)
+    pub fn get(&self, axis: usize) -> i32 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z,
+        }
+    }
+
+    fn parse_i32(body: &mut ResponseReader) -> AdcsResult<Self> {
+        Ok(AxisValues {
+            x: body.i32()?,
+            y: body.i32()?,
+            z: body.i32()?,
+        })
+    }
+
+    fn parse_i16(body: &mut ResponseReader) -> AdcsResult<Self> {
+        Ok(AxisValues {
+            x: i32::from(body.i16()?),
+            y: i32::from(body.i16()?),
+            z: i32::from(body.i16()?),
+        })
+    }
+}
+
+/// Measurements taken during a single self-test step
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct StepResult {
+    /// Stage of the self-test
+    pub step: TestStep,
+    /// Error flags raised by the iMTQ
+    pub errors: TestErrors,
+    /// Raw magnetometer measurement, in 7.5 * 10<sup>-9</sup> T per count
+    pub mtm_raw: AxisValues,
+    /// Calibrated magnetometer measurement, in 10<sup>-9</sup> T
+    pub mtm_calibrated: AxisValues,
+    /// Coil currents, in 10<sup>-4</sup> A
+    pub coil_current: AxisValues,
+    /// Coil temperatures, in degrees Celsius
+    pub coil_temperature: AxisValues,
+}
+
+impl StepResult {
+    fn parse(body: &mut ResponseReader) -> AdcsResult<Self> {
+        let errors = TestErrors::parse(body.u8()?);
+        Ok(StepResult {
+            step: TestStep::parse(body.u8()?)?,
+            errors,
+            mtm_raw: AxisValues::parse_i32(body)?,
+            mtm_calibrated: AxisValues::parse_i32(body)?,
+            coil_current: AxisValues::parse_i16(body)?,
+            coil_temperature: AxisValues::parse_i16(body)?,
+        })
+    }
+}
+
+/// Results of a self-test, one for each step in the order they were measured
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct SelfTestResults {
+    /// Step results
+    pub steps: Vec<StepResult>,
+}
+
+impl SelfTestResults {
+    // The first step's header has already been checked by `Imtq::execute`. The rest are
+    // embedded in the body and have to be checked here
+    fn parse(body: &mut ResponseReader, count: usize) -> AdcsResult<Self> {
+        let mut steps = vec![StepResult::parse(body)?];
+        for _ in 1..count {
+            if body.u8()? != GET_TEST {
+                return Err(AdcsError::Generic);
+            }
+            Status::parse(body.u8()?).check()?;
+            steps.push(StepResult::parse(body)?);
+        }
+        Ok(SelfTestResults { steps })
+    }
+
+    /// Result of the given step, if it was part of the test
+    pub fn step(&self, step: TestStep) -> Option<&StepResult> {
+        self.steps.iter().find(|result| result.step == step)
+    }
+
+    /// Compares the measurements against the expected ranges
+    pub fn evaluate(&self, limits: &SelfTestLimits) -> SelfTestReport {
+        let mut checks = vec![];
+        let init = self.step(TestStep::Init);
+
+        for result in &self.steps {
+            let errors = result.errors.names();
+            checks.push(SelfTestCheck::new(
+                result.step,
+                "error flags",
+                errors.is_empty(),
+                if errors.is_empty() {
+                    "none".to_owned()
+                } else {
+                    errors.join(", ")
+                },
+            ));
+
+            let temps = &result.coil_temperature;
+            let out_of_range: Vec<String> = (0..3)
+                .filter(|axis| {
+                    let temp = temps.get(*axis);
+                    temp < limits.min_coil_temperature || temp > limits.max_coil_temperature
+                })
+                .map(|axis| format!("{} {}C", AXIS_NAMES[axis], temps.get(axis)))
+                .collect();
+            checks.push(SelfTestCheck::new(
+                result.step,
+                "coil temperature",
+                out_of_range.is_empty(),
+                if out_of_range.is_empty() {
+                    format!("{}C, {}C, {}C", temps.x, temps.y, temps.z)
+                } else {
+                    format!(
+                        "{} outside {}C to {}C",
+                        out_of_range.join(", "),
+                        limits.min_coil_temperature,
+                        limits.max_coil_temperature
+                    )
+                },
+            ));
+
+            checks.extend(check_currents(result, limits));
+
+            if let (Some((axis, _)), Some(init)) = (result.step.actuation(), init) {
+                let change =
+                    (result.mtm_calibrated.get(axis) - init.mtm_calibrated.get(axis)).abs();
+                checks.push(SelfTestCheck::new(
+                    result.step,
+                    &format!("{} field change", AXIS_NAMES[axis]),
+                    change >= limits.min_field_change,
+                    format!(
+                        "{} nT (at least {} nT expected)",
+                        change, limits.min_field_change
+                    ),
+                ));
+            }
+        }
+
+        SelfTestReport {
+            passed: !self.steps.is_empty() && checks.iter().all(|check| check.passed),
+            checks,
+        }
+    }
+}
+
+const AXIS_NAMES: [&str; 3] = ["X", "Y", "Z"];
+
+// Checks that only the coil being actuated is drawing current, and that it's drawing a
+// sensible amount in the right direction
+fn check_currents(result: &StepResult, limits: &SelfTestLimits) -> Vec<SelfTestCheck> {
+    let actuation = result.step.actuation();
+    (0..3)
+        .map(|axis| {
+            let current = result.coil_current.get(axis);
+            let name = format!("{} coil current", AXIS_NAMES[axis]);
+            match actuation {
+                Some((actuated, positive)) if actuated == axis => {
+                    let direction = if positive { 1 } else { -1 };
+                    let passed = current * direction >= limits.min_coil_current
+                        && current * direction <= limits.max_coil_current;
+                    SelfTestCheck::new(
+                        result.step,
+                        &name,
+                        passed,
+                        format!(
+                            "{} (expected {} to {})",
+                            current,
+                            limits.min_coil_current * direction,
+                            limits.max_coil_current * direction
+                        ),
+                    )
+                }
+                _ => SelfTestCheck::new(
+                    result.step,
+                    &name,
+                    current.abs() <= limits.max_idle_current,
+                    format!(
+                        "{} (idle, at most {} expected)",
+                        current, limits.max_idle_current
+                    ),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Ranges which self-test measurements are expected to fall within
+///
+/// The defaults are deliberately loose. Missions should tighten them using the results of
+/// self-tests run on the ground with their own unit.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct SelfTestLimits {
+    /// Smallest current expected in an actuated coil, in 10<sup>-4</sup> A
+    pub min_coil_current: i32,
+    /// Largest current expected in an actuated coil, in 10<sup>-4</sup> A
+    pub max_coil_current: i32,
+    /// Largest current expected in a coil which isn't being actuated, in 10<sup>-4</sup> A
+    pub max_idle_current: i32,
+    /// Smallest change in the measured field along an actuated axis, compared to the `Init`
+    /// step, in 10<sup>-9</sup> T
+    pub min_field_change: i32,
+    /// Lowest coil temperature expected, in degrees Celsius
+    pub min_coil_temperature: i32,
+    /// Highest coil temperature expected, in degrees Celsius
+    pub max_coil_temperature: i32,
+}
+
+impl Default for SelfTestLimits {
+    fn default() -> Self {
+        SelfTestLimits {
+            min_coil_current: 500,
+            max_coil_current: 2500,
+            max_idle_current: 100,
+            min_field_change: 1000,
+            min_coil_temperature: -40,
+            max_coil_temperature: 70,
+        }
+    }
+}
+
+/// Outcome of a single self-test check
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct SelfTestCheck {
+    /// Step the check was made against
+    pub step: TestStep,
+    /// What was checked, eg. "X coil current"
+    pub name: String,
+    /// Whether the measurement was as expected
+    pub passed: bool,
+    /// The measurement, and what was expected
+    pub detail: String,
+}
+
+impl SelfTestCheck {
+    fn new(step: TestStep, name: &str, passed: bool, detail: String) -> Self {
+        SelfTestCheck {
+            step,
+            name: name.to_owned(),
+            passed,
+            detail,
+        }
+    }
+}
+
+/// Pass/fail breakdown of a self-test
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct SelfTestReport {
+    /// Whether every check passed
+    pub passed: bool,
+    /// Every check made, in step order
+    pub checks: Vec<SelfTestCheck>,
+}
+
+/// Start a self-test of the given coil direction(s)
+pub struct StartTest {
+    /// Direction(s) to test
+    pub axis: TestAxis,
+}
+
+impl Command for StartTest {
+    const CODE: u8 = 0x08;
+    const RESPONSE_LEN: usize = 0;
+    type Response = ();
+
+    fn params(&self, params: &mut ParamWriter) {
+        params.u8(self.axis.code());
+    }
+
+    fn parse(_body: &mut ResponseReader) -> AdcsResult<()> {
+        Ok(())
+    }
+}
+
+/// Get the results of the last single-axis self-test
+pub struct GetTestResultsSingle;
+
+impl Command for GetTestResultsSingle {
+    const CODE: u8 = GET_TEST;
+    const RESPONSE_LEN: usize = STEP_LEN + 2 * (RESPONSE_HEADER_LEN + STEP_LEN);
+    type Response = SelfTestResults;
+
+    fn parse(body: &mut ResponseReader) -> AdcsResult<SelfTestResults> {
+        SelfTestResults::parse(body, 3)
+    }
+}
+
+/// Get the results of the last all-axes self-test
+pub struct GetTestResultsAll;
+
+impl Command for GetTestResultsAll {
+    const CODE: u8 = GET_TEST;
+    const RESPONSE_LEN: usize = STEP_LEN + 7 * (RESPONSE_HEADER_LEN + STEP_LEN);
+    type Response = SelfTestResults;
+
+    fn parse(body: &mut ResponseReader) -> AdcsResult<SelfTestResults> {
+        SelfTestResults::parse(body, 8)
+    }
+}
+
+impl<T: ImtqFFI> Imtq<T> {
+    /// Runs a self-test and reads back its results.
+    /// Blocks for a little over a second while the test runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `axis` - Coil direction(s) to test
+    pub fn run_self_test(&self, axis: TestAxis) -> AdcsResult<SelfTestResults> {
+        self.execute(&StartTest { axis })?;
+        thread::sleep(TEST_DURATION);
+
+        match axis {
+            TestAxis::All => self.execute(&GetTestResultsAll),
+            _ => self.execute(&GetTestResultsSingle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a step result, header included
+    fn step(step: u8, error: u8, calib_x: i32, current: [i16; 3]) -> Vec<u8> {
+        let mut data = vec![GET_TEST, 0x80, error, step];
+        for value in &[10, 20, 30, calib_x, -2000, 40000] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in current.iter().chain(&[21, 22, 23]) {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data
+    }
+
+    fn single_axis(error: u8, current: i16) -> Vec<u8> {
+        let mut rx = step(0x00, 0, 15000, [0, 0, 0]);
+        rx.extend(step(0x01, error, 18000, [current, 2, -3]));
+        rx.extend(step(0x07, 0, 15010, [1, 0, 0]));
+        rx
+    }
+
+    fn parse(rx: &[u8]) -> AdcsResult<SelfTestResults> {
+        GetTestResultsSingle::parse(&mut ResponseReader::new(&rx[RESPONSE_HEADER_LEN..]))
+    }
+
+    #[test]
+    fn test_step_len() {
+        assert_eq!(
+            RESPONSE_HEADER_LEN + STEP_LEN,
+            step(0, 0, 0, [0, 0, 0]).len()
+        );
+    }
+
+    #[test]
+    fn test_parse_single() {
+        let results = parse(&single_axis(0, 1200)).unwrap();
+        assert_eq!(3, results.steps.len());
+
+        let actuated = results.step(TestStep::XPos).unwrap();
+        assert_eq!(TestErrors::default(), actuated.errors);
+        assert_eq!(
+            AxisValues {
+                x: 10,
+                y: 20,
+                z: 30
+            },
+            actuated.mtm_raw
+        );
+        assert_eq!(
+            AxisValues {
+                x: 18000,
+                y: -2000,
+                z: 40000
+            },
+            actuated.mtm_calibrated
+        );
+        assert_eq!(
+            AxisValues {
+                x: 1200,
+                y: 2,
+                z: -3
+            },
+            actuated.coil_current
+        );
+        assert_eq!(
+            AxisValues {
+                x: 21,
+                y: 22,
+                z: 23
+            },
+            actuated.coil_temperature
+        );
+        assert_eq!(TestStep::Final, results.steps[2].step);
+    }
+
+    #[test]
+    fn test_parse_embedded_header() {
+        let mut rx = single_axis(0, 1200);
+        // Corrupt the command code of the second step
+        rx[RESPONSE_HEADER_LEN + STEP_LEN] = 0x41;
+        assert_eq!(Err(AdcsError::Generic), parse(&rx));
+    }
+
+    #[test]
+    fn test_error_flags() {
+        let errors = TestErrors::parse(0x61);
+        assert!(errors.any());
+        assert_eq!(
+            vec!["I2C", "MTM range", "coil current range"],
+            errors.names()
+        );
+        assert!(!TestErrors::parse(0x00).any());
+    }
+
+    #[test]
+    fn test_evaluate_pass() {
+        let report = parse(&single_axis(0, 1200))
+            .unwrap()
+            .evaluate(&SelfTestLimits::default());
+        assert!(report.passed);
+        assert!(report
+            .checks
+            .iter()
+            .any(|check| check.step == TestStep::XPos && check.name == "X field change"));
+    }
+
+    #[test]
+    fn test_evaluate_failures() {
+        // Flagged by the iMTQ, and drawing current in the wrong direction
+        let report = parse(&single_axis(TEST_ERROR_PWM, -1200))
+            .unwrap()
+            .evaluate(&SelfTestLimits::default());
+        assert!(!report.passed);
+
+        let failed: Vec<(TestStep, &str)> = report
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| (check.step, check.name.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (TestStep::XPos, "error flags"),
+                (TestStep::XPos, "X coil current")
+            ],
+            failed
+        );
+    }
+
+    #[test]
+    fn test_evaluate_field_change() {
+        let limits = SelfTestLimits {
+            min_field_change: 5000,
+            ..Default::default()
+        };
+        let report = parse(&single_axis(0, 1200)).unwrap().evaluate(&limits);
+        assert!(!report.passed);
+    }
+}
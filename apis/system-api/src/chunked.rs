@@ -0,0 +1,165 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//!
+//! Responses split across several UDP datagrams
+//!
+//! A service's response to a GraphQL request sent over UDP normally has to fit in a single
+//! datagram. A request may instead ask for its response to be split into chunks, with a comment
+//! line holding the size of the largest datagram the requester wants to receive:
+//!
+//! ```text
+//! # chunked: 1024
+//! { telemetry { timestamp, subsystem, parameter, value } }
+//! ```
+//!
+//! The comment may come before or after a `# trace-id` line. Since it's a comment, services
+//! which don't know about chunked responses still answer the request, in a single datagram.
+//!
+//! Each datagram of a chunked response starts with a five byte header: a `0xff` marker, then the
+//! datagram's sequence number (counting from zero) and the total number of datagrams, as
+//! big-endian `u16`s. The rest of the datagram is the next part of the response. The marker is
+//! never the first byte of a CBOR response, so [`receive`](fn.receive.html) can tell a single
+//! datagram answer from a service which ignored the request for chunks.
+//!
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Start of the comment line which asks for a chunked response
+pub const CHUNKED_PREFIX: &str = "# chunked: ";
+/// Size of the header at the start of each datagram of a chunked response
+pub const HEADER_SIZE: usize = 5;
+/// Smallest datagram size a response can be chunked into. Smaller requested sizes are raised to it
+pub const MIN_DATAGRAM_SIZE: usize = 64;
+/// Largest datagram size a response can be chunked into, the most a UDP datagram can carry
+/// over IPv4. Larger requested sizes are lowered to it
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// First byte of each datagram of a chunked response. Never the first byte of a CBOR value
+const MARKER: u8 = 0xff;
+
+/// Gets the datagram size a request asked for its response to be chunked into, if it did
+pub fn datagram_size(request: &str) -> Option<usize> {
+    request
+        .lines()
+        .take_while(|line| line.starts_with('#'))
+        .filter(|line| line.starts_with(CHUNKED_PREFIX))
+        .filter_map(|line| line[CHUNKED_PREFIX.len()..].trim().parse::<usize>().ok())
+        .next()
+        .map(|size| size.max(MIN_DATAGRAM_SIZE).min(MAX_DATAGRAM_SIZE))
+}
+
+/// Asks for a request's response to be chunked into datagrams of the given size,
+/// unless it already asks for a chunked response
+pub fn stamp(request: &str, datagram_size: usize) -> String {
+    if self::datagram_size(request).is_some() {
+        return request.to_owned();
+    }
+
+    format!("{}{}\n{}", CHUNKED_PREFIX, datagram_size, request)
+}
+
+/// Splits a response into datagrams of at most the given size, each starting with its header.
+///
+/// Returns `None` if the response would need more datagrams than the header can count
+pub fn split(response: &[u8], datagram_size: usize) -> Option<Vec<Vec<u8>>> {
+    let datagram_size = datagram_size.max(MIN_DATAGRAM_SIZE).min(MAX_DATAGRAM_SIZE);
+    let chunks: Vec<&[u8]> = if response.is_empty() {
+        vec![response]
+    } else {
+        response.chunks(datagram_size - HEADER_SIZE).collect()
+    };
+    if chunks.len() > usize::from(u16::max_value()) {
+        return None;
+    }
+
+    let total = chunks.len() as u16;
+    Some(
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(sequence, chunk)| {
+                let mut datagram = Vec::with_capacity(HEADER_SIZE + chunk.len());
+                datagram.push(MARKER);
+                datagram.extend_from_slice(&(sequence as u16).to_be_bytes());
+                datagram.extend_from_slice(&total.to_be_bytes());
+                datagram.extend_from_slice(chunk);
+                datagram
+            })
+            .collect(),
+    )
+}
+
+/// Receives a response on the socket, putting it back together if it was chunked.
+///
+/// Datagrams can arrive in any order, and repeats are ignored. Datagrams from anywhere other than
+/// the sender of the first one are skipped. If any are lost, this waits until the socket's read
+/// timeout runs out, and returns its error.
+pub fn receive(socket: &UdpSocket) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut sender: Option<SocketAddr> = None;
+    let mut chunks: Vec<Option<Vec<u8>>> = vec![];
+    let mut received = HashSet::new();
+
+    loop {
+        let (size, peer) = socket.recv_from(&mut buf)?;
+        if sender.map_or(false, |sender| sender != peer) {
+            continue;
+        }
+        let datagram = &buf[0..size];
+
+        // A service which doesn't chunk its responses answers with a single datagram
+        if sender.is_none() && datagram.first() != Some(&MARKER) {
+            return Ok(datagram.to_vec());
+        }
+        sender = Some(peer);
+
+        let (sequence, total) = header(datagram)?;
+        if chunks.is_empty() {
+            chunks = vec![None; total];
+        } else if chunks.len() != total {
+            return Err(invalid("Chunked response changed its number of datagrams"));
+        }
+        if received.insert(sequence) {
+            chunks[sequence] = Some(datagram[HEADER_SIZE..].to_vec());
+        }
+
+        if received.len() == total {
+            return Ok(chunks.into_iter().flatten().flatten().collect());
+        }
+    }
+}
+
+// Gets the sequence number and total number of datagrams from a datagram's header
+fn header(datagram: &[u8]) -> io::Result<(usize, usize)> {
+    if datagram.len() < HEADER_SIZE || datagram[0] != MARKER {
+        return Err(invalid("Datagram is not part of a chunked response"));
+    }
+
+    let sequence = usize::from(u16::from_be_bytes([datagram[1], datagram[2]]));
+    let total = usize::from(u16::from_be_bytes([datagram[3], datagram[4]]));
+    if sequence >= total {
+        return Err(invalid("Chunked response datagram is out of range"));
+    }
+
+    Ok((sequence, total))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -151,6 +151,34 @@ impl Config {
     }
 }
 
+/// Returns the name and address of every App / Service in the system configuration file,
+/// or the path passed as the '-c' or '--config' option to this executable.
+///
+/// These are the categories which contain an `addr` entry. They are sorted by name.
+pub fn service_addresses() -> Result<Vec<(String, Address)>, Error> {
+    service_addresses_from_str(&get_file_data(get_config_path()?)?)
+}
+
+/// Returns the name and address of every App / Service in the passed in configuration string
+///
+/// # Arguments
+/// `config` - Config data as a string
+pub fn service_addresses_from_str(config: &str) -> Result<Vec<(String, Address)>, Error> {
+    let data: Value = toml::from_str(config)?;
+    let mut addresses = vec![];
+
+    if let Some(categories) = data.as_table() {
+        for (name, category) in categories {
+            if let Some(address) = category.get("addr") {
+                addresses.push((name.to_owned(), address.clone().try_into()?));
+            }
+        }
+    }
+
+    addresses.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(addresses)
+}
+
 fn get_config_path() -> Result<String, Error> {
     // Manually check for a "-c {config-path}" command line argument specifying a custom config
     // file path to use.
@@ -18,8 +18,11 @@
 
 //! KubOS System level APIs
 
+pub mod chunked;
 mod config;
 pub mod logger;
+pub mod rlimit;
+pub mod trace;
 mod uboot;
 
 pub use crate::config::DEFAULT_PATH as DEFAULT_CONFIG_PATH;
@@ -0,0 +1,58 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//!
+//! Resource limits for launched apps
+//!
+//! The services which launch apps (the applications service and the scheduler) cap an app's
+//! memory, CPU time and open files by setting resource limits in the child process, just
+//! before the app is executed:
+//!
+//! ```no_run
+//! use kubos_system::rlimit::set_rlimit;
+//! use std::os::unix::process::CommandExt;
+//! use std::process::Command;
+//!
+//! let mut cmd = Command::new("my-app");
+//! unsafe {
+//!     cmd.pre_exec(|| set_rlimit(libc::RLIMIT_CPU, 60));
+//! }
+//! ```
+//!
+
+use std::io;
+
+/// Type of the resource argument to `setrlimit`, which differs between C libraries
+#[cfg(target_env = "gnu")]
+pub type RlimitResource = libc::__rlimit_resource_t;
+/// Type of the resource argument to `setrlimit`, which differs between C libraries
+#[cfg(not(target_env = "gnu"))]
+pub type RlimitResource = libc::c_int;
+
+/// Sets both the soft and hard limit of a resource of the current process
+///
+/// Only `setrlimit` is called, which is async-signal-safe, so this can be used in a
+/// `pre_exec` closure.
+pub fn set_rlimit(resource: RlimitResource, value: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
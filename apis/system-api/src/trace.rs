@@ -0,0 +1,64 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//!
+//! Trace IDs for correlating a request across services
+//!
+//! A GraphQL request sent to a service over UDP may start with a comment line holding a
+//! trace ID:
+//!
+//! ```text
+//! # trace-id: 1234
+//! { ping }
+//! ```
+//!
+//! Since it's a comment, the request is still valid GraphQL, so services which don't know
+//! about trace IDs answer it as usual. Services built on `kubos-service` include the ID in
+//! their logs, and the communications service stamps the requests it uplinks with their
+//! packet's command ID, so a ground command can be followed through each service it touches.
+//!
+
+/// Start of the comment line which holds a request's trace ID
+pub const TRACE_PREFIX: &str = "# trace-id: ";
+
+/// Gets the trace ID a request was stamped with, if any.
+///
+/// The ID may be on any of the comment lines at the start of the request, so that other
+/// comments (such as a `# chunked` line) can be added before or after it
+pub fn trace_id(request: &str) -> Option<&str> {
+    request
+        .lines()
+        .take_while(|line| line.starts_with('#'))
+        .find(|line| line.starts_with(TRACE_PREFIX))
+        .map(|line| line[TRACE_PREFIX.len()..].trim())
+        .filter(|id| !id.is_empty())
+}
+
+/// Stamps a request with a trace ID, unless it already has one.
+///
+/// Line breaks in the ID are replaced, so that they can't end the comment early
+pub fn stamp(request: &str, id: &str) -> String {
+    if trace_id(request).is_some() {
+        return request.to_owned();
+    }
+
+    format!(
+        "{}{}\n{}",
+        TRACE_PREFIX,
+        id.replace(|c| c == '\r' || c == '\n', " "),
+        request
+    )
+}
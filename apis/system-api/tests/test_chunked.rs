@@ -0,0 +1,120 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#![deny(warnings)]
+
+use kubos_system::chunked;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+#[test]
+fn requested_size() {
+    assert_eq!(chunked::datagram_size("{ ping }"), None);
+    assert_eq!(
+        chunked::datagram_size("# chunked: 1024\n{ ping }"),
+        Some(1024)
+    );
+    assert_eq!(
+        chunked::datagram_size("# trace-id: 12\n# chunked: 512\n{ ping }"),
+        Some(512)
+    );
+    // The size is clamped to what a datagram can carry
+    assert_eq!(chunked::datagram_size("# chunked: 10\n{ ping }"), Some(64));
+    assert_eq!(
+        chunked::datagram_size("# chunked: 100000\n{ ping }"),
+        Some(65_507)
+    );
+    // Only the leading comments count
+    assert_eq!(chunked::datagram_size("{ ping }\n# chunked: 1024"), None);
+    assert_eq!(chunked::datagram_size("# chunked: lots\n{ ping }"), None);
+}
+
+#[test]
+fn stamp_request() {
+    let request = chunked::stamp("{ ping }", 1024);
+    assert_eq!(request, "# chunked: 1024\n{ ping }");
+    assert_eq!(chunked::stamp(&request, 512), request);
+}
+
+#[test]
+fn split_response() {
+    let response: Vec<u8> = (0..200).collect();
+    let datagrams = chunked::split(&response, 100).unwrap();
+    assert_eq!(datagrams.len(), 3);
+    assert!(datagrams.iter().all(|datagram| datagram.len() <= 100));
+    assert_eq!(&datagrams[1][0..5], &[0xff, 0, 1, 0, 3]);
+
+    let joined: Vec<u8> = datagrams
+        .iter()
+        .flat_map(|datagram| datagram[chunked::HEADER_SIZE..].to_vec())
+        .collect();
+    assert_eq!(joined, response);
+
+    // Even an empty response is sent as a datagram
+    assert_eq!(
+        chunked::split(&[], 100).unwrap(),
+        vec![vec![0xff, 0, 0, 0, 1]]
+    );
+
+    // The header can't count this many datagrams
+    assert!(chunked::split(&vec![0; 70_000 * 59], 64).is_none());
+}
+
+#[test]
+fn receive_out_of_order() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = receiver.local_addr().unwrap();
+
+    let response: Vec<u8> = (0..1000).map(|byte| byte as u8).collect();
+    let datagrams = chunked::split(&response, 256).unwrap();
+    for index in &[2, 0, 3, 0, 1] {
+        sender.send_to(&datagrams[*index], addr).unwrap();
+    }
+
+    assert_eq!(chunked::receive(&receiver).unwrap(), response);
+}
+
+#[test]
+fn receive_unchunked() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    sender
+        .send_to(&[0xa1, 0x64], receiver.local_addr().unwrap())
+        .unwrap();
+    assert_eq!(chunked::receive(&receiver).unwrap(), vec![0xa1, 0x64]);
+}
+
+#[test]
+fn receive_lost_datagram() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let datagrams = chunked::split(&[7; 500], 256).unwrap();
+    sender
+        .send_to(&datagrams[0], receiver.local_addr().unwrap())
+        .unwrap();
+    assert!(chunked::receive(&receiver).is_err());
+}
@@ -76,6 +76,38 @@ fn new_from_file() {
     assert_eq!(config.get("root-a"), None);
 }
 
+#[test]
+fn service_addresses_from_str() {
+    let addresses = kubos_system::service_addresses_from_str(
+        r#"
+    [telemetry-service]
+    database = "/var/lib/telemetry.db"
+    [telemetry-service.addr]
+    ip = "127.0.0.1"
+    port = 8006
+    [discovery]
+    ping_timeout = 100
+    [app-service.addr]
+    ip = "0.0.0.0"
+    port = 8000
+    "#,
+    )
+    .unwrap();
+
+    let addresses: Vec<(String, String, u16)> = addresses
+        .iter()
+        .map(|(name, addr)| (name.to_owned(), addr.ip().to_owned(), addr.port()))
+        .collect();
+
+    assert_eq!(
+        addresses,
+        vec![
+            ("app-service".to_owned(), "0.0.0.0".to_owned(), 8000),
+            ("telemetry-service".to_owned(), "127.0.0.1".to_owned(), 8006),
+        ]
+    );
+}
+
 #[test]
 fn missing_port() {
     let result = kubos_system::Config::new_from_str(
@@ -0,0 +1,48 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#![deny(warnings)]
+
+use kubos_system::rlimit::set_rlimit;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+// Runs a shell command with a resource limit set in the child
+fn limited(resource: kubos_system::rlimit::RlimitResource, value: u64, script: &str) -> String {
+    let mut cmd = Command::new("sh");
+    cmd.args(&["-c", script]);
+    unsafe {
+        cmd.pre_exec(move || set_rlimit(resource, value));
+    }
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
+
+#[test]
+fn limit_open_files() {
+    assert_eq!(limited(libc::RLIMIT_NOFILE, 64, "ulimit -n"), "64");
+}
+
+#[test]
+fn limit_cpu_time() {
+    assert_eq!(limited(libc::RLIMIT_CPU, 30, "ulimit -t"), "30");
+}
+
+#[test]
+fn hard_limit_set() {
+    // The app can't raise its own limit back up
+    assert_eq!(limited(libc::RLIMIT_NOFILE, 64, "ulimit -Hn"), "64");
+}
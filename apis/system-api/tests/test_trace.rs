@@ -0,0 +1,64 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+#![deny(warnings)]
+
+use kubos_system::{chunked, trace};
+
+#[test]
+fn no_trace_id() {
+    assert_eq!(trace::trace_id("{ ping }"), None);
+    assert_eq!(trace::trace_id("# a comment\n{ ping }"), None);
+    assert_eq!(trace::trace_id("# trace-id: \n{ ping }"), None);
+}
+
+#[test]
+fn stamp_request() {
+    let request = trace::stamp("{ ping }", "1234");
+    assert_eq!(request, "# trace-id: 1234\n{ ping }");
+    assert_eq!(trace::trace_id(&request), Some("1234"));
+}
+
+#[test]
+fn stamp_keeps_existing_id() {
+    let request = trace::stamp("# trace-id: ground-42\n{ ping }", "1234");
+    assert_eq!(trace::trace_id(&request), Some("ground-42"));
+}
+
+#[test]
+fn stamp_single_line() {
+    let request = trace::stamp("{ ping }", "12\n{ evil }");
+    assert_eq!(request, "# trace-id: 12 { evil }\n{ ping }");
+}
+
+#[test]
+fn trace_id_after_other_comments() {
+    // Chunked after traced
+    let request = chunked::stamp(&trace::stamp("{ ping }", "1234"), 512);
+    assert_eq!(request, "# chunked: 512\n# trace-id: 1234\n{ ping }");
+    assert_eq!(trace::trace_id(&request), Some("1234"));
+    assert_eq!(trace::stamp(&request, "5678"), request);
+
+    // Traced after chunked
+    let request = trace::stamp(&chunked::stamp("{ ping }", 512), "1234");
+    assert_eq!(request, "# trace-id: 1234\n# chunked: 512\n{ ping }");
+    assert_eq!(trace::trace_id(&request), Some("1234"));
+    assert_eq!(chunked::datagram_size(&request), Some(512));
+}
+
+#[test]
+fn trace_id_only_in_leading_comments() {
+    assert_eq!(trace::trace_id("{ ping }\n# trace-id: 1234"), None);
+}
@@ -0,0 +1,199 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Batch transfers
+//
+// A manifest file lists transfers to perform, one per line:
+//
+//     upload <local path> [remote path]
+//     download <remote path> [local path]
+//
+// Blank lines and lines starting with '#' are ignored. The transfers are shared out between
+// `jobs` workers, which run at the same time. Each worker has its own protocol instance, bound
+// to its own local port (host port + worker number), so replies for one transfer are never
+// picked up by another. Every transfer uses a new channel.
+
+use failure::{bail, format_err};
+use file_protocol::FileProtocol;
+use log::{error, info};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Upload,
+    Download,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub operation: Operation,
+    pub source_path: String,
+    pub target_path: String,
+}
+
+// Running totals, reported each time a transfer finishes
+#[derive(Default)]
+struct Progress {
+    done: usize,
+    failed: usize,
+    bytes: u64,
+}
+
+// Default target is the source's file name, in the destination's current directory
+pub fn default_target(source_path: &str) -> String {
+    Path::new(source_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| source_path.to_owned())
+}
+
+pub fn parse_manifest(contents: &str) -> Result<Vec<Transfer>, failure::Error> {
+    let mut transfers = vec![];
+
+    for (num, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let operation = match fields[0] {
+            "upload" => Operation::Upload,
+            "download" => Operation::Download,
+            other => bail!("Line {}: unknown operation '{}'", num + 1, other),
+        };
+
+        let (source_path, target_path) = match fields.len() {
+            2 => (fields[1].to_owned(), default_target(fields[1])),
+            3 => (fields[1].to_owned(), fields[2].to_owned()),
+            _ => bail!(
+                "Line {}: expected '{} <source> [target]'",
+                num + 1,
+                fields[0]
+            ),
+        };
+
+        transfers.push(Transfer {
+            operation,
+            source_path,
+            target_path,
+        });
+    }
+
+    Ok(transfers)
+}
+
+pub fn read_manifest(path: &str) -> Result<Vec<Transfer>, failure::Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format_err!("Failed to read manifest {}: {}", path, err))?;
+    parse_manifest(&contents)
+}
+
+// Runs the transfers with up to `jobs` at once. `new_protocol` is called by each worker, with
+// the worker's number, to create the protocol instance the worker's transfers go through.
+pub fn run<F>(transfers: Vec<Transfer>, jobs: u16, new_protocol: F) -> Result<(), failure::Error>
+where
+    F: Fn(u16) -> FileProtocol + Send + Sync + 'static,
+{
+    let total = transfers.len();
+    if total == 0 {
+        bail!("Manifest contains no transfers");
+    }
+
+    let jobs = std::cmp::max(1, std::cmp::min(jobs as usize, total)) as u16;
+    info!("Starting {} transfers, {} at a time", total, jobs);
+
+    let queue = Arc::new(Mutex::new(transfers.into_iter().collect::<VecDeque<_>>()));
+    let progress = Arc::new(Mutex::new(Progress::default()));
+    let new_protocol = Arc::new(new_protocol);
+
+    let workers: Vec<_> = (0..jobs)
+        .map(|worker| {
+            let queue = queue.clone();
+            let progress = progress.clone();
+            let new_protocol = new_protocol.clone();
+            thread::spawn(move || {
+                let protocol = new_protocol(worker);
+                loop {
+                    let transfer = match queue.lock().unwrap().pop_front() {
+                        Some(transfer) => transfer,
+                        None => return,
+                    };
+
+                    let result = match transfer.operation {
+                        Operation::Upload => crate::upload(
+                            &protocol,
+                            &transfer.source_path,
+                            &transfer.target_path,
+                            None,
+                        ),
+                        Operation::Download => crate::download(
+                            &protocol,
+                            &transfer.source_path,
+                            &transfer.target_path,
+                            0,
+                            None,
+                        ),
+                    };
+
+                    let mut progress = progress.lock().unwrap();
+                    match result {
+                        Ok(()) => {
+                            progress.done += 1;
+                            progress.bytes += transferred_size(&transfer);
+                        }
+                        Err(err) => {
+                            progress.failed += 1;
+                            error!(
+                                "{:?} of {} failed: {}",
+                                transfer.operation, transfer.source_path, err
+                            );
+                        }
+                    }
+                    info!(
+                        "Progress: {}/{} transfers complete, {} failed, {} bytes transferred",
+                        progress.done, total, progress.failed, progress.bytes
+                    );
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        if worker.join().is_err() {
+            bail!("Transfer worker panicked");
+        }
+    }
+
+    let progress = progress.lock().unwrap();
+    if progress.failed > 0 {
+        bail!("{} of {} transfers failed", progress.failed, total);
+    }
+    Ok(())
+}
+
+// Size of the local copy of a finished transfer
+fn transferred_size(transfer: &Transfer) -> u64 {
+    let local = match transfer.operation {
+        Operation::Upload => &transfer.source_path,
+        Operation::Download => &transfer.target_path,
+    };
+    fs::metadata(local).map(|meta| meta.len()).unwrap_or(0)
+}
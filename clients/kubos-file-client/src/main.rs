@@ -14,19 +14,60 @@
 // limitations under the License.
 //
 
+mod batch;
+mod schedule;
+
+use crate::schedule::{ScheduleRequest, When};
 use clap::{App, AppSettings, Arg, SubCommand};
 use failure::bail;
-use file_protocol::{FileProtocol, FileProtocolConfig, State};
-use log::{error, info};
+use file_protocol::{ChannelAllocation, FileProtocol, FileProtocolConfig, ProtocolError, State};
+use log::{error, info, warn};
 use simplelog::*;
+use std::fs::{self, File};
+use std::io;
 use std::path::Path;
+use std::process;
 use std::time::Duration;
 
+// Path standing for stdin as an upload's source, or stdout as a download's target
+const STDIO_PATH: &str = "-";
+
+// Number of times a transfer is restarted on a new channel when the service
+// reports that the channel is already in use by another transfer
+const CHANNEL_RETRIES: usize = 3;
+
+// Runs a transfer on a new channel, picking another channel if the service rejects it
+fn with_channel<F>(protocol_instance: &FileProtocol, transfer: F) -> Result<(), failure::Error>
+where
+    F: Fn(u32) -> Result<(), failure::Error>,
+{
+    let mut retries = 0;
+    loop {
+        let channel = protocol_instance.generate_channel()?;
+        let err = match transfer(channel) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        match err.downcast_ref::<ProtocolError>() {
+            Some(ProtocolError::ChannelInUse { channel_id }) if retries < CHANNEL_RETRIES => {
+                warn!(
+                    "Channel {} is already in use, retrying on a new channel",
+                    channel_id
+                );
+                retries += 1;
+            }
+            _ => return Err(err),
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn upload(
-    protocol_instance: FileProtocol,
+    protocol_instance: &FileProtocol,
     source_path: &str,
     target_path: &str,
+    signature: Option<&[u8]>,
 ) -> Result<(), failure::Error> {
     info!(
         "Uploading local:{} to remote:{}",
@@ -36,66 +77,160 @@ fn upload(
     // Copy file to upload to temp storage. Calculate the hash and chunk info
     let (hash, num_chunks, mode) = protocol_instance.initialize_file(&source_path)?;
 
-    // Generate channel id for transaction
-    let channel = protocol_instance.generate_channel()?;
+    with_channel(protocol_instance, |channel| {
+        // Tell our destination the hash and number of chunks to expect
+        protocol_instance.send_metadata(channel, &hash, num_chunks)?;
 
-    // Tell our destination the hash and number of chunks to expect
-    protocol_instance.send_metadata(channel, &hash, num_chunks)?;
+        std::thread::sleep(Duration::from_millis(200));
 
-    std::thread::sleep(Duration::from_millis(200));
+        // Services which only export signed uploads need the signature before the export command
+        if let Some(signature) = signature {
+            protocol_instance.send_signature(channel, &hash, signature)?;
+        }
 
-    // Send export command for file
-    protocol_instance.send_export(channel, &hash, &target_path, mode)?;
+        // Send export command for file
+        protocol_instance.send_export(channel, &hash, &target_path, mode)?;
 
-    // Start the engine to send the file data chunks
-    protocol_instance.message_engine(
-        |d| protocol_instance.recv(Some(d)),
-        Duration::from_secs(2),
-        &State::Transmitting,
-    )?;
-    Ok(())
+        // Start the engine to send the file data chunks
+        protocol_instance.message_engine(
+            |d| protocol_instance.recv(Some(d)),
+            Duration::from_secs(2),
+            &State::Transmitting,
+        )?;
+        Ok(())
+    })
+}
+
+// Uploads the bytes of a file past `offset`, to be added to the remote target's copy of it
+fn append(
+    protocol_instance: &FileProtocol,
+    source_path: &str,
+    target_path: &str,
+    offset: u64,
+) -> Result<(), failure::Error> {
+    info!(
+        "Appending local:{} from offset {} to remote:{}",
+        source_path, offset, target_path
+    );
+
+    // Only the new bytes are chunked, but the ones before them are hashed so the remote
+    // target can check its copy matches
+    let (hash, num_chunks, prefix_hash) =
+        protocol_instance.initialize_append(&source_path, offset)?;
+
+    with_channel(protocol_instance, |channel| {
+        protocol_instance.send_metadata(channel, &hash, num_chunks)?;
+
+        std::thread::sleep(Duration::from_millis(200));
+
+        protocol_instance.send_append(channel, &hash, &target_path, offset, &prefix_hash)?;
+
+        protocol_instance.message_engine(
+            |d| protocol_instance.recv(Some(d)),
+            Duration::from_secs(2),
+            &State::Transmitting,
+        )?;
+        Ok(())
+    })
 }
 
 fn download(
-    protocol_instance: FileProtocol,
+    protocol_instance: &FileProtocol,
     source_path: &str,
     target_path: &str,
+    offset: u64,
+    length: Option<u64>,
 ) -> Result<(), failure::Error> {
     info!(
         "Downloading remote: {} to local: {}",
         source_path, target_path
     );
+    if offset != 0 || length.is_some() {
+        match length {
+            Some(length) => info!("Requesting {} bytes from offset {}", length, offset),
+            None => info!("Requesting everything from offset {}", offset),
+        }
+    }
 
-    // Generate channel id for transaction
-    let channel = protocol_instance.generate_channel()?;
+    with_channel(protocol_instance, |channel| {
+        // Send our file request to the remote addr and verify that it's
+        // going to be able to send it
+        protocol_instance.send_import_range(channel, source_path, offset, length)?;
 
-    // Send our file request to the remote addr and verify that it's
-    // going to be able to send it
-    protocol_instance.send_import(channel, source_path)?;
-
-    // Wait for the request reply.
-    // (out of date) Note/TODO: We don't use a timeout here because we don't know how long it will
-    // (out of date) take the server to prepare the file we've requested.
-    // (out of date) Larger files (> 100MB) can take over a minute to process.
-    // Set timeout to 10m as this is the duration of a pass, and we want to timeout before the next pass.
-    let reply = match protocol_instance.recv(Some(Duration::from_secs(10 * 60))) {
-        Ok(message) => message,
-        Err(error) => bail!("Failed to import file: {}", error),
-    };
+        // Wait for the request reply.
+        // (out of date) Note/TODO: We don't use a timeout here because we don't know how long it will
+        // (out of date) take the server to prepare the file we've requested.
+        // (out of date) Larger files (> 100MB) can take over a minute to process.
+        // Set timeout to 10m as this is the duration of a pass, and we want to timeout before the next pass.
+        let reply = match protocol_instance.recv(Some(Duration::from_secs(10 * 60))) {
+            Ok(message) => message,
+            Err(error) => bail!("Failed to import file: {}", error),
+        };
 
-    let state = protocol_instance.process_message(
-        reply,
-        &State::StartReceive {
-            path: target_path.to_string(),
-        },
-    )?;
-
-    protocol_instance.message_engine(
-        |d| protocol_instance.recv(Some(d)),
-        Duration::from_secs(2),
-        &state,
-    )?;
-    Ok(())
+        let state = protocol_instance.process_message(
+            reply,
+            &State::StartReceive {
+                path: target_path.to_string(),
+            },
+        )?;
+
+        protocol_instance.message_engine(
+            |d| protocol_instance.recv(Some(d)),
+            Duration::from_secs(2),
+            &state,
+        )?;
+        Ok(())
+    })
+}
+
+// Path of a file in the transfer storage which piped data is spooled through
+fn spool_path(storage_prefix: &str, name: &str) -> Result<String, failure::Error> {
+    fs::create_dir_all(storage_prefix)?;
+    Ok(Path::new(storage_prefix)
+        .join(format!("{}-{}", name, process::id()))
+        .to_string_lossy()
+        .into_owned())
+}
+
+// Uploads whatever is piped into stdin. It's spooled into the transfer storage first,
+// since the whole file has to be hashed before any of it is sent
+fn upload_stdin(
+    protocol_instance: &FileProtocol,
+    storage_prefix: &str,
+    target_path: &str,
+    signature: Option<&[u8]>,
+) -> Result<(), failure::Error> {
+    let spool = spool_path(storage_prefix, "stdin")?;
+    let result = File::create(&spool)
+        .and_then(|mut file| io::copy(&mut io::stdin().lock(), &mut file))
+        .map_err(failure::Error::from)
+        .and_then(|size| {
+            info!("Read {} bytes from stdin", size);
+            upload(protocol_instance, &spool, target_path, signature)
+        });
+
+    let _ = fs::remove_file(&spool);
+    result
+}
+
+// Downloads a file and writes it to stdout. It's spooled into the transfer storage first,
+// since the received file is checked against its hash before it's passed on
+fn download_stdout(
+    protocol_instance: &FileProtocol,
+    storage_prefix: &str,
+    source_path: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<(), failure::Error> {
+    let spool = spool_path(storage_prefix, "stdout")?;
+    let result = download(protocol_instance, source_path, &spool, offset, length).and_then(|()| {
+        let mut file = File::open(&spool)?;
+        io::copy(&mut file, &mut io::stdout().lock())?;
+        Ok(())
+    });
+
+    let _ = fs::remove_file(&spool);
+    result
 }
 
 fn cleanup(protocol_instance: FileProtocol, hash: Option<String>) -> Result<(), failure::Error> {
@@ -114,18 +249,61 @@ fn cleanup(protocol_instance: FileProtocol, hash: Option<String>) -> Result<(),
     Ok(())
 }
 
-fn main() {
-    CombinedLogger::init(vec![
-        TermLogger::new(LevelFilter::Info, Config::default()).unwrap()
-    ])
-    .unwrap();
+fn transfer_log(protocol_instance: FileProtocol, count: u32) -> Result<(), failure::Error> {
+    info!(
+        "Requesting the {} newest transfers from the remote log",
+        count
+    );
 
-    info!("Starting file transfer client");
+    let records = protocol_instance.request_log(count, Duration::from_secs(2))?;
+    for record in records {
+        let result = match record.error {
+            Some(error) => format!("failed: {}", error),
+            None => "ok".to_owned(),
+        };
+        info!(
+            "{} {} {} on channel {} from {}: {} ({} bytes in {}ms)",
+            record.time,
+            record.operation,
+            record.path,
+            record.channel_id,
+            record.peer,
+            result,
+            record.bytes,
+            record.duration_ms
+        );
+    }
 
+    Ok(())
+}
+
+fn main() {
     let args = App::new("File transfer client")
         .subcommand(
             SubCommand::with_name("upload")
                 .about("Initiates upload of local file")
+                .arg(
+                    Arg::with_name("source_path")
+                        .help("Local file path to upload, or - to read it from stdin")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("target_path")
+                        .help("Destination path on remote target (required when uploading stdin)")
+                        .takes_value(true)
+                        .required_if("source_path", STDIO_PATH),
+                )
+                .arg(
+                    Arg::with_name("signature")
+                        .help("File holding the detached Ed25519 signature over the file's hash")
+                        .long("signature")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+                .about("Uploads the new bytes of a growing local file to its remote copy")
                 .arg(
                     Arg::with_name("source_path")
                         .help("Local file path to upload")
@@ -134,8 +312,15 @@ fn main() {
                 )
                 .arg(
                     Arg::with_name("target_path")
-                        .help("Destination path on remote target")
+                        .help("Remote copy of the file, which already holds its start")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .help("Number of bytes of the file the remote copy already holds")
+                        .long("offset")
+                        .takes_value(true)
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -149,10 +334,41 @@ fn main() {
                 )
                 .arg(
                     Arg::with_name("target_path")
-                        .help("Local destination path")
+                        .help("Local destination path, or - to write the file to stdout")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .help("Byte offset within the remote file to start downloading from")
+                        .long("offset")
+                        .takes_value(true)
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("length")
+                        .help("Maximum number of bytes to download (defaults to the rest of the file)")
+                        .long("length")
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Performs the uploads and downloads listed in a manifest file")
+                .arg(
+                    Arg::with_name("manifest")
+                        .help("File listing one 'upload' or 'download' transfer per line")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help("Number of transfers to run at the same time")
+                        .long("jobs")
+                        .short("-j")
+                        .takes_value(true)
+                        .default_value("1"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("cleanup")
                 .about("Requests cleanup of remote temporary storage")
@@ -162,6 +378,18 @@ fn main() {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("Lists the newest transfers handled by the remote target")
+                .arg(
+                    Arg::with_name("count")
+                        .help("Number of transfers to list")
+                        .long("count")
+                        .short("-n")
+                        .takes_value(true)
+                        .default_value("20"),
+                ),
+        )
         .arg(
             Arg::with_name("host_ip")
                 .help("IP address of the local host to use")
@@ -240,11 +468,102 @@ fn main() {
                 .short("-m")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("storage_class")
+                .help("Storage class the file transfer service should keep the transfer's chunks in")
+                .long("storage-class")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("at")
+                .help("Schedule the transfer for a time (yyyy-mm-dd hh:mm:ss) instead of running it now")
+                .long("at")
+                .takes_value(true)
+                .conflicts_with("delay"),
+        )
+        .arg(
+            Arg::with_name("delay")
+                .help("Schedule the transfer after a delay (Xh Ym Zs) instead of running it now")
+                .long("delay")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("schedule_mode")
+                .help("Scheduler mode to add the deferred transfer to")
+                .long("schedule-mode")
+                .takes_value(true)
+                .default_value("nominal"),
+        )
+        .arg(
+            Arg::with_name("task_name")
+                .help("Name of the task list created for the deferred transfer")
+                .long("task-name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scheduler_ip")
+                .help("IP address of the scheduler service")
+                .long("scheduler-ip")
+                .takes_value(true)
+                .default_value("0.0.0.0"),
+        )
+        .arg(
+            Arg::with_name("scheduler_port")
+                .help("UDP port of the scheduler service")
+                .long("scheduler-port")
+                .takes_value(true)
+                .default_value("8010"),
+        )
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::DeriveDisplayOrder)
         .get_matches();
 
+    // Log messages would be mixed into a download written to stdout, so they go to stderr
+    let to_stdout = args
+        .subcommand_matches("download")
+        .and_then(|download_args| download_args.value_of("target_path"))
+        == Some(STDIO_PATH);
+    if to_stdout {
+        WriteLogger::init(LevelFilter::Info, Config::default(), io::stderr()).unwrap();
+    } else {
+        CombinedLogger::init(vec![
+            TermLogger::new(LevelFilter::Info, Config::default()).unwrap()
+        ])
+        .unwrap();
+    }
+
+    info!("Starting file transfer client");
+
     let host_ip = args.value_of("host_ip").unwrap();
+
+    let when = match (args.value_of("at"), args.value_of("delay")) {
+        (Some(time), _) => Some(When::At(time.to_owned())),
+        (None, Some(delay)) => Some(When::Delay(delay.to_owned())),
+        (None, None) => None,
+    };
+
+    if let Some(when) = when {
+        let request = ScheduleRequest {
+            when,
+            mode: args.value_of("schedule_mode").unwrap().to_owned(),
+            task_name: args.value_of("task_name").map(|name| name.to_owned()),
+            scheduler_addr: format!(
+                "{}:{}",
+                args.value_of("scheduler_ip").unwrap(),
+                args.value_of("scheduler_port").unwrap()
+            ),
+        };
+
+        match schedule::schedule(request, host_ip) {
+            Ok(()) => info!("Transfer scheduled"),
+            Err(err) => {
+                error!("Failed to schedule transfer: {}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     let host_port: u16 = args.value_of("host_port").unwrap().parse().unwrap();
     let remote_addr = format!(
         "{}:{}",
@@ -272,18 +591,26 @@ fn main() {
     };
 
     let protocol_config = FileProtocolConfig::new(
-        Some(storage_prefix),
+        Some(storage_prefix.clone()),
         transfer_chunk_size,
         hold_count,
         inter_chunk_delay,
         max_chunks_transmit,
         hash_chunk_size,
-    );
-    let protocol_instance = FileProtocol::new(
-        &format!("{}:{}", host_ip, host_port),
-        &remote_addr,
-        protocol_config,
-    );
+    )
+    .with_channel_allocation(ChannelAllocation::Sequential);
+    let storage_class = args.value_of("storage_class").map(|class| class.to_owned());
+    // Each simultaneous transfer needs its own local port, so that replies aren't mixed up
+    let host_ip = host_ip.to_owned();
+    let new_protocol = move |port_offset: u16| {
+        let f_protocol = FileProtocol::new(
+            &format!("{}:{}", host_ip, host_port + port_offset),
+            &remote_addr,
+            protocol_config.clone(),
+        );
+        f_protocol.set_storage_class(storage_class.as_deref());
+        f_protocol
+    };
 
     let result = match args.subcommand_name() {
         Some("upload") => {
@@ -298,7 +625,37 @@ fn main() {
                     .into_owned(),
             };
 
-            upload(protocol_instance, &source_path, &target_path)
+            let signature = upload_args.value_of("signature").map(std::fs::read);
+            match signature.transpose() {
+                Ok(signature) if source_path == STDIO_PATH => upload_stdin(
+                    &new_protocol(0),
+                    &storage_prefix,
+                    &target_path,
+                    signature.as_deref(),
+                ),
+                Ok(signature) => upload(
+                    &new_protocol(0),
+                    &source_path,
+                    &target_path,
+                    signature.as_deref(),
+                ),
+                Err(err) => Err(err.into()),
+            }
+        }
+        Some("append") => {
+            let append_args = args.subcommand_matches("append").unwrap();
+            let source_path = append_args.value_of("source_path").unwrap();
+            let target_path = match append_args.value_of("target_path") {
+                Some(path) => path.to_owned(),
+                None => Path::new(&source_path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let offset: u64 = append_args.value_of("offset").unwrap().parse().unwrap();
+
+            append(&new_protocol(0), &source_path, &target_path, offset)
         }
         Some("download") => {
             let download_args = args.subcommand_matches("download").unwrap();
@@ -312,7 +669,29 @@ fn main() {
                     .into_owned(),
             };
 
-            download(protocol_instance, &source_path, &target_path)
+            let offset: u64 = download_args.value_of("offset").unwrap().parse().unwrap();
+            let length: Option<u64> = download_args
+                .value_of("length")
+                .map(|length| length.parse().unwrap());
+
+            if target_path == STDIO_PATH {
+                download_stdout(
+                    &new_protocol(0),
+                    &storage_prefix,
+                    &source_path,
+                    offset,
+                    length,
+                )
+            } else {
+                download(&new_protocol(0), &source_path, &target_path, offset, length)
+            }
+        }
+        Some("batch") => {
+            let batch_args = args.subcommand_matches("batch").unwrap();
+            let jobs: u16 = batch_args.value_of("jobs").unwrap().parse().unwrap();
+
+            batch::read_manifest(batch_args.value_of("manifest").unwrap())
+                .and_then(|transfers| batch::run(transfers, jobs, new_protocol))
         }
         Some("cleanup") => {
             let hash = args
@@ -321,7 +700,17 @@ fn main() {
                 .value_of("hash")
                 .to_owned()
                 .map(|v| v.to_owned());
-            cleanup(protocol_instance, hash)
+            cleanup(new_protocol(0), hash)
+        }
+        Some("log") => {
+            let count: u32 = args
+                .subcommand_matches("log")
+                .unwrap()
+                .value_of("count")
+                .unwrap()
+                .parse()
+                .unwrap();
+            transfer_log(new_protocol(0), count)
         }
         _ => panic!("Invalid command"),
     };
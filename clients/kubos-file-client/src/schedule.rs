@@ -0,0 +1,336 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Deferred transfers
+//
+// Rather than performing a transfer immediately, the client can register it with the
+// scheduler service as a single-task task list. When the task fires, the scheduler runs this
+// same client with the original transfer arguments (minus the scheduling ones).
+//
+// Transfers can be tied to a specific time (`--at`), a delay from now (`--delay`), or to
+// the activation of a mode (`--schedule-mode` with `--delay 0s`). The last option allows large
+// downlinks to automatically start at AOS if the pass mode is activated then.
+
+use failure::{bail, format_err};
+use log::info;
+use serde_cbor::Value;
+use serde_json::json;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Name of the binary the scheduler should execute
+const CLIENT_APP: &str = "kubos-file-client";
+
+// Arguments which control scheduling and shouldn't be passed on to the deferred transfer
+const SCHEDULE_ARGS: &[&str] = &[
+    "--at",
+    "--delay",
+    "--schedule-mode",
+    "--scheduler-ip",
+    "--scheduler-port",
+    "--task-name",
+];
+
+// When the deferred transfer should be run
+pub enum When {
+    // Absolute time in yyyy-mm-dd hh:mm:ss format
+    At(String),
+    // Delay in Xh Ym Zs format, from now or from the mode's activation
+    Delay(String),
+}
+
+pub struct ScheduleRequest {
+    pub when: When,
+    pub mode: String,
+    pub task_name: Option<String>,
+    pub scheduler_addr: String,
+}
+
+// Remove all scheduling arguments (and their values) so the remaining arguments describe only
+// the transfer itself
+pub fn transfer_args<I: IntoIterator<Item = String>>(args: I) -> Vec<String> {
+    let mut out = vec![];
+    let mut skip_next = false;
+
+    for arg in args {
+        if skip_next {
+            skip_next = false;
+            continue;
+        }
+
+        if SCHEDULE_ARGS.contains(&arg.as_str()) {
+            skip_next = true;
+            continue;
+        }
+
+        if SCHEDULE_ARGS
+            .iter()
+            .any(|flag| arg.starts_with(&format!("{}=", flag)))
+        {
+            continue;
+        }
+
+        out.push(arg);
+    }
+
+    out
+}
+
+// Build the JSON task list containing the deferred transfer
+pub fn task_list(when: &When, args: Vec<String>) -> String {
+    let task = match when {
+        When::At(time) => json!({
+            "time": time,
+            "app": { "name": CLIENT_APP, "args": args }
+        }),
+        When::Delay(delay) => json!({
+            "delay": delay,
+            "app": { "name": CLIENT_APP, "args": args }
+        }),
+    };
+
+    json!({ "tasks": [task] }).to_string()
+}
+
+// Register the transfer with the scheduler service
+pub fn schedule(request: ScheduleRequest, host_ip: &str) -> Result<(), failure::Error> {
+    let args = transfer_args(std::env::args().skip(1));
+
+    let name = match request.task_name {
+        Some(name) => name,
+        None => format!(
+            "transfer-{}",
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+        ),
+    };
+
+    info!(
+        "Scheduling transfer as task list '{}' in mode '{}'",
+        name, request.mode
+    );
+
+    let list = task_list(&request.when, args);
+
+    // JSON string escaping is also valid GraphQL string escaping
+    let query = format!(
+        r#"mutation {{ importRawTaskList(name: {}, mode: {}, json: {}) {{ success, errors }} }}"#,
+        serde_json::to_string(&name)?,
+        serde_json::to_string(&request.mode)?,
+        serde_json::to_string(&list)?
+    );
+
+    let socket = UdpSocket::bind(format!("{}:0", host_ip))?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    socket.send_to(query.as_bytes(), &request.scheduler_addr)?;
+
+    let mut buf = vec![0; 4096];
+    let (size, _) = socket
+        .recv_from(&mut buf)
+        .map_err(|err| format_err!("No response from scheduler service: {}", err))?;
+
+    let response: Value = serde_cbor::from_slice(&buf[0..size])?;
+
+    check_response(&response)
+}
+
+// Pull the result of the importRawTaskList mutation out of the service's response
+fn check_response(response: &Value) -> Result<(), failure::Error> {
+    let field = |value: &Value, key: &str| -> Option<Value> {
+        match value {
+            Value::Map(map) => map.get(&Value::Text(key.to_owned())).cloned(),
+            _ => None,
+        }
+    };
+
+    match field(response, "errors") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(ref errors)) if errors.is_empty() => {}
+        Some(errors) => bail!("Scheduler service returned errors: {:?}", errors),
+    }
+
+    let result = field(response, "data")
+        .and_then(|data| field(&data, "importRawTaskList"))
+        .ok_or_else(|| format_err!("Malformed response from scheduler service"))?;
+
+    match field(&result, "success") {
+        Some(Value::Bool(true)) => Ok(()),
+        _ => match field(&result, "errors") {
+            Some(Value::Text(errors)) => bail!("Failed to schedule transfer: {}", errors),
+            _ => bail!("Failed to schedule transfer"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value as Json;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    fn cbor(json: Json) -> Value {
+        serde_cbor::value::to_value(json).unwrap()
+    }
+
+    #[test]
+    fn transfer_args_strips_schedule_args() {
+        let args = strings(&[
+            "--at",
+            "2020-05-01 12:00:00",
+            "-r",
+            "10.0.0.5",
+            "--schedule-mode",
+            "pass",
+            "--task-name",
+            "nightly",
+            "download",
+            "log.txt",
+        ]);
+
+        assert_eq!(
+            transfer_args(args),
+            strings(&["-r", "10.0.0.5", "download", "log.txt"])
+        );
+    }
+
+    #[test]
+    fn transfer_args_strips_inline_values() {
+        let args = strings(&[
+            "--delay=0s",
+            "--scheduler-ip=10.0.0.1",
+            "--scheduler-port=8010",
+            "upload",
+            "image.bin",
+        ]);
+
+        assert_eq!(transfer_args(args), strings(&["upload", "image.bin"]));
+    }
+
+    #[test]
+    fn transfer_args_keeps_similar_args() {
+        // Only exact flags are scheduling args, not anything which happens to share a prefix
+        let args = strings(&["--attempts", "3", "--delayed", "upload", "image.bin"]);
+
+        assert_eq!(transfer_args(args.clone()), args);
+    }
+
+    #[test]
+    fn transfer_args_trailing_flag() {
+        let args = strings(&["upload", "image.bin", "--delay"]);
+
+        assert_eq!(transfer_args(args), strings(&["upload", "image.bin"]));
+    }
+
+    #[test]
+    fn task_list_at() {
+        let list = task_list(
+            &When::At("2020-05-01 12:00:00".to_owned()),
+            strings(&["download", "log.txt"]),
+        );
+
+        let list: Json = serde_json::from_str(&list).unwrap();
+        assert_eq!(
+            list,
+            json!({
+                "tasks": [{
+                    "time": "2020-05-01 12:00:00",
+                    "app": { "name": "kubos-file-client", "args": ["download", "log.txt"] }
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn task_list_delay() {
+        let list = task_list(
+            &When::Delay("0s".to_owned()),
+            strings(&["upload", "a b.txt"]),
+        );
+
+        let list: Json = serde_json::from_str(&list).unwrap();
+        assert_eq!(
+            list,
+            json!({
+                "tasks": [{
+                    "delay": "0s",
+                    "app": { "name": "kubos-file-client", "args": ["upload", "a b.txt"] }
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn check_response_success() {
+        let response = cbor(json!({
+            "data": { "importRawTaskList": { "success": true, "errors": "" } },
+            "errors": null
+        }));
+
+        assert!(check_response(&response).is_ok());
+    }
+
+    #[test]
+    fn check_response_empty_errors() {
+        let response = cbor(json!({
+            "data": { "importRawTaskList": { "success": true, "errors": "" } },
+            "errors": []
+        }));
+
+        assert!(check_response(&response).is_ok());
+    }
+
+    #[test]
+    fn check_response_failure() {
+        let response = cbor(json!({
+            "data": { "importRawTaskList": { "success": false, "errors": "Mode not found" } }
+        }));
+
+        assert_eq!(
+            check_response(&response).unwrap_err().to_string(),
+            "Failed to schedule transfer: Mode not found"
+        );
+    }
+
+    #[test]
+    fn check_response_graphql_errors() {
+        let response = cbor(json!({
+            "data": null,
+            "errors": [{ "message": "Unknown argument" }]
+        }));
+
+        let err = check_response(&response).unwrap_err().to_string();
+        assert!(err.starts_with("Scheduler service returned errors"));
+    }
+
+    #[test]
+    fn check_response_malformed() {
+        let response = cbor(json!({ "data": { "importTaskList": {} } }));
+
+        assert_eq!(
+            check_response(&response).unwrap_err().to_string(),
+            "Malformed response from scheduler service"
+        );
+
+        assert_eq!(
+            check_response(&Value::Text("nope".to_owned()))
+                .unwrap_err()
+                .to_string(),
+            "Malformed response from scheduler service"
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Ground client for sending GraphQL requests to Kubos services
+//
+// Wraps the user's query in a SpacePacket and sends it to the ground side of the
+// communications service, which forwards it up to the requested destination port. The response
+// is returned the same way.
+//
+// In flatsat mode the query is sent straight to the service over UDP instead, which is useful
+// for bench testing without a radio (or comms service) in the loop. Responses too large for a
+// single datagram can be fetched by passing `--chunked`.
+//
+// Kubos services reply with CBOR, which is converted to pretty-printed JSON for display.
+// Responses can be compressed for the downlink by passing `--compress`.
+
+use clap::{App, Arg};
+use comms_service::{decompress, Compression, LinkPacket, PayloadType, SpacePacket};
+use failure::{bail, Error};
+use kubos_system::chunked;
+use std::fs::File;
+use std::io::Read;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Return type for this client.
+type ClientResult<T> = Result<T, Error>;
+
+// Largest response we're able to receive
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+// Command line arguments accepted by the client
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Kubos GraphQL Client")
+        .arg(
+            Arg::with_name("service_port")
+                .help("UDP port of the destination service")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("query")
+                .help("Query or mutation to send")
+                .required_unless("file")
+                .conflicts_with("file"),
+        )
+        .arg(
+            Arg::with_name("file")
+                .help("File containing the query or mutation to send")
+                .short("f")
+                .long("file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_ip")
+                .help("IP address of the comms service (or the service itself in flatsat mode)")
+                .short("i")
+                .long("remote-ip")
+                .takes_value(true)
+                .default_value("0.0.0.0"),
+        )
+        .arg(
+            Arg::with_name("remote_port")
+                .help("UDP port of the comms service's ground gateway")
+                .short("p")
+                .long("remote-port")
+                .takes_value(true)
+                .default_value("14011"),
+        )
+        .arg(
+            Arg::with_name("host_ip")
+                .help("IP address of the local host to use")
+                .short("H")
+                .long("host-ip")
+                .takes_value(true)
+                .default_value("0.0.0.0"),
+        )
+        .arg(
+            Arg::with_name("host_port")
+                .help("UDP port to receive responses on")
+                .short("P")
+                .long("host-port")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .help("Number of seconds to wait for a response")
+                .short("t")
+                .long("timeout")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("flatsat")
+                .help("Send the request directly to the service over UDP")
+                .long("flatsat"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .help("Ask for the response to be compressed before it's downlinked")
+                .short("z")
+                .long("compress")
+                .takes_value(true)
+                .possible_values(&["deflate", "zstd"])
+                .conflicts_with("flatsat"),
+        )
+        .arg(
+            Arg::with_name("chunked")
+                .help("Ask for the response in datagrams of at most this many bytes")
+                .long("chunked")
+                .takes_value(true)
+                .requires("flatsat"),
+        )
+}
+
+fn main() -> ClientResult<()> {
+    let args = app().get_matches();
+
+    let service_port: u16 = args.value_of("service_port").unwrap().parse()?;
+    let remote_ip = args.value_of("remote_ip").unwrap();
+    let remote_port: u16 = args.value_of("remote_port").unwrap().parse()?;
+    let host_ip = args.value_of("host_ip").unwrap();
+    let host_port: u16 = args.value_of("host_port").unwrap().parse()?;
+    let timeout: u64 = args.value_of("timeout").unwrap().parse()?;
+    let compression = match args.value_of("compress") {
+        Some("deflate") => Compression::Deflate,
+        Some("zstd") => Compression::Zstd,
+        _ => Compression::None,
+    };
+    let datagram_size: Option<usize> = match args.value_of("chunked") {
+        Some(size) => Some(size.parse()?),
+        None => None,
+    };
+
+    let query = if let Some(file) = args.value_of("file") {
+        let mut raw = String::new();
+        File::open(file).and_then(|mut f| f.read_to_string(&mut raw))?;
+        raw
+    } else {
+        args.value_of("query").unwrap().to_string()
+    };
+
+    let socket = UdpSocket::bind((host_ip, host_port))?;
+    socket.set_read_timeout(Some(Duration::from_secs(timeout)))?;
+
+    let response = if args.is_present("flatsat") {
+        send_direct(&socket, &query, remote_ip, service_port, datagram_size)?
+    } else {
+        send_comms(
+            &socket,
+            &query,
+            remote_ip,
+            remote_port,
+            service_port,
+            compression,
+        )?
+    };
+
+    // Kubos services respond with CBOR, but fall back to displaying plain text in case
+    // we're talking to something else
+    match serde_cbor::from_slice::<serde_cbor::Value>(&response) {
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+        Err(_) => println!("{}", String::from_utf8_lossy(&response)),
+    }
+
+    Ok(())
+}
+
+// Send the query straight to the service and wait for its response, which is put back
+// together if it was chunked
+fn send_direct(
+    socket: &UdpSocket,
+    query: &str,
+    remote_ip: &str,
+    service_port: u16,
+    datagram_size: Option<usize>,
+) -> ClientResult<Vec<u8>> {
+    if let Some(size) = datagram_size {
+        let query = chunked::stamp(query, size);
+        socket.send_to(query.as_bytes(), (remote_ip, service_port))?;
+        return Ok(chunked::receive(socket)?);
+    }
+
+    socket.send_to(query.as_bytes(), (remote_ip, service_port))?;
+
+    let mut buf = vec![0; MAX_RESPONSE_SIZE];
+    let (size, _) = socket.recv_from(&mut buf)?;
+    buf.truncate(size);
+
+    Ok(buf)
+}
+
+// Wrap the query in a SpacePacket, send it through the comms service and wait for the matching
+// response packet
+fn send_comms(
+    socket: &UdpSocket,
+    query: &str,
+    remote_ip: &str,
+    remote_port: u16,
+    service_port: u16,
+    compression: Compression,
+) -> ClientResult<Vec<u8>> {
+    // Use the current time as the command ID so we can pick our response out of any other
+    // downlinked traffic
+    let command_id = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    let packet = SpacePacket::build(
+        command_id,
+        PayloadType::GraphQL,
+        service_port,
+        query.as_bytes(),
+    )
+    .and_then(|mut packet| {
+        packet.set_compression(compression)?;
+        packet.to_bytes()
+    })?;
+
+    socket.send_to(&packet, (remote_ip, remote_port))?;
+
+    let mut buf = vec![0; MAX_RESPONSE_SIZE];
+    loop {
+        let (size, _) = socket.recv_from(&mut buf)?;
+        let packet = SpacePacket::parse(&buf[0..size])?;
+
+        if !packet.validate() {
+            bail!("Received corrupted response packet");
+        }
+
+        match packet.payload_type() {
+            PayloadType::GraphQL if packet.command_id() == command_id => {
+                return response_payload(&packet);
+            }
+            PayloadType::GraphQL => {
+                eprintln!(
+                    "Ignoring response for unrelated command {}",
+                    packet.command_id()
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+// Extract the response from its packet. Encrypted responses can't be read, since the client
+// doesn't have the comms service's keys
+fn response_payload(packet: &SpacePacket) -> ClientResult<Vec<u8>> {
+    if packet.key_slot() != 0 {
+        bail!(
+            "Received encrypted response (key slot {}), which can't be decrypted",
+            packet.key_slot()
+        );
+    }
+
+    // The service may have decided not to compress the response, so go by the
+    // response's own flag rather than what we asked for
+    Ok(decompress(&packet.payload(), packet.compression())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ErrorKind;
+
+    #[test]
+    fn host_ip_short_flag() {
+        let args = app().get_matches_from(vec!["client", "-H", "10.0.0.1", "8006", "{ping}"]);
+
+        assert_eq!(args.value_of("host_ip"), Some("10.0.0.1"));
+        assert_eq!(args.value_of("remote_ip"), Some("0.0.0.0"));
+    }
+
+    #[test]
+    fn remote_ip_short_flag() {
+        let args = app().get_matches_from(vec!["client", "-i", "10.0.0.2", "8006", "{ping}"]);
+
+        assert_eq!(args.value_of("remote_ip"), Some("10.0.0.2"));
+        assert_eq!(args.value_of("host_ip"), Some("0.0.0.0"));
+    }
+
+    #[test]
+    fn short_help_flag() {
+        let err = app()
+            .get_matches_from_safe(vec!["client", "-h"])
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::HelpDisplayed);
+    }
+
+    #[test]
+    fn defaults() {
+        let args = app().get_matches_from(vec!["client", "8006", "{ping}"]);
+
+        assert_eq!(args.value_of("service_port"), Some("8006"));
+        assert_eq!(args.value_of("query"), Some("{ping}"));
+        assert_eq!(args.value_of("remote_port"), Some("14011"));
+        assert_eq!(args.value_of("host_port"), Some("0"));
+        assert_eq!(args.value_of("timeout"), Some("5"));
+        assert!(!args.is_present("flatsat"));
+        assert!(!args.is_present("compress"));
+    }
+
+    #[test]
+    fn query_from_file() {
+        let args = app().get_matches_from(vec!["client", "-f", "query.graphql", "8006"]);
+
+        assert_eq!(args.value_of("file"), Some("query.graphql"));
+        assert_eq!(args.value_of("query"), None);
+    }
+
+    #[test]
+    fn query_and_file_conflict() {
+        let err = app()
+            .get_matches_from_safe(vec!["client", "-f", "query.graphql", "8006", "{ping}"])
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn query_required() {
+        let err = app()
+            .get_matches_from_safe(vec!["client", "8006"])
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn compress_conflicts_with_flatsat() {
+        let err = app()
+            .get_matches_from_safe(vec!["client", "--flatsat", "-z", "zstd", "8006", "{ping}"])
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn unknown_compression() {
+        let err = app()
+            .get_matches_from_safe(vec!["client", "-z", "lz4", "8006", "{ping}"])
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn encrypted_response() {
+        let mut packet = SpacePacket::build(1, PayloadType::GraphQL, 8006, b"{}").unwrap();
+        assert_eq!(response_payload(&packet).unwrap(), b"{}".to_vec());
+
+        packet.set_key_slot(2).unwrap();
+        let err = response_payload(&packet).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Received encrypted response (key slot 2), which can't be decrypted"
+        );
+    }
+
+    #[test]
+    fn chunked_requires_flatsat() {
+        let err = app()
+            .get_matches_from_safe(vec!["client", "--chunked", "512", "8006", "{ping}"])
+            .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::MissingRequiredArgument);
+
+        let args = app().get_matches_from(vec![
+            "client",
+            "--flatsat",
+            "--chunked",
+            "512",
+            "8006",
+            "{ping}",
+        ]);
+        assert_eq!(args.value_of("chunked"), Some("512"));
+    }
+}
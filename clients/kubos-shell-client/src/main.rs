@@ -18,8 +18,12 @@
 use channel_protocol::{ChannelProtocol, ProtocolError};
 use clap::{value_t, App, AppSettings, Arg, SubCommand};
 use failure::{bail, Error};
+use shell_protocol::recording::parse_recorded_event;
 use std::collections::HashMap;
+use std::fs::File;
 use std::io;
+use std::io::Write;
+use std::thread;
 use std::time::Duration;
 
 fn start_session(channel_proto: &ChannelProtocol) -> Result<(), Error> {
@@ -129,6 +133,70 @@ fn list_sessions(channel_proto: &ChannelProtocol) -> Result<(), Error> {
     Ok(())
 }
 
+fn list_recordings(channel_proto: &ChannelProtocol) -> Result<(), Error> {
+    channel_proto.send(&shell_protocol::messages::recordings::to_cbor(
+        channel_protocol::generate_channel(),
+        None,
+    )?)?;
+
+    let parsed_msg = shell_protocol::messages::parse_message(
+        &channel_proto.recv_message(Some(Duration::from_millis(100)))?,
+    )?;
+
+    match parsed_msg {
+        shell_protocol::messages::Message::Recordings { recordings, .. } => {
+            let recordings = recordings.unwrap_or_default();
+
+            if recordings.is_empty() {
+                println!("\tNo recorded sessions");
+            } else {
+                for (path, size) in recordings.iter() {
+                    println!("\t{}\t{} bytes", path, size);
+                }
+            }
+        }
+        shell_protocol::messages::Message::Error { message, .. } => {
+            bail!("Error received from service: {}", message)
+        }
+        _ => bail!("Shell service is not responding correctly".to_owned()),
+    }
+
+    Ok(())
+}
+
+// Plays back a downloaded session transcript, with the original timing
+fn replay_recording(path: &str, speed: f64) -> Result<(), Error> {
+    use io::BufRead;
+
+    let file = io::BufReader::new(File::open(path)?);
+    let mut last: Option<f64> = None;
+
+    for line in file.lines() {
+        let event = match parse_recorded_event(&line?) {
+            Some(event) => event,
+            None => continue,
+        };
+
+        if let Some(last) = last {
+            let delay = (event.timestamp - last) / speed;
+            if delay > 0.0 {
+                thread::sleep(Duration::from_millis((delay * 1000.0) as u64));
+            }
+        }
+        last = Some(event.timestamp);
+
+        match event.event.as_ref() {
+            "stdout" => print!("{}", event.data),
+            "stderr" => eprint!("{}", event.data),
+            "stdin" => print!("> {}", event.data),
+            other => println!("[{}: {}]", other, event.data),
+        }
+        io::stdout().flush()?;
+    }
+
+    Ok(())
+}
+
 fn kill_session(
     channel_proto: &ChannelProtocol,
     channel_id: u32,
@@ -212,6 +280,27 @@ fn main() -> Result<(), failure::Error> {
     let args = App::new("Shell client")
         .subcommand(SubCommand::with_name("start").about("Starts new shell session"))
         .subcommand(SubCommand::with_name("list").about("Lists existing shell sessions"))
+        .subcommand(
+            SubCommand::with_name("recordings").about("Lists recorded shell session transcripts"),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Replays a downloaded shell session transcript")
+                .arg(
+                    Arg::with_name("file")
+                        .help("Local path of the transcript")
+                        .short("f")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .help("Playback speed multiplier")
+                        .short("s")
+                        .takes_value(true)
+                        .default_value("1"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("join")
                 .about("Joins an existing shell session")
@@ -293,6 +382,25 @@ fn main() -> Result<(), failure::Error> {
             println!("Fetching existing shell sessions:");
             list_sessions(&channel_proto)
         }
+        Some("recordings") => {
+            println!("Fetching recorded shell sessions:");
+            list_recordings(&channel_proto)
+        }
+        Some("replay") => {
+            let (file, speed) = if let Some(replay_args) = args.subcommand_matches("replay") {
+                (
+                    replay_args.value_of("file").unwrap(),
+                    value_t!(replay_args, "speed", f64).unwrap_or_else(|e| e.exit()),
+                )
+            } else {
+                bail!("No arguments found for replay");
+            };
+            if speed <= 0.0 {
+                bail!("Invalid speed specified");
+            }
+
+            replay_recording(file, speed)
+        }
         Some("join") => {
             let channel_id = if let Some(kill_args) = args.subcommand_matches("join") {
                 value_t!(kill_args, "channel_id", u32).unwrap_or_else(|e| e.exit())
@@ -28,6 +28,8 @@ use log::error;
 fn main() {
     Logger::init("example-service").unwrap();
 
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
     Service::new(
         Config::new("example-service")
             .map_err(|err| {
@@ -56,6 +56,8 @@ fn main() {
 fn main() {
     Logger::init("example-service").unwrap();
 
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
     Service::new(
         Config::new("example-service")
             .map_err(|err| {
@@ -54,6 +54,7 @@ fn main() -> SerialServiceResult<()> {
     Logger::init("serial-comms-service").unwrap();
 
     let service_config = Config::new("serial-comms-service")?;
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
 
     let bus = service_config
         .get("bus")
@@ -77,6 +78,9 @@ fn main() -> SerialServiceResult<()> {
         comms_config,
     )?;
 
+    // Keep a handle to the keys, so the active downlink key can be switched over GraphQL
+    let keys = controls.keys.clone();
+
     // Initialize new `CommsTelemetry` object.
     let telem = Arc::new(Mutex::new(CommsTelemetry::default()));
 
@@ -84,7 +88,7 @@ fn main() -> SerialServiceResult<()> {
     info!("Serial Communications Service starting on {}", bus);
     CommsService::start::<Arc<Mutex<SerialComms>>, SpacePacket>(controls, &telem.clone())?;
 
-    let subsystem = Subsystem::new(telem);
+    let subsystem = Subsystem::new(telem, keys);
     Service::new(service_config, subsystem, QueryRoot, MutationRoot).start();
 
     Ok(())
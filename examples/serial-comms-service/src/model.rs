@@ -19,17 +19,54 @@
 //! information over the GraphQL interface.
 //!
 
-use comms_service::CommsTelemetry;
+use comms_service::{
+    reset_telemetry, snapshot_telemetry, CommsTelemetry, DestinationTelemetry, KeySlots,
+    SourceTelemetry, WriterTelemetry,
+};
+use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
 
+// Telemetry counts at a single point in time
+#[derive(GraphQLObject)]
+pub struct TelemetrySnapshot {
+    failed_packets_up: i32,
+    failed_packets_down: i32,
+    packets_up: i32,
+    packets_down: i32,
+    cached_responses: i32,
+    shed_packets: i32,
+    destinations: Vec<DestinationTelemetry>,
+    writers: Vec<WriterTelemetry>,
+    sources: Vec<SourceTelemetry>,
+    errors: Vec<String>,
+}
+
+impl From<CommsTelemetry> for TelemetrySnapshot {
+    fn from(item: CommsTelemetry) -> TelemetrySnapshot {
+        TelemetrySnapshot {
+            failed_packets_up: item.failed_packets_up,
+            failed_packets_down: item.failed_packets_down,
+            packets_up: item.packets_up,
+            packets_down: item.packets_down,
+            cached_responses: item.cached_responses,
+            shed_packets: item.shed_packets,
+            destinations: item.destinations,
+            writers: item.writers,
+            sources: item.sources,
+            errors: item.errors,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Subsystem {
     telem: Arc<Mutex<CommsTelemetry>>,
+    keys: KeySlots,
 }
 
 impl Subsystem {
-    pub fn new(telem: Arc<Mutex<CommsTelemetry>>) -> Subsystem {
-        Subsystem { telem }
+    pub fn new(telem: Arc<Mutex<CommsTelemetry>>, keys: KeySlots) -> Subsystem {
+        Subsystem { telem, keys }
     }
 
     pub fn failed_packets_up(&self) -> Result<i32, String> {
@@ -66,4 +103,31 @@ impl Subsystem {
             Err(_) => Err("Failed to lock telemetry".to_owned()),
         }
     }
+
+    pub fn telemetry(&self) -> Result<TelemetrySnapshot, String> {
+        snapshot_telemetry(&self.telem)
+            .map(TelemetrySnapshot::from)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn reset_telemetry(&self) -> Result<TelemetrySnapshot, String> {
+        reset_telemetry(&self.telem)
+            .map(TelemetrySnapshot::from)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn downlink_key(&self) -> i32 {
+        i32::from(self.keys.downlink_slot(None))
+    }
+
+    pub fn set_downlink_key(&self, slot: i32, port: Option<i32>) -> Result<(), String> {
+        let slot = u8::try_from(slot).map_err(|_| format!("Invalid key slot {}", slot))?;
+        let port = match port {
+            Some(port) => Some(u16::try_from(port).map_err(|_| format!("Invalid port {}", port))?),
+            None => None,
+        };
+        self.keys
+            .set_downlink_slot(slot, port)
+            .map_err(|err| err.to_string())
+    }
 }
@@ -19,7 +19,7 @@
 //! telemetry information.
 //!
 
-use crate::model::Subsystem;
+use crate::model::{Subsystem, TelemetrySnapshot};
 use juniper::FieldResult;
 
 type Context = kubos_service::Context<Subsystem>;
@@ -150,11 +150,162 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
     {
         Ok(executor.context().subsystem().errors()?)
     }
+
+    // Request all telemetry counts at once, so they're consistent with each other
+    //
+    // Query
+    //
+    // {
+    //     telemetry {
+    //         packetsUp
+    //         packetsDown
+    //         failedPacketsUp
+    //         failedPacketsDown
+    //         cachedResponses
+    //         shedPackets
+    //         destinations {
+    //             port
+    //             reachable
+    //             consecutiveFailures
+    //             rejected
+    //         }
+    //         writers {
+    //             index
+    //             packetsDown
+    //             failedPacketsDown
+    //         }
+    //         sources {
+    //             port
+    //             address
+    //             packetsDown
+    //             bytesDown
+    //             failedPacketsDown
+    //             shedPackets
+    //         }
+    //         errors
+    //     }
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "telemetry" : {
+    //                    "packetsUp" : 12,
+    //                    "packetsDown" : 10,
+    //                    "failedPacketsUp" : 0,
+    //                    "failedPacketsDown" : 0,
+    //                    "cachedResponses" : 2,
+    //                    "shedPackets" : 0,
+    //                    "destinations" : [
+    //                        {
+    //                            "port" : 8000,
+    //                            "reachable" : true,
+    //                            "consecutiveFailures" : 0,
+    //                            "rejected" : 0
+    //                        }
+    //                    ],
+    //                    "writers" : [
+    //                        {
+    //                            "index" : 0,
+    //                            "packetsDown" : 10,
+    //                            "failedPacketsDown" : 0
+    //                        }
+    //                    ],
+    //                    "sources" : [
+    //                        {
+    //                            "port" : 14011,
+    //                            "address" : "127.0.0.1:41234",
+    //                            "packetsDown" : 8,
+    //                            "bytesDown" : 2048,
+    //                            "failedPacketsDown" : 0,
+    //                            "shedPackets" : 0
+    //                        }
+    //                    ],
+    //                    "errors" : []
+    //                }
+    //            },
+    //     "errors" : ""
+    // }
+    field telemetry(&executor) -> FieldResult<TelemetrySnapshot>
+    {
+        Ok(executor.context().subsystem().telemetry()?)
+    }
+
+    // Request the key slot used to encrypt responses to the ground
+    //
+    // Query
+    //
+    // {
+    //     downlinkKey
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "downlinkKey" : 1
+    //            },
+    //     "errors" : ""
+    // }
+    field downlink_key(&executor) -> FieldResult<i32>
+    {
+        Ok(executor.context().subsystem().downlink_key())
+    }
 });
 
 pub struct MutationRoot;
 
 // Base GraphQL mutation model
 graphql_object!(MutationRoot: Context as "Mutation" |&self| {
+    // Fetch all telemetry counts and reset them to zero, in one step.
+    // Sending this at the end of each pass gives that pass's statistics.
+    //
+    // Mutation
+    //
+    // mutation {
+    //     resetTelemetry {
+    //         packetsUp
+    //         packetsDown
+    //     }
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "resetTelemetry" : {
+    //                    "packetsUp" : 12,
+    //                    "packetsDown" : 10
+    //                }
+    //            },
+    //     "errors" : ""
+    // }
+    field reset_telemetry(&executor) -> FieldResult<TelemetrySnapshot>
+    {
+        Ok(executor.context().subsystem().reset_telemetry()?)
+    }
 
+    // Switch the key used to encrypt downlinked packets, for all responses or
+    // for a single downlink port. Slot 0 turns downlink encryption off.
+    //
+    // Mutation
+    //
+    // mutation {
+    //     setDownlinkKey(slot: 2)
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "setDownlinkKey" : 2
+    //            },
+    //     "errors" : ""
+    // }
+    field set_downlink_key(&executor, slot: i32, port: Option<i32>) -> FieldResult<i32>
+    {
+        executor.context().subsystem().set_downlink_key(slot, port)?;
+        Ok(slot)
+    }
 });
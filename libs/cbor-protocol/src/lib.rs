@@ -167,12 +167,8 @@ impl Protocol {
     pub fn send_message(&self, message: &[u8], dest: SocketAddr) -> Result<(), ProtocolError> {
         // TODO: If paused, just queue up the message
 
-        let mut payload = vec![];
-        payload.extend(message);
-        payload.insert(0, 0);
-
         self.handle
-            .send_to(&payload, &dest)
+            .send_to(&frame_message(message), &dest)
             .map_err(|err| ProtocolError::SendFailed { dest, err })?;
         Ok(())
     }
@@ -260,7 +256,7 @@ impl Protocol {
             .recv_from(&mut buf)
             .map_err(|err| ProtocolError::ReceiveFailed { err })?;
 
-        self.recv_start(&buf[0..size])
+        parse_frame(&buf[0..size])
     }
 
     /// Peek at the sender information for the next message in the UDP receive buffer
@@ -313,7 +309,7 @@ impl Protocol {
             .recv_from(&mut buf)
             .map_err(|err| ProtocolError::ReceiveFailed { err })?;
 
-        let message = self.recv_start(&buf[0..size])?;
+        let message = parse_frame(&buf[0..size])?;
         Ok((peer, message))
     }
 
@@ -372,7 +368,7 @@ impl Protocol {
             },
         };
 
-        let message = self.recv_start(&buf[0..size])?;
+        let message = parse_frame(&buf[0..size])?;
         Ok((peer, message))
     }
 
@@ -431,47 +427,86 @@ impl Protocol {
             },
         };
 
-        Ok(self.recv_start(&buf[0..size])?)
+        Ok(parse_frame(&buf[0..size])?)
     }
+}
 
-    // Parse the received CBOR message
-    fn recv_start(&self, data: &[u8]) -> Result<serde_cbor::Value, ProtocolError> {
-        if data.is_empty() {
-            return Err(ProtocolError::NoDataReceived);
-        }
+/// Wrap a CBOR packet in a frame for sending, for callers which use their own socket
+///
+/// # Examples
+///
+/// ```
+/// use cbor_protocol::*;
+/// use serde_cbor::ser;
+///
+/// let message = ser::to_vec_packed(&["ping"]).unwrap();
+/// let frame = frame_message(&message);
+///
+/// assert_eq!(frame[0], 0);
+/// ```
+///
+pub fn frame_message(message: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(message.len() + 1);
+    payload.push(0);
+    payload.extend(message);
+    payload
+}
 
-        let result = match data[0] {
-            0 => {
-                let message: serde_cbor::Value =
-                    de::from_slice(&data[1..]).map_err(|err| ProtocolError::ParseFail {
-                        err: format!("{:?}", err),
-                    })?;
-
-                if let serde_cbor::Value::Array(_) = message {
-                    message
-                } else {
-                    return Err(ProtocolError::ParseFail {
-                        err: "Body is not an array".to_owned(),
-                    });
-                }
-            }
-            1 => {
-                println!("<- pause");
-                //TODO: Evaluate whether to keep/use pause & resume
-                //TODO: self.pause()?;
-                return Err(ProtocolError::NoDataReceived);
-            }
-            2 => {
-                println!("<- resume");
-                // TODO: self.resume()?;
-                return Err(ProtocolError::NoDataReceived);
-            }
-            x => {
-                eprintln!("Ignoring unknown control frame: {}", x);
-                return Err(ProtocolError::NoDataReceived);
+/// Parse a received frame into a CBOR message, for callers which use their own socket
+///
+/// # Errors
+///
+/// - If the frame is a pause, resume or unknown control frame, this function will return
+///   `ProtocolError::NoDataReceived`
+/// - If the frame's body isn't a CBOR array, this function will return
+///   `ProtocolError::ParseFail`
+///
+/// # Examples
+///
+/// ```
+/// use cbor_protocol::*;
+/// use serde_cbor::ser;
+///
+/// let frame = frame_message(&ser::to_vec_packed(&["ping"]).unwrap());
+/// let message = parse_frame(&frame).unwrap();
+/// ```
+///
+pub fn parse_frame(data: &[u8]) -> Result<serde_cbor::Value, ProtocolError> {
+    if data.is_empty() {
+        return Err(ProtocolError::NoDataReceived);
+    }
+
+    let result = match data[0] {
+        0 => {
+            let message: serde_cbor::Value =
+                de::from_slice(&data[1..]).map_err(|err| ProtocolError::ParseFail {
+                    err: format!("{:?}", err),
+                })?;
+
+            if let serde_cbor::Value::Array(_) = message {
+                message
+            } else {
+                return Err(ProtocolError::ParseFail {
+                    err: "Body is not an array".to_owned(),
+                });
             }
-        };
+        }
+        1 => {
+            println!("<- pause");
+            //TODO: Evaluate whether to keep/use pause & resume
+            //TODO: self.pause()?;
+            return Err(ProtocolError::NoDataReceived);
+        }
+        2 => {
+            println!("<- resume");
+            // TODO: self.resume()?;
+            return Err(ProtocolError::NoDataReceived);
+        }
+        x => {
+            eprintln!("Ignoring unknown control frame: {}", x);
+            return Err(ProtocolError::NoDataReceived);
+        }
+    };
 
-        Ok(result)
-    }
+    Ok(result)
 }
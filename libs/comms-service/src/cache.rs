@@ -0,0 +1,241 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Caching of GraphQL query responses
+//!
+//! When the link is lossy the ground tends to resend a query several times in quick succession.
+//! Rather than passing every copy on to the destination service (and tying up a message handler
+//! each time), the communications service can keep the responses to recent queries and answer
+//! an identical query to the same port straight from the cache.
+//!
+//! Only queries are cached. Mutations, and anything which doesn't look like a query, are always
+//! passed on to their destination. Cached responses expire after `response_cache_ttl`
+//! milliseconds, and the least recently used response is dropped once `response_cache_size`
+//! responses are held.
+
+use crate::config::CommsConfig;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default time a cached response can be used for (in milliseconds)
+pub const DEFAULT_CACHE_TTL: u64 = 2000;
+
+struct CacheEntry {
+    port: u16,
+    hash: u64,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    stored: Instant,
+}
+
+/// Recent responses to GraphQL queries, shared between the message handlers
+///
+/// Clones refer to the same cache. A cache with a size of zero never holds anything.
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<VecDeque<CacheEntry>>>,
+    size: usize,
+    ttl: Duration,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new(0, DEFAULT_CACHE_TTL)
+    }
+}
+
+impl ::std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "ResponseCache {{ size: {}, ttl: {:?}, cached: {} }}",
+            self.size,
+            self.ttl,
+            self.len()
+        )
+    }
+}
+
+impl ResponseCache {
+    /// Creates a cache holding up to `size` responses, each usable for `ttl` milliseconds
+    pub fn new(size: usize, ttl: u64) -> Self {
+        ResponseCache {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+            size,
+            ttl: Duration::from_millis(ttl),
+        }
+    }
+
+    /// Creates a cache using the service's `response_cache_size` and `response_cache_ttl`
+    pub fn from_config(config: &CommsConfig) -> Self {
+        ResponseCache::new(
+            config.response_cache_size.unwrap_or(0),
+            config.response_cache_ttl.unwrap_or(DEFAULT_CACHE_TTL),
+        )
+    }
+
+    /// Whether responses are cached at all
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    /// Number of responses currently held, including any which have expired
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.len())
+            .unwrap_or(0)
+    }
+
+    /// Whether no responses are currently held
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cached response to a request sent to the given port, if the request is a
+    /// query and its response hasn't expired
+    pub fn get(&self, port: u16, request: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_enabled() || !is_query(request) {
+            return None;
+        }
+
+        let hash = hash_request(request);
+        let mut entries = self.entries.lock().ok()?;
+        entries.retain(|entry| entry.stored.elapsed() < self.ttl);
+
+        let index = entries.iter().position(|entry| {
+            entry.port == port && entry.hash == hash && entry.request == request
+        })?;
+
+        // Move the entry to the back, so it's the last to be evicted
+        let entry = entries.remove(index)?;
+        let response = entry.response.clone();
+        entries.push_back(entry);
+        Some(response)
+    }
+
+    /// Keeps the response to a request sent to the given port, if the request is a query
+    pub fn insert(&self, port: u16, request: &[u8], response: &[u8]) {
+        if !self.is_enabled() || !is_query(request) {
+            return;
+        }
+
+        let hash = hash_request(request);
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|entry| {
+                !(entry.port == port && entry.hash == hash && entry.request == request)
+            });
+            while entries.len() >= self.size {
+                entries.pop_front();
+            }
+            entries.push_back(CacheEntry {
+                port,
+                hash,
+                request: request.to_vec(),
+                response: response.to_vec(),
+                stored: Instant::now(),
+            });
+        }
+    }
+
+    /// Drops all of the cached responses
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+fn hash_request(request: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Queries either use the shorthand `{ ... }` form or start with the `query` keyword.
+// Anything else (mutations, subscriptions, non-UTF8 data) could have side effects.
+fn is_query(request: &[u8]) -> bool {
+    match ::std::str::from_utf8(request) {
+        Ok(text) => {
+            let text = text.trim_start();
+            text.starts_with('{') || text.starts_with("query")
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn cached_query() {
+        let cache = ResponseCache::new(4, 1000);
+        cache.insert(8000, b"{ ping }", b"pong");
+
+        assert_eq!(cache.get(8000, b"{ ping }"), Some(b"pong".to_vec()));
+        assert_eq!(cache.get(8001, b"{ ping }"), None);
+        assert_eq!(cache.get(8000, b"{ power }"), None);
+    }
+
+    #[test]
+    fn mutations_not_cached() {
+        let cache = ResponseCache::new(4, 1000);
+        cache.insert(8000, b"mutation { noop { success } }", b"{}");
+
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(8000, b"mutation { noop { success } }"), None);
+    }
+
+    #[test]
+    fn disabled_cache() {
+        let cache = ResponseCache::default();
+        cache.insert(8000, b"{ ping }", b"pong");
+
+        assert!(!cache.is_enabled());
+        assert_eq!(cache.get(8000, b"{ ping }"), None);
+    }
+
+    #[test]
+    fn expired_response() {
+        let cache = ResponseCache::new(4, 10);
+        cache.insert(8000, b"{ ping }", b"pong");
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(cache.get(8000, b"{ ping }"), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let cache = ResponseCache::new(2, 1000);
+        cache.insert(8000, b"{ one }", b"1");
+        cache.insert(8000, b"{ two }", b"2");
+
+        // Using the first response makes the second the least recently used
+        assert!(cache.get(8000, b"{ one }").is_some());
+        cache.insert(8000, b"query { three }", b"3");
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(8000, b"{ two }"), None);
+        assert_eq!(cache.get(8000, b"{ one }"), Some(b"1".to_vec()));
+        assert_eq!(cache.get(8000, b"query { three }"), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,105 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Coalesced downlink packets
+//!
+//! When a downlink port sets `coalesce_size`, packets sent to the port which are waiting to be
+//! downlinked at the same time are sent together in one link packet with the `Coalesced`
+//! payload type, rather than each paying the link's per-packet overhead. The link packet's
+//! payload holds each packet as a sub-frame:
+//!
+//! | Field  | Size    | Description                               |
+//! |--------|---------|-------------------------------------------|
+//! | Length | 2 bytes | Length of the packet's data               |
+//! | Data   | Length  | The packet's payload, as sent to the port |
+//!
+//! in the order the packets were sent. The length is big-endian. A packet which is downlinked
+//! on its own keeps the `UDP` payload type and has no sub-frame header.
+
+use crate::errors::*;
+use byteorder::{BigEndian, ByteOrder};
+
+/// Size of the header in front of each packet in a coalesced payload
+pub const SUBFRAME_HEADER_SIZE: usize = 2;
+
+/// Builds the payload of a coalesced packet
+///
+/// # Arguments
+///
+/// - packets - The payloads to coalesce, each shorter than 64 KiB
+pub fn coalesce<'a, I>(packets: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut coalesced = vec![];
+    for packet in packets {
+        let mut header = [0; SUBFRAME_HEADER_SIZE];
+        BigEndian::write_u16(&mut header, packet.len() as u16);
+        coalesced.extend_from_slice(&header);
+        coalesced.extend_from_slice(packet);
+    }
+    coalesced
+}
+
+/// Splits the payload of a coalesced packet into the payloads of the packets it holds
+pub fn split_coalesced(payload: &[u8]) -> CommsResult<Vec<&[u8]>> {
+    let mut packets = vec![];
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if rest.len() < SUBFRAME_HEADER_SIZE {
+            return Err(CommsServiceError::ParsingError(format!(
+                "Sub-frame header needs {} bytes, got {}",
+                SUBFRAME_HEADER_SIZE,
+                rest.len()
+            ))
+            .into());
+        }
+
+        let len = BigEndian::read_u16(&rest[0..SUBFRAME_HEADER_SIZE]) as usize;
+        let end = SUBFRAME_HEADER_SIZE + len;
+        if rest.len() < end {
+            return Err(CommsServiceError::ParsingError(format!(
+                "Sub-frame needs {} bytes, got {}",
+                len,
+                rest.len() - SUBFRAME_HEADER_SIZE
+            ))
+            .into());
+        }
+        packets.push(&rest[SUBFRAME_HEADER_SIZE..end]);
+        rest = &rest[end..];
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_round_trip() {
+        let packets: Vec<&[u8]> = vec![b"temp 21", b"", b"volts 3.3"];
+        let coalesced = coalesce(packets.iter().cloned());
+        assert_eq!(3 * SUBFRAME_HEADER_SIZE + 16, coalesced.len());
+        assert_eq!(packets, split_coalesced(&coalesced).unwrap());
+    }
+
+    #[test]
+    fn split_coalesced_truncated() {
+        let coalesced = coalesce(vec![&b"temp 21"[..]]);
+        assert!(split_coalesced(&coalesced[..1]).is_err());
+        assert!(split_coalesced(&coalesced[..5]).is_err());
+    }
+}
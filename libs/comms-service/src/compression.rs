@@ -0,0 +1,188 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Compression of GraphQL response payloads
+//!
+//! The ground requests compression by flagging the uplinked GraphQL request packet
+//! (see [`LinkPacket::compression`]). The response is compressed with the requested
+//! algorithm and the downlinked packet is flagged with the algorithm which was actually
+//! applied. A response is sent uncompressed if the algorithm wasn't compiled into the
+//! service (the `flate2` and `zstd` features) or if compressing didn't make it smaller.
+//!
+//! [`LinkPacket::compression`]: trait.LinkPacket.html#method.compression
+
+use crate::errors::*;
+use serde_derive::Deserialize;
+
+/// Compression algorithms which may be applied to a packet's payload
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum Compression {
+    /// Payload is not compressed
+    None,
+    /// Payload is compressed with deflate (RFC 1951)
+    Deflate,
+    /// Payload is compressed with zstd
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl From<u8> for Compression {
+    fn from(num: u8) -> Compression {
+        match num {
+            1 => Compression::Deflate,
+            2 => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+impl From<Compression> for u8 {
+    fn from(value: Compression) -> u8 {
+        match value {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Zstd => 2,
+        }
+    }
+}
+
+/// Compresses a payload with the requested algorithm
+///
+/// Returns the algorithm which was actually applied along with the resulting payload.
+/// Falls back to `Compression::None` (and the original payload) if the algorithm isn't
+/// available, compression fails, or the result wouldn't be any smaller.
+pub fn compress(payload: &[u8], requested: Compression) -> (Compression, Vec<u8>) {
+    let compressed = match requested {
+        Compression::None => return (Compression::None, payload.to_vec()),
+        Compression::Deflate => deflate(payload),
+        Compression::Zstd => zstd_compress(payload),
+    };
+
+    match compressed {
+        Ok(ref data) if data.len() < payload.len() => (requested, data.to_vec()),
+        _ => (Compression::None, payload.to_vec()),
+    }
+}
+
+/// Reverses `compress`, given the algorithm the payload was flagged with
+pub fn decompress(payload: &[u8], compression: Compression) -> CommsResult<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(payload.to_vec()),
+        Compression::Deflate => inflate(payload),
+        Compression::Zstd => zstd_decompress(payload),
+    }
+}
+
+#[cfg(not(all(feature = "flate2", feature = "zstd")))]
+fn unavailable(compression: Compression) -> failure::Error {
+    CommsServiceError::GenericError(format!("{:?} compression is not available", compression))
+        .into()
+}
+
+#[cfg(feature = "flate2")]
+fn deflate(payload: &[u8]) -> CommsResult<Vec<u8>> {
+    use flate2::write::DeflateEncoder;
+    use std::io::Write;
+
+    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::best());
+    encoder.write_all(payload)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(not(feature = "flate2"))]
+fn deflate(_payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Err(unavailable(Compression::Deflate))
+}
+
+#[cfg(feature = "flate2")]
+fn inflate(payload: &[u8]) -> CommsResult<Vec<u8>> {
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    let mut data = vec![];
+    DeflateDecoder::new(payload).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(not(feature = "flate2"))]
+fn inflate(_payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Err(unavailable(Compression::Deflate))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Ok(zstd::stream::encode_all(payload, 19)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Err(unavailable(Compression::Zstd))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Ok(zstd::stream::decode_all(payload)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Err(unavailable(Compression::Zstd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &[u8] = br#"{"data":{"telemetry":[{"subsystem":"eps","parameter":"voltage","value":"4.1"},{"subsystem":"eps","parameter":"voltage","value":"4.1"},{"subsystem":"eps","parameter":"voltage","value":"4.0"}]},"errors":[]}"#;
+
+    #[test]
+    fn compress_none() {
+        assert_eq!(
+            (Compression::None, RESPONSE.to_vec()),
+            compress(RESPONSE, Compression::None)
+        );
+    }
+
+    #[test]
+    fn compress_not_smaller() {
+        let (applied, payload) = compress(b"{}", Compression::Deflate);
+        assert_eq!(Compression::None, applied);
+        assert_eq!(b"{}".to_vec(), payload);
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn deflate_round_trip() {
+        let (applied, payload) = compress(RESPONSE, Compression::Deflate);
+        assert_eq!(Compression::Deflate, applied);
+        assert!(payload.len() < RESPONSE.len());
+        assert_eq!(RESPONSE.to_vec(), decompress(&payload, applied).unwrap());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        let (applied, payload) = compress(RESPONSE, Compression::Zstd);
+        assert_eq!(Compression::Zstd, applied);
+        assert!(payload.len() < RESPONSE.len());
+        assert_eq!(RESPONSE.to_vec(), decompress(&payload, applied).unwrap());
+    }
+}
@@ -19,7 +19,11 @@
 //! TOML parser for the `comms-service`. This module parses a `toml` file and returns a
 //! struct containing configuration information for a `comms-service`.
 
+use crate::encryption::KeySlotConfig;
 use crate::errors::*;
+use crate::fec::Fec;
+use crate::spacepacket::ApidRoute;
+use crate::timecode::TimeCode;
 use serde_derive::Deserialize;
 
 /// Default maximum number of message handlers
@@ -31,7 +35,8 @@ pub const DEFAULT_TIMEOUT: u64 = 1500;
 /// Created by parsing a configuration file in the `toml` file format.
 #[derive(Clone, Debug, Deserialize)]
 pub struct CommsConfig {
-    /// The maximum number of concurrent message handlers allowed
+    /// The maximum number of concurrent message handlers allowed.
+    /// This many message handler threads are started with the service.
     /// Default: 50
     pub max_num_handlers: Option<u16>,
     /// Optional list of ports used by downlink endpoints that send messages to the ground.
@@ -45,6 +50,76 @@ pub struct CommsConfig {
     pub write_timeout: Option<u64>,
     /// Required. IP address on which comms service will listen.
     pub ip: String,
+    /// Optional: Directory in which time-tagged commands are stored until they're due.
+    /// Time-tagged commands are rejected if this isn't set.
+    pub time_tag_dir: Option<String>,
+    /// Optional: File in which the last command counter accepted from each ground station is
+    /// kept. Setting it turns on replay protection: every uplinked payload must start with a
+    /// command counter higher than the last one accepted from its station.
+    pub command_counter_file: Option<String>,
+    /// Optional: File in which the ports disabled while the service is running are kept, so
+    /// they stay disabled across restarts.
+    /// Default: None (every port is enabled at startup)
+    pub port_state_file: Option<String>,
+    /// Optional: Payload encryption keys to load into key slots
+    pub keys: Option<Vec<KeySlotConfig>>,
+    /// Optional: Key slot used to encrypt responses to the ground's requests, and
+    /// packets from downlink ports which don't set their own `key_slot`.
+    /// Default: 0 (not encrypted)
+    pub downlink_key_slot: Option<u8>,
+    /// Optional: Number of GraphQL query responses to cache, so that repeated identical
+    /// queries are answered without being passed on to their destination.
+    /// Default: 0 (no caching)
+    pub response_cache_size: Option<usize>,
+    /// Optional: Time a cached query response can be used for (in milliseconds).
+    /// Default: 2000
+    pub response_cache_ttl: Option<u64>,
+    /// Optional: Number of times a service must fail to answer GraphQL requests within a minute
+    /// before further requests to it are answered with an error straight away, rather than
+    /// being passed on.
+    /// Default: 0 (requests are always passed on)
+    pub unreachable_threshold: Option<u32>,
+    /// Optional: Pools of local ports which GraphQL requests to a destination port are spread
+    /// across in turn, for services run as several replicas.
+    pub destination_pools: Option<Vec<DestinationPool>>,
+    /// Optional: Routes from SpacePacket APIDs to service ports, for ground systems which
+    /// assign an APID to each subsystem. Routed packets have no command ID.
+    pub apid_routes: Option<Vec<ApidRoute>>,
+    /// Optional: CCSDS time code which SpacePackets are stamped with as they're built, from the
+    /// system clock, so the ground can tell when downlinked data was generated.
+    /// Default: None (packets aren't stamped)
+    pub time_code: Option<TimeCode>,
+    /// Optional: Whether the startup self-test should write a no-op frame (an empty UDP packet
+    /// addressed to port 0) with each write function, to check the gateway can be written to.
+    /// Default: false
+    pub self_test_write: Option<bool>,
+    /// Optional: Maximum number of bytes which queued downlink packets and GraphQL responses may
+    /// hold at once. Once it's reached, the lowest priority traffic is dropped.
+    /// Default: 0 (no cap)
+    pub memory_cap: Option<usize>,
+    /// Optional: Forward error correction expected on frames read from the gateway, and added
+    /// to responses to the ground's requests and packets from downlink ports which don't set
+    /// their own `fec`. Useful when the radio doesn't provide any coding of its own.
+    /// Default: None
+    pub fec: Option<Fec>,
+    /// Optional: Recommended payload size advertised to producers of downlink traffic,
+    /// which shrinks when writes to the gateway fail or are slow.
+    /// Default: None (no size is advertised)
+    pub payload_sizing: Option<PayloadSizingConfig>,
+    /// Optional: Whether to bypass the radio gateway and bridge the link over plain UDP to
+    /// `ground_ip`, so the flight stack can be tested on a bench without a radio. The read and
+    /// write functions given to the service are not used.
+    /// Default: false
+    pub flatsat: Option<bool>,
+    /// Optional: IP address of the ground computer which frames are bridged to in flatsat mode.
+    /// Default: 127.0.0.1
+    pub ground_ip: Option<String>,
+    /// Optional: Local port on which uplinked frames are received in flatsat mode.
+    /// Default: 14100
+    pub flatsat_uplink_port: Option<u16>,
+    /// Optional: Port on the ground computer which downlinked frames are sent to in flatsat mode.
+    /// Default: 14101
+    pub flatsat_downlink_port: Option<u16>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -54,6 +129,63 @@ pub struct DownlinkPort {
     pub port: u16,
     /// Optional: Bufer size
     pub buf_size: Option<usize>,
+    /// Optional: ID of the ground station packets from this port are intended for.
+    /// Default: 0 (any station)
+    pub station_id: Option<u8>,
+    /// Optional: Key slot used to encrypt packets from this port.
+    /// Default: the service's `downlink_key_slot`
+    pub key_slot: Option<u8>,
+    /// Optional: Indexes, in the service's list of write functions, of further write functions
+    /// which packets from this port are mirrored to. For example, a local packet logger or a
+    /// redundant transmitter.
+    pub mirror_writes: Option<Vec<usize>>,
+    /// Optional: Priority of packets from this port when the service's `memory_cap` is reached.
+    /// Lower priority traffic is dropped first. Responses to the ground's requests always
+    /// have the highest priority (255).
+    /// Default: 0
+    pub priority: Option<u8>,
+    /// Optional: Forward error correction added to packets from this port.
+    /// Default: the service's `fec`
+    pub fec: Option<Fec>,
+    /// Optional: Quality of service of packets from this port. Packets from all downlink ports
+    /// are downlinked highest QoS first.
+    /// Default: 0 (bulk traffic)
+    pub qos: Option<u8>,
+    /// Optional: Whether each packet sent to this port starts with a byte giving its own QoS,
+    /// which is removed before the packet is downlinked.
+    /// Default: false
+    pub qos_prefix: Option<bool>,
+    /// Optional: Largest payload, in bytes, of a link packet which packets from this port that
+    /// are waiting to be downlinked at the same time are coalesced into. Typically the link's MTU
+    /// less the link packet's own headers.
+    /// Default: 0 (no coalescing)
+    pub coalesce_size: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Pool of replicas of a service, which GraphQL requests to its port are passed to in turn.
+pub struct DestinationPool {
+    /// Destination port which the ground addresses requests to
+    pub port: u16,
+    /// Local ports of the service's replicas. May include `port` itself.
+    pub replicas: Vec<u16>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Adaptive payload sizing config.
+pub struct PayloadSizingConfig {
+    /// Local port which producers query the recommended payload size on
+    pub port: u16,
+    /// Optional: Smallest payload size recommended, however badly the link is doing.
+    /// Default: 128
+    pub min_size: Option<usize>,
+    /// Largest payload size recommended, when the link is doing well. Typically the link's MTU
+    /// less the link packet's own headers.
+    pub max_size: usize,
+    /// Optional: Writes to the gateway which take longer than this (in milliseconds) count
+    /// as failures.
+    /// Default: 1000
+    pub slow_write_ms: Option<u64>,
 }
 
 impl CommsConfig {
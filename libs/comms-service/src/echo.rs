@@ -0,0 +1,83 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Echo (link test) packets
+//!
+//! A packet with the `Echo` payload type is answered by the communications service itself,
+//! without involving any other service, so the ground can measure round trip time and packet
+//! loss even when the rest of the system is unhealthy. The response has the same command ID,
+//! payload type and station as the request, and its payload starts with an echo header:
+//!
+//! | Field         | Size    | Description                                                |
+//! |---------------|---------|------------------------------------------------------------|
+//! | Received time | 8 bytes | Unix time (microseconds) at which the request was received |
+//!
+//! followed by the request's payload, unchanged. The header field is big-endian.
+
+use crate::errors::*;
+use byteorder::{BigEndian, ByteOrder};
+
+/// Size of the echo header at the start of an echo response's payload
+pub const ECHO_HEADER_SIZE: usize = 8;
+
+/// Builds the payload of an echo response
+///
+/// # Arguments
+///
+/// - received - Unix time (microseconds) at which the request was received
+/// - payload - Payload of the echo request
+pub fn echo_response(received: u64, payload: &[u8]) -> Vec<u8> {
+    let mut response = vec![0; ECHO_HEADER_SIZE];
+    BigEndian::write_u64(&mut response[0..8], received);
+    response.extend_from_slice(payload);
+    response
+}
+
+/// Splits the payload of an echo response into the time the request was received
+/// and the request's payload
+pub fn parse_echo_response(payload: &[u8]) -> CommsResult<(u64, &[u8])> {
+    if payload.len() < ECHO_HEADER_SIZE {
+        return Err(CommsServiceError::ParsingError(format!(
+            "Echo header needs {} bytes, got {}",
+            ECHO_HEADER_SIZE,
+            payload.len()
+        ))
+        .into());
+    }
+
+    let received = BigEndian::read_u64(&payload[0..8]);
+    Ok((received, &payload[ECHO_HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_round_trip() {
+        let response = echo_response(1_600_000_000_123_456, b"seq 42");
+        assert_eq!(ECHO_HEADER_SIZE + 6, response.len());
+
+        let (received, payload) = parse_echo_response(&response).unwrap();
+        assert_eq!(1_600_000_000_123_456, received);
+        assert_eq!(b"seq 42", payload);
+    }
+
+    #[test]
+    fn parse_echo_response_short() {
+        assert!(parse_echo_response(&[0, 0, 0, 0]).is_err());
+    }
+}
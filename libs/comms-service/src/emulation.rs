@@ -0,0 +1,536 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Link emulation for test harnesses
+//!
+//! A `LinkEmulator` wraps the read and write functions of a gateway (typically an in-memory one
+//! used by a test) so that packets crossing it see the latency, bandwidth and loss of a real
+//! space link. This lets protocol-level behaviour, such as file protocol NAKs and GraphQL
+//! timeouts, be exercised against realistic link conditions without any radio hardware.
+//!
+//! The uplink (ground to satellite, the packets returned by the gateway's read function) and
+//! the downlink (satellite to ground, the packets passed to its write functions) are emulated
+//! separately, so asymmetric links can be modelled. All randomness comes from the configured
+//! seed, so a failing test run can be reproduced.
+//!
+//! ```toml
+//! seed = 42
+//!
+//! [uplink]
+//! bandwidth = 1200
+//! latency = { distribution = "fixed", ms = 250 }
+//! loss = { model = "random", probability = 0.01 }
+//!
+//! [downlink]
+//! bandwidth = 9600
+//! latency = { distribution = "normal", mean_ms = 250.0, std_dev_ms = 20.0 }
+//! loss = { model = "burst", good_to_bad = 0.01, bad_to_good = 0.3, good_loss = 0.0, bad_loss = 0.8 }
+//! ```
+//!
+//! The gateway's read function should time out, rather than block forever, so that packets
+//! which are still in flight can be delivered while nothing new is arriving.
+
+use crate::service::{ReadFn, WriteFn};
+use serde_derive::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Distribution the one-way latency of each packet is drawn from
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum Latency {
+    /// Every packet is delayed by the same amount
+    Fixed {
+        /// Latency, in milliseconds
+        ms: u64,
+    },
+    /// Latency is uniformly distributed between a minimum and maximum
+    Uniform {
+        /// Minimum latency, in milliseconds
+        min_ms: u64,
+        /// Maximum latency, in milliseconds
+        max_ms: u64,
+    },
+    /// Latency is normally distributed. Negative samples are treated as zero
+    Normal {
+        /// Mean latency, in milliseconds
+        mean_ms: f64,
+        /// Standard deviation of the latency, in milliseconds
+        std_dev_ms: f64,
+    },
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Fixed { ms: 0 }
+    }
+}
+
+/// How packets are lost in transit
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum Loss {
+    /// No packets are lost
+    None,
+    /// Each packet is lost independently
+    Random {
+        /// Chance of each packet being lost, from 0 to 1
+        probability: f64,
+    },
+    /// Gilbert-Elliott burst loss. The link moves between a good and a bad state before each
+    /// packet, and packets are lost at the rate of the state it's in
+    Burst {
+        /// Chance of moving from the good state to the bad state
+        good_to_bad: f64,
+        /// Chance of moving from the bad state back to the good state
+        bad_to_good: f64,
+        /// Chance of a packet being lost in the good state
+        good_loss: f64,
+        /// Chance of a packet being lost in the bad state
+        bad_loss: f64,
+    },
+}
+
+impl Default for Loss {
+    fn default() -> Self {
+        Loss::None
+    }
+}
+
+/// Conditions of one direction of the link
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct LinkConditions {
+    /// Latency of each packet
+    #[serde(default)]
+    pub latency: Latency,
+    /// Bandwidth of the link, in bytes per second. Unlimited if not given
+    pub bandwidth: Option<u64>,
+    /// Loss model of the link
+    #[serde(default)]
+    pub loss: Loss,
+}
+
+/// Conditions of both directions of the link
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct LinkEmulation {
+    /// Conditions of packets sent from the ground to the satellite
+    #[serde(default)]
+    pub uplink: LinkConditions,
+    /// Conditions of packets sent from the satellite to the ground
+    #[serde(default)]
+    pub downlink: LinkConditions,
+    /// Seed for the random latency and loss
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// Counts of the packets which have crossed the emulated link
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    /// Uplink packets which reached the satellite
+    pub uplink_delivered: u64,
+    /// Uplink packets which were lost
+    pub uplink_dropped: u64,
+    /// Downlink packets which reached the ground
+    pub downlink_delivered: u64,
+    /// Downlink packets which were lost
+    pub downlink_dropped: u64,
+}
+
+// xorshift64*. Test runs only need to be reproducible, not unpredictable
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    // Uniformly distributed in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+// When a packet has finished being sent, and when it arrives
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Transit {
+    sent: Instant,
+    arrival: Instant,
+}
+
+// State of one direction of the link
+struct Channel {
+    conditions: LinkConditions,
+    rng: Rng,
+    // Whether a burst loss model is in its bad state
+    bad: bool,
+    // When the link will have finished sending the packets given to it so far
+    free_at: Option<Instant>,
+    // Packets still in flight, in order of arrival
+    in_flight: VecDeque<(Instant, Vec<u8>)>,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl Channel {
+    fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Channel {
+            conditions,
+            rng: Rng::new(seed),
+            bad: false,
+            free_at: None,
+            in_flight: VecDeque::new(),
+            delivered: 0,
+            dropped: 0,
+        }
+    }
+
+    fn latency(&mut self) -> Duration {
+        let ms = match self.conditions.latency {
+            Latency::Fixed { ms } => ms as f64,
+            Latency::Uniform { min_ms, max_ms } => {
+                min_ms as f64 + self.rng.next_f64() * max_ms.saturating_sub(min_ms) as f64
+            }
+            Latency::Normal {
+                mean_ms,
+                std_dev_ms,
+            } => {
+                // Box-Muller transform
+                let u1 = 1.0 - self.rng.next_f64();
+                let u2 = self.rng.next_f64();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                mean_ms + z * std_dev_ms
+            }
+        };
+        Duration::from_micros((ms.max(0.0) * 1000.0) as u64)
+    }
+
+    fn lost(&mut self) -> bool {
+        match self.conditions.loss {
+            Loss::None => false,
+            Loss::Random { probability } => self.rng.chance(probability),
+            Loss::Burst {
+                good_to_bad,
+                bad_to_good,
+                good_loss,
+                bad_loss,
+            } => {
+                self.bad = if self.bad {
+                    !self.rng.chance(bad_to_good)
+                } else {
+                    self.rng.chance(good_to_bad)
+                };
+                self.rng.chance(if self.bad { bad_loss } else { good_loss })
+            }
+        }
+    }
+
+    // Send a packet over the link. Returns `None` if the packet is lost.
+    // Lost packets still take up bandwidth, since they were transmitted.
+    fn send(&mut self, len: usize, now: Instant) -> Option<Transit> {
+        let start = match self.free_at {
+            Some(free_at) if free_at > now => free_at,
+            _ => now,
+        };
+        let sent = match self.conditions.bandwidth {
+            Some(bandwidth) if bandwidth > 0 => {
+                start + Duration::from_micros(len as u64 * 1_000_000 / bandwidth)
+            }
+            _ => start,
+        };
+        self.free_at = Some(sent);
+
+        if self.lost() {
+            self.dropped += 1;
+            return None;
+        }
+        self.delivered += 1;
+
+        let arrival = sent + self.latency();
+        Some(Transit { sent, arrival })
+    }
+
+    // Add a packet which is in flight, keeping them in order of arrival
+    fn queue(&mut self, arrival: Instant, data: Vec<u8>) {
+        let index = self
+            .in_flight
+            .iter()
+            .position(|(other, _)| *other > arrival)
+            .unwrap_or(self.in_flight.len());
+        self.in_flight.insert(index, (arrival, data));
+    }
+}
+
+/// Emulates the conditions of a space link on a gateway
+///
+/// Clones share the same link.
+#[derive(Clone)]
+pub struct LinkEmulator {
+    uplink: Arc<Mutex<Channel>>,
+    downlink: Arc<Mutex<Channel>>,
+}
+
+impl LinkEmulator {
+    /// Create a new emulated link
+    pub fn new(emulation: LinkEmulation) -> Self {
+        LinkEmulator {
+            uplink: Arc::new(Mutex::new(Channel::new(emulation.uplink, emulation.seed))),
+            downlink: Arc::new(Mutex::new(Channel::new(
+                emulation.downlink,
+                emulation.seed.wrapping_add(1),
+            ))),
+        }
+    }
+
+    /// Counts of the packets which have crossed the link so far
+    pub fn stats(&self) -> LinkStats {
+        let uplink = self.uplink.lock().unwrap();
+        let downlink = self.downlink.lock().unwrap();
+        LinkStats {
+            uplink_delivered: uplink.delivered,
+            uplink_dropped: uplink.dropped,
+            downlink_delivered: downlink.delivered,
+            downlink_dropped: downlink.dropped,
+        }
+    }
+
+    /// Wrap a gateway's read function, so the packets it returns cross the emulated uplink
+    ///
+    /// Packets are held back until they would have arrived. Lost packets are never returned.
+    pub fn read<Connection: 'static>(
+        &self,
+        read: Arc<ReadFn<Connection>>,
+    ) -> Arc<ReadFn<Connection>> {
+        let uplink = self.uplink.clone();
+
+        Arc::new(move |conn: &Connection| loop {
+            let now = Instant::now();
+            let next_arrival = {
+                let mut channel = uplink.lock().unwrap();
+                match channel.in_flight.front() {
+                    Some((arrival, _)) if *arrival <= now => {
+                        return Ok(channel.in_flight.pop_front().unwrap().1);
+                    }
+                    Some((arrival, _)) => Some(*arrival),
+                    None => None,
+                }
+            };
+
+            match read(conn) {
+                Ok(data) => {
+                    let mut channel = uplink.lock().unwrap();
+                    if let Some(transit) = channel.send(data.len(), Instant::now()) {
+                        channel.queue(transit.arrival, data);
+                    }
+                }
+                // Nothing new has arrived. Wait for the next packet which is in flight
+                Err(err) => match next_arrival {
+                    Some(arrival) => thread::sleep(arrival.saturating_duration_since(now)),
+                    None => return Err(err),
+                },
+            }
+        })
+    }
+
+    /// Wrap a gateway's write function, so the packets given to it cross the emulated downlink
+    ///
+    /// Writes block until the packet would have been sent, so a bandwidth cap slows down the
+    /// writer. The packet is then written to the gateway once it would have arrived. Lost
+    /// packets are never written, but the write still succeeds, since a radio can't tell.
+    pub fn write<Connection: Clone + Send + 'static>(
+        &self,
+        write: Arc<WriteFn<Connection>>,
+    ) -> Arc<WriteFn<Connection>> {
+        let downlink = self.downlink.clone();
+
+        Arc::new(move |conn: &Connection, data: &[u8]| {
+            let transit = downlink.lock().unwrap().send(data.len(), Instant::now());
+            let transit = match transit {
+                Some(transit) => transit,
+                None => return Ok(()),
+            };
+
+            let now = Instant::now();
+            if transit.sent > now {
+                thread::sleep(transit.sent - now);
+            }
+
+            let now = Instant::now();
+            if transit.arrival <= now {
+                return write(conn, data);
+            }
+
+            let write = write.clone();
+            let conn = conn.clone();
+            let data = data.to_vec();
+            let delay = transit.arrival - now;
+            thread::spawn(move || {
+                thread::sleep(delay);
+                if let Err(err) = write(&conn, &data) {
+                    warn!("Failed to write emulated downlink packet: {}", err);
+                }
+            });
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::*;
+
+    fn channel(conditions: LinkConditions) -> Channel {
+        Channel::new(conditions, 7)
+    }
+
+    #[test]
+    fn random_loss() {
+        let mut link = channel(LinkConditions {
+            loss: Loss::Random { probability: 0.25 },
+            ..Default::default()
+        });
+
+        let now = Instant::now();
+        for _ in 0..10_000 {
+            link.send(10, now);
+        }
+        assert_eq!(10_000, link.delivered + link.dropped);
+        assert!(link.dropped > 2_200 && link.dropped < 2_800);
+    }
+
+    #[test]
+    fn burst_loss() {
+        let mut link = channel(LinkConditions {
+            loss: Loss::Burst {
+                good_to_bad: 0.05,
+                bad_to_good: 0.25,
+                good_loss: 0.0,
+                bad_loss: 1.0,
+            },
+            ..Default::default()
+        });
+
+        let now = Instant::now();
+        let lost: Vec<bool> = (0..10_000).map(|_| link.send(10, now).is_none()).collect();
+
+        // Losses come in runs of four packets, on average
+        let bursts = lost.windows(2).filter(|pair| !pair[0] && pair[1]).count();
+        let mean_burst = link.dropped as f64 / bursts as f64;
+        assert!(mean_burst > 3.0 && mean_burst < 5.0);
+    }
+
+    #[test]
+    fn bandwidth_and_latency() {
+        let mut link = channel(LinkConditions {
+            latency: Latency::Fixed { ms: 100 },
+            bandwidth: Some(1000),
+            ..Default::default()
+        });
+
+        // Packets sent back to back queue up behind each other
+        let now = Instant::now();
+        let first = link.send(100, now).unwrap();
+        let second = link.send(100, now).unwrap();
+        assert_eq!(first.sent, now + Duration::from_millis(100));
+        assert_eq!(first.arrival, now + Duration::from_millis(200));
+        assert_eq!(second.sent, now + Duration::from_millis(200));
+        assert_eq!(second.arrival, now + Duration::from_millis(300));
+
+        // Once the link is idle, packets are sent straight away
+        let later = now + Duration::from_secs(1);
+        let third = link.send(100, later).unwrap();
+        assert_eq!(third.sent, later + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn normal_latency() {
+        let mut link = channel(LinkConditions {
+            latency: Latency::Normal {
+                mean_ms: 250.0,
+                std_dev_ms: 20.0,
+            },
+            ..Default::default()
+        });
+
+        let samples: Vec<f64> = (0..10_000)
+            .map(|_| link.latency().as_micros() as f64 / 1000.0)
+            .collect();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        assert!(mean > 245.0 && mean < 255.0);
+    }
+
+    #[test]
+    fn emulated_gateway() {
+        type Buffer = Arc<Mutex<Vec<Vec<u8>>>>;
+
+        let emulator = LinkEmulator::new(LinkEmulation {
+            uplink: LinkConditions {
+                loss: Loss::Random { probability: 1.0 },
+                ..Default::default()
+            },
+            downlink: LinkConditions {
+                latency: Latency::Fixed { ms: 100 },
+                ..Default::default()
+            },
+            seed: 0,
+        });
+
+        let read = emulator.read::<Buffer>(Arc::new(|buffer: &Buffer| {
+            buffer
+                .lock()
+                .unwrap()
+                .pop()
+                .ok_or_else(|| CommsServiceError::NoReadData.into())
+        }));
+        let write = emulator.write::<Buffer>(Arc::new(|buffer: &Buffer, data: &[u8]| {
+            buffer.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }));
+
+        // Everything sent up is lost
+        let uplink: Buffer = Arc::new(Mutex::new(vec![vec![1, 2, 3]]));
+        assert!(read(&uplink).is_err());
+
+        // Packets sent down arrive after the link's latency
+        let downlink: Buffer = Arc::new(Mutex::new(vec![]));
+        write(&downlink, &[4, 5, 6]).unwrap();
+        assert!(downlink.lock().unwrap().is_empty());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*downlink.lock().unwrap(), vec![vec![4, 5, 6]]);
+
+        assert_eq!(
+            emulator.stats(),
+            LinkStats {
+                uplink_delivered: 0,
+                uplink_dropped: 1,
+                downlink_delivered: 1,
+                downlink_dropped: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Payload encryption with rotatable keys
+//!
+//! Up to 15 keys can be loaded into numbered key slots from the service's config.
+//! Each packet's header carries the slot of the key its payload was encrypted with
+//! (see [`LinkPacket::key_slot`]), with slot `0` meaning the payload isn't encrypted.
+//!
+//! Uplinked packets are decrypted with whichever slot the ground used. Downlinked packets are
+//! encrypted with the active downlink slot, which can be set for each downlink port and changed
+//! at runtime with [`KeySlots::set_downlink_slot`]. Keys can then be rotated on orbit by loading
+//! the next key into a spare slot ahead of time and switching to it when the ground is ready.
+//!
+//! Payloads are encrypted with ChaCha20-Poly1305 (the `chacha20poly1305` feature).
+//! An encrypted payload is the 12-byte nonce, followed by the ciphertext and the 16-byte tag.
+//!
+//! [`LinkPacket::key_slot`]: trait.LinkPacket.html#method.key_slot
+//! [`KeySlots::set_downlink_slot`]: struct.KeySlots.html#method.set_downlink_slot
+
+use crate::config::CommsConfig;
+use crate::errors::*;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// Key slot used for payloads which aren't encrypted
+pub const NO_KEY_SLOT: u8 = 0;
+/// Highest key slot which can hold a key
+pub const MAX_KEY_SLOT: u8 = 15;
+/// Size of an encryption key, in bytes
+pub const KEY_SIZE: usize = 32;
+
+/// A key to load into a key slot, as given in the service's config
+#[derive(Clone, Deserialize)]
+pub struct KeySlotConfig {
+    /// Slot to load the key into (1-15)
+    pub slot: u8,
+    /// The 32-byte key, hex-encoded
+    pub key: String,
+}
+
+impl fmt::Debug for KeySlotConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep keys out of the logs
+        write!(
+            f,
+            "KeySlotConfig {{ slot: {}, key: <redacted> }}",
+            self.slot
+        )
+    }
+}
+
+#[derive(Default)]
+struct KeySlotState {
+    keys: HashMap<u8, [u8; KEY_SIZE]>,
+    // Active downlink slot for packets which don't come from a downlink port
+    downlink: u8,
+    // Active downlink slot of each downlink port which doesn't use the default
+    port_downlink: HashMap<u16, u8>,
+}
+
+impl KeySlotState {
+    fn check_slot(&self, slot: u8) -> CommsResult<()> {
+        if slot == NO_KEY_SLOT || self.keys.contains_key(&slot) {
+            Ok(())
+        } else {
+            Err(CommsServiceError::ConfigError(format!("No key loaded in slot {}", slot)).into())
+        }
+    }
+}
+
+/// The loaded encryption keys and active downlink key slots, shared between the service's threads
+///
+/// Clones refer to the same keys, so a clone can be handed to the service's GraphQL schema
+/// to let the ground switch the active downlink key.
+#[derive(Clone, Default)]
+pub struct KeySlots {
+    state: Arc<RwLock<KeySlotState>>,
+}
+
+impl fmt::Debug for KeySlots {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "KeySlots {{ loaded: {:?}, downlink: {} }}",
+            self.loaded_slots(),
+            self.downlink_slot(None)
+        )
+    }
+}
+
+impl KeySlots {
+    /// Loads the keys and initial downlink key slots from the service's config
+    pub fn new(config: &CommsConfig) -> CommsResult<Self> {
+        let mut state = KeySlotState::default();
+
+        for entry in config.keys.iter().flatten() {
+            if entry.slot == NO_KEY_SLOT || entry.slot > MAX_KEY_SLOT {
+                return Err(CommsServiceError::ConfigError(format!(
+                    "Key slot must be between 1 and {}, got {}",
+                    MAX_KEY_SLOT, entry.slot
+                ))
+                .into());
+            }
+            let key = parse_key(&entry.key).ok_or_else(|| {
+                CommsServiceError::ConfigError(format!(
+                    "Key in slot {} must be {} hex-encoded bytes",
+                    entry.slot, KEY_SIZE
+                ))
+            })?;
+            if state.keys.insert(entry.slot, key).is_some() {
+                return Err(CommsServiceError::ConfigError(format!(
+                    "Key slot {} is given more than once",
+                    entry.slot
+                ))
+                .into());
+            }
+        }
+
+        state.downlink = config.downlink_key_slot.unwrap_or(NO_KEY_SLOT);
+        state.check_slot(state.downlink)?;
+
+        for port in config.downlink_ports.iter().flatten() {
+            if let Some(slot) = port.key_slot {
+                state.check_slot(slot)?;
+                state.port_downlink.insert(port.port, slot);
+            }
+        }
+
+        Ok(KeySlots {
+            state: Arc::new(RwLock::new(state)),
+        })
+    }
+
+    /// Slots which have a key loaded, in ascending order
+    pub fn loaded_slots(&self) -> Vec<u8> {
+        let mut slots: Vec<u8> = match self.state.read() {
+            Ok(state) => state.keys.keys().cloned().collect(),
+            Err(_) => vec![],
+        };
+        slots.sort();
+        slots
+    }
+
+    /// Active downlink key slot of a downlink port, or of responses to the ground's requests
+    /// if no port is given
+    pub fn downlink_slot(&self, port: Option<u16>) -> u8 {
+        match self.state.read() {
+            Ok(state) => port
+                .and_then(|port| state.port_downlink.get(&port).cloned())
+                .unwrap_or(state.downlink),
+            Err(_) => NO_KEY_SLOT,
+        }
+    }
+
+    /// Switches the active downlink key slot of a downlink port, or of responses to the
+    /// ground's requests if no port is given. Slot `0` turns downlink encryption off.
+    pub fn set_downlink_slot(&self, slot: u8, port: Option<u16>) -> CommsResult<()> {
+        let mut state = self
+            .state
+            .write()
+            .map_err(|_| CommsServiceError::MutexPoisoned)?;
+        state.check_slot(slot)?;
+
+        match port {
+            Some(port) => {
+                state.port_downlink.insert(port, slot);
+            }
+            None => state.downlink = slot,
+        }
+        Ok(())
+    }
+
+    /// Encrypts a payload with the key in the given slot.
+    /// Payloads for slot `0` are returned unchanged.
+    pub fn encrypt(&self, slot: u8, payload: &[u8]) -> CommsResult<Vec<u8>> {
+        match self.key(slot)? {
+            Some(key) => seal(&key, payload),
+            None => Ok(payload.to_vec()),
+        }
+    }
+
+    /// Reverses `encrypt`, given the slot the payload was flagged with
+    pub fn decrypt(&self, slot: u8, payload: &[u8]) -> CommsResult<Vec<u8>> {
+        match self.key(slot)? {
+            Some(key) => open(&key, payload),
+            None => Ok(payload.to_vec()),
+        }
+    }
+
+    fn key(&self, slot: u8) -> CommsResult<Option<[u8; KEY_SIZE]>> {
+        if slot == NO_KEY_SLOT {
+            return Ok(None);
+        }
+        let state = self
+            .state
+            .read()
+            .map_err(|_| CommsServiceError::MutexPoisoned)?;
+        match state.keys.get(&slot) {
+            Some(key) => Ok(Some(*key)),
+            None => Err(
+                CommsServiceError::GenericError(format!("No key loaded in slot {}", slot)).into(),
+            ),
+        }
+    }
+}
+
+fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; KEY_SIZE];
+    for (byte, chunk) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(feature = "chacha20poly1305")]
+const NONCE_SIZE: usize = 12;
+
+#[cfg(feature = "chacha20poly1305")]
+fn seal(key: &[u8; KEY_SIZE], payload: &[u8]) -> CommsResult<Vec<u8>> {
+    use chacha20poly1305::aead::{Aead, NewAead};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use std::io::Read;
+
+    // A random nonce, since nothing else is guaranteed to be unique across reboots
+    let mut nonce = [0; NONCE_SIZE];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| CommsServiceError::GenericError("Failed to encrypt payload".to_owned()))?;
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(sealed);
+    Ok(encrypted)
+}
+
+#[cfg(feature = "chacha20poly1305")]
+fn open(key: &[u8; KEY_SIZE], payload: &[u8]) -> CommsResult<Vec<u8>> {
+    use chacha20poly1305::aead::{Aead, NewAead};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+    if payload.len() < NONCE_SIZE {
+        return Err(CommsServiceError::ParsingError(
+            "Encrypted payload is shorter than its nonce".to_owned(),
+        )
+        .into());
+    }
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    Ok(cipher
+        .decrypt(
+            Nonce::from_slice(&payload[0..NONCE_SIZE]),
+            &payload[NONCE_SIZE..],
+        )
+        .map_err(|_| CommsServiceError::GenericError("Failed to decrypt payload".to_owned()))?)
+}
+
+#[cfg(not(feature = "chacha20poly1305"))]
+fn unavailable() -> failure::Error {
+    CommsServiceError::GenericError("Payload encryption is not available".to_owned()).into()
+}
+
+#[cfg(not(feature = "chacha20poly1305"))]
+fn seal(_key: &[u8; KEY_SIZE], _payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Err(unavailable())
+}
+
+#[cfg(not(feature = "chacha20poly1305"))]
+fn open(_key: &[u8; KEY_SIZE], _payload: &[u8]) -> CommsResult<Vec<u8>> {
+    Err(unavailable())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn config(extra: &str) -> CommsConfig {
+        let raw = format!(
+            r#"
+            [comms-service.comms]
+            ip = "0.0.0.0"
+            {}
+
+            [[comms-service.comms.keys]]
+            slot = 1
+            key = "{}"
+
+            [[comms-service.comms.keys]]
+            slot = 2
+            key = "{}"
+            "#,
+            extra, KEY_1, KEY_2
+        );
+        let config = kubos_system::Config::new_from_str("comms-service", &raw).unwrap();
+        CommsConfig::new(config).unwrap()
+    }
+
+    #[test]
+    fn load_keys() {
+        let keys = KeySlots::new(&config("downlink_key_slot = 2")).unwrap();
+        assert_eq!(vec![1, 2], keys.loaded_slots());
+        assert_eq!(2, keys.downlink_slot(None));
+        assert_eq!(2, keys.downlink_slot(Some(14011)));
+    }
+
+    #[test]
+    fn load_missing_downlink_slot() {
+        assert!(KeySlots::new(&config("downlink_key_slot = 3")).is_err());
+    }
+
+    #[test]
+    fn parse_bad_key() {
+        assert_eq!(None, parse_key("0001"));
+        assert_eq!(None, parse_key(&KEY_1.replace("0a", "zz")));
+        assert_eq!(Some(1), parse_key(KEY_1).map(|key| key[1]));
+    }
+
+    #[test]
+    fn switch_downlink_slot() {
+        let keys = KeySlots::new(&config("")).unwrap();
+        assert_eq!(NO_KEY_SLOT, keys.downlink_slot(None));
+
+        keys.set_downlink_slot(1, Some(14011)).unwrap();
+        assert_eq!(1, keys.downlink_slot(Some(14011)));
+        assert_eq!(NO_KEY_SLOT, keys.downlink_slot(None));
+
+        // Clones share the active slots
+        keys.clone().set_downlink_slot(2, None).unwrap();
+        assert_eq!(2, keys.downlink_slot(None));
+
+        assert!(keys.set_downlink_slot(5, None).is_err());
+        assert_eq!(2, keys.downlink_slot(None));
+    }
+
+    #[test]
+    fn no_key_slot_unchanged() {
+        let keys = KeySlots::new(&config("")).unwrap();
+        assert_eq!(b"{ ping }".to_vec(), keys.encrypt(0, b"{ ping }").unwrap());
+        assert_eq!(b"{ ping }".to_vec(), keys.decrypt(0, b"{ ping }").unwrap());
+        assert!(keys.encrypt(7, b"{ ping }").is_err());
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn encrypt_round_trip() {
+        let keys = KeySlots::new(&config("")).unwrap();
+        let encrypted = keys.encrypt(1, b"{ ping }").unwrap();
+        assert_eq!(12 + 8 + 16, encrypted.len());
+        assert_eq!(b"{ ping }".to_vec(), keys.decrypt(1, &encrypted).unwrap());
+
+        // The wrong key fails authentication rather than producing garbage
+        assert!(keys.decrypt(2, &encrypted).is_err());
+    }
+}
@@ -53,6 +53,59 @@ pub enum CommsServiceError {
     /// Unknown payload type encountered
     #[fail(display = "Unknown payload type encountered: {}", _0)]
     UnknownPayloadType(u16),
+    /// The startup self-test failed
+    #[fail(display = "Self-test failed: {}", _0)]
+    SelfTestFailed(String),
+    /// Traffic was dropped because the memory cap was reached
+    #[fail(display = "Memory cap reached, traffic dropped")]
+    MemoryCapReached,
+    /// The memory cap was reached, so traffic with this priority has started being shed
+    #[fail(display = "Memory cap reached, shedding priority {} traffic", _0)]
+    SheddingTraffic(u8),
+    /// A time-tagged command was received, but no time tag directory is configured
+    #[fail(display = "Time-tagged commands are not enabled")]
+    TimeTagsDisabled,
+    /// A frame read from the gateway had more errors than its forward error correction could fix
+    #[fail(display = "Frame has {} uncorrectable FEC blocks", _0)]
+    UncorrectableFrame(usize),
+    /// An uplinked command's counter wasn't higher than the last one accepted from its station
+    #[fail(
+        display = "Replayed command from station {}: counter {} is not above {}",
+        _0, _1, _2
+    )]
+    ReplayedCommand(u8, u64, u64),
+    /// A downlink port which the service wasn't configured with was enabled or disabled
+    #[fail(display = "Port {} is not a downlink port", _0)]
+    UnknownDownlinkPort(u16),
+    /// A packet's data field was empty, or too large for the link layer's length field
+    #[fail(display = "Invalid packet data field length: {} bytes", _0)]
+    InvalidDataLength(usize),
+}
+
+impl CommsServiceError {
+    /// Name of the error's variant, which the error is counted under in the service's telemetry
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CommsServiceError::ConfigError(_) => "ConfigError",
+            CommsServiceError::MutexPoisoned => "MutexPoisoned",
+            CommsServiceError::HeaderParsing => "HeaderParsing",
+            CommsServiceError::InvalidChecksum => "InvalidChecksum",
+            CommsServiceError::ParameterLengthMismatch => "ParameterLengthMismatch",
+            CommsServiceError::NoAvailablePorts => "NoAvailablePorts",
+            CommsServiceError::NoReadData => "NoReadData",
+            CommsServiceError::ParsingError(_) => "ParsingError",
+            CommsServiceError::GenericError(_) => "GenericError",
+            CommsServiceError::UnknownPayloadType(_) => "UnknownPayloadType",
+            CommsServiceError::SelfTestFailed(_) => "SelfTestFailed",
+            CommsServiceError::MemoryCapReached => "MemoryCapReached",
+            CommsServiceError::SheddingTraffic(_) => "SheddingTraffic",
+            CommsServiceError::TimeTagsDisabled => "TimeTagsDisabled",
+            CommsServiceError::UncorrectableFrame(_) => "UncorrectableFrame",
+            CommsServiceError::ReplayedCommand(..) => "ReplayedCommand",
+            CommsServiceError::UnknownDownlinkPort(_) => "UnknownDownlinkPort",
+            CommsServiceError::InvalidDataLength(_) => "InvalidDataLength",
+        }
+    }
 }
 
 /// Result returned by the `comms-service`.
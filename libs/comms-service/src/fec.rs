@@ -0,0 +1,389 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Forward error correction of link frames
+//!
+//! For radios which don't provide any coding of their own, the bytes of each link packet can
+//! be protected with a Reed-Solomon code before they're written to the gateway, and frames read
+//! from the gateway decoded (correcting any errors) before they're parsed.
+//!
+//! A frame is split into blocks of up to [`RS_DATA_SIZE`] bytes, each followed by
+//! [`RS_PARITY_SIZE`] parity bytes, so that up to 16 corrupted bytes can be corrected in each
+//! block. The last block is shortened rather than padded. The code is RS(255,223) over GF(2^8)
+//! with the field polynomial x^8 + x^4 + x^3 + x^2 + 1 (`0x11d`), and a generator polynomial
+//! with the roots α^0 to α^31, where α is `0x02`.
+//!
+//! [`RS_DATA_SIZE`]: constant.RS_DATA_SIZE.html
+//! [`RS_PARITY_SIZE`]: constant.RS_PARITY_SIZE.html
+
+use lazy_static::lazy_static;
+use serde_derive::Deserialize;
+
+/// Size of a full Reed-Solomon block, in bytes
+pub const RS_BLOCK_SIZE: usize = 255;
+/// Most data bytes carried by a Reed-Solomon block
+pub const RS_DATA_SIZE: usize = 223;
+/// Parity bytes added to each Reed-Solomon block
+pub const RS_PARITY_SIZE: usize = RS_BLOCK_SIZE - RS_DATA_SIZE;
+
+// Field polynomial of GF(2^8)
+const FIELD_POLY: u16 = 0x11d;
+
+/// Forward error correction which may be applied to link frames
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum Fec {
+    /// Frames are written and read as they are
+    None,
+    /// Frames are protected with RS(255,223)
+    ReedSolomon,
+}
+
+impl Default for Fec {
+    fn default() -> Self {
+        Fec::None
+    }
+}
+
+/// A frame read from the gateway, once its forward error correction has been decoded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FecDecoded {
+    /// The decoded frame. Blocks which couldn't be corrected are left as received.
+    pub frame: Vec<u8>,
+    /// Number of blocks which had errors that were corrected
+    pub corrected_blocks: usize,
+    /// Number of blocks which had too many errors to correct
+    pub uncorrectable_blocks: usize,
+}
+
+impl Fec {
+    /// Adds forward error correction to a frame which is about to be written to the gateway
+    pub fn encode(self, frame: &[u8]) -> Vec<u8> {
+        match self {
+            Fec::None => frame.to_vec(),
+            Fec::ReedSolomon => {
+                let blocks = (frame.len() + RS_DATA_SIZE - 1) / RS_DATA_SIZE;
+                let mut encoded = Vec::with_capacity(frame.len() + blocks * RS_PARITY_SIZE);
+                for data in frame.chunks(RS_DATA_SIZE) {
+                    encoded.extend_from_slice(data);
+                    encoded.extend_from_slice(&rs_parity(data));
+                }
+                encoded
+            }
+        }
+    }
+
+    /// Decodes the forward error correction of a frame read from the gateway,
+    /// correcting whatever errors it can
+    pub fn decode(self, frame: &[u8]) -> FecDecoded {
+        match self {
+            Fec::None => FecDecoded {
+                frame: frame.to_vec(),
+                ..Default::default()
+            },
+            Fec::ReedSolomon => {
+                let mut decoded = FecDecoded::default();
+                for block in frame.chunks(RS_BLOCK_SIZE) {
+                    // A block with no room for data must have been cut short
+                    if block.len() <= RS_PARITY_SIZE {
+                        decoded.uncorrectable_blocks += 1;
+                        continue;
+                    }
+
+                    let mut block = block.to_vec();
+                    match rs_correct(&mut block) {
+                        Some(0) => {}
+                        Some(_) => decoded.corrected_blocks += 1,
+                        None => decoded.uncorrectable_blocks += 1,
+                    }
+                    decoded
+                        .frame
+                        .extend_from_slice(&block[..block.len() - RS_PARITY_SIZE]);
+                }
+                decoded
+            }
+        }
+    }
+}
+
+// Log and antilog tables of GF(2^8). The antilog table is doubled so that the sum of
+// two logs can be looked up without reducing it first
+struct Field {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+lazy_static! {
+    static ref FIELD: Field = {
+        let mut field = Field {
+            exp: [0; 512],
+            log: [0; 256],
+        };
+        let mut value: u16 = 1;
+        for power in 0..255 {
+            field.exp[power] = value as u8;
+            field.exp[power + 255] = value as u8;
+            field.log[value as usize] = power as u8;
+            value <<= 1;
+            if value & 0x100 != 0 {
+                value ^= FIELD_POLY;
+            }
+        }
+        field
+    };
+
+    // Generator polynomial, highest power first
+    static ref GENERATOR: Vec<u8> = {
+        let mut generator = vec![1u8];
+        for power in 0..RS_PARITY_SIZE {
+            let root = FIELD.exp[power];
+            let mut next = vec![0u8; generator.len() + 1];
+            for (index, coef) in generator.iter().enumerate() {
+                next[index] ^= coef;
+                next[index + 1] ^= mul(*coef, root);
+            }
+            generator = next;
+        }
+        generator
+    };
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        FIELD.exp[FIELD.log[a as usize] as usize + FIELD.log[b as usize] as usize]
+    }
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        0
+    } else {
+        FIELD.exp[FIELD.log[a as usize] as usize + 255 - FIELD.log[b as usize] as usize]
+    }
+}
+
+// α raised to a power in 0..255
+fn pow(power: usize) -> u8 {
+    FIELD.exp[power % 255]
+}
+
+// Evaluates a polynomial, lowest power first
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, coef| mul(acc, x) ^ coef)
+}
+
+// Parity bytes of a block of data: the remainder of dividing it by the generator polynomial
+fn rs_parity(data: &[u8]) -> [u8; RS_PARITY_SIZE] {
+    let mut parity = [0u8; RS_PARITY_SIZE];
+    for byte in data {
+        let feedback = byte ^ parity[0];
+        parity.copy_within(1.., 0);
+        parity[RS_PARITY_SIZE - 1] = 0;
+        if feedback != 0 {
+            for (parity, coef) in parity.iter_mut().zip(&GENERATOR[1..]) {
+                *parity ^= mul(*coef, feedback);
+            }
+        }
+    }
+    parity
+}
+
+// Syndromes of a block, which are all zero if it has no errors. The first byte of a block
+// is its highest power, so shortened blocks need no padding
+fn block_syndromes(block: &[u8]) -> [u8; RS_PARITY_SIZE] {
+    let mut syndromes = [0u8; RS_PARITY_SIZE];
+    for (power, syndrome) in syndromes.iter_mut().enumerate() {
+        let root = pow(power);
+        *syndrome = block.iter().fold(0, |acc, byte| mul(acc, root) ^ byte);
+    }
+    syndromes
+}
+
+// Corrects the errors in a block (data followed by parity), returning how many bytes were
+// corrected, or `None` if there were too many errors to correct
+fn rs_correct(block: &mut [u8]) -> Option<usize> {
+    let syndromes = block_syndromes(block);
+    if syndromes.iter().all(|syndrome| *syndrome == 0) {
+        return Some(0);
+    }
+
+    // Find the error locator polynomial with Berlekamp-Massey (lowest power first)
+    let mut locator = vec![0u8; RS_PARITY_SIZE + 1];
+    let mut previous = vec![0u8; RS_PARITY_SIZE + 1];
+    locator[0] = 1;
+    previous[0] = 1;
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+    for step in 0..RS_PARITY_SIZE {
+        let discrepancy = (1..=errors).fold(syndromes[step], |acc, index| {
+            acc ^ mul(locator[index], syndromes[step - index])
+        });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let scale = div(discrepancy, previous_discrepancy);
+        let last = locator.clone();
+        for index in shift..locator.len() {
+            locator[index] ^= mul(scale, previous[index - shift]);
+        }
+        if 2 * errors <= step {
+            errors = step + 1 - errors;
+            previous = last;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+    }
+    if errors > RS_PARITY_SIZE / 2 || locator[errors + 1..].iter().any(|coef| *coef != 0) {
+        return None;
+    }
+    locator.truncate(errors + 1);
+
+    // Find the error positions with a Chien search. An error in the byte at `index` has the
+    // locator α^power, where `power` counts back from the end of the block
+    let positions: Vec<usize> = (0..block.len())
+        .filter(|index| {
+            let power = block.len() - 1 - index;
+            eval(&locator, pow(255 - power)) == 0
+        })
+        .collect();
+    // Roots outside of the block mean the errors can't be located
+    if positions.len() != errors {
+        return None;
+    }
+
+    // Find the error values with Forney's algorithm
+    let mut evaluator = vec![0u8; RS_PARITY_SIZE];
+    for (i, syndrome) in syndromes.iter().enumerate() {
+        for (j, coef) in locator.iter().enumerate().take(RS_PARITY_SIZE - i) {
+            evaluator[i + j] ^= mul(*syndrome, *coef);
+        }
+    }
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(index, coef)| if index % 2 == 1 { *coef } else { 0 })
+        .collect();
+
+    for index in &positions {
+        let power = block.len() - 1 - index;
+        let inverse = pow(255 - power);
+        let denominator = eval(&derivative, inverse);
+        if denominator == 0 {
+            return None;
+        }
+        block[*index] ^= mul(pow(power), div(eval(&evaluator, inverse), denominator));
+    }
+
+    // Make sure the result really is a codeword
+    if block_syndromes(block).iter().any(|syndrome| *syndrome != 0) {
+        return None;
+    }
+    Some(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn none_unchanged() {
+        let data = frame(300);
+        assert_eq!(Fec::None.encode(&data), data);
+
+        let decoded = Fec::None.decode(&data);
+        assert_eq!(decoded.frame, data);
+        assert_eq!(decoded.corrected_blocks, 0);
+        assert_eq!(decoded.uncorrectable_blocks, 0);
+    }
+
+    #[test]
+    fn reed_solomon_round_trip() {
+        let data = frame(500);
+        let encoded = Fec::ReedSolomon.encode(&data);
+        // Two full blocks and a shortened one
+        assert_eq!(encoded.len(), 500 + 3 * RS_PARITY_SIZE);
+        assert_eq!(&encoded[0..RS_DATA_SIZE], &data[0..RS_DATA_SIZE]);
+
+        assert_eq!(
+            Fec::ReedSolomon.decode(&encoded),
+            FecDecoded {
+                frame: data,
+                corrected_blocks: 0,
+                uncorrectable_blocks: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn reed_solomon_empty() {
+        assert!(Fec::ReedSolomon.encode(&[]).is_empty());
+        assert_eq!(Fec::ReedSolomon.decode(&[]), FecDecoded::default());
+    }
+
+    #[test]
+    fn reed_solomon_corrects_errors() {
+        let data = frame(500);
+        let mut encoded = Fec::ReedSolomon.encode(&data);
+        // Sixteen errors in the first block, including its parity
+        for index in 0..16 {
+            encoded[index * 15] ^= 0x5a;
+        }
+        // One in the shortened last block
+        let last = encoded.len() - 10;
+        encoded[last] ^= 0xff;
+
+        let decoded = Fec::ReedSolomon.decode(&encoded);
+        assert_eq!(decoded.frame, data);
+        assert_eq!(decoded.corrected_blocks, 2);
+        assert_eq!(decoded.uncorrectable_blocks, 0);
+    }
+
+    #[test]
+    fn reed_solomon_uncorrectable() {
+        let data = frame(100);
+        let mut encoded = Fec::ReedSolomon.encode(&data);
+        for index in 0..17 {
+            encoded[index * 7] ^= 0x33;
+        }
+
+        let decoded = Fec::ReedSolomon.decode(&encoded);
+        assert_eq!(decoded.corrected_blocks, 0);
+        assert_eq!(decoded.uncorrectable_blocks, 1);
+        assert_ne!(decoded.frame, data);
+    }
+
+    #[test]
+    fn reed_solomon_truncated() {
+        let data = frame(RS_DATA_SIZE + 1);
+        let encoded = Fec::ReedSolomon.encode(&data);
+
+        // Cutting the last block down to its parity leaves no data to recover
+        let decoded = Fec::ReedSolomon.decode(&encoded[0..RS_BLOCK_SIZE + RS_PARITY_SIZE]);
+        assert_eq!(decoded.frame, &data[0..RS_DATA_SIZE]);
+        assert_eq!(decoded.uncorrectable_blocks, 1);
+    }
+}
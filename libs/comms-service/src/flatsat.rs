@@ -0,0 +1,151 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Flatsat mode
+//!
+//! On a bench there's often no radio to talk to, but the rest of the flight stack still needs
+//! testing end-to-end. If `flatsat` is set, the service ignores the read and write functions it
+//! was given and bridges the link over plain UDP instead. Frames are read from datagrams which the
+//! ground sends to `flatsat_uplink_port` on the service's `ip`, and written as datagrams to
+//! `flatsat_downlink_port` on `ground_ip`. Everything else (link packet parsing, FEC, encryption,
+//! replay protection, downlink queueing and so on) behaves exactly as it would in flight.
+//!
+//! ```toml
+//! [service-name.comms]
+//! ip = "192.168.8.2"
+//! flatsat = true
+//! ground_ip = "192.168.8.1"
+//! ```
+//!
+//! Datagrams from any address other than `ground_ip` are dropped.
+
+use crate::config::CommsConfig;
+use crate::errors::*;
+use crate::service::{ReadFn, WriteFn};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Ground IP address frames are bridged to, unless the config says otherwise
+pub const DEFAULT_GROUND_IP: &str = "127.0.0.1";
+/// Local port uplinked frames are received on, unless the config says otherwise
+pub const DEFAULT_FLATSAT_UPLINK_PORT: u16 = 14100;
+/// Ground port downlinked frames are sent to, unless the config says otherwise
+pub const DEFAULT_FLATSAT_DOWNLINK_PORT: u16 = 14101;
+
+// Largest frame which can be read from the ground
+const MAX_FRAME_SIZE: usize = 65_507;
+
+/// UDP bridge which stands in for the radio gateway in flatsat mode
+///
+/// Clones share the same socket.
+#[derive(Clone, Debug)]
+pub struct FlatsatBridge {
+    socket: Arc<UdpSocket>,
+    ground: SocketAddr,
+}
+
+impl FlatsatBridge {
+    /// Creates a bridge which receives frames on `local` and sends them to `ground`
+    pub fn new(local: SocketAddr, ground: SocketAddr) -> CommsResult<Self> {
+        let socket = UdpSocket::bind(local).map_err(|err| {
+            CommsServiceError::ConfigError(format!(
+                "Unable to bind flatsat uplink port {}: {}",
+                local, err
+            ))
+        })?;
+
+        Ok(FlatsatBridge {
+            socket: Arc::new(socket),
+            ground,
+        })
+    }
+
+    /// Creates a bridge from the service's config, if `flatsat` is set
+    pub fn from_config(config: &CommsConfig) -> CommsResult<Option<Self>> {
+        if !config.flatsat.unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let parse_ip = |ip: &str| {
+            Ipv4Addr::from_str(ip).map_err(|_| {
+                CommsServiceError::ConfigError(format!("Invalid flatsat IP address: {}", ip))
+            })
+        };
+        let ip = parse_ip(&config.ip)?;
+        let ground_ip = parse_ip(
+            config
+                .ground_ip
+                .as_ref()
+                .map_or(DEFAULT_GROUND_IP, |ip| ip.as_str()),
+        )?;
+
+        let local = SocketAddr::new(
+            IpAddr::V4(ip),
+            config
+                .flatsat_uplink_port
+                .unwrap_or(DEFAULT_FLATSAT_UPLINK_PORT),
+        );
+        let ground = SocketAddr::new(
+            IpAddr::V4(ground_ip),
+            config
+                .flatsat_downlink_port
+                .unwrap_or(DEFAULT_FLATSAT_DOWNLINK_PORT),
+        );
+
+        let bridge = FlatsatBridge::new(local, ground)?;
+        info!("Flatsat mode: bridging {} to {}", local, ground);
+        Ok(Some(bridge))
+    }
+
+    /// Address the bridge receives uplinked frames on
+    pub fn local_addr(&self) -> CommsResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Read function which returns the next frame the ground sends
+    ///
+    /// The gateway connection it's given is ignored.
+    pub fn read<Connection: 'static>(&self) -> Arc<ReadFn<Connection>> {
+        let socket = self.socket.clone();
+        let ground = self.ground.ip();
+
+        Arc::new(move |_: &Connection| {
+            let mut buf = vec![0; MAX_FRAME_SIZE];
+            loop {
+                let (size, source) = socket.recv_from(&mut buf)?;
+                if source.ip() == ground {
+                    buf.truncate(size);
+                    return Ok(buf);
+                }
+                warn!("Dropping flatsat frame from unexpected address {}", source);
+            }
+        })
+    }
+
+    /// Write function which sends each frame to the ground
+    ///
+    /// The gateway connection it's given is ignored.
+    pub fn write<Connection: 'static>(&self) -> Arc<WriteFn<Connection>> {
+        let socket = self.socket.clone();
+        let ground = self.ground;
+
+        Arc::new(move |_: &Connection, data: &[u8]| {
+            socket.send_to(data, ground)?;
+            Ok(())
+        })
+    }
+}
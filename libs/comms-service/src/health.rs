@@ -0,0 +1,234 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Health of GraphQL destinations
+//!
+//! The communications service keeps track of whether each service it passes GraphQL requests to
+//! is answering them. A request which can't be sent, or which isn't answered within the read
+//! timeout, counts as a failure for its destination port. Any answer clears the port's failures.
+//!
+//! When a service has crashed or hung, every request sent to it ties up a message handler for
+//! the whole read timeout, which wastes handlers and pass time. If `unreachable_threshold` is set,
+//! once a port has failed that many times within a minute, further requests to it are answered
+//! straight away with a GraphQL error instead. As the failures age out, requests are passed on
+//! again, so the service gets another chance once it has had time to recover.
+
+use crate::config::CommsConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Length of time over which failures are counted towards the threshold
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Destination {
+    consecutive_failures: u32,
+    // Times of the most recent failures, oldest first. No more than the threshold are kept
+    failures: VecDeque<Instant>,
+}
+
+/// Failures of the services which GraphQL requests are passed to, shared between the
+/// message handlers
+///
+/// Clones refer to the same destinations. A threshold of zero means requests are always
+/// passed on, no matter how often their destination fails.
+#[derive(Clone)]
+pub struct DestinationHealth {
+    destinations: Arc<Mutex<HashMap<u16, Destination>>>,
+    threshold: u32,
+}
+
+impl Default for DestinationHealth {
+    fn default() -> Self {
+        DestinationHealth::new(0)
+    }
+}
+
+impl ::std::fmt::Debug for DestinationHealth {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "DestinationHealth {{ threshold: {} }}", self.threshold)
+    }
+}
+
+impl DestinationHealth {
+    /// Creates a tracker which treats a port as unreachable once it has failed `threshold`
+    /// times within a minute
+    pub fn new(threshold: u32) -> Self {
+        DestinationHealth {
+            destinations: Arc::new(Mutex::new(HashMap::new())),
+            threshold,
+        }
+    }
+
+    /// Creates a tracker using the service's `unreachable_threshold`
+    pub fn from_config(config: &CommsConfig) -> Self {
+        DestinationHealth::new(config.unreachable_threshold.unwrap_or(0))
+    }
+
+    /// Notes that a request sent to the given port was answered
+    pub fn record_success(&self, port: u16) {
+        if let Ok(mut destinations) = self.destinations.lock() {
+            let destination = destinations.entry(port).or_default();
+            destination.consecutive_failures = 0;
+            destination.failures.clear();
+        }
+    }
+
+    /// Notes that a request sent to the given port wasn't answered.
+    /// Returns the number of requests in a row which the port has failed to answer.
+    pub fn record_failure(&self, port: u16) -> u32 {
+        match self.destinations.lock() {
+            Ok(mut destinations) => {
+                let destination = destinations.entry(port).or_default();
+                destination.consecutive_failures += 1;
+                if self.threshold > 0 {
+                    while destination.failures.len() >= self.threshold as usize {
+                        destination.failures.pop_front();
+                    }
+                    destination.failures.push_back(Instant::now());
+                }
+                destination.consecutive_failures
+            }
+            Err(_) => 0,
+        }
+    }
+
+    /// Number of requests in a row which the given port has failed to answer
+    pub fn consecutive_failures(&self, port: u16) -> u32 {
+        self.destinations
+            .lock()
+            .ok()
+            .and_then(|destinations| {
+                destinations
+                    .get(&port)
+                    .map(|destination| destination.consecutive_failures)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Whether requests to the given port should be answered with an error rather than
+    /// passed on, because it has failed `threshold` times within the last minute
+    pub fn is_unreachable(&self, port: u16) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+
+        let mut destinations = match self.destinations.lock() {
+            Ok(destinations) => destinations,
+            Err(_) => return false,
+        };
+
+        match destinations.get_mut(&port) {
+            Some(destination) => {
+                while let Some(failure) = destination.failures.front() {
+                    if failure.elapsed() < FAILURE_WINDOW {
+                        break;
+                    }
+                    destination.failures.pop_front();
+                }
+                destination.failures.len() >= self.threshold as usize
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets all of the failures, so that requests are passed on to every port again
+    pub fn clear(&self) {
+        if let Ok(mut destinations) = self.destinations.lock() {
+            destinations.clear();
+        }
+    }
+}
+
+/// Builds the GraphQL error response sent to the ground in place of a request to an
+/// unreachable port
+pub fn unreachable_response(port: u16, consecutive_failures: u32) -> Vec<u8> {
+    format!(
+        r#"{{"data":null,"errors":[{{"message":"Service on port {} is unreachable ({} consecutive failures)"}}]}}"#,
+        port, consecutive_failures
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consecutive_failures_counted() {
+        let health = DestinationHealth::default();
+        health.record_failure(8000);
+        assert_eq!(health.record_failure(8000), 2);
+        assert_eq!(health.consecutive_failures(8001), 0);
+
+        health.record_success(8000);
+        assert_eq!(health.consecutive_failures(8000), 0);
+    }
+
+    #[test]
+    fn threshold_disabled() {
+        let health = DestinationHealth::new(0);
+        for _ in 0..10 {
+            health.record_failure(8000);
+        }
+
+        assert!(!health.is_unreachable(8000));
+    }
+
+    #[test]
+    fn unreachable_after_threshold() {
+        let health = DestinationHealth::new(3);
+        health.record_failure(8000);
+        health.record_failure(8000);
+        assert!(!health.is_unreachable(8000));
+
+        health.record_failure(8000);
+        assert!(health.is_unreachable(8000));
+        assert!(!health.is_unreachable(8001));
+
+        // Any answer means the service is back
+        health.record_success(8000);
+        assert!(!health.is_unreachable(8000));
+    }
+
+    #[test]
+    fn old_failures_ignored() {
+        let health = DestinationHealth::new(2);
+        health.record_failure(8000);
+        health.record_failure(8000);
+
+        // Age the failures past the window
+        {
+            let mut destinations = health.destinations.lock().unwrap();
+            let destination = destinations.get_mut(&8000).unwrap();
+            for failure in destination.failures.iter_mut() {
+                *failure -= FAILURE_WINDOW;
+            }
+        }
+
+        assert!(!health.is_unreachable(8000));
+        assert_eq!(health.consecutive_failures(8000), 2);
+    }
+
+    #[test]
+    fn unreachable_response_message() {
+        assert_eq!(
+            unreachable_response(8000, 3),
+            br#"{"data":null,"errors":[{"message":"Service on port 8000 is unreachable (3 consecutive failures)"}]}"#.to_vec()
+        );
+    }
+}
@@ -79,14 +79,42 @@ extern crate log;
 extern crate byteorder;
 extern crate failure;
 
+#[cfg(feature = "service")]
+mod cache;
+mod coalesce;
+mod compression;
 mod config;
+mod echo;
+#[cfg(feature = "service")]
+mod emulation;
+mod encryption;
 mod errors;
+mod fec;
+#[cfg(feature = "service")]
+mod flatsat;
+#[cfg(feature = "service")]
+mod health;
+#[cfg(feature = "service")]
+mod memory;
 mod packet;
 #[cfg(feature = "service")]
+mod pools;
+#[cfg(feature = "service")]
+mod ports;
+#[cfg(feature = "service")]
+mod queue;
+mod replay;
+#[cfg(feature = "service")]
+mod selftest;
+#[cfg(feature = "service")]
 mod service;
+#[cfg(feature = "service")]
+mod sizing;
 mod spacepacket;
 #[cfg(feature = "service")]
 mod telemetry;
+mod timecode;
+mod timetag;
 
 #[cfg(test)]
 mod tests;
@@ -100,11 +128,71 @@ pub use crate::errors::*;
 
 /// Communication Service telemetry.
 #[cfg(feature = "service")]
-pub use crate::telemetry::CommsTelemetry;
+pub use crate::telemetry::{
+    reset_telemetry, snapshot_telemetry, CommsTelemetry, DestinationTelemetry, ErrorCount,
+    ErrorRecord, SourceTelemetry, StationTelemetry, WriterTelemetry,
+};
 
 /// Communication Service configuration parsing.
 pub use crate::config::*;
 
+/// Communication Service query response caching.
+#[cfg(feature = "service")]
+pub use crate::cache::{ResponseCache, DEFAULT_CACHE_TTL};
+
+/// Communication Service destination health tracking.
+#[cfg(feature = "service")]
+pub use crate::health::DestinationHealth;
+
+/// Communication Service load balancing across service replicas.
+#[cfg(feature = "service")]
+pub use crate::pools::DestinationPools;
+
+/// Communication Service adaptive payload sizing.
+#[cfg(feature = "service")]
+pub use crate::sizing::{
+    parse_payload_size_answer, payload_size_answer, query_payload_size, PayloadSizing,
+    PAYLOAD_SIZE_ANSWER_SIZE,
+};
+
+/// Communication Service runtime port controls.
+#[cfg(feature = "service")]
+pub use crate::ports::PortControls;
+
+/// Communication Service memory accounting.
+#[cfg(feature = "service")]
+pub use crate::memory::{MemoryBudget, Reservation, RESPONSE_PRIORITY};
+
+/// Communication Service response compression.
+pub use crate::compression::{compress, decompress, Compression};
+
+/// Communication Service payload encryption.
+pub use crate::encryption::{KeySlotConfig, KeySlots, KEY_SIZE, MAX_KEY_SLOT, NO_KEY_SLOT};
+
+/// Communication Service forward error correction.
+pub use crate::fec::{Fec, FecDecoded, RS_BLOCK_SIZE, RS_DATA_SIZE, RS_PARITY_SIZE};
+
+/// Communication Service downlink coalescing.
+pub use crate::coalesce::{coalesce, split_coalesced, SUBFRAME_HEADER_SIZE};
+
+/// Communication Service link testing.
+pub use crate::echo::{echo_response, parse_echo_response, ECHO_HEADER_SIZE};
+
+/// Communication Service link emulation, for test harnesses.
+#[cfg(feature = "service")]
+pub use crate::emulation::{Latency, LinkConditions, LinkEmulation, LinkEmulator, LinkStats, Loss};
+
+/// Communication Service flatsat mode, for testing without a radio.
+#[cfg(feature = "service")]
+pub use crate::flatsat::{
+    FlatsatBridge, DEFAULT_FLATSAT_DOWNLINK_PORT, DEFAULT_FLATSAT_UPLINK_PORT, DEFAULT_GROUND_IP,
+};
+
 pub use packet::LinkPacket;
 pub use packet::PayloadType;
-pub use spacepacket::SpacePacket;
+pub use spacepacket::{ApidRoute, SpacePacket};
+pub use replay::{command_counter, parse_command_counter, COMMAND_COUNTER_SIZE};
+pub use timetag::{parse_time_tag, time_tag, TIME_TAG_HEADER_SIZE};
+
+/// Communication Service packet timestamps.
+pub use crate::timecode::{TimeCode, TimeStamp};
@@ -0,0 +1,225 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Memory accounting
+//!
+//! The communications service holds packets in memory while they wait to be downlinked. Each
+//! downlink port queues the packets it receives until its endpoint thread has written them, and
+//! message handlers hold the responses they receive from services while they're sent. A service
+//! which floods a downlink port could make these queues grow until the comms service is killed
+//! for running out of memory.
+//!
+//! If `memory_cap` is set, the bytes held by queued packets and responses are counted against
+//! it. Each piece of traffic has a priority: downlink ports use their `priority` setting, and
+//! responses to the ground's requests always have the highest priority. Traffic which would take
+//! the total over the cap is dropped. If lower priority traffic is holding memory at the time,
+//! the lowest priority of it is shed as well: its queued packets are dropped rather than
+//! downlinked, and new ones are refused, until the total falls back to half of the cap.
+
+use crate::config::CommsConfig;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Priority of the responses to the ground's requests
+pub const RESPONSE_PRIORITY: u8 = u8::MAX;
+
+#[derive(Default)]
+struct Usage {
+    // Total bytes held
+    held: usize,
+    // Bytes held by each priority
+    priorities: BTreeMap<u8, usize>,
+    // Traffic with a lower priority than this is being shed
+    shedding_below: u8,
+}
+
+/// Outcome of asking for memory to hold some traffic
+#[derive(Debug, PartialEq)]
+pub enum Reservation {
+    /// The memory has been reserved, and must be released once the traffic is sent
+    Granted,
+    /// The cap has been reached, so the traffic should be dropped
+    Refused,
+    /// The cap has been reached, so the traffic should be dropped, and traffic with the given
+    /// priority has started being shed to make room for it
+    Shedding(u8),
+}
+
+/// Bytes held by the communication service's queued traffic, shared between its threads
+///
+/// Clones refer to the same usage. A cap of zero means traffic is never dropped, and nothing
+/// is counted.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    usage: Arc<Mutex<Usage>>,
+    cap: usize,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget::new(0)
+    }
+}
+
+impl ::std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "MemoryBudget {{ cap: {} }}", self.cap)
+    }
+}
+
+impl MemoryBudget {
+    /// Creates a budget which holds no more than `cap` bytes of traffic at once
+    pub fn new(cap: usize) -> Self {
+        MemoryBudget {
+            usage: Arc::new(Mutex::new(Usage::default())),
+            cap,
+        }
+    }
+
+    /// Creates a budget using the service's `memory_cap`
+    pub fn from_config(config: &CommsConfig) -> Self {
+        MemoryBudget::new(config.memory_cap.unwrap_or(0))
+    }
+
+    /// Asks for memory to hold `bytes` of traffic with the given priority
+    pub fn reserve(&self, bytes: usize, priority: u8) -> Reservation {
+        if self.cap == 0 {
+            return Reservation::Granted;
+        }
+
+        let mut usage = match self.usage.lock() {
+            Ok(usage) => usage,
+            Err(_) => return Reservation::Refused,
+        };
+
+        if priority < usage.shedding_below {
+            return Reservation::Refused;
+        }
+
+        if usage.held + bytes <= self.cap {
+            usage.held += bytes;
+            *usage.priorities.entry(priority).or_insert(0) += bytes;
+            return Reservation::Granted;
+        }
+
+        // Make room by shedding the lowest priority traffic which isn't already being shed
+        let lowest = usage
+            .priorities
+            .range(usage.shedding_below..priority)
+            .next()
+            .map(|(lowest, _)| *lowest);
+        match lowest {
+            Some(lowest) => {
+                usage.shedding_below = lowest + 1;
+                Reservation::Shedding(lowest)
+            }
+            None => Reservation::Refused,
+        }
+    }
+
+    /// Returns memory reserved for traffic which has been sent or dropped
+    pub fn release(&self, bytes: usize, priority: u8) {
+        if self.cap == 0 {
+            return;
+        }
+
+        if let Ok(mut usage) = self.usage.lock() {
+            usage.held = usage.held.saturating_sub(bytes);
+            let emptied = match usage.priorities.get_mut(&priority) {
+                Some(held) => {
+                    *held = held.saturating_sub(bytes);
+                    *held == 0
+                }
+                None => false,
+            };
+            if emptied {
+                usage.priorities.remove(&priority);
+            }
+
+            if usage.shedding_below > 0 && usage.held <= self.cap / 2 {
+                info!("Memory use is back under half of the cap, no longer shedding traffic");
+                usage.shedding_below = 0;
+            }
+        }
+    }
+
+    /// Whether traffic with the given priority is being shed, so any of it which is already
+    /// queued should be dropped
+    pub fn is_shedding(&self, priority: u8) -> bool {
+        self.usage
+            .lock()
+            .map(|usage| priority < usage.shedding_below)
+            .unwrap_or(false)
+    }
+
+    /// Total bytes currently held
+    pub fn held(&self) -> usize {
+        self.usage.lock().map(|usage| usage.held).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncapped() {
+        let memory = MemoryBudget::new(0);
+        assert_eq!(memory.reserve(1 << 30, 0), Reservation::Granted);
+        assert_eq!(memory.held(), 0);
+    }
+
+    #[test]
+    fn refused_over_cap() {
+        let memory = MemoryBudget::new(100);
+        assert_eq!(memory.reserve(60, 1), Reservation::Granted);
+        assert_eq!(memory.reserve(60, 1), Reservation::Refused);
+        assert_eq!(memory.reserve(60, 0), Reservation::Refused);
+        assert!(!memory.is_shedding(0));
+
+        memory.release(60, 1);
+        assert_eq!(memory.held(), 0);
+        assert_eq!(memory.reserve(60, 1), Reservation::Granted);
+    }
+
+    #[test]
+    fn sheds_lowest_priority() {
+        let memory = MemoryBudget::new(100);
+        assert_eq!(memory.reserve(30, 0), Reservation::Granted);
+        assert_eq!(memory.reserve(30, 1), Reservation::Granted);
+        assert_eq!(memory.reserve(30, 1), Reservation::Granted);
+
+        // Only the lowest priority is shed to make room for a response
+        assert_eq!(
+            memory.reserve(40, RESPONSE_PRIORITY),
+            Reservation::Shedding(0)
+        );
+        assert!(memory.is_shedding(0));
+        assert!(!memory.is_shedding(1));
+        assert_eq!(memory.reserve(10, 0), Reservation::Refused);
+
+        // Dropping the shed packets makes room
+        memory.release(30, 0);
+        assert_eq!(memory.reserve(40, RESPONSE_PRIORITY), Reservation::Granted);
+        assert!(memory.is_shedding(0));
+
+        // Once usage falls back to half of the cap, nothing is shed
+        memory.release(40, RESPONSE_PRIORITY);
+        memory.release(30, 1);
+        assert!(!memory.is_shedding(0));
+        assert_eq!(memory.reserve(10, 0), Reservation::Granted);
+    }
+}
@@ -16,8 +16,10 @@
 
 //! Link layer definitions used by the communications service
 
+use crate::compression::Compression;
 use crate::CommsResult;
 use serde::Deserialize;
+use std::time::SystemTime;
 
 /// Enum representing the different payload types handled
 /// by the communications service
@@ -30,6 +32,15 @@ pub enum PayloadType {
     UDP,
     /// Packet intended for UDP passthrough and streaming
     UDPDlStream,
+    /// Packet carrying a command to be released at a later time
+    /// (see the [`time_tag`](fn.time_tag.html) function)
+    TimeTagged,
+    /// Packet answered by the communications service itself, for testing the link
+    /// (see the [`echo_response`](fn.echo_response.html) function)
+    Echo,
+    /// Packet holding several packets from the same downlink port
+    /// (see the [`coalesce`](fn.coalesce.html) function)
+    Coalesced,
     /// Unknown type
     Unknown(u16),
 }
@@ -40,6 +51,9 @@ impl From<u16> for PayloadType {
             0 => PayloadType::GraphQL,
             1 => PayloadType::UDP,
             2 => PayloadType::UDPDlStream,
+            3 => PayloadType::TimeTagged,
+            4 => PayloadType::Echo,
+            5 => PayloadType::Coalesced,
             other => PayloadType::Unknown(other),
         }
     }
@@ -51,6 +65,9 @@ impl From<PayloadType> for u16 {
             PayloadType::GraphQL => 0,
             PayloadType::UDP => 1,
             PayloadType::UDPDlStream => 2,
+            PayloadType::TimeTagged => 3,
+            PayloadType::Echo => 4,
+            PayloadType::Coalesced => 5,
             PayloadType::Unknown(value) => value as u16,
         }
     }
@@ -68,6 +85,18 @@ pub trait LinkPacket {
         destination_port: u16,
         payload: &[u8],
     ) -> CommsResult<Box<Self>>;
+    /// Build packet tagged with a ground station ID
+    ///
+    /// Link layers which can't carry a station ID ignore it
+    fn build_for_station(
+        command_id: u64,
+        link_type: PayloadType,
+        destination_port: u16,
+        payload: &[u8],
+        _station_id: u8,
+    ) -> CommsResult<Box<Self>> {
+        Self::build(command_id, link_type, destination_port, payload)
+    }
     /// Create a bytes representation of the packet
     fn to_bytes(&self) -> CommsResult<Vec<u8>>;
     /// The Command ID of the packet
@@ -78,6 +107,64 @@ pub trait LinkPacket {
     fn payload_type(&self) -> PayloadType;
     /// The Destination port of the packet
     fn destination(&self) -> u16;
+    /// Destination port to give responses to this packet.
+    /// Zero (the default) means responses aren't addressed to any port
+    ///
+    /// Link layers which route by something other than port (eg. SpacePacket APID routes)
+    /// use this to address responses back to the packet's route
+    fn response_port(&self) -> u16 {
+        0
+    }
+    /// The ground station the packet came from (uplink) or is intended for (downlink).
+    /// Zero means no specific station
+    fn station_id(&self) -> u8 {
+        0
+    }
+    /// Compression requested for the response to this packet (uplink) or
+    /// applied to this packet's payload (downlink)
+    ///
+    /// Link layers which can't carry a compression flag never request compression
+    fn compression(&self) -> Compression {
+        Compression::None
+    }
+    /// Flag the packet's payload as compressed with the given algorithm.
+    /// Fails, leaving the packet as it was, if the packet would no longer fit the link layer
+    fn set_compression(&mut self, _compression: Compression) -> CommsResult<()> {
+        Ok(())
+    }
+    /// Key slot of the key this packet's payload is encrypted with.
+    /// Zero means the payload isn't encrypted
+    ///
+    /// Link layers which can't carry a key slot never carry encrypted payloads
+    fn key_slot(&self) -> u8 {
+        0
+    }
+    /// Flag the packet's payload as encrypted with the key in the given slot.
+    /// Fails, leaving the packet as it was, if the packet would no longer fit the link layer
+    fn set_key_slot(&mut self, _slot: u8) -> CommsResult<()> {
+        Ok(())
+    }
+    /// Quality of service of the packet. Packets with a higher QoS are downlinked first.
+    /// Zero is the lowest, for bulk traffic
+    ///
+    /// Link layers which can't carry a QoS treat every packet as bulk traffic
+    fn qos(&self) -> u8 {
+        0
+    }
+    /// Set the packet's quality of service
+    ///
+    /// Link layers which carry fewer levels clamp it to the highest they can carry. Fails,
+    /// leaving the packet as it was, if the packet would no longer fit the link layer
+    fn set_qos(&mut self, _qos: u8) -> CommsResult<()> {
+        Ok(())
+    }
+    /// Time the packet was built, so the ground can tell when downlinked data was generated
+    /// rather than when it was received
+    ///
+    /// Link layers which can't carry a timestamp never have one
+    fn timestamp(&self) -> Option<SystemTime> {
+        None
+    }
     /// Validate the contents of the link packet
     fn validate(&self) -> bool {
         true
@@ -0,0 +1,175 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Load balancing of GraphQL requests across service replicas
+//!
+//! A stateless service (such as a query worker for the telemetry database) can be run as several
+//! replicas, each listening on its own local port, to answer more requests at once. The ground
+//! keeps addressing the service's usual port, which the `destination_pools` config maps to the
+//! replicas' ports. Requests to the port are passed to each replica in turn.
+//!
+//! Replicas which the destination health tracking finds unreachable are skipped until they
+//! recover. If every replica in a pool is unreachable, the request is answered with the usual
+//! unreachable error. Cached responses are kept for the pool's port rather than each replica's,
+//! since any replica would give the same answer.
+
+use crate::config::{CommsConfig, DestinationPool};
+use crate::errors::*;
+use crate::health::DestinationHealth;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Pool {
+    replicas: Vec<u16>,
+    // Index of the replica which is next in turn
+    next: AtomicUsize,
+}
+
+/// Replicas of the services which GraphQL requests are passed to, shared between the
+/// message handlers
+///
+/// Clones share their place in each pool's rotation. Ports without a pool are passed on
+/// to themselves.
+#[derive(Clone, Default)]
+pub struct DestinationPools {
+    pools: Arc<HashMap<u16, Pool>>,
+}
+
+impl ::std::fmt::Debug for DestinationPools {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let mut ports: Vec<(&u16, &Vec<u16>)> = self
+            .pools
+            .iter()
+            .map(|(port, pool)| (port, &pool.replicas))
+            .collect();
+        ports.sort();
+        write!(f, "DestinationPools {{ pools: {:?} }}", ports)
+    }
+}
+
+impl DestinationPools {
+    /// Creates the pools from their configs. Each port may only have one pool, and each pool
+    /// needs at least one replica
+    pub fn new(configs: &[DestinationPool]) -> CommsResult<Self> {
+        let mut pools = HashMap::new();
+        for config in configs {
+            if config.replicas.is_empty() {
+                return Err(CommsServiceError::ConfigError(format!(
+                    "Destination pool for port {} has no replicas",
+                    config.port
+                ))
+                .into());
+            }
+
+            let pool = Pool {
+                replicas: config.replicas.clone(),
+                next: AtomicUsize::new(0),
+            };
+            if pools.insert(config.port, pool).is_some() {
+                return Err(CommsServiceError::ConfigError(format!(
+                    "Port {} has more than one destination pool",
+                    config.port
+                ))
+                .into());
+            }
+        }
+
+        Ok(DestinationPools {
+            pools: Arc::new(pools),
+        })
+    }
+
+    /// Creates the pools from the service's `destination_pools`
+    pub fn from_config(config: &CommsConfig) -> CommsResult<Self> {
+        DestinationPools::new(config.destination_pools.as_ref().map_or(&[], |pools| pools))
+    }
+
+    /// Picks the local port a GraphQL request addressed to the given port is passed to.
+    /// Replicas are taken in turn, skipping any which are unreachable, unless they all are.
+    pub fn pick(&self, port: u16, health: &DestinationHealth) -> u16 {
+        let pool = match self.pools.get(&port) {
+            Some(pool) => pool,
+            None => return port,
+        };
+
+        let len = pool.replicas.len();
+        let first = pool.next.fetch_add(1, Ordering::SeqCst) % len;
+        let index = (0..len)
+            .map(|offset| (first + offset) % len)
+            .find(|index| !health.is_unreachable(pool.replicas[*index]))
+            .unwrap_or(first);
+        if index != first {
+            // The replicas which were skipped don't get another turn straight away
+            pool.next.store(index + 1, Ordering::SeqCst);
+        }
+        pool.replicas[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pools() -> DestinationPools {
+        DestinationPools::new(&[DestinationPool {
+            port: 8006,
+            replicas: vec![8106, 8206, 8306],
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn round_robin() {
+        let pools = pools();
+        let health = DestinationHealth::default();
+        let picked: Vec<u16> = (0..4).map(|_| pools.pick(8006, &health)).collect();
+        assert_eq!(picked, vec![8106, 8206, 8306, 8106]);
+
+        // Ports without a pool are passed on as they are
+        assert_eq!(pools.pick(8007, &health), 8007);
+    }
+
+    #[test]
+    fn unreachable_replicas_skipped() {
+        let pools = pools();
+        let health = DestinationHealth::new(1);
+        health.record_failure(8206);
+        let picked: Vec<u16> = (0..4).map(|_| pools.pick(8006, &health)).collect();
+        assert_eq!(picked, vec![8106, 8306, 8106, 8306]);
+
+        // With every replica unreachable, they're still taken in turn
+        health.record_failure(8106);
+        health.record_failure(8306);
+        let picked: Vec<u16> = (0..3).map(|_| pools.pick(8006, &health)).collect();
+        assert_eq!(picked, vec![8106, 8206, 8306]);
+    }
+
+    #[test]
+    fn invalid_pools() {
+        assert!(DestinationPools::new(&[DestinationPool {
+            port: 8006,
+            replicas: vec![],
+        }])
+        .is_err());
+
+        let pool = DestinationPool {
+            port: 8006,
+            replicas: vec![8106],
+        };
+        assert!(DestinationPools::new(&[pool.clone(), pool]).is_err());
+    }
+}
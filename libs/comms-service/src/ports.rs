@@ -0,0 +1,425 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Runtime port controls
+//!
+//! Individual downlink ports, and the uplink read path, can be disabled while the service is
+//! running, for example to keep the transmitter quiet during RF-sensitive payload operations.
+//! Packets sent to a disabled downlink port are dropped rather than queued, as are any of its
+//! packets which were already waiting to be downlinked. While the uplink is disabled, frames are
+//! still read from the gateway, so they don't back up in the radio, but are dropped unparsed.
+//!
+//! If `port_state_file` is set, the disabled ports are kept in it, so they stay disabled across
+//! restarts. Disabling the uplink cuts the ground off from the service, so it can be given a
+//! duration after which the uplink is enabled again by itself.
+
+use crate::config::CommsConfig;
+use crate::errors::*;
+use crate::telemetry::CommsTelemetry;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Which ports are disabled, saved to a file with an "uplink" or "uplink {until}" line if the
+// uplink is disabled, and a "downlink {port}" line for each disabled downlink port
+#[derive(Clone, Debug, Default, PartialEq)]
+struct PortState {
+    uplink_disabled: bool,
+    // Time the uplink is enabled again at, in seconds since the UNIX epoch
+    uplink_until: Option<u64>,
+    downlink_disabled: BTreeSet<u16>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: PortState,
+    telem: Option<Arc<Mutex<CommsTelemetry>>>,
+}
+
+/// Switches for the uplink read path and each downlink port, shared between the service's
+/// threads
+///
+/// Clones refer to the same switches. Everything is enabled unless a previous run left ports
+/// disabled in the `port_state_file`.
+#[derive(Clone, Default)]
+pub struct PortControls {
+    inner: Arc<Mutex<Inner>>,
+    // Downlink ports which the service was configured with
+    ports: Arc<BTreeSet<u16>>,
+    path: Option<PathBuf>,
+}
+
+impl ::std::fmt::Debug for PortControls {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let state = self
+            .lock()
+            .map(|inner| inner.state.clone())
+            .unwrap_or_default();
+        write!(
+            f,
+            "PortControls {{ uplink_disabled: {}, downlink_disabled: {:?}, path: {:?} }}",
+            state.uplink_disabled, state.downlink_disabled, self.path
+        )
+    }
+}
+
+impl PortControls {
+    /// Creates the switches for the service's downlink ports, restoring the ports left disabled
+    /// in its `port_state_file`
+    ///
+    /// A state file which can't be read is logged and ignored, rather than stopping the
+    /// service, since that would cut the ground off entirely.
+    pub fn from_config(config: &CommsConfig) -> Self {
+        let ports = config
+            .downlink_ports
+            .iter()
+            .flatten()
+            .map(|port| port.port)
+            .collect::<BTreeSet<u16>>();
+        let path = config.port_state_file.as_ref().map(PathBuf::from);
+
+        let mut state = PortState::default();
+        if let Some(ref path) = path {
+            if path.exists() {
+                match fs::read_to_string(path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|contents| parse_state(&contents))
+                {
+                    Ok(saved) => state = saved,
+                    Err(err) => warn!("Ignoring port state file {}: {}", path.display(), err),
+                }
+            }
+        }
+        // Ports which are no longer configured can't be enabled again, so they're forgotten
+        state.downlink_disabled.retain(|port| ports.contains(port));
+
+        if state.uplink_disabled {
+            warn!("Uplink is disabled");
+        }
+        for port in state.downlink_disabled.iter() {
+            warn!("Downlink port {} is disabled", port);
+        }
+
+        PortControls {
+            inner: Arc::new(Mutex::new(Inner { state, telem: None })),
+            ports: Arc::new(ports),
+            path,
+        }
+    }
+
+    /// Whether frames read from the gateway are passed on. The uplink is enabled again by
+    /// itself once the duration it was disabled for has passed
+    pub fn uplink_enabled(&self) -> bool {
+        let mut inner = match self.lock() {
+            Ok(inner) => inner,
+            Err(_) => return true,
+        };
+        if !inner.state.uplink_disabled {
+            return true;
+        }
+
+        match inner.state.uplink_until {
+            Some(until) if now() >= until => {
+                info!("Uplink enabled after disabled duration passed");
+                let mut updated = inner.state.clone();
+                updated.uplink_disabled = false;
+                updated.uplink_until = None;
+                // The uplink must come back even if the file can't be written
+                if let Err(err) = self.update(&mut inner, updated) {
+                    warn!("Failed to save port state: {}", err);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops passing on frames read from the gateway, for the given duration if any
+    pub fn disable_uplink(&self, duration: Option<Duration>) -> CommsResult<()> {
+        let mut inner = self.lock()?;
+        let mut updated = inner.state.clone();
+        updated.uplink_disabled = true;
+        updated.uplink_until = duration.map(|duration| now() + duration.as_secs());
+        self.update(&mut inner, updated)?;
+        match duration {
+            Some(duration) => info!("Uplink disabled for {}s", duration.as_secs()),
+            None => info!("Uplink disabled"),
+        }
+        Ok(())
+    }
+
+    /// Starts passing on frames read from the gateway again
+    pub fn enable_uplink(&self) -> CommsResult<()> {
+        let mut inner = self.lock()?;
+        let mut updated = inner.state.clone();
+        updated.uplink_disabled = false;
+        updated.uplink_until = None;
+        self.update(&mut inner, updated)?;
+        info!("Uplink enabled");
+        Ok(())
+    }
+
+    /// Whether packets sent to the given downlink port are downlinked
+    pub fn downlink_enabled(&self, port: u16) -> bool {
+        self.lock()
+            .map(|inner| !inner.state.downlink_disabled.contains(&port))
+            .unwrap_or(true)
+    }
+
+    /// Enables or disables one of the service's downlink ports
+    pub fn set_downlink_enabled(&self, port: u16, enabled: bool) -> CommsResult<()> {
+        if !self.ports.contains(&port) {
+            return Err(CommsServiceError::UnknownDownlinkPort(port).into());
+        }
+
+        let mut inner = self.lock()?;
+        let mut updated = inner.state.clone();
+        if enabled {
+            updated.downlink_disabled.remove(&port);
+        } else {
+            updated.downlink_disabled.insert(port);
+        }
+        self.update(&mut inner, updated)?;
+        info!(
+            "Downlink port {} {}",
+            port,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    /// Downlink ports which are currently disabled, lowest first
+    pub fn disabled_downlink_ports(&self) -> Vec<u16> {
+        self.lock()
+            .map(|inner| inner.state.downlink_disabled.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Keeps the service's telemetry showing which ports are disabled
+    pub(crate) fn attach_telemetry(&self, telem: &Arc<Mutex<CommsTelemetry>>) {
+        if let Ok(mut inner) = self.lock() {
+            inner.telem = Some(telem.clone());
+            log_state(&inner);
+        }
+    }
+
+    fn lock(&self) -> CommsResult<MutexGuard<'_, Inner>> {
+        self.inner
+            .lock()
+            .map_err(|_| CommsServiceError::MutexPoisoned.into())
+    }
+
+    // The new state is saved before it's used, so a reset can't undo the change
+    fn update(&self, inner: &mut Inner, updated: PortState) -> CommsResult<()> {
+        if let Some(ref path) = self.path {
+            let mut contents = String::new();
+            if updated.uplink_disabled {
+                match updated.uplink_until {
+                    Some(until) => contents.push_str(&format!("uplink {}\n", until)),
+                    None => contents.push_str("uplink\n"),
+                }
+            }
+            for port in updated.downlink_disabled.iter() {
+                contents.push_str(&format!("downlink {}\n", port));
+            }
+
+            // Write to a temporary file first, so a reset mid-write can't lose the state
+            let temp = path.with_extension("tmp");
+            fs::write(&temp, contents)?;
+            fs::rename(&temp, path)?;
+        }
+
+        inner.state = updated;
+        log_state(inner);
+        Ok(())
+    }
+}
+
+fn log_state(inner: &Inner) {
+    if let Some(ref telem) = inner.telem {
+        if let Ok(mut telem) = telem.lock() {
+            telem.uplink_disabled = inner.state.uplink_disabled;
+            telem.uplink_disabled_until = inner.state.uplink_until.map(|until| until as f64);
+            telem.disabled_downlink_ports = inner
+                .state
+                .downlink_disabled
+                .iter()
+                .map(|port| i32::from(*port))
+                .collect();
+        }
+    }
+}
+
+fn parse_state(contents: &str) -> Result<PortState, String> {
+    let mut state = PortState::default();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        parse_line(&mut state, line).ok_or_else(|| format!("Invalid line: {}", line))?;
+    }
+    Ok(state)
+}
+
+fn parse_line(state: &mut PortState, line: &str) -> Option<()> {
+    let mut fields = line.split_whitespace();
+    match (fields.next()?, fields.next(), fields.next()) {
+        ("uplink", until, None) => {
+            state.uplink_until = match until {
+                Some(until) => Some(until.parse().ok()?),
+                None => None,
+            };
+            state.uplink_disabled = true;
+        }
+        ("downlink", Some(port), None) => {
+            state.downlink_disabled.insert(port.parse().ok()?);
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(path: Option<&PathBuf>) -> CommsConfig {
+        let state_file = path.map_or(String::new(), |path| {
+            format!("port_state_file = \"{}\"", path.display())
+        });
+        let raw = format!(
+            r#"
+            [comms-service.comms]
+            ip = "0.0.0.0"
+            {}
+
+            [[comms-service.comms.downlink_ports]]
+            port = 14011
+
+            [[comms-service.comms.downlink_ports]]
+            port = 14012
+            "#,
+            state_file
+        );
+        let config = kubos_system::Config::new_from_str("comms-service", &raw).unwrap();
+        CommsConfig::new(config).unwrap()
+    }
+
+    #[test]
+    fn enabled_by_default() {
+        let ports = PortControls::from_config(&config(None));
+        assert!(ports.uplink_enabled());
+        assert!(ports.downlink_enabled(14011));
+        assert!(ports.disabled_downlink_ports().is_empty());
+    }
+
+    #[test]
+    fn disable_and_enable_downlink() {
+        let ports = PortControls::from_config(&config(None));
+        ports.set_downlink_enabled(14012, false).unwrap();
+        assert!(ports.downlink_enabled(14011));
+        assert!(!ports.downlink_enabled(14012));
+        assert_eq!(ports.disabled_downlink_ports(), vec![14012]);
+
+        ports.set_downlink_enabled(14012, true).unwrap();
+        assert!(ports.downlink_enabled(14012));
+    }
+
+    #[test]
+    fn unknown_downlink_port() {
+        let ports = PortControls::from_config(&config(None));
+        let err = ports.set_downlink_enabled(14013, false).unwrap_err();
+        assert_eq!(
+            err.downcast::<CommsServiceError>().unwrap(),
+            CommsServiceError::UnknownDownlinkPort(14013)
+        );
+    }
+
+    #[test]
+    fn uplink_reenabled_after_duration() {
+        let ports = PortControls::from_config(&config(None));
+        ports.disable_uplink(None).unwrap();
+        assert!(!ports.uplink_enabled());
+
+        ports.disable_uplink(Some(Duration::from_secs(0))).unwrap();
+        assert!(ports.uplink_enabled());
+        assert!(ports.uplink_enabled());
+    }
+
+    #[test]
+    fn state_shown_in_telemetry() {
+        let telem = Arc::new(Mutex::new(CommsTelemetry::default()));
+        let ports = PortControls::from_config(&config(None));
+        ports.attach_telemetry(&telem);
+        ports.set_downlink_enabled(14011, false).unwrap();
+        ports.disable_uplink(None).unwrap();
+
+        // The state isn't a count, so it's kept when the telemetry is reset
+        let reset = telem.lock().unwrap().reset();
+        assert!(reset.uplink_disabled);
+        let telem = telem.lock().unwrap();
+        assert!(telem.uplink_disabled);
+        assert_eq!(telem.uplink_disabled_until, None);
+        assert_eq!(telem.disabled_downlink_ports, vec![14011]);
+    }
+
+    #[test]
+    fn state_kept_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ports");
+
+        let ports = PortControls::from_config(&config(Some(&path)));
+        ports.set_downlink_enabled(14011, false).unwrap();
+        ports
+            .disable_uplink(Some(Duration::from_secs(3600)))
+            .unwrap();
+
+        let restarted = PortControls::from_config(&config(Some(&path)));
+        assert!(!restarted.uplink_enabled());
+        assert!(!restarted.downlink_enabled(14011));
+        assert!(restarted.downlink_enabled(14012));
+    }
+
+    #[test]
+    fn invalid_state_file_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ports");
+        fs::write(&path, "downlink 14011\nsideways\n").unwrap();
+
+        let ports = PortControls::from_config(&config(Some(&path)));
+        assert!(ports.downlink_enabled(14011));
+    }
+
+    #[test]
+    fn parse_saved_state() {
+        let state = parse_state("uplink 1600000000\ndownlink 14011\n\ndownlink 14012\n").unwrap();
+        assert!(state.uplink_disabled);
+        assert_eq!(state.uplink_until, Some(1_600_000_000));
+        assert_eq!(
+            state.downlink_disabled.into_iter().collect::<Vec<u16>>(),
+            vec![14011, 14012]
+        );
+
+        assert!(parse_state("uplink soon").is_err());
+        assert!(parse_state("downlink").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Downlink queue
+//!
+//! Packets received by all of the downlink ports wait in one queue until they're written, so that
+//! urgent traffic (eg. emergency telemetry) is downlinked ahead of bulk traffic whichever port it
+//! was sent to. Packets are taken highest QoS first, and in the order they arrived within a QoS.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex};
+
+struct Queued<T> {
+    qos: u8,
+    // Order the packet arrived in
+    sequence: u64,
+    item: T,
+}
+
+impl<T> Ord for Queued<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Earlier packets come first within a QoS
+        self.qos
+            .cmp(&other.qos)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+struct Pending<T> {
+    heap: BinaryHeap<Queued<T>>,
+    next_sequence: u64,
+}
+
+/// Packets waiting to be downlinked, shared between the downlink endpoints
+///
+/// Clones refer to the same queue.
+pub struct DownlinkQueue<T> {
+    pending: Arc<(Mutex<Pending<T>>, Condvar)>,
+}
+
+impl<T> Clone for DownlinkQueue<T> {
+    fn clone(&self) -> Self {
+        DownlinkQueue {
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<T> Default for DownlinkQueue<T> {
+    fn default() -> Self {
+        DownlinkQueue {
+            pending: Arc::new((
+                Mutex::new(Pending {
+                    heap: BinaryHeap::new(),
+                    next_sequence: 0,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+}
+
+impl<T> DownlinkQueue<T> {
+    /// Adds a packet with the given QoS to the queue
+    pub fn push(&self, qos: u8, item: T) {
+        let (lock, ready) = &*self.pending;
+        let mut pending = lock.lock().unwrap();
+        let sequence = pending.next_sequence;
+        pending.next_sequence += 1;
+        pending.heap.push(Queued {
+            qos,
+            sequence,
+            item,
+        });
+        ready.notify_one();
+    }
+
+    /// Takes the next packet to downlink, waiting for one if the queue is empty
+    pub fn pop(&self) -> T {
+        let (lock, ready) = &*self.pending;
+        let mut pending = lock.lock().unwrap();
+        loop {
+            if let Some(queued) = pending.heap.pop() {
+                return queued.item;
+            }
+            pending = ready.wait(pending).unwrap();
+        }
+    }
+
+    /// Takes the next packet which `matches`, without waiting, if `accept` agrees to take it.
+    /// Packets which don't match are left where they are, as is the next match if it isn't
+    /// accepted, so the order packets are taken in doesn't change.
+    pub fn try_pop_next<M, A>(&self, matches: M, accept: A) -> Option<T>
+    where
+        M: Fn(&T) -> bool,
+        A: FnOnce(&T) -> bool,
+    {
+        let (lock, _) = &*self.pending;
+        let mut pending = lock.lock().unwrap();
+        let mut queued = std::mem::take(&mut pending.heap).into_vec();
+        let next = queued
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| matches(&candidate.item))
+            .max_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(index, _)| index)
+            .filter(|index| accept(&queued[*index].item));
+        let item = next.map(|index| queued.swap_remove(index).item);
+        pending.heap = BinaryHeap::from(queued);
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn highest_qos_first() {
+        let queue = DownlinkQueue::default();
+        queue.push(0, "bulk 1");
+        queue.push(0, "bulk 2");
+        queue.push(3, "emergency");
+        queue.push(1, "housekeeping");
+        queue.push(0, "bulk 3");
+
+        let order: Vec<&str> = (0..5).map(|_| queue.pop()).collect();
+        assert_eq!(
+            order,
+            vec!["emergency", "housekeeping", "bulk 1", "bulk 2", "bulk 3"]
+        );
+    }
+
+    #[test]
+    fn pop_waits() {
+        let queue = DownlinkQueue::default();
+        let queue_ref = queue.clone();
+        let popped = thread::spawn(move || queue_ref.pop());
+        queue.push(0, 7);
+        assert_eq!(popped.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn try_pop_next_in_order() {
+        let queue = DownlinkQueue::default();
+        queue.push(0, "a 1");
+        queue.push(0, "b 1");
+        queue.push(2, "a 2");
+        queue.push(0, "a 3");
+
+        let is_a = |item: &&str| item.starts_with('a');
+        assert_eq!(queue.try_pop_next(is_a, |_| true), Some("a 2"));
+        // A match which isn't accepted keeps its place
+        assert_eq!(queue.try_pop_next(is_a, |item| *item != "a 1"), None);
+        assert_eq!(queue.try_pop_next(is_a, |_| true), Some("a 1"));
+        assert_eq!(queue.try_pop_next(is_a, |_| true), Some("a 3"));
+        assert_eq!(queue.try_pop_next(is_a, |_| true), None);
+        assert_eq!(queue.pop(), "b 1");
+    }
+}
@@ -0,0 +1,217 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Uplink command counters
+//!
+//! When replay protection is turned on (by setting `command_counter_file`), the payload of
+//! every uplinked packet starts with a command counter:
+//!
+//! | Field           | Size    | Description                                         |
+//! |-----------------|---------|-----------------------------------------------------|
+//! | Command counter | 8 bytes | Higher than any counter the station has sent before |
+//!
+//! followed by the packet's usual payload. The counter is big-endian. It's inside the payload,
+//! so it's covered by payload encryption and can't be altered without the key.
+//!
+//! Each ground station keeps its own counter. A packet whose counter isn't higher than the last
+//! one accepted from its station is a replay and is dropped. Counters may skip values, so
+//! packets lost on the way up don't matter. The last accepted counters are kept in the
+//! `command_counter_file`, so a reboot doesn't open a window for replays.
+
+use crate::errors::*;
+use byteorder::{BigEndian, ByteOrder};
+
+/// Size of the command counter at the start of an uplinked packet's payload
+pub const COMMAND_COUNTER_SIZE: usize = 8;
+
+/// Builds the payload of an uplinked packet, prefixed with its command counter
+///
+/// # Arguments
+///
+/// - counter - The station's next command counter
+/// - payload - The packet's usual payload
+pub fn command_counter(counter: u64, payload: &[u8]) -> Vec<u8> {
+    let mut counted = vec![0; COMMAND_COUNTER_SIZE];
+    BigEndian::write_u64(&mut counted, counter);
+    counted.extend_from_slice(payload);
+    counted
+}
+
+/// Splits the payload of an uplinked packet into its command counter and usual payload
+pub fn parse_command_counter(payload: &[u8]) -> CommsResult<(u64, &[u8])> {
+    if payload.len() < COMMAND_COUNTER_SIZE {
+        return Err(CommsServiceError::ParsingError(format!(
+            "Command counter needs {} bytes, got {}",
+            COMMAND_COUNTER_SIZE,
+            payload.len()
+        ))
+        .into());
+    }
+
+    Ok((
+        BigEndian::read_u64(&payload[0..COMMAND_COUNTER_SIZE]),
+        &payload[COMMAND_COUNTER_SIZE..],
+    ))
+}
+
+#[cfg(feature = "service")]
+pub use self::store::CommandCounters;
+
+#[cfg(feature = "service")]
+mod store {
+    use crate::errors::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    // Last command counter accepted from each ground station, saved to a file with one
+    // "{station_id} {counter}" line per station
+    #[derive(Clone)]
+    pub struct CommandCounters {
+        path: PathBuf,
+        last: Arc<Mutex<BTreeMap<u8, u64>>>,
+    }
+
+    impl CommandCounters {
+        // Loads the counters saved by a previous run. A file which can't be read is an error
+        // rather than a fresh start, which would let old commands be replayed
+        pub fn open(path: &str) -> CommsResult<Self> {
+            let path = PathBuf::from(path);
+            let mut last = BTreeMap::new();
+            if path.exists() {
+                let contents = fs::read_to_string(&path).map_err(|err| {
+                    CommsServiceError::ConfigError(format!(
+                        "Failed to read command counter file {}: {}",
+                        path.display(),
+                        err
+                    ))
+                })?;
+                for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                    let (station_id, counter) = parse_line(line).ok_or_else(|| {
+                        CommsServiceError::ConfigError(format!(
+                            "Invalid line in command counter file {}: {}",
+                            path.display(),
+                            line
+                        ))
+                    })?;
+                    last.insert(station_id, counter);
+                }
+            }
+
+            Ok(CommandCounters {
+                path,
+                last: Arc::new(Mutex::new(last)),
+            })
+        }
+
+        // Accepts a command counter from a station if it's higher than the last one accepted.
+        // The new counter is saved before it's accepted, so the command can't be replayed after
+        // a reset
+        pub fn check(&self, station_id: u8, counter: u64) -> CommsResult<()> {
+            let mut last = self
+                .last
+                .lock()
+                .map_err(|_| CommsServiceError::MutexPoisoned)?;
+            if let Some(&previous) = last.get(&station_id) {
+                if counter <= previous {
+                    return Err(
+                        CommsServiceError::ReplayedCommand(station_id, counter, previous).into(),
+                    );
+                }
+            }
+
+            let mut updated = last.clone();
+            updated.insert(station_id, counter);
+            self.save(&updated)?;
+            *last = updated;
+            Ok(())
+        }
+
+        // Write to a temporary file first, so a reset mid-write can't lose the counters
+        fn save(&self, last: &BTreeMap<u8, u64>) -> CommsResult<()> {
+            let contents: String = last
+                .iter()
+                .map(|(station_id, counter)| format!("{} {}\n", station_id, counter))
+                .collect();
+            let temp = self.path.with_extension("tmp");
+            fs::write(&temp, contents)?;
+            fs::rename(&temp, &self.path)?;
+            Ok(())
+        }
+    }
+
+    fn parse_line(line: &str) -> Option<(u8, u64)> {
+        let mut fields = line.split_whitespace();
+        let station_id = fields.next()?.parse().ok()?;
+        let counter = fields.next()?.parse().ok()?;
+        match fields.next() {
+            Some(_) => None,
+            None => Some((station_id, counter)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_counter_round_trip() {
+        let counted = command_counter(42, b"{ ping }");
+        assert_eq!(COMMAND_COUNTER_SIZE + 8, counted.len());
+
+        let (counter, payload) = parse_command_counter(&counted).unwrap();
+        assert_eq!(42, counter);
+        assert_eq!(b"{ ping }", payload);
+    }
+
+    #[test]
+    fn parse_command_counter_short() {
+        assert!(parse_command_counter(&[0, 0, 0, 0]).is_err());
+    }
+
+    #[cfg(feature = "service")]
+    #[test]
+    fn counters_reject_replays() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters");
+        let path = path.to_str().unwrap();
+
+        let counters = CommandCounters::open(path).unwrap();
+        counters.check(1, 5).unwrap();
+        counters.check(2, 1).unwrap();
+        // Counters can skip values
+        counters.check(1, 9).unwrap();
+        assert!(counters.check(1, 9).is_err());
+        assert!(counters.check(1, 6).is_err());
+
+        // The counters survive a restart
+        let counters = CommandCounters::open(path).unwrap();
+        assert!(counters.check(1, 9).is_err());
+        assert!(counters.check(2, 1).is_err());
+        counters.check(2, 2).unwrap();
+    }
+
+    #[cfg(feature = "service")]
+    #[test]
+    fn counters_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters");
+        std::fs::write(&path, "1 five\n").unwrap();
+        assert!(CommandCounters::open(path.to_str().unwrap()).is_err());
+    }
+}
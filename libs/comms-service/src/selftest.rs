@@ -0,0 +1,155 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Startup self-test
+//!
+//! Run by `CommsService::start` before any threads are spawned, so that a broken link layer,
+//! a downlink port which is already in use or a gateway which can't be written to stops the
+//! service with a clear error, rather than leaving threads running which can never do anything.
+
+use crate::config::DownlinkPort;
+use crate::errors::*;
+use crate::packet::{LinkPacket, PayloadType};
+use crate::service::WriteFn;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::Arc;
+
+// Contents of the packet looped through the link layer
+const TEST_COMMAND_ID: u64 = 0x5E1F_7E57;
+const TEST_PAYLOAD: &[u8] = b"{ ping }";
+
+fn failed(step: &str, err: impl ::std::fmt::Display) -> failure::Error {
+    CommsServiceError::SelfTestFailed(format!("{}: {}", step, err)).into()
+}
+
+/// Builds a packet, converts it to bytes and parses it back, checking that nothing was lost
+pub fn check_link_layer<Packet: LinkPacket>() -> CommsResult<()> {
+    let packet = Packet::build(TEST_COMMAND_ID, PayloadType::GraphQL, 0, TEST_PAYLOAD)
+        .map_err(|err| failed("Building test packet", err))?;
+    let raw = packet
+        .to_bytes()
+        .map_err(|err| failed("Converting test packet to bytes", err))?;
+    let parsed = Packet::parse(&raw).map_err(|err| failed("Parsing test packet", err))?;
+
+    if !parsed.validate() {
+        return Err(failed("Validating test packet", "validation failed"));
+    }
+
+    if parsed.command_id() != TEST_COMMAND_ID
+        || u16::from(parsed.payload_type()) != u16::from(PayloadType::GraphQL)
+        || parsed.destination() != 0
+        || parsed.payload() != TEST_PAYLOAD
+    {
+        return Err(failed(
+            "Parsing test packet",
+            "parsed packet doesn't match the one built",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Binds a socket for each downlink port. The sockets are handed on to the downlink endpoints.
+pub fn bind_downlink_ports(ip: Ipv4Addr, ports: &[DownlinkPort]) -> CommsResult<Vec<UdpSocket>> {
+    ports
+        .iter()
+        .map(|port| {
+            UdpSocket::bind((ip, port.port))
+                .map_err(|err| failed(&format!("Binding downlink port {}", port.port), err))
+        })
+        .collect()
+}
+
+/// Binds the socket which payload size queries are answered on
+pub fn bind_sizing_port(ip: Ipv4Addr, port: u16) -> CommsResult<UdpSocket> {
+    UdpSocket::bind((ip, port))
+        .map_err(|err| failed(&format!("Binding payload sizing port {}", port), err))
+}
+
+/// Writes a packet with an empty payload, addressed to port 0, with each of the write functions
+pub fn check_write<WriteConnection: Clone, Packet: LinkPacket>(
+    write: &[Arc<WriteFn<WriteConnection>>],
+    write_conn: &WriteConnection,
+) -> CommsResult<()> {
+    let frame = Packet::build(0, PayloadType::UDP, 0, &[])
+        .and_then(|packet| packet.to_bytes())
+        .map_err(|err| failed("Building no-op frame", err))?;
+
+    for (index, write) in write.iter().enumerate() {
+        write(write_conn, &frame)
+            .map_err(|err| failed(&format!("Writing with write function {}", index), err))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spacepacket::SpacePacket;
+    use std::sync::Mutex;
+
+    #[test]
+    fn spacepacket_link_layer() {
+        assert!(check_link_layer::<SpacePacket>().is_ok());
+    }
+
+    #[test]
+    fn downlink_port_in_use() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = DownlinkPort {
+            port: taken.local_addr().unwrap().port(),
+            buf_size: None,
+            station_id: None,
+            key_slot: None,
+            mirror_writes: None,
+            priority: None,
+            fec: None,
+            qos: None,
+            qos_prefix: None,
+            coalesce_size: None,
+        };
+
+        let err = bind_downlink_ports(Ipv4Addr::LOCALHOST, &[port])
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Self-test failed: Binding downlink port"));
+    }
+
+    #[test]
+    fn write_failure() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let written_ref = written.clone();
+        let good: Arc<WriteFn<()>> = Arc::new(move |_, frame: &[u8]| {
+            written_ref.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        });
+        let bad: Arc<WriteFn<()>> = Arc::new(|_, _: &[u8]| {
+            Err(CommsServiceError::GenericError("offline".to_owned()).into())
+        });
+
+        assert!(check_write::<(), SpacePacket>(&[good.clone()], &()).is_ok());
+        assert_eq!(written.lock().unwrap().len(), 1);
+
+        let err = check_write::<(), SpacePacket>(&[good, bad], &())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Self-test failed: Writing with write function 1: Error encountered offline"
+        );
+    }
+}
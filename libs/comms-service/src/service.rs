@@ -14,20 +14,39 @@
 // Contributed by: William Greer (wgreer184@gmail.com) and Sam Justice (sam.justice1@gmail.com)
 //
 
+use crate::cache::ResponseCache;
+use crate::coalesce::{coalesce, SUBFRAME_HEADER_SIZE};
+use crate::compression::{compress, Compression};
 use crate::config::*;
+use crate::echo::echo_response;
+use crate::encryption::KeySlots;
 use crate::errors::*;
+use crate::fec::Fec;
+use crate::flatsat::FlatsatBridge;
+use crate::health::{unreachable_response, DestinationHealth};
+use crate::memory::{MemoryBudget, Reservation, RESPONSE_PRIORITY};
 use crate::packet::{LinkPacket, PayloadType};
+use crate::pools::DestinationPools;
+use crate::ports::PortControls;
+use crate::queue::DownlinkQueue;
+use crate::replay::{parse_command_counter, CommandCounters};
+use crate::selftest;
+use crate::sizing::{self, PayloadSizing};
+use crate::spacepacket::SpacePacket;
 use crate::telemetry::*;
+use crate::timetag::{parse_time_tag, TimeTagStore};
+use kubos_system::trace;
 use log::info;
 use std::fmt::Debug;
-use std::net::{Ipv4Addr, UdpSocket};
+use std::iter;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
 use std::str::FromStr;
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
-use std::sync::mpsc::SendError;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Type definition for a "read" function pointer.
 pub type ReadFn<Connection> = dyn Fn(&Connection) -> CommsResult<Vec<u8>> + Send + Sync + 'static;
@@ -57,6 +76,36 @@ pub struct CommsControlBlock<ReadConnection: Clone, WriteConnection: Clone> {
     /// Optional list of ports used by downlink endpoints that send messages to the ground.
     /// Each port in the list will be used by one downlink endpoint.
     pub downlink_ports: Option<Vec<DownlinkPort>>,
+    /// Optional directory in which time-tagged commands are stored until they're due.
+    pub time_tag_dir: Option<String>,
+    /// Optional file in which the last command counter accepted from each ground station is
+    /// kept. Uplinked packets are only checked for replays if this is set.
+    pub command_counter_file: Option<String>,
+    /// Payload encryption keys and active downlink key slots.
+    /// A clone can be kept to switch the active downlink key while the service is running.
+    pub keys: KeySlots,
+    /// Recent GraphQL query responses, used to answer repeated queries.
+    /// A clone can be kept to clear the cache while the service is running.
+    pub response_cache: ResponseCache,
+    /// Failures of the services which GraphQL requests are passed to.
+    /// A clone can be kept to forget the failures while the service is running.
+    pub destination_health: DestinationHealth,
+    /// Replicas which GraphQL requests to pooled destination ports are spread across.
+    pub destination_pools: DestinationPools,
+    /// Whether the startup self-test writes a no-op frame with each write function.
+    pub self_test_write: bool,
+    /// Bytes held by queued downlink packets and GraphQL responses.
+    /// A clone can be kept to check the service's memory use while it is running.
+    pub memory: MemoryBudget,
+    /// Forward error correction expected on frames read from the gateway, and added to
+    /// responses to the ground's requests. Downlink ports may use their own.
+    pub fec: Fec,
+    /// Recommended payload size advertised to producers of downlink traffic, if any.
+    /// A clone can be kept to check the recommended size while the service is running.
+    pub payload_sizing: Option<PayloadSizing>,
+    /// Whether the uplink and each downlink port are enabled.
+    /// A clone can be kept to disable and enable ports while the service is running.
+    pub ports: PortControls,
 }
 
 impl<ReadConnection: Clone + Debug, WriteConnection: Clone + Debug> Debug
@@ -80,7 +129,10 @@ impl<ReadConnection: Clone + Debug, WriteConnection: Clone + Debug> Debug
         write!(
             f,
             "CommsControlBlock {{ read: {}, write: {:?}, read_conn: {:?}, write_conn: {:?},
-            max_num_handlers: {:?}, timeout: {:?}:{:?}, ip: {:?}, downlink_ports: {:?} }}",
+            max_num_handlers: {:?}, timeout: {:?}:{:?}, ip: {:?}, downlink_ports: {:?},
+            time_tag_dir: {:?}, command_counter_file: {:?}, keys: {:?}, response_cache: {:?},
+            destination_health: {:?}, destination_pools: {:?}, self_test_write: {:?},
+            memory: {:?}, fec: {:?}, payload_sizing: {:?}, ports: {:?} }}",
             read,
             write,
             self.read_conn,
@@ -90,6 +142,17 @@ impl<ReadConnection: Clone + Debug, WriteConnection: Clone + Debug> Debug
             self.write_timeout,
             self.ip,
             self.downlink_ports,
+            self.time_tag_dir,
+            self.command_counter_file,
+            self.keys,
+            self.response_cache,
+            self.destination_health,
+            self.destination_pools,
+            self.self_test_write,
+            self.memory,
+            self.fec,
+            self.payload_sizing,
+            self.ports,
         )
     }
 }
@@ -104,7 +167,26 @@ impl<ReadConnection: Clone, WriteConnection: Clone>
         read_conn: ReadConnection,
         write_conn: WriteConnection,
         config: CommsConfig,
-    ) -> CommsResult<Self> {
+    ) -> CommsResult<Self>
+    where
+        ReadConnection: 'static,
+        WriteConnection: 'static,
+    {
+        // In flatsat mode, the gateway is replaced by a UDP bridge to the ground. There's one
+        // write function for each downlink port, so no write functions need to be given.
+        let (read, write) = match FlatsatBridge::from_config(&config)? {
+            Some(bridge) => {
+                let count = config
+                    .downlink_ports
+                    .as_ref()
+                    .map_or(0, |ports| ports.len())
+                    .max(write.len())
+                    .max(1);
+                (Some(bridge.read()), vec![bridge.write(); count])
+            }
+            None => (read, write),
+        };
+
         if write.is_empty() {
             return Err(
                 CommsServiceError::ConfigError("No `write` function provided".to_owned()).into(),
@@ -112,14 +194,36 @@ impl<ReadConnection: Clone, WriteConnection: Clone>
         }
 
         if let Some(ports) = config.clone().downlink_ports {
-            if write.len() != ports.len() {
+            // Any write functions after the downlink ports' own are only used as mirrors
+            if write.len() < ports.len() {
                 return Err(CommsServiceError::ConfigError(
                     "There must be a unique write function for each downlink port".to_owned(),
                 )
                 .into());
             }
+
+            if ports
+                .iter()
+                .flat_map(|port| port.mirror_writes.iter().flatten())
+                .any(|index| *index >= write.len())
+            {
+                return Err(CommsServiceError::ConfigError(
+                    "Downlink port mirrors a write function which doesn't exist".to_owned(),
+                )
+                .into());
+            }
         }
 
+        let keys = KeySlots::new(&config)?;
+        let response_cache = ResponseCache::from_config(&config);
+        let destination_health = DestinationHealth::from_config(&config);
+        let destination_pools = DestinationPools::from_config(&config)?;
+        let memory = MemoryBudget::from_config(&config);
+        let payload_sizing = PayloadSizing::from_config(&config)?;
+        let ports = PortControls::from_config(&config);
+        SpacePacket::set_apid_routes(config.apid_routes.as_ref().map_or(&[], |routes| routes))?;
+        SpacePacket::set_time_code(config.time_code)?;
+
         Ok(CommsControlBlock {
             read,
             write,
@@ -130,6 +234,17 @@ impl<ReadConnection: Clone, WriteConnection: Clone>
             write_timeout: config.write_timeout.unwrap_or(DEFAULT_TIMEOUT),
             ip: Ipv4Addr::from_str(&config.ip)?,
             downlink_ports: config.downlink_ports,
+            time_tag_dir: config.time_tag_dir,
+            command_counter_file: config.command_counter_file,
+            keys,
+            response_cache,
+            destination_health,
+            destination_pools,
+            self_test_write: config.self_test_write.unwrap_or(false),
+            memory,
+            fec: config.fec.unwrap_or_default(),
+            payload_sizing,
+            ports,
         })
     }
 }
@@ -139,6 +254,11 @@ pub struct CommsService;
 
 impl CommsService {
     /// Starts an instance of the Communication Service and its associated background threads.
+    ///
+    /// A self-test is run first. It checks that packets survive being built and parsed by the
+    /// link layer, binds each of the downlink ports and the payload sizing port and, if
+    /// `self_test_write` is set, writes a no-op frame with each of the write functions. If any of these fail, an error is returned
+    /// and no threads are started.
     pub fn start<
         ReadConnection: Clone + Send + 'static,
         WriteConnection: Clone + Send + 'static,
@@ -147,35 +267,144 @@ impl CommsService {
         control: CommsControlBlock<ReadConnection, WriteConnection>,
         telem: &Arc<Mutex<CommsTelemetry>>,
     ) -> CommsResult<()> {
+        selftest::check_link_layer::<Packet>()?;
+        let downlink_sockets = match control.downlink_ports {
+            Some(ref ports) => selftest::bind_downlink_ports(control.ip, ports)?,
+            None => vec![],
+        };
+        let sizing_socket = match control.payload_sizing {
+            Some(ref sizing) => Some(selftest::bind_sizing_port(control.ip, sizing.port())?),
+            None => None,
+        };
+        if control.self_test_write {
+            selftest::check_write::<WriteConnection, Packet>(&control.write, &control.write_conn)?;
+        }
+        info!("Self-test passed");
+        control.ports.attach_telemetry(telem);
+
+        // If desired, spawn a thread which tells producers the recommended payload size
+        if let (Some(sizing), Some(socket)) = (control.payload_sizing.clone(), sizing_socket) {
+            thread::Builder::new()
+                .stack_size(16 * 1024)
+                .spawn(move || sizing::answer_queries(&sizing, &socket))
+                .unwrap();
+        }
+
         // If desired, spawn a read thread
         if control.read.is_some() {
+            // Start the message handlers
+            let handlers = HandlerPool::new(control.max_num_handlers);
+
+            // Time-tagged commands are only accepted if there's somewhere to keep them
+            let time_tags = match control.time_tag_dir {
+                Some(ref dir) => Some(TimeTagStore::open(dir)?),
+                None => None,
+            };
+
+            // Uplinked commands are only checked for replays if their counters can be kept
+            let counters = match control.command_counter_file {
+                Some(ref path) => Some(CommandCounters::open(path)?),
+                None => None,
+            };
+
+            if let Some(ref store) = time_tags {
+                let telem_ref = telem.clone();
+                let control_ref = control.clone();
+                let handlers_ref = handlers.clone();
+                let store_ref = store.clone();
+                thread::Builder::new()
+                    .stack_size(16 * 1024)
+                    .spawn(move || {
+                        time_tag_thread::<ReadConnection, WriteConnection, Packet>(
+                            control_ref,
+                            &telem_ref,
+                            handlers_ref,
+                            store_ref,
+                        )
+                    })
+                    .unwrap();
+            }
+
             let telem_ref = telem.clone();
             let control_ref = control.clone();
             thread::Builder::new()
                 .stack_size(16 * 1024)
                 .spawn(move || {
-                    read_thread::<ReadConnection, WriteConnection, Packet>(control_ref, &telem_ref)
+                    read_thread::<ReadConnection, WriteConnection, Packet>(
+                        control_ref,
+                        &telem_ref,
+                        handlers,
+                        time_tags,
+                        counters,
+                    )
                 })
                 .unwrap();
         }
 
-        // For each provided `write()` function, spawn a downlink endpoint thread.
-        if let Some(ports) = control.downlink_ports {
-            for (_, (port, write)) in ports.iter().zip(control.write.iter()).enumerate() {
+        // For each downlink port, spawn a downlink endpoint thread which queues the packets
+        // sent to the port. A single thread then writes the queued packets, highest QoS first,
+        // with each port's own `write()` function and any it's mirrored to.
+        if let Some(ref ports) = control.downlink_ports {
+            let queue = DownlinkQueue::default();
+            let mut endpoints = vec![];
+            for (index, (port, socket)) in ports.iter().zip(downlink_sockets).enumerate() {
+                let (return_tx, return_rx) = mpsc::channel();
+                let num_packets = Arc::new(AtomicU32::new(0));
+                endpoints.push(DownlinkEndpoint {
+                    port: port.clone(),
+                    writes: iter::once(index)
+                        .chain(port.mirror_writes.iter().flatten().cloned())
+                        .map(|index| (index, control.write[index].clone()))
+                        .collect(),
+                    fec: port.fec.unwrap_or(control.fec),
+                    num_packets: num_packets.clone(),
+                    return_tx,
+                });
+
                 let telem_ref = telem.clone();
                 let port_ref = port.clone();
-                let conn_ref = control.write_conn.clone();
-                let write_ref = write.clone();
-                let ip = control.ip;
+                let queue_ref = queue.clone();
+                let memory_ref = control.memory.clone();
+                let ports_ref = control.ports.clone();
                 thread::Builder::new()
                     .stack_size(16 * 1024)
                     .spawn(move || {
-                        downlink_endpoint::<ReadConnection, WriteConnection, Packet>(
-                            &telem_ref, port_ref, conn_ref, &write_ref, ip,
+                        downlink_endpoint(
+                            &telem_ref,
+                            index,
+                            port_ref,
+                            socket,
+                            &queue_ref,
+                            &memory_ref,
+                            &ports_ref,
+                            &num_packets,
+                            &return_rx,
                         );
                     })
                     .unwrap();
             }
+
+            let telem_ref = telem.clone();
+            let conn_ref = control.write_conn.clone();
+            let keys_ref = control.keys.clone();
+            let memory_ref = control.memory.clone();
+            let sizing_ref = control.payload_sizing.clone();
+            let ports_ref = control.ports.clone();
+            thread::Builder::new()
+                .stack_size(16 * 1024)
+                .spawn(move || {
+                    downlink_writer::<WriteConnection, Packet>(
+                        &telem_ref,
+                        &endpoints,
+                        &queue,
+                        conn_ref,
+                        &keys_ref,
+                        &memory_ref,
+                        &ports_ref,
+                        sizing_ref.as_ref(),
+                    );
+                })
+                .unwrap();
         }
 
         info!("Communication service started");
@@ -191,21 +420,52 @@ fn read_thread<
 >(
     comms: CommsControlBlock<ReadConnection, WriteConnection>,
     data: &Arc<Mutex<CommsTelemetry>>,
+    handlers: HandlerPool,
+    time_tags: Option<TimeTagStore>,
+    counters: Option<CommandCounters>,
 ) {
     // Take reader from control block.
-    let read = comms.read.unwrap();
-
-    // Initiate counter for handlers
-    let num_handlers: Arc<Mutex<u16>> = Arc::new(Mutex::new(0));
+    let read = comms.read.clone().unwrap();
 
     loop {
         // Read bytes from the radio.
         let bytes = match (read)(&comms.read_conn.clone()) {
             Ok(bytes) => bytes,
             Err(e) => {
-                log_error(&data, e.to_string()).unwrap();
+                log_error(&data, &e).unwrap();
+                continue;
+            }
+        };
+
+        // The frame is still read while the uplink is disabled, so it doesn't back up in the
+        // radio, but it goes no further
+        if !comms.ports.uplink_enabled() {
+            log_telemetry(&data, &TelemType::MutedUp).unwrap();
+            continue;
+        }
+
+        // Correct any errors in the frame, if the ground added forward error correction
+        let bytes = if comms.fec == Fec::None {
+            bytes
+        } else {
+            let decoded = comms.fec.decode(&bytes);
+            log_fec_telemetry(
+                &data,
+                decoded.corrected_blocks,
+                decoded.uncorrectable_blocks,
+            )
+            .unwrap();
+            if decoded.uncorrectable_blocks > 0 {
+                let e = CommsServiceError::UncorrectableFrame(decoded.uncorrectable_blocks);
+                log_telemetry(&data, &TelemType::UpFailed).unwrap();
+                error!("Failed to decode frame: {}", e);
+                log_error(&data, &e.into()).unwrap();
                 continue;
             }
+            if decoded.corrected_blocks > 0 {
+                debug!("Corrected errors in {} FEC blocks", decoded.corrected_blocks);
+            }
+            decoded.frame
         };
 
         // Create a link packet from the received information.
@@ -213,7 +473,7 @@ fn read_thread<
             Ok(packet) => packet,
             Err(e) => {
                 log_telemetry(&data, &TelemType::UpFailed).unwrap();
-                log_error(&data, CommsServiceError::HeaderParsing.to_string()).unwrap();
+                log_error(&data, &CommsServiceError::HeaderParsing.into()).unwrap();
                 error!("Failed to parse packet header {}", e);
                 continue;
             }
@@ -222,189 +482,663 @@ fn read_thread<
         // Validate the link packet
         if !packet.validate() {
             log_telemetry(&data, &TelemType::UpFailed).unwrap();
-            log_error(&data, CommsServiceError::InvalidChecksum.to_string()).unwrap();
+            log_error(&data, &CommsServiceError::InvalidChecksum.into()).unwrap();
             error!("Packet checksum failed");
             continue;
         }
 
+        // Decrypt the payload if the ground encrypted it
+        let packet = match decrypt_packet(&comms.keys, packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log_telemetry(&data, &TelemType::UpFailed).unwrap();
+                log_error(&data, &e).unwrap();
+                error!("Failed to decrypt packet: {}", e);
+                continue;
+            }
+        };
+
+        // Drop the packet if it's a replay of one the station has already sent
+        let packet = match counters {
+            Some(ref counters) => match check_counter(&data, counters, packet) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log_telemetry(&data, &TelemType::UpFailed).unwrap();
+                    log_error(&data, &e).unwrap();
+                    error!("Rejected uplinked packet: {}", e);
+                    continue;
+                }
+            },
+            None => packet,
+        };
+
         // Update number of packets up.
         log_telemetry(&data, &TelemType::Up).unwrap();
+        log_station_telemetry(&data, packet.station_id(), &TelemType::Up).unwrap();
+        debug!("Packet uplinked from ground station {}", packet.station_id());
         // info!("Packet successfully uplinked");
 
-        // Check link type for appropriate message handling path
-        match packet.payload_type() {
-            PayloadType::Unknown(value) => {
-                log_error(
-                    &data,
-                    CommsServiceError::UnknownPayloadType(value).to_string(),
-                )
-                .unwrap();
-                error!("Unknown payload type encountered: {}", value);
+        dispatch(&comms, data, &handlers, time_tags.as_ref(), packet);
+    }
+}
+
+// This thread releases time-tagged commands to the message handlers once they're due.
+fn time_tag_thread<
+    ReadConnection: Clone + Send + 'static,
+    WriteConnection: Clone + Send + 'static,
+    Packet: LinkPacket + Send + 'static,
+>(
+    comms: CommsControlBlock<ReadConnection, WriteConnection>,
+    data: &Arc<Mutex<CommsTelemetry>>,
+    handlers: HandlerPool,
+    store: TimeTagStore,
+) {
+    loop {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(0);
+
+        let due = match store.take_due(now) {
+            Ok(due) => due,
+            Err(e) => {
+                log_error(&data, &e).unwrap();
+                vec![]
             }
-            PayloadType::UDP => {
-                let sat_ref = comms.ip;
-                let data_ref = data.clone();
-
-                //                 thread::Builder::new()
-                //                     .stack_size(16 * 1024)
-                //                     .spawn(move ||
-                match handle_udp_passthrough(packet, sat_ref) {
+        };
+
+        for bytes in due {
+            match Packet::parse(&bytes) {
+                Ok(packet) => {
+                    info!("Releasing time-tagged command {}", packet.command_id());
+                    dispatch(&comms, data, &handlers, None, packet);
+                }
+                Err(e) => {
+                    log_error(&data, &e).unwrap();
+                    error!("Failed to parse time-tagged command {}", e);
+                }
+            }
+        }
+
+        thread::sleep(TIME_TAG_INTERVAL);
+    }
+}
+
+// How often to check for due time-tagged commands
+const TIME_TAG_INTERVAL: Duration = Duration::from_secs(1);
+
+// Passes a packet to the appropriate message handling path
+fn dispatch<
+    ReadConnection: Clone + Send + 'static,
+    WriteConnection: Clone + Send + 'static,
+    Packet: LinkPacket + Send + 'static,
+>(
+    comms: &CommsControlBlock<ReadConnection, WriteConnection>,
+    data: &Arc<Mutex<CommsTelemetry>>,
+    handlers: &HandlerPool,
+    time_tags: Option<&TimeTagStore>,
+    packet: Box<Packet>,
+) {
+    // Check link type for appropriate message handling path
+    match packet.payload_type() {
+        PayloadType::Unknown(value) => {
+            log_error(&data, &CommsServiceError::UnknownPayloadType(value).into()).unwrap();
+            error!("Unknown payload type encountered: {}", value);
+        }
+        PayloadType::UDP => {
+            let sat_ref = comms.ip;
+            let data_ref = data.clone();
+
+            //                 thread::Builder::new()
+            //                     .stack_size(16 * 1024)
+            //                     .spawn(move ||
+            match handle_udp_passthrough(packet, sat_ref) {
+                Ok(_) => {
+                    log_telemetry(&data_ref, &TelemType::Down).unwrap();
+                    // info!("UDP Packet successfully uplinked");
+                }
+                Err(e) => {
+                    log_telemetry(&data_ref, &TelemType::DownFailed).unwrap();
+                    log_error(&data_ref, &e).unwrap();
+                    error!("UDP packet failed to uplink: {}", e.to_string());
+                }
+            }
+            //                     })
+            //                     .unwrap();
+        }
+        PayloadType::GraphQL => {
+            debug!("Received GraphQL Packet");
+
+            // Answer repeated queries without bothering the destination service
+            if let Some(response) = comms
+                .response_cache
+                .get(packet.destination(), &packet.payload())
+            {
+                let station_id = packet.station_id();
+                let res = downlink_graphql_response(
+                    comms.write_conn.clone(),
+                    &comms.write[0],
+                    &*packet,
+                    &comms.keys,
+                    comms.fec,
+                    &response,
+                );
+
+                match res {
+                    Ok(_) => {
+                        log_telemetry(&data, &TelemType::Cached).unwrap();
+                        log_telemetry(&data, &TelemType::Down).unwrap();
+                        log_station_telemetry(&data, station_id, &TelemType::Down).unwrap();
+                    }
+                    Err(e) => {
+                        log_telemetry(&data, &TelemType::DownFailed).unwrap();
+                        log_error(&data, &e).unwrap();
+                        error!("Cached GraphQL response failed to downlink: {}", e);
+                    }
+                }
+                return;
+            }
+
+            // Don't tie up a message handler waiting on a service which keeps failing.
+            // Requests to a pooled port go to the next of its replicas which is reachable
+            let port = comms
+                .destination_pools
+                .pick(packet.destination(), &comms.destination_health);
+            if comms.destination_health.is_unreachable(port) {
+                let station_id = packet.station_id();
+                let failures = comms.destination_health.consecutive_failures(port);
+                let res = downlink_graphql_response(
+                    comms.write_conn.clone(),
+                    &comms.write[0],
+                    &*packet,
+                    &comms.keys,
+                    comms.fec,
+                    &unreachable_response(port, failures),
+                );
+                log_destination_telemetry(&data, port, failures, true).unwrap();
+                debug!("Rejected GraphQL request to unreachable port {}", port);
+
+                match res {
+                    Ok(_) => {
+                        log_telemetry(&data, &TelemType::Down).unwrap();
+                        log_station_telemetry(&data, station_id, &TelemType::Down).unwrap();
+                    }
+                    Err(e) => {
+                        log_telemetry(&data, &TelemType::DownFailed).unwrap();
+                        log_error(&data, &e).unwrap();
+                        error!("GraphQL error response failed to downlink: {}", e);
+                    }
+                }
+                return;
+            }
+
+            // Hand off to a message handler.
+            let conn_ref = comms.write_conn.clone();
+            let write_ref = comms.write[0].clone();
+            let data_ref = data.clone();
+            let keys_ref = comms.keys.clone();
+            let fec = comms.fec;
+            let cache_ref = comms.response_cache.clone();
+            let health_ref = comms.destination_health.clone();
+            let memory_ref = comms.memory.clone();
+            let sat_ref = comms.ip;
+            let read_time_ref = comms.read_timeout;
+            let write_time_ref = comms.write_timeout;
+            let handled = handlers.execute(move || {
+                let station_id = packet.station_id();
+                let res = handle_graphql_request(
+                    conn_ref,
+                    &write_ref,
+                    packet,
+                    port,
+                    &keys_ref,
+                    fec,
+                    &cache_ref,
+                    &health_ref,
+                    &memory_ref,
+                    read_time_ref,
+                    write_time_ref,
+                    sat_ref,
+                );
+                let failures = health_ref.consecutive_failures(port);
+                log_destination_telemetry(&data_ref, port, failures, false).unwrap();
+
+                match res {
                     Ok(_) => {
                         log_telemetry(&data_ref, &TelemType::Down).unwrap();
-                        // info!("UDP Packet successfully uplinked");
+                        log_station_telemetry(&data_ref, station_id, &TelemType::Down).unwrap();
+                        // info!("GraphQL Packet successfully downlinked");
                     }
                     Err(e) => {
                         log_telemetry(&data_ref, &TelemType::DownFailed).unwrap();
-                        log_error(&data_ref, e.to_string()).unwrap();
-                        error!("UDP packet failed to uplink: {}", e.to_string());
+                        log_error(&data_ref, &e).unwrap();
+                        error!("GraphQL packet failed to downlink: {}", e.to_string());
                     }
                 }
-                //                     })
-                //                     .unwrap();
+            });
+
+            if !handled {
+                log_error(&data, &CommsServiceError::NoAvailablePorts.into()).unwrap();
+                error!("No message handler ports available");
             }
-            PayloadType::GraphQL => {
-                debug!("Received GraphQL Packet");
-                if let Ok(mut num_handlers) = num_handlers.lock() {
-                    if *num_handlers >= comms.max_num_handlers {
-                        log_error(&data, CommsServiceError::NoAvailablePorts.to_string()).unwrap();
-                        error!("No message handler ports available");
-                        continue;
-                    } else {
-                        *num_handlers += 1;
+        }
+        PayloadType::UDPDlStream => {
+            // Hand off to a message handler.
+            let conn_ref = comms.write_conn.clone();
+            let write_ref = comms.write[0].clone();
+            let data_ref = data.clone();
+            let keys_ref = comms.keys.clone();
+            let fec = comms.fec;
+            let memory_ref = comms.memory.clone();
+            let sat_ref = comms.ip;
+            let read_time_ref = comms.read_timeout * 10;
+            let write_time_ref = comms.write_timeout * 10;
+            let handled = handlers.execute(move || {
+                let station_id = packet.station_id();
+                let res = handle_udp_dl_stream_request(
+                    conn_ref,
+                    &write_ref,
+                    packet,
+                    &keys_ref,
+                    fec,
+                    &memory_ref,
+                    read_time_ref,
+                    write_time_ref,
+                    sat_ref,
+                );
+
+                match res {
+                    Ok(_) => {
+                        log_telemetry(&data_ref, &TelemType::Down).unwrap();
+                        log_station_telemetry(&data_ref, station_id, &TelemType::Down).unwrap();
+                        // info!("UDP DL Stream Completed");
+                    }
+                    Err(e) => {
+                        log_telemetry(&data_ref, &TelemType::DownFailed).unwrap();
+                        log_error(&data_ref, &e).unwrap();
+                        error!("UDP Dl Stream Error: {}", e.to_string());
                     }
                 }
+            });
 
-                // Spawn new message handler.
-                let conn_ref = comms.write_conn.clone();
-                let write_ref = comms.write[0].clone();
-                let data_ref = data.clone();
-                let sat_ref = comms.ip;
-                let read_time_ref = comms.read_timeout;
-                let write_time_ref = comms.write_timeout;
-                let num_handlers_ref = num_handlers.clone();
-                thread::Builder::new()
-                    .stack_size(80 * 1024)
-                    .spawn(move || {
-                        let res = handle_graphql_request(
-                            conn_ref,
-                            &write_ref,
-                            packet,
-                            read_time_ref,
-                            write_time_ref,
-                            sat_ref,
-                        );
+            if !handled {
+                log_error(&data, &CommsServiceError::NoAvailablePorts.into()).unwrap();
+                error!("No message handler ports available");
+            }
+        }
+        PayloadType::Echo => {
+            // Answered straight from the read thread, so it works even if every handler is busy
+            let station_id = packet.station_id();
+            let res = downlink_echo(
+                comms.write_conn.clone(),
+                &comms.write[0],
+                &*packet,
+                &comms.keys,
+                comms.fec,
+            );
 
-                        if let Ok(mut num_handlers) = num_handlers_ref.lock() {
-                            *num_handlers -= 1;
-                        }
-
-                        match res {
-                            Ok(_) => {
-                                log_telemetry(&data_ref, &TelemType::Down).unwrap();
-                                // info!("GraphQL Packet successfully downlinked");
-                            }
-                            Err(e) => {
-                                log_telemetry(&data_ref, &TelemType::DownFailed).unwrap();
-                                log_error(&data_ref, e.to_string()).unwrap();
-                                error!("GraphQL packet failed to downlink: {}", e.to_string());
-                            }
-                        }
-                    })
-                    .unwrap();
+            match res {
+                Ok(_) => {
+                    log_telemetry(&data, &TelemType::Down).unwrap();
+                    log_station_telemetry(&data, station_id, &TelemType::Down).unwrap();
+                }
+                Err(e) => {
+                    log_telemetry(&data, &TelemType::DownFailed).unwrap();
+                    log_error(&data, &e).unwrap();
+                    error!("Echo response failed to downlink: {}", e);
+                }
             }
-            PayloadType::UDPDlStream => {
-                if let Ok(mut num_handlers) = num_handlers.lock() {
-                    if *num_handlers >= comms.max_num_handlers {
-                        log_error(&data, CommsServiceError::NoAvailablePorts.to_string()).unwrap();
-                        error!("No message handler ports available");
-                        continue;
-                    } else {
-                        *num_handlers += 1;
-                    }
+        }
+        PayloadType::Coalesced => {
+            log_error(
+                &data,
+                &CommsServiceError::ParsingError("Coalesced packets can't be uplinked".to_owned())
+                    .into(),
+            )
+            .unwrap();
+            error!("Coalesced packet received from the ground");
+        }
+        PayloadType::TimeTagged => match time_tags {
+            Some(store) => {
+                if let Err(e) = store_time_tagged(store, packet) {
+                    log_error(&data, &e).unwrap();
+                    error!("Failed to store time-tagged command: {}", e);
                 }
+            }
+            None => {
+                log_error(&data, &CommsServiceError::TimeTagsDisabled.into()).unwrap();
+                error!("Time-tagged command received, but no time_tag_dir is configured");
+            }
+        },
+    }
+}
 
-                // Spawn new message handler.
-                let conn_ref = comms.write_conn.clone();
-                let write_ref = comms.write[0].clone();
-                let data_ref = data.clone();
-                let sat_ref = comms.ip;
-                let read_time_ref = comms.read_timeout * 10;
-                let write_time_ref = comms.write_timeout * 10;
-                let num_handlers_ref = num_handlers.clone();
-                thread::Builder::new()
-                    .stack_size(16 * 1024)
-                    .spawn(move || {
-                        let res = handle_udp_dl_stream_request(
-                            conn_ref,
-                            &write_ref,
-                            packet,
-                            read_time_ref,
-                            write_time_ref,
-                            sat_ref,
-                        );
+// Stores the command embedded in a time-tagged packet, as a packet of its own,
+// until it's due to be released
+#[allow(clippy::boxed_local)]
+fn store_time_tagged<Packet: LinkPacket>(
+    store: &TimeTagStore,
+    message: Box<Packet>,
+) -> CommsResult<()> {
+    let payload = message.payload();
+    let (exec_time, payload_type, command) = parse_time_tag(&payload)?;
+
+    let mut packet = Packet::build_for_station(
+        message.command_id(),
+        payload_type,
+        message.destination(),
+        command,
+        message.station_id(),
+    )?;
+    packet.set_compression(message.compression())?;
+    packet.set_qos(message.qos())?;
+
+    store.store(exec_time, message.command_id(), &packet.to_bytes()?)?;
+    info!(
+        "Stored time-tagged command {} for {}",
+        message.command_id(),
+        exec_time
+    );
+    Ok(())
+}
 
-                        if let Ok(mut num_handlers) = num_handlers_ref.lock() {
-                            *num_handlers -= 1;
-                        }
-
-                        match res {
-                            Ok(_) => {
-                                log_telemetry(&data_ref, &TelemType::Down).unwrap();
-                                // info!("UDP DL Stream Completed");
-                            }
-                            Err(e) => {
-                                log_telemetry(&data_ref, &TelemType::DownFailed).unwrap();
-                                log_error(&data_ref, e.to_string()).unwrap();
-                                error!("UDP Dl Stream Error: {}", e.to_string());
-                            }
-                        }
-                    })
-                    .unwrap();
+// Replaces an encrypted packet with a plaintext copy of itself
+#[allow(clippy::boxed_local)]
+fn decrypt_packet<Packet: LinkPacket>(
+    keys: &KeySlots,
+    message: Box<Packet>,
+) -> CommsResult<Box<Packet>> {
+    if message.key_slot() == 0 {
+        return Ok(message);
+    }
+
+    let payload = keys.decrypt(message.key_slot(), &message.payload())?;
+    let mut packet = Packet::build_for_station(
+        message.command_id(),
+        message.payload_type(),
+        message.destination(),
+        &payload,
+        message.station_id(),
+    )?;
+    packet.set_compression(message.compression())?;
+    packet.set_qos(message.qos())?;
+    Ok(packet)
+}
+
+// Checks an uplinked packet's command counter and replaces the packet with a copy of itself
+// without the counter
+#[allow(clippy::boxed_local)]
+fn check_counter<Packet: LinkPacket>(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    counters: &CommandCounters,
+    message: Box<Packet>,
+) -> CommsResult<Box<Packet>> {
+    let payload = message.payload();
+    let (counter, payload) = parse_command_counter(&payload)?;
+    counters.check(message.station_id(), counter)?;
+    log_counter_telemetry(data, message.station_id(), counter)?;
+
+    let mut packet = Packet::build_for_station(
+        message.command_id(),
+        message.payload_type(),
+        message.destination(),
+        payload,
+        message.station_id(),
+    )?;
+    packet.set_compression(message.compression())?;
+    packet.set_qos(message.qos())?;
+    Ok(packet)
+}
+
+// Wraps a payload in a link packet for downlink, encrypting it with the active key
+fn build_downlink<Packet: LinkPacket>(
+    keys: &KeySlots,
+    key_slot: u8,
+    command_id: u64,
+    payload_type: PayloadType,
+    destination_port: u16,
+    payload: &[u8],
+    station_id: u8,
+    qos: u8,
+) -> CommsResult<Box<Packet>> {
+    let payload = keys.encrypt(key_slot, payload)?;
+    let mut packet = Packet::build_for_station(
+        command_id,
+        payload_type,
+        destination_port,
+        &payload,
+        station_id,
+    )?;
+    packet.set_key_slot(key_slot)?;
+    packet.set_qos(qos)?;
+    Ok(packet)
+}
+
+// Sends an echo request's payload straight back to the ground, with the time it was received
+fn downlink_echo<WriteConnection: Clone, Packet: LinkPacket>(
+    write_conn: WriteConnection,
+    write: &Arc<WriteFn<WriteConnection>>,
+    message: &Packet,
+    keys: &KeySlots,
+    fec: Fec,
+) -> CommsResult<()> {
+    let received = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_micros() as u64)
+        .unwrap_or(0);
+
+    let packet = build_downlink::<Packet>(
+        keys,
+        keys.downlink_slot(None),
+        message.command_id(),
+        PayloadType::Echo,
+        message.response_port(),
+        &echo_response(received, &message.payload()),
+        message.station_id(),
+        message.qos(),
+    )
+    .and_then(|packet| packet.to_bytes())
+    .map(|frame| fec.encode(&frame))?;
+
+    write(&write_conn, &packet)?;
+    debug!("Downlinked echo response {}", message.command_id());
+
+    Ok(())
+}
+
+// Work done by a message handler
+type Handler = Box<dyn FnOnce() + Send + 'static>;
+
+// Stack size of each message handler thread
+const HANDLER_STACK_SIZE: usize = 80 * 1024;
+
+// Fixed pool of message handler threads, fed through a bounded channel.
+// The threads are started up front so that memory use doesn't spike (or fragment)
+// when a burst of requests is uplinked.
+#[derive(Clone)]
+struct HandlerPool {
+    sender: mpsc::SyncSender<Handler>,
+    // Number of handlers which are queued or running
+    busy: Arc<Mutex<u16>>,
+    size: u16,
+}
+
+impl HandlerPool {
+    fn new(size: u16) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Handler>(size as usize);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..size {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .stack_size(HANDLER_STACK_SIZE)
+                .spawn(move || loop {
+                    // Only hold the lock while waiting for work, not while doing it
+                    let handler = match receiver.lock().unwrap().recv() {
+                        Ok(handler) => handler,
+                        Err(_) => return,
+                    };
+                    handler();
+                })
+                .unwrap();
+        }
+
+        HandlerPool {
+            sender,
+            busy: Arc::new(Mutex::new(0)),
+            size,
+        }
+    }
+
+    // Queues work for the next free message handler.
+    // Returns false if all of the message handlers are already busy.
+    fn execute<F: FnOnce() + Send + 'static>(&self, work: F) -> bool {
+        {
+            let mut busy = self.busy.lock().unwrap();
+            if *busy >= self.size {
+                return false;
             }
+            *busy += 1;
+        }
+
+        let busy = self.busy.clone();
+        let handler: Handler = Box::new(move || {
+            work();
+            *busy.lock().unwrap() -= 1;
+        });
+
+        if self.sender.try_send(handler).is_err() {
+            *self.busy.lock().unwrap() -= 1;
+            return false;
         }
+        true
     }
 }
 
-// This thread sends a query/mutation to its intended destination and waits for a response.
+// This thread sends a query/mutation to the local port picked for its destination (one of the
+// destination's replicas, if it has any) and waits for a response.
 // The thread then writes the response to the gateway.
 #[allow(clippy::boxed_local)]
 fn handle_graphql_request<WriteConnection: Clone, Packet: LinkPacket>(
     write_conn: WriteConnection,
     write: &Arc<WriteFn<WriteConnection>>,
     message: Box<Packet>,
+    port: u16,
+    keys: &KeySlots,
+    fec: Fec,
+    cache: &ResponseCache,
+    health: &DestinationHealth,
+    memory: &MemoryBudget,
     read_timeout: u64,
     write_timeout: u64,
     sat_ip: Ipv4Addr,
-) -> Result<(), String> {
+) -> CommsResult<()> {
     use std::time::Duration;
 
-    let socket = UdpSocket::bind((sat_ip, 0)).map_err(|e| e.to_string())?;
+    let socket = UdpSocket::bind((sat_ip, 0))?;
 
     socket
-        .set_read_timeout(Some(Duration::from_millis(read_timeout)))
-        .map_err(|e| e.to_string())?;
+        .set_read_timeout(Some(Duration::from_millis(read_timeout)))?;
 
     socket
-        .set_write_timeout(Some(Duration::from_millis(write_timeout)))
-        .map_err(|e| e.to_string())?;
-
-    socket
-        .send_to(&message.payload(), (sat_ip, message.destination()))
-        .map_err(|e| e.to_string())?;
-    debug!("Sent GraphQL Request to {}", message.destination());
+        .set_write_timeout(Some(Duration::from_millis(write_timeout)))?;
 
     let mut buf = [0; 64 * 1024];
+    let request = traced_request(&message.payload(), message.command_id());
+
+    // Only the destination's part of the transaction counts towards its health
+    let received = socket
+        .send_to(&request, (sat_ip, port))
+        .and_then(|_| {
+            debug!("Sent GraphQL Request {} to {}", message.command_id(), port);
+            socket.recv_from(&mut buf)
+        });
+
+    let (size, _addr) = match received {
+        Ok(received) => {
+            health.record_success(port);
+            received
+        }
+        Err(e) => {
+            health.record_failure(port);
+            return Err(e.into());
+        }
+    };
+    debug!("Received GraphQL Response from {}", port);
 
-    let (size, _addr) = socket.recv_from(&mut buf).map_err(|e| e.to_string())?;
-    debug!("Received GraphQL Response from {}", message.destination());
+    // Keep the uncompressed response, since a retry might ask for a different compression.
+    // Any of a pool's replicas would give the same response, so it's kept for the pool's port
+    cache.insert(message.destination(), &message.payload(), &buf[0..size]);
 
-    // Take received message and wrap it in a LinkPacket
-    let packet = Packet::build(message.command_id(), PayloadType::GraphQL, 0, &buf[0..size])
-        .and_then(|packet| packet.to_bytes())
-        .map_err(|e| e.to_string())?;
+    reserve_response(memory, size)?;
+    let res = downlink_graphql_response(write_conn, write, &*message, keys, fec, &buf[0..size]);
+    memory.release(size, RESPONSE_PRIORITY);
+    res
+}
+
+// Stamps a GraphQL request with the command ID of the packet it arrived in, so that the
+// services it passes through can log it. Requests which the ground already stamped keep
+// their own trace ID
+fn traced_request(payload: &[u8], command_id: u64) -> Vec<u8> {
+    match std::str::from_utf8(payload) {
+        Ok(request) => trace::stamp(request, &command_id.to_string()).into_bytes(),
+        // Not something the destination can parse anyway, so leave it alone
+        Err(_) => payload.to_vec(),
+    }
+}
+
+// Reserves memory for a response to the ground while it's downlinked
+fn reserve_response(memory: &MemoryBudget, size: usize) -> CommsResult<()> {
+    match memory.reserve(size, RESPONSE_PRIORITY) {
+        Reservation::Granted => Ok(()),
+        Reservation::Refused => Err(CommsServiceError::MemoryCapReached.into()),
+        Reservation::Shedding(priority) => {
+            Err(CommsServiceError::SheddingTraffic(priority).into())
+        }
+    }
+}
+
+// Compresses and encrypts a GraphQL response, as requested by the ground,
+// and writes it to the gateway
+fn downlink_graphql_response<WriteConnection: Clone, Packet: LinkPacket>(
+    write_conn: WriteConnection,
+    write: &Arc<WriteFn<WriteConnection>>,
+    message: &Packet,
+    keys: &KeySlots,
+    fec: Fec,
+    response: &[u8],
+) -> CommsResult<()> {
+    // Compress the response if the ground asked for it
+    let (compression, payload) = compress(response, message.compression());
+    if compression != Compression::None {
+        debug!(
+            "Compressed GraphQL Response with {:?}: {} -> {} bytes",
+            compression,
+            response.len(),
+            payload.len()
+        );
+    }
+
+    // Take received message and wrap it in a LinkPacket addressed to the requesting station.
+    // Compression has to come first, since encrypted data doesn't compress.
+    let packet = build_downlink::<Packet>(
+        keys,
+        keys.downlink_slot(None),
+        message.command_id(),
+        PayloadType::GraphQL,
+        message.response_port(),
+        &payload,
+        message.station_id(),
+        message.qos(),
+    )
+    .and_then(|mut packet| {
+        packet.set_compression(compression)?;
+        packet.to_bytes()
+    })
+    .map(|frame| fec.encode(&frame))?;
 
     // Write packet to the gateway
-    write(&write_conn.clone(), &packet).map_err(|e| e.to_string())?;
+    write(&write_conn.clone(), &packet)?;
     debug!("Downlinked GraphQL Response from {}", message.destination());
 
     Ok(())
@@ -415,41 +1149,49 @@ fn handle_udp_dl_stream_request<WriteConnection: Clone, Packet: LinkPacket>(
     write_conn: WriteConnection,
     write: &Arc<WriteFn<WriteConnection>>,
     message: Box<Packet>,
+    keys: &KeySlots,
+    fec: Fec,
+    memory: &MemoryBudget,
     read_timeout: u64,
     write_timeout: u64,
     sat_ip: Ipv4Addr,
-) -> Result<(), String> {
+) -> CommsResult<()> {
     use std::time::Duration;
 
-    let socket = UdpSocket::bind((sat_ip, 0)).map_err(|e| e.to_string())?;
+    let socket = UdpSocket::bind((sat_ip, 0))?;
 
     socket
-        .set_read_timeout(Some(Duration::from_millis(read_timeout)))
-        .map_err(|e| e.to_string())?;
+        .set_read_timeout(Some(Duration::from_millis(read_timeout)))?;
 
     socket
-        .set_write_timeout(Some(Duration::from_millis(write_timeout)))
-        .map_err(|e| e.to_string())?;
+        .set_write_timeout(Some(Duration::from_millis(write_timeout)))?;
 
     socket
-        .send_to(&message.payload(), (sat_ip, message.destination()))
-        .map_err(|e| e.to_string())?;
+        .send_to(&message.payload(), (sat_ip, message.destination()))?;
 
     let mut buf = [0; 16 * 1024];
 
     while let Ok((size, _addr)) = socket.recv_from(&mut buf) {
-        // Take received message and wrap it in a LinkPacket
-        let packet = Packet::build(
+        reserve_response(memory, size)?;
+
+        // Take received message and wrap it in a LinkPacket addressed to the requesting station
+        let res = build_downlink::<Packet>(
+            keys,
+            keys.downlink_slot(None),
             message.command_id(),
             PayloadType::UDPDlStream,
-            0,
+            message.response_port(),
             &buf[0..size],
+            message.station_id(),
+            message.qos(),
         )
         .and_then(|packet| packet.to_bytes())
-        .map_err(|e| e.to_string())?;
-
+        .map(|frame| fec.encode(&frame))
         // Write packet to the gateway
-        write(&write_conn.clone(), &packet).map_err(|e| e.to_string())?;
+        .and_then(|packet| write(&write_conn.clone(), &packet));
+
+        memory.release(size, RESPONSE_PRIORITY);
+        res?;
     }
 
     Ok(())
@@ -461,157 +1203,414 @@ fn handle_udp_dl_stream_request<WriteConnection: Clone, Packet: LinkPacket>(
 fn handle_udp_passthrough<Packet: LinkPacket>(
     message: Box<Packet>,
     sat_ip: Ipv4Addr,
-) -> Result<(), String> {
-    let socket = UdpSocket::bind((sat_ip, 0)).map_err(|e| e.to_string())?;
+) -> CommsResult<()> {
+    let socket = UdpSocket::bind((sat_ip, 0))?;
 
-    socket
-        .send_to(&message.payload(), (sat_ip, message.destination()))
-        .map_err(|e| e.to_string())
-        .map(|_c| ())
+    socket.send_to(&message.payload(), (sat_ip, message.destination()))?;
+    Ok(())
 }
 
-// This thread reads indefinitely from a UDP socket, creating link packets from
-// the UDP packet payload and then writes the link packets to a gateway.
-fn downlink_endpoint<ReadConnection: Clone, WriteConnection: Clone, Packet: LinkPacket>(
+// Most packets from each downlink port which may be waiting to be downlinked
+const MAX_QUEUED_PACKETS: u32 = 32;
+
+// A downlink port's share of the downlink writer thread
+struct DownlinkEndpoint<WriteConnection> {
+    port: DownlinkPort,
+    // The port's own write function and any it's mirrored to, paired with their index in the
+    // control block
+    writes: Vec<(usize, Arc<WriteFn<WriteConnection>>)>,
+    fec: Fec,
+    // Number of the port's packets waiting in the queue
+    num_packets: Arc<AtomicU32>,
+    // Hands buffers back to the port's endpoint thread once their packets have been downlinked
+    return_tx: mpsc::Sender<Vec<u8>>,
+}
+
+// A packet received by a downlink port, waiting to be downlinked
+struct QueuedPacket {
+    // Index of the port in the downlink ports list
+    endpoint: usize,
+    qos: u8,
+    address: SocketAddr,
+    buf: Vec<u8>,
+    // Where the payload lies in the buffer, after any QoS prefix
+    start: usize,
+    end: usize,
+}
+
+impl QueuedPacket {
+    fn payload(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+}
+
+// This thread reads indefinitely from a UDP socket (bound to the endpoint's port by the
+// self-test) and adds the packets it receives to the downlink queue.
+// The number of buffers is limited, the thread will loop/wait for buffers to be released then
+// continue.
+#[allow(clippy::too_many_arguments)]
+fn downlink_endpoint(
     data: &Arc<Mutex<CommsTelemetry>>,
+    index: usize,
     port: DownlinkPort,
-    write_conn: WriteConnection,
-    write: &Arc<WriteFn<WriteConnection>>,
-    sat_ip: Ipv4Addr,
+    socket: UdpSocket,
+    queue: &DownlinkQueue<QueuedPacket>,
+    memory: &MemoryBudget,
+    ports: &PortControls,
+    num_packets: &AtomicU32,
+    return_rx: &mpsc::Receiver<Vec<u8>>,
 ) {
-    // Bind the downlink endpoint to a UDP socket.
-    // let socket = match UdpSocket::bind((sat_ip, port)) {
-    //     Ok(sock) => sock,
-    //     Err(e) => return log_error(&data, e.to_string()).unwrap(),
-    // };
-
     debug!("Starting downlink endpoint {:?}", &port);
+    let priority = port.priority.unwrap_or(0);
+    let buf_size = port.buf_size.unwrap_or(8 * 1024);
+    info!(
+        "Starting UDP receiving thread for {}, buf_size: {}",
+        &port.port, &buf_size
+    );
+
+    let mut buf: Option<Vec<u8>> = None;
+    loop {
+        if let None = &buf {
+            buf = Some(match return_rx.try_recv() {
+                Ok(buf) => buf,
+                Err(_) => {
+                    let num_pkts = num_packets.load(Ordering::SeqCst);
+                    if num_pkts >= MAX_QUEUED_PACKETS {
+                        std::thread::yield_now();
+                        continue;
+                    } else {
+                        debug!("Created new buffer for {}", &port.port);
+                        vec![0; buf_size]
+                    }
+                }
+            });
+        }
 
-    let (packet_tx, packet_rx) = mpsc::channel();
-    let (return_tx, return_rx) = mpsc::channel();
-    let num_packets = Arc::new(AtomicU32::new(0));
-
-    let max = 32;
-
-    let data_c = data.clone();
-    let num_packets_c = num_packets.clone();
-
-    // This thread receives data for downlink, buffers it and puts it in a fifo.
-    // The number of buffers is limited, the thread will loop/wait for buffers to be released then
-    // continue.
-    let port_c = port.clone();
-    thread::Builder::new()
-        .stack_size(4 * 1024)
-        .spawn(move || {
-            let buf_size = port_c.buf_size.unwrap_or(8 * 1024);
-            let port = port_c.port;
-            info!(
-                "Starting UDP receiving thread for {}, buf_size: {}",
-                &port, &buf_size
-            );
-            let data = data_c;
-            let num_packets = num_packets_c;
-            // Bind the downlink endpoint to a UDP socket.
-            let socket = match UdpSocket::bind((sat_ip, port)) {
-                Ok(sock) => sock,
-                Err(e) => return log_error(&data, e.to_string()).unwrap(),
-            };
-
-            let mut buf: Option<Vec<u8>> = None;
-            loop {
-                if let None = &buf {
-                    buf = Some(match return_rx.try_recv() {
-                        Ok(buf) => buf,
-                        Err(_) => {
-                            let num_pkts = num_packets.load(Ordering::SeqCst);
-                            if num_pkts >= max {
-                                std::thread::yield_now();
-                                continue;
-                            } else {
-                                debug!("Created new buffer for {}", &port);
-                                vec![0; buf_size]
-                            }
-                        }
-                    });
+        if let Some(mut mut_buf) = buf.take() {
+            // Indefinitely wait for a message from any application or service.
+            let (size, address) = match socket.recv_from(&mut mut_buf) {
+                Ok(tuple) => tuple,
+                Err(e) => {
+                    log_error(&data, &e.into()).unwrap();
+                    buf = Some(mut_buf);
+                    continue;
                 }
+            };
 
-                if let Some(mut mut_buf) = buf.take() {
-                    // Indefinitely wait for a message from any application or service.
-                    let (size, address) = match socket.recv_from(&mut mut_buf) {
-                        Ok(tuple) => tuple,
-                        Err(e) => {
-                            log_error(&data, e.to_string()).unwrap();
-                            buf = Some(mut_buf);
-                            continue;
-                        }
-                    };
+            // Packets sent to a disabled port are dropped rather than queued
+            if !ports.downlink_enabled(port.port) {
+                log_telemetry(&data, &TelemType::MutedDown).unwrap();
+                buf = Some(mut_buf);
+                continue;
+            }
 
-                    if let Err(SendError((_size, _address, bad_buf))) =
-                        packet_tx.send((size, address, mut_buf))
-                    {
-                        error!("Failed to send packet to channel");
-                        buf = Some(bad_buf);
+            // Packets can carry their own QoS in their first byte
+            let (qos, start) = if port.qos_prefix.unwrap_or(false) {
+                match mut_buf[0..size].first() {
+                    Some(qos) => (*qos, 1),
+                    None => {
+                        debug!("Dropping empty packet sent to {}", &port.port);
+                        buf = Some(mut_buf);
                         continue;
                     }
-
-                    num_packets.fetch_add(1, Ordering::SeqCst);
                 }
+            } else {
+                (port.qos.unwrap_or(0), 0)
+            };
+
+            // The whole buffer is held until the packet has been downlinked
+            if !reserve_downlink(&data, &memory, mut_buf.len(), priority) {
+                log_source_telemetry(&data, port.port, &address, size, &TelemType::Shed).unwrap();
+                buf = Some(mut_buf);
+                continue;
             }
-        })
-        .unwrap();
 
+            num_packets.fetch_add(1, Ordering::SeqCst);
+            queue.push(
+                qos,
+                QueuedPacket {
+                    endpoint: index,
+                    qos,
+                    address,
+                    buf: mut_buf,
+                    start,
+                    end: size,
+                },
+            );
+        }
+    }
+}
+
+// This thread takes the packets from the downlink queue, highest QoS first, creates link packets
+// from their payloads and then writes the link packets to a gateway with each of their port's
+// write functions. It also tells each sender how many packets it may send, and notes how each
+// write with a port's own function went, for the recommended payload size.
+#[allow(clippy::too_many_arguments)]
+fn downlink_writer<WriteConnection: Clone, Packet: LinkPacket>(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    endpoints: &[DownlinkEndpoint<WriteConnection>],
+    queue: &DownlinkQueue<QueuedPacket>,
+    write_conn: WriteConnection,
+    keys: &KeySlots,
+    memory: &MemoryBudget,
+    ports: &PortControls,
+    sizing: Option<&PayloadSizing>,
+) {
     // This socket is used specifically for sending backpreassure to the client
     let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
 
-    // Take the packets from the FIFO and downlink them.
-    // Also tell the sender how many packets we want from them.
-    while let Ok((size, address, buf)) = packet_rx.recv() {
-        if let Some(num_pkts) = num_packets
-            .fetch_update(
-                |x| match x {
-                    x if x > 0 => Some(x - 1),
-                    _ => None,
-                },
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            )
-            .ok()
-        {
-            // tell the sender how many packets they're allowed to send us.
-            let msg = &[max as u8 - std::cmp::min(num_pkts, max) as u8];
-            if let Err(e) = socket.send_to(msg, address) {
-                debug!("Could not send backpreassure: {:?}", e);
+    loop {
+        let first = queue.pop();
+        let endpoint = &endpoints[first.endpoint];
+        let port = &endpoint.port;
+        let priority = port.priority.unwrap_or(0);
+        let coalesce_size = port.coalesce_size.unwrap_or(0).min(Packet::max_size());
+
+        let mut batch = vec![];
+        for queued in coalesce_batch(queue, first, coalesce_size) {
+            if let Some(num_pkts) = endpoint
+                .num_packets
+                .fetch_update(
+                    |x| match x {
+                        x if x > 0 => Some(x - 1),
+                        _ => None,
+                    },
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .ok()
+            {
+                // tell the sender how many packets they're allowed to send us.
+                let msg = &[
+                    MAX_QUEUED_PACKETS as u8 - std::cmp::min(num_pkts, MAX_QUEUED_PACKETS) as u8
+                ];
+                if let Err(e) = socket.send_to(msg, queued.address) {
+                    debug!("Could not send backpreassure: {:?}", e);
+                }
             }
+
+            // Drop the packet if its port was disabled while it was waiting to be downlinked
+            if !ports.downlink_enabled(port.port) {
+                memory.release(queued.buf.len(), priority);
+                log_telemetry(&data, &TelemType::MutedDown).unwrap();
+                continue;
+            }
+
+            // Drop the packet if its priority is being shed to make room for other traffic.
+            // Its buffer is freed rather than reused, so the memory is given back.
+            if memory.is_shedding(priority) {
+                memory.release(queued.buf.len(), priority);
+                log_telemetry(&data, &TelemType::Shed).unwrap();
+                log_source_telemetry(
+                    &data,
+                    port.port,
+                    &queued.address,
+                    queued.payload().len(),
+                    &TelemType::Shed,
+                )
+                .unwrap();
+                continue;
+            }
+            batch.push(queued);
+        }
+        if batch.is_empty() {
+            continue;
         }
 
-        // Take received message and wrap it in a Link packet.
-        // Setting port to 0 because we don't know the ground port...
-        // That is known by the ground comms service
-        let packet = match Packet::build(0, PayloadType::UDP, port.port, &buf[0..size])
-            .and_then(|packet| packet.to_bytes())
+        // Packets downlinked together are coalesced, each behind a sub-frame header
+        let coalesced;
+        let (payload_type, payload) = if batch.len() == 1 {
+            (PayloadType::UDP, batch[0].payload())
+        } else {
+            coalesced = coalesce(batch.iter().map(QueuedPacket::payload));
+            (PayloadType::Coalesced, &coalesced[..])
+        };
+
+        // Take received message and wrap it in a Link packet, tagged for the port's
+        // ground station (if any).
+        let station_id = port.station_id.unwrap_or(0);
+        let packet = match build_downlink::<Packet>(
+            keys,
+            keys.downlink_slot(Some(port.port)),
+            0,
+            payload_type,
+            port.port,
+            payload,
+            station_id,
+            batch[0].qos,
+        )
+        .and_then(|packet| packet.to_bytes())
+        .map(|frame| endpoint.fec.encode(&frame))
         {
             Ok(packet) => packet,
             Err(e) => {
-                log_error(&data, e.to_string()).unwrap();
+                for queued in batch {
+                    memory.release(queued.buf.len(), priority);
+                }
+                log_error(&data, &e).unwrap();
                 continue;
             }
         };
 
-        // Write packet to the gateway and update telemetry.
-        match write(&write_conn.clone(), &packet) {
-            Ok(_) => {
-                log_telemetry(&data, &TelemType::Down).unwrap();
-                // info!("Packet successfully downlinked");
-            }
-            Err(e) => {
-                log_telemetry(&data, &TelemType::DownFailed).unwrap();
-                log_error(&data, e.to_string()).unwrap();
-                error!("Packet failed to downlink");
+        // Write packet to the gateway with each write function and update telemetry.
+        // The packet has been downlinked if any of the writes succeeded.
+        let mut downlinked = false;
+        for (position, (index, write)) in endpoint.writes.iter().enumerate() {
+            let started = Instant::now();
+            let result = write(&write_conn.clone(), &packet);
+            // Mirrors don't necessarily go over the link, so only the port's own write counts
+            if let (0, Some(sizing)) = (position, sizing) {
+                sizing.record_write(result.is_ok(), started.elapsed());
             }
+
+            match result {
+                Ok(_) => {
+                    downlinked = true;
+                    log_writer_telemetry(&data, *index, &TelemType::Down).unwrap();
+                }
+                Err(e) => {
+                    log_writer_telemetry(&data, *index, &TelemType::DownFailed).unwrap();
+                    log_error(&data, &e).unwrap();
+                    error!("Packet failed to downlink with write function {}", index);
+                }
+            };
+        }
+
+        let telem_type = if downlinked {
+            log_telemetry(&data, &TelemType::Down).unwrap();
+            log_station_telemetry(&data, station_id, &TelemType::Down).unwrap();
+            TelemType::Down
+        } else {
+            log_telemetry(&data, &TelemType::DownFailed).unwrap();
+            TelemType::DownFailed
         };
 
-        if let Err(_) = return_tx.send(buf) {
-            error!("Dropping packet as failed to send back to udp thread");
+        for queued in batch {
+            log_source_telemetry(
+                &data,
+                port.port,
+                &queued.address,
+                queued.payload().len(),
+                &telem_type,
+            )
+            .unwrap();
+
+            memory.release(queued.buf.len(), priority);
+            if let Err(_) = endpoint.return_tx.send(queued.buf) {
+                error!("Dropping packet as failed to send back to udp thread");
+            }
+        }
+    }
+}
+
+// Takes the packets from the same port, with the same QoS, which are waiting to be downlinked
+// along with the first, for as long as their sub-frames fit in `coalesce_size` bytes
+fn coalesce_batch(
+    queue: &DownlinkQueue<QueuedPacket>,
+    first: QueuedPacket,
+    coalesce_size: usize,
+) -> Vec<QueuedPacket> {
+    let (endpoint, qos) = (first.endpoint, first.qos);
+    let mut size = SUBFRAME_HEADER_SIZE + first.payload().len();
+    let mut batch = vec![first];
+    while let Some(queued) = queue.try_pop_next(
+        |queued| queued.endpoint == endpoint && queued.qos == qos,
+        |queued| size + SUBFRAME_HEADER_SIZE + queued.payload().len() <= coalesce_size,
+    ) {
+        size += SUBFRAME_HEADER_SIZE + queued.payload().len();
+        batch.push(queued);
+    }
+    batch
+}
+
+// Reserves memory for a packet received by a downlink port, logging the packet as shed
+// if the memory cap has been reached
+fn reserve_downlink(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    memory: &MemoryBudget,
+    size: usize,
+    priority: u8,
+) -> bool {
+    match memory.reserve(size, priority) {
+        Reservation::Granted => return true,
+        Reservation::Refused => {}
+        Reservation::Shedding(shed) => {
+            log_error(&data, &CommsServiceError::SheddingTraffic(shed).into()).unwrap();
+            warn!("Memory cap reached, shedding priority {} traffic", shed);
         }
     }
+    log_telemetry(&data, &TelemType::Shed).unwrap();
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::parse_echo_response;
+
+    #[test]
+    fn echo_downlinked() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let written_ref = written.clone();
+        let write: Arc<WriteFn<()>> = Arc::new(move |_, frame: &[u8]| {
+            written_ref.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        });
+        let config = kubos_system::Config::new_from_str(
+            "comms-service",
+            "[comms-service.comms]\nip = \"0.0.0.0\"",
+        )
+        .unwrap();
+        let keys = KeySlots::new(&CommsConfig::new(config).unwrap()).unwrap();
+        let request =
+            SpacePacket::build_for_station(77, PayloadType::Echo, 0, b"seq 1", 3).unwrap();
+
+        downlink_echo((), &write, &*request, &keys, Fec::None).unwrap();
+
+        let written = written.lock().unwrap();
+        let response = SpacePacket::parse(&written[0]).unwrap();
+        assert_eq!(response.command_id(), 77);
+        assert_eq!(u16::from(response.payload_type()), 4);
+        assert_eq!(response.station_id(), 3);
+        let payload = response.payload();
+        let (received, payload) = parse_echo_response(&payload).unwrap();
+        assert!(received > 0);
+        assert_eq!(payload, b"seq 1");
+    }
+
+    #[test]
+    fn echo_downlinked_with_fec() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let written_ref = written.clone();
+        let write: Arc<WriteFn<()>> = Arc::new(move |_, frame: &[u8]| {
+            written_ref.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        });
+        let keys = KeySlots::default();
+        let request =
+            SpacePacket::build_for_station(78, PayloadType::Echo, 0, b"seq 2", 0).unwrap();
+
+        downlink_echo((), &write, &*request, &keys, Fec::ReedSolomon).unwrap();
+
+        let mut frame = written.lock().unwrap()[0].clone();
+        frame[0] ^= 0xff;
+        let decoded = Fec::ReedSolomon.decode(&frame);
+        assert_eq!(decoded.corrected_blocks, 1);
+        let response = SpacePacket::parse(&decoded.frame).unwrap();
+        assert_eq!(response.command_id(), 78);
+        let payload = response.payload();
+        let (_received, payload) = parse_echo_response(&payload).unwrap();
+        assert_eq!(payload, b"seq 2");
+    }
+
+    #[test]
+    fn request_traced() {
+        let request = traced_request(b"{ ping }", 77);
+        assert_eq!(request, b"# trace-id: 77\n{ ping }".to_vec());
+
+        let request = traced_request(b"# trace-id: ground-1\n{ ping }", 77);
+        assert_eq!(request, b"# trace-id: ground-1\n{ ping }".to_vec());
+
+        assert_eq!(traced_request(&[0xff, 0xfe], 77), vec![0xff, 0xfe]);
+    }
 }
@@ -0,0 +1,321 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Adaptive payload sizing
+//!
+//! Services which produce bulk downlink traffic (such as file transfers or telemetry exports)
+//! choose how much data to put in each packet they send to a downlink port. Large packets make
+//! the best use of a good link, but when the link degrades, each lost or stalled frame costs more.
+//!
+//! If `payload_sizing` is set, the downlink writer keeps track of how its writes to the gateway
+//! are going, and the service advertises a recommended payload size. A write which fails, or
+//! which takes longer than `slow_write_ms`, halves the recommended size, down to `min_size`.
+//! After `RECOVERY_WRITES` good writes in a row, it grows by an eighth of the range between
+//! `min_size` and `max_size`, until it's back at `max_size`.
+//!
+//! Producers fetch the recommended size by sending a datagram (its contents are ignored) to the
+//! local `payload_sizing.port`. The answer is the size, as a four byte big-endian integer.
+//! [`query_payload_size`](fn.query_payload_size.html) does this for them.
+
+use crate::config::CommsConfig;
+use crate::errors::*;
+use byteorder::{BigEndian, ByteOrder};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Size of the answer to a recommended payload size query
+pub const PAYLOAD_SIZE_ANSWER_SIZE: usize = 4;
+
+// Smallest payload size recommended, unless the config says otherwise
+const DEFAULT_MIN_SIZE: usize = 128;
+// Writes which take longer than this (in milliseconds) count as failures, unless the config
+// says otherwise
+const DEFAULT_SLOW_WRITE_MS: u64 = 1000;
+// Good writes in a row needed before the recommended size grows
+const RECOVERY_WRITES: u32 = 16;
+// Number of steps the recommended size takes to grow from `min_size` to `max_size`
+const RECOVERY_STEPS: usize = 8;
+
+struct Sizing {
+    recommended: usize,
+    good_writes: u32,
+}
+
+/// Recommended payload size for packets sent to the downlink ports, based on how recent writes
+/// to the gateway went
+///
+/// Clones share the recommended size.
+#[derive(Clone)]
+pub struct PayloadSizing {
+    sizing: Arc<Mutex<Sizing>>,
+    port: u16,
+    min_size: usize,
+    max_size: usize,
+    slow_write: Duration,
+}
+
+impl ::std::fmt::Debug for PayloadSizing {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "PayloadSizing {{ port: {}, recommended: {}, min_size: {}, max_size: {} }}",
+            self.port,
+            self.recommended(),
+            self.min_size,
+            self.max_size
+        )
+    }
+}
+
+impl PayloadSizing {
+    /// Creates a tracker which recommends `max_size` until writes start failing or taking longer
+    /// than `slow_write`, and is queried on the given local port
+    pub fn new(
+        port: u16,
+        min_size: usize,
+        max_size: usize,
+        slow_write: Duration,
+    ) -> CommsResult<Self> {
+        if min_size == 0 || min_size > max_size {
+            return Err(CommsServiceError::ConfigError(format!(
+                "Payload sizing needs 0 < min_size <= max_size, got {} and {}",
+                min_size, max_size
+            ))
+            .into());
+        }
+
+        Ok(PayloadSizing {
+            sizing: Arc::new(Mutex::new(Sizing {
+                recommended: max_size,
+                good_writes: 0,
+            })),
+            port,
+            min_size,
+            max_size,
+            slow_write,
+        })
+    }
+
+    /// Creates a tracker from the service's `payload_sizing`, if it's set
+    pub fn from_config(config: &CommsConfig) -> CommsResult<Option<Self>> {
+        match config.payload_sizing {
+            Some(ref sizing) => PayloadSizing::new(
+                sizing.port,
+                sizing.min_size.unwrap_or(DEFAULT_MIN_SIZE),
+                sizing.max_size,
+                Duration::from_millis(sizing.slow_write_ms.unwrap_or(DEFAULT_SLOW_WRITE_MS)),
+            )
+            .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Local port which producers query the recommended size on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Payload size, in bytes, which producers are currently recommended to send
+    pub fn recommended(&self) -> usize {
+        self.sizing
+            .lock()
+            .map(|sizing| sizing.recommended)
+            .unwrap_or(self.min_size)
+    }
+
+    /// Notes how a write to the gateway went: whether it succeeded and how long it took
+    pub fn record_write(&self, written: bool, elapsed: Duration) {
+        let mut sizing = match self.sizing.lock() {
+            Ok(sizing) => sizing,
+            Err(_) => return,
+        };
+
+        if !written || elapsed > self.slow_write {
+            sizing.good_writes = 0;
+            let recommended = (sizing.recommended / 2).max(self.min_size);
+            if recommended != sizing.recommended {
+                warn!(
+                    "Downlink degraded, recommending {} byte payloads",
+                    recommended
+                );
+                sizing.recommended = recommended;
+            }
+            return;
+        }
+
+        sizing.good_writes += 1;
+        if sizing.good_writes >= RECOVERY_WRITES && sizing.recommended < self.max_size {
+            sizing.good_writes = 0;
+            let step = ((self.max_size - self.min_size) / RECOVERY_STEPS).max(1);
+            sizing.recommended = (sizing.recommended + step).min(self.max_size);
+            info!(
+                "Downlink recovering, recommending {} byte payloads",
+                sizing.recommended
+            );
+        }
+    }
+}
+
+/// Builds the answer to a recommended payload size query
+pub fn payload_size_answer(size: usize) -> [u8; PAYLOAD_SIZE_ANSWER_SIZE] {
+    let mut answer = [0; PAYLOAD_SIZE_ANSWER_SIZE];
+    BigEndian::write_u32(&mut answer, size as u32);
+    answer
+}
+
+/// Reads the recommended payload size from the answer to a query
+pub fn parse_payload_size_answer(answer: &[u8]) -> CommsResult<usize> {
+    if answer.len() != PAYLOAD_SIZE_ANSWER_SIZE {
+        return Err(CommsServiceError::ParsingError(format!(
+            "Payload size answer needs {} bytes, got {}",
+            PAYLOAD_SIZE_ANSWER_SIZE,
+            answer.len()
+        ))
+        .into());
+    }
+
+    Ok(BigEndian::read_u32(answer) as usize)
+}
+
+/// Asks a communications service for the payload size it currently recommends
+///
+/// # Arguments
+///
+/// - service - Address of the service's `payload_sizing.port`
+/// - timeout - How long to wait for the answer
+pub fn query_payload_size(service: SocketAddr, timeout: Duration) -> CommsResult<usize> {
+    let local = match service {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send_to(&[], service)?;
+
+    let mut buf = [0; PAYLOAD_SIZE_ANSWER_SIZE];
+    let (size, _) = socket.recv_from(&mut buf)?;
+    parse_payload_size_answer(&buf[0..size])
+}
+
+// This thread answers each datagram sent to the sizing port with the recommended payload size
+pub(crate) fn answer_queries(sizing: &PayloadSizing, socket: &UdpSocket) {
+    info!("Answering payload size queries on {}", sizing.port);
+    let mut buf = [0; 64];
+    loop {
+        let address = match socket.recv_from(&mut buf) {
+            Ok((_, address)) => address,
+            Err(e) => {
+                debug!("Failed to receive payload size query: {:?}", e);
+                continue;
+            }
+        };
+
+        let answer = payload_size_answer(sizing.recommended());
+        if let Err(e) = socket.send_to(&answer, address) {
+            debug!("Failed to answer payload size query: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const FAST: Duration = Duration::from_millis(1);
+
+    fn sizing() -> PayloadSizing {
+        PayloadSizing::new(0, 100, 900, Duration::from_millis(500)).unwrap()
+    }
+
+    #[test]
+    fn failures_shrink_payloads() {
+        let sizing = sizing();
+        assert_eq!(sizing.recommended(), 900);
+
+        sizing.record_write(false, FAST);
+        assert_eq!(sizing.recommended(), 450);
+
+        // Slow writes count as failures
+        sizing.record_write(true, Duration::from_secs(1));
+        assert_eq!(sizing.recommended(), 225);
+
+        // The size never drops below the minimum
+        sizing.record_write(false, FAST);
+        sizing.record_write(false, FAST);
+        assert_eq!(sizing.recommended(), 100);
+    }
+
+    #[test]
+    fn good_writes_grow_payloads() {
+        let sizing = sizing();
+        sizing.record_write(false, FAST);
+        sizing.record_write(false, FAST);
+        sizing.record_write(false, FAST);
+        assert_eq!(sizing.recommended(), 112);
+
+        for _ in 0..RECOVERY_WRITES - 1 {
+            sizing.record_write(true, FAST);
+        }
+        assert_eq!(sizing.recommended(), 112);
+        sizing.record_write(true, FAST);
+        assert_eq!(sizing.recommended(), 212);
+
+        // A failure part way through starts the count again
+        for _ in 0..RECOVERY_WRITES - 1 {
+            sizing.record_write(true, FAST);
+        }
+        sizing.record_write(false, FAST);
+        assert_eq!(sizing.recommended(), 106);
+
+        for _ in 0..RECOVERY_WRITES * 20 {
+            sizing.record_write(true, FAST);
+        }
+        assert_eq!(sizing.recommended(), 900);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert!(PayloadSizing::new(0, 0, 900, FAST).is_err());
+        assert!(PayloadSizing::new(0, 1000, 900, FAST).is_err());
+    }
+
+    #[test]
+    fn answer_round_trip() {
+        assert_eq!(
+            parse_payload_size_answer(&payload_size_answer(1234)).unwrap(),
+            1234
+        );
+        assert!(parse_payload_size_answer(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn query_answered() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let sizing = sizing();
+        sizing.record_write(false, FAST);
+
+        let sizing_ref = sizing.clone();
+        thread::spawn(move || answer_queries(&sizing_ref, &socket));
+
+        assert_eq!(
+            query_payload_size(address, Duration::from_secs(1)).unwrap(),
+            450
+        );
+    }
+}
@@ -15,13 +15,48 @@
 //
 
 //! Packet Definition for SpacePacket
+//!
+//! By default the APID of a packet gives its payload type, and a command header at the start of
+//! the packet data field gives its command ID and destination port. Ground systems which follow
+//! the CCSDS convention of assigning an APID to each subsystem can instead be supported with
+//! APID routes (see [`SpacePacket::set_apid_routes`]). A packet with a routed APID has no
+//! command header: its payload type and destination port come from the route, and packets from
+//! the routed port (responses and downlink endpoint packets) are sent with the routed APID.
+//!
+//! When the primary header's secondary header flag is set, a secondary header holding the
+//! packet's metadata sits between the primary header and the rest of the packet data field.
+//! It starts with a 2-byte, big-endian flags field, which says which optional fields follow it:
+//!
+//! - Bit 0: a 1-byte ground station ID follows
+//! - Bit 1: a time code follows
+//! - Bits 2-7: reserved, zero
+//! - Bits 8-9: the payload's compression (see [`Compression`])
+//! - Bits 10-11: the packet's QoS
+//! - Bits 12-15: the slot of the key the payload is encrypted with, or zero if it isn't
+//!
+//! Packets with no metadata to carry leave the secondary header out, so they keep the original
+//! layout.
+//!
+//! Packets can also be stamped with the time they were built, in a CCSDS time code
+//! (see [`SpacePacket::set_time_code`]). The time code follows the station ID in the secondary
+//! header, starting with its P-field, so stamped packets can be parsed whatever the time code.
+//!
+//! [`Compression`]: ../compression/enum.Compression.html
+//! [`SpacePacket::set_apid_routes`]: struct.SpacePacket.html#method.set_apid_routes
+//! [`SpacePacket::set_time_code`]: struct.SpacePacket.html#method.set_time_code
 
+use crate::compression::Compression;
+use crate::errors::CommsServiceError;
 use crate::packet::{LinkPacket, PayloadType};
+use crate::timecode::{TimeCode, TimeStamp};
 use crate::CommsResult;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use lazy_static::lazy_static;
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
 use std::io::Cursor;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
 
 #[derive(Eq, Debug, PartialEq)]
 struct PrimaryHeader {
@@ -34,6 +69,7 @@ struct PrimaryHeader {
     /// Application Process ID - 11 bits
     app_proc_id: u16,
     /// Sequence Flags - 2 bits
+    /// Packets are never segmented, so these are always "unsegmented"
     sequence_flags: u8,
     /// Packet Sequence Count or Packet Name - 14 bits
     sequence_count: u16,
@@ -41,8 +77,23 @@ struct PrimaryHeader {
     data_length: u16,
 }
 
-#[derive(Eq, Debug, PartialEq)]
+#[derive(Clone, Default, Eq, Debug, PartialEq)]
 struct SecondaryHeader {
+    /// Ground station ID - 8 bits
+    /// Zero (no specific station) isn't carried on the wire
+    station_id: u8,
+    /// Payload compression - 2 bits of the flags field
+    compression: Compression,
+    /// Payload encryption key slot - 4 bits of the flags field
+    key_slot: u8,
+    /// Quality of service - 2 bits of the flags field
+    qos: u8,
+    /// Time the packet was built, if it was stamped with one
+    time_stamp: Option<TimeStamp>,
+}
+
+#[derive(Eq, Debug, PartialEq)]
+struct CommandHeader {
     /// Command ID from MT - 64 bits
     command_id: u64,
     /// Destination service port - 16 bits
@@ -54,50 +105,321 @@ struct SecondaryHeader {
 pub struct SpacePacket {
     primary_header: PrimaryHeader,
     secondary_header: SecondaryHeader,
+    command_header: CommandHeader,
+    /// Payload type of a packet with a routed APID, which has no command header on the wire.
+    /// The command header then holds the routed port, with no command ID.
+    routed_type: Option<u16>,
     payload: Vec<u8>,
 }
 
+/// Ties an APID to a service, for ground systems which assign APIDs per subsystem
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApidRoute {
+    /// Application process ID (6-2047). APIDs 0-5 carry the payload type of unrouted packets.
+    pub apid: u16,
+    /// Port of the service packets with this APID are sent to, and which their responses come from
+    pub port: u16,
+    /// Optional: Payload type of packets with this APID.
+    /// Default: GraphQL
+    pub payload_type: Option<PayloadType>,
+}
+
+impl ApidRoute {
+    fn payload_type(&self) -> u16 {
+        u16::from(self.payload_type.clone().unwrap_or(PayloadType::GraphQL))
+    }
+}
+
+// Highest APID which fits in the primary header
+const MAX_APID: u16 = 0x7FF;
+// APIDs below this carry the payload type of unrouted packets, up to `PayloadType::Coalesced`
+const MIN_ROUTED_APID: u16 = 6;
+
 #[cfg(not(feature = "uplink"))]
 const PACKET_TYPE: u8 = 0;
 #[cfg(feature = "uplink")]
 const PACKET_TYPE: u8 = 1;
 
+// Bits of the sequence count field
+const SEQUENCE_COUNT_MASK: u16 = 0x3FFF;
+
+// Sequence flags of a packet which isn't segmented
+const UNSEGMENTED: u8 = 0b11;
+
+// Size of the command header
+const COMMAND_HEADER_SIZE: usize = 10;
+// Secondary header flag marking a ground station ID
+const STATION_ID_FLAG: u16 = 0x1;
+// Secondary header flag marking a time code
+const TIME_CODE_FLAG: u16 = 0x2;
+// Position of the compression within the secondary header flags
+const COMPRESSION_SHIFT: u16 = 8;
+// Position of the QoS within the secondary header flags
+const QOS_SHIFT: u16 = 10;
+// Highest QoS which fits in the secondary header flags
+const MAX_QOS: u8 = 3;
+// Position of the key slot within the secondary header flags
+const KEY_SLOT_SHIFT: u16 = 12;
+
 lazy_static! {
     static ref SEQUENCE_COUNT: Mutex<u16> = Mutex::new(0);
+    static ref APID_ROUTES: RwLock<Vec<ApidRoute>> = RwLock::new(vec![]);
+    static ref TIME_CODE: RwLock<Option<TimeCode>> = RwLock::new(None);
 }
 
-impl LinkPacket for SpacePacket {
-    fn build(
+impl SecondaryHeader {
+    // Flags for the optional fields the header carries. None are set if the packet doesn't
+    // need a secondary header
+    fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.station_id != 0 {
+            flags |= STATION_ID_FLAG;
+        }
+        if self.time_stamp.is_some() {
+            flags |= TIME_CODE_FLAG;
+        }
+        flags
+            | u16::from(u8::from(self.compression)) << COMPRESSION_SHIFT
+            | u16::from(self.qos) << QOS_SHIFT
+            | u16::from(self.key_slot) << KEY_SLOT_SHIFT
+    }
+
+    fn read(reader: &mut Cursor<Vec<u8>>) -> CommsResult<Self> {
+        let flags = reader.read_u16::<BigEndian>()?;
+        let station_id = if flags & STATION_ID_FLAG != 0 {
+            reader.read_u8()?
+        } else {
+            0
+        };
+        let time_stamp = if flags & TIME_CODE_FLAG != 0 {
+            Some(TimeStamp::read(reader)?)
+        } else {
+            None
+        };
+
+        Ok(SecondaryHeader {
+            station_id,
+            compression: Compression::from(((flags >> COMPRESSION_SHIFT) & 0x3) as u8),
+            key_slot: (flags >> KEY_SLOT_SHIFT) as u8,
+            qos: ((flags >> QOS_SHIFT) as u8) & MAX_QOS,
+            time_stamp,
+        })
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) -> CommsResult<()> {
+        let flags = self.flags();
+        bytes.write_u16::<BigEndian>(flags)?;
+        if flags & STATION_ID_FLAG != 0 {
+            bytes.write_u8(self.station_id)?;
+        }
+        if let Some(time_stamp) = &self.time_stamp {
+            time_stamp.write(bytes)?;
+        }
+        Ok(())
+    }
+
+    // Size of the header on the wire, if the packet needs one
+    fn size(&self) -> usize {
+        let flags = self.flags();
+        if flags == 0 {
+            return 0;
+        }
+
+        let mut size = 2;
+        if flags & STATION_ID_FLAG != 0 {
+            size += 1;
+        }
+        size + self.time_stamp.map_or(0, |stamp| stamp.size())
+    }
+}
+
+impl SpacePacket {
+    /// Replaces the APID routing table used when parsing and building packets
+    ///
+    /// This is called by [`CommsControlBlock::new`] with the service's `apid_routes` config.
+    ///
+    /// [`CommsControlBlock::new`]: struct.CommsControlBlock.html#method.new
+    pub fn set_apid_routes(routes: &[ApidRoute]) -> CommsResult<()> {
+        for (index, route) in routes.iter().enumerate() {
+            if route.apid < MIN_ROUTED_APID || route.apid > MAX_APID {
+                return Err(CommsServiceError::ConfigError(format!(
+                    "Routed APIDs must be between {} and {}, got {}",
+                    MIN_ROUTED_APID, MAX_APID, route.apid
+                ))
+                .into());
+            }
+            if let PayloadType::Unknown(value) = PayloadType::from(route.payload_type()) {
+                return Err(CommsServiceError::UnknownPayloadType(value).into());
+            }
+            if routes[..index].iter().any(|other| other.apid == route.apid) {
+                return Err(CommsServiceError::ConfigError(format!(
+                    "APID {} is routed more than once",
+                    route.apid
+                ))
+                .into());
+            }
+        }
+
+        let mut table = APID_ROUTES
+            .write()
+            .map_err(|_| CommsServiceError::MutexPoisoned)?;
+        *table = routes.to_vec();
+        Ok(())
+    }
+
+    /// Sets the time code which packets are stamped with as they're built, or stops stamping
+    /// them if `None`
+    ///
+    /// This is called by [`CommsControlBlock::new`] with the service's `time_code` config, so
+    /// packets built on the ground aren't stamped. Stamped packets can be parsed whatever the
+    /// time code is set to.
+    ///
+    /// [`CommsControlBlock::new`]: struct.CommsControlBlock.html#method.new
+    pub fn set_time_code(code: Option<TimeCode>) -> CommsResult<()> {
+        let mut time_code = TIME_CODE
+            .write()
+            .map_err(|_| CommsServiceError::MutexPoisoned)?;
+        *time_code = code;
+        Ok(())
+    }
+
+    // Builds a packet, stamped with the current time in the given time code if there is one
+    fn build_stamped(
         command_id: u64,
         payload_type: PayloadType,
         destination_port: u16,
         payload: &[u8],
+        station_id: u8,
+        time_code: Option<TimeCode>,
     ) -> CommsResult<Box<Self>> {
-        Ok(Box::new(SpacePacket {
+        let payload_type = u16::from(payload_type);
+        let route = Self::route_by_port(destination_port, payload_type);
+
+        // Routed packets have no command header, so can't carry a command ID
+        let (app_proc_id, command_id) = match route {
+            Some(ref route) => (route.apid, 0),
+            None => (payload_type, command_id),
+        };
+
+        let mut packet = SpacePacket {
             primary_header: PrimaryHeader {
                 version: 0,
                 packet_type: PACKET_TYPE,
                 sec_header_flag: 0,
-                app_proc_id: u16::from(payload_type),
-                sequence_flags: 0,
+                app_proc_id,
+                sequence_flags: UNSEGMENTED,
                 sequence_count: {
                     match SEQUENCE_COUNT.lock() {
                         Ok(mut sc) => {
                             let ret = *sc;
-                            *sc = sc.overflowing_add(1).0;
+                            *sc = (*sc + 1) & SEQUENCE_COUNT_MASK;
                             ret
                         }
-                        Err(_) => std::u16::MAX,
+                        Err(_) => SEQUENCE_COUNT_MASK,
                     }
                 },
-                data_length: (payload.len() + 10 - 1) as u16,
+                data_length: 0,
             },
             secondary_header: SecondaryHeader {
+                station_id,
+                time_stamp: time_code.map(|code| TimeStamp::new(code, SystemTime::now())),
+                ..Default::default()
+            },
+            command_header: CommandHeader {
                 command_id,
                 destination_port,
             },
+            routed_type: route.map(|_| payload_type),
             payload: payload.to_vec(),
-        }))
+        };
+        packet.sync_primary_header()?;
+        Ok(Box::new(packet))
+    }
+
+    // Sets the secondary header flag and data length to match the rest of the packet.
+    // Packets with no metadata leave the secondary header out, so they keep the original
+    // layout and remain compatible with ground software which doesn't know about it
+    fn sync_primary_header(&mut self) -> CommsResult<()> {
+        let command_header_len = match self.routed_type {
+            Some(_) => 0,
+            None => COMMAND_HEADER_SIZE,
+        };
+
+        // The primary header holds one less than the length of the packet data field, which
+        // can't be empty
+        let length = self.secondary_header.size() + command_header_len + self.payload.len();
+        self.primary_header.data_length = length
+            .checked_sub(1)
+            .and_then(|data_length| u16::try_from(data_length).ok())
+            .ok_or(CommsServiceError::InvalidDataLength(length))?;
+        self.primary_header.sec_header_flag = if self.secondary_header.flags() != 0 {
+            1
+        } else {
+            0
+        };
+        Ok(())
+    }
+
+    // Changes the secondary header, leaving the packet as it was if it would no longer fit
+    fn update_secondary_header<F>(&mut self, update: F) -> CommsResult<()>
+    where
+        F: FnOnce(&mut SecondaryHeader),
+    {
+        let previous = self.secondary_header.clone();
+        update(&mut self.secondary_header);
+        self.sync_primary_header().map_err(|err| {
+            self.secondary_header = previous;
+            err
+        })
+    }
+
+    // The route for packets with the given APID
+    fn route_by_apid(apid: u16) -> Option<ApidRoute> {
+        APID_ROUTES
+            .read()
+            .ok()?
+            .iter()
+            .find(|route| route.apid == apid)
+            .cloned()
+    }
+
+    // The route for packets of the given type from the given port
+    fn route_by_port(port: u16, payload_type: u16) -> Option<ApidRoute> {
+        APID_ROUTES
+            .read()
+            .ok()?
+            .iter()
+            .find(|route| route.port == port && route.payload_type() == payload_type)
+            .cloned()
+    }
+}
+
+impl LinkPacket for SpacePacket {
+    fn build(
+        command_id: u64,
+        payload_type: PayloadType,
+        destination_port: u16,
+        payload: &[u8],
+    ) -> CommsResult<Box<Self>> {
+        Self::build_for_station(command_id, payload_type, destination_port, payload, 0)
+    }
+
+    fn build_for_station(
+        command_id: u64,
+        payload_type: PayloadType,
+        destination_port: u16,
+        payload: &[u8],
+        station_id: u8,
+    ) -> CommsResult<Box<Self>> {
+        let time_code = TIME_CODE.read().ok().and_then(|code| *code);
+        Self::build_stamped(
+            command_id,
+            payload_type,
+            destination_port,
+            payload,
+            station_id,
+            time_code,
+        )
     }
 
     fn parse(raw: &[u8]) -> CommsResult<Box<Self>> {
@@ -114,8 +436,21 @@ impl LinkPacket for SpacePacket {
         let sequence_count = (header_1 & 0x3FFF) as u16;
 
         let data_length = reader.read_u16::<BigEndian>()?;
-        let command_id = reader.read_u64::<BigEndian>()?;
-        let destination_port = reader.read_u16::<BigEndian>()?;
+
+        let secondary_header = if sec_header_flag == 1 {
+            SecondaryHeader::read(&mut reader)?
+        } else {
+            SecondaryHeader::default()
+        };
+
+        let route = Self::route_by_apid(app_proc_id);
+        let (command_id, destination_port) = match route {
+            Some(ref route) => (0, route.port),
+            None => (
+                reader.read_u64::<BigEndian>()?,
+                reader.read_u16::<BigEndian>()?,
+            ),
+        };
         let pos = reader.position() as usize;
         let payload = raw[pos..].to_vec();
         Ok(Box::new(SpacePacket {
@@ -128,10 +463,12 @@ impl LinkPacket for SpacePacket {
                 sequence_count,
                 data_length,
             },
-            secondary_header: SecondaryHeader {
+            secondary_header,
+            command_header: CommandHeader {
                 command_id,
                 destination_port,
             },
+            routed_type: route.map(|route| route.payload_type()),
             payload,
         }))
     }
@@ -152,8 +489,13 @@ impl LinkPacket for SpacePacket {
         bytes.write_u16::<BigEndian>(header_0)?;
         bytes.write_u16::<BigEndian>(header_1)?;
         bytes.write_u16::<BigEndian>(header_2)?;
-        bytes.write_u64::<BigEndian>(self.secondary_header.command_id)?;
-        bytes.write_u16::<BigEndian>(self.secondary_header.destination_port)?;
+        if self.primary_header.sec_header_flag == 1 {
+            self.secondary_header.write(&mut bytes)?;
+        }
+        if self.routed_type.is_none() {
+            bytes.write_u64::<BigEndian>(self.command_header.command_id)?;
+            bytes.write_u16::<BigEndian>(self.command_header.destination_port)?;
+        }
 
         // bytes.append(&mut self.payload.clone());
         bytes.extend(&self.payload);
@@ -162,7 +504,7 @@ impl LinkPacket for SpacePacket {
     }
 
     fn command_id(&self) -> u64 {
-        self.secondary_header.command_id
+        self.command_header.command_id
     }
 
     fn payload(&self) -> Vec<u8> {
@@ -170,11 +512,51 @@ impl LinkPacket for SpacePacket {
     }
 
     fn payload_type(&self) -> PayloadType {
-        PayloadType::from(self.primary_header.app_proc_id)
+        PayloadType::from(self.routed_type.unwrap_or(self.primary_header.app_proc_id))
     }
 
     fn destination(&self) -> u16 {
-        self.secondary_header.destination_port
+        self.command_header.destination_port
+    }
+
+    fn response_port(&self) -> u16 {
+        // Responses to routed packets need the route's port to get the route's APID
+        match self.routed_type {
+            Some(_) => self.command_header.destination_port,
+            None => 0,
+        }
+    }
+
+    fn station_id(&self) -> u8 {
+        self.secondary_header.station_id
+    }
+
+    fn timestamp(&self) -> Option<SystemTime> {
+        self.secondary_header.time_stamp.map(|stamp| stamp.time())
+    }
+
+    fn compression(&self) -> Compression {
+        self.secondary_header.compression
+    }
+
+    fn set_compression(&mut self, compression: Compression) -> CommsResult<()> {
+        self.update_secondary_header(|header| header.compression = compression)
+    }
+
+    fn key_slot(&self) -> u8 {
+        self.secondary_header.key_slot
+    }
+
+    fn set_key_slot(&mut self, slot: u8) -> CommsResult<()> {
+        self.update_secondary_header(|header| header.key_slot = slot & 0xF)
+    }
+
+    fn qos(&self) -> u8 {
+        self.secondary_header.qos
+    }
+
+    fn set_qos(&mut self, qos: u8) -> CommsResult<()> {
+        self.update_secondary_header(|header| header.qos = qos.min(MAX_QOS))
     }
 
     fn max_size() -> usize {
@@ -201,6 +583,280 @@ mod tests {
         assert_eq!(packet, parsed.unwrap());
     }
 
+    #[test]
+    fn do_build_parse_station() {
+        let packet =
+            SpacePacket::build_for_station(1294, PayloadType::GraphQL, 15001, &[5, 4, 3], 7)
+                .unwrap();
+
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 3 + 10 + 3);
+        // The secondary header flag is set, and the secondary header flags a station ID
+        assert_eq!(raw[0] & 0x08, 0x08);
+        assert_eq!(&raw[6..9], &[0x00, 0x01, 7]);
+
+        let parsed = SpacePacket::parse(&raw).unwrap();
+        assert_eq!(parsed.station_id(), 7);
+        assert_eq!(parsed.payload(), vec![5, 4, 3]);
+        assert_eq!(packet, parsed);
+    }
+
+    #[test]
+    fn build_no_station_unchanged() {
+        let packet = SpacePacket::build(1294, PayloadType::GraphQL, 15001, &[5, 4, 3]).unwrap();
+
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 10 + 3);
+        assert_eq!(raw[0] & 0x08, 0);
+        assert_eq!(SpacePacket::parse(&raw).unwrap().station_id(), 0);
+    }
+
+    #[test]
+    fn do_build_parse_compression() {
+        let mut packet = SpacePacket::build(1294, PayloadType::GraphQL, 15001, &[5, 4, 3]).unwrap();
+        assert_eq!(packet.compression(), Compression::None);
+        packet.set_compression(Compression::Zstd).unwrap();
+
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 2 + 10 + 3);
+        // The sequence flags are left as "unsegmented"
+        assert_eq!(raw[2] >> 6, 0b11);
+        assert_eq!(&raw[6..8], &[0x02, 0x00]);
+
+        let parsed = SpacePacket::parse(&raw).unwrap();
+        assert_eq!(parsed.compression(), Compression::Zstd);
+        assert_eq!(packet, parsed);
+
+        // Clearing it drops the secondary header again
+        packet.set_compression(Compression::None).unwrap();
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 10 + 3);
+        assert_eq!(raw[0] & 0x08, 0);
+        assert_eq!(SpacePacket::parse(&raw).unwrap(), packet);
+    }
+
+    #[test]
+    fn do_build_parse_key_slot() {
+        let mut packet = SpacePacket::build(1294, PayloadType::GraphQL, 15001, &[5, 4, 3]).unwrap();
+        assert_eq!(packet.key_slot(), 0);
+        packet.set_key_slot(9).unwrap();
+
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 2 + 10 + 3);
+        assert_eq!(&raw[6..8], &[0x90, 0x00]);
+        // The key slot no longer takes any of the sequence count
+        assert_eq!(raw[2] & 0x3C, 0);
+
+        let parsed = SpacePacket::parse(&raw).unwrap();
+        assert_eq!(parsed.key_slot(), 9);
+        assert_eq!(packet, parsed);
+    }
+
+    #[test]
+    fn do_build_parse_qos() {
+        let mut packet = SpacePacket::build(1294, PayloadType::UDP, 15001, &[5, 4, 3]).unwrap();
+        assert_eq!(packet.qos(), 0);
+        packet.set_key_slot(9).unwrap();
+        packet.set_qos(2).unwrap();
+
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 2 + 10 + 3);
+        assert_eq!(&raw[6..8], &[0x98, 0x00]);
+        assert_eq!(raw[2] & 0x03, 0);
+
+        let parsed = SpacePacket::parse(&raw).unwrap();
+        assert_eq!(parsed.qos(), 2);
+        assert_eq!(parsed.key_slot(), 9);
+        assert_eq!(packet, parsed);
+
+        // Levels above the highest the header can carry are clamped to it
+        packet.set_qos(200).unwrap();
+        assert_eq!(packet.qos(), 3);
+        assert_eq!(packet.key_slot(), 9);
+    }
+
+    #[test]
+    fn do_build_parse_time_code() {
+        let before = std::time::SystemTime::now();
+        let mut packet = SpacePacket::build_stamped(
+            1294,
+            PayloadType::UDP,
+            15001,
+            &[5, 4, 3],
+            7,
+            Some(TimeCode::Cds),
+        )
+        .unwrap();
+        packet.set_key_slot(9).unwrap();
+        packet.set_qos(3).unwrap();
+
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 3 + 9 + 10 + 3);
+        // The time code follows the station ID in the secondary header
+        assert_eq!(&raw[6..10], &[0x9C, 0x03, 7, 0x41]);
+
+        let parsed = SpacePacket::parse(&raw).unwrap();
+        assert_eq!(parsed.station_id(), 7);
+        assert_eq!(parsed.key_slot(), 9);
+        assert_eq!(parsed.qos(), 3);
+        assert_eq!(parsed.payload(), vec![5, 4, 3]);
+        assert_eq!(packet, parsed);
+
+        let stamped = parsed.timestamp().unwrap();
+        let resolution = std::time::Duration::from_micros(1);
+        assert!(stamped + resolution >= before);
+        assert!(stamped <= std::time::SystemTime::now());
+
+        // Packets which aren't stamped don't have a time code
+        let packet = SpacePacket::build(1294, PayloadType::GraphQL, 15001, &[5, 4, 3]).unwrap();
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 10 + 3);
+        assert_eq!(SpacePacket::parse(&raw).unwrap().timestamp(), None);
+    }
+
+    #[test]
+    fn time_codes_round_trip() {
+        for (code, p_field, size) in &[(TimeCode::Cuc, 0x2E, 7), (TimeCode::Cds, 0x41, 9)] {
+            let packet = SpacePacket::build_stamped(
+                1294,
+                PayloadType::GraphQL,
+                15001,
+                &[5, 4, 3],
+                0,
+                Some(*code),
+            )
+            .unwrap();
+
+            let raw = packet.to_bytes().unwrap();
+            assert_eq!(raw.len(), 6 + 2 + size + 10 + 3);
+            // The secondary header flag is set, and the secondary header flags a time code
+            assert_eq!(raw[0] & 0x08, 0x08);
+            assert_eq!(&raw[6..9], &[0x00, 0x02, *p_field]);
+
+            let parsed = SpacePacket::parse(&raw).unwrap();
+            assert_eq!(parsed.timestamp(), packet.timestamp());
+            assert_eq!(parsed.command_id(), 1294);
+            assert_eq!(parsed.destination(), 15001);
+            assert_eq!(parsed.payload(), vec![5, 4, 3]);
+            assert_eq!(parsed.to_bytes().unwrap(), raw);
+            assert_eq!(packet, parsed);
+        }
+    }
+
+    #[test]
+    fn sequence_count_uses_all_bits() {
+        // Other tests build packets at the same time, but each build still gets its own count
+        let counts: std::collections::HashSet<u16> = (0..300)
+            .map(|_| {
+                let packet = SpacePacket::build(1, PayloadType::GraphQL, 15001, &[1]).unwrap();
+                let raw = packet.to_bytes().unwrap();
+                u16::from_be_bytes([raw[2], raw[3]]) & 0x3FFF
+            })
+            .collect();
+        assert_eq!(counts.len(), 300);
+    }
+
+    #[test]
+    fn apid_routes() {
+        // Other tests use port 15001, so aren't affected by these routes
+        let routes: Vec<ApidRoute> = vec![
+            ApidRoute {
+                apid: 0x100,
+                port: 16001,
+                payload_type: None,
+            },
+            ApidRoute {
+                apid: 0x101,
+                port: 16002,
+                payload_type: Some(PayloadType::UDP),
+            },
+        ];
+        SpacePacket::set_apid_routes(&routes).unwrap();
+
+        // Routed packets have no command header
+        let raw = b"\x01\x00\xc0\x00\x00\x04{ ping }";
+        let parsed = SpacePacket::parse(raw).unwrap();
+        assert_eq!(u16::from(parsed.payload_type()), 0);
+        assert_eq!(parsed.destination(), 16001);
+        assert_eq!(parsed.response_port(), 16001);
+        assert_eq!(parsed.payload(), b"{ ping }".to_vec());
+
+        // Responses and downlinks from a routed port use its APID
+        let response = SpacePacket::build(5, PayloadType::GraphQL, 16001, &[1, 2]).unwrap();
+        let raw = response.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 2);
+        assert_eq!(&raw[0..2], &[0x01, 0x00]);
+        assert_eq!(SpacePacket::parse(&raw).unwrap(), response);
+
+        // They can still carry a station ID in the secondary header
+        let response =
+            SpacePacket::build_for_station(5, PayloadType::GraphQL, 16001, &[1, 2], 3).unwrap();
+        let raw = response.to_bytes().unwrap();
+        assert_eq!(raw.len(), 6 + 3 + 2);
+        assert_eq!(&raw[0..2], &[0x09, 0x00]);
+        let parsed = SpacePacket::parse(&raw).unwrap();
+        assert_eq!(parsed.station_id(), 3);
+        assert_eq!(parsed.destination(), 16001);
+        assert_eq!(parsed, response);
+
+        // Without a secondary header or payload, they'd have an empty data field
+        assert!(SpacePacket::build(5, PayloadType::GraphQL, 16001, &[]).is_err());
+        assert!(SpacePacket::build_for_station(5, PayloadType::GraphQL, 16001, &[], 3).is_ok());
+
+        let downlink = SpacePacket::build(0, PayloadType::UDP, 16002, &[1, 2]).unwrap();
+        assert_eq!(&downlink.to_bytes().unwrap()[0..2], &[0x01, 0x01]);
+
+        // Only packets of the route's type are routed
+        let unrouted = SpacePacket::build(0, PayloadType::UDP, 16001, &[1, 2]).unwrap();
+        assert_eq!(unrouted.to_bytes().unwrap().len(), 6 + 10 + 2);
+        assert_eq!(unrouted.response_port(), 0);
+    }
+
+    #[test]
+    fn data_length_limits() {
+        // The largest data field the primary header can describe is 65536 bytes
+        let payload = vec![0; 65536 - 10];
+        let mut packet = SpacePacket::build(1, PayloadType::GraphQL, 15001, &payload).unwrap();
+        let raw = packet.to_bytes().unwrap();
+        assert_eq!(&raw[4..6], &[0xFF, 0xFF]);
+
+        // Adding a secondary header would make it too large, so the packet is left as it was
+        assert!(packet.set_qos(1).is_err());
+        assert_eq!(packet.qos(), 0);
+        assert_eq!(packet.to_bytes().unwrap(), raw);
+
+        let payload = vec![0; 65536 - 10 + 1];
+        assert!(SpacePacket::build(1, PayloadType::GraphQL, 15001, &payload).is_err());
+    }
+
+    #[test]
+    fn apid_routes_invalid() {
+        let route = |apid| ApidRoute {
+            apid,
+            port: 17001,
+            payload_type: None,
+        };
+
+        assert!(SpacePacket::set_apid_routes(&[route(2)]).is_err());
+        assert!(SpacePacket::set_apid_routes(&[route(5)]).is_err());
+        assert!(SpacePacket::set_apid_routes(&[route(0x800)]).is_err());
+        assert!(SpacePacket::set_apid_routes(&[route(0x200), route(0x200)]).is_err());
+    }
+
+    #[test]
+    fn apid_routes_skip_payload_types() {
+        // Every payload type's APID must be refused as a route, so they can't be confused
+        let coalesced = u16::from(PayloadType::Coalesced);
+        for apid in 0..=coalesced {
+            assert!(SpacePacket::set_apid_routes(&[ApidRoute {
+                apid,
+                port: 17001,
+                payload_type: None,
+            }])
+            .is_err());
+        }
+    }
+
     #[test]
     fn parse_python_spacepacket() {
         let raw = b"\x00\x01\x00\x00\x00\x0f\x00\x00\x00\x00\x00\x00\x00o\x05\xdcquery";
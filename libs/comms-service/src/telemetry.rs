@@ -17,15 +17,35 @@
 //
 
 use crate::errors::*;
+use failure::Error;
 use juniper::GraphQLObject;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Most sources tracked at once. Packets from further sources are counted together, so a
+// producer which sends from a new socket each time can't grow the list without bound.
+const MAX_SOURCES: usize = 64;
+// Address which packets from untracked sources are counted under
+const OTHER_SOURCES: &str = "other";
+// Most error records kept. Once full, the oldest record is dropped for each new one, so a
+// flapping link can't grow the list without bound. The per-kind counts keep the totals.
+const MAX_ERROR_RECORDS: usize = 32;
+// Kinds which errors other than `CommsServiceError`s are counted under
+const IO_ERROR: &str = "IoError";
+const OTHER_ERROR: &str = "Other";
 
 /// Generic telemetry collected by the communication service.
-#[derive(Default)]
+#[derive(Clone, Debug, Default)]
 #[cfg_attr(feature = "graphql", derive(GraphQLObject))]
 pub struct CommsTelemetry {
-    /// Errors that have occured within the communication service.
-    pub errors: Vec<String>,
+    /// The most recent errors that have occured within the communication service, oldest
+    /// first. Repeats of the latest error are counted in its record rather than added again.
+    pub errors: Vec<ErrorRecord>,
+    /// Number of errors of each kind which have occured.
+    pub error_counts: Vec<ErrorCount>,
     /// Number of bad uplink packets.
     pub failed_packets_up: i32,
     /// Number of bad downlink packets.
@@ -34,6 +54,132 @@ pub struct CommsTelemetry {
     pub packets_up: i32,
     /// Number of packets successfully downlinked.
     pub packets_down: i32,
+    /// Number of GraphQL queries answered from the response cache.
+    pub cached_responses: i32,
+    /// Number of downlink port packets dropped because the service's memory cap was reached.
+    pub shed_packets: i32,
+    /// Number of frames read from the gateway and dropped because the uplink was disabled.
+    pub muted_packets_up: i32,
+    /// Number of downlink port packets dropped because their port was disabled.
+    pub muted_packets_down: i32,
+    /// Whether frames read from the gateway are currently dropped.
+    pub uplink_disabled: bool,
+    /// Time the uplink is enabled again at, in seconds since the UNIX epoch, if it was
+    /// disabled for a limited duration.
+    pub uplink_disabled_until: Option<f64>,
+    /// Downlink ports whose packets are currently dropped.
+    pub disabled_downlink_ports: Vec<i32>,
+    /// Number of uplinked FEC blocks which had errors that were corrected.
+    pub fec_corrected_blocks: i32,
+    /// Number of uplinked FEC blocks which had too many errors to correct.
+    pub fec_uncorrectable_blocks: i32,
+    /// Packet counts for each ground station which has been heard from or sent to.
+    pub stations: Vec<StationTelemetry>,
+    /// Health of each service which GraphQL requests have been passed to.
+    pub destinations: Vec<DestinationTelemetry>,
+    /// Packet counts for each write function which downlink ports have written with.
+    pub writers: Vec<WriterTelemetry>,
+    /// Traffic from each local address which has sent packets to a downlink port.
+    pub sources: Vec<SourceTelemetry>,
+}
+
+impl CommsTelemetry {
+    /// Returns the telemetry collected so far and starts counting again from zero.
+    /// Which ports are disabled isn't a count, so it carries over.
+    pub fn reset(&mut self) -> CommsTelemetry {
+        let fresh = CommsTelemetry {
+            uplink_disabled: self.uplink_disabled,
+            uplink_disabled_until: self.uplink_disabled_until,
+            disabled_downlink_ports: self.disabled_downlink_ports.clone(),
+            ..CommsTelemetry::default()
+        };
+        mem::replace(self, fresh)
+    }
+}
+
+/// An error, or a run of identical errors, which occured within the communication service
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct ErrorRecord {
+    /// Time of the latest occurence, in seconds since the UNIX epoch.
+    pub timestamp: f64,
+    /// Kind of error. This is the name of the `CommsServiceError` variant, "IoError" or "Other".
+    pub kind: String,
+    /// Description of the error.
+    pub message: String,
+    /// Number of times in a row the error occured.
+    pub count: i32,
+}
+
+/// Number of errors of one kind
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct ErrorCount {
+    /// Kind of error, as in `ErrorRecord`.
+    pub kind: String,
+    /// Number of errors of this kind.
+    pub count: i32,
+}
+
+/// Per ground station packet counts
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct StationTelemetry {
+    /// Ground station ID. Zero is used for packets with no specific station.
+    pub station_id: i32,
+    /// Number of packets successfully uplinked from this station.
+    pub packets_up: i32,
+    /// Number of packets successfully downlinked to this station.
+    pub packets_down: i32,
+    /// Last command counter accepted from this station, if replay protection is turned on and
+    /// a command has been accepted since the telemetry was last reset.
+    pub last_counter: Option<f64>,
+}
+
+/// Health of a service which GraphQL requests have been passed to
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct DestinationTelemetry {
+    /// Port of the service.
+    pub port: i32,
+    /// Whether the last request passed to the service was answered.
+    pub reachable: bool,
+    /// Number of requests in a row which the service has failed to answer.
+    pub consecutive_failures: i32,
+    /// Number of requests answered with an error straight away, because the service had
+    /// failed too many times in the last minute.
+    pub rejected: i32,
+}
+
+/// Per write function downlink packet counts
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct WriterTelemetry {
+    /// Index of the write function in the service's list of write functions.
+    pub index: i32,
+    /// Number of downlink port packets successfully written with this function.
+    pub packets_down: i32,
+    /// Number of downlink port packets which this function failed to write.
+    pub failed_packets_down: i32,
+}
+
+/// Downlink traffic sent to a downlink port from one local address
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(GraphQLObject))]
+pub struct SourceTelemetry {
+    /// Downlink port the packets were sent to.
+    pub port: i32,
+    /// Address (IP and port) the packets were sent from, or "other" once too many sources
+    /// have been seen.
+    pub address: String,
+    /// Number of packets successfully downlinked.
+    pub packets_down: i32,
+    /// Number of payload bytes successfully downlinked.
+    pub bytes_down: i32,
+    /// Number of packets which failed to downlink.
+    pub failed_packets_down: i32,
+    /// Number of packets dropped because the service's memory cap was reached.
+    pub shed_packets: i32,
 }
 
 /// Enum used to differentiate types of telemetry collected by the communication service.
@@ -46,13 +192,82 @@ pub enum TelemType {
     Up,
     /// Packets up that failed
     UpFailed,
+    /// Queries answered from the response cache
+    Cached,
+    /// Packets dropped because the memory cap was reached
+    Shed,
+    /// Frames dropped because the uplink was disabled
+    MutedUp,
+    /// Packets dropped because their downlink port was disabled
+    MutedDown,
+}
+
+/// Get a copy of the communication service's telemetry, as it is right now.
+pub fn snapshot_telemetry(data: &Arc<Mutex<CommsTelemetry>>) -> CommsResult<CommsTelemetry> {
+    match data.lock() {
+        Ok(telem) => Ok(telem.clone()),
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
 }
 
-// Function used to obtain a mutex lock and update communication service errors.
-pub fn log_error(data: &Arc<Mutex<CommsTelemetry>>, error: String) -> CommsResult<()> {
+/// Get the communication service's telemetry and reset it to zero.
+///
+/// Both happen under the same lock, so no packets are missed between the snapshot and
+/// the reset, and the snapshots taken at the end of each pass add up to the service's totals.
+pub fn reset_telemetry(data: &Arc<Mutex<CommsTelemetry>>) -> CommsResult<CommsTelemetry> {
+    match data.lock() {
+        Ok(mut telem) => Ok(telem.reset()),
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Function used to obtain a mutex lock and record an error in the communication service
+// telemetry.
+pub fn log_error(data: &Arc<Mutex<CommsTelemetry>>, error: &Error) -> CommsResult<()> {
+    let kind = if let Some(error) = error.downcast_ref::<CommsServiceError>() {
+        error.kind()
+    } else if error.downcast_ref::<io::Error>().is_some() {
+        IO_ERROR
+    } else {
+        OTHER_ERROR
+    };
+    let message = error.to_string();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or_default();
+
     match data.lock() {
         Ok(mut telem) => {
-            telem.errors.push(error);
+            match telem
+                .error_counts
+                .iter_mut()
+                .find(|entry| entry.kind == kind)
+            {
+                Some(entry) => entry.count = entry.count.saturating_add(1),
+                None => telem.error_counts.push(ErrorCount {
+                    kind: kind.to_owned(),
+                    count: 1,
+                }),
+            }
+
+            match telem.errors.last_mut() {
+                Some(last) if last.kind == kind && last.message == message => {
+                    last.timestamp = timestamp;
+                    last.count = last.count.saturating_add(1);
+                }
+                _ => {
+                    if telem.errors.len() >= MAX_ERROR_RECORDS {
+                        telem.errors.remove(0);
+                    }
+                    telem.errors.push(ErrorRecord {
+                        timestamp,
+                        kind: kind.to_owned(),
+                        message,
+                        count: 1,
+                    });
+                }
+            }
             Ok(())
         }
         Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
@@ -68,9 +283,434 @@ pub fn log_telemetry(data: &Arc<Mutex<CommsTelemetry>>, telem_type: &TelemType)
                 TelemType::DownFailed => telem.failed_packets_down += 1,
                 TelemType::Up => telem.packets_up += 1,
                 TelemType::UpFailed => telem.failed_packets_up += 1,
+                TelemType::Cached => telem.cached_responses += 1,
+                TelemType::Shed => telem.shed_packets += 1,
+                TelemType::MutedUp => telem.muted_packets_up += 1,
+                TelemType::MutedDown => telem.muted_packets_down += 1,
+            };
+            Ok(())
+        }
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Function used to obtain a mutex lock and count the FEC blocks decoded in an uplinked frame.
+pub fn log_fec_telemetry(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    corrected: usize,
+    uncorrectable: usize,
+) -> CommsResult<()> {
+    match data.lock() {
+        Ok(mut telem) => {
+            telem.fec_corrected_blocks += corrected as i32;
+            telem.fec_uncorrectable_blocks += uncorrectable as i32;
+            Ok(())
+        }
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Finds the telemetry of a ground station, adding it if the station hasn't been seen yet
+fn station_telemetry(telem: &mut CommsTelemetry, station_id: u8) -> &mut StationTelemetry {
+    let station_id = i32::from(station_id);
+    let index = match telem
+        .stations
+        .iter()
+        .position(|station| station.station_id == station_id)
+    {
+        Some(index) => index,
+        None => {
+            telem.stations.push(StationTelemetry {
+                station_id,
+                ..Default::default()
+            });
+            telem.stations.len() - 1
+        }
+    };
+    &mut telem.stations[index]
+}
+
+// Function used to obtain a mutex lock and update the packet counts of a ground station.
+pub fn log_station_telemetry(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    station_id: u8,
+    telem_type: &TelemType,
+) -> CommsResult<()> {
+    match data.lock() {
+        Ok(mut telem) => {
+            let station = station_telemetry(&mut telem, station_id);
+            match telem_type {
+                TelemType::Down => station.packets_down += 1,
+                TelemType::Up => station.packets_up += 1,
+                _ => {}
+            };
+            Ok(())
+        }
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Function used to obtain a mutex lock and record the last command counter accepted from a
+// ground station.
+pub fn log_counter_telemetry(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    station_id: u8,
+    counter: u64,
+) -> CommsResult<()> {
+    match data.lock() {
+        Ok(mut telem) => {
+            station_telemetry(&mut telem, station_id).last_counter = Some(counter as f64);
+            Ok(())
+        }
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Function used to obtain a mutex lock and update the packet counts of a write function.
+pub fn log_writer_telemetry(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    index: usize,
+    telem_type: &TelemType,
+) -> CommsResult<()> {
+    match data.lock() {
+        Ok(mut telem) => {
+            let index = index as i32;
+            let position = match telem
+                .writers
+                .iter()
+                .position(|writer| writer.index == index)
+            {
+                Some(position) => position,
+                None => {
+                    telem.writers.push(WriterTelemetry {
+                        index,
+                        ..Default::default()
+                    });
+                    telem.writers.len() - 1
+                }
             };
+
+            let writer = &mut telem.writers[position];
+            match telem_type {
+                TelemType::Down => writer.packets_down += 1,
+                TelemType::DownFailed => writer.failed_packets_down += 1,
+                _ => {}
+            };
+            Ok(())
+        }
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Function used to obtain a mutex lock and update the traffic counts of a downlink port's
+// source address. `bytes` is the size of the packet's payload.
+pub fn log_source_telemetry(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    port: u16,
+    source: &SocketAddr,
+    bytes: usize,
+    telem_type: &TelemType,
+) -> CommsResult<()> {
+    match data.lock() {
+        Ok(mut telem) => {
+            let port = i32::from(port);
+            let mut address = source.to_string();
+            let mut position = telem
+                .sources
+                .iter()
+                .position(|entry| entry.port == port && entry.address == address);
+            if position.is_none() && telem.sources.len() >= MAX_SOURCES {
+                address = OTHER_SOURCES.to_owned();
+                position = telem
+                    .sources
+                    .iter()
+                    .position(|entry| entry.port == port && entry.address == address);
+            }
+            let position = match position {
+                Some(position) => position,
+                None => {
+                    telem.sources.push(SourceTelemetry {
+                        port,
+                        address,
+                        ..Default::default()
+                    });
+                    telem.sources.len() - 1
+                }
+            };
+
+            let entry = &mut telem.sources[position];
+            match telem_type {
+                TelemType::Down => {
+                    entry.packets_down += 1;
+                    entry.bytes_down = entry.bytes_down.saturating_add(bytes as i32);
+                }
+                TelemType::DownFailed => entry.failed_packets_down += 1,
+                TelemType::Shed => entry.shed_packets += 1,
+                _ => {}
+            };
+            Ok(())
+        }
+        Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
+    }
+}
+
+// Function used to obtain a mutex lock and update the health of a GraphQL destination.
+pub fn log_destination_telemetry(
+    data: &Arc<Mutex<CommsTelemetry>>,
+    port: u16,
+    consecutive_failures: u32,
+    rejected: bool,
+) -> CommsResult<()> {
+    match data.lock() {
+        Ok(mut telem) => {
+            let port = i32::from(port);
+            let index = match telem
+                .destinations
+                .iter()
+                .position(|destination| destination.port == port)
+            {
+                Some(index) => index,
+                None => {
+                    telem.destinations.push(DestinationTelemetry {
+                        port,
+                        ..Default::default()
+                    });
+                    telem.destinations.len() - 1
+                }
+            };
+
+            let destination = &mut telem.destinations[index];
+            destination.reachable = consecutive_failures == 0;
+            destination.consecutive_failures = consecutive_failures as i32;
+            if rejected {
+                destination.rejected += 1;
+            }
             Ok(())
         }
         Err(_) => Err(CommsServiceError::MutexPoisoned.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_returns_counts() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        log_telemetry(&data, &TelemType::Up).unwrap();
+        log_telemetry(&data, &TelemType::Down).unwrap();
+        log_station_telemetry(&data, 3, &TelemType::Up).unwrap();
+        log_error(&data, &CommsServiceError::HeaderParsing.into()).unwrap();
+
+        let snapshot = snapshot_telemetry(&data).unwrap();
+        assert_eq!(snapshot.packets_up, 1);
+
+        let pass = reset_telemetry(&data).unwrap();
+        assert_eq!(pass.packets_up, 1);
+        assert_eq!(pass.packets_down, 1);
+        assert_eq!(pass.errors[0].kind, "HeaderParsing");
+        assert_eq!(pass.error_counts[0].count, 1);
+        assert_eq!(pass.stations[0].station_id, 3);
+
+        let after = snapshot_telemetry(&data).unwrap();
+        assert_eq!(after.packets_up, 0);
+        assert_eq!(after.packets_down, 0);
+        assert!(after.errors.is_empty());
+        assert!(after.error_counts.is_empty());
+        assert!(after.stations.is_empty());
+    }
+
+    #[test]
+    fn counter_recorded() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        log_station_telemetry(&data, 3, &TelemType::Up).unwrap();
+        log_counter_telemetry(&data, 3, 41).unwrap();
+        log_counter_telemetry(&data, 3, 42).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(telem.stations.len(), 1);
+        assert_eq!(telem.stations[0].packets_up, 1);
+        assert_eq!(telem.stations[0].last_counter, Some(42.0));
+    }
+
+    #[test]
+    fn fec_counts() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        log_fec_telemetry(&data, 2, 0).unwrap();
+        log_fec_telemetry(&data, 1, 3).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(telem.fec_corrected_blocks, 3);
+        assert_eq!(telem.fec_uncorrectable_blocks, 3);
+    }
+
+    #[test]
+    fn errors_bounded() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        for _ in 0..3 {
+            log_error(&data, &CommsServiceError::InvalidChecksum.into()).unwrap();
+        }
+        for port in 0..(MAX_ERROR_RECORDS as u16 + 10) {
+            log_error(&data, &CommsServiceError::UnknownPayloadType(port).into()).unwrap();
+        }
+        let io_error = io::Error::new(io::ErrorKind::Other, "read failed");
+        log_error(&data, &io_error.into()).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(telem.errors.len(), MAX_ERROR_RECORDS);
+        let last = telem.errors.last().unwrap();
+        assert_eq!(last.kind, "IoError");
+        assert_eq!(last.message, "read failed");
+        assert_eq!(
+            telem.error_counts,
+            vec![
+                ErrorCount {
+                    kind: "InvalidChecksum".to_owned(),
+                    count: 3,
+                },
+                ErrorCount {
+                    kind: "UnknownPayloadType".to_owned(),
+                    count: MAX_ERROR_RECORDS as i32 + 10,
+                },
+                ErrorCount {
+                    kind: "IoError".to_owned(),
+                    count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_errors_counted() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        log_error(&data, &CommsServiceError::InvalidChecksum.into()).unwrap();
+        log_error(&data, &CommsServiceError::InvalidChecksum.into()).unwrap();
+        log_error(&data, &CommsServiceError::HeaderParsing.into()).unwrap();
+        log_error(&data, &CommsServiceError::InvalidChecksum.into()).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        let runs: Vec<(&str, i32)> = telem
+            .errors
+            .iter()
+            .map(|record| (record.kind.as_str(), record.count))
+            .collect();
+        assert_eq!(
+            runs,
+            vec![
+                ("InvalidChecksum", 2),
+                ("HeaderParsing", 1),
+                ("InvalidChecksum", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn destination_health() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        log_destination_telemetry(&data, 8000, 0, false).unwrap();
+        log_destination_telemetry(&data, 8001, 1, false).unwrap();
+        log_destination_telemetry(&data, 8001, 2, true).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(
+            telem.destinations,
+            vec![
+                DestinationTelemetry {
+                    port: 8000,
+                    reachable: true,
+                    consecutive_failures: 0,
+                    rejected: 0,
+                },
+                DestinationTelemetry {
+                    port: 8001,
+                    reachable: false,
+                    consecutive_failures: 2,
+                    rejected: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn writer_counts() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        log_writer_telemetry(&data, 0, &TelemType::Down).unwrap();
+        log_writer_telemetry(&data, 2, &TelemType::DownFailed).unwrap();
+        log_writer_telemetry(&data, 0, &TelemType::Down).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(
+            telem.writers,
+            vec![
+                WriterTelemetry {
+                    index: 0,
+                    packets_down: 2,
+                    failed_packets_down: 0,
+                },
+                WriterTelemetry {
+                    index: 2,
+                    packets_down: 0,
+                    failed_packets_down: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn source_counts() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        let payload = "127.0.0.1:5000".parse().unwrap();
+        let camera = "127.0.0.1:5001".parse().unwrap();
+        log_source_telemetry(&data, 14011, &payload, 100, &TelemType::Down).unwrap();
+        log_source_telemetry(&data, 14011, &payload, 50, &TelemType::Down).unwrap();
+        log_source_telemetry(&data, 14011, &camera, 100, &TelemType::Shed).unwrap();
+        log_source_telemetry(&data, 14012, &payload, 10, &TelemType::DownFailed).unwrap();
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(
+            telem.sources,
+            vec![
+                SourceTelemetry {
+                    port: 14011,
+                    address: "127.0.0.1:5000".to_owned(),
+                    packets_down: 2,
+                    bytes_down: 150,
+                    failed_packets_down: 0,
+                    shed_packets: 0,
+                },
+                SourceTelemetry {
+                    port: 14011,
+                    address: "127.0.0.1:5001".to_owned(),
+                    packets_down: 0,
+                    bytes_down: 0,
+                    failed_packets_down: 0,
+                    shed_packets: 1,
+                },
+                SourceTelemetry {
+                    port: 14012,
+                    address: "127.0.0.1:5000".to_owned(),
+                    packets_down: 0,
+                    bytes_down: 0,
+                    failed_packets_down: 1,
+                    shed_packets: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sources_capped() {
+        let data = Arc::new(Mutex::new(CommsTelemetry::default()));
+        for port in 0..(MAX_SOURCES as u16 + 2) {
+            let source = SocketAddr::from(([127, 0, 0, 1], 5000 + port));
+            log_source_telemetry(&data, 14011, &source, 1, &TelemType::Down).unwrap();
+        }
+
+        let telem = snapshot_telemetry(&data).unwrap();
+        assert_eq!(telem.sources.len(), MAX_SOURCES + 1);
+        let other = telem.sources.last().unwrap();
+        assert_eq!(other.address, "other");
+        assert_eq!(other.packets_down, 2);
+    }
+}
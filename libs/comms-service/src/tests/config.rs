@@ -109,3 +109,235 @@ fn config_write_downlink_mismatch() {
         "Config error: There must be a unique write function for each downlink port"
     );
 }
+
+#[test]
+fn config_mirror_writes() {
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        r#"
+        [comms-service.comms]
+        ip = "0.0.0.0"
+
+        [[comms-service.comms.downlink_ports]]
+        port = 14011
+        mirror_writes = [1]
+        "#,
+    )
+    .unwrap();
+
+    let config = CommsConfig::new(config).unwrap();
+
+    let result = CommsControlBlock::new(
+        Some(Arc::new(test_read)),
+        vec![Arc::new(test_write), Arc::new(test_write)],
+        1,
+        2,
+        config,
+    );
+
+    assert!(result.is_ok());
+}
+
+#[test]
+fn config_mirror_writes_missing() {
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        r#"
+        [comms-service.comms]
+        ip = "0.0.0.0"
+
+        [[comms-service.comms.downlink_ports]]
+        port = 14011
+        mirror_writes = [1]
+        "#,
+    )
+    .unwrap();
+
+    let config = CommsConfig::new(config).unwrap();
+
+    let result = CommsControlBlock::new(
+        Some(Arc::new(test_read)),
+        vec![Arc::new(test_write)],
+        1,
+        2,
+        config,
+    );
+
+    assert_eq!(
+        format!("{}", result.unwrap_err()),
+        "Config error: Downlink port mirrors a write function which doesn't exist"
+    );
+}
+
+#[test]
+fn config_payload_sizing() {
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        r#"
+        [comms-service.comms]
+        ip = "0.0.0.0"
+
+        [comms-service.comms.payload_sizing]
+        port = 14020
+        max_size = 200
+        "#,
+    )
+    .unwrap();
+
+    let config = CommsConfig::new(config).unwrap();
+
+    let control = CommsControlBlock::new(
+        Some(Arc::new(test_read)),
+        vec![Arc::new(test_write)],
+        1,
+        2,
+        config,
+    )
+    .unwrap();
+
+    let sizing = control.payload_sizing.unwrap();
+    assert_eq!(sizing.port(), 14020);
+    assert_eq!(sizing.recommended(), 200);
+}
+
+#[test]
+fn config_payload_sizing_invalid() {
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        r#"
+        [comms-service.comms]
+        ip = "0.0.0.0"
+
+        [comms-service.comms.payload_sizing]
+        port = 14020
+        min_size = 500
+        max_size = 200
+        "#,
+    )
+    .unwrap();
+
+    let config = CommsConfig::new(config).unwrap();
+
+    let result = CommsControlBlock::new(
+        Some(Arc::new(test_read)),
+        vec![Arc::new(test_write)],
+        1,
+        2,
+        config,
+    );
+
+    assert_eq!(
+        format!("{}", result.unwrap_err()),
+        "Config error: Payload sizing needs 0 < min_size <= max_size, got 500 and 200"
+    );
+}
+
+#[test]
+fn config_flatsat() {
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        r#"
+        [comms-service.comms]
+        ip = "127.0.0.1"
+        flatsat = true
+        flatsat_uplink_port = 0
+
+        [[comms-service.comms.downlink_ports]]
+        port = 14011
+
+        [[comms-service.comms.downlink_ports]]
+        port = 14012
+        "#,
+    )
+    .unwrap();
+
+    let mut config = CommsConfig::new(config).unwrap();
+
+    // The bridge sends downlinked frames to a stand-in ground socket
+    let ground = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    config.flatsat_downlink_port = Some(ground.local_addr().unwrap().port());
+
+    // No read or write functions are needed in flatsat mode
+    let control = CommsControlBlock::new(None, vec![], 1u8, 2u8, config).unwrap();
+    assert_eq!(control.write.len(), 2);
+
+    (control.write[1])(&2, b"downlink").unwrap();
+    let mut buf = [0; 16];
+    let (size, bridge) = ground.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..size], b"downlink");
+
+    ground.send_to(b"uplink", bridge).unwrap();
+    let read = control.read.unwrap();
+    assert_eq!(read(&1).unwrap(), b"uplink".to_vec());
+}
+
+#[test]
+fn config_flatsat_invalid_ground_ip() {
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        r#"
+        [comms-service.comms]
+        ip = "127.0.0.1"
+        flatsat = true
+        ground_ip = "ground"
+        flatsat_uplink_port = 0
+        "#,
+    )
+    .unwrap();
+
+    let config = CommsConfig::new(config).unwrap();
+
+    let result = CommsControlBlock::new(
+        Some(Arc::new(test_read)),
+        vec![Arc::new(test_write)],
+        1,
+        2,
+        config,
+    );
+
+    assert_eq!(
+        format!("{}", result.unwrap_err()),
+        "Config error: Invalid flatsat IP address: ground"
+    );
+}
+
+#[test]
+fn config_port_state_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ports");
+    std::fs::write(&path, "downlink 14012\ndownlink 14013\n").unwrap();
+
+    let config = kubos_system::Config::new_from_str(
+        "comms-service",
+        &format!(
+            r#"
+            [comms-service.comms]
+            ip = "0.0.0.0"
+            port_state_file = "{}"
+
+            [[comms-service.comms.downlink_ports]]
+            port = 14011
+
+            [[comms-service.comms.downlink_ports]]
+            port = 14012
+            "#,
+            path.display()
+        ),
+    )
+    .unwrap();
+
+    let config = CommsConfig::new(config).unwrap();
+
+    let control = CommsControlBlock::new(
+        Some(Arc::new(test_read)),
+        vec![Arc::new(test_write), Arc::new(test_write)],
+        1,
+        2,
+        config,
+    )
+    .unwrap();
+
+    // Ports which are no longer configured are forgotten
+    assert_eq!(control.ports.disabled_downlink_ports(), vec![14012]);
+    assert!(control.ports.uplink_enabled());
+}
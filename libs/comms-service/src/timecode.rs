@@ -0,0 +1,223 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! CCSDS time codes (CCSDS 301.0-B-4), which downlinked packets can be stamped with
+//!
+//! Each time code starts with its P-field, so the ground can decode it without knowing
+//! which code the service was configured with:
+//!
+//! - CUC: P-field `0x2E`, then 4 bytes of seconds since the Unix epoch and 2 bytes of binary
+//!   fraction of the second
+//! - CDS: P-field `0x41`, then 2 bytes of days since 1958-01-01, 4 bytes of milliseconds of
+//!   the day and 2 bytes of microseconds of the millisecond
+//!
+//! All fields are big-endian. Times come from the system clock, so they're UTC rather than TAI.
+
+use crate::errors::*;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde_derive::Deserialize;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// P-field of a CUC time code with an agency-defined epoch (the Unix epoch),
+// 4 coarse bytes and 2 fine bytes
+const CUC_P_FIELD: u8 = 0x2E;
+// P-field of a CDS time code with the CCSDS epoch, a 16-bit day segment and
+// microsecond resolution
+const CDS_P_FIELD: u8 = 0x41;
+// Seconds from the CCSDS epoch (1958-01-01) to the Unix epoch
+const CDS_EPOCH_OFFSET: u64 = 4383 * 86400;
+
+/// CCSDS time codes which downlinked packets can be stamped with
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum TimeCode {
+    /// CCSDS Unsegmented Time Code, with a resolution of 1/65536 seconds. 7 bytes
+    Cuc,
+    /// CCSDS Day Segmented Time Code, with a resolution of 1 microsecond. 9 bytes
+    Cds,
+}
+
+/// Time a packet was generated, as carried in a CCSDS time code
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeStamp {
+    /// Unsegmented time code
+    Cuc {
+        /// Seconds since the Unix epoch
+        seconds: u32,
+        /// Fraction of the second, in units of 1/65536 seconds
+        fraction: u16,
+    },
+    /// Day segmented time code
+    Cds {
+        /// Days since 1958-01-01
+        days: u16,
+        /// Milliseconds of the day
+        millis: u32,
+        /// Microseconds of the millisecond
+        micros: u16,
+    },
+}
+
+impl TimeStamp {
+    /// Encodes a time with the given time code. Times before the Unix epoch are stamped
+    /// as the Unix epoch
+    pub fn new(code: TimeCode, time: SystemTime) -> TimeStamp {
+        let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match code {
+            TimeCode::Cuc => TimeStamp::Cuc {
+                seconds: since_unix.as_secs() as u32,
+                fraction: ((u64::from(since_unix.subsec_nanos()) << 16) / 1_000_000_000) as u16,
+            },
+            TimeCode::Cds => {
+                let seconds = since_unix.as_secs() + CDS_EPOCH_OFFSET;
+                TimeStamp::Cds {
+                    days: (seconds / 86400) as u16,
+                    millis: ((seconds % 86400) * 1000) as u32 + since_unix.subsec_millis(),
+                    micros: (since_unix.subsec_micros() % 1000) as u16,
+                }
+            }
+        }
+    }
+
+    /// The time the stamp was taken
+    pub fn time(&self) -> SystemTime {
+        match *self {
+            TimeStamp::Cuc { seconds, fraction } => {
+                UNIX_EPOCH
+                    + Duration::new(
+                        u64::from(seconds),
+                        ((u64::from(fraction) * 1_000_000_000) >> 16) as u32,
+                    )
+            }
+            TimeStamp::Cds {
+                days,
+                millis,
+                micros,
+            } => {
+                UNIX_EPOCH - Duration::from_secs(CDS_EPOCH_OFFSET)
+                    + Duration::from_secs(u64::from(days) * 86400)
+                    + Duration::from_millis(u64::from(millis))
+                    + Duration::from_micros(u64::from(micros))
+            }
+        }
+    }
+
+    /// Number of bytes the time code takes up, including its P-field
+    pub fn size(&self) -> usize {
+        match self {
+            TimeStamp::Cuc { .. } => 7,
+            TimeStamp::Cds { .. } => 9,
+        }
+    }
+
+    /// Reads a time code, starting with its P-field
+    pub fn read<R: Read>(reader: &mut R) -> CommsResult<TimeStamp> {
+        match reader.read_u8()? {
+            CUC_P_FIELD => Ok(TimeStamp::Cuc {
+                seconds: reader.read_u32::<BigEndian>()?,
+                fraction: reader.read_u16::<BigEndian>()?,
+            }),
+            CDS_P_FIELD => Ok(TimeStamp::Cds {
+                days: reader.read_u16::<BigEndian>()?,
+                millis: reader.read_u32::<BigEndian>()?,
+                micros: reader.read_u16::<BigEndian>()?,
+            }),
+            other => Err(CommsServiceError::ParsingError(format!(
+                "Unsupported time code P-field {:#04x}",
+                other
+            ))
+            .into()),
+        }
+    }
+
+    /// Writes the time code, starting with its P-field
+    pub fn write<W: Write>(&self, writer: &mut W) -> CommsResult<()> {
+        match *self {
+            TimeStamp::Cuc { seconds, fraction } => {
+                writer.write_u8(CUC_P_FIELD)?;
+                writer.write_u32::<BigEndian>(seconds)?;
+                writer.write_u16::<BigEndian>(fraction)?;
+            }
+            TimeStamp::Cds {
+                days,
+                millis,
+                micros,
+            } => {
+                writer.write_u8(CDS_P_FIELD)?;
+                writer.write_u16::<BigEndian>(days)?;
+                writer.write_u32::<BigEndian>(millis)?;
+                writer.write_u16::<BigEndian>(micros)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-06-01 12:34:56.789012 UTC
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::new(1_591_014_896, 789_012_000)
+    }
+
+    #[test]
+    fn cuc() {
+        let stamp = TimeStamp::new(TimeCode::Cuc, time());
+        assert_eq!(
+            stamp,
+            TimeStamp::Cuc {
+                seconds: 1_591_014_896,
+                fraction: 51708
+            }
+        );
+
+        let mut raw = vec![];
+        stamp.write(&mut raw).unwrap();
+        assert_eq!(raw, vec![0x2E, 0x5E, 0xD4, 0xF5, 0xF0, 0xC9, 0xFC]);
+        assert_eq!(raw.len(), stamp.size());
+        assert_eq!(TimeStamp::read(&mut &raw[..]).unwrap(), stamp);
+
+        // Accurate to the fraction's resolution
+        let error = time().duration_since(stamp.time()).unwrap();
+        assert!(error < Duration::from_micros(16));
+    }
+
+    #[test]
+    fn cds() {
+        let stamp = TimeStamp::new(TimeCode::Cds, time());
+        assert_eq!(
+            stamp,
+            TimeStamp::Cds {
+                days: 22797,
+                millis: 45_296_789,
+                micros: 12
+            }
+        );
+
+        let mut raw = vec![];
+        stamp.write(&mut raw).unwrap();
+        assert_eq!(raw.len(), stamp.size());
+        assert_eq!(TimeStamp::read(&mut &raw[..]).unwrap(), stamp);
+        assert_eq!(stamp.time(), time());
+    }
+
+    #[test]
+    fn unknown_p_field() {
+        assert!(TimeStamp::read(&mut &[0x1C, 0, 0, 0, 0][..]).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Time-tagged commands
+//!
+//! A packet with the `TimeTagged` payload type carries a command which should be run
+//! later, rather than as soon as it's uplinked. Its payload starts with a time tag header:
+//!
+//! | Field          | Size    | Description                                              |
+//! |----------------|---------|----------------------------------------------------------|
+//! | Execution time | 8 bytes | Unix time (seconds) at which to release the command      |
+//! | Payload type   | 2 bytes | Payload type of the embedded command (GraphQL, UDP, ...) |
+//!
+//! followed by the embedded command's payload. Both header fields are big-endian.
+//!
+//! The communications service writes each time-tagged command to its `time_tag_dir`, so
+//! that pending commands survive a reboot, and hands it to the normal message handling
+//! path (using the packet's destination port) once its execution time is reached.
+//! Commands whose execution time has already passed are released straight away.
+
+use crate::errors::*;
+use crate::packet::PayloadType;
+use byteorder::{BigEndian, ByteOrder};
+
+/// Size of the time tag header at the start of a time-tagged packet's payload
+pub const TIME_TAG_HEADER_SIZE: usize = 10;
+
+/// Builds the payload of a time-tagged command
+///
+/// # Arguments
+///
+/// - exec_time - Unix time (seconds) at which the command should be released
+/// - payload_type - Payload type of the embedded command
+/// - payload - Payload of the embedded command
+pub fn time_tag(exec_time: u64, payload_type: PayloadType, payload: &[u8]) -> Vec<u8> {
+    let mut tagged = vec![0; TIME_TAG_HEADER_SIZE];
+    BigEndian::write_u64(&mut tagged[0..8], exec_time);
+    BigEndian::write_u16(&mut tagged[8..10], u16::from(payload_type));
+    tagged.extend_from_slice(payload);
+    tagged
+}
+
+/// Splits the payload of a time-tagged command into its execution time,
+/// embedded payload type and embedded payload
+pub fn parse_time_tag(payload: &[u8]) -> CommsResult<(u64, PayloadType, &[u8])> {
+    if payload.len() < TIME_TAG_HEADER_SIZE {
+        return Err(CommsServiceError::ParsingError(format!(
+            "Time tag header needs {} bytes, got {}",
+            TIME_TAG_HEADER_SIZE,
+            payload.len()
+        ))
+        .into());
+    }
+
+    let exec_time = BigEndian::read_u64(&payload[0..8]);
+    let payload_type = PayloadType::from(BigEndian::read_u16(&payload[8..10]));
+    match payload_type {
+        PayloadType::TimeTagged => Err(CommsServiceError::ParsingError(
+            "Time-tagged commands can't be nested".to_owned(),
+        )
+        .into()),
+        PayloadType::Unknown(value) => Err(CommsServiceError::UnknownPayloadType(value).into()),
+        payload_type => Ok((exec_time, payload_type, &payload[TIME_TAG_HEADER_SIZE..])),
+    }
+}
+
+#[cfg(feature = "service")]
+pub use self::store::TimeTagStore;
+
+#[cfg(feature = "service")]
+mod store {
+    use crate::errors::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    // Pending time-tagged commands, stored as one file per command.
+    //
+    // Files are named "{exec_time}-{command_id}", so a command which is uplinked again
+    // (eg. because the ground never saw it acknowledged) replaces the stored copy rather
+    // than running twice.
+    #[derive(Clone)]
+    pub struct TimeTagStore {
+        dir: PathBuf,
+        // Execution time and file name of each pending command, in execution order
+        pending: Arc<Mutex<BTreeSet<(u64, String)>>>,
+    }
+
+    impl TimeTagStore {
+        // Opens the store, picking up any commands left from a previous run
+        pub fn open(dir: &str) -> CommsResult<Self> {
+            let dir = PathBuf::from(dir);
+            fs::create_dir_all(&dir).map_err(|err| {
+                CommsServiceError::ConfigError(format!(
+                    "Failed to create time tag directory {}: {}",
+                    dir.display(),
+                    err
+                ))
+            })?;
+
+            let mut pending = BTreeSet::new();
+            for entry in fs::read_dir(&dir)? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                // Anything else (eg. a partially written command) is ignored
+                if let Some(exec_time) = exec_time(&name) {
+                    pending.insert((exec_time, name));
+                }
+            }
+
+            if !pending.is_empty() {
+                info!("Loaded {} pending time-tagged commands", pending.len());
+            }
+
+            Ok(TimeTagStore {
+                dir,
+                pending: Arc::new(Mutex::new(pending)),
+            })
+        }
+
+        // Saves a packet to be released at the given time
+        pub fn store(&self, exec_time: u64, command_id: u64, packet: &[u8]) -> CommsResult<()> {
+            let name = format!("{}-{}", exec_time, command_id);
+            let path = self.dir.join(&name);
+            let temp = self.dir.join(format!("{}.tmp", name));
+
+            // Write to a temporary file first, so a reset mid-write can't leave
+            // a truncated command behind
+            fs::write(&temp, packet)?;
+            fs::rename(&temp, &path)?;
+
+            self.pending
+                .lock()
+                .map_err(|_| CommsServiceError::MutexPoisoned)?
+                .insert((exec_time, name));
+            Ok(())
+        }
+
+        // Removes and returns the packets of all commands due at or before `now`.
+        // Commands are removed before they're run, so a command is never run twice,
+        // even if the service restarts while it's being handled.
+        pub fn take_due(&self, now: u64) -> CommsResult<Vec<Vec<u8>>> {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|_| CommsServiceError::MutexPoisoned)?;
+
+            let mut packets = vec![];
+            while let Some(next) = pending.iter().next().cloned() {
+                if next.0 > now {
+                    break;
+                }
+                pending.remove(&next);
+
+                let path = self.dir.join(&next.1);
+                match fs::read(&path) {
+                    Ok(packet) => packets.push(packet),
+                    Err(err) => error!("Failed to read time-tagged command {}: {}", next.1, err),
+                }
+                if let Err(err) = fs::remove_file(&path) {
+                    error!("Failed to remove time-tagged command {}: {}", next.1, err);
+                }
+            }
+
+            Ok(packets)
+        }
+    }
+
+    // Execution time of a stored command's file, or None if the file isn't a stored command
+    fn exec_time(name: &str) -> Option<u64> {
+        let mut parts = name.splitn(2, '-');
+        let exec_time = parts.next()?.parse::<u64>().ok()?;
+        parts.next()?.parse::<u64>().ok()?;
+        Some(exec_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_tag_round_trip() {
+        let tagged = time_tag(1_600_000_000, PayloadType::GraphQL, b"{ ping }");
+        assert_eq!(TIME_TAG_HEADER_SIZE + 8, tagged.len());
+
+        let (exec_time, payload_type, payload) = parse_time_tag(&tagged).unwrap();
+        assert_eq!(1_600_000_000, exec_time);
+        assert_eq!(0, u16::from(payload_type));
+        assert_eq!(b"{ ping }", payload);
+    }
+
+    #[test]
+    fn parse_time_tag_short() {
+        assert!(parse_time_tag(&[0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn parse_time_tag_nested() {
+        let tagged = time_tag(0, PayloadType::TimeTagged, &[]);
+        assert!(parse_time_tag(&tagged).is_err());
+    }
+
+    #[cfg(feature = "service")]
+    #[test]
+    fn store_take_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let store = TimeTagStore::open(path).unwrap();
+        store.store(200, 1, &[2]).unwrap();
+        store.store(100, 2, &[1]).unwrap();
+        store.store(300, 3, &[3]).unwrap();
+
+        assert!(store.take_due(99).unwrap().is_empty());
+        assert_eq!(vec![vec![1], vec![2]], store.take_due(250).unwrap());
+
+        // The remaining command survives a restart
+        let store = TimeTagStore::open(path).unwrap();
+        assert_eq!(vec![vec![3]], store.take_due(1000).unwrap());
+        assert!(store.take_due(1000).unwrap().is_empty());
+    }
+}
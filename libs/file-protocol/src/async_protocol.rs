@@ -0,0 +1,323 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Async (tokio) variant of the file protocol
+//!
+//! The blocking protocol sends and receives on its own socket, so each transfer needs a thread
+//! dedicated to it. An async protocol drives the same transaction logic from a tokio task
+//! instead: the blocking protocol it wraps queues the messages it would have sent, along with any
+//! delay between chunks, and the async protocol sends them on its tokio socket.
+//!
+//! Operations which only touch storage, like `initialize_file` and `generate_channel`, are
+//! called on [`protocol`](struct.AsyncProtocol.html#method.protocol). Operations which send
+//! messages are passed to [`run`](struct.AsyncProtocol.html#method.run), so that their messages
+//! are flushed afterwards.
+//!
+//! Requires the `async` feature.
+
+use crate::error::ProtocolError;
+use crate::protocol::{Protocol, ProtocolConfig, State, Step};
+use cbor_protocol::ProtocolError as CborError;
+use serde_cbor::Value;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+/// Async file protocol information structure
+pub struct AsyncProtocol {
+    protocol: Protocol,
+    socket: UdpSocket,
+}
+
+impl AsyncProtocol {
+    /// Create a new async file protocol instance
+    ///
+    /// # Arguments
+    ///
+    /// * host_addr - The local IP and port to bind to
+    /// * remote_addr - The remote IP and port to communicate with
+    /// * config - Configuration for the protocol
+    ///
+    /// # Errors
+    ///
+    /// If the remote address can't be parsed or the socket can't be bound, an error will be
+    /// returned
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// # async fn example() -> Result<(), ProtocolError> {
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = AsyncFileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn new(
+        host_addr: &str,
+        remote_addr: &str,
+        config: ProtocolConfig,
+    ) -> Result<Self, ProtocolError> {
+        let socket = UdpSocket::bind(parse_addr(host_addr)?)
+            .await
+            .map_err(|err| CborError::IoError { err })?;
+
+        Ok(AsyncProtocol {
+            protocol: Protocol::queued(parse_addr(remote_addr)?, config),
+            socket,
+        })
+    }
+
+    /// The wrapped protocol, for operations which don't send any messages
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    /// Run a protocol operation, then send any messages it queued
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// # async fn example() -> Result<(), ProtocolError> {
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let mut f_protocol = AsyncFileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config).await?;
+    ///
+    /// let channel_id = f_protocol.protocol().generate_channel()?;
+    /// f_protocol.run(|p| p.send_import(channel_id, "service.txt")).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run<T, F>(&mut self, op: F) -> Result<T, ProtocolError>
+    where
+        F: FnOnce(&Protocol) -> Result<T, ProtocolError>,
+    {
+        let result = op(&self.protocol);
+        self.flush().await?;
+        result
+    }
+
+    /// Send the messages queued by the wrapped protocol, waiting between them
+    /// as it asked
+    pub async fn flush(&mut self) -> Result<(), ProtocolError> {
+        for (message, delay) in self.protocol.take_queued() {
+            self.send(&message).await?;
+            if delay > Duration::default() {
+                time::delay_for(delay).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send CBOR packet to the destination port
+    ///
+    /// # Arguments
+    ///
+    /// * vec - CBOR packet to send
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    pub async fn send(&mut self, vec: &[u8]) -> Result<(), ProtocolError> {
+        let dest = self.protocol.remote_addr();
+        self.socket
+            .send_to(&cbor_protocol::frame_message(vec), &dest)
+            .await
+            .map_err(|err| CborError::SendFailed { dest, err })?;
+        Ok(())
+    }
+
+    /// Receive a file protocol message
+    ///
+    /// # Arguments
+    ///
+    /// * timeout - Maximum time to wait for a reply. If `None`, will block indefinitely
+    ///
+    /// # Errors
+    ///
+    /// - If the timeout elapses, `ProtocolError::ReceiveTimeout` will be returned
+    /// - If any other errors are encountered, an error message string will be returned
+    pub async fn recv(&mut self, timeout: Option<Duration>) -> Result<Value, ProtocolError> {
+        let mut buf = vec![0; self.protocol.message_size()];
+        let received = match timeout {
+            Some(value) => time::timeout(value, self.socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| ProtocolError::ReceiveTimeout)?,
+            None => self.socket.recv_from(&mut buf).await,
+        };
+        let (size, _peer) = received.map_err(|err| CborError::ReceiveFailed { err })?;
+
+        Ok(cbor_protocol::parse_frame(&buf[0..size])?)
+    }
+
+    /// Listen for and process file protocol messages on this protocol's socket
+    ///
+    /// # Arguments
+    ///
+    /// * timeout - Maximum time to listen for a single message
+    /// * start_state - Current transaction state
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// # async fn example() -> Result<(), ProtocolError> {
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let mut f_protocol = AsyncFileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config).await?;
+    ///
+    /// f_protocol
+    ///     .message_engine(Duration::from_millis(10), &State::Transmitting)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn message_engine(
+        &mut self,
+        timeout: Duration,
+        start_state: &State,
+    ) -> Result<(), ProtocolError> {
+        let mut state = start_state.clone();
+        loop {
+            let received = self.recv(Some(timeout)).await;
+            match self.step(received, state).await? {
+                Step::Continue(next) => state = next,
+                Step::Done => return Ok(()),
+            }
+        }
+    }
+
+    /// Listen for and process file protocol messages from another source,
+    /// such as a channel fed by a service's receive loop
+    ///
+    /// # Arguments
+    ///
+    /// * pump - Function which returns a future resolving to the next message for processing
+    /// * timeout - Maximum time to listen for a single message
+    /// * start_state - Current transaction state
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    pub async fn message_engine_from<F, Fut>(
+        &mut self,
+        mut pump: F,
+        timeout: Duration,
+        start_state: &State,
+    ) -> Result<(), ProtocolError>
+    where
+        F: FnMut(Duration) -> Fut,
+        Fut: Future<Output = Result<Value, ProtocolError>>,
+    {
+        let mut state = start_state.clone();
+        loop {
+            let received = pump(timeout).await;
+            match self.step(received, state).await? {
+                Step::Continue(next) => state = next,
+                Step::Done => return Ok(()),
+            }
+        }
+    }
+
+    // Process a received message or timeout, then send any replies
+    async fn step(
+        &mut self,
+        received: Result<Value, ProtocolError>,
+        state: State,
+    ) -> Result<Step, ProtocolError> {
+        let step = self.protocol.engine_step(received, state);
+        self.flush().await?;
+        step
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, ProtocolError> {
+    addr.parse::<SocketAddr>().map_err(|err| {
+        ProtocolError::from(CborError::IoError {
+            err: io::Error::new(io::ErrorKind::InvalidInput, err),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    fn test_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("file-protocol-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn async_upload() {
+        let dir = test_dir("async");
+        let source = format!("{}/source", dir);
+        let dest = format!("{}/dest", dir);
+        let contents = vec![7u8; 5000];
+        fs::write(&source, &contents).unwrap();
+
+        let server_config =
+            ProtocolConfig::new(Some(format!("{}/server", dir)), 1024, 5, 1, None, 2048);
+        let server = Protocol::new("127.0.0.1:7201", "127.0.0.1:7200", server_config);
+        let server = thread::spawn(move || {
+            server.message_engine(
+                |d| server.recv(Some(d)),
+                Duration::from_millis(500),
+                &State::Holding {
+                    count: 0,
+                    prev_state: Box::new(State::Done),
+                },
+            )
+        });
+
+        let config = ProtocolConfig::new(Some(format!("{}/client", dir)), 1024, 5, 1, None, 2048);
+        let mut f_protocol = AsyncProtocol::new("127.0.0.1:7200", "127.0.0.1:7201", config)
+            .await
+            .unwrap();
+
+        let (hash, num_chunks, mode) = f_protocol.protocol().initialize_file(&source).unwrap();
+        let channel_id = f_protocol.protocol().generate_channel().unwrap();
+        f_protocol
+            .run(|p| {
+                p.send_metadata(channel_id, &hash, num_chunks)?;
+                p.send_export(channel_id, &hash, &dest, mode)
+            })
+            .await
+            .unwrap();
+        f_protocol
+            .message_engine(Duration::from_millis(500), &State::Transmitting)
+            .await
+            .unwrap();
+
+        server.join().unwrap().unwrap();
+        assert_eq!(contents, fs::read(&dest).unwrap());
+    }
+}
@@ -14,6 +14,7 @@
 // limitations under the License.
 //
 
+use crate::resume::ResumeToken;
 use cbor_protocol;
 use failure::Fail;
 use serde_cbor;
@@ -22,6 +23,23 @@ use std::io;
 /// Errors which occur when using FileProtocol
 #[derive(Debug, Fail)]
 pub enum ProtocolError {
+    /// The receiver's copy of a file didn't match the start of the file being appended to it
+    #[fail(display = "Unable to append to {}: {}", path, cause)]
+    AppendMismatch {
+        /// Path of the receiver's copy of the file
+        path: String,
+        /// How the copy differs
+        cause: String,
+    },
+    /// The remote target is already using the channel ID for a different transfer
+    #[fail(
+        display = "Channel {} is already in use by another transfer",
+        channel_id
+    )]
+    ChannelInUse {
+        /// Channel ID which was rejected
+        channel_id: u32,
+    },
     /// A file in storage was corrupt
     #[fail(display = "File was corrupt: {}", _0)]
     CorruptFile(String),
@@ -40,6 +58,23 @@ pub enum ProtocolError {
     /// A hash mismatch was found when finalizing the file
     #[fail(display = "File hash mismatch")]
     HashMismatch,
+    /// The requested part of a file starts beyond the end of the file
+    #[fail(
+        display = "Requested range starts at byte {} of a {} byte file",
+        offset, file_size
+    )]
+    InvalidRange {
+        /// Requested starting offset
+        offset: u64,
+        /// Actual size of the file
+        file_size: u64,
+    },
+    /// An upload signing key couldn't be used
+    #[fail(display = "Invalid upload key: {}", cause)]
+    InvalidKey {
+        /// Why the key couldn't be used
+        cause: String,
+    },
     /// An invalid value was found when parsing a message
     #[fail(display = "Unable to parse {} message: Invalid {} param", _0, _1)]
     InvalidParam(String, String),
@@ -66,12 +101,26 @@ pub enum ProtocolError {
         /// Underlying error encountered
         err: String,
     },
+    /// An interrupted transfer couldn't be resumed with the given token
+    #[fail(display = "Unable to resume transfer: {}", cause)]
+    ResumeError {
+        /// Why the transfer couldn't be resumed
+        cause: String,
+    },
     /// An error was encountered when serializing data
     #[fail(display = "Failed to serialize: {}", err)]
     Serialize {
         /// Underlying serde error
         err: serde_cbor::error::Error,
     },
+    /// An upload wasn't exported because its signature was missing or didn't verify
+    #[fail(display = "Upload of {} rejected: {}", hash, cause)]
+    SignatureRejected {
+        /// Hash of the file which was rejected
+        hash: String,
+        /// Why the signature was rejected
+        cause: String,
+    },
     /// An error was encountered when writing to or reading from file storage
     #[fail(display = "Storage failed to {}: {}", action, err)]
     StorageError {
@@ -86,6 +135,27 @@ pub enum ProtocolError {
     /// A timeout occurred when receiving data
     #[fail(display = "A receive timeout was encountered")]
     ReceiveTimeout,
+    /// The progress callback aborted a transfer before it finished
+    #[fail(display = "Transfer of {} aborted", hash)]
+    TransferAborted {
+        /// Hash of the file whose transfer was aborted
+        hash: String,
+    },
+    /// A transfer was refused because it broke the receiver's transfer limits
+    #[fail(display = "Transfer of {} refused: {}", path, cause)]
+    TransferRefused {
+        /// Path of the file which was refused
+        path: String,
+        /// Which limit the transfer broke
+        cause: String,
+    },
+    /// A transfer stopped getting replies before it finished.
+    /// It can be picked up where it left off with the token
+    #[fail(display = "Transfer timed out, resume token: {}", token)]
+    TransferTimeout {
+        /// Token for resuming the transfer
+        token: ResumeToken,
+    },
     /// An error was encountered when transmitting
     #[fail(
         display = "Transmission failure on channel {}: {}",
@@ -83,21 +83,97 @@
 //! }
 //! ```
 //!
+//! With the `async` feature enabled, [`AsyncFileProtocol`](async_protocol/index.html) drives the
+//! same transfers from a tokio task rather than a dedicated thread.
+//!
+//! Transfers which time out can be picked up on a later pass with a
+//! [`ResumeToken`](resume/index.html).
+//!
+//! Receivers can refuse to export uploads which weren't signed with one of a set of
+//! [`UploadKeys`](signing/index.html).
+//!
+//! Receivers can also refuse transfers which are too large, or which involve the wrong kinds
+//! of file or the wrong directories, with [`TransferLimits`](limits/index.html).
+//!
+//! Growing files, such as logs or ephemerides, can have just their new bytes uploaded with
+//! [`send_append`](protocol/struct.Protocol.html#method.send_append). The receiver checks that
+//! its copy matches the start of the file before adding anything to it.
+//!
+//! The throughput, retransmit ratio and ETA of a transfer are reported as
+//! [`TransferStats`](stats/index.html).
+//!
 
 #![deny(missing_docs)]
 
+#[cfg(feature = "async")]
+pub mod async_protocol;
 mod error;
+pub mod limits;
 mod messages;
 mod parsers;
 pub mod protocol;
+pub mod resume;
+pub mod signing;
+pub mod stats;
 mod storage;
+mod transfer_log;
 
+#[cfg(feature = "async")]
+pub use crate::async_protocol::AsyncProtocol as AsyncFileProtocol;
 pub use crate::error::ProtocolError;
+pub use crate::limits::TransferLimits;
+pub use crate::protocol::ChannelAllocation;
 pub use crate::protocol::Protocol as FileProtocol;
 pub use crate::protocol::ProtocolConfig as FileProtocolConfig;
 pub use crate::protocol::State;
+pub use crate::resume::ResumeToken;
+pub use crate::signing::UploadKeys;
+pub use crate::stats::TransferStats;
+pub use crate::storage::{ChunkMeta, ChunkStore, FsChunkStore, LogChunkStore, MemoryChunkStore};
+pub use crate::transfer_log::{TransferLog, TransferOperation, TransferRecord, TransferTracker};
+
+pub use crate::parsers::{parse_channel_id, parse_transfer_key};
+
+use serde_cbor::Value;
+
+/// Create the message telling a client that the channel ID it picked is already in use by
+/// another transfer. `key` is the rejected request's transfer key
+/// (see [`parse_transfer_key`](fn.parse_transfer_key.html))
+pub fn channel_in_use_message(channel_id: u32, key: &str) -> Result<Vec<u8>, ProtocolError> {
+    messages::channel_in_use(channel_id, key)
+}
+
+/// Create the message telling a client that its request on the given channel failed
+pub fn failure_message(channel_id: u32, error: &str) -> Result<Vec<u8>, ProtocolError> {
+    messages::operation_failure(channel_id, error)
+}
 
-pub use crate::parsers::parse_channel_id;
+/// Parse out a transfer log request, returning its channel ID, the number of newest entries
+/// to skip and the number of entries wanted
+pub fn parse_log_request(message: &Value) -> Option<(u32, u32, u32)> {
+    match parsers::parse_message(message.to_owned()) {
+        Ok(Message::ReqLog(channel_id, skip, count)) => Some((channel_id, skip, count)),
+        _ => None,
+    }
+}
+
+/// Create the reply to a transfer log request, holding as many of `records` as fit in a
+/// message of `max_size` bytes. `total` is the number of entries in the log
+pub fn log_entries_message(
+    channel_id: u32,
+    total: u32,
+    records: &[TransferRecord],
+    max_size: usize,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut count = records.len();
+    loop {
+        let message = messages::log_entries(channel_id, total, &records[0..count])?;
+        if message.len() <= max_size || count == 0 {
+            return Ok(message);
+        }
+        count -= 1;
+    }
+}
 
 /// File protocol message types
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -106,30 +182,49 @@ pub enum Message {
     Sync(u32, String),
     /// Receiver should prepare a new temporary storage folder with the specified metadata
     Metadata(u32, String, u32),
-    /// File data chunk message
-    ReceiveChunk(u32, String, u32, Vec<u8>),
+    /// File data chunk message, with the CRC32 of the data if the sender included one
+    ReceiveChunk(u32, String, u32, Vec<u8>, Option<u32>),
     /// Receiver has successfully gotten all data chunks of the requested file
     ACK(u32, String),
     /// Receiver is missing the specified file data chunks
     NAK(u32, String, Option<Vec<(u32, u32)>>),
-    /// (Client Only) Message requesting the recipient to receive the specified file
-    ReqReceive(u32, String, String, Option<u32>),
-    /// (Client Only) Message requesting the recipient to transmit the specified file
-    ReqTransmit(u32, String),
+    /// (Client Only) Message requesting the recipient to receive the specified file,
+    /// keeping its chunks in the named storage class if one is given
+    ReqReceive(u32, String, String, Option<u32>, Option<String>),
+    /// (Client Only) Message requesting the recipient to receive the bytes of the specified
+    /// file past the given offset, adding them to its existing copy. The hash of the bytes
+    /// before the offset comes next, so the recipient can check its copy matches them
+    ReqAppend(u32, String, String, u64, String, Option<String>),
+    /// (Client Only) Detached signature over the hash of the file about to be exported
+    Signature(u32, String, Vec<u8>),
+    /// (Client Only) Message requesting the recipient to transmit the specified file,
+    /// starting at the given byte offset and optionally limited to the given number of bytes,
+    /// keeping its chunks in the named storage class if one is given
+    ReqTransmit(u32, String, u64, Option<u64>, Option<String>),
     /// (Server Only) Recipient has successfully processed a request to receive a file
     SuccessReceive(u32, String),
     /// (Server Only) Recipient has successfully prepared to transmit a file
     SuccessTransmit(u32, String, u32, Option<u32>),
+    /// (Server Only) Recipient already has a copy of the file, so no chunks need to be sent
+    AlreadyPresent(u32, String, String),
+    /// (Server Only) The channel ID is already in use by a different transfer.
+    /// Carries the transfer key of the rejected request
+    ChannelInUse(u32, String),
     /// (Server Only) The transmit or receive request has failed to be completed
     Failure(u32, String),
     /// Request Cleanup of either whole storage directory or individual file's storage
     Cleanup(u32, Option<String>),
+    /// (Client Only) Request entries from the transfer log, newest first,
+    /// skipping the given number of newest entries
+    ReqLog(u32, u32, u32),
+    /// (Server Only) Entries from the transfer log, along with the number of entries in it
+    LogEntries(u32, u32, Vec<TransferRecord>),
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{messages, parsers, Message};
-    use serde_cbor::de;
+    use super::{messages, parsers, Message, TransferOperation, TransferRecord};
+    use serde_cbor::{de, ser, Value};
 
     #[test]
     fn create_parse_export_request() {
@@ -138,16 +233,257 @@ mod tests {
         let target_path = "/path/to/file".to_owned();
         let mode = 0o623;
 
-        let raw = messages::export_request(channel_id, &hash, &target_path, mode).unwrap();
+        let raw = messages::export_request(channel_id, &hash, &target_path, mode, None).unwrap();
+
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqReceive(channel_id, hash, target_path, Some(mode), None)
+        );
+    }
+
+    #[test]
+    fn create_parse_append_request() {
+        let channel_id = 10;
+        let hash = "abcdedf".to_owned();
+        let target_path = "/path/to/file".to_owned();
+        let prefix_hash = "0123456".to_owned();
 
+        let raw =
+            messages::append_request(channel_id, &hash, &target_path, 4096, &prefix_hash, None)
+                .unwrap();
         let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqAppend(
+                channel_id,
+                hash.clone(),
+                target_path.clone(),
+                4096,
+                prefix_hash.clone(),
+                None
+            )
+        );
 
+        let raw = messages::append_request(
+            channel_id,
+            &hash,
+            &target_path,
+            4096,
+            &prefix_hash,
+            Some("ram"),
+        )
+        .unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
         assert_eq!(
             msg.unwrap(),
-            Message::ReqReceive(channel_id, hash, target_path, Some(mode))
+            Message::ReqAppend(
+                channel_id,
+                hash,
+                target_path,
+                4096,
+                prefix_hash,
+                Some("ram".to_owned())
+            )
         );
     }
 
+    #[test]
+    fn create_parse_import_request() {
+        let channel_id = 10;
+        let source_path = "/path/to/file".to_owned();
+
+        let raw = messages::import_request(channel_id, &source_path, 0, None, None).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, source_path, 0, None, None)
+        );
+    }
+
+    #[test]
+    fn create_parse_storage_class_requests() {
+        let channel_id = 10;
+        let hash = "abcdedf".to_owned();
+        let path = "/path/to/file".to_owned();
+
+        let raw = messages::export_request(channel_id, &hash, &path, 0o644, Some("ram")).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqReceive(
+                channel_id,
+                hash,
+                path.clone(),
+                Some(0o644),
+                Some("ram".to_owned())
+            )
+        );
+
+        // The offset and length are sent even for a whole file, so the class comes after them
+        let raw = messages::import_request(channel_id, &path, 0, None, Some("sdcard")).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, path, 0, None, Some("sdcard".to_owned()))
+        );
+    }
+
+    #[test]
+    fn create_parse_signature() {
+        let channel_id = 10;
+        let hash = "abcdedf".to_owned();
+        let signature = vec![0x5a; 64];
+
+        let raw = messages::signature(channel_id, &hash, &signature).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Signature(channel_id, hash, signature)
+        );
+    }
+
+    #[test]
+    fn create_parse_log_request() {
+        let raw = messages::log_request(10, 20, 5).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::ReqLog(10, 20, 5));
+    }
+
+    #[test]
+    fn create_parse_log_entries() {
+        let records = vec![
+            TransferRecord {
+                time: 1_600_000_000,
+                peer: "127.0.0.1:7000".to_owned(),
+                channel_id: 123_456,
+                operation: TransferOperation::Import,
+                path: "/home/system/log.txt".to_owned(),
+                error: None,
+                duration_ms: 1500,
+                bytes: 8192,
+            },
+            TransferRecord {
+                time: 1_600_000_100,
+                peer: "127.0.0.1:7001".to_owned(),
+                channel_id: 123_457,
+                operation: TransferOperation::Export,
+                path: "/home/system/upload.bin".to_owned(),
+                error: Some("File hash mismatch".to_owned()),
+                duration_ms: 300,
+                bytes: 0,
+            },
+        ];
+
+        let raw = messages::log_entries(10, 7, &records).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::LogEntries(10, 7, records));
+    }
+
+    #[test]
+    fn log_entries_message_fits() {
+        let record = TransferRecord {
+            time: 1_600_000_000,
+            peer: "127.0.0.1:7000".to_owned(),
+            channel_id: 123_456,
+            operation: TransferOperation::Export,
+            path: "/home/system/file".to_owned(),
+            error: None,
+            duration_ms: 10,
+            bytes: 10,
+        };
+        let records = vec![record; 50];
+
+        let raw = super::log_entries_message(10, 50, &records, 512).unwrap();
+        assert!(raw.len() <= 512);
+        match parsers::parse_message(de::from_slice(&raw).unwrap()).unwrap() {
+            Message::LogEntries(10, 50, entries) => {
+                assert!(!entries.is_empty() && entries.len() < 50)
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn create_parse_import_range_request() {
+        let channel_id = 10;
+        let source_path = "/path/to/file".to_owned();
+
+        let raw =
+            messages::import_request(channel_id, &source_path, 4096, Some(512), None).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, source_path.clone(), 4096, Some(512), None)
+        );
+
+        let raw = messages::import_request(channel_id, &source_path, 4096, None, None).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, source_path, 4096, None, None)
+        );
+    }
+
+    #[test]
+    fn create_parse_already_present() {
+        let channel_id = 12;
+        let hash = "abcdefg".to_owned();
+        let path = "/path/to/file".to_owned();
+
+        let raw = messages::already_present(channel_id, &hash, &path).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::AlreadyPresent(channel_id, hash, path)
+        );
+    }
+
+    #[test]
+    fn create_parse_channel_in_use() {
+        let channel_id = 13;
+        let key = "/path/to/file".to_owned();
+
+        let raw = messages::channel_in_use(channel_id, &key).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::ChannelInUse(channel_id, key));
+    }
+
+    #[test]
+    fn transfer_keys() {
+        let key = |raw: Vec<u8>| parsers::parse_transfer_key(&de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            key(messages::metadata(1, "abcdef", 4).unwrap()),
+            Some("abcdef".to_owned())
+        );
+        assert_eq!(
+            key(messages::export_request(1, "abcdef", "/target", 0o644, None).unwrap()),
+            Some("abcdef".to_owned())
+        );
+        assert_eq!(
+            key(messages::append_request(1, "abcdef", "/target", 10, "012345", None).unwrap()),
+            Some("abcdef".to_owned())
+        );
+        assert_eq!(
+            key(messages::signature(1, "abcdef", &[0; 64]).unwrap()),
+            Some("abcdef".to_owned())
+        );
+        assert_eq!(
+            key(messages::import_request(1, "/source", 0, None, None).unwrap()),
+            Some("/source".to_owned())
+        );
+        assert_eq!(key(messages::nak(1, "abcdef", &[0, 1]).unwrap()), None);
+        assert_eq!(key(messages::cleanup(1, None).unwrap()), None);
+    }
+
     #[test]
     fn create_parse_sync() {
         let channel_id = 10;
@@ -186,7 +522,35 @@ mod tests {
 
         assert_eq!(
             msg.unwrap(),
-            Message::ReceiveChunk(channel_id, hash, chunk_num, chunk_data)
+            Message::ReceiveChunk(
+                channel_id,
+                hash,
+                chunk_num,
+                chunk_data,
+                Some(0x81f6_7724)
+            )
+        );
+    }
+
+    #[test]
+    fn parse_chunk_without_crc() {
+        let channel_id = 10;
+        let hash = "abcdefg".to_owned();
+        let chunk_num = 10;
+        let chunk_data: Vec<u8> = vec![1, 2, 3, 4, 5, 6];
+
+        let raw = ser::to_vec_packed(&(
+            channel_id,
+            &hash,
+            chunk_num,
+            Value::Bytes(chunk_data.clone()),
+        ))
+        .unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReceiveChunk(channel_id, hash, chunk_num, chunk_data, None)
         );
     }
 
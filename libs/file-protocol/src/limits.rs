@@ -0,0 +1,325 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Transfer limits
+//!
+//! A mistaken request, such as one to downlink a raw disk partition, can use up a whole pass and
+//! fill the temporary storage before anyone notices. A receiver configured with
+//! [`TransferLimits`](struct.TransferLimits.html) (see
+//! [`with_limits`](../protocol/struct.ProtocolConfig.html#method.with_limits)) refuses such
+//! requests up front, replying with a failure which says why.
+//!
+//! Limits apply to the files this side is asked to send (imports) and to the destinations of
+//! the files it's asked to receive (exports):
+//!
+//! - The maximum file size limits the number of bytes transferred. For an import of part of a
+//!   file, only the requested range counts. Anything which isn't a regular file (such as a
+//!   device) is refused, since its size can't be known up front. An export's size is worked
+//!   out from its number of chunks and this side's transfer chunk size, so it's refused once it's
+//!   certain to be over the limit.
+//! - The allowed extensions limit which kinds of file can be transferred. Extensions are
+//!   matched without regard to case, and files without an extension are refused.
+//! - The allowed paths limit where files can be transferred from and to. Each path must be
+//!   inside one of the allowed directories once symlinks and `..` components are resolved.
+
+use crate::error::ProtocolError;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Limits on the files which can be transferred
+#[derive(Clone, Debug, Default)]
+pub struct TransferLimits {
+    max_file_size: Option<u64>,
+    allowed_extensions: Option<Vec<String>>,
+    allowed_paths: Option<Vec<PathBuf>>,
+}
+
+impl TransferLimits {
+    /// Create a set of limits which allows any transfer
+    pub fn new() -> Self {
+        TransferLimits::default()
+    }
+
+    /// Refuse transfers of more than `bytes` bytes
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let limits = TransferLimits::new().with_max_file_size(50 * 1024 * 1024);
+    /// ```
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Only transfer files with one of the given extensions (with or without the leading `.`)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let limits = TransferLimits::new().with_allowed_extensions(&["log", ".csv"]);
+    /// ```
+    pub fn with_allowed_extensions<S: AsRef<str>>(mut self, extensions: &[S]) -> Self {
+        self.allowed_extensions = Some(
+            extensions
+                .iter()
+                .map(|ext| ext.as_ref().trim_start_matches('.').to_lowercase())
+                .collect(),
+        );
+        self
+    }
+
+    /// Only transfer files from and to the given directories, or anywhere inside them
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let limits = TransferLimits::new().with_allowed_paths(&["/home/system/logs", "/upgrade"]);
+    /// ```
+    pub fn with_allowed_paths<P: AsRef<Path>>(mut self, paths: &[P]) -> Self {
+        self.allowed_paths = Some(paths.iter().map(|path| path.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Check that the remote target may import `length` bytes (or the rest of the file, if
+    /// `None`) of the file at `path`, starting at `offset`
+    pub fn check_import(
+        &self,
+        path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<(), ProtocolError> {
+        self.check_path(path)?;
+
+        let max = match self.max_file_size {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        // Files which can't be found are reported when they're opened
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+        if !metadata.is_file() {
+            return Err(refused(path, "not a regular file"));
+        }
+
+        let remaining = metadata.len().saturating_sub(offset);
+        let size = length.map_or(remaining, |length| length.min(remaining));
+        check_size(path, size, max)
+    }
+
+    /// Check that the remote target may export a file of `num_chunks` chunks of up to
+    /// `chunk_size` bytes each to `path`
+    pub fn check_export(
+        &self,
+        path: &str,
+        num_chunks: Option<u32>,
+        chunk_size: usize,
+    ) -> Result<(), ProtocolError> {
+        self.check_append(path, 0, num_chunks, chunk_size)
+    }
+
+    /// Check that the remote target may append `num_chunks` chunks of up to `chunk_size` bytes
+    /// each to the first `offset` bytes of the file at `path`. The whole file, once appended
+    /// to, counts towards the size limit
+    pub fn check_append(
+        &self,
+        path: &str,
+        offset: u64,
+        num_chunks: Option<u32>,
+        chunk_size: usize,
+    ) -> Result<(), ProtocolError> {
+        self.check_path(path)?;
+
+        match (self.max_file_size, num_chunks) {
+            // Every chunk but the last is full, and the last holds at least one byte
+            (Some(max), Some(num_chunks)) if num_chunks > 0 => check_size(
+                path,
+                offset + u64::from(num_chunks - 1) * chunk_size as u64 + 1,
+                max,
+            ),
+            (Some(max), _) => check_size(path, offset, max),
+            _ => Ok(()),
+        }
+    }
+
+    // Check a path against the allowed extensions and directories
+    fn check_path(&self, path: &str) -> Result<(), ProtocolError> {
+        if let Some(extensions) = &self.allowed_extensions {
+            let extension = Path::new(path)
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+            match extension {
+                Some(ext) if extensions.contains(&ext) => {}
+                Some(ext) => {
+                    return Err(refused(path, format!(".{} files are not allowed", ext)));
+                }
+                None => return Err(refused(path, "files without an extension are not allowed")),
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_paths {
+            let resolved = resolve(Path::new(path));
+            if !allowed.iter().any(|dir| resolved.starts_with(resolve(dir))) {
+                return Err(refused(path, "path is not in an allowed directory"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn refused<S: Into<String>>(path: &str, cause: S) -> ProtocolError {
+    ProtocolError::TransferRefused {
+        path: path.to_owned(),
+        cause: cause.into(),
+    }
+}
+
+fn check_size(path: &str, size: u64, max: u64) -> Result<(), ProtocolError> {
+    if size > max {
+        Err(refused(
+            path,
+            format!("{} bytes is over the {} byte limit", size, max),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// The absolute path a path refers to, with symlinks followed as far as the path exists and
+// `.` and `..` components removed. The destination of an export usually doesn't exist yet
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    };
+
+    for ancestor in absolute.ancestors() {
+        if let Ok(resolved) = fs::canonicalize(ancestor) {
+            let rest = absolute
+                .strip_prefix(ancestor)
+                .unwrap_or_else(|_| Path::new(""));
+            return rest.components().fold(resolved, |mut resolved, component| {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => {
+                        resolved.pop();
+                    }
+                    other => resolved.push(other.as_os_str()),
+                }
+                resolved
+            });
+        }
+    }
+
+    absolute
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "file-protocol-limits-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn max_file_size() {
+        let dir = test_dir("size");
+        let file = dir.join("data.bin");
+        fs::write(&file, vec![0u8; 1000]).unwrap();
+        let file = file.to_str().unwrap();
+
+        let limits = TransferLimits::new().with_max_file_size(500);
+        match limits.check_import(file, 0, None) {
+            Err(ProtocolError::TransferRefused { cause, .. }) => {
+                assert_eq!(cause, "1000 bytes is over the 500 byte limit")
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // Only the requested range counts
+        assert!(limits.check_import(file, 600, None).is_ok());
+        assert!(limits.check_import(file, 0, Some(500)).is_ok());
+
+        // Devices and directories have no size to check
+        assert!(limits.check_import(dir.to_str().unwrap(), 0, None).is_err());
+
+        // Five full chunks of 100 bytes and a partial one are certainly over the limit
+        assert!(limits.check_export(file, Some(5), 100).is_ok());
+        assert!(limits.check_export(file, Some(6), 100).is_err());
+        assert!(limits.check_export(file, None, 100).is_ok());
+
+        // Appended bytes count along with the ones already there
+        assert!(limits.check_append(file, 400, Some(1), 100).is_ok());
+        assert!(limits.check_append(file, 400, Some(2), 100).is_err());
+        assert!(limits.check_append(file, 600, None, 100).is_err());
+    }
+
+    #[test]
+    fn allowed_extensions() {
+        let limits = TransferLimits::new().with_allowed_extensions(&["log", ".CSV"]);
+
+        assert!(limits.check_export("/tmp/out.log", None, 1024).is_ok());
+        assert!(limits.check_export("/tmp/out.csv", None, 1024).is_ok());
+        assert!(limits.check_export("/tmp/OUT.LOG", None, 1024).is_ok());
+        assert!(limits.check_export("/tmp/out.img", None, 1024).is_err());
+        assert!(limits.check_export("/dev/sda", None, 1024).is_err());
+    }
+
+    #[test]
+    fn allowed_paths() {
+        let dir = test_dir("paths");
+        fs::create_dir_all(dir.join("allowed/nested")).unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        let limits = TransferLimits::new().with_allowed_paths(&[dir.join("allowed")]);
+        let check = |path: &str| {
+            limits
+                .check_export(dir.join(path).to_str().unwrap(), None, 1024)
+                .is_ok()
+        };
+
+        assert!(check("allowed/file"));
+        assert!(check("allowed/nested/file"));
+        assert!(check("allowed/missing/file"));
+        assert!(!check("other/file"));
+        assert!(!check("allowed/../other/file"));
+        assert!(!check("allowed-not/file"));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("other"), dir.join("allowed/link")).unwrap();
+            assert!(!check("allowed/link/file"));
+        }
+    }
+}
@@ -15,37 +15,114 @@
 //
 
 use crate::error::ProtocolError;
+use crate::transfer_log::TransferRecord;
 use log::info;
 use serde_cbor::{ser, Value};
 
 // Create export message
+// The storage class is only added when there is one, so older services can still handle it
 pub fn export_request(
     channel_id: u32,
     hash: &str,
     target_path: &str,
     mode: u32,
+    storage_class: Option<&str>,
+) -> Result<Vec<u8>, ProtocolError> {
+    let result = match storage_class {
+        Some(class) => {
+            info!(
+                "-> {{ {}, export, {}, {}, {}, {} }}",
+                channel_id, hash, target_path, mode, class
+            );
+            ser::to_vec_packed(&(channel_id, "export", hash, target_path, mode, class))
+        }
+        None => {
+            info!(
+                "-> {{ {}, export, {}, {}, {} }}",
+                channel_id, hash, target_path, mode
+            );
+            ser::to_vec_packed(&(channel_id, "export", hash, target_path, mode))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "export".to_owned(),
+        err,
+    })
+}
+
+// Create append message, asking for new bytes to be added to an existing file past
+// the first `offset` bytes, which must hash to `prefix_hash`
+pub fn append_request(
+    channel_id: u32,
+    hash: &str,
+    target_path: &str,
+    offset: u64,
+    prefix_hash: &str,
+    storage_class: Option<&str>,
 ) -> Result<Vec<u8>, ProtocolError> {
     info!(
-        "-> {{ {}, export, {}, {}, {} }}",
-        channel_id, hash, target_path, mode
+        "-> {{ {}, append, {}, {}, {}, {}, {:?} }}",
+        channel_id, hash, target_path, offset, prefix_hash, storage_class
     );
+    ser::to_vec_packed(&(
+        channel_id,
+        "append",
+        hash,
+        target_path,
+        offset,
+        prefix_hash,
+        storage_class,
+    ))
+    .map_err(|err| ProtocolError::MessageCreationError {
+        message: "append".to_owned(),
+        err,
+    })
+}
 
-    ser::to_vec_packed(&(channel_id, "export", hash, target_path, mode)).map_err(|err| {
+// Create signature message, carrying a detached signature over the hash of the file
+// about to be exported
+pub fn signature(channel_id: u32, hash: &str, signature: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, signature, {} }}", channel_id, hash);
+    let signature = Value::Bytes(signature.to_vec());
+    ser::to_vec_packed(&(channel_id, "signature", hash, signature)).map_err(|err| {
         ProtocolError::MessageCreationError {
-            message: "export".to_owned(),
+            message: "signature".to_owned(),
             err,
         }
     })
 }
 
 // Create import message
-pub fn import_request(channel_id: u32, source_path: &str) -> Result<Vec<u8>, ProtocolError> {
-    info!("-> {{ import, {} }}", source_path);
-    ser::to_vec_packed(&(channel_id, "import", source_path)).map_err(|err| {
-        ProtocolError::MessageCreationError {
-            message: "import".to_owned(),
-            err,
+// Requests for a whole file use the original form, so older services can still handle them
+pub fn import_request(
+    channel_id: u32,
+    source_path: &str,
+    offset: u64,
+    length: Option<u64>,
+    storage_class: Option<&str>,
+) -> Result<Vec<u8>, ProtocolError> {
+    let result = match storage_class {
+        Some(class) => {
+            info!(
+                "-> {{ import, {}, {}, {:?}, {} }}",
+                source_path, offset, length, class
+            );
+            ser::to_vec_packed(&(channel_id, "import", source_path, offset, length, class))
+        }
+        None if offset == 0 && length.is_none() => {
+            info!("-> {{ import, {} }}", source_path);
+            ser::to_vec_packed(&(channel_id, "import", source_path))
+        }
+        None => {
+            info!("-> {{ import, {}, {}, {:?} }}", source_path, offset, length);
+            ser::to_vec_packed(&(channel_id, "import", source_path, offset, length))
         }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "import".to_owned(),
+        err,
     })
 }
 
@@ -105,7 +182,7 @@ pub fn nak(channel_id: u32, hash: &str, missing_chunks: &[u32]) -> Result<Vec<u8
     Ok(vec)
 }
 
-// Create chunk message
+// Create chunk message, including the CRC32 of the chunk data
 pub fn chunk(
     channel_id: u32,
     hash: &str,
@@ -113,8 +190,12 @@ pub fn chunk(
     chunk: &[u8],
 ) -> Result<Vec<u8>, ProtocolError> {
     let chunk_bytes = Value::Bytes(chunk.to_vec());
-    info!("-> {{ {}, {}, {}, chunk_data }}", channel_id, hash, index);
-    ser::to_vec_packed(&(channel_id, hash, index, chunk_bytes)).map_err(|err| {
+    let crc = crc32fast::hash(chunk);
+    info!(
+        "-> {{ {}, {}, {}, chunk_data, {:#010x} }}",
+        channel_id, hash, index, crc
+    );
+    ser::to_vec_packed(&(channel_id, hash, index, chunk_bytes, crc)).map_err(|err| {
         ProtocolError::MessageCreationError {
             message: "chunk".to_owned(),
             err,
@@ -153,6 +234,31 @@ pub fn operation_success(channel_id: u32, hash: &str) -> Result<Vec<u8>, Protoco
     })
 }
 
+// Create a response message telling the sender the file is already present, so no
+// chunks need to be sent
+pub fn already_present(channel_id: u32, hash: &str, path: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, present, {}, {} }}", channel_id, hash, path);
+    ser::to_vec_packed(&(channel_id, "present", hash, path)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "already present".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create a response message telling the sender another transfer is already using the channel.
+// The key identifies the rejected request (see `parsers::parse_transfer_key`), so that the
+// client already using the channel can tell the rejection isn't meant for it
+pub fn channel_in_use(channel_id: u32, key: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, in_use, {} }}", channel_id, key);
+    ser::to_vec_packed(&(channel_id, "in_use", key)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "channel in use".to_owned(),
+            err,
+        }
+    })
+}
+
 // Create an operation failure response message
 pub fn operation_failure(channel_id: u32, error: &str) -> Result<Vec<u8>, ProtocolError> {
     info!("-> {{ {}, false, {} }}", channel_id, error);
@@ -182,3 +288,49 @@ pub fn cleanup(channel_id: u32, hash: Option<String>) -> Result<Vec<u8>, Protoco
         }
     })
 }
+
+// Create transfer log request message
+pub fn log_request(channel_id: u32, skip: u32, count: u32) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, log, {}, {} }}", channel_id, skip, count);
+    ser::to_vec_packed(&(channel_id, "log", skip, count)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "log".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create transfer log response message. Each entry is sent as an array of the record's fields
+pub fn log_entries(
+    channel_id: u32,
+    total: u32,
+    records: &[TransferRecord],
+) -> Result<Vec<u8>, ProtocolError> {
+    info!(
+        "-> {{ {}, log_entries, {}, [{} entries] }}",
+        channel_id,
+        total,
+        records.len()
+    );
+    let entries: Vec<_> = records
+        .iter()
+        .map(|record| {
+            (
+                record.time,
+                &record.peer,
+                record.channel_id,
+                record.operation.as_str(),
+                &record.path,
+                &record.error,
+                record.duration_ms,
+                record.bytes,
+            )
+        })
+        .collect();
+    ser::to_vec_packed(&(channel_id, "log_entries", total, entries)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "log entries".to_owned(),
+            err,
+        }
+    })
+}
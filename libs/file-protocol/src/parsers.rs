@@ -16,6 +16,8 @@
 
 use super::Message;
 use crate::error::ProtocolError;
+use crate::transfer_log::{TransferOperation, TransferRecord};
+use serde_cbor::value::from_value;
 use serde_cbor::Value;
 use std::slice::Iter;
 
@@ -72,9 +74,27 @@ pub fn parse_message(message: Value) -> Result<Message, ProtocolError> {
         if let Some(msg) = parse_cleanup_request(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
+        if let Some(msg) = parse_already_present(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_channel_in_use(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_log_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_log_entries(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_signature(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
         if let Some(msg) = parse_export_request(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
+        if let Some(msg) = parse_append_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
         if let Some(msg) = parse_import_request(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
@@ -106,6 +126,21 @@ pub fn parse_message(message: Value) -> Result<Message, ProtocolError> {
     })
 }
 
+/// Parse out the key identifying the transfer a message starts, if it starts one
+///
+/// Metadata, signatures, export and append requests are keyed by the file's hash and import
+/// requests by the requested path. Other messages belong to a transfer which is already underway.
+pub fn parse_transfer_key(message: &Value) -> Option<String> {
+    match parse_message(message.to_owned()) {
+        Ok(Message::Metadata(_, hash, _))
+        | Ok(Message::Signature(_, hash, _))
+        | Ok(Message::ReqReceive(_, hash, _, _, _))
+        | Ok(Message::ReqAppend(_, hash, _, _, _, _)) => Some(hash),
+        Ok(Message::ReqTransmit(_, path, _, _, _)) => Some(path),
+        _ => None,
+    }
+}
+
 // Parse out cleanup request
 // { channel_id, "cleanup", [hash] }
 pub fn parse_cleanup_request(
@@ -133,8 +168,218 @@ pub fn parse_cleanup_request(
     Ok(None)
 }
 
+// Parse out already present response
+// { channel_id, "present", hash, path }
+pub fn parse_already_present(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Text(op)) = pieces.next() {
+        if op == "present" {
+            let hash = match pieces.next().ok_or_else(|| {
+                ProtocolError::MissingParam("present".to_owned(), "hash".to_owned())
+            })? {
+                Value::Text(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "present".to_owned(),
+                        "hash".to_owned(),
+                    ));
+                }
+            };
+
+            let path = match pieces.next().ok_or_else(|| {
+                ProtocolError::MissingParam("present".to_owned(), "path".to_owned())
+            })? {
+                Value::Text(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "present".to_owned(),
+                        "path".to_owned(),
+                    ));
+                }
+            };
+
+            return Ok(Some(Message::AlreadyPresent(
+                channel_id,
+                hash.to_owned(),
+                path.to_owned(),
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out upload signature
+// { channel_id, "signature", hash, signature }
+pub fn parse_signature(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Text(op)) = pieces.next() {
+        if op == "signature" {
+            let hash = match pieces.next().ok_or_else(|| {
+                ProtocolError::MissingParam("signature".to_owned(), "hash".to_owned())
+            })? {
+                Value::Text(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "signature".to_owned(),
+                        "hash".to_owned(),
+                    ));
+                }
+            };
+
+            let signature = match pieces.next().ok_or_else(|| {
+                ProtocolError::MissingParam("signature".to_owned(), "signature".to_owned())
+            })? {
+                Value::Bytes(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "signature".to_owned(),
+                        "signature".to_owned(),
+                    ));
+                }
+            };
+
+            return Ok(Some(Message::Signature(
+                channel_id,
+                hash.to_owned(),
+                signature.to_owned(),
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out channel in use response
+// { channel_id, "in_use", key }
+pub fn parse_channel_in_use(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Text(op)) = pieces.next() {
+        if op == "in_use" {
+            return match pieces.next() {
+                Some(Value::Text(key)) => {
+                    Ok(Some(Message::ChannelInUse(channel_id, key.to_owned())))
+                }
+                Some(_) => Err(ProtocolError::InvalidParam(
+                    "in_use".to_owned(),
+                    "key".to_owned(),
+                )),
+                None => Err(ProtocolError::MissingParam(
+                    "in_use".to_owned(),
+                    "key".to_owned(),
+                )),
+            };
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out transfer log request
+// { channel_id, "log", skip, count }
+pub fn parse_log_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Text(op)) = pieces.next() {
+        if op == "log" {
+            let mut param = |name: &str| match pieces.next() {
+                Some(Value::Integer(num)) if *num >= 0 => Ok(*num as u32),
+                Some(_) => Err(ProtocolError::InvalidParam(
+                    "log".to_owned(),
+                    name.to_owned(),
+                )),
+                None => Err(ProtocolError::MissingParam(
+                    "log".to_owned(),
+                    name.to_owned(),
+                )),
+            };
+            let skip = param("skip")?;
+            let count = param("count")?;
+
+            return Ok(Some(Message::ReqLog(channel_id, skip, count)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out transfer log response
+// { channel_id, "log_entries", total, [entries] }
+pub fn parse_log_entries(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Text(op)) = pieces.next() {
+        if op == "log_entries" {
+            let total = match pieces.next() {
+                Some(Value::Integer(num)) if *num >= 0 => *num as u32,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "log_entries".to_owned(),
+                        "total".to_owned(),
+                    ));
+                }
+            };
+
+            let entries = match pieces.next() {
+                Some(Value::Array(entries)) => entries,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "log_entries".to_owned(),
+                        "entries".to_owned(),
+                    ));
+                }
+            };
+
+            let records = entries
+                .iter()
+                .map(|entry| parse_log_entry(entry.to_owned()))
+                .collect::<Result<Vec<TransferRecord>, ProtocolError>>()?;
+
+            return Ok(Some(Message::LogEntries(channel_id, total, records)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out a single transfer log entry
+// { time, peer, channel_id, operation, path, error, duration_ms, bytes }
+fn parse_log_entry(entry: Value) -> Result<TransferRecord, ProtocolError> {
+    let invalid = || ProtocolError::InvalidParam("log_entries".to_owned(), "entry".to_owned());
+
+    let (time, peer, channel_id, operation, path, error, duration_ms, bytes): (
+        u64,
+        String,
+        u32,
+        String,
+        String,
+        Option<String>,
+        u64,
+        u64,
+    ) = from_value(entry).map_err(|_| invalid())?;
+
+    Ok(TransferRecord {
+        time,
+        peer,
+        channel_id,
+        operation: TransferOperation::from_name(&operation).ok_or_else(invalid)?,
+        path,
+        error,
+        duration_ms,
+        bytes,
+    })
+}
+
 // Parse out export request
-// { channel_id, "export", hash, path, [, mode] }
+// { channel_id, "export", hash, path, [, mode [, storage_class]] }
 pub fn parse_export_request(
     channel_id: u32,
     mut pieces: Iter<Value>,
@@ -170,11 +415,96 @@ pub fn parse_export_request(
                 _ => None,
             };
 
+            let storage_class = match pieces.next() {
+                Some(Value::Text(class)) => Some(class.to_owned()),
+                _ => None,
+            };
+
             return Ok(Some(Message::ReqReceive(
                 channel_id,
                 hash.to_owned(),
                 path.to_owned(),
                 mode,
+                storage_class,
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out append request
+// { channel_id, "append", hash, path, offset, prefix_hash [, storage_class] }
+pub fn parse_append_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Text(op)) = pieces.next() {
+        if op == "append" {
+            let mut text = |name: &str| match pieces.next() {
+                Some(Value::Text(val)) => Ok(val.to_owned()),
+                Some(_) => Err(ProtocolError::InvalidParam(
+                    "append".to_owned(),
+                    name.to_owned(),
+                )),
+                None => Err(ProtocolError::MissingParam(
+                    "append".to_owned(),
+                    name.to_owned(),
+                )),
+            };
+            let hash = text("hash")?;
+            let path = text("path")?;
+
+            let offset = match pieces.next() {
+                Some(Value::Integer(num)) if *num >= 0 => *num as u64,
+                Some(_) => {
+                    return Err(ProtocolError::InvalidParam(
+                        "append".to_owned(),
+                        "offset".to_owned(),
+                    ));
+                }
+                None => {
+                    return Err(ProtocolError::MissingParam(
+                        "append".to_owned(),
+                        "offset".to_owned(),
+                    ));
+                }
+            };
+
+            let prefix_hash = match pieces.next() {
+                Some(Value::Text(val)) => val.to_owned(),
+                Some(_) => {
+                    return Err(ProtocolError::InvalidParam(
+                        "append".to_owned(),
+                        "prefix_hash".to_owned(),
+                    ));
+                }
+                None => {
+                    return Err(ProtocolError::MissingParam(
+                        "append".to_owned(),
+                        "prefix_hash".to_owned(),
+                    ));
+                }
+            };
+
+            let storage_class = match pieces.next() {
+                Some(Value::Text(class)) => Some(class.to_owned()),
+                Some(Value::Null) | None => None,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "append".to_owned(),
+                        "storage_class".to_owned(),
+                    ));
+                }
+            };
+
+            return Ok(Some(Message::ReqAppend(
+                channel_id,
+                hash,
+                path,
+                offset,
+                prefix_hash,
+                storage_class,
             )));
         }
     }
@@ -183,7 +513,7 @@ pub fn parse_export_request(
 }
 
 // Parse out import request
-// { channel_id, "import", path }
+// { channel_id, "import", path [, offset, length [, storage_class]] }
 pub fn parse_import_request(
     channel_id: u32,
     mut pieces: Iter<Value>,
@@ -201,9 +531,47 @@ pub fn parse_import_request(
                     ));
                 }
             };
+
+            // Older clients always request the whole file
+            let offset = match pieces.next() {
+                Some(Value::Integer(num)) if *num >= 0 => *num as u64,
+                Some(Value::Null) | None => 0,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "import".to_owned(),
+                        "offset".to_owned(),
+                    ));
+                }
+            };
+
+            let length = match pieces.next() {
+                Some(Value::Integer(num)) if *num >= 0 => Some(*num as u64),
+                Some(Value::Null) | None => None,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "import".to_owned(),
+                        "length".to_owned(),
+                    ));
+                }
+            };
+
+            let storage_class = match pieces.next() {
+                Some(Value::Text(class)) => Some(class.to_owned()),
+                Some(Value::Null) | None => None,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "import".to_owned(),
+                        "storage_class".to_owned(),
+                    ));
+                }
+            };
+
             return Ok(Some(Message::ReqTransmit(
                 channel_id as u32,
                 path.to_owned(),
+                offset,
+                length,
+                storage_class,
             )));
         }
     }
@@ -370,7 +738,7 @@ pub fn parse_nak(
 }
 
 // Parse out chunk
-// { hash, chunk_index, data }
+// { hash, chunk_index, data [, crc] }
 pub fn parse_chunk(
     channel_id: u32,
     mut pieces: Iter<Value>,
@@ -379,11 +747,23 @@ pub fn parse_chunk(
         if let Some(Value::Integer(num)) = pieces.next() {
             if let Some(third_param) = pieces.next() {
                 if let Value::Bytes(data) = third_param {
+                    // Older senders don't include a CRC
+                    let crc = match pieces.next() {
+                        Some(Value::Integer(crc)) => Some(*crc as u32),
+                        None => None,
+                        _ => {
+                            return Err(ProtocolError::InvalidParam(
+                                "chunk".to_owned(),
+                                "crc".to_owned(),
+                            ));
+                        }
+                    };
                     return Ok(Some(Message::ReceiveChunk(
                         channel_id,
                         hash.to_owned(),
                         *num as u32,
                         data.to_vec(),
+                        crc,
                     )));
                 } else {
                     return Err(ProtocolError::InvalidParam(
@@ -17,17 +17,56 @@
 
 use super::{messages, parsers, storage, Message};
 use crate::error::ProtocolError;
+use crate::limits::TransferLimits;
+use crate::resume::ResumeToken;
+use crate::signing::UploadKeys;
+use crate::stats::{StatsTracker, TransferStats};
+use crate::storage::{ChunkStore, FsChunkStore};
+use crate::transfer_log::TransferRecord;
 use cbor_protocol::Protocol as CborProtocol;
 use log::{error, info, warn};
 use rand::{self, Rng};
 use serde_cbor::Value;
-use std::{cell::Cell, net::SocketAddr, str, thread, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    net::SocketAddr,
+    str,
+    sync::atomic::{AtomicU32, Ordering},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+// Range channel IDs are picked from
+const MIN_CHANNEL_ID: u32 = 100_000;
+const MAX_CHANNEL_ID: u32 = 999_999;
+
+// Next channel ID handed out with `ChannelAllocation::Sequential`.
+// Zero until the first one is needed, when a random starting point is picked
+static NEXT_CHANNEL_ID: AtomicU32 = AtomicU32::new(0);
+
+// Called with a transfer's stats after each chunk. Returns whether to carry on
+type ProgressCallback = Arc<dyn Fn(&TransferStats) -> bool + Send + Sync>;
+
+/// How new channel IDs are picked
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChannelAllocation {
+    /// Each ID is picked at random
+    Random,
+    /// IDs are taken in turn from a counter shared by every protocol instance in the process,
+    /// starting from a random point. Simultaneous transfers from the same process never
+    /// share an ID, and transfers from different processes are unlikely to
+    Sequential,
+}
 
 /// Configuration data for Protocol
 #[derive(Clone)]
 pub struct ProtocolConfig {
-    // Name of folder used to store protocol metadata
-    storage_prefix: String,
+    // Storage for the chunks and metadata of files being transferred
+    store: Arc<dyn ChunkStore>,
+    // Alternative stores which transfers can ask for by name
+    storage_classes: HashMap<String, Arc<dyn ChunkStore>>,
     // Chunk size used in transfers
     transfer_chunk_size: usize,
     // How many times do we read and timeout
@@ -39,6 +78,16 @@ pub struct ProtocolConfig {
     max_chunks_transmit: Option<u32>,
     // Chunk size used in storage hashing
     hash_chunk_size: usize,
+    // How new channel IDs are picked
+    channel_allocation: ChannelAllocation,
+    // Whether a file's chunks are removed from storage once its transfer succeeds
+    cleanup_on_success: bool,
+    // Called with a transfer's stats after each chunk
+    progress: Option<ProgressCallback>,
+    // Keys which uploads have to be signed with before they're exported
+    upload_keys: Option<UploadKeys>,
+    // Limits on the files the remote target can import and export
+    limits: TransferLimits,
 }
 
 impl ProtocolConfig {
@@ -52,21 +101,208 @@ impl ProtocolConfig {
         hash_chunk_size: usize,
     ) -> Self {
         ProtocolConfig {
-            storage_prefix: storage_prefix.unwrap_or_else(|| "file-storage".to_owned()),
+            store: Arc::new(FsChunkStore::new(
+                &storage_prefix.unwrap_or_else(|| "file-storage".to_owned()),
+            )),
+            storage_classes: HashMap::new(),
             transfer_chunk_size,
             hold_count,
             inter_chunk_delay: Duration::from_millis(inter_chunk_delay),
             max_chunks_transmit,
             hash_chunk_size,
+            channel_allocation: ChannelAllocation::Random,
+            cleanup_on_success: true,
+            progress: None,
+            upload_keys: None,
+            limits: TransferLimits::new(),
         }
     }
+
+    /// Use a different store for the chunks of files being transferred,
+    /// in place of the default one-file-per-chunk storage in the storage prefix
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    /// use std::sync::Arc;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048)
+    ///     .with_chunk_store(Arc::new(MemoryChunkStore::new()));
+    /// ```
+    pub fn with_chunk_store(mut self, store: Arc<dyn ChunkStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Add a store which transfers can ask for by name, in place of the default store.
+    /// For example, small critical uploads can be kept in RAM while large payload files
+    /// go to an SD card
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    /// use std::sync::Arc;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048)
+    ///     .with_storage_class("ram", Arc::new(FsChunkStore::new("/tmp/file-storage")));
+    /// ```
+    pub fn with_storage_class(mut self, name: &str, store: Arc<dyn ChunkStore>) -> Self {
+        self.storage_classes.insert(name.to_owned(), store);
+        self
+    }
+
+    /// Pick new channel IDs with the given scheme, in place of the default random IDs
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048)
+    ///     .with_channel_allocation(ChannelAllocation::Sequential);
+    /// ```
+    pub fn with_channel_allocation(mut self, allocation: ChannelAllocation) -> Self {
+        self.channel_allocation = allocation;
+        self
+    }
+
+    /// Choose whether a file's chunks are removed from storage as soon as its transfer
+    /// succeeds, which is the default. When turned off, the chunks stay until
+    /// [`delete_file`](#method.delete_file) is called or a cleanup is requested
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048)
+    ///     .with_cleanup_on_success(false);
+    /// ```
+    pub fn with_cleanup_on_success(mut self, cleanup: bool) -> Self {
+        self.cleanup_on_success = cleanup;
+        self
+    }
+
+    /// Call `progress` with the transfer's [`TransferStats`] after each chunk is sent or
+    /// received. Returning `false` aborts the transfer, with `ProtocolError::TransferAborted`
+    ///
+    /// [`TransferStats`]: ../stats/struct.TransferStats.html
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// // Give up on transfers which won't finish within the pass
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048).with_progress(|stats| {
+    ///     println!("{:.0} B/s, ETA {:?}", stats.bytes_per_sec(), stats.eta());
+    ///     stats.eta().map_or(true, |eta| eta < Duration::from_secs(600))
+    /// });
+    /// ```
+    pub fn with_progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(&TransferStats) -> bool + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Only export uploads which were signed with one of the given keys.
+    /// See [`signing`](../signing/index.html)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let keys = UploadKeys::from_hex(&[
+    ///     "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+    /// ])
+    /// .unwrap();
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048).with_upload_keys(keys);
+    /// ```
+    pub fn with_upload_keys(mut self, keys: UploadKeys) -> Self {
+        self.upload_keys = Some(keys);
+        self
+    }
+
+    /// Refuse imports and exports which break the given limits.
+    /// See [`limits`](../limits/index.html)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let limits = TransferLimits::new()
+    ///     .with_max_file_size(50 * 1024 * 1024)
+    ///     .with_allowed_paths(&["/home/system"]);
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048).with_limits(limits);
+    /// ```
+    pub fn with_limits(mut self, limits: TransferLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Remove a file's chunks and metadata from storage.
+    ///
+    /// The file is removed from whichever store holds it, so this succeeds if any of the
+    /// stores held the file.
+    pub fn delete_file(&self, hash: &str) -> Result<(), ProtocolError> {
+        let mut result = self.store.delete_file(hash);
+        for store in self.storage_classes.values() {
+            if store.delete_file(hash).is_ok() {
+                result = Ok(());
+            }
+        }
+        result
+    }
+}
+
+// How a protocol's messages are sent and received
+enum Link {
+    // Over the protocol's own socket
+    Socket(CborProtocol),
+    // Queued for an async protocol to send, along with how long to wait after sending each one.
+    // The async protocol also does the receiving
+    #[cfg(feature = "async")]
+    Queue(RefCell<Vec<(Vec<u8>, Duration)>>),
+}
+
+// What the message engine does after handling a message or timeout
+pub(crate) enum Step {
+    // Wait for the next message in this state
+    Continue(State),
+    // The transaction has finished
+    Done,
 }
 
 /// File protocol information structure
 pub struct Protocol {
-    cbor_proto: CborProtocol,
+    link: Link,
     remote_addr: Cell<SocketAddr>,
     config: ProtocolConfig,
+    // Transfer key of the last request sent, used to tell whether a "channel in use"
+    // rejection is meant for us
+    last_request: RefCell<Option<String>>,
+    // Last failure reported to the remote target
+    last_failure: RefCell<Option<String>>,
+    // Storage class used by this side, and asked for in requests to the remote target
+    storage_class: RefCell<Option<String>>,
+    // File being uploaded, along with the chunks the remote target last reported holding
+    upload: RefCell<Option<ResumeToken>>,
+    // Token of the download being resumed, until the remote target replies
+    resuming: RefCell<Option<ResumeToken>>,
+    // Stats of the transfer whose chunks are being sent or received
+    stats: RefCell<Option<StatsTracker>>,
+    // Hash and signature of the upload the remote target is about to export
+    signature: RefCell<Option<(String, Vec<u8>)>>,
+    // Hash of the bytes being appended to a file, along with the offset they go at and the
+    // hash of the bytes before it
+    append: RefCell<Option<(String, u64, String)>>,
 }
 
 /// Current state of the file protocol transaction
@@ -151,21 +387,129 @@ impl Protocol {
         let c_protocol = CborProtocol::new(host_addr, config.transfer_chunk_size);
 
         // Set up the full connection info
+        Protocol::with_link(
+            Link::Socket(c_protocol),
+            remote_addr
+                .parse::<SocketAddr>()
+                .map_err(|err| {
+                    error!("Failed to parse remote_addr: {:?}", err);
+                    err
+                })
+                .unwrap(),
+            config,
+        )
+    }
+
+    // Create a protocol instance whose messages are queued for an async protocol to send
+    #[cfg(feature = "async")]
+    pub(crate) fn queued(remote_addr: SocketAddr, config: ProtocolConfig) -> Self {
+        Protocol::with_link(Link::Queue(RefCell::new(vec![])), remote_addr, config)
+    }
+
+    fn with_link(link: Link, remote_addr: SocketAddr, config: ProtocolConfig) -> Self {
         Protocol {
-            cbor_proto: c_protocol,
-            remote_addr: Cell::new(
-                remote_addr
-                    .parse::<SocketAddr>()
-                    .map_err(|err| {
-                        error!("Failed to parse remote_addr: {:?}", err);
-                        err
-                    })
-                    .unwrap(),
-            ),
+            link,
+            remote_addr: Cell::new(remote_addr),
             config,
+            last_request: RefCell::new(None),
+            last_failure: RefCell::new(None),
+            storage_class: RefCell::new(None),
+            upload: RefCell::new(None),
+            resuming: RefCell::new(None),
+            stats: RefCell::new(None),
+            signature: RefCell::new(None),
+            append: RefCell::new(None),
         }
     }
 
+    // Take the messages queued for an async protocol to send
+    #[cfg(feature = "async")]
+    pub(crate) fn take_queued(&self) -> Vec<(Vec<u8>, Duration)> {
+        match &self.link {
+            Link::Queue(queue) => queue.replace(vec![]),
+            Link::Socket(_) => vec![],
+        }
+    }
+
+    // The remote IP and port this instance communicates with
+    #[cfg(feature = "async")]
+    pub(crate) fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr.get()
+    }
+
+    // Largest message which can be received, including the CBOR framing
+    #[cfg(feature = "async")]
+    pub(crate) fn message_size(&self) -> usize {
+        self.config.transfer_chunk_size + 50
+    }
+
+    /// Ask the remote target to keep the chunks of the transfers requested from now on
+    /// in the given storage class, rather than its default storage.
+    ///
+    /// Classes are names, such as "ram" or "sdcard", which the remote target maps to stores
+    /// with [`with_storage_class`]. A target which doesn't know the class uses its default
+    /// storage. This side's chunks are also kept in the class's store, if it has one.
+    ///
+    /// [`with_storage_class`]: struct.ProtocolConfig.html#method.with_storage_class
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    /// f_protocol.set_storage_class(Some("sdcard"));
+    /// ```
+    pub fn set_storage_class(&self, storage_class: Option<&str>) {
+        self.storage_class
+            .replace(storage_class.map(|class| class.to_owned()));
+    }
+
+    // The store used for the current transfer
+    fn store(&self) -> Arc<dyn ChunkStore> {
+        self.storage_class
+            .borrow()
+            .as_ref()
+            .and_then(|class| self.config.storage_classes.get(class))
+            .unwrap_or(&self.config.store)
+            .clone()
+    }
+
+    // Switch to the storage class requested by the remote target.
+    // An upload's metadata arrives before its export request, so it's moved to the new store.
+    fn use_storage_class(
+        &self,
+        storage_class: &Option<String>,
+        hash: Option<&str>,
+    ) -> Result<(), ProtocolError> {
+        let class = match storage_class {
+            Some(class) if self.storage_class.borrow().as_ref() != Some(class) => class,
+            _ => return Ok(()),
+        };
+        if !self.config.storage_classes.contains_key(class) {
+            warn!("Unknown storage class '{}', using default storage", class);
+            return Ok(());
+        }
+
+        let old = self.store();
+        self.set_storage_class(Some(class));
+        let new = self.store();
+
+        if let Some(hash) = hash {
+            if new.load_meta(hash).is_err() {
+                if let Ok(meta) = old.load_meta(hash) {
+                    new.store_meta(hash, &meta)?;
+                    // Chunks from an earlier attempt are left where they are
+                    if old.chunks(hash).map_or(false, |chunks| chunks.is_empty()) {
+                        old.delete_file(hash)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Send CBOR packet to the destination port
     ///
     /// # Arguments
@@ -189,10 +533,117 @@ impl Protocol {
     /// f_protocol.send(&message);
     /// ```
     pub fn send(&self, vec: &[u8]) -> Result<(), ProtocolError> {
-        self.cbor_proto.send_message(&vec, self.remote_addr.get())?;
+        match &self.link {
+            Link::Socket(cbor_proto) => cbor_proto.send_message(vec, self.remote_addr.get())?,
+            #[cfg(feature = "async")]
+            Link::Queue(queue) => queue.borrow_mut().push((vec.to_vec(), Duration::default())),
+        }
+        Ok(())
+    }
+
+    // Wait between sending messages. An async protocol waits after sending the last
+    // queued message, rather than blocking its runtime here
+    fn delay(&self, delay: Duration) {
+        match &self.link {
+            Link::Socket(_) => thread::sleep(delay),
+            #[cfg(feature = "async")]
+            Link::Queue(queue) => {
+                if let Some(last) = queue.borrow_mut().last_mut() {
+                    last.1 += delay;
+                }
+            }
+        }
+    }
+
+    // Check that the file about to be exported was signed with one of the upload keys,
+    // if uploads have to be signed
+    fn check_signature(&self, hash: &str) -> Result<(), ProtocolError> {
+        let keys = match &self.config.upload_keys {
+            Some(keys) => keys,
+            None => return Ok(()),
+        };
+
+        match self.signature.borrow().as_ref() {
+            Some((signed, signature)) if signed == hash => keys.verify(hash, signature),
+            _ => Err(ProtocolError::SignatureRejected {
+                hash: hash.to_owned(),
+                cause: "No signature was sent".to_owned(),
+            }),
+        }
+    }
+
+    // Tell the remote target that its request has failed
+    fn send_failure(&self, channel_id: u32, error: &str) -> Result<(), ProtocolError> {
+        self.last_failure.replace(Some(error.to_owned()));
+        self.send(&messages::operation_failure(channel_id, error)?)
+    }
+
+    /// The last failure this instance reported to the remote target, if any
+    pub fn last_failure(&self) -> Option<String> {
+        self.last_failure.borrow().clone()
+    }
+
+    /// Throughput, retransmit ratio and ETA of the latest transfer whose chunks this instance
+    /// sent or received, if any
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    ///
+    /// if let Some(stats) = f_protocol.stats() {
+    ///     println!("{}/{} chunks, ETA {:?}", stats.chunks_held, stats.num_chunks, stats.eta());
+    /// }
+    /// ```
+    pub fn stats(&self) -> Option<TransferStats> {
+        self.stats.borrow().as_ref().map(|tracker| tracker.stats())
+    }
+
+    // Start the stats of a file's transfer, given the chunks the receiver already holds
+    fn track(&self, hash: &str, num_chunks: u32, held: &[u32]) {
+        self.stats
+            .replace(Some(StatsTracker::new(hash, num_chunks, held)));
+    }
+
+    // Start the stats of a file being received, unless they're already being kept.
+    // Chunks left from an earlier pass are already held
+    fn track_receive(&self, hash: &str) -> Result<(), ProtocolError> {
+        if self.stats.borrow().as_ref().map(|tracker| tracker.hash()) == Some(hash) {
+            return Ok(());
+        }
+        let store = self.store();
+        let num_chunks = store.load_meta(hash)?.num_chunks;
+        self.track(hash, num_chunks, &store.chunks(hash)?);
         Ok(())
     }
 
+    // Count a chunk of a file towards its stats, then pass them to the progress callback.
+    // The remote target is told if the callback aborts the transfer
+    fn count_chunk<F>(&self, channel_id: u32, hash: &str, count: F) -> Result<(), ProtocolError>
+    where
+        F: FnOnce(&mut StatsTracker),
+    {
+        let stats = match self.stats.borrow_mut().as_mut() {
+            Some(tracker) if tracker.hash() == hash => {
+                count(tracker);
+                tracker.stats()
+            }
+            _ => return Ok(()),
+        };
+
+        match &self.config.progress {
+            Some(progress) if !progress(&stats) => {
+                let error = ProtocolError::TransferAborted { hash: stats.hash };
+                self.send_failure(channel_id, &format!("{}", error))?;
+                Err(error)
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Receive a file protocol message
     ///
     /// # Arguments
@@ -224,14 +675,27 @@ impl Protocol {
     /// };
     /// ```
     pub fn recv(&self, timeout: Option<Duration>) -> Result<Value, ProtocolError> {
+        let cbor_proto = match &self.link {
+            Link::Socket(cbor_proto) => cbor_proto,
+            #[cfg(feature = "async")]
+            Link::Queue(_) => {
+                return Err(ProtocolError::ReceiveError {
+                    err: "Messages are received by the async protocol".to_owned(),
+                })
+            }
+        };
+
         match timeout {
-            Some(value) => Ok(self.cbor_proto.recv_message_timeout(value)?),
-            None => Ok(self.cbor_proto.recv_message()?),
+            Some(value) => Ok(cbor_proto.recv_message_timeout(value)?),
+            None => Ok(cbor_proto.recv_message()?),
         }
     }
 
-    /// Generates a new random channel ID for use when initiating a
-    /// file transfer.
+    /// Generates a new channel ID for use when initiating a
+    /// file transfer, using the configured `ChannelAllocation`.
+    ///
+    /// The remote target rejects a request on a channel which is already in use by another
+    /// transfer, in which case a new channel should be generated and the request sent again.
     ///
     /// # Errors
     ///
@@ -248,9 +712,10 @@ impl Protocol {
     /// let channel_id = f_protocol.generate_channel();
     /// ```
     pub fn generate_channel(&self) -> Result<u32, ProtocolError> {
-        let mut rng = rand::thread_rng();
-        let channel_id: u32 = rng.gen_range(100_000, 999_999);
-        Ok(channel_id)
+        match self.config.channel_allocation {
+            ChannelAllocation::Random => Ok(random_channel()),
+            ChannelAllocation::Sequential => Ok(next_channel()),
+        }
     }
 
     /// Send a file's metadata information to the remote target
@@ -285,9 +750,49 @@ impl Protocol {
         hash: &str,
         num_chunks: u32,
     ) -> Result<(), ProtocolError> {
+        self.last_request.replace(Some(hash.to_owned()));
+        self.upload
+            .replace(Some(ResumeToken::new(hash, num_chunks, &[])));
+        self.track(hash, num_chunks, &[]);
         self.send(&messages::metadata(channel_id, &hash, num_chunks)?)
     }
 
+    /// Send the signature over a file's hash to a remote target which only exports signed
+    /// uploads (see [`signing`](../signing/index.html)). Must be sent before
+    /// [`send_export`](#method.send_export) or [`resume_export`](#method.resume_export)
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel ID used for transaction
+    /// * hash - BLAKE2s hash of file
+    /// * signature - Detached Ed25519 signature over the hash
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    ///
+    /// # ::std::fs::File::create("client.txt").unwrap();
+    /// # let signature = [0u8; 64];
+    /// let (hash, num_chunks, mode) = f_protocol.initialize_file("client.txt").unwrap();
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    /// f_protocol.send_metadata(channel_id, &hash, num_chunks);
+    /// f_protocol.send_signature(channel_id, &hash, &signature);
+    /// f_protocol.send_export(channel_id, &hash, "final/dir/service.txt", mode);
+    /// ```
+    pub fn send_signature(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        signature: &[u8],
+    ) -> Result<(), ProtocolError> {
+        self.last_request.replace(Some(hash.to_owned()));
+        self.send(&messages::signature(channel_id, hash, signature)?)
+    }
+
     /// Send a request to cleanup the remote storage folder
     pub fn send_cleanup(&self, channel_id: u32, hash: Option<String>) -> Result<(), ProtocolError> {
         self.send(&messages::cleanup(channel_id, hash)?)
@@ -313,53 +818,303 @@ impl Protocol {
     ///
     /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
     /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
-    ///
-    /// # ::std::fs::File::create("client.txt").unwrap();
-    ///
-    /// let (hash, _num_chunks, mode) = f_protocol.initialize_file("client.txt").unwrap();
+    ///
+    /// # ::std::fs::File::create("client.txt").unwrap();
+    ///
+    /// let (hash, _num_chunks, mode) = f_protocol.initialize_file("client.txt").unwrap();
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    /// f_protocol.send_export(channel_id, &hash, "final/dir/service.txt", mode);
+    /// ```
+    pub fn send_export(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        target_path: &str,
+        mode: u32,
+    ) -> Result<(), ProtocolError> {
+        self.last_request.replace(Some(hash.to_owned()));
+        self.send(&messages::export_request(
+            channel_id,
+            hash,
+            target_path,
+            mode,
+            self.storage_class.borrow().as_deref(),
+        )?)?;
+
+        Ok(())
+    }
+
+    /// Request remote target to append the new bytes of a growing file to its existing copy
+    ///
+    /// The remote target first checks that its copy starts with the bytes before `offset`,
+    /// and fails the request if it doesn't. Anything its copy holds past `offset` is replaced.
+    /// The new bytes must have been prepared with [`initialize_append`] and their metadata
+    /// sent with [`send_metadata`]
+    ///
+    /// [`initialize_append`]: #method.initialize_append
+    /// [`send_metadata`]: #method.send_metadata
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel ID used for transaction
+    /// * hash - BLAKE2s hash of the new bytes
+    /// * target_path - Destination file path
+    /// * offset - Number of bytes of the file the remote target already has
+    /// * prefix_hash - BLAKE2s hash of the bytes before `offset`
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    ///
+    /// # ::std::fs::write("ephemeris.txt", vec![0u8; 8192]).unwrap();
+    ///
+    /// let (hash, num_chunks, prefix_hash) =
+    ///     f_protocol.initialize_append("ephemeris.txt", 4096).unwrap();
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    /// f_protocol.send_metadata(channel_id, &hash, num_chunks);
+    /// f_protocol.send_append(channel_id, &hash, "final/dir/ephemeris.txt", 4096, &prefix_hash);
+    /// ```
+    pub fn send_append(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        target_path: &str,
+        offset: u64,
+        prefix_hash: &str,
+    ) -> Result<(), ProtocolError> {
+        self.last_request.replace(Some(hash.to_owned()));
+        self.send(&messages::append_request(
+            channel_id,
+            hash,
+            target_path,
+            offset,
+            prefix_hash,
+            self.storage_class.borrow().as_deref(),
+        )?)?;
+
+        Ok(())
+    }
+
+    /// Resume an upload which timed out on an earlier pass
+    ///
+    /// Takes the place of [`initialize_file`], [`send_metadata`] and [`send_export`]. The file
+    /// must still be in this side's storage. Once the message engine is started, the remote
+    /// target asks for the chunks it's missing.
+    ///
+    /// [`initialize_file`]: #method.initialize_file
+    /// [`send_metadata`]: #method.send_metadata
+    /// [`send_export`]: #method.send_export
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel ID used for transaction
+    /// * token - Token returned with the upload's `ProtocolError::TransferTimeout`
+    /// * target_path - Destination file path
+    /// * mode - File mode
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProtocolError::ResumeError` if the file is no longer in storage,
+    /// or doesn't match the token
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// # fn resume(token: &str) -> Result<(), ProtocolError> {
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    ///
+    /// let token: ResumeToken = token.parse()?;
+    /// let channel_id = f_protocol.generate_channel()?;
+    /// f_protocol.resume_export(channel_id, &token, "final/dir/service.txt", 0o644)?;
+    /// f_protocol.message_engine(
+    ///     |d| f_protocol.recv(Some(d)),
+    ///     Duration::from_millis(10),
+    ///     &State::Transmitting,
+    /// )
+    /// # }
+    /// ```
+    pub fn resume_export(
+        &self,
+        channel_id: u32,
+        token: &ResumeToken,
+        target_path: &str,
+        mode: u32,
+    ) -> Result<(), ProtocolError> {
+        let num_chunks = match self.store().load_meta(&token.hash) {
+            Ok(meta) => meta.num_chunks,
+            Err(_) => {
+                return Err(ProtocolError::ResumeError {
+                    cause: format!("File {} is no longer in storage", token.hash),
+                })
+            }
+        };
+        if num_chunks != token.num_chunks {
+            return Err(ProtocolError::ResumeError {
+                cause: format!(
+                    "File {} has {} chunks, not {}",
+                    token.hash, num_chunks, token.num_chunks
+                ),
+            });
+        }
+
+        info!("Resuming upload of {}", token.hash);
+        self.send_export(channel_id, &token.hash, target_path, mode)?;
+        self.upload.replace(Some(token.clone()));
+        // Which chunks the remote target holds isn't known until it asks for the rest
+        self.track(&token.hash, num_chunks, &[]);
+        Ok(())
+    }
+
+    /// Request a file from a remote target
+    ///
+    /// # Arguments
+    ///
+    /// * source_path - File remote target should send
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    ///
+    /// f_protocol.send_import(channel_id, "service.txt");
+    /// ```
+    pub fn send_import(&self, channel_id: u32, source_path: &str) -> Result<(), ProtocolError> {
+        self.send_import_range(channel_id, source_path, 0, None)
+    }
+
+    /// Request part of a file from a remote target
+    ///
+    /// The remote target only chunks and sends the requested bytes, so the received file
+    /// holds just that part of the source file
+    ///
+    /// # Arguments
+    ///
+    /// * source_path - File remote target should send
+    /// * offset - Byte offset within the file to start from
+    /// * length - Maximum number of bytes to send. If `None`, everything after `offset` is sent
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
     /// let channel_id = f_protocol.generate_channel().unwrap();
-    /// f_protocol.send_export(channel_id, &hash, "final/dir/service.txt", mode);
+    ///
+    /// // Fetch the first 4KB of the file
+    /// f_protocol.send_import_range(channel_id, "service.log", 0, Some(4096));
     /// ```
-    pub fn send_export(
+    pub fn send_import_range(
         &self,
         channel_id: u32,
-        hash: &str,
-        target_path: &str,
-        mode: u32,
+        source_path: &str,
+        offset: u64,
+        length: Option<u64>,
     ) -> Result<(), ProtocolError> {
-        self.send(&messages::export_request(
+        self.last_request.replace(Some(source_path.to_owned()));
+        self.resuming.replace(None);
+        self.send(&messages::import_request(
             channel_id,
-            hash,
-            target_path,
-            mode,
+            source_path,
+            offset,
+            length,
+            self.storage_class.borrow().as_deref(),
         )?)?;
-
         Ok(())
     }
 
-    /// Request a file from a remote target
+    /// Resume a download which timed out on an earlier pass
+    ///
+    /// Takes the place of [`send_import`]. The reply is processed as usual, with the
+    /// `StartReceive` state. This side then asks for the chunks it's missing.
+    ///
+    /// [`send_import`]: #method.send_import
     ///
     /// # Arguments
     ///
+    /// * channel_id - Channel ID used for transaction
+    /// * token - Token returned with the download's `ProtocolError::TransferTimeout`
     /// * source_path - File remote target should send
     ///
     /// # Errors
     ///
-    /// If this function encounters any errors, it will return an error message string
+    /// If the remote target's file no longer matches the token, processing its reply
+    /// returns `ProtocolError::ResumeError`
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use file_protocol::*;
     ///
+    /// # fn resume(token: &str) -> Result<(), ProtocolError> {
     /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
     /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
-    /// let channel_id = f_protocol.generate_channel().unwrap();
     ///
-    /// f_protocol.send_import(channel_id, "service.txt");
+    /// let token: ResumeToken = token.parse()?;
+    /// let channel_id = f_protocol.generate_channel()?;
+    /// f_protocol.resume_import(channel_id, &token, "service.txt")
+    /// # }
     /// ```
-    pub fn send_import(&self, channel_id: u32, source_path: &str) -> Result<(), ProtocolError> {
-        self.send(&messages::import_request(channel_id, source_path)?)?;
+    pub fn resume_import(
+        &self,
+        channel_id: u32,
+        token: &ResumeToken,
+        source_path: &str,
+    ) -> Result<(), ProtocolError> {
+        self.resume_import_range(channel_id, token, source_path, 0, None)
+    }
+
+    /// Resume a download of part of a file which timed out on an earlier pass
+    ///
+    /// Behaves like [`resume_import`]. The range must be the same one which was requested
+    /// originally
+    ///
+    /// [`resume_import`]: #method.resume_import
+    pub fn resume_import_range(
+        &self,
+        channel_id: u32,
+        token: &ResumeToken,
+        source_path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<(), ProtocolError> {
+        // Chunks lost from storage since the token was made are just asked for again
+        match ResumeToken::from_store(&*self.store(), &token.hash) {
+            Ok(held) if held == *token => {}
+            _ => warn!(
+                "Stored chunks of {} have changed since the transfer timed out",
+                token.hash
+            ),
+        }
+
+        info!("Resuming download of {}", token.hash);
+        self.send_import_range(channel_id, source_path, offset, length)?;
+        self.resuming.replace(Some(token.clone()));
         Ok(())
     }
 
@@ -388,14 +1143,56 @@ impl Protocol {
     /// let (_hash, _num_chunks, _mode) = f_protocol.initialize_file("client.txt").unwrap();
     /// ```
     pub fn initialize_file(&self, source_path: &str) -> Result<(String, u32, u32), ProtocolError> {
+        self.initialize_file_range(source_path, 0, None)
+    }
+
+    /// Prepare part of a file for transfer
+    ///
+    /// Behaves like [`initialize_file`], but only the bytes starting at `offset`
+    /// (and at most `length` of them, if given) are chunked and hashed
+    ///
+    /// [`initialize_file`]: #method.initialize_file
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProtocolError::InvalidRange` if `offset` is past the end of the file
+    pub fn initialize_file_range(
+        &self,
+        source_path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<(String, u32, u32), ProtocolError> {
         storage::initialize_file(
-            &self.config.storage_prefix,
+            &*self.store(),
             source_path,
+            offset,
+            length,
             self.config.transfer_chunk_size,
             self.config.hash_chunk_size,
         )
     }
 
+    /// Prepare the bytes of a growing file past `offset` for appending to the remote target's
+    /// copy, with [`send_append`]
+    ///
+    /// Returns the hash of the new bytes, the number of chunks they make up and the hash of
+    /// the bytes before `offset`
+    ///
+    /// [`send_append`]: #method.send_append
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProtocolError::InvalidRange` if `offset` is past the end of the file
+    pub fn initialize_append(
+        &self,
+        source_path: &str,
+        offset: u64,
+    ) -> Result<(String, u32, String), ProtocolError> {
+        let (hash, num_chunks, _) = self.initialize_file_range(source_path, offset, None)?;
+        let prefix_hash = storage::prefix_hash(source_path, offset, self.config.hash_chunk_size)?;
+        Ok((hash, num_chunks, prefix_hash))
+    }
+
     // Verify the integrity of received file data and then transfer into the requested permanent file location.
     // Notify the connection peer of the results
     //
@@ -410,25 +1207,147 @@ impl Protocol {
         target_path: &str,
         mode: Option<u32>,
     ) -> Result<(), ProtocolError> {
-        match storage::finalize_file(
-            &self.config.storage_prefix,
-            hash,
-            target_path,
-            mode,
-            self.config.hash_chunk_size,
-        ) {
+        let result = match self.append.borrow().as_ref() {
+            Some((appended, offset, prefix_hash)) if appended == hash => storage::finalize_append(
+                &*self.store(),
+                hash,
+                target_path,
+                *offset,
+                prefix_hash,
+                self.config.hash_chunk_size,
+            ),
+            _ => storage::finalize_file(
+                &*self.store(),
+                hash,
+                target_path,
+                mode,
+                self.config.hash_chunk_size,
+            ),
+        };
+        match result {
             Ok(_) => {
                 self.send(&messages::operation_success(channel_id, hash)?)?;
-                storage::delete_file(&self.config.storage_prefix, hash)?;
+                self.transferred(hash)?;
                 Ok(())
             }
             Err(e) => {
-                self.send(&messages::operation_failure(channel_id, &format!("{}", e))?)?;
+                self.send_failure(channel_id, &format!("{}", e))?;
+                Err(e)
+            }
+        }
+    }
+
+    // Handle a request to receive a file, or to append the bytes past `offset` to an existing
+    // copy of one whose first `offset` bytes have the given hash. Returns the new state
+    fn receive_file(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        path: &str,
+        mode: Option<u32>,
+        storage_class: &Option<String>,
+        append: Option<(u64, &str)>,
+    ) -> Result<State, ProtocolError> {
+        if let Err(e) = self.check_signature(hash) {
+            warn!("{}", e);
+            self.send_failure(channel_id, &format!("{}", e))?;
+            return Err(e);
+        }
+        let num_chunks = self
+            .store()
+            .load_meta(hash)
+            .ok()
+            .map(|meta| meta.num_chunks);
+        let checked = match append {
+            Some((offset, prefix_hash)) => self
+                .config
+                .limits
+                .check_append(path, offset, num_chunks, self.config.transfer_chunk_size)
+                .and_then(|_| {
+                    storage::check_prefix(path, offset, prefix_hash, self.config.hash_chunk_size)
+                }),
+            None => {
+                self.config
+                    .limits
+                    .check_export(path, num_chunks, self.config.transfer_chunk_size)
+            }
+        };
+        if let Err(e) = checked {
+            warn!("{}", e);
+            self.send_failure(channel_id, &format!("{}", e))?;
+            return Err(e);
+        }
+        self.append.replace(
+            append.map(|(offset, prefix_hash)| (hash.to_owned(), offset, prefix_hash.to_owned())),
+        );
+        self.use_storage_class(storage_class, Some(hash))?;
+        // The client wants to send us a file.
+        // See what state the file is currently in on our side
+        match storage::validate_file(&*self.store(), hash, None) {
+            Ok((true, _)) => {
+                // We've already got all the file data in temporary storage
+                self.send(&messages::ack(channel_id, hash, None)?)?;
+
+                Ok(State::ReceivingDone {
+                    channel_id,
+                    hash: hash.to_string(),
+                    path: path.to_string(),
+                    mode,
+                })
+            }
+            Ok((false, chunks)) => {
+                // Before asking for any chunks, check whether we already
+                // have this exact file somewhere. An append only has part of the file
+                let existing = match append {
+                    Some(_) => Ok(None),
+                    None => storage::export_existing(
+                        &*self.store(),
+                        hash,
+                        path,
+                        mode,
+                        self.config.hash_chunk_size,
+                    ),
+                };
+                match existing {
+                    Ok(Some(existing)) => {
+                        info!("File {} already present at {}", hash, existing);
+                        self.send(&messages::already_present(channel_id, hash, &existing)?)?;
+                        self.transferred(hash)?;
+                        Ok(State::Done)
+                    }
+                    result => {
+                        if let Err(e) = result {
+                            warn!("Failed to use existing copy of {}: {}", hash, e);
+                        }
+                        // We're missing some number of data chunks of the requrested file
+                        self.track_receive(hash)?;
+                        self.send(&messages::nak(channel_id, hash, &chunks)?)?;
+                        Ok(State::Receiving {
+                            channel_id,
+                            hash: hash.to_string(),
+                            path: path.to_string(),
+                            mode,
+                        })
+                    }
+                }
+            }
+            Err(e) => {
+                // Let the client know, in case it was resuming a transfer
+                // whose metadata has since been cleaned up
+                self.send_failure(channel_id, &format!("{}", e))?;
                 Err(e)
             }
         }
     }
 
+    // Remove the chunks of a file whose transfer succeeded, unless they're being kept
+    fn transferred(&self, hash: &str) -> Result<(), ProtocolError> {
+        if self.config.cleanup_on_success {
+            self.store().delete_file(hash)?;
+        }
+        Ok(())
+    }
+
     /// Send all requested chunks of a file to the remote destination
     ///
     /// # Arguments
@@ -444,11 +1363,16 @@ impl Protocol {
         let mut chunks_transmitted = 0;
         for (first, last) in chunks {
             for chunk_index in *first..*last {
-                match storage::load_chunk(&self.config.storage_prefix, hash, chunk_index) {
-                    Ok(c) => self.send(&messages::chunk(channel_id, hash, chunk_index, &c)?)?,
+                match storage::load_chunk(&*self.store(), hash, chunk_index) {
+                    Ok(c) => {
+                        self.send(&messages::chunk(channel_id, hash, chunk_index, &c)?)?;
+                        self.count_chunk(channel_id, hash, |stats| {
+                            stats.chunk(chunk_index, c.len())
+                        })?;
+                    }
                     Err(e) => {
                         warn!("Failed to load chunk {}:{} : {}", hash, chunk_index, e);
-                        storage::delete_file(&self.config.storage_prefix, hash)?;
+                        self.store().delete_file(hash)?;
                         return Err(ProtocolError::CorruptFile(hash.to_string()));
                     }
                 };
@@ -459,12 +1383,80 @@ impl Protocol {
                     }
                 }
 
-                thread::sleep(self.config.inter_chunk_delay);
+                self.delay(self.config.inter_chunk_delay);
             }
         }
         Ok(())
     }
 
+    /// Fetch entries from the remote target's transfer log, newest first
+    ///
+    /// Entries are requested a message's worth at a time, until `count` entries have
+    /// been received or there are no more.
+    ///
+    /// # Arguments
+    ///
+    /// * count - Maximum number of entries to fetch
+    /// * timeout - Maximum time to wait for each reply
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 1024, 5, 1, None, 2048);
+    /// let f_protocol = FileProtocol::new("0.0.0.0:8000", "0.0.0.0:7000", config);
+    ///
+    /// let entries = f_protocol.request_log(20, Duration::from_secs(2));
+    /// ```
+    pub fn request_log(
+        &self,
+        count: u32,
+        timeout: Duration,
+    ) -> Result<Vec<TransferRecord>, ProtocolError> {
+        let mut records = vec![];
+        while (records.len() as u32) < count {
+            let channel_id = self.generate_channel()?;
+            self.send(&messages::log_request(
+                channel_id,
+                records.len() as u32,
+                count - records.len() as u32,
+            )?)?;
+
+            // Skip anything left over from an earlier request
+            let (total, entries) = loop {
+                match parsers::parse_message(self.recv(Some(timeout))?)? {
+                    Message::LogEntries(id, total, entries) if id == channel_id => {
+                        break (total, entries)
+                    }
+                    Message::Failure(id, error_message) if id == channel_id => {
+                        return Err(ProtocolError::TransmissionError {
+                            channel_id,
+                            error_message,
+                        });
+                    }
+                    _ => continue,
+                }
+            };
+
+            if entries.is_empty() {
+                break;
+            }
+            records.extend(entries);
+            if records.len() as u32 >= total {
+                break;
+            }
+        }
+
+        records.truncate(count as usize);
+        Ok(records)
+    }
+
     /// Listen for and process file protocol messages
     ///
     /// # Arguments
@@ -504,140 +1496,174 @@ impl Protocol {
         let mut state = start_state.clone();
         loop {
             // Listen on UDP port
-            let message = match pump(timeout) {
-                Ok(message) => {
-                    // If we previously timed out, restore the old state
-                    if let State::Holding { prev_state, .. } = state {
-                        state = *prev_state;
-                    }
+            match self.engine_step(pump(timeout), state)? {
+                Step::Continue(next) => state = next,
+                Step::Done => return Ok(()),
+            }
+        }
+    }
 
-                    message
+    // Handle the next message received by the message engine, or the timeout waiting for it
+    pub(crate) fn engine_step(
+        &self,
+        received: Result<Value, ProtocolError>,
+        mut state: State,
+    ) -> Result<Step, ProtocolError> {
+        let message = match received {
+            Ok(message) => {
+                // If we previously timed out, restore the old state
+                if let State::Holding { prev_state, .. } = state {
+                    state = *prev_state;
                 }
-                Err(ProtocolError::ReceiveTimeout) => match state.clone() {
-                    State::Receiving {
-                        channel_id,
-                        hash,
-                        path,
-                        mode,
-                    } => {
-                        match storage::validate_file(&self.config.storage_prefix, &hash, None) {
-                            Ok((true, _)) => {
-                                self.send(&messages::ack(channel_id, &hash, None)?)?;
-                                state = State::ReceivingDone {
-                                    channel_id,
-                                    hash: hash.clone(),
-                                    path: path.clone(),
-                                    mode,
-                                };
-                            }
-                            Ok((false, chunks)) => {
-                                self.send(&messages::nak(channel_id, &hash, &chunks)?)?;
-                                state = State::Holding {
-                                    count: 0,
-                                    prev_state: Box::new(state.clone()),
-                                };
-                                continue;
-                            }
-                            Err(e) => return Err(e),
-                        };
 
-                        match self.finalize_file(channel_id, &hash, &path, mode) {
-                            Ok(_) => {
-                                return Ok(());
-                            }
-                            Err(e) => {
-                                warn!("Failed to finalize file {} as {}: {}", hash, path, e);
-                                // TODO: Handle finalization failures (ex. corrupted chunk file)
-                                state = State::Holding {
-                                    count: 0,
-                                    prev_state: Box::new(state.clone()),
-                                };
-                                continue;
-                            }
+                message
+            }
+            Err(ProtocolError::ReceiveTimeout) => return self.engine_timeout(state),
+            Err(e) => return Err(e),
+        };
+
+        let state = self.process_message(message, &state)?;
+
+        match state.clone() {
+            State::ReceivingDone {
+                channel_id,
+                hash,
+                path,
+                mode,
+            } => {
+                // We've got all the chunks of data we want.
+                // Stitch it back together and verify the hash of the official file
+                self.finalize_file(channel_id, &hash, &path, mode)?;
+                Ok(Step::Done)
+            }
+            State::Done => Ok(Step::Done),
+            _ => Ok(Step::Continue(state)),
+        }
+    }
+
+    // Handle a timeout while the message engine waits for a message
+    fn engine_timeout(&self, state: State) -> Result<Step, ProtocolError> {
+        match state.clone() {
+            State::Receiving {
+                channel_id,
+                hash,
+                path,
+                mode,
+            } => {
+                let state = match storage::validate_file(&*self.store(), &hash, None)? {
+                    (true, _) => {
+                        self.send(&messages::ack(channel_id, &hash, None)?)?;
+                        State::ReceivingDone {
+                            channel_id,
+                            hash: hash.clone(),
+                            path: path.clone(),
+                            mode,
                         }
                     }
-                    State::ReceivingDone {
-                        channel_id,
-                        hash,
-                        path,
-                        mode,
-                    } => {
-                        // We've got all the chunks of data we want.
-                        // Stitch it back together and verify the hash of the official file
-                        self.finalize_file(channel_id, &hash, &path, mode)?;
-                        return Ok(());
+                    (false, chunks) => {
+                        self.send(&messages::nak(channel_id, &hash, &chunks)?)?;
+                        return Ok(Step::Continue(State::Holding {
+                            count: 0,
+                            prev_state: Box::new(state),
+                        }));
                     }
-                    State::Done => {
-                        return Ok(());
+                };
+
+                match self.finalize_file(channel_id, &hash, &path, mode) {
+                    Ok(_) => Ok(Step::Done),
+                    Err(e) => {
+                        warn!("Failed to finalize file {} as {}: {}", hash, path, e);
+                        // TODO: Handle finalization failures (ex. corrupted chunk file)
+                        Ok(Step::Continue(State::Holding {
+                            count: 0,
+                            prev_state: Box::new(state),
+                        }))
                     }
-                    State::Holding { count, prev_state } => {
-                        if count > self.config.hold_count {
-                            match prev_state.as_ref() {
-                                State::Holding { .. } => return Ok(()),
-                                State::Receiving { .. } => {
-                                    state = *prev_state;
-                                    continue;
-                                }
-                                _other => {
-                                    return Err(ProtocolError::ReceiveTimeout);
-                                }
-                            }
-                        } else {
-                            match prev_state.as_ref() {
-                                State::StartTrasmitting {
-                                    channel_id,
-                                    hash,
-                                    num_chunks,
-                                    mode,
-                                } => {
-                                    self.send(&messages::import_setup_success(
-                                        *channel_id,
-                                        &hash,
-                                        num_chunks.to_owned(),
-                                        mode.to_owned(),
-                                    )?)?;
-                                }
-                                _ => {}
-                            };
-
-                            state = State::Holding {
-                                count: count + 1,
-                                prev_state,
-                            };
-                            continue;
+                }
+            }
+            State::ReceivingDone {
+                channel_id,
+                hash,
+                path,
+                mode,
+            } => {
+                // We've got all the chunks of data we want.
+                // Stitch it back together and verify the hash of the official file
+                self.finalize_file(channel_id, &hash, &path, mode)?;
+                Ok(Step::Done)
+            }
+            State::Done => Ok(Step::Done),
+            State::Holding { count, prev_state } => {
+                if count > self.config.hold_count {
+                    match prev_state.as_ref() {
+                        State::Holding { .. } => Ok(Step::Done),
+                        State::Receiving { .. } | State::Transmitting => {
+                            Err(self.transfer_timeout(&prev_state))
                         }
+                        _other => Err(ProtocolError::ReceiveTimeout),
                     }
-                    _ => {
-                        state = State::Holding {
-                            count: 0,
-                            prev_state: Box::new(state.clone()),
-                        };
-                        continue;
+                } else {
+                    if let State::StartTrasmitting {
+                        channel_id,
+                        hash,
+                        num_chunks,
+                        mode,
+                    } = prev_state.as_ref()
+                    {
+                        self.send(&messages::import_setup_success(
+                            *channel_id,
+                            &hash,
+                            num_chunks.to_owned(),
+                            mode.to_owned(),
+                        )?)?;
                     }
-                },
-                Err(e) => return Err(e),
-            };
 
-            match self.process_message(message, &state) {
-                Ok(new_state) => state = new_state,
-                Err(e) => return Err(e),
+                    Ok(Step::Continue(State::Holding {
+                        count: count + 1,
+                        prev_state,
+                    }))
+                }
             }
+            _ => Ok(Step::Continue(State::Holding {
+                count: 0,
+                prev_state: Box::new(state),
+            })),
+        }
+    }
 
-            match state.clone() {
-                State::ReceivingDone {
-                    channel_id,
-                    hash,
-                    path,
-                    mode,
-                } => {
-                    // We've got all the chunks of data we want.
-                    // Stitch it back together and verify the hash of the official file
-                    self.finalize_file(channel_id, &hash, &path, mode)?;
-                    return Ok(());
-                }
-                State::Done => return Ok(()),
-                _ => continue,
-            };
+    // The error ending a transfer which has stopped getting replies,
+    // holding a token for resuming it if there's one
+    fn transfer_timeout(&self, state: &State) -> ProtocolError {
+        let token = match state {
+            State::Receiving { hash, .. } => match ResumeToken::from_store(&*self.store(), hash) {
+                Ok(token) => token,
+                Err(err) => return err,
+            },
+            _ => match self.upload.borrow().clone() {
+                Some(token) => token,
+                None => return ProtocolError::ReceiveTimeout,
+            },
+        };
+        ProtocolError::TransferTimeout { token }
+    }
+
+    // Keep track of the chunks the remote target is missing, in case the upload times out
+    fn note_missing(&self, hash: &str, missing_chunks: &[(u32, u32)]) {
+        let mut upload = self.upload.borrow_mut();
+        if let Some(token) = upload.as_mut() {
+            if token.hash == hash {
+                *token = ResumeToken::from_missing(hash, token.num_chunks, missing_chunks);
+            }
+        }
+    }
+
+    // Check that the file being downloaded is the one a resumed download was expecting
+    fn check_resumed(&self, hash: &str) -> Result<(), ProtocolError> {
+        match self.resuming.replace(None) {
+            Some(token) if token.hash != hash => Err(ProtocolError::ResumeError {
+                cause: format!("Remote file has changed, its hash is now {}", hash),
+            }),
+            _ => Ok(()),
         }
     }
 
@@ -684,28 +1710,38 @@ impl Protocol {
                     }
                     Message::Metadata(channel_id, hash, num_chunks) => {
                         info!("<- {{ {}, {}, {} }}", channel_id, hash, num_chunks);
-                        storage::store_meta(
-                            &self.config.storage_prefix,
-                            &hash,
-                            *num_chunks,
-                            None,
-                            None,
-                        )?;
+                        storage::store_meta(&*self.store(), &hash, *num_chunks, None, None)?;
+                        self.track_receive(hash)?;
                         new_state = State::StartReceive {
                             path: hash.to_owned(),
                         };
                     }
-                    Message::ReceiveChunk(channel_id, hash, chunk_num, data) => {
+                    Message::ReceiveChunk(channel_id, hash, chunk_num, data, crc) => {
                         info!(
-                            "<- {{ {}, {}, {}, chunk_data }}",
-                            channel_id, hash, chunk_num
+                            "<- {{ {}, {}, {}, chunk_data, {:?} }}",
+                            channel_id, hash, chunk_num, crc
                         );
-                        storage::store_chunk(
-                            &self.config.storage_prefix,
-                            &hash,
-                            *chunk_num,
-                            &data,
-                        )?;
+                        match crc {
+                            Some(crc) if *crc != crc32fast::hash(&data) => {
+                                // Don't wait for the whole file's hash to fail,
+                                // ask for the chunk again straight away
+                                warn!("Chunk {}:{} failed CRC check", hash, chunk_num);
+                                self.send(&messages::nak(
+                                    *channel_id,
+                                    &hash,
+                                    &[*chunk_num, *chunk_num + 1],
+                                )?)?;
+                                self.count_chunk(*channel_id, hash, |stats| {
+                                    stats.corrupt_chunk(data.len())
+                                })?;
+                            }
+                            _ => {
+                                self.store().store_chunk(&hash, *chunk_num, &data)?;
+                                self.count_chunk(*channel_id, hash, |stats| {
+                                    stats.chunk(*chunk_num, data.len())
+                                })?;
+                            }
+                        }
                         new_state = state.clone();
                     }
                     Message::ACK(_channel_id, ack_hash) => {
@@ -718,12 +1754,19 @@ impl Protocol {
                             "<- {{ {}, {}, false, {:?} }}",
                             channel_id, hash, missing_chunks
                         );
+                        self.note_missing(hash, missing_chunks);
+                        if let Some(tracker) = self.stats.borrow_mut().as_mut() {
+                            if tracker.hash() == hash {
+                                tracker.missing(missing_chunks);
+                            }
+                        }
                         match self.send_chunks(*channel_id, &hash, &missing_chunks) {
                             Ok(()) => {}
-                            Err(error) => self.send(&messages::operation_failure(
-                                *channel_id,
-                                &format!("{}", error),
-                            )?)?,
+                            // The remote target has already been told
+                            Err(error @ ProtocolError::TransferAborted { .. }) => {
+                                return Err(error)
+                            }
+                            Err(error) => self.send_failure(*channel_id, &format!("{}", error))?,
                         };
                         new_state = State::Transmitting;
                     }
@@ -732,43 +1775,56 @@ impl Protocol {
                         // TODO: Maybe trigger a failure?
                         new_state = state.clone();
                     }
-                    Message::ReqReceive(channel_id, hash, path, mode) => {
+                    Message::Signature(channel_id, hash, signature) => {
+                        info!("<- {{ {}, signature, {} }}", channel_id, hash);
+                        self.signature
+                            .replace(Some((hash.to_owned(), signature.to_owned())));
+                        new_state = state.clone();
+                    }
+                    Message::ReqReceive(channel_id, hash, path, mode, storage_class) => {
                         info!(
-                            "<- {{ {}, export, {}, {}, {:?} }}",
-                            channel_id, hash, path, mode
+                            "<- {{ {}, export, {}, {}, {:?}, {:?} }}",
+                            channel_id, hash, path, mode, storage_class
                         );
-                        // The client wants to send us a file.
-                        // See what state the file is currently in on our side
-                        match storage::validate_file(&self.config.storage_prefix, hash, None) {
-                            Ok((true, _)) => {
-                                // We've already got all the file data in temporary storage
-                                self.send(&messages::ack(*channel_id, &hash, None)?)?;
-
-                                new_state = State::ReceivingDone {
-                                    channel_id: *channel_id,
-                                    hash: hash.to_string(),
-                                    path: path.to_string(),
-                                    mode: *mode,
-                                };
-                            }
-                            Ok((false, chunks)) => {
-                                // We're missing some number of data chunks of the requrested file
-                                self.send(&messages::nak(*channel_id, &hash, &chunks)?)?;
-                                new_state = State::Receiving {
-                                    channel_id: *channel_id,
-                                    hash: hash.to_string(),
-                                    path: path.to_string(),
-                                    mode: *mode,
-                                };
-                            }
-                            Err(e) => return Err(e),
-                        }
+                        new_state =
+                            self.receive_file(*channel_id, hash, path, *mode, storage_class, None)?;
+                    }
+                    Message::ReqAppend(
+                        channel_id,
+                        hash,
+                        path,
+                        offset,
+                        prefix_hash,
+                        storage_class,
+                    ) => {
+                        info!(
+                            "<- {{ {}, append, {}, {}, {}, {}, {:?} }}",
+                            channel_id, hash, path, offset, prefix_hash, storage_class
+                        );
+                        new_state = self.receive_file(
+                            *channel_id,
+                            hash,
+                            path,
+                            None,
+                            storage_class,
+                            Some((*offset, prefix_hash)),
+                        )?;
                     }
-                    Message::ReqTransmit(channel_id, path) => {
-                        info!("<- {{ {}, import, {} }}", channel_id, path);
-                        // Set up the requested file for transmission
-                        match self.initialize_file(path) {
+                    Message::ReqTransmit(channel_id, path, offset, length, storage_class) => {
+                        info!(
+                            "<- {{ {}, import, {}, {}, {:?}, {:?} }}",
+                            channel_id, path, offset, length, storage_class
+                        );
+                        if let Err(e) = self.config.limits.check_import(path, *offset, *length) {
+                            warn!("{}", e);
+                            self.send_failure(*channel_id, &format!("{}", e))?;
+                            return Err(e);
+                        }
+                        self.use_storage_class(storage_class, None)?;
+                        // Set up the requested file (or part of it) for transmission
+                        match self.initialize_file_range(path, *offset, *length) {
                             Ok((hash, num_chunks, mode)) => {
+                                self.track(&hash, num_chunks, &[]);
                                 // It worked, let the requester know we're ready to send
                                 self.send(&messages::import_setup_success(
                                     *channel_id,
@@ -787,10 +1843,7 @@ impl Protocol {
                             Err(error) => {
                                 // It failed. Let the requester know that we can't transmit
                                 // the file they want.
-                                self.send(&messages::operation_failure(
-                                    *channel_id,
-                                    &format!("{}", error),
-                                )?)?;
+                                self.send_failure(*channel_id, &format!("{}", error))?;
 
                                 new_state = State::Done;
                             }
@@ -799,7 +1852,12 @@ impl Protocol {
                     Message::SuccessReceive(channel_id, hash) => {
                         info!("<- {{ {}, true }}", channel_id);
                         new_state = State::Done;
-                        storage::delete_file(&self.config.storage_prefix, hash)?;
+                        self.transferred(hash)?;
+                    }
+                    Message::AlreadyPresent(channel_id, hash, path) => {
+                        info!("<- {{ {}, present, {}, {} }}", channel_id, hash, path);
+                        new_state = State::Done;
+                        self.transferred(hash)?;
                     }
                     Message::SuccessTransmit(channel_id, hash, num_chunks, mode) => {
                         match mode {
@@ -812,12 +1870,15 @@ impl Protocol {
                             }
                         }
 
+                        self.check_resumed(hash)?;
+
                         // TODO: handle channel_id mismatch
-                        match storage::validate_file(
-                            &self.config.storage_prefix,
-                            hash,
-                            Some(*num_chunks),
-                        ) {
+                        let validated =
+                            storage::validate_file(&*self.store(), hash, Some(*num_chunks));
+                        if validated.is_ok() {
+                            self.track_receive(hash)?;
+                        }
+                        match validated {
                             Ok((true, _)) => {
                                 self.send(&messages::ack(*channel_id, &hash, Some(*num_chunks))?)?;
                                 new_state = match state.clone() {
@@ -845,6 +1906,17 @@ impl Protocol {
                             Err(e) => return Err(e),
                         }
                     }
+                    Message::ChannelInUse(channel_id, key) => {
+                        info!("<- {{ {}, in_use, {} }}", channel_id, key);
+                        // Another client's request may have been rejected on our channel
+                        if self.last_request.borrow().as_ref() != Some(key) {
+                            new_state = state.clone();
+                        } else {
+                            return Err(ProtocolError::ChannelInUse {
+                                channel_id: *channel_id,
+                            });
+                        }
+                    }
                     Message::Failure(channel_id, error_message) => {
                         info!("<- {{ {}, false, {} }}", channel_id, error_message);
                         return Err(ProtocolError::TransmissionError {
@@ -852,14 +1924,35 @@ impl Protocol {
                             error_message: error_message.to_string(),
                         });
                     }
+                    Message::ReqLog(channel_id, skip, count) => {
+                        // Answered by the service before a transaction is started
+                        info!("<- {{ {}, log, {}, {} }}", channel_id, skip, count);
+                        new_state = state.clone();
+                    }
+                    Message::LogEntries(channel_id, total, entries) => {
+                        info!(
+                            "<- {{ {}, log_entries, {}, [{} entries] }}",
+                            channel_id,
+                            total,
+                            entries.len()
+                        );
+                        new_state = state.clone();
+                    }
                     Message::Cleanup(channel_id, Some(hash)) => {
                         info!("<- {{ {}, cleanup, {} }}", channel_id, hash);
-                        storage::delete_file(&self.config.storage_prefix, hash)?;
+                        // Cleanup requests don't name a storage class,
+                        // so the file is removed from whichever store holds it
+                        self.config.delete_file(hash)?;
                         new_state = State::Done;
                     }
                     Message::Cleanup(channel_id, None) => {
                         info!("< {{ {}, cleanup }}", channel_id);
-                        storage::delete_storage(&self.config.storage_prefix)?;
+                        for (class, store) in &self.config.storage_classes {
+                            if let Err(e) = store.delete_storage() {
+                                warn!("Failed to clean up storage class '{}': {}", class, e);
+                            }
+                        }
+                        self.config.store.delete_storage()?;
                         new_state = State::Done;
                     }
                 }
@@ -868,3 +1961,30 @@ impl Protocol {
         }
     }
 }
+
+fn random_channel() -> u32 {
+    rand::thread_rng().gen_range(MIN_CHANNEL_ID, MAX_CHANNEL_ID)
+}
+
+fn next_channel() -> u32 {
+    loop {
+        let current = NEXT_CHANNEL_ID.load(Ordering::SeqCst);
+        let channel_id = if current == 0 {
+            random_channel()
+        } else {
+            current
+        };
+        let next = if channel_id + 1 >= MAX_CHANNEL_ID {
+            MIN_CHANNEL_ID
+        } else {
+            channel_id + 1
+        };
+
+        if NEXT_CHANNEL_ID
+            .compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return channel_id;
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Resuming transfers across passes
+//!
+//! A transfer which is cut off, for example by the end of a ground pass, leaves the chunks
+//! received so far in the receiver's storage. When the message engine stops getting replies
+//! during an upload or download, it returns `ProtocolError::TransferTimeout` holding a
+//! [`ResumeToken`](struct.ResumeToken.html): the file's hash, its number of chunks, and a digest
+//! of which chunks the receiver holds.
+//!
+//! On the next pass, the token is passed to
+//! [`resume_export`](../protocol/struct.Protocol.html#method.resume_export) or
+//! [`resume_import`](../protocol/struct.Protocol.html#method.resume_import) in place of
+//! setting the transfer up again. The file isn't hashed again and no metadata is sent; the
+//! receiver asks for the chunks it's still missing and the transfer carries on from there.
+//!
+//! Tokens can be saved between passes in their text form, `<hash>:<num_chunks>:<digest>`.
+
+use crate::error::ProtocolError;
+use crate::storage::ChunkStore;
+use std::fmt;
+use std::str::FromStr;
+
+/// Everything needed to resume an interrupted transfer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResumeToken {
+    /// Hash of the file being transferred
+    pub hash: String,
+    /// Number of chunks in the file
+    pub num_chunks: u32,
+    /// CRC32 of a bitmap of the chunks the receiver holds (bit `n % 8` of byte `n / 8` is set
+    /// for chunk `n`). When uploading, this is as the receiver last reported it
+    pub digest: u32,
+}
+
+impl ResumeToken {
+    /// Create a token for a file, given the chunks the receiver holds
+    pub fn new(hash: &str, num_chunks: u32, held: &[u32]) -> Self {
+        let mut chunks = vec![false; num_chunks as usize];
+        for index in held.iter().filter(|index| **index < num_chunks) {
+            chunks[*index as usize] = true;
+        }
+        ResumeToken::from_bitmap(hash, &chunks)
+    }
+
+    // Create a token from the chunk ranges the receiver reported missing
+    pub(crate) fn from_missing(hash: &str, num_chunks: u32, missing: &[(u32, u32)]) -> Self {
+        let mut chunks = vec![true; num_chunks as usize];
+        for (first, last) in missing {
+            for index in *first..(*last).min(num_chunks) {
+                chunks[index as usize] = false;
+            }
+        }
+        ResumeToken::from_bitmap(hash, &chunks)
+    }
+
+    // Create a token from the chunks of a file held in a store
+    pub(crate) fn from_store(store: &dyn ChunkStore, hash: &str) -> Result<Self, ProtocolError> {
+        let num_chunks = store.load_meta(hash)?.num_chunks;
+        Ok(ResumeToken::new(hash, num_chunks, &store.chunks(hash)?))
+    }
+
+    fn from_bitmap(hash: &str, chunks: &[bool]) -> Self {
+        let mut bitmap = vec![0u8; (chunks.len() + 7) / 8];
+        for (index, _) in chunks.iter().enumerate().filter(|(_, held)| **held) {
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+        ResumeToken {
+            hash: hash.to_owned(),
+            num_chunks: chunks.len() as u32,
+            digest: crc32fast::hash(&bitmap),
+        }
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{:08x}", self.hash, self.num_chunks, self.digest)
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = ProtocolError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProtocolError::ResumeError {
+            cause: format!("Invalid token '{}'", token),
+        };
+
+        let mut parts = token.split(':');
+        let (hash, num_chunks, digest) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(hash), Some(num_chunks), Some(digest)) if parts.next().is_none() => {
+                (hash, num_chunks, digest)
+            }
+            _ => return Err(invalid()),
+        };
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        Ok(ResumeToken {
+            hash: hash.to_owned(),
+            num_chunks: num_chunks.parse().map_err(|_| invalid())?,
+            digest: u32::from_str_radix(digest, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Protocol, ProtocolConfig, State};
+    use crate::{parsers, Message};
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("file-protocol-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn config(dir: &str, side: &str) -> ProtocolConfig {
+        ProtocolConfig::new(Some(format!("{}/{}", dir, side)), 1024, 2, 1, None, 2048)
+    }
+
+    #[test]
+    fn token_text() {
+        let token = ResumeToken::new("0123456789abcdef", 20, &[0, 1, 2, 7]);
+        let parsed: ResumeToken = token.to_string().parse().unwrap();
+        assert_eq!(parsed, token);
+
+        for bad in &[
+            "",
+            "0123:20",
+            "0123:20:zz",
+            "0123:x:0",
+            "xyz:20:0",
+            "0123:20:0:0",
+        ] {
+            assert!(bad.parse::<ResumeToken>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn token_digest() {
+        let held = ResumeToken::new("abcd", 10, &[0, 1, 2, 6, 7, 8, 9]);
+        let missing = ResumeToken::from_missing("abcd", 10, &[(3, 6)]);
+        assert_eq!(held, missing);
+
+        assert_ne!(held, ResumeToken::new("abcd", 10, &[0, 1, 2]));
+        assert_ne!(
+            ResumeToken::new("abcd", 10, &[]),
+            ResumeToken::new("abcd", 11, &[])
+        );
+    }
+
+    #[test]
+    fn resume_upload() {
+        let dir = test_dir("resume-upload");
+        let source = format!("{}/source", dir);
+        let dest = format!("{}/dest", dir);
+        let contents: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        fs::write(&source, &contents).unwrap();
+
+        // First pass: the pass ends after the receiver gets a few chunks
+        let server = Protocol::new("127.0.0.1:7203", "127.0.0.1:7202", config(&dir, "server"));
+        let server = thread::spawn(move || {
+            let received = Cell::new(0);
+            server.message_engine(
+                |d| {
+                    received.set(received.get() + 1);
+                    if received.get() > 7 {
+                        return Err(ProtocolError::ReceiveError {
+                            err: "Pass over".to_owned(),
+                        });
+                    }
+                    server.recv(Some(d))
+                },
+                Duration::from_millis(200),
+                &State::Holding {
+                    count: 0,
+                    prev_state: Box::new(State::Done),
+                },
+            )
+        });
+
+        let client = Protocol::new("127.0.0.1:7202", "127.0.0.1:7203", config(&dir, "client"));
+        let (hash, num_chunks, mode) = client.initialize_file(&source).unwrap();
+        let channel_id = client.generate_channel().unwrap();
+        client.send_metadata(channel_id, &hash, num_chunks).unwrap();
+        client.send_export(channel_id, &hash, &dest, mode).unwrap();
+        let result = client.message_engine(
+            |d| client.recv(Some(d)),
+            Duration::from_millis(200),
+            &State::Transmitting,
+        );
+        let token = match result {
+            Err(ProtocolError::TransferTimeout { token }) => token,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        assert!(server.join().unwrap().is_err());
+        drop(client);
+
+        // The receiver held nothing when it asked for the chunks
+        assert_eq!(token, ResumeToken::new(&hash, num_chunks, &[]));
+
+        // Second pass, from a new client
+        let token: ResumeToken = token.to_string().parse().unwrap();
+        let server = Protocol::new("127.0.0.1:7203", "127.0.0.1:7202", config(&dir, "server"));
+        let server = thread::spawn(move || {
+            server.message_engine(
+                |d| server.recv(Some(d)),
+                Duration::from_millis(200),
+                &State::Holding {
+                    count: 0,
+                    prev_state: Box::new(State::Done),
+                },
+            )
+        });
+
+        let client = Protocol::new("127.0.0.1:7202", "127.0.0.1:7203", config(&dir, "client"));
+        let channel_id = client.generate_channel().unwrap();
+        client
+            .resume_export(channel_id, &token, &dest, mode)
+            .unwrap();
+        client
+            .message_engine(
+                |d| client.recv(Some(d)),
+                Duration::from_millis(200),
+                &State::Transmitting,
+            )
+            .unwrap();
+
+        server.join().unwrap().unwrap();
+        assert_eq!(contents, fs::read(&dest).unwrap());
+    }
+
+    #[test]
+    fn resume_download() {
+        let dir = test_dir("resume-download");
+        let source = format!("{}/source", dir);
+        let dest = format!("{}/dest", dir);
+        let contents: Vec<u8> = (0..20_000).map(|i| (i / 7) as u8).collect();
+        fs::write(&source, &contents).unwrap();
+
+        let serve = |passes: usize| {
+            let server = Protocol::new("127.0.0.1:7205", "127.0.0.1:7204", config(&dir, "server"));
+            thread::spawn(move || {
+                let received = Cell::new(0);
+                server.message_engine(
+                    |d| {
+                        received.set(received.get() + 1);
+                        if received.get() > passes {
+                            return Err(ProtocolError::ReceiveError {
+                                err: "Pass over".to_owned(),
+                            });
+                        }
+                        server.recv(Some(d))
+                    },
+                    Duration::from_millis(200),
+                    &State::Holding {
+                        count: 0,
+                        prev_state: Box::new(State::Done),
+                    },
+                )
+            })
+        };
+        let start_receive = State::StartReceive { path: dest.clone() };
+
+        // First pass: the server answers the import request and the first request for chunks,
+        // and only a few of the chunks arrive
+        let server = serve(2);
+        let client = Protocol::new("127.0.0.1:7204", "127.0.0.1:7205", config(&dir, "client"));
+        let channel_id = client.generate_channel().unwrap();
+        client.send_import(channel_id, &source).unwrap();
+        let reply = client.recv(Some(Duration::from_secs(1))).unwrap();
+        let state = client.process_message(reply, &start_receive).unwrap();
+        let chunks = Cell::new(0);
+        let result = client.message_engine(
+            |d| loop {
+                let message = client.recv(Some(d))?;
+                if let Ok(Message::ReceiveChunk(..)) = parsers::parse_message(message.clone()) {
+                    chunks.set(chunks.get() + 1);
+                    if chunks.get() > 5 {
+                        continue;
+                    }
+                }
+                return Ok(message);
+            },
+            Duration::from_millis(200),
+            &state,
+        );
+        let token = match result {
+            Err(ProtocolError::TransferTimeout { token }) => token,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        assert!(server.join().unwrap().is_err());
+        drop(client);
+        assert_eq!(token, ResumeToken::new(&token.hash, 20, &[0, 1, 2, 3, 4]));
+
+        // Second pass, from a new client
+        let server = serve(usize::max_value());
+        let client = Protocol::new("127.0.0.1:7204", "127.0.0.1:7205", config(&dir, "client"));
+        let channel_id = client.generate_channel().unwrap();
+        client.resume_import(channel_id, &token, &source).unwrap();
+        let reply = client.recv(Some(Duration::from_secs(1))).unwrap();
+        let state = client.process_message(reply, &start_receive).unwrap();
+        client
+            .message_engine(|d| client.recv(Some(d)), Duration::from_millis(200), &state)
+            .unwrap();
+
+        server.join().unwrap().unwrap();
+        assert_eq!(contents, fs::read(&dest).unwrap());
+    }
+}
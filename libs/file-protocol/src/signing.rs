@@ -0,0 +1,224 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Signed uploads
+//!
+//! A receiver configured with [`UploadKeys`](struct.UploadKeys.html) (see
+//! [`with_upload_keys`](../protocol/struct.ProtocolConfig.html#method.with_upload_keys)) only
+//! exports files which were signed with one of the keys. The signature is a detached Ed25519
+//! signature over the file's hash, as the hex string used in the protocol's messages, made on
+//! the ground with the matching secret key. The sender passes it to
+//! [`send_signature`](../protocol/struct.Protocol.html#method.send_signature) before sending the
+//! export request.
+//!
+//! Exports whose signature is missing, or doesn't verify against any of the keys, fail before
+//! anything is written to their destination. Since the hash of the received file is checked
+//! before it's exported, a file which was changed after it was signed is also never written.
+
+use crate::error::ProtocolError;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use std::convert::TryFrom;
+
+/// Public keys which uploads have to be signed with
+#[derive(Clone, Debug)]
+pub struct UploadKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl UploadKeys {
+    /// Create the set of keys from raw 32 byte Ed25519 public keys
+    pub fn new<K: AsRef<[u8]>>(keys: &[K]) -> Result<Self, ProtocolError> {
+        let keys = keys
+            .iter()
+            .map(|key| {
+                PublicKey::from_bytes(key.as_ref()).map_err(|err| ProtocolError::InvalidKey {
+                    cause: err.to_string(),
+                })
+            })
+            .collect::<Result<Vec<PublicKey>, ProtocolError>>()?;
+
+        Ok(UploadKeys { keys })
+    }
+
+    /// Create the set of keys from hex-encoded 32 byte Ed25519 public keys,
+    /// as they're given in the file service's config
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let keys = UploadKeys::from_hex(&[
+    ///     "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+    /// ])
+    /// .unwrap();
+    /// ```
+    pub fn from_hex<K: AsRef<str>>(keys: &[K]) -> Result<Self, ProtocolError> {
+        let keys = keys
+            .iter()
+            .map(|key| decode_hex(key.as_ref()))
+            .collect::<Result<Vec<Vec<u8>>, ProtocolError>>()?;
+        UploadKeys::new(&keys)
+    }
+
+    /// Check that `signature` is a signature over the file hash made with one of the keys
+    pub fn verify(&self, hash: &str, signature: &[u8]) -> Result<(), ProtocolError> {
+        let signature =
+            Signature::try_from(signature).map_err(|_| ProtocolError::SignatureRejected {
+                hash: hash.to_owned(),
+                cause: "Malformed signature".to_owned(),
+            })?;
+
+        if self
+            .keys
+            .iter()
+            .any(|key| key.verify(hash.as_bytes(), &signature).is_ok())
+        {
+            Ok(())
+        } else {
+            Err(ProtocolError::SignatureRejected {
+                hash: hash.to_owned(),
+                cause: "Signature doesn't match any upload key".to_owned(),
+            })
+        }
+    }
+}
+
+fn decode_hex(key: &str) -> Result<Vec<u8>, ProtocolError> {
+    if key.len() % 2 != 0 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ProtocolError::InvalidKey {
+            cause: format!("{} is not a hex string", key),
+        });
+    }
+
+    Ok((0..key.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&key[index..index + 2], 16).unwrap_or_default())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Protocol, ProtocolConfig, State};
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    #[test]
+    fn verify_signature() {
+        let signer = keypair(1);
+        let keys =
+            UploadKeys::new(&[keypair(2).public.to_bytes(), signer.public.to_bytes()]).unwrap();
+
+        let signature = signer.sign(b"abcdef").to_bytes();
+        assert!(keys.verify("abcdef", &signature).is_ok());
+
+        // The signature is only good for the file it was made for
+        assert!(keys.verify("abcdee", &signature).is_err());
+        assert!(keys.verify("abcdef", &signature[0..32]).is_err());
+    }
+
+    #[test]
+    fn unknown_key() {
+        let keys = UploadKeys::new(&[keypair(2).public.to_bytes()]).unwrap();
+        let signature = keypair(1).sign(b"abcdef").to_bytes();
+
+        match keys.verify("abcdef", &signature) {
+            Err(ProtocolError::SignatureRejected { hash, .. }) => assert_eq!(hash, "abcdef"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hex_keys() {
+        let key = keypair(1).public.to_bytes();
+        let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            UploadKeys::from_hex(&[&hex]).unwrap().keys[0].to_bytes(),
+            key
+        );
+
+        assert!(UploadKeys::from_hex(&["abc"]).is_err());
+        assert!(UploadKeys::from_hex(&["not a key"]).is_err());
+        assert!(UploadKeys::from_hex(&["abcd"]).is_err());
+    }
+
+    // Upload the file to the destination, signed with the given key if there is one,
+    // returning the result of the client's message engine
+    fn upload(dir: &str, dest: &str, signer: Option<&Keypair>) -> Result<(), ProtocolError> {
+        let keys = UploadKeys::new(&[keypair(1).public.to_bytes()]).unwrap();
+        let server_config =
+            ProtocolConfig::new(Some(format!("{}/server", dir)), 1024, 5, 1, None, 2048)
+                .with_upload_keys(keys);
+        let server = Protocol::new("127.0.0.1:7207", "127.0.0.1:7206", server_config);
+        let server = thread::spawn(move || {
+            server.message_engine(
+                |d| server.recv(Some(d)),
+                Duration::from_millis(500),
+                &State::Holding {
+                    count: 0,
+                    prev_state: Box::new(State::Done),
+                },
+            )
+        });
+
+        let config = ProtocolConfig::new(Some(format!("{}/client", dir)), 1024, 5, 1, None, 2048);
+        let client = Protocol::new("127.0.0.1:7206", "127.0.0.1:7207", config);
+        let (hash, num_chunks, mode) = client.initialize_file(&format!("{}/source", dir))?;
+        let channel_id = client.generate_channel()?;
+        client.send_metadata(channel_id, &hash, num_chunks)?;
+        if let Some(signer) = signer {
+            let signature = signer.sign(hash.as_bytes()).to_bytes();
+            client.send_signature(channel_id, &hash, &signature)?;
+        }
+        client.send_export(channel_id, &hash, dest, mode)?;
+        let result = client.message_engine(
+            |d| client.recv(Some(d)),
+            Duration::from_millis(500),
+            &State::Transmitting,
+        );
+
+        let _ = server.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn signed_uploads() {
+        let dir = env::temp_dir().join(format!("file-protocol-signing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+        fs::write(format!("{}/source", dir), vec![7u8; 5000]).unwrap();
+        let dest = format!("{}/dest", dir);
+
+        // Neither an unsigned upload nor one signed with another key is exported
+        assert!(upload(&dir, &dest, None).is_err());
+        assert!(upload(&dir, &dest, Some(&keypair(2))).is_err());
+        assert!(fs::metadata(&dest).is_err());
+
+        upload(&dir, &dest, Some(&keypair(1))).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), vec![7u8; 5000]);
+    }
+}
@@ -0,0 +1,330 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Throughput and ETA of transfers
+//!
+//! While a file's chunks are being sent or received, the protocol counts the chunk data which
+//! has moved, how much of it was sent more than once, and how many of the file's chunks the
+//! receiver holds. The resulting [`TransferStats`](struct.TransferStats.html) are passed to the
+//! callback given to
+//! [`with_progress`](../protocol/struct.ProtocolConfig.html#method.with_progress) after each
+//! chunk, and the latest ones are returned by
+//! [`stats`](../protocol/struct.Protocol.html#method.stats).
+//!
+//! The callback can abort a transfer which isn't going to finish, such as one whose ETA is past
+//! the end of the pass, by returning `false`. The message engine then tells the remote target
+//! and returns `ProtocolError::TransferAborted`. The chunks already moved are kept, so the
+//! transfer can still be resumed later.
+
+use std::time::{Duration, Instant};
+
+/// Progress of the transfer of a file's chunks
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferStats {
+    /// Hash of the file being transferred
+    pub hash: String,
+    /// Number of chunks in the file
+    pub num_chunks: u32,
+    /// Number of chunks the receiver holds. When sending, chunks are counted as soon as they're
+    /// sent, until the receiver reports which ones it's missing
+    pub chunks_held: u32,
+    /// Number of chunks sent or received, including retransmits
+    pub chunks: u64,
+    /// Number of chunks which the receiver already held, or which failed their CRC check
+    pub retransmits: u64,
+    /// Bytes of chunk data sent or received, including retransmits
+    pub bytes: u64,
+    /// Time since the transfer started
+    pub elapsed: Duration,
+}
+
+impl TransferStats {
+    /// Chunk data sent or received per second, including retransmits
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Fraction of the chunks sent or received which were retransmits
+    pub fn retransmit_ratio(&self) -> f64 {
+        if self.chunks > 0 {
+            self.retransmits as f64 / self.chunks as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated time until the receiver holds every chunk, at the rate new chunks have
+    /// arrived so far. `None` until a new chunk has arrived
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.num_chunks.saturating_sub(self.chunks_held);
+        if remaining == 0 {
+            return Some(Duration::from_secs(0));
+        }
+
+        let useful = self.chunks - self.retransmits;
+        if useful == 0 || self.elapsed == Duration::from_secs(0) {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            self.elapsed.as_secs_f64() * f64::from(remaining) / useful as f64,
+        ))
+    }
+}
+
+// Keeps the stats of the transfer a protocol instance is taking part in
+pub(crate) struct StatsTracker {
+    stats: TransferStats,
+    started: Instant,
+    // Chunks the receiver holds, as far as this side knows
+    held: Vec<bool>,
+    // Chunks sent or received during this transfer
+    seen: Vec<bool>,
+}
+
+impl StatsTracker {
+    // Start tracking the transfer of a file, given the chunks the receiver already holds
+    pub(crate) fn new(hash: &str, num_chunks: u32, held: &[u32]) -> Self {
+        let mut tracker = StatsTracker {
+            stats: TransferStats {
+                hash: hash.to_owned(),
+                num_chunks,
+                chunks_held: 0,
+                chunks: 0,
+                retransmits: 0,
+                bytes: 0,
+                elapsed: Duration::default(),
+            },
+            started: Instant::now(),
+            held: vec![false; num_chunks as usize],
+            seen: vec![false; num_chunks as usize],
+        };
+        for index in held {
+            if let Some(chunk) = tracker.held.get_mut(*index as usize) {
+                *chunk = true;
+            }
+        }
+        tracker.count_held();
+        tracker
+    }
+
+    pub(crate) fn hash(&self) -> &str {
+        &self.stats.hash
+    }
+
+    // A chunk was sent or received. It's a retransmit if it was already sent during this
+    // transfer, or the receiver already held it
+    pub(crate) fn chunk(&mut self, index: u32, len: usize) {
+        self.stats.chunks += 1;
+        self.stats.bytes += len as u64;
+
+        let index = index as usize;
+        if index >= self.held.len() {
+            return;
+        }
+        if self.seen[index] || self.held[index] {
+            self.stats.retransmits += 1;
+        }
+        if !self.held[index] {
+            self.stats.chunks_held += 1;
+        }
+        self.seen[index] = true;
+        self.held[index] = true;
+    }
+
+    // A chunk was received which failed its CRC check, so has to be sent again
+    pub(crate) fn corrupt_chunk(&mut self, len: usize) {
+        self.stats.chunks += 1;
+        self.stats.retransmits += 1;
+        self.stats.bytes += len as u64;
+    }
+
+    // The receiver reported the chunk ranges it's missing, so holds all of the others
+    pub(crate) fn missing(&mut self, missing: &[(u32, u32)]) {
+        for chunk in self.held.iter_mut() {
+            *chunk = true;
+        }
+        for (first, last) in missing {
+            let last = (*last as usize).min(self.held.len());
+            for index in (*first as usize).min(last)..last {
+                self.held[index] = false;
+            }
+        }
+        self.count_held();
+    }
+
+    fn count_held(&mut self) {
+        self.stats.chunks_held = self.held.iter().filter(|held| **held).count() as u32;
+    }
+
+    // The transfer's stats as of now
+    pub(crate) fn stats(&self) -> TransferStats {
+        TransferStats {
+            elapsed: self.started.elapsed(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProtocolError;
+    use crate::protocol::{Protocol, ProtocolConfig, State};
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    fn stats(chunks: u64, retransmits: u64, elapsed: u64) -> TransferStats {
+        TransferStats {
+            hash: "abcdef".to_owned(),
+            num_chunks: 100,
+            chunks_held: (chunks - retransmits) as u32,
+            chunks,
+            retransmits,
+            bytes: chunks * 1024,
+            elapsed: Duration::from_secs(elapsed),
+        }
+    }
+
+    #[test]
+    fn rates() {
+        let stats = stats(40, 10, 10);
+        assert_eq!(stats.bytes_per_sec() as u64, 4096);
+        assert_eq!((stats.retransmit_ratio() * 100.0) as u32, 25);
+        // 30 new chunks in 10 seconds leaves 70 chunks to go
+        assert_eq!(stats.eta().unwrap().as_millis(), 23_333);
+    }
+
+    #[test]
+    fn eta_unknown() {
+        assert_eq!(stats(0, 0, 10).eta(), None);
+        assert_eq!(stats(5, 5, 10).eta(), None);
+        assert_eq!(stats(0, 0, 0).bytes_per_sec() as u64, 0);
+        assert_eq!(stats(0, 0, 0).retransmit_ratio() as u64, 0);
+    }
+
+    #[test]
+    fn sending() {
+        let mut tracker = StatsTracker::new("abcdef", 4, &[]);
+        for index in 0..4 {
+            tracker.chunk(index, 10);
+        }
+        assert_eq!(tracker.stats().chunks_held, 4);
+        assert_eq!(tracker.stats().eta(), Some(Duration::from_secs(0)));
+
+        // Chunks 1 and 2 were lost, so they're sent again
+        tracker.missing(&[(1, 3)]);
+        assert_eq!(tracker.stats().chunks_held, 2);
+        tracker.chunk(1, 10);
+        tracker.chunk(2, 10);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.chunks_held, 4);
+        assert_eq!(stats.chunks, 6);
+        assert_eq!(stats.retransmits, 2);
+        assert_eq!(stats.bytes, 60);
+    }
+
+    #[test]
+    fn receiving() {
+        // Chunk 0 arrived on an earlier pass
+        let mut tracker = StatsTracker::new("abcdef", 3, &[0]);
+        assert_eq!(tracker.stats().chunks_held, 1);
+
+        tracker.chunk(0, 10);
+        tracker.corrupt_chunk(10);
+        tracker.chunk(1, 10);
+        tracker.chunk(1, 10);
+        tracker.chunk(2, 10);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.chunks_held, 3);
+        assert_eq!(stats.chunks, 5);
+        assert_eq!(stats.retransmits, 3);
+    }
+
+    #[test]
+    fn chunk_out_of_range() {
+        let mut tracker = StatsTracker::new("abcdef", 2, &[5]);
+        tracker.chunk(5, 10);
+        tracker.missing(&[(1, 9)]);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.chunks_held, 1);
+        assert_eq!(stats.chunks, 1);
+        assert_eq!(stats.bytes, 10);
+    }
+
+    #[test]
+    fn abort_upload() {
+        let dir = env::temp_dir().join(format!("file-protocol-stats-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+        let source = format!("{}/source", dir);
+        fs::write(&source, vec![7u8; 5000]).unwrap();
+
+        let server_config =
+            ProtocolConfig::new(Some(format!("{}/server", dir)), 1024, 5, 1, None, 2048);
+        let server = Protocol::new("127.0.0.1:7203", "127.0.0.1:7202", server_config);
+        let server = thread::spawn(move || {
+            server.message_engine(
+                |d| server.recv(Some(d)),
+                Duration::from_millis(500),
+                &State::Holding {
+                    count: 0,
+                    prev_state: Box::new(State::Done),
+                },
+            )
+        });
+
+        // Give up after the second chunk
+        let config = ProtocolConfig::new(Some(format!("{}/client", dir)), 1024, 5, 1, None, 2048)
+            .with_progress(|stats| stats.chunks < 2);
+        let f_protocol = Protocol::new("127.0.0.1:7202", "127.0.0.1:7203", config);
+
+        let (hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+        let channel_id = f_protocol.generate_channel().unwrap();
+        f_protocol
+            .send_metadata(channel_id, &hash, num_chunks)
+            .unwrap();
+        f_protocol
+            .send_export(channel_id, &hash, &format!("{}/dest", dir), mode)
+            .unwrap();
+        match f_protocol.message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_millis(500),
+            &State::Transmitting,
+        ) {
+            Err(ProtocolError::TransferAborted { hash: aborted }) => assert_eq!(aborted, hash),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let stats = f_protocol.stats().unwrap();
+        assert_eq!(stats.num_chunks, 5);
+        assert_eq!(stats.chunks_held, 2);
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(stats.bytes, 2048);
+
+        // The remote target was told the transfer is over
+        assert!(server.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,365 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::filesystem::{load_export, store_export};
+use super::{ChunkMeta, ChunkStore};
+use crate::error::ProtocolError;
+use serde_cbor::{de, to_vec};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// Record kinds
+const CHUNK: u8 = 0;
+const DELETED: u8 = 1;
+const META: u8 = 2;
+
+// Kind, chunk index and data length
+const RECORD_HEADER_SIZE: usize = 9;
+
+// Where everything in a file's log currently lives
+#[derive(Default)]
+struct LogIndex {
+    // Offset and length of the latest copy of each chunk's data
+    chunks: HashMap<u32, (u64, u32)>,
+    meta: Option<ChunkMeta>,
+}
+
+/// Chunk storage using a single append-only log per file
+///
+/// Each file's chunks and metadata are appended to `{prefix}/storage/{hash}.log`,
+/// and nothing is ever rewritten in place: a deleted chunk is recorded by appending
+/// a marker. This avoids creating (and later deleting) a file per chunk, which
+/// wears out flash and is slow on flash file systems such as JFFS2 and UBIFS.
+///
+/// A record which was only partly written (for example, because of a reset) is
+/// discarded the next time the log is opened.
+pub struct LogChunkStore {
+    prefix: String,
+    // Index of each log which has been opened
+    logs: Mutex<HashMap<String, LogIndex>>,
+}
+
+impl LogChunkStore {
+    /// Creates a store in the given directory
+    pub fn new(prefix: &str) -> Self {
+        LogChunkStore {
+            prefix: prefix.to_owned(),
+            logs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn storage_path(&self) -> PathBuf {
+        Path::new(&format!("{}/storage", self.prefix)).to_owned()
+    }
+
+    fn log_path(&self, hash: &str) -> PathBuf {
+        self.storage_path().join(format!("{}.log", hash))
+    }
+
+    fn logs(&self) -> MutexGuard<'_, HashMap<String, LogIndex>> {
+        // Indexes are rebuilt from the logs if anything goes wrong, so a panic elsewhere
+        // doesn't matter
+        self.logs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Get the index of a file's log, reading the log if it hasn't been opened yet.
+    // Returns None if there is no log for the file.
+    fn index<'a>(
+        &self,
+        logs: &'a mut HashMap<String, LogIndex>,
+        hash: &str,
+    ) -> Result<Option<&'a mut LogIndex>, ProtocolError> {
+        if !logs.contains_key(hash) {
+            match self.scan(hash)? {
+                Some(index) => {
+                    logs.insert(hash.to_owned(), index);
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(logs.get_mut(hash))
+    }
+
+    // Build the index of a file's log, truncating any partly written record at the end
+    fn scan(&self, hash: &str) -> Result<Option<LogIndex>, ProtocolError> {
+        let path = self.log_path(hash);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(ProtocolError::StorageError {
+                    action: format!("open {:?}", path),
+                    err,
+                })
+            }
+        };
+
+        let file_len = file
+            .metadata()
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("stat {:?}", path),
+                err,
+            })?
+            .len();
+
+        let mut index = LogIndex::default();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0u64;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        loop {
+            if read_full(&mut reader, &mut header).map_err(|err| ProtocolError::StorageError {
+                action: format!("read {:?}", path),
+                err,
+            })? != RECORD_HEADER_SIZE
+            {
+                break;
+            }
+
+            let kind = header[0];
+            let chunk = u32_from_be(&header[1..5]);
+            let len = u32_from_be(&header[5..9]);
+            let data_offset = offset + RECORD_HEADER_SIZE as u64;
+            if data_offset + u64::from(len) > file_len {
+                break;
+            }
+
+            let mut data = vec![0; len as usize];
+            if read_full(&mut reader, &mut data).map_err(|err| ProtocolError::StorageError {
+                action: format!("read {:?}", path),
+                err,
+            })? != data.len()
+            {
+                break;
+            }
+
+            match kind {
+                CHUNK => {
+                    index.chunks.insert(chunk, (data_offset, len));
+                }
+                DELETED => {
+                    index.chunks.remove(&chunk);
+                }
+                META => {
+                    index.meta = Some(de::from_slice(&data).map_err(|err| {
+                        ProtocolError::StorageParseError(format!(
+                            "Unable to parse metadata for {}: {}",
+                            hash, err
+                        ))
+                    })?);
+                }
+                _ => {
+                    return Err(ProtocolError::StorageParseError(format!(
+                        "Unknown record kind {} in {:?}",
+                        kind, path
+                    )))
+                }
+            }
+
+            offset = data_offset + u64::from(len);
+        }
+
+        // Drop the partial record, so new records are appended after the last complete one
+        if file_len != offset {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(offset))
+                .map_err(|err| ProtocolError::StorageError {
+                    action: format!("truncate {:?}", path),
+                    err,
+                })?;
+        }
+
+        Ok(Some(index))
+    }
+
+    // Append a record to a file's log, returning the offset of its data
+    fn append(&self, hash: &str, kind: u8, chunk: u32, data: &[u8]) -> Result<u64, ProtocolError> {
+        let storage_path = self.storage_path();
+        fs::create_dir_all(&storage_path).map_err(|err| ProtocolError::StorageError {
+            action: format!("create storage directory {:?}", storage_path),
+            err,
+        })?;
+
+        let path = self.log_path(hash);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("open {:?} for writing", path),
+                err,
+            })?;
+        let offset = file
+            .seek(SeekFrom::End(0))
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("seek to end of {:?}", path),
+                err,
+            })?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+        record.push(kind);
+        record.extend_from_slice(&chunk.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+
+        file.write_all(&record)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("write to {:?}", path),
+                err,
+            })?;
+
+        Ok(offset + RECORD_HEADER_SIZE as u64)
+    }
+}
+
+// Read until the buffer is full or the end of the file is reached,
+// returning the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(size) => read += size,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+fn u32_from_be(bytes: &[u8]) -> u32 {
+    let mut raw = [0; 4];
+    raw.copy_from_slice(bytes);
+    u32::from_be_bytes(raw)
+}
+
+fn not_found(action: String) -> ProtocolError {
+    ProtocolError::StorageError {
+        action,
+        err: io::Error::from(io::ErrorKind::NotFound),
+    }
+}
+
+impl ChunkStore for LogChunkStore {
+    fn store_chunk(&self, hash: &str, index: u32, data: &[u8]) -> Result<(), ProtocolError> {
+        let mut logs = self.logs();
+        // Make sure an existing log is indexed before adding to it
+        self.index(&mut logs, hash)?;
+        let offset = self.append(hash, CHUNK, index, data)?;
+        logs.entry(hash.to_owned())
+            .or_default()
+            .chunks
+            .insert(index, (offset, data.len() as u32));
+        Ok(())
+    }
+
+    fn load_chunk(&self, hash: &str, index: u32) -> Result<Vec<u8>, ProtocolError> {
+        let mut logs = self.logs();
+        let (offset, len) = self
+            .index(&mut logs, hash)?
+            .and_then(|log| log.chunks.get(&index).cloned())
+            .ok_or_else(|| not_found(format!("open chunk {}", index)))?;
+
+        let path = self.log_path(hash);
+        let mut file = File::open(&path).map_err(|err| ProtocolError::StorageError {
+            action: format!("open {:?}", path),
+            err,
+        })?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("seek to chunk {} in {:?}", index, path),
+                err,
+            })?;
+
+        let mut data = vec![0; len as usize];
+        file.read_exact(&mut data)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("read chunk {}", index),
+                err,
+            })?;
+        Ok(data)
+    }
+
+    fn delete_chunk(&self, hash: &str, index: u32) -> Result<(), ProtocolError> {
+        let mut logs = self.logs();
+        let present = self
+            .index(&mut logs, hash)?
+            .map_or(false, |log| log.chunks.contains_key(&index));
+        if !present {
+            return Err(not_found(format!("deleting chunk {}", index)));
+        }
+
+        self.append(hash, DELETED, index, &[])?;
+        if let Some(log) = logs.get_mut(hash) {
+            log.chunks.remove(&index);
+        }
+        Ok(())
+    }
+
+    fn chunks(&self, hash: &str) -> Result<Vec<u32>, ProtocolError> {
+        let mut logs = self.logs();
+        self.index(&mut logs, hash)?
+            .map(|log| log.chunks.keys().cloned().collect())
+            .ok_or_else(|| not_found(format!("read {} chunks", hash)))
+    }
+
+    fn store_meta(&self, hash: &str, meta: &ChunkMeta) -> Result<(), ProtocolError> {
+        let mut logs = self.logs();
+        self.index(&mut logs, hash)?;
+        self.append(hash, META, 0, &to_vec(meta)?)?;
+        logs.entry(hash.to_owned()).or_default().meta = Some(meta.clone());
+        Ok(())
+    }
+
+    fn load_meta(&self, hash: &str) -> Result<ChunkMeta, ProtocolError> {
+        let mut logs = self.logs();
+        self.index(&mut logs, hash)?
+            .and_then(|log| log.meta.clone())
+            .ok_or_else(|| not_found(format!("open {} metadata", hash)))
+    }
+
+    fn delete_file(&self, hash: &str) -> Result<(), ProtocolError> {
+        let mut logs = self.logs();
+        logs.remove(hash);
+        fs::remove_file(self.log_path(hash)).map_err(|err| ProtocolError::StorageError {
+            action: format!("deleting file {}", hash),
+            err,
+        })
+    }
+
+    fn delete_storage(&self) -> Result<(), ProtocolError> {
+        let mut logs = self.logs();
+        logs.clear();
+        fs::remove_dir_all(&self.prefix).map_err(|err| ProtocolError::StorageError {
+            action: format!("deleting path {:?}", self.prefix),
+            err,
+        })
+    }
+
+    fn store_export(&self, hash: &str, path: &str) -> Result<(), ProtocolError> {
+        store_export(&self.prefix, hash, path)
+    }
+
+    fn load_export(&self, hash: &str) -> Option<String> {
+        load_export(&self.prefix, hash)
+    }
+}
@@ -0,0 +1,206 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::{ChunkMeta, ChunkStore};
+use crate::error::ProtocolError;
+use serde_cbor::{de, to_vec};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Chunk storage using one file per chunk
+///
+/// Chunks are kept in `{prefix}/storage/{hash}/{index}`, next to the file's
+/// metadata in `{prefix}/storage/{hash}/meta`. This is the default storage.
+#[derive(Clone, Debug)]
+pub struct FsChunkStore {
+    prefix: String,
+}
+
+impl FsChunkStore {
+    /// Creates a store in the given directory
+    pub fn new(prefix: &str) -> Self {
+        FsChunkStore {
+            prefix: prefix.to_owned(),
+        }
+    }
+
+    fn hash_path(&self, hash: &str) -> PathBuf {
+        Path::new(&format!("{}/storage", self.prefix)).join(hash)
+    }
+}
+
+impl ChunkStore for FsChunkStore {
+    fn store_chunk(&self, hash: &str, index: u32, data: &[u8]) -> Result<(), ProtocolError> {
+        let storage_path = self.hash_path(hash).join(format!("{}", index));
+
+        if let Some(parent) = &storage_path.parent() {
+            fs::create_dir_all(parent).map_err(|err| ProtocolError::StorageError {
+                action: format!("create storage directory {:?}", storage_path),
+                err,
+            })?;
+        }
+
+        let mut file = File::create(&storage_path).map_err(|err| ProtocolError::StorageError {
+            action: "create storage file".to_owned(),
+            err,
+        })?;
+
+        file.write_all(data)
+            .map_err(|err| ProtocolError::StorageError {
+                action: "write chunk".to_owned(),
+                err,
+            })?;
+
+        Ok(())
+    }
+
+    fn load_chunk(&self, hash: &str, index: u32) -> Result<Vec<u8>, ProtocolError> {
+        let mut data = vec![];
+        File::open(self.hash_path(hash).join(format!("{}", index)))
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("open chunk file {}", index),
+                err,
+            })?
+            .read_to_end(&mut data)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("read chunk file {}", index),
+                err,
+            })?;
+        Ok(data)
+    }
+
+    fn delete_chunk(&self, hash: &str, index: u32) -> Result<(), ProtocolError> {
+        fs::remove_file(self.hash_path(hash).join(format!("{}", index))).map_err(|err| {
+            ProtocolError::StorageError {
+                action: format!("deleting chunk file {}", index),
+                err,
+            }
+        })
+    }
+
+    fn chunks(&self, hash: &str) -> Result<Vec<u32>, ProtocolError> {
+        let hash_path = self.hash_path(hash);
+        let entries = fs::read_dir(&hash_path).map_err(|err| ProtocolError::StorageError {
+            action: format!("read {:?} directory", hash_path),
+            err,
+        })?;
+
+        // Anything which isn't a chunk (ie. the metadata) is skipped
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter_map(|name| name.parse::<u32>().ok())
+            .collect())
+    }
+
+    fn store_meta(&self, hash: &str, meta: &ChunkMeta) -> Result<(), ProtocolError> {
+        let vec = to_vec(meta)?;
+
+        let file_dir = self.hash_path(hash);
+        // Make sure the directory exists
+        fs::create_dir_all(file_dir.clone()).map_err(|err| ProtocolError::StorageError {
+            action: "create temp storage directory".to_owned(),
+            err,
+        })?;
+
+        let meta_path = file_dir.join("meta");
+        let temp_path = file_dir.join(".meta.tmp");
+
+        File::create(&temp_path)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("create/open {:?} for writing", temp_path),
+                err,
+            })?
+            .write_all(&vec)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("write metadata to {:?}", temp_path),
+                err,
+            })?;
+
+        fs::rename(temp_path.clone(), meta_path.clone()).map_err(|err| {
+            ProtocolError::StorageError {
+                action: format!("rename {:?} to {:?}", temp_path, meta_path),
+                err,
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn load_meta(&self, hash: &str) -> Result<ChunkMeta, ProtocolError> {
+        let mut data = vec![];
+        File::open(self.hash_path(hash).join("meta"))
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("open {} metadata file", hash),
+                err,
+            })?
+            .read_to_end(&mut data)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("read {} metadata file", hash),
+                err,
+            })?;
+
+        de::from_slice(&data).map_err(|err| {
+            ProtocolError::StorageParseError(format!(
+                "Unable to parse metadata for {}: {}",
+                hash, err
+            ))
+        })
+    }
+
+    fn delete_file(&self, hash: &str) -> Result<(), ProtocolError> {
+        fs::remove_dir_all(self.hash_path(hash)).map_err(|err| ProtocolError::StorageError {
+            action: format!("deleting file {}", hash),
+            err,
+        })
+    }
+
+    fn delete_storage(&self) -> Result<(), ProtocolError> {
+        fs::remove_dir_all(&self.prefix).map_err(|err| ProtocolError::StorageError {
+            action: format!("deleting path {:?}", self.prefix),
+            err,
+        })
+    }
+
+    fn store_export(&self, hash: &str, path: &str) -> Result<(), ProtocolError> {
+        store_export(&self.prefix, hash, path)
+    }
+
+    fn load_export(&self, hash: &str) -> Option<String> {
+        load_export(&self.prefix, hash)
+    }
+}
+
+// Record the location a file was exported to, in `{prefix}/exports/{hash}`
+pub(super) fn store_export(prefix: &str, hash: &str, path: &str) -> Result<(), ProtocolError> {
+    let exports_path = Path::new(&format!("{}/exports", prefix)).to_owned();
+
+    fs::create_dir_all(&exports_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("create exports directory {:?}", exports_path),
+        err,
+    })?;
+
+    fs::write(exports_path.join(hash), path.as_bytes()).map_err(|err| ProtocolError::StorageError {
+        action: format!("write export record for {}", hash),
+        err,
+    })
+}
+
+// Look up the location a file was exported to
+pub(super) fn load_export(prefix: &str, hash: &str) -> Option<String> {
+    fs::read_to_string(Path::new(&format!("{}/exports", prefix)).join(hash)).ok()
+}
@@ -0,0 +1,143 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::{ChunkMeta, ChunkStore};
+use crate::error::ProtocolError;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Default)]
+struct MemoryFile {
+    meta: Option<ChunkMeta>,
+    chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Contents {
+    files: HashMap<String, MemoryFile>,
+    exports: HashMap<String, String>,
+}
+
+/// Chunk storage held entirely in memory
+///
+/// Useful for tests, and for systems without any writable temporary space.
+/// Partially transferred files are lost if the process exits, so transfers
+/// can't be resumed after a restart.
+#[derive(Default)]
+pub struct MemoryChunkStore {
+    contents: Mutex<Contents>,
+}
+
+impl MemoryChunkStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        MemoryChunkStore::default()
+    }
+
+    fn contents(&self) -> MutexGuard<'_, Contents> {
+        // The contents are always left consistent, so a panic elsewhere doesn't matter
+        self.contents
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn not_found(action: String) -> ProtocolError {
+    ProtocolError::StorageError {
+        action,
+        err: io::Error::from(io::ErrorKind::NotFound),
+    }
+}
+
+impl ChunkStore for MemoryChunkStore {
+    fn store_chunk(&self, hash: &str, index: u32, data: &[u8]) -> Result<(), ProtocolError> {
+        self.contents()
+            .files
+            .entry(hash.to_owned())
+            .or_default()
+            .chunks
+            .insert(index, data.to_vec());
+        Ok(())
+    }
+
+    fn load_chunk(&self, hash: &str, index: u32) -> Result<Vec<u8>, ProtocolError> {
+        self.contents()
+            .files
+            .get(hash)
+            .and_then(|file| file.chunks.get(&index))
+            .cloned()
+            .ok_or_else(|| not_found(format!("open chunk {}", index)))
+    }
+
+    fn delete_chunk(&self, hash: &str, index: u32) -> Result<(), ProtocolError> {
+        self.contents()
+            .files
+            .get_mut(hash)
+            .and_then(|file| file.chunks.remove(&index))
+            .map(|_| ())
+            .ok_or_else(|| not_found(format!("deleting chunk {}", index)))
+    }
+
+    fn chunks(&self, hash: &str) -> Result<Vec<u32>, ProtocolError> {
+        self.contents()
+            .files
+            .get(hash)
+            .map(|file| file.chunks.keys().cloned().collect())
+            .ok_or_else(|| not_found(format!("read {} chunks", hash)))
+    }
+
+    fn store_meta(&self, hash: &str, meta: &ChunkMeta) -> Result<(), ProtocolError> {
+        self.contents()
+            .files
+            .entry(hash.to_owned())
+            .or_default()
+            .meta = Some(meta.clone());
+        Ok(())
+    }
+
+    fn load_meta(&self, hash: &str) -> Result<ChunkMeta, ProtocolError> {
+        self.contents()
+            .files
+            .get(hash)
+            .and_then(|file| file.meta.clone())
+            .ok_or_else(|| not_found(format!("open {} metadata", hash)))
+    }
+
+    fn delete_file(&self, hash: &str) -> Result<(), ProtocolError> {
+        self.contents()
+            .files
+            .remove(hash)
+            .map(|_| ())
+            .ok_or_else(|| not_found(format!("deleting file {}", hash)))
+    }
+
+    fn delete_storage(&self) -> Result<(), ProtocolError> {
+        *self.contents() = Contents::default();
+        Ok(())
+    }
+
+    fn store_export(&self, hash: &str, path: &str) -> Result<(), ProtocolError> {
+        self.contents()
+            .exports
+            .insert(hash.to_owned(), path.to_owned());
+        Ok(())
+    }
+
+    fn load_export(&self, hash: &str) -> Option<String> {
+        self.contents().exports.get(hash).cloned()
+    }
+}
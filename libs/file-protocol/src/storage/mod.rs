@@ -0,0 +1,824 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Temporary storage of the chunks of files being transferred
+//!
+//! The chunks (and metadata) of in-progress transfers are kept in a [`ChunkStore`].
+//! Three are provided:
+//!
+//! - [`FsChunkStore`] - One file per chunk. This is the default
+//! - [`LogChunkStore`] - One append-only log file per transfer, suited to flash storage
+//! - [`MemoryChunkStore`] - Kept in memory, for tests and systems without writable temp space
+//!
+//! [`ChunkStore`]: trait.ChunkStore.html
+//! [`FsChunkStore`]: struct.FsChunkStore.html
+//! [`LogChunkStore`]: struct.LogChunkStore.html
+//! [`MemoryChunkStore`]: struct.MemoryChunkStore.html
+
+mod append_log;
+mod filesystem;
+mod memory;
+
+pub use self::append_log::LogChunkStore;
+pub use self::filesystem::FsChunkStore;
+pub use self::memory::MemoryChunkStore;
+
+use crate::error::ProtocolError;
+use blake2_rfc::blake2s::Blake2s;
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+const HASH_SIZE: usize = 16;
+// Mode sent with a file when the source's mode can't be read
+const DEFAULT_MODE: u32 = 0o644;
+// Mode sent with a read-only file by targets without unix modes
+#[cfg(not(unix))]
+const READ_ONLY_MODE: u32 = 0o444;
+
+/// Metadata about a file being transferred
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChunkMeta {
+    /// Number of chunks in the file
+    pub num_chunks: u32,
+    /// When sending a file, the size of each chunk read from `file_path`
+    pub chunk_size: Option<u64>,
+    /// When sending a file, the file which chunks are read from
+    pub file_path: Option<String>,
+    /// When sending part of a file, the byte offset and length of the part within `file_path`
+    #[serde(default)]
+    pub range: Option<(u64, u64)>,
+}
+
+/// Storage for the chunks of files which are being transferred
+///
+/// Chunks and metadata are grouped by the hash of the file they belong to.
+/// A store is shared by all of the transfers using a protocol configuration,
+/// so implementations must be safe to use from several threads at once.
+pub trait ChunkStore: Send + Sync {
+    /// Save a chunk of a file, replacing any existing copy
+    fn store_chunk(&self, hash: &str, index: u32, data: &[u8]) -> Result<(), ProtocolError>;
+    /// Load a previously saved chunk
+    fn load_chunk(&self, hash: &str, index: u32) -> Result<Vec<u8>, ProtocolError>;
+    /// Remove a single chunk
+    fn delete_chunk(&self, hash: &str, index: u32) -> Result<(), ProtocolError>;
+    /// Indices of the chunks currently saved for a file, in any order
+    fn chunks(&self, hash: &str) -> Result<Vec<u32>, ProtocolError>;
+    /// Save a file's metadata, replacing any existing copy
+    fn store_meta(&self, hash: &str, meta: &ChunkMeta) -> Result<(), ProtocolError>;
+    /// Load a file's metadata
+    fn load_meta(&self, hash: &str) -> Result<ChunkMeta, ProtocolError>;
+    /// Remove all of a file's chunks and metadata
+    fn delete_file(&self, hash: &str) -> Result<(), ProtocolError>;
+    /// Remove everything in the store
+    fn delete_storage(&self) -> Result<(), ProtocolError>;
+    /// Record where a file was exported to, so a later upload of it can be skipped.
+    /// Stores which can't keep the record may ignore it.
+    fn store_export(&self, _hash: &str, _path: &str) -> Result<(), ProtocolError> {
+        Ok(())
+    }
+    /// Where a file was last exported to, if known
+    fn load_export(&self, _hash: &str) -> Option<String> {
+        None
+    }
+}
+
+pub fn store_meta(
+    store: &dyn ChunkStore,
+    hash: &str,
+    num_chunks: u32,
+    chunk_size: Option<u64>,
+    file_path: Option<&str>,
+) -> Result<(), ProtocolError> {
+    store.store_meta(
+        hash,
+        &ChunkMeta {
+            num_chunks,
+            chunk_size,
+            file_path: file_path.map(|f| f.to_owned()),
+            range: None,
+        },
+    )
+}
+
+// Load a chunk, either from the file being sent or from the store
+pub fn load_chunk(
+    store: &dyn ChunkStore,
+    hash: &str,
+    index: u32,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut data = vec![];
+    if let ChunkMeta {
+        chunk_size: Some(chunk_size),
+        file_path: Some(path),
+        range,
+        ..
+    } = store.load_meta(hash)?
+    {
+        let mut file = File::open(&path).map_err(|err| ProtocolError::StorageError {
+            action: format!("open chunk file {}", index),
+            err,
+        })?;
+
+        // Chunks of a partial file mustn't run past the end of the requested range
+        let start = chunk_size * index as u64;
+        let (offset, size) = match range {
+            Some((offset, length)) => (offset, chunk_size.min(length.saturating_sub(start))),
+            None => (0, chunk_size),
+        };
+
+        file.seek(SeekFrom::Start(offset + start))
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("seek to chunk in file {}", &path),
+                err,
+            })?;
+
+        file.take(size)
+            .read_to_end(&mut data)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("read chunk file {}", index),
+                err,
+            })?;
+    } else {
+        data = store.load_chunk(hash, index)?;
+    }
+    Ok(data)
+}
+
+// Check if all of a files chunks are present in the store
+pub fn validate_file(
+    store: &dyn ChunkStore,
+    hash: &str,
+    num_chunks: Option<u32>,
+) -> Result<(bool, Vec<u32>), ProtocolError> {
+    let num_chunks = if let Some(num) = num_chunks {
+        store_meta(store, hash, num, None, None)?;
+        num
+    } else {
+        store.load_meta(hash)?.num_chunks
+    };
+
+    let mut missing_ranges: Vec<u32> = vec![];
+
+    let mut prev_entry: i32 = -1;
+
+    let mut converted_entries: Vec<i32> = store
+        .chunks(hash)?
+        .into_iter()
+        .map(|num| num as i32)
+        .collect();
+
+    converted_entries.sort();
+
+    let mut max_entries = 186;
+    for &entry_num in converted_entries.iter() {
+        //println!("checking {} vs {}", entry_num, prev_entry);
+        // Check for non-sequential dir entries to detect missing chunk ranges
+        if entry_num - prev_entry > 1 {
+            // Add start of range (inclusive)
+            missing_ranges.push((prev_entry + 1) as u32);
+            // Add end of range (non-inclusive)
+            missing_ranges.push(entry_num as u32);
+
+            max_entries -= 1;
+
+            if max_entries == 0 {
+                break;
+            }
+        }
+
+        prev_entry = entry_num;
+    }
+
+    // Check for a trailing range
+    // Ex. Last known chunk is 5, but there are 10 chunks.
+    //     We will already have added '6', so we need to add '10'
+    //     to close it out.
+    if max_entries != 0 && (num_chunks as i32) - prev_entry != 1 {
+        // Add start of range
+        missing_ranges.push((prev_entry + 1) as u32);
+        // Add end of range
+        missing_ranges.push(num_chunks as u32);
+    }
+
+    Ok((missing_ranges.is_empty(), missing_ranges))
+}
+
+/// Create temporary folder for chunks
+/// Stream copy file from mutable space to immutable space
+/// Move folder to hash of contents
+/// Import file into chunked storage for transfer
+///
+/// Only the part of the file starting at `offset` (and at most `length` bytes long, if given)
+/// is transferred. The hash covers just that part, so the receiver ends up with a file
+/// holding only the requested bytes.
+pub fn initialize_file(
+    store: &dyn ChunkStore,
+    source_path: &str,
+    offset: u64,
+    length: Option<u64>,
+    transfer_chunk_size: usize,
+    hash_chunk_size: usize,
+) -> Result<(String, u32, u32), ProtocolError> {
+    // Confirm file exists
+    let metadata = fs::metadata(source_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat file {}", source_path),
+        err,
+    })?;
+
+    let file_size = metadata.len() as u64;
+    if offset > file_size {
+        return Err(ProtocolError::InvalidRange { offset, file_size });
+    }
+
+    // A range running past the end of the file just gets whatever is there
+    let remaining = file_size - offset;
+    let size = length.map_or(remaining, |length| length.min(remaining));
+    let range = if offset == 0 && size == file_size {
+        None
+    } else {
+        Some((offset, size))
+    };
+
+    // Calculate hash of temp file
+    let hash = calc_range_hash(&source_path, offset, size, hash_chunk_size)?;
+
+    let index = (size / transfer_chunk_size as u64) as u32
+        + ((size % transfer_chunk_size as u64) > 0) as u32;
+
+    store.store_meta(
+        &hash,
+        &ChunkMeta {
+            num_chunks: index,
+            chunk_size: Some(transfer_chunk_size as u64),
+            file_path: Some(source_path.to_owned()),
+            range,
+        },
+    )?;
+
+    if let Ok(meta) = fs::metadata(source_path) {
+        Ok((hash, index, file_mode(&meta)))
+    } else {
+        Ok((hash, index, DEFAULT_MODE))
+    }
+}
+
+// Unix mode of a file
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    meta.mode()
+}
+
+// Other targets (eg. Windows ground stations) only have a read-only flag, so send whichever
+// standard mode matches it
+#[cfg(not(unix))]
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    if meta.permissions().readonly() {
+        READ_ONLY_MODE
+    } else {
+        DEFAULT_MODE
+    }
+}
+
+// Set the unix mode of a file
+#[cfg(unix)]
+fn set_file_mode(path: &str, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+// Other targets only have a read-only flag, which is set if the mode has no write bits
+#[cfg(not(unix))]
+fn set_file_mode(path: &str, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+// Export received chunks into final file and verify correct file hash
+pub fn finalize_file(
+    store: &dyn ChunkStore,
+    hash: &str,
+    target_path: &str,
+    mode: Option<u32>,
+    hash_chunk_size: usize,
+) -> Result<(), ProtocolError> {
+    // Double check that all the chunks of the file are present
+    let (result, _) = validate_file(store, hash, None)?;
+
+    if !result {
+        return Err(ProtocolError::FinalizeError {
+            cause: "file missing chunks".to_owned(),
+        });
+    }
+
+    // Get the total number of chunks we're saving
+    let num_chunks = store.load_meta(hash)?.num_chunks;
+
+    // Q: Do we want to create the parent directories if they don't exist?
+    let mut file = File::create(target_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("create/open file for writing {}", target_path),
+        err,
+    })?;
+
+    // Set exported file's mode
+    if let Some(mode_val) = mode {
+        set_file_mode(target_path, mode_val).map_err(|err| ProtocolError::StorageError {
+            action: "set target file's mode".to_owned(),
+            err,
+        })?;
+    }
+
+    // Iterate through chunks and reassemble file
+    let mut load_chunk_err = None;
+    for chunk_num in 0..num_chunks {
+        let chunk = match load_chunk(store, hash, chunk_num) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(
+                    "Error encountered loading chunk {}, deleting : {}",
+                    chunk_num, e
+                );
+                store.delete_chunk(hash, chunk_num)?;
+                load_chunk_err = Some(e);
+                continue;
+            }
+        };
+
+        // Write the chunk to the destination file
+        file.write_all(&chunk)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("write chunk {}", chunk_num),
+                err,
+            })?;
+    }
+
+    if let Some(e) = load_chunk_err {
+        return Err(e);
+    }
+
+    // Calculate hash of exported file
+    let calc_hash_str = calc_file_hash(&target_path, hash_chunk_size)?;
+
+    // Final determination if file was correctly received and assembled
+    if calc_hash_str == hash {
+        // Remember where this file ended up so a future upload of it can be skipped
+        if let Err(e) = record_export(store, hash, target_path) {
+            warn!(
+                "Failed to record export of {} to {}: {}",
+                hash, target_path, e
+            );
+        }
+        Ok(())
+    } else {
+        // If the hash doesn't match then we start over
+        store.delete_file(&hash)?;
+        Err(ProtocolError::HashMismatch)
+    }
+}
+
+/// Calculate the hash of the first `offset` bytes of a file, which the receiver of an append
+/// checks its own copy of the file against
+pub fn prefix_hash(
+    path: &str,
+    offset: u64,
+    hash_chunk_size: usize,
+) -> Result<String, ProtocolError> {
+    let file_size = fs::metadata(path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("stat file {}", path),
+            err,
+        })?
+        .len();
+    if offset > file_size {
+        return Err(ProtocolError::InvalidRange { offset, file_size });
+    }
+
+    calc_range_hash(path, 0, offset, hash_chunk_size)
+}
+
+// Check that an existing file is at least `offset` bytes long and that those bytes have the
+// given hash, so the rest of the file can be appended to it
+pub fn check_prefix(
+    path: &str,
+    offset: u64,
+    prefix_hash: &str,
+    hash_chunk_size: usize,
+) -> Result<(), ProtocolError> {
+    let mismatch = |cause: String| ProtocolError::AppendMismatch {
+        path: path.to_owned(),
+        cause,
+    };
+
+    let file_size = fs::metadata(path)
+        .map_err(|err| mismatch(format!("unable to stat file: {}", err)))?
+        .len();
+    if file_size < offset {
+        return Err(mismatch(format!(
+            "file is {} bytes long, short of the {} bytes expected",
+            file_size, offset
+        )));
+    }
+
+    if calc_range_hash(path, 0, offset, hash_chunk_size)? != prefix_hash {
+        return Err(mismatch(format!("first {} bytes don't match", offset)));
+    }
+
+    Ok(())
+}
+
+// Append received chunks to an existing file, replacing anything past its first `offset`
+// bytes. The chunks are checked against the hash before the file is touched, and the file's
+// prefix is checked again in case it changed while the chunks were arriving
+pub fn finalize_append(
+    store: &dyn ChunkStore,
+    hash: &str,
+    target_path: &str,
+    offset: u64,
+    prefix_hash: &str,
+    hash_chunk_size: usize,
+) -> Result<(), ProtocolError> {
+    // Double check that all the chunks of the file are present
+    let (result, _) = validate_file(store, hash, None)?;
+
+    if !result {
+        return Err(ProtocolError::FinalizeError {
+            cause: "file missing chunks".to_owned(),
+        });
+    }
+
+    let num_chunks = store.load_meta(hash)?.num_chunks;
+
+    let mut hasher = Blake2s::new(HASH_SIZE);
+    for chunk_num in 0..num_chunks {
+        hasher.update(&load_chunk(store, hash, chunk_num)?);
+    }
+    if hex_digest(hasher) != hash {
+        // If the hash doesn't match then we start over
+        store.delete_file(hash)?;
+        return Err(ProtocolError::HashMismatch);
+    }
+
+    check_prefix(target_path, offset, prefix_hash, hash_chunk_size)?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(target_path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("open file for appending {}", target_path),
+            err,
+        })?;
+
+    // Anything already past the offset is replaced by the new bytes
+    file.set_len(offset)
+        .and_then(|_| file.seek(SeekFrom::End(0)))
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("truncate file {} to {} bytes", target_path, offset),
+            err,
+        })?;
+
+    for chunk_num in 0..num_chunks {
+        let chunk = load_chunk(store, hash, chunk_num)?;
+        file.write_all(&chunk)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("write chunk {}", chunk_num),
+                err,
+            })?;
+    }
+
+    if calc_range_hash(target_path, offset, u64::max_value(), hash_chunk_size)? == hash {
+        Ok(())
+    } else {
+        // Put the file back the way it was rather than leave it half appended
+        let _ = file.set_len(offset);
+        Err(ProtocolError::HashMismatch)
+    }
+}
+
+// Record the location a file was exported to
+fn record_export(
+    store: &dyn ChunkStore,
+    hash: &str,
+    target_path: &str,
+) -> Result<(), ProtocolError> {
+    // Store the absolute path, since the working directory may differ between transfers
+    let target_path = fs::canonicalize(target_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("resolve path {}", target_path),
+        err,
+    })?;
+
+    store.store_export(hash, &target_path.to_string_lossy())
+}
+
+// Check whether a file with the given hash is already present on this side, either at the
+// target path or wherever it was previously exported to. If it is present somewhere else,
+// it is copied into place.
+//
+// Returns the path the existing copy was found at, or `None` if the file needs to be
+// transferred
+pub fn export_existing(
+    store: &dyn ChunkStore,
+    hash: &str,
+    target_path: &str,
+    mode: Option<u32>,
+    hash_chunk_size: usize,
+) -> Result<Option<String>, ProtocolError> {
+    let matches = |path: &str| match calc_file_hash(path, hash_chunk_size) {
+        Ok(file_hash) => file_hash == hash,
+        Err(_) => false,
+    };
+
+    if Path::new(target_path).is_file() && matches(target_path) {
+        return Ok(Some(target_path.to_owned()));
+    }
+
+    let existing = match store.load_export(hash) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    if !Path::new(&existing).is_file() || !matches(&existing) {
+        return Ok(None);
+    }
+
+    fs::copy(&existing, target_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("copy {} to {}", existing, target_path),
+        err,
+    })?;
+
+    if let Some(mode_val) = mode {
+        set_file_mode(target_path, mode_val).map_err(|err| ProtocolError::StorageError {
+            action: "set target file's mode".to_owned(),
+            err,
+        })?;
+    }
+
+    record_export(store, hash, target_path)?;
+
+    Ok(Some(existing))
+}
+
+/// Calculate the blake2s hash for a file at given path
+fn calc_file_hash(path: &str, hash_chunk_size: usize) -> Result<String, ProtocolError> {
+    calc_range_hash(path, 0, u64::max_value(), hash_chunk_size)
+}
+
+/// Calculate the blake2s hash of `length` bytes of a file, starting at `offset`
+fn calc_range_hash(
+    path: &str,
+    offset: u64,
+    length: u64,
+    hash_chunk_size: usize,
+) -> Result<String, ProtocolError> {
+    let mut hasher = Blake2s::new(HASH_SIZE);
+    let mut input = File::open(&path).map_err(|err| ProtocolError::StorageError {
+        action: format!("open {:?}", path),
+        err,
+    })?;
+    input
+        .seek(SeekFrom::Start(offset))
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("seek to {} in {:?}", offset, path),
+            err,
+        })?;
+    let mut reader = BufReader::with_capacity(hash_chunk_size * 8, input.take(length));
+
+    // Need to bring in blake2fs here to create hash
+    loop {
+        let length = {
+            let chunk = reader
+                .fill_buf()
+                .map_err(|err| ProtocolError::StorageError {
+                    action: "read chunk from source".to_owned(),
+                    err,
+                })?;
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.update(&chunk);
+            chunk.len()
+        };
+        reader.consume(length);
+        // thread::sleep(Duration::from_millis(2));
+    }
+
+    Ok(hex_digest(hasher))
+}
+
+// Finish a hash, as the hex string used to identify files
+fn hex_digest(hasher: Blake2s) -> String {
+    hasher
+        .finalize()
+        .as_bytes()
+        .iter()
+        .map(|val| format!("{:02x}", val))
+        .collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("file-protocol-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn check_store(store: &dyn ChunkStore) {
+        let hash = "abcdef";
+        assert!(store.load_meta(hash).is_err());
+
+        store_meta(store, hash, 4, None, None).unwrap();
+        store.store_chunk(hash, 0, &[0, 1]).unwrap();
+        store.store_chunk(hash, 3, &[6, 7]).unwrap();
+        store.store_chunk(hash, 3, &[6, 8]).unwrap();
+
+        assert_eq!(4, store.load_meta(hash).unwrap().num_chunks);
+        assert_eq!(vec![6, 8], load_chunk(store, hash, 3).unwrap());
+        assert_eq!(
+            (false, vec![1, 3]),
+            validate_file(store, hash, None).unwrap()
+        );
+
+        store.delete_chunk(hash, 3).unwrap();
+        assert!(store.load_chunk(hash, 3).is_err());
+        assert_eq!(vec![0], store.chunks(hash).unwrap());
+
+        store.store_export(hash, "/home/kubos/file").unwrap();
+        assert_eq!(Some("/home/kubos/file".to_owned()), store.load_export(hash));
+
+        store.delete_file(hash).unwrap();
+        assert!(store.load_meta(hash).is_err());
+        assert!(store.chunks(hash).is_err());
+    }
+
+    #[test]
+    fn fs_store() {
+        let dir = test_dir("fs");
+        check_store(&FsChunkStore::new(&dir));
+        FsChunkStore::new(&dir).delete_storage().unwrap();
+    }
+
+    #[test]
+    fn log_store() {
+        let dir = test_dir("log");
+        check_store(&LogChunkStore::new(&dir));
+        LogChunkStore::new(&dir).delete_storage().unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&MemoryChunkStore::new());
+    }
+
+    #[test]
+    fn initialize_file_range() {
+        let dir = test_dir("range");
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/source", dir);
+        let contents: Vec<u8> = (0..100).collect();
+        fs::write(&path, &contents).unwrap();
+
+        let store = MemoryChunkStore::new();
+        let (hash, num_chunks, _) = initialize_file(&store, &path, 90, Some(20), 4, 8).unwrap();
+        assert_eq!(3, num_chunks);
+        assert_eq!(vec![90, 91, 92, 93], load_chunk(&store, &hash, 0).unwrap());
+        assert_eq!(vec![98, 99], load_chunk(&store, &hash, 2).unwrap());
+
+        let (hash, num_chunks, _) = initialize_file(&store, &path, 10, Some(6), 4, 8).unwrap();
+        assert_eq!(2, num_chunks);
+        assert_eq!(vec![14, 15], load_chunk(&store, &hash, 1).unwrap());
+
+        // A range covering the whole file is the same as a normal transfer
+        let (whole, _, _) = initialize_file(&store, &path, 0, None, 4, 8).unwrap();
+        assert_eq!(whole, calc_file_hash(&path, 8).unwrap());
+        assert_eq!(None, store.load_meta(&whole).unwrap().range);
+
+        match initialize_file(&store, &path, 101, None, 4, 8) {
+            Err(ProtocolError::InvalidRange { offset, file_size }) => {
+                assert_eq!((101, 100), (offset, file_size))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_file() {
+        let dir = test_dir("append");
+        fs::create_dir_all(&dir).unwrap();
+        let source = format!("{}/source", dir);
+        let target = format!("{}/target", dir);
+        let contents: Vec<u8> = (0..100).collect();
+        fs::write(&source, &contents).unwrap();
+        // The receiver has the first 60 bytes, plus a partial line which gets replaced
+        let mut existing = contents[0..60].to_vec();
+        existing.extend_from_slice(&[0xff; 5]);
+        fs::write(&target, &existing).unwrap();
+
+        let sender = MemoryChunkStore::new();
+        let (hash, num_chunks, _) = initialize_file(&sender, &source, 60, None, 16, 8).unwrap();
+        let prefix = prefix_hash(&source, 60, 8).unwrap();
+        assert_eq!(3, num_chunks);
+
+        check_prefix(&target, 60, &prefix, 8).unwrap();
+        match check_prefix(&target, 80, &prefix, 8) {
+            Err(ProtocolError::AppendMismatch { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match check_prefix(&target, 50, &prefix, 8) {
+            Err(ProtocolError::AppendMismatch { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let receiver = MemoryChunkStore::new();
+        store_meta(&receiver, &hash, num_chunks, None, None).unwrap();
+        for index in 0..num_chunks {
+            let chunk = load_chunk(&sender, &hash, index).unwrap();
+            receiver.store_chunk(&hash, index, &chunk).unwrap();
+        }
+
+        // Nothing is written if the copy has changed since the request
+        fs::write(&target, &contents[1..61]).unwrap();
+        match finalize_append(&receiver, &hash, &target, 60, &prefix, 8) {
+            Err(ProtocolError::AppendMismatch { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(contents[1..61].to_vec(), fs::read(&target).unwrap());
+
+        fs::write(&target, &existing).unwrap();
+        finalize_append(&receiver, &hash, &target, 60, &prefix, 8).unwrap();
+        assert_eq!(contents, fs::read(&target).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_mode_portable() {
+        let dir = test_dir("mode");
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/file", dir);
+        fs::write(&path, b"mode").unwrap();
+
+        set_file_mode(&path, 0o444).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert!(meta.permissions().readonly());
+        assert_eq!(0o444, file_mode(&meta) & 0o777);
+
+        set_file_mode(&path, DEFAULT_MODE).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert!(!meta.permissions().readonly());
+        assert_eq!(DEFAULT_MODE, file_mode(&meta) & 0o777);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_store_reopen_truncated() {
+        let dir = test_dir("log-reopen");
+        let store = LogChunkStore::new(&dir);
+        store_meta(&store, "abc", 2, None, None).unwrap();
+        store.store_chunk("abc", 0, &[1, 2, 3]).unwrap();
+        store.store_chunk("abc", 1, &[4, 5, 6]).unwrap();
+
+        // Lose the end of the last record, as if the system reset while writing it
+        let path = format!("{}/storage/abc.log", dir);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let store = LogChunkStore::new(&dir);
+        assert_eq!(2, store.load_meta("abc").unwrap().num_chunks);
+        assert_eq!(vec![0], store.chunks("abc").unwrap());
+        assert_eq!(vec![1, 2, 3], store.load_chunk("abc", 0).unwrap());
+
+        // New records go after the last complete one
+        store.store_chunk("abc", 1, &[7]).unwrap();
+        let store = LogChunkStore::new(&dir);
+        assert_eq!(vec![7], store.load_chunk("abc", 1).unwrap());
+
+        store.delete_storage().unwrap();
+    }
+}
@@ -0,0 +1,446 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Transfer log
+//!
+//! An audit trail of the transfers handled by a file service. Each finished import, export
+//! or cleanup request is appended to a log file as a CBOR record. Only the newest records
+//! are kept: once the file holds twice as many records as the log's capacity it's rewritten
+//! with just the newest ones, so most transfers only need to append to it.
+
+use super::{parsers, Message};
+use crate::error::ProtocolError;
+use serde::{Deserialize, Serialize};
+use serde_cbor::{Deserializer, Value};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Kind of request which started a transfer
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferOperation {
+    /// A client downloaded a file
+    Import,
+    /// A client uploaded a file
+    Export,
+    /// A client cleaned up temporary storage
+    Cleanup,
+}
+
+impl TransferOperation {
+    /// Name used for the operation in messages
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransferOperation::Import => "import",
+            TransferOperation::Export => "export",
+            TransferOperation::Cleanup => "cleanup",
+        }
+    }
+
+    /// Operation with the given name, if there is one
+    pub fn from_name(name: &str) -> Option<TransferOperation> {
+        match name {
+            "import" => Some(TransferOperation::Import),
+            "export" => Some(TransferOperation::Export),
+            "cleanup" => Some(TransferOperation::Cleanup),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TransferOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A finished transfer
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TransferRecord {
+    /// Unix time (seconds) at which the transfer started
+    pub time: u64,
+    /// Address of the client which requested the transfer
+    pub peer: String,
+    /// Channel ID the transfer used
+    pub channel_id: u32,
+    /// Kind of transfer
+    pub operation: TransferOperation,
+    /// Path of the file transferred. For cleanups, the hash of the file whose storage
+    /// was cleaned up, or empty if all of the storage was cleaned up
+    pub path: String,
+    /// Why the transfer failed, or `None` if it succeeded
+    pub error: Option<String>,
+    /// How long the transfer took, in milliseconds
+    pub duration_ms: u64,
+    /// Number of bytes of file data transferred
+    pub bytes: u64,
+}
+
+/// Persistent log of the newest transfers handled by a file service
+pub struct TransferLog {
+    path: PathBuf,
+    capacity: usize,
+    // Number of records in the log file, including ones which are no longer kept
+    records: Mutex<usize>,
+}
+
+impl TransferLog {
+    /// Opens the log at the given path, creating it if needed
+    ///
+    /// # Arguments
+    ///
+    /// * path - Path of log file
+    /// * capacity - Number of records to keep
+    pub fn open(path: &str, capacity: usize) -> Result<TransferLog, ProtocolError> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| ProtocolError::StorageError {
+                action: format!("create {}", dir.display()),
+                err,
+            })?;
+        }
+
+        let log = TransferLog {
+            path,
+            capacity: capacity.max(1),
+            records: Mutex::new(0),
+        };
+
+        // Start from a clean file, in case the last record was only partly written
+        let records = log.read_records()?;
+        *log.records() = log.rewrite(&records)?;
+
+        Ok(log)
+    }
+
+    /// Adds a finished transfer to the log
+    pub fn record(&self, record: &TransferRecord) -> Result<(), ProtocolError> {
+        let mut records = self.records();
+        let data = serde_cbor::to_vec(record)?;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&data))
+            .map_err(|err| ProtocolError::StorageError {
+                action: "append to transfer log".to_owned(),
+                err,
+            })?;
+        *records += 1;
+
+        if *records >= self.capacity * 2 {
+            let kept = self.read_records()?;
+            *records = self.rewrite(&kept)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetches records from the log, newest first
+    ///
+    /// Returns the number of records in the log along with the requested records
+    ///
+    /// # Arguments
+    ///
+    /// * skip - Number of newest records to skip
+    /// * count - Maximum number of records to return
+    pub fn recent(
+        &self,
+        skip: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<TransferRecord>), ProtocolError> {
+        let _records = self.records();
+        let records = self.read_records()?;
+        let total = records.len();
+
+        Ok((
+            total,
+            records.into_iter().rev().skip(skip).take(count).collect(),
+        ))
+    }
+
+    fn records(&self) -> MutexGuard<'_, usize> {
+        // The count is only used to decide when to rewrite the file, so a panic
+        // elsewhere doesn't matter
+        self.records
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Reads the newest records in the log file, oldest first.
+    // Reading stops at the first record which can't be parsed.
+    fn read_records(&self) -> Result<Vec<TransferRecord>, ProtocolError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => {
+                return Err(ProtocolError::StorageError {
+                    action: "read transfer log".to_owned(),
+                    err,
+                })
+            }
+        };
+
+        let mut records = vec![];
+        for record in Deserializer::from_slice(&data).into_iter::<TransferRecord>() {
+            match record {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+        }
+
+        let excess = records.len().saturating_sub(self.capacity);
+        records.drain(..excess);
+        Ok(records)
+    }
+
+    // Replaces the log file with the given records, returning how many were written
+    fn rewrite(&self, records: &[TransferRecord]) -> Result<usize, ProtocolError> {
+        let mut data = vec![];
+        for record in records {
+            data.extend(serde_cbor::to_vec(record)?);
+        }
+
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, &data)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|err| ProtocolError::StorageError {
+                action: "rewrite transfer log".to_owned(),
+                err,
+            })?;
+
+        Ok(records.len())
+    }
+}
+
+/// Follows the messages of a single transaction to build its transfer log record
+pub struct TransferTracker {
+    peer: String,
+    channel_id: u32,
+    time: u64,
+    started: Instant,
+    // Operation, path, offset and length of the transfer's request
+    request: Option<(TransferOperation, String, u64, Option<u64>)>,
+    // Hash of the file transferred, once it's known
+    hash: Option<String>,
+    // Whether the client acknowledged receiving the whole file
+    acked: bool,
+}
+
+impl TransferTracker {
+    /// Starts tracking a transaction
+    ///
+    /// # Arguments
+    ///
+    /// * peer - Address of the client which started the transaction
+    /// * channel_id - Channel ID of the transaction
+    pub fn new(peer: &str, channel_id: u32) -> Self {
+        TransferTracker {
+            peer: peer.to_owned(),
+            channel_id,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0),
+            started: Instant::now(),
+            request: None,
+            hash: None,
+            acked: false,
+        }
+    }
+
+    /// Hash of the file transferred, once it's known. Uploads name it in their request,
+    /// and downloads when the client acknowledges receiving the file.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_ref().map(|hash| hash.as_str())
+    }
+
+    /// Notes a message received for the transaction
+    pub fn observe(&mut self, message: &Value) {
+        match parsers::parse_message(message.to_owned()) {
+            Ok(Message::ReqReceive(_, hash, path, _, _)) => {
+                self.request = Some((TransferOperation::Export, path, 0, None));
+                self.hash = Some(hash);
+            }
+            // Only the bytes past the offset are sent
+            Ok(Message::ReqAppend(_, hash, path, offset, _, _)) => {
+                self.request = Some((TransferOperation::Export, path, offset, None));
+                self.hash = Some(hash);
+            }
+            Ok(Message::ReqTransmit(_, path, offset, length, _)) => {
+                self.request = Some((TransferOperation::Import, path, offset, length));
+            }
+            Ok(Message::Cleanup(_, hash)) => {
+                self.request = Some((
+                    TransferOperation::Cleanup,
+                    hash.unwrap_or_default(),
+                    0,
+                    None,
+                ));
+            }
+            Ok(Message::ACK(_, hash)) => {
+                self.acked = true;
+                self.hash = Some(hash);
+            }
+            _ => {}
+        }
+    }
+
+    /// Builds the record of the finished transaction, or `None` if it never received
+    /// an import, export or cleanup request
+    ///
+    /// # Arguments
+    ///
+    /// * result - Result of the transaction's message engine
+    /// * failure - Failure reported to the client, if any
+    ///   (see [`Protocol::last_failure`](../protocol/struct.Protocol.html#method.last_failure))
+    pub fn finish(
+        self,
+        result: &Result<(), ProtocolError>,
+        failure: Option<String>,
+    ) -> Option<TransferRecord> {
+        let (operation, path, offset, length) = self.request?;
+
+        let error = match failure {
+            Some(failure) => Some(failure),
+            // The client doesn't send anything more once it has acknowledged a download,
+            // so the message engine times out even though the transfer succeeded
+            None if operation == TransferOperation::Import && self.acked => None,
+            None => result.as_ref().err().map(|err| err.to_string()),
+        };
+
+        let bytes = match (operation, &error) {
+            (TransferOperation::Export, None) => file_size(&path).saturating_sub(offset),
+            (TransferOperation::Import, None) => {
+                let available = file_size(&path).saturating_sub(offset);
+                length.map_or(available, |length| length.min(available))
+            }
+            _ => 0,
+        };
+
+        Some(TransferRecord {
+            time: self.time,
+            peer: self.peer,
+            channel_id: self.channel_id,
+            operation,
+            path,
+            error,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            bytes,
+        })
+    }
+}
+
+fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map(|meta| meta.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages;
+    use std::env;
+
+    fn test_log_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("transfer-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("transfers.log")
+    }
+
+    fn record(channel_id: u32) -> TransferRecord {
+        TransferRecord {
+            time: 1_600_000_000,
+            peer: "127.0.0.1:7000".to_owned(),
+            channel_id,
+            operation: TransferOperation::Export,
+            path: "/home/system/file".to_owned(),
+            error: None,
+            duration_ms: 250,
+            bytes: 4096,
+        }
+    }
+
+    #[test]
+    fn keeps_newest_records() {
+        let path = test_log_path("newest");
+        let path = path.to_str().unwrap();
+
+        let log = TransferLog::open(path, 3).unwrap();
+        for channel_id in 0..8 {
+            log.record(&record(channel_id)).unwrap();
+        }
+
+        let (total, records) = log.recent(0, 10).unwrap();
+        assert_eq!(total, 3);
+        let channels: Vec<u32> = records.iter().map(|record| record.channel_id).collect();
+        assert_eq!(channels, vec![7, 6, 5]);
+
+        let (_, records) = log.recent(1, 1).unwrap();
+        assert_eq!(records, vec![record(6)]);
+
+        // Records survive the log being reopened
+        let log = TransferLog::open(path, 3).unwrap();
+        assert_eq!(log.recent(0, 1).unwrap().1, vec![record(7)]);
+    }
+
+    #[test]
+    fn tracks_hash() {
+        let observe = |tracker: &mut TransferTracker, raw: Vec<u8>| {
+            tracker.observe(&serde_cbor::from_slice(&raw).unwrap())
+        };
+
+        let mut upload = TransferTracker::new("127.0.0.1:7000", 1);
+        observe(&mut upload, messages::metadata(1, "abcd", 4).unwrap());
+        assert_eq!(upload.hash(), None);
+        observe(
+            &mut upload,
+            messages::export_request(1, "abcd", "/target", 0o644, None).unwrap(),
+        );
+        assert_eq!(upload.hash(), Some("abcd"));
+
+        let mut download = TransferTracker::new("127.0.0.1:7000", 2);
+        observe(
+            &mut download,
+            messages::import_request(2, "/source", 0, None, None).unwrap(),
+        );
+        assert_eq!(download.hash(), None);
+        observe(&mut download, messages::ack(2, "ef01", None).unwrap());
+        assert_eq!(download.hash(), Some("ef01"));
+    }
+
+    #[test]
+    fn drops_partial_record() {
+        let path = test_log_path("partial");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let mut data = serde_cbor::to_vec(&record(1)).unwrap();
+        let partial = serde_cbor::to_vec(&record(2)).unwrap();
+        data.extend(&partial[0..partial.len() / 2]);
+        fs::write(&path, &data).unwrap();
+
+        let log = TransferLog::open(path.to_str().unwrap(), 10).unwrap();
+        log.record(&record(3)).unwrap();
+
+        let (total, records) = log.recent(0, 10).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(records, vec![record(3), record(1)]);
+    }
+}
@@ -31,6 +31,9 @@ pub mod messages;
 mod process;
 mod protocol;
 
+/// Shell session recording
+pub mod recording;
+
 pub use crate::error::ProtocolError;
 pub use crate::messages::parse_message;
 pub use crate::messages::Message as ShellMessage;
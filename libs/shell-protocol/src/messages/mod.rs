@@ -54,6 +54,15 @@ pub enum Message {
         /// a request is sent.
         process_list: Option<HashMap<u32, (String, u32)>>,
     },
+    /// This message is used to request and respond with the list of recorded session
+    /// transcripts kept by the shell service.
+    Recordings {
+        /// Channel ID of shell session
+        channel_id: u32,
+        /// Optional list of transcript paths and their sizes in bytes. No list is sent
+        /// when a request is sent.
+        recordings: Option<Vec<(String, u64)>>,
+    },
     /// This message is sent by the shell service after a process is spawned
     /// to indicate the process' PID
     Pid {
@@ -114,6 +123,8 @@ pub mod kill;
 pub mod list;
 /// Helper functions for Message::Pid
 pub mod pid;
+/// Helper functions for Message::Recordings
+pub mod recordings;
 /// Helper functions for Message::Spawn
 pub mod spawn;
 /// Helper functions for Message::Stderr
@@ -131,6 +142,7 @@ pub fn parse_message(message: &ChannelMessage) -> Result<Message, ProtocolError>
         "kill" => Ok(kill::from_cbor(&message)?),
         "list" => Ok(list::from_cbor(&message)?),
         "pid" => Ok(pid::from_cbor(&message)?),
+        "recordings" => Ok(recordings::from_cbor(&message)?),
         "spawn" => Ok(spawn::from_cbor(&message)?),
         "stderr" => Ok(stderr::from_cbor(&message)?),
         "stdin" => Ok(stdin::from_cbor(&message)?),
@@ -0,0 +1,110 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::*;
+use crate::error::ProtocolError;
+use channel_protocol::ChannelMessage;
+use log::info;
+use serde_cbor::ser;
+
+/// CBOR -> Message::Recordings
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let recordings = match message.payload.get(0) {
+        Some(Value::Array(raw_list)) => Some(
+            raw_list
+                .iter()
+                // Extract path/size, skipping any malformed entries
+                .filter_map(|entry| match entry {
+                    Value::Array(data) => match (data.get(0), data.get(1)) {
+                        (Some(Value::Text(path)), Some(Value::Integer(size))) => {
+                            Some((path.to_owned(), *size as u64))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    Ok(Message::Recordings {
+        channel_id: message.channel_id,
+        recordings,
+    })
+}
+
+/// Recordings -> CBOR
+pub fn to_cbor(
+    channel_id: u32,
+    recordings: Option<Vec<(String, u64)>>,
+) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, recordings, '{:?}' }}", channel_id, recordings);
+
+    Ok(
+        ser::to_vec_packed(&(channel_id, "recordings", recordings)).map_err(|err| {
+            ProtocolError::MessageCreationError {
+                message: "recordings".to_owned(),
+                err,
+            }
+        })?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_message() {
+        let channel_id = 13;
+        let recordings = vec![
+            ("/var/shell/1600000000-10.log".to_owned(), 1024),
+            ("/var/shell/1600000100-12.log".to_owned(), 0),
+        ];
+
+        let raw = to_cbor(channel_id, Some(recordings.to_owned())).unwrap();
+        let parsed = channel_protocol::parse_message(de::from_slice(&raw).unwrap()).unwrap();
+        let msg = parse_message(&parsed);
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Recordings {
+                channel_id: channel_id,
+                recordings: Some(recordings),
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_message_empty() {
+        let channel_id = 13;
+
+        let raw = to_cbor(channel_id, None).unwrap();
+        let parsed = channel_protocol::parse_message(de::from_slice(&raw).unwrap()).unwrap();
+        let msg = parse_message(&parsed);
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Recordings {
+                channel_id: channel_id,
+                recordings: None,
+            }
+        );
+    }
+}
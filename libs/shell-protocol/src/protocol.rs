@@ -17,6 +17,7 @@
 use crate::error::ProtocolError;
 use crate::messages;
 use crate::process::ProcessHandler;
+use crate::recording::Recorder;
 use channel_protocol::{ChannelMessage, ChannelProtocol};
 use log::{info, warn};
 use std::time::Duration;
@@ -30,6 +31,7 @@ pub struct Protocol {
     channel_protocol: ChannelProtocol,
     process: Box<ProcessHandler>,
     channel_id: u32,
+    recorder: Option<Recorder>,
 }
 
 impl Protocol {
@@ -50,6 +52,27 @@ impl Protocol {
             channel_protocol,
             process,
             channel_id,
+            recorder: None,
+        }
+    }
+
+    /// Record everything which passes through the session to a transcript
+    ///
+    /// # Arguments
+    ///
+    /// * recorder - Recorder for the session's transcript file
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    // Failing to record is logged, rather than ending the session
+    fn record(&mut self, event: &str, data: &str) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(err) = recorder.record(event, data) {
+                warn!("Failed to record session {}: {}", self.channel_id, err);
+                self.recorder = None;
+            }
         }
     }
 
@@ -73,11 +96,11 @@ impl Protocol {
 
         loop {
             {
-                let process = self.process.as_mut();
                 // Check if process has stdout output
-                if process.stdout_reader.is_some() {
-                    match process.read_stdout() {
+                if self.process.stdout_reader.is_some() {
+                    match self.process.read_stdout() {
                         Ok(Some(data)) => {
+                            self.record("stdout", &data);
                             // If this is the first bit of data we receive since emptying the
                             // stdout then start the timer so we send this data soon...
                             if stdout_string.is_empty() {
@@ -89,15 +112,16 @@ impl Protocol {
                         _ => {
                             self.channel_protocol
                                 .send(&messages::stdout::to_cbor(self.channel_id, None)?)?;
-                            process.stdout_reader = None;
+                            self.process.stdout_reader = None;
                         }
                     }
                 }
 
                 // Check if process has stderr output
-                if process.stderr_reader.is_some() {
-                    match process.read_stderr() {
+                if self.process.stderr_reader.is_some() {
+                    match self.process.read_stderr() {
                         Ok(Some(data)) => {
+                            self.record("stderr", &data);
                             self.channel_protocol
                                 .send(&messages::stderr::to_cbor(self.channel_id, Some(&data))?)?;
                         }
@@ -105,7 +129,7 @@ impl Protocol {
                         _ => {
                             self.channel_protocol
                                 .send(&messages::stderr::to_cbor(self.channel_id, None)?)?;
-                            process.stderr_reader = None;
+                            self.process.stderr_reader = None;
                         }
                     }
                 }
@@ -113,9 +137,10 @@ impl Protocol {
                 // When the process ends we will start to get `None` on stdout/stderr
                 // Once we have closed those pipes we can check for the status code
                 // and clean up. Other wise we might miss output
-                if process.stdout_reader.is_none() && process.stderr_reader.is_none() {
+                if self.process.stdout_reader.is_none() && self.process.stderr_reader.is_none() {
                     // Check if process has exited
-                    if let Some((code, signal)) = process.status()? {
+                    if let Some((code, signal)) = self.process.status()? {
+                        self.record("exit", &format!("{} {}", code, signal));
                         self.channel_protocol.send(&messages::exit::to_cbor(
                             self.channel_id,
                             code,
@@ -161,6 +186,7 @@ impl Protocol {
         match parsed_message {
             messages::Message::Stdin { channel_id, data } => {
                 info!("<- {{ {}, stdin, {:?} }}", channel_id, data);
+                self.record("stdin", data.as_ref().map_or("", |data| data.as_str()));
                 {
                     let process = self.process.as_mut();
                     match data {
@@ -171,6 +197,7 @@ impl Protocol {
             }
             messages::Message::Kill { channel_id, signal } => {
                 info!("<- {{ {}, kill, {:?} }}", channel_id, signal);
+                self.record("kill", &signal.unwrap_or(9).to_string());
                 {
                     let process = self.process.as_mut();
                     process.kill(signal)?;
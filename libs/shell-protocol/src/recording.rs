@@ -0,0 +1,260 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Session recording
+//!
+//! When the shell service has a recording directory, everything which passes through each
+//! session is written to a transcript file in it, so that manual commanding can be reviewed
+//! after an anomaly. Transcripts can be downloaded with the file protocol and replayed with
+//! the shell client.
+//!
+//! Each line of a transcript is one event:
+//!
+//! ```text
+//! <unix time, in seconds with millisecond precision> <event> <data>
+//! ```
+//!
+//! Where the event is one of `spawn`, `stdin`, `stdout`, `stderr`, `kill` or `exit`.
+//! Backslashes, carriage returns and newlines in the data are escaped as `\\`, `\r` and `\n`.
+
+use crate::error::ProtocolError;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extension of transcript files
+pub const RECORDING_EXTENSION: &str = "log";
+
+/// A single event read back from a transcript
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedEvent {
+    /// Unix time the event happened at, in seconds
+    pub timestamp: f64,
+    /// Kind of event (eg. `stdout`)
+    pub event: String,
+    /// Data which passed through the session
+    pub data: String,
+}
+
+/// Writes the transcript of a single shell session
+pub struct Recorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Create a new transcript file for a session in the recording directory
+    ///
+    /// # Arguments
+    ///
+    /// * dir - Recording directory
+    /// * channel_id - Channel ID of shell session
+    /// * command - Process command which was spawned
+    /// * args - Arguments the command was spawned with
+    pub fn create(
+        dir: &str,
+        channel_id: u32,
+        command: &str,
+        args: &Option<Vec<String>>,
+    ) -> Result<Recorder, ProtocolError> {
+        let record_err = |err| ProtocolError::ProcesssError {
+            action: "creating session recording".to_owned(),
+            err,
+        };
+
+        fs::create_dir_all(dir).map_err(record_err)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(0);
+        let path = Path::new(dir).join(format!("{}-{}.{}", now, channel_id, RECORDING_EXTENSION));
+        let file = File::create(&path).map_err(record_err)?;
+
+        let mut recorder = Recorder {
+            path,
+            writer: BufWriter::new(file),
+        };
+
+        let mut spawned = command.to_owned();
+        for arg in args.iter().flatten() {
+            spawned.push(' ');
+            spawned.push_str(arg);
+        }
+        recorder.record("spawn", &spawned)?;
+
+        Ok(recorder)
+    }
+
+    /// Path of the transcript file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add an event to the transcript
+    ///
+    /// The transcript is flushed after every event, so nothing is lost if the
+    /// service is killed part way through a session.
+    pub fn record(&mut self, event: &str, data: &str) -> Result<(), ProtocolError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis())
+            .unwrap_or(0);
+
+        writeln!(
+            self.writer,
+            "{}.{:03} {} {}",
+            now / 1000,
+            now % 1000,
+            event,
+            escape(data)
+        )
+        .and_then(|_| self.writer.flush())
+        .map_err(|err| ProtocolError::ProcesssError {
+            action: "recording session".to_owned(),
+            err,
+        })
+    }
+}
+
+/// Parse a line of a transcript
+///
+/// Returns `None` if the line isn't a valid event.
+pub fn parse_recorded_event(line: &str) -> Option<RecordedEvent> {
+    let mut parts = line.trim_end_matches('\n').splitn(3, ' ');
+    let timestamp = parts.next()?.parse::<f64>().ok()?;
+    let event = parts.next()?.to_owned();
+    let data = unescape(parts.next().unwrap_or(""));
+
+    Some(RecordedEvent {
+        timestamp,
+        event,
+        data,
+    })
+}
+
+/// List the transcripts in a recording directory, along with their sizes in bytes,
+/// oldest first
+pub fn list_recordings(dir: &str) -> Result<Vec<(String, u64)>, ProtocolError> {
+    let list_err = |err| ProtocolError::ProcesssError {
+        action: "listing session recordings".to_owned(),
+        err,
+    };
+
+    let mut recordings: Vec<(String, u64)> = fs::read_dir(dir)
+        .map_err(list_err)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .path()
+                .extension()
+                .map_or(false, |ext| ext == RECORDING_EXTENSION)
+        })
+        .filter_map(|entry| {
+            let size = entry.metadata().ok()?.len();
+            Some((entry.path().to_str()?.to_owned(), size))
+        })
+        .collect();
+
+    // Names start with the session's start time
+    recordings.sort_by_key(|(path, _)| {
+        Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('-').next())
+            .and_then(|time| time.parse::<u64>().ok())
+            .unwrap_or(0)
+    });
+
+    Ok(recordings)
+}
+
+fn escape(data: &str) -> String {
+    data.replace('\\', "\\\\")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+fn unescape(data: &str) -> String {
+    let mut unescaped = String::with_capacity(data.len());
+    let mut chars = data.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn escape_round_trip() {
+        let data = "line one\r\nC:\\path\\n\n";
+        assert!(!escape(data).contains('\n'));
+        assert_eq!(unescape(&escape(data)), data);
+    }
+
+    #[test]
+    fn record_session() {
+        let dir = std::env::temp_dir().join(format!("shell-recording-{}", std::process::id()));
+        let dir_str = dir.to_str().unwrap();
+
+        let mut recorder =
+            Recorder::create(dir_str, 13, "/bin/sh", &Some(vec!["-x".to_owned()])).unwrap();
+        recorder.record("stdin", "ls\n").unwrap();
+        recorder.record("stdout", "file one\nfile two\n").unwrap();
+        recorder.record("exit", "0 0").unwrap();
+
+        let file = File::open(recorder.path()).unwrap();
+        let events: Vec<RecordedEvent> = BufReader::new(file)
+            .lines()
+            .map(|line| parse_recorded_event(&line.unwrap()).unwrap())
+            .collect();
+
+        let kinds: Vec<(&str, &str)> = events
+            .iter()
+            .map(|event| (event.event.as_str(), event.data.as_str()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("spawn", "/bin/sh -x"),
+                ("stdin", "ls\n"),
+                ("stdout", "file one\nfile two\n"),
+                ("exit", "0 0"),
+            ]
+        );
+        assert!(events[0].timestamp > 0.0);
+
+        let recordings = list_recordings(dir_str).unwrap();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].0, recorder.path().to_str().unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -36,6 +36,26 @@ pub struct AppMetadata {
     pub author: String,
     /// The custom configuration file which should be passed to the application when it is started
     pub config: Option<String>,
+    /// Optional. Run the application as a supervised daemon
+    pub supervision: Option<Supervision>,
+}
+
+/// Resource limits and restart policy for applications which should be kept running
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Supervision {
+    /// The number of times the application is restarted after crashing before it's left stopped
+    #[serde(default)]
+    pub max_restarts: u32,
+    /// Seconds to wait before restarting a crashed application. Defaults to 1
+    pub restart_delay: Option<u64>,
+    /// Maximum address space, in bytes
+    pub max_memory: Option<u64>,
+    /// Maximum CPU time, in seconds
+    pub max_cpu: Option<u64>,
+    /// Maximum number of open files
+    pub max_files: Option<u64>,
+    /// cgroup directory the application's process is added to
+    pub cgroup: Option<String>,
 }
 /// Kubos App struct
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -50,6 +70,8 @@ pub struct App {
     pub author: String,
     /// Configuration file to be passed to the application
     pub config: String,
+    /// Supervision settings, if the application should be run as a supervised daemon
+    pub supervision: Option<Supervision>,
 }
 /// AppRegistryEntry
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -24,6 +24,7 @@ mod monitor;
 mod objects;
 mod registry;
 mod schema;
+mod supervisor;
 #[cfg(test)]
 mod tests;
 
@@ -39,6 +40,7 @@ fn main() -> Result<(), Error> {
         error!("Failed to load service config: {:?}", err);
         err
     })?;
+    kubos_service::schema::exit_if_requested::<schema::QueryRoot, schema::MutationRoot>();
 
     let registry = {
         match config.get("registry-dir") {
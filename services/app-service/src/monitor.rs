@@ -34,6 +34,13 @@ pub struct MonitorEntry {
     pub last_signal: Option<i32>,
     pub args: Option<Vec<String>>,
     pub config: String,
+    /// Whether the app is restarted if it crashes
+    pub supervised: bool,
+    /// Number of times a supervised app has been restarted since it was started
+    pub restarts: i32,
+    /// Set when the app is killed on purpose, so that it isn't restarted
+    #[graphql(skip)]
+    pub stopping: bool,
 }
 
 // Check if any version of the application is running
@@ -150,6 +157,70 @@ pub fn finish_entry(
     Ok(())
 }
 
+// A supervised app has been restarted after crashing. Update its entry with the new process
+pub fn restart_entry(
+    registry: &Arc<Mutex<Vec<MonitorEntry>>>,
+    name: &str,
+    version: &str,
+    pid: i32,
+    restarts: u32,
+) -> Result<(), AppError> {
+    let mut entries = registry.lock().map_err(|err| AppError::MonitorError {
+        err: format!(
+            "Failed to update {} restart. Couldn't get entries mutex: {:?}",
+            name, err
+        ),
+    })?;
+
+    if let Some(entry) = entries
+        .iter_mut()
+        .find(|e| e.name == name && e.version == version)
+    {
+        entry.start_time = Utc::now();
+        entry.end_time = None;
+        entry.running = true;
+        entry.pid = Some(pid);
+        entry.restarts = restarts as i32;
+    } else {
+        warn!("Unable to find entry for {} {}", name, version);
+    }
+
+    Ok(())
+}
+
+// Mark a running app as being killed on purpose, so that it won't be restarted
+pub fn stop_entry(registry: &Arc<Mutex<Vec<MonitorEntry>>>, name: &str) -> Result<(), AppError> {
+    let mut entries = registry.lock().map_err(|err| AppError::MonitorError {
+        err: format!(
+            "Failed to stop {}. Couldn't get entries mutex: {:?}",
+            name, err
+        ),
+    })?;
+
+    for entry in entries.iter_mut().filter(|e| e.name == name && e.running) {
+        entry.stopping = true;
+    }
+
+    Ok(())
+}
+
+// Check whether a supervised app which has exited should be restarted.
+// It shouldn't be if it was killed on purpose or its entry has been removed (it was uninstalled)
+pub fn should_restart(
+    registry: &Arc<Mutex<Vec<MonitorEntry>>>,
+    name: &str,
+    version: &str,
+) -> Result<bool, AppError> {
+    let entries = registry.lock().map_err(|err| AppError::MonitorError {
+        err: format!("Failed get entries mutex: {:?}", err),
+    })?;
+
+    Ok(entries
+        .iter()
+        .find(|e| e.name == name && e.version == version)
+        .map_or(false, |e| !e.stopping))
+}
+
 // Remove an entry from the monitoring registry
 // Used when uninstalling a version of an application from the master app registry
 pub fn remove_entry(
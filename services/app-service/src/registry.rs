@@ -17,6 +17,7 @@
 use crate::app_entry::*;
 use crate::error::*;
 use crate::monitor::*;
+use crate::supervisor;
 use chrono::Utc;
 use failure::format_err;
 use fs_extra;
@@ -340,6 +341,7 @@ impl AppRegistry {
                 version: metadata.version,
                 author: metadata.author,
                 config,
+                supervision: metadata.supervision,
             },
             active_version: true,
         };
@@ -669,7 +671,7 @@ impl AppRegistry {
             }
         }
 
-        let mut cmd = Command::new(app_path);
+        let mut cmd = Command::new(&app_path);
 
         let config_path = match config {
             // Use the requested config file
@@ -684,11 +686,22 @@ impl AppRegistry {
             cmd.args(&add_args);
         }
 
-        let mut child = cmd.spawn().map_err(|err| {
-            error!("Failed to spawn app {}: {:?}", app_name, err);
-            AppError::StartError {
-                err: format!("Failed to spawn app: {:?}", err),
+        let mut child = match &app.supervision {
+            Some(supervision) => {
+                // Restarts happen later, after our cwd may have changed
+                if let Some(parent_dir) = app_path.parent() {
+                    cmd.current_dir(parent_dir);
+                }
+                supervisor::prepare(&mut cmd, supervision);
+                supervisor::spawn(&mut cmd, supervision)
             }
+            None => cmd.spawn().map_err(|err| AppError::StartError {
+                err: format!("Failed to spawn app: {:?}", err),
+            }),
+        }
+        .map_err(|err| {
+            error!("Failed to spawn app {}: {}", app_name, err);
+            err
         })?;
 
         let start_time = Utc::now();
@@ -709,6 +722,9 @@ impl AppRegistry {
             last_signal: None,
             args,
             config: config_path,
+            supervised: app.supervision.is_some(),
+            restarts: 0,
+            stopping: false,
         };
         if let Err(error) = start_entry(&self.monitoring, &entry) {
             // The only way this happens is if the monitoring registry mutex gets poisoned.
@@ -746,7 +762,17 @@ impl AppRegistry {
 
                 // Spawn monitor thread
                 thread::spawn(move || {
-                    let result = monitor_app(registry, child, &name, &app.version);
+                    let result = match &app.supervision {
+                        Some(supervision) => supervisor::supervise_app(
+                            registry,
+                            cmd,
+                            child,
+                            &name,
+                            &app.version,
+                            supervision,
+                        ),
+                        None => monitor_app(registry, child, &name, &app.version),
+                    };
 
                     if let Err(error) = result {
                         error!("{:?}", error);
@@ -774,7 +800,14 @@ impl AppRegistry {
             err: "No active PID found in registry".to_owned(),
         })?;
 
-        let pid = Pid::from_raw(pid);
+        // Supervised apps have their own process group, which is killed as a whole.
+        // They're also marked so that they aren't restarted once they exit.
+        let pid = if app.supervised {
+            stop_entry(&self.monitoring, name)?;
+            Pid::from_raw(-pid)
+        } else {
+            Pid::from_raw(pid)
+        };
         let sig = signal::Signal::from_c_int(signal.unwrap_or(15) as i32)
             .unwrap_or(signal::Signal::SIGTERM);
 
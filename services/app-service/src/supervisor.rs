@@ -0,0 +1,231 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// Supervised execution
+//
+// Apps with a `[supervision]` section in their manifest are run as managed daemons: each one
+// gets its own process group and resource limits, is restarted if it crashes, and reports
+// whether it's alive to the telemetry service while it runs.
+
+use crate::app_entry::Supervision;
+use crate::error::*;
+use crate::monitor::*;
+use chrono::{DateTime, Utc};
+use kubos_service::Config;
+use kubos_system::rlimit::set_rlimit;
+use log::*;
+use serde_derive::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Telemetry subsystems used to report the liveness and restart count of supervised apps.
+// The parameter is the app's name.
+const LIVENESS_SUBSYSTEM: &str = "app-liveness";
+const RESTARTS_SUBSYSTEM: &str = "app-restarts";
+
+// How often a running app's liveness is reported
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
+// How often a running app is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Default time to wait before restarting a crashed app
+const DEFAULT_RESTART_DELAY: u64 = 1;
+
+// Set up a command so the app runs in its own process group, with the configured limits
+pub fn prepare(cmd: &mut Command, supervision: &Supervision) {
+    let limits = supervision.clone();
+
+    // Runs in the child just before the app is executed.
+    // Only async-signal-safe calls can be made here.
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(bytes) = limits.max_memory {
+                set_rlimit(libc::RLIMIT_AS, bytes)?;
+            }
+            if let Some(secs) = limits.max_cpu {
+                set_rlimit(libc::RLIMIT_CPU, secs)?;
+            }
+            if let Some(files) = limits.max_files {
+                set_rlimit(libc::RLIMIT_NOFILE, files)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+// Move a newly started app into its configured cgroup, if it has one
+pub fn join_cgroup(supervision: &Supervision, pid: u32) -> Result<(), AppError> {
+    let dir = match &supervision.cgroup {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    OpenOptions::new()
+        .write(true)
+        .open(Path::new(dir).join("cgroup.procs"))
+        .and_then(|mut procs| procs.write_all(pid.to_string().as_bytes()))
+        .map_err(|err| AppError::StartError {
+            err: format!("Failed to add process {} to cgroup {}: {}", pid, dir, err),
+        })
+}
+
+// Start a command set up by `prepare`, moving it into its cgroup.
+// If the cgroup can't be joined, the app is killed rather than left running uncapped.
+pub fn spawn(cmd: &mut Command, supervision: &Supervision) -> Result<Child, AppError> {
+    let mut child = cmd.spawn().map_err(|err| AppError::StartError {
+        err: format!("Failed to spawn app: {:?}", err),
+    })?;
+
+    if let Err(err) = join_cgroup(supervision, child.id()) {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(err);
+    }
+
+    Ok(child)
+}
+
+// Wait for a supervised app to exit, restarting it if it crashes.
+//
+// The app isn't restarted if it exited successfully, was killed with the `killApp` mutation,
+// was uninstalled or started again by someone else, or has used up its restarts.
+pub fn supervise_app(
+    registry: Arc<Mutex<Vec<MonitorEntry>>>,
+    mut cmd: Command,
+    mut child: Child,
+    name: &str,
+    version: &str,
+    supervision: &Supervision,
+) -> Result<(), AppError> {
+    let mut restarts = 0;
+
+    loop {
+        report_liveness(name, true, restarts);
+        let status = wait_for_exit(&mut child, name, restarts)?;
+        let restart = should_restart(&registry, name, version)?;
+        finish_entry(&registry, name, version, status)?;
+        report_liveness(name, false, restarts);
+
+        if status.success() || !restart {
+            return Ok(());
+        }
+        if restarts >= supervision.max_restarts {
+            error!(
+                "App {} crashed after {} restarts. Not restarting it again",
+                name, restarts
+            );
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_secs(
+            supervision.restart_delay.unwrap_or(DEFAULT_RESTART_DELAY),
+        ));
+
+        // Someone may have started or uninstalled the app while we were waiting
+        if !should_restart(&registry, name, version)? || find_running(&registry, name)?.is_some() {
+            return Ok(());
+        }
+
+        restarts += 1;
+        warn!(
+            "Restarting app {} ({}/{})",
+            name, restarts, supervision.max_restarts
+        );
+
+        child = spawn(&mut cmd, supervision).map_err(|err| {
+            error!("Failed to restart app {}: {}", name, err);
+            err
+        })?;
+        restart_entry(&registry, name, version, child.id() as i32, restarts)?;
+    }
+}
+
+// Wait for the app to exit, reporting that it's alive while it runs
+fn wait_for_exit(child: &mut Child, name: &str, restarts: u32) -> Result<ExitStatus, AppError> {
+    let mut last_report = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) => {}
+            Err(err) => {
+                return Err(AppError::MonitorError {
+                    err: format!("Failed to wait for {} to finish: {:?}", name, err),
+                })
+            }
+        }
+
+        if last_report.elapsed() >= LIVENESS_INTERVAL {
+            report_liveness(name, true, restarts);
+            last_report = Instant::now();
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// A telemetry data point, in the layout the telemetry service accepts on its direct UDP port:
+// timestamp, subsystem, parameter and value
+#[derive(Serialize)]
+struct DataPoint<'a>(DateTime<Utc>, &'a str, &'a str, i32);
+
+// Port the telemetry service accepts direct UDP DataPoints on, if it's configured
+fn telemetry_port() -> Option<u16> {
+    let config = Config::new("telemetry-service").ok()?;
+    config
+        .get("direct_port")
+        .and_then(|port| port.as_integer())
+        .map(|port| port as u16)
+}
+
+// Failures are only logged, since telemetry being unavailable shouldn't affect the app
+fn report_liveness(name: &str, alive: bool, restarts: u32) {
+    let port = match telemetry_port() {
+        Some(port) => port,
+        None => return,
+    };
+
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(err) => {
+            debug!("Couldn't create socket for app liveness: {}", err);
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    let dps = vec![
+        DataPoint(now, LIVENESS_SUBSYSTEM, name, if alive { 1 } else { 0 }),
+        DataPoint(now, RESTARTS_SUBSYSTEM, name, restarts as i32),
+    ];
+    match serde_cbor::to_vec(&dps) {
+        Ok(buf) => {
+            if let Err(err) = socket.send_to(&buf, ("0.0.0.0", port)) {
+                debug!("Couldn't send app liveness to telemetry service: {:?}", err);
+            }
+        }
+        Err(_) => debug!("Couldn't serialize app liveness"),
+    }
+}
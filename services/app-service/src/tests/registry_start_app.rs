@@ -221,3 +221,91 @@ fn start_app_nonzero_rc() {
         }
     );
 }
+
+// Create a supervised app which is restarted up to twice
+fn create_supervised_app(registry_dir: &TempDir, src: &str) {
+    let app_dir = registry_dir.path().join("tiny-app/1.0");
+
+    fs::create_dir_all(app_dir.clone()).unwrap();
+
+    let mut bin = fs::File::create(app_dir.join("tiny-app")).unwrap();
+    bin.write_all(src.as_bytes()).unwrap();
+    let mut perms = bin.metadata().unwrap().permissions();
+    perms.set_mode(0o755);
+    bin.set_permissions(perms).unwrap();
+
+    let toml = format!(
+        r#"
+            active_version = true
+
+            [app]
+            executable = "{}/tiny-app/1.0/tiny-app"
+            name = "tiny-app"
+            version = "1.0"
+            author = "user"
+            config = "/custom/config.toml"
+
+            [app.supervision]
+            max_restarts = 2
+            restart_delay = 0
+            max_files = 64
+            "#,
+        registry_dir.path().to_string_lossy(),
+    );
+
+    fs::write(app_dir.join("app.toml"), toml).unwrap();
+}
+
+#[test]
+fn start_app_supervised_restarts() {
+    let registry_dir = TempDir::new().unwrap();
+
+    create_supervised_app(
+        &registry_dir,
+        r#"
+            #!/bin/bash
+            sleep 0.5
+            exit 1
+            "#,
+    );
+
+    let registry = AppRegistry::new_from_dir(&registry_dir.path().to_string_lossy()).unwrap();
+
+    let result = registry.start_app("tiny-app", None, None);
+    assert!(result.unwrap().is_some());
+
+    // Wait for the app to crash and be restarted twice
+    thread::sleep(Duration::from_secs(4));
+
+    let entries = registry.monitoring.lock().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].supervised);
+    assert_eq!(entries[0].restarts, 2);
+    assert_eq!(entries[0].running, false);
+    assert_eq!(entries[0].last_rc, Some(1));
+}
+
+#[test]
+fn kill_app_supervised_not_restarted() {
+    let registry_dir = TempDir::new().unwrap();
+
+    create_supervised_app(
+        &registry_dir,
+        r#"
+            #!/bin/bash
+            sleep 10
+            "#,
+    );
+
+    let registry = AppRegistry::new_from_dir(&registry_dir.path().to_string_lossy()).unwrap();
+
+    registry.start_app("tiny-app", None, None).unwrap();
+    registry.kill_app("tiny-app", None).unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let entries = registry.monitoring.lock().unwrap();
+    assert_eq!(entries[0].restarts, 0);
+    assert_eq!(entries[0].running, false);
+    assert_eq!(entries[0].last_signal, Some(15));
+}
@@ -64,6 +64,7 @@ fn serialize_entry() {
             author: String::from("noone"),
             executable: String::from("/fake/path"),
             config: String::from("/etc/kubos-config.toml"),
+            supervision: None,
         },
         active_version: true,
     };
@@ -77,3 +78,28 @@ fn serialize_entry() {
     assert_eq!(parsed.app.version, dummy.app.version);
     assert_eq!(parsed.app.author, dummy.app.author);
 }
+
+#[test]
+fn serialize_supervised_entry() {
+    let dummy = AppRegistryEntry {
+        app: App {
+            name: String::from("dummy"),
+            version: String::from("0.0.1"),
+            author: String::from("noone"),
+            executable: String::from("/fake/path"),
+            config: String::from("/etc/kubos-config.toml"),
+            supervision: Some(Supervision {
+                max_restarts: 3,
+                max_memory: Some(1 << 20),
+                cgroup: Some(String::from("/sys/fs/cgroup/memory/apps")),
+                ..Default::default()
+            }),
+        },
+        active_version: true,
+    };
+
+    let str = toml::to_string(&dummy).unwrap();
+    let parsed: AppRegistryEntry = toml::from_str(&str).unwrap();
+
+    assert_eq!(parsed.app.supervision, dummy.app.supervision);
+}
@@ -425,6 +425,8 @@ fn main() {
             err
         })
         .unwrap();
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
     let bus = config
         .get("bus")
         .ok_or_else(|| {
@@ -16,14 +16,241 @@
 
 #![allow(clippy::block_in_if_condition_stmt)]
 
-use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_protocol::{
+    ChunkStore, FileProtocol, FileProtocolConfig, FsChunkStore, LogChunkStore, MemoryChunkStore,
+    ProtocolError, State, TransferLimits, TransferLog, TransferOperation, TransferRecord,
+    TransferTracker, UploadKeys,
+};
 use kubos_system::Config as ServiceConfig;
 use log::{error, info, warn};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// Number of transfers kept in the transfer log, unless configured otherwise
+const DEFAULT_TRANSFER_LOG_SIZE: i64 = 1000;
+
+// Transactions in progress, by channel ID
+type Transactions = Arc<Mutex<HashMap<u32, Transaction>>>;
+
+// A transaction in progress
+struct Transaction {
+    // Passes the transaction's messages on to the thread handling it
+    sender: Sender<serde_cbor::Value>,
+    // Key of the transfer the transaction was started for, once it's known
+    // (see `file_protocol::parse_transfer_key`)
+    key: Option<String>,
+}
+
+// When the chunks of a successful transfer are removed from storage, in place of the
+// protocol removing them as soon as the transfer succeeds
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CleanupPolicy {
+    // Left until the ground asks for them to be cleaned up
+    Never,
+    // As soon as the transfer finishes
+    Immediately,
+    // Once the transfer has been finished for the given time
+    After(Duration),
+}
+
+impl CleanupPolicy {
+    // Reads the `auto_cleanup` setting: "never", "immediately", or a number of hours
+    fn from_config(config: &ServiceConfig) -> Result<Option<Self>, failure::Error> {
+        let value = match config.get("auto_cleanup") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match (value.as_str(), value.as_integer()) {
+            (Some("never"), _) => Ok(Some(CleanupPolicy::Never)),
+            (Some("immediately"), _) | (_, Some(0)) => Ok(Some(CleanupPolicy::Immediately)),
+            (_, Some(hours)) if hours > 0 => Ok(Some(CleanupPolicy::After(Duration::from_secs(
+                hours as u64 * 60 * 60,
+            )))),
+            _ => Err(failure::format_err!(
+                "Failed to parse auto_cleanup, expected never, immediately or a number of hours"
+            )),
+        }
+    }
+}
+
+// Applies the cleanup policy to each finished transfer
+#[derive(Clone)]
+struct AutoCleanup {
+    policy: CleanupPolicy,
+    config: FileProtocolConfig,
+    // Passes hashes to the thread which cleans them up once the policy's delay has passed
+    delayed: Option<Sender<String>>,
+}
+
+impl AutoCleanup {
+    fn new(policy: CleanupPolicy, config: FileProtocolConfig, transactions: Transactions) -> Self {
+        let delayed = match policy {
+            CleanupPolicy::After(delay) => {
+                let (sender, receiver) = mpsc::channel();
+                let config = config.clone();
+                thread::Builder::new()
+                    .stack_size(16 * 1024)
+                    .spawn(move || delayed_cleanup(&config, delay, &receiver, &transactions))
+                    .unwrap();
+                Some(sender)
+            }
+            _ => None,
+        };
+
+        AutoCleanup {
+            policy,
+            config,
+            delayed,
+        }
+    }
+
+    // Decides what to do with the storage of a finished transaction
+    fn finished(&self, record: &TransferRecord, hash: Option<&str>) {
+        let hash = match (record.operation, hash) {
+            (TransferOperation::Cleanup, _) | (_, None) => return,
+            (_, Some(hash)) => hash,
+        };
+        if let Some(error) = &record.error {
+            info!(
+                "Keeping storage of {} for a retry, since its {} failed: {}",
+                hash, record.operation, error
+            );
+            return;
+        }
+
+        match (&self.delayed, self.policy) {
+            (_, CleanupPolicy::Never) => {
+                info!("Keeping storage of {} until a cleanup is requested", hash)
+            }
+            (Some(delayed), CleanupPolicy::After(delay)) => {
+                info!(
+                    "Cleaning up storage of {} in {} hours, since its {} succeeded",
+                    hash,
+                    delay.as_secs() / 3600,
+                    record.operation
+                );
+                if delayed.send(hash.to_owned()).is_err() {
+                    warn!("Cleanup thread has stopped, keeping storage of {}", hash);
+                }
+            }
+            _ => {
+                info!(
+                    "Cleaning up storage of {}, since its {} succeeded",
+                    hash, record.operation
+                );
+                if let Err(e) = self.config.delete_file(hash) {
+                    warn!("Failed to clean up storage of {}: {}", hash, e);
+                }
+            }
+        }
+    }
+}
+
+// Cleans up the storage of each hash received once it has waited for the delay. Files which
+// are being transferred again by then are left alone, to be cleaned up after that transfer
+fn delayed_cleanup(
+    config: &FileProtocolConfig,
+    delay: Duration,
+    receiver: &Receiver<String>,
+    transactions: &Transactions,
+) {
+    // Every hash waits for the same delay, so the queue is in order of when they're due
+    let mut pending: VecDeque<(Instant, String)> = VecDeque::new();
+    loop {
+        let received = match pending.front() {
+            Some((due, _)) => {
+                match receiver.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                    Ok(hash) => Some(hash),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            None => match receiver.recv() {
+                Ok(hash) => Some(hash),
+                Err(_) => return,
+            },
+        };
+        if let Some(hash) = received {
+            pending.push_back((Instant::now() + delay, hash));
+        }
+
+        while pending
+            .front()
+            .map_or(false, |(due, _)| *due <= Instant::now())
+        {
+            let (_, hash) = pending.pop_front().unwrap();
+            let in_use = transactions
+                .lock()
+                .map(|transactions| {
+                    transactions
+                        .values()
+                        .any(|transaction| transaction.key.as_ref() == Some(&hash))
+                })
+                .unwrap_or(false);
+            if in_use {
+                info!(
+                    "Not cleaning up storage of {}, it's being transferred",
+                    hash
+                );
+            } else {
+                info!("Cleaning up storage of {}", hash);
+                if let Err(e) = config.delete_file(&hash) {
+                    warn!("Failed to clean up storage of {}: {}", hash, e);
+                }
+            }
+        }
+    }
+}
+
+// Reads the `max_file_size`, `allowed_extensions` and `allowed_paths` settings, if any are set
+fn transfer_limits(config: &ServiceConfig) -> Result<Option<TransferLimits>, failure::Error> {
+    let strings = |key: &str| -> Result<Option<Vec<String>>, failure::Error> {
+        match config.get(key) {
+            Some(val) => val
+                .as_array()
+                .and_then(|items| {
+                    items
+                        .iter()
+                        .map(|item| item.as_str().map(|item| item.to_owned()))
+                        .collect()
+                })
+                .map(Some)
+                .ok_or_else(|| failure::format_err!("Failed to parse {}", key)),
+            None => Ok(None),
+        }
+    };
+
+    let max_file_size = match config.get("max_file_size") {
+        Some(val) => match val.as_integer() {
+            Some(size) if size > 0 => Some(size as u64),
+            _ => return Err(failure::format_err!("Failed to parse max_file_size")),
+        },
+        None => None,
+    };
+    let allowed_extensions = strings("allowed_extensions")?;
+    let allowed_paths = strings("allowed_paths")?;
+
+    if max_file_size.is_none() && allowed_extensions.is_none() && allowed_paths.is_none() {
+        return Ok(None);
+    }
+
+    let mut limits = TransferLimits::new();
+    if let Some(size) = max_file_size {
+        limits = limits.with_max_file_size(size);
+    }
+    if let Some(extensions) = allowed_extensions {
+        limits = limits.with_allowed_extensions(&extensions);
+    }
+    if let Some(paths) = allowed_paths {
+        limits = limits.with_allowed_paths(&paths);
+    }
+    Ok(Some(limits))
+}
 
 // We need this in this lib.rs file so we can build integration tests
 pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
@@ -45,6 +272,30 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         None => None,
     };
 
+    // Get the kind of storage used for the chunks of in-progress transfers
+    let storage_backend = config
+        .get("storage_backend")
+        .and_then(|val| val.as_str().map(|str| str.to_owned()))
+        .unwrap_or_else(|| "filesystem".to_owned());
+
+    // Get the storage directory prefixes which transfers can ask for by class name
+    let storage_classes: Vec<(String, String)> = match config.get("storage_classes") {
+        Some(val) => val
+            .as_table()
+            .and_then(|classes| {
+                classes
+                    .iter()
+                    .map(|(name, prefix)| {
+                        prefix
+                            .as_str()
+                            .map(|prefix| (name.to_owned(), prefix.to_owned()))
+                    })
+                    .collect()
+            })
+            .ok_or_else(|| failure::format_err!("Failed to parse storage classes"))?,
+        None => vec![],
+    };
+
     // Get the chunk size to be used for transfers
     let transfer_chunk_size = match config.get("transfer_chunk_size") {
         Some(val) => val.as_integer().unwrap_or(1024),
@@ -91,36 +342,125 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         .and_then(|chunks| chunks.as_integer())
         .map(|chunks| chunks as u32);
 
+    // Get the path of the log of finished transfers, if one should be kept
+    let transfer_log = match config
+        .get("transfer_log")
+        .and_then(|val| val.as_str().map(|str| str.to_owned()))
+    {
+        Some(path) => {
+            let size = config
+                .get("transfer_log_size")
+                .and_then(|val| val.as_integer())
+                .unwrap_or(DEFAULT_TRANSFER_LOG_SIZE);
+            Some(Arc::new(TransferLog::open(&path, size as usize)?))
+        }
+        None => None,
+    };
+
+    // Get when the storage of successful transfers is cleaned up
+    let cleanup_policy = CleanupPolicy::from_config(config)?;
+
+    // Get the public keys uploads have to be signed with, if only signed uploads are exported
+    let upload_keys = match config.get("upload_keys") {
+        Some(val) => {
+            let keys: Vec<&str> = val
+                .as_array()
+                .and_then(|keys| keys.iter().map(|key| key.as_str()).collect())
+                .ok_or_else(|| failure::format_err!("Failed to parse upload keys"))?;
+            Some(UploadKeys::from_hex(&keys)?)
+        }
+        None => None,
+    };
+
+    // Get the limits on which files can be imported and exported
+    let limits = transfer_limits(config)?;
+
     info!("Starting file transfer service");
     info!("Listening on {}", host);
     info!("Downlinking to {}:{}", downlink_ip, downlink_port);
     info!("Transfer Chunk {}", transfer_chunk_size);
     info!("Hash Chunk Size {}", hash_chunk_size);
+    info!("Storage Backend {}", storage_backend);
+    if let Some(policy) = cleanup_policy {
+        info!("Auto Cleanup {:?}", policy);
+    }
+    if upload_keys.is_some() {
+        info!("Only exporting signed uploads");
+    }
+    if let Some(limits) = &limits {
+        info!("Transfer Limits {:?}", limits);
+    }
 
     let f_config = FileProtocolConfig::new(
-        prefix,
+        prefix.clone(),
         transfer_chunk_size,
         hold_count,
         inter_chunk_delay,
         max_chunks_transmit,
         hash_chunk_size,
-    );
+    )
+    .with_cleanup_on_success(cleanup_policy.is_none());
+
+    let f_config = match storage_backend.as_str() {
+        "filesystem" => f_config,
+        "log" => f_config.with_chunk_store(Arc::new(LogChunkStore::new(
+            &prefix.unwrap_or_else(|| "file-storage".to_owned()),
+        ))),
+        "memory" => f_config.with_chunk_store(Arc::new(MemoryChunkStore::new())),
+        other => {
+            warn!(
+                "Unknown storage_backend '{}', using the filesystem instead",
+                other
+            );
+            f_config
+        }
+    };
+
+    // Storage classes use the same kind of storage as the default store
+    let f_config = storage_classes
+        .iter()
+        .fold(f_config, |f_config, (name, prefix)| {
+            info!("Storage Class {} - {}", name, prefix);
+            let store: Arc<dyn ChunkStore> = match storage_backend.as_str() {
+                "log" => Arc::new(LogChunkStore::new(prefix)),
+                "memory" => Arc::new(MemoryChunkStore::new()),
+                _ => Arc::new(FsChunkStore::new(prefix)),
+            };
+            f_config.with_storage_class(name, store)
+        });
+
+    let f_config = match upload_keys {
+        Some(keys) => f_config.with_upload_keys(keys),
+        None => f_config,
+    };
+
+    let f_config = match limits {
+        Some(limits) => f_config.with_limits(limits),
+        None => f_config,
+    };
 
     let c_protocol = cbor_protocol::Protocol::new(&host.clone(), transfer_chunk_size);
 
+    let downlink_addr: SocketAddr = format!("{}:{}", downlink_ip, downlink_port)
+        .parse()
+        .map_err(|err| failure::format_err!("Failed to parse downlink address: {}", err))?;
+
     let timeout = config
         .get("timeout")
         .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)))
         .unwrap_or(Duration::from_secs(2));
 
-    // Setup map of channel IDs to thread channels
-    let raw_threads: HashMap<u32, Sender<serde_cbor::Value>> = HashMap::new();
+    // Setup map of channel IDs to transactions
+    let raw_threads: HashMap<u32, Transaction> = HashMap::new();
     // Create thread sharable wrapper
-    let threads = Arc::new(Mutex::new(raw_threads));
+    let threads: Transactions = Arc::new(Mutex::new(raw_threads));
+
+    let auto_cleanup =
+        cleanup_policy.map(|policy| AutoCleanup::new(policy, f_config.clone(), threads.clone()));
 
     loop {
         // Listen on UDP port
-        let (_source, first_message) = match c_protocol.recv_message_peer() {
+        let (source, first_message) = match c_protocol.recv_message_peer() {
             Ok((source, first_message)) => (source, first_message),
             Err(e) => {
                 warn!("Error receiving message: {:?}", e);
@@ -140,6 +480,75 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
             }
         };
 
+        // Transfer log requests are answered straight away, without starting a transaction
+        if let Some((channel_id, skip, count)) = file_protocol::parse_log_request(&first_message) {
+            let reply = match &transfer_log {
+                Some(log) => {
+                    log.recent(skip as usize, count as usize)
+                        .and_then(|(total, records)| {
+                            file_protocol::log_entries_message(
+                                channel_id,
+                                total as u32,
+                                &records,
+                                transfer_chunk_size,
+                            )
+                        })
+                }
+                None => file_protocol::failure_message(channel_id, "Transfer log is not enabled"),
+            };
+            let result = reply.and_then(|reply| {
+                c_protocol
+                    .send_message(&reply, downlink_addr)
+                    .map_err(ProtocolError::from)
+            });
+            if let Err(e) = result {
+                warn!(
+                    "Failed to send transfer log on channel {}: {}",
+                    channel_id, e
+                );
+            }
+            continue;
+        }
+
+        // A request to start a different transfer on a channel which is already in use would
+        // mix the two transfers up. Reject it, so the client can try again with a new channel
+        let key = file_protocol::parse_transfer_key(&first_message);
+        let in_use = match threads
+            .lock()
+            .map_err(|err| {
+                error!("Failed to get threads mutex: {:?}", err);
+                err
+            })
+            .unwrap()
+            .get_mut(&channel_id)
+        {
+            Some(transaction) => match (&transaction.key, &key) {
+                (Some(active), Some(key)) => active != key,
+                (None, Some(key)) => {
+                    transaction.key = Some(key.to_owned());
+                    false
+                }
+                _ => false,
+            },
+            None => false,
+        };
+
+        if let (true, Some(key)) = (in_use, &key) {
+            warn!(
+                "Channel {} is already in use, rejecting request for {}",
+                channel_id, key
+            );
+            let result = file_protocol::channel_in_use_message(channel_id, key).and_then(|reply| {
+                c_protocol
+                    .send_message(&reply, downlink_addr)
+                    .map_err(ProtocolError::from)
+            });
+            if let Err(e) = result {
+                warn!("Failed to reject request on channel {}: {}", channel_id, e);
+            }
+            continue;
+        }
+
         if !threads
             .lock()
             .map_err(|err| {
@@ -159,12 +568,14 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                     err
                 })
                 .unwrap()
-                .insert(channel_id, sender.clone());
+                .insert(channel_id, Transaction { sender, key });
 
             // Break the processing work off into its own thread so we can
             // listen for requests from other clients
             let shared_threads = threads.clone();
             let downlink_ip_ref = downlink_ip.to_owned();
+            let log_ref = transfer_log.clone();
+            let cleanup_ref = auto_cleanup.clone();
             thread::Builder::new()
                 .stack_size(16 * 1024)
                 .spawn(move || {
@@ -180,11 +591,17 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                         config_ref,
                     );
 
+                    let tracker =
+                        RefCell::new(TransferTracker::new(&source.to_string(), channel_id));
+
                     // Listen, process, and react to the remaining messages in the
                     // requested operation
-                    if let Err(e) = f_protocol.message_engine(
+                    let result = f_protocol.message_engine(
                         |d| match receiver.recv_timeout(d) {
-                            Ok(v) => Ok(v),
+                            Ok(v) => {
+                                tracker.borrow_mut().observe(&v);
+                                Ok(v)
+                            }
                             Err(RecvTimeoutError::Timeout) => Err(ProtocolError::ReceiveTimeout),
                             Err(e) => Err(ProtocolError::ReceiveError {
                                 err: format!("Error {:?}", e),
@@ -192,10 +609,24 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                         },
                         timeout_ref,
                         &state,
-                    ) {
+                    );
+                    if let Err(e) = &result {
                         warn!("Encountered errors while processing transaction: {}", e);
                     }
 
+                    let tracker = tracker.into_inner();
+                    let hash = tracker.hash().map(|hash| hash.to_owned());
+                    if let Some(record) = tracker.finish(&result, f_protocol.last_failure()) {
+                        if let Some(log) = log_ref {
+                            if let Err(e) = log.record(&record) {
+                                warn!("Failed to add transfer to the transfer log: {}", e);
+                            }
+                        }
+                        if let Some(cleanup) = &cleanup_ref {
+                            cleanup.finished(&record, hash.as_ref().map(|hash| hash.as_str()));
+                        }
+                    }
+
                     // Remove ourselves from threads list if we are finished
                     shared_threads
                         .lock()
@@ -209,7 +640,7 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                 .unwrap();
         }
 
-        if let Some(sender) = threads
+        if let Some(transaction) = threads
             .lock()
             .map_err(|err| {
                 error!("Failed to get threads mutex: {:?}", err);
@@ -218,7 +649,7 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
             .unwrap()
             .get(&channel_id)
         {
-            if let Err(e) = sender.send(first_message) {
+            if let Err(e) = transaction.sender.send(first_message) {
                 warn!("Error when sending to channel {}: {:?}", channel_id, e);
             }
         }
@@ -0,0 +1,104 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The new bytes of a growing file are added to the service's copy of it
+#[test]
+fn upload_append_good() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7021;
+    let downlink_port = 6021;
+
+    let contents: Vec<u8> = (0..10000).map(|num| (num % 251) as u8).collect();
+    create_test_file(&source, &contents);
+    create_test_file(&dest, &contents[0..6000]);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let result = upload_append(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        6000,
+    );
+    assert!(result.is_ok());
+    assert_eq!(fs::read(&dest).unwrap(), contents);
+}
+
+// Nothing is appended to a copy which doesn't match the start of the file
+#[test]
+fn upload_append_mismatch() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7022;
+    let downlink_port = 6022;
+
+    let contents: Vec<u8> = (0..10000).map(|num| (num % 251) as u8).collect();
+    create_test_file(&source, &contents);
+    let mut changed = contents[0..6000].to_vec();
+    changed[100] = !changed[100];
+    create_test_file(&dest, &changed);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let result = upload_append(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        6000,
+    );
+    assert!(result.is_err());
+    assert_eq!(fs::read(&dest).unwrap(), changed);
+
+    // A copy shorter than the offset can't be appended to either
+    let missing = format!("{}/missing", test_dir_str);
+    let result = upload_append(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &missing,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        6000,
+    );
+    assert!(result.is_err());
+    assert!(fs::metadata(&missing).is_err());
+}
@@ -0,0 +1,86 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Download a file from a service with the given cleanup policy, returning where the service
+// keeps the file's storage
+fn download_with_policy(
+    test_dir: &TempDir,
+    contents: &[u8],
+    policy: &str,
+    service_port: u16,
+    downlink_port: u16,
+) -> String {
+    let test_dir_str = test_dir.path().to_str().unwrap().to_owned();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+
+    let hash = create_test_file(&source, contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let extra = format!("timeout = 1\nauto_cleanup = {}", policy);
+    service_new!(service_port, downlink_port, 4096, storage_dir, extra);
+
+    download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    )
+    .unwrap();
+    assert_eq!(contents, fs::read(dest).unwrap().as_slice());
+
+    format!("{}/service/storage/{}", test_dir_str, hash)
+}
+
+// The storage of a successful download is removed once the service's transaction finishes
+#[test]
+fn cleanup_immediately() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let storage = download_with_policy(&test_dir, &[3; 6000], "\"immediately\"", 7015, 6015);
+
+    let mut attempts = 0;
+    while fs::read_dir(&storage).is_ok() && attempts < 20 {
+        thread::sleep(Duration::from_millis(500));
+        attempts += 1;
+    }
+
+    assert!(fs::read_dir(storage).is_err());
+}
+
+// The storage of a successful download is kept until the delay has passed
+#[test]
+fn cleanup_after_delay() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let storage = download_with_policy(&test_dir, &[4; 6000], "1", 7016, 6016);
+
+    // Give the service's transaction time to finish
+    thread::sleep(Duration::from_secs(8));
+
+    assert!(fs::read_dir(storage).is_ok());
+}
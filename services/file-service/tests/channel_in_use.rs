@@ -0,0 +1,78 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::{ChannelAllocation, FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A request for a different file on a channel which is already in use is rejected,
+// and succeeds once it's sent again on a new channel
+#[test]
+fn channel_in_use() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let first = format!("{}/first", test_dir_str);
+    let second = format!("{}/second", test_dir_str);
+    let service_port = 8010;
+    let downlink_port = 7010;
+
+    create_test_file(&first, b"channel_in_use_first");
+    create_test_file(&second, b"channel_in_use_second");
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let f_config = FileProtocolConfig::new(
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        5,
+        1,
+        None,
+        8192,
+    )
+    .with_channel_allocation(ChannelAllocation::Sequential);
+    let f_protocol = FileProtocol::new(
+        &format!("127.0.0.1:{}", downlink_port),
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+    let channel = f_protocol.generate_channel().unwrap();
+
+    f_protocol.send_import(channel, &first).unwrap();
+    let reply = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(file_protocol::parse_channel_id(&reply).unwrap(), channel);
+
+    f_protocol.send_import(channel, &second).unwrap();
+    let reply = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+    match f_protocol.process_message(reply, &State::Done) {
+        Err(ProtocolError::ChannelInUse { channel_id }) => assert_eq!(channel_id, channel),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    let new_channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_import(new_channel, &second).unwrap();
+    let reply = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(
+        file_protocol::parse_channel_id(&reply).unwrap(),
+        new_channel
+    );
+}
@@ -17,6 +17,7 @@
 #![allow(dead_code)]
 
 use blake2_rfc::blake2s::Blake2s;
+use ed25519_dalek::{Keypair, Signer};
 use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
 use serde_cbor::{from_slice, ser};
 use std::fs::File;
@@ -27,6 +28,9 @@ use std::time::Duration;
 #[macro_export]
 macro_rules! service_new {
     ($port:expr, $down_port:expr, $chunk_size:expr, $storage_dir:expr) => {{
+        service_new!($port, $down_port, $chunk_size, $storage_dir, "")
+    }};
+    ($port:expr, $down_port:expr, $chunk_size:expr, $storage_dir:expr, $extra:expr) => {{
         thread::spawn(move || {
             recv_loop(
                 &ServiceConfig::new_from_str(
@@ -39,11 +43,12 @@ macro_rules! service_new {
                 hold_count = 5
                 downlink_ip = "127.0.0.1"
                 downlink_port = {}
+                {}
                 [file-transfer-service.addr]
                 ip = "127.0.0.1"
                 port = {}
                 "#,
-                        $storage_dir, $chunk_size, $down_port, $port
+                        $storage_dir, $chunk_size, $down_port, $extra, $port
                     ),
                 )
                 .unwrap(),
@@ -63,6 +68,31 @@ pub fn download(
     target_path: &str,
     prefix: Option<String>,
     chunk_size: u32,
+) -> Result<(), ProtocolError> {
+    download_range(
+        host_ip,
+        host_port,
+        remote_addr,
+        source_path,
+        target_path,
+        prefix,
+        chunk_size,
+        0,
+        None,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn download_range(
+    host_ip: &str,
+    host_port: u16,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+    offset: u64,
+    length: Option<u64>,
 ) -> Result<(), ProtocolError> {
     let hold_count = 5;
     let f_config = FileProtocolConfig::new(
@@ -80,7 +110,7 @@ pub fn download(
 
     // Send our file request to the remote addr and verify that it's
     // going to be able to send it
-    f_protocol.send_import(channel, source_path)?;
+    f_protocol.send_import_range(channel, source_path, offset, length)?;
 
     // Wait for the request reply.
     // Note/TODO: We don't use a timeout here because we don't know how long it will
@@ -109,6 +139,29 @@ pub fn download_partial(
     target_path: &str,
     prefix: Option<String>,
     chunk_size: u32,
+) -> Result<(), ProtocolError> {
+    download_partial_with_class(
+        host_ip,
+        host_port,
+        remote_addr,
+        source_path,
+        target_path,
+        prefix,
+        chunk_size,
+        None,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn download_partial_with_class(
+    host_ip: &str,
+    host_port: u16,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+    storage_class: Option<&str>,
 ) -> Result<(), ProtocolError> {
     let hold_count = 5;
     let f_config = FileProtocolConfig::new(
@@ -122,6 +175,8 @@ pub fn download_partial(
     let f_protocol =
         FileProtocol::new(&format!("{}:{}", host_ip, host_port), remote_addr, f_config);
 
+    f_protocol.set_storage_class(storage_class);
+
     let channel = f_protocol.generate_channel()?;
 
     // Send our file request to the remote addr and verify that it's
@@ -207,6 +262,51 @@ pub fn upload(
     Ok(hash.to_owned())
 }
 
+// Upload a file, signing its hash with the given key
+#[allow(clippy::too_many_arguments)]
+pub fn upload_signed(
+    host_ip: &str,
+    host_port: u16,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+    signer: &Keypair,
+) -> Result<String, ProtocolError> {
+    let hold_count = 5;
+    let f_config = FileProtocolConfig::new(
+        prefix,
+        chunk_size as usize,
+        hold_count,
+        1,
+        None,
+        (chunk_size as usize) * 2,
+    );
+    let f_protocol =
+        FileProtocol::new(&format!("{}:{}", host_ip, host_port), remote_addr, f_config);
+
+    let (hash, num_chunks, mode) = f_protocol.initialize_file(&source_path)?;
+
+    let channel = f_protocol.generate_channel()?;
+
+    f_protocol.send_metadata(channel, &hash, num_chunks)?;
+
+    // The signature has to arrive before the export request
+    let signature = signer.sign(hash.as_bytes()).to_bytes();
+    f_protocol.send_signature(channel, &hash, &signature)?;
+
+    f_protocol.send_export(channel, &hash, &target_path, mode)?;
+
+    f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        &State::Transmitting,
+    )?;
+
+    Ok(hash.to_owned())
+}
+
 pub fn upload_partial(
     host_ip: &str,
     host_port: u16,
@@ -251,6 +351,47 @@ pub fn upload_partial(
     Ok(hash.to_owned())
 }
 
+// Upload the bytes of a file past `offset`, to be appended to the service's copy of it
+#[allow(clippy::too_many_arguments)]
+pub fn upload_append(
+    host_ip: &str,
+    host_port: u16,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+    offset: u64,
+) -> Result<String, ProtocolError> {
+    let hold_count = 5;
+    let f_config = FileProtocolConfig::new(
+        prefix,
+        chunk_size as usize,
+        hold_count,
+        1,
+        None,
+        (chunk_size as usize) * 2,
+    );
+    let f_protocol =
+        FileProtocol::new(&format!("{}:{}", host_ip, host_port), remote_addr, f_config);
+
+    let (hash, num_chunks, prefix_hash) = f_protocol.initialize_append(&source_path, offset)?;
+
+    let channel = f_protocol.generate_channel()?;
+
+    f_protocol.send_metadata(channel, &hash, num_chunks)?;
+
+    f_protocol.send_append(channel, &hash, &target_path, offset, &prefix_hash)?;
+
+    f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        &State::Transmitting,
+    )?;
+
+    Ok(hash.to_owned())
+}
+
 pub fn cleanup(
     host_ip: &str,
     host_port: u16,
@@ -262,3 +262,38 @@ fn download_bad_hash() {
     );
     assert_eq!("File hash mismatch", format!("{}", result.unwrap_err()));
 }
+
+// Download just part of a multi-chunk file
+#[test]
+fn download_range_multi() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 8005;
+    let downlink_port = 7005;
+
+    let contents: Vec<u8> = (0..10000).map(|num| (num % 251) as u8).collect();
+
+    create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let result = download_range(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        3000,
+        Some(5000),
+    );
+    result.unwrap();
+
+    // Only the requested bytes should have been transferred
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[3000..8000], dest_contents.as_slice());
+}
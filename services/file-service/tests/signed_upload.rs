@@ -0,0 +1,126 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+// Start a service which only exports uploads signed with the given key
+fn signed_service(service_port: u16, downlink_port: u16, storage_dir: String, key: &Keypair) {
+    let key: String = key
+        .public
+        .to_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let keys = format!("upload_keys = [\"{}\"]", key);
+    service_new!(service_port, downlink_port, 4096, storage_dir, keys);
+}
+
+// Upload a file signed with the service's key
+#[test]
+fn upload_signed_file() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7017;
+    let downlink_port = 6017;
+
+    let contents = [5; 6000];
+    create_test_file(&source, &contents);
+
+    let signer = keypair(1);
+    signed_service(
+        service_port,
+        downlink_port,
+        format!("{}/service", test_dir_str),
+        &signer,
+    );
+
+    let result = upload_signed(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        &signer,
+    );
+
+    assert!(result.is_ok());
+    assert_eq!(fs::read(&dest).unwrap(), contents.to_vec());
+}
+
+// Neither an unsigned upload nor one signed with a different key is exported
+#[test]
+fn upload_unsigned_file() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7018;
+    let downlink_port = 6018;
+
+    let contents = [6; 6000];
+    create_test_file(&source, &contents);
+
+    signed_service(
+        service_port,
+        downlink_port,
+        format!("{}/service", test_dir_str),
+        &keypair(1),
+    );
+
+    let result = upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    assert!(result.is_err());
+
+    let result = upload_signed(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        &keypair(2),
+    );
+    assert!(result.is_err());
+
+    assert!(fs::metadata(&dest).is_err());
+}
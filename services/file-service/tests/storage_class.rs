@@ -0,0 +1,95 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Download part of a file, asking for the service's chunks to be kept in the "ram"
+// storage class
+#[test]
+fn download_storage_class() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7013;
+    let downlink_port = 6013;
+
+    let contents = [3; 6000];
+
+    let hash = create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let classes = format!(
+        "[file-transfer-service.storage_classes]\nram = \"{}/ram\"",
+        test_dir_str
+    );
+    service_new!(service_port, downlink_port, 4096, storage_dir, classes);
+
+    let _result = download_partial_with_class(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        Some("ram"),
+    );
+
+    // The chunks should have been kept in the storage class, not the default storage
+    assert!(fs::read_dir(format!("{}/ram/storage/{}", test_dir_str, hash)).is_ok());
+    assert!(fs::read_dir(format!("{}/service/storage/{}", test_dir_str, hash)).is_err());
+}
+
+// Ask for a storage class the service doesn't know about
+#[test]
+fn download_unknown_storage_class() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7014;
+    let downlink_port = 6014;
+
+    let contents = [4; 6000];
+
+    let hash = create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let _result = download_partial_with_class(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        Some("sdcard"),
+    );
+
+    // The service should have fallen back to its default storage
+    assert!(fs::read_dir(format!("{}/service/storage/{}", test_dir_str, hash)).is_ok());
+}
@@ -0,0 +1,124 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Files over the maximum size are refused, but a small enough part of one can be downloaded
+#[test]
+fn download_over_max_size() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7019;
+    let downlink_port = 6019;
+
+    let contents = [7; 6000];
+    create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(
+        service_port,
+        downlink_port,
+        4096,
+        storage_dir,
+        "max_file_size = 5000"
+    );
+
+    let result = download(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    );
+    assert!(result.is_err());
+    assert!(fs::metadata(&dest).is_err());
+
+    let result = download_range(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        1000,
+        Some(5000),
+    );
+    assert!(result.is_ok());
+    assert_eq!(fs::read(&dest).unwrap(), contents[1000..].to_vec());
+}
+
+// Uploads are only exported to allowed directories, with allowed extensions
+#[test]
+fn upload_outside_allowed_paths() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let allowed = format!("{}/allowed", test_dir_str);
+    let service_port = 7020;
+    let downlink_port = 6020;
+
+    let contents = [8; 6000];
+    create_test_file(&source, &contents);
+    fs::create_dir(&allowed).unwrap();
+
+    let limits = format!(
+        "allowed_paths = [\"{}\"]\nallowed_extensions = [\"txt\"]",
+        allowed
+    );
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir, limits);
+
+    let upload_to = |dest: &str| {
+        upload(
+            "127.0.0.1",
+            downlink_port,
+            &format!("127.0.0.1:{}", service_port),
+            &source,
+            dest,
+            Some(format!("{}/client", test_dir_str)),
+            4096,
+        )
+    };
+
+    let outside = format!("{}/dest.txt", test_dir_str);
+    assert!(upload_to(&outside).is_err());
+    assert!(fs::metadata(&outside).is_err());
+
+    let escaping = format!("{}/../dest.txt", allowed);
+    assert!(upload_to(&escaping).is_err());
+    assert!(fs::metadata(&outside).is_err());
+
+    let wrong_type = format!("{}/dest.bin", allowed);
+    assert!(upload_to(&wrong_type).is_err());
+    assert!(fs::metadata(&wrong_type).is_err());
+
+    let inside = format!("{}/dest.txt", allowed);
+    assert!(upload_to(&inside).is_ok());
+    assert_eq!(fs::read(&inside).unwrap(), contents.to_vec());
+}
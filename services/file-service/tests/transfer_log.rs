@@ -0,0 +1,128 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod common;
+
+use crate::common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, TransferOperation};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Finished transfers can be fetched from the service's transfer log
+#[test]
+fn transfer_log() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 8011;
+    let downlink_port = 7011;
+
+    let contents = "transfer_log".as_bytes();
+    create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let log_path = format!("{}/transfers.log", test_dir_str);
+    thread::spawn(move || {
+        recv_loop(
+            &ServiceConfig::new_from_str(
+                "file-transfer-service",
+                &format!(
+                    r#"
+                [file-transfer-service]
+                storage_dir = "{}"
+                transfer_chunk_size = 4096
+                hold_count = 5
+                downlink_ip = "127.0.0.1"
+                downlink_port = {}
+                transfer_log = "{}"
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = {}
+                "#,
+                    storage_dir, downlink_port, log_path, service_port
+                ),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+    });
+    thread::sleep(Duration::new(1, 0));
+
+    upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    )
+    .unwrap();
+
+    // Give the service time to finish up the transaction
+    thread::sleep(Duration::from_secs(2));
+
+    let f_config = FileProtocolConfig::new(
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+        5,
+        1,
+        None,
+        8192,
+    );
+    let f_protocol = FileProtocol::new(
+        &format!("127.0.0.1:{}", downlink_port),
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let records = f_protocol.request_log(10, Duration::from_secs(1)).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].operation, TransferOperation::Export);
+    assert_eq!(records[0].path, dest);
+    assert_eq!(records[0].error, None);
+    assert_eq!(records[0].bytes, contents.len() as u64);
+}
+
+// Fetching the transfer log fails if the service doesn't keep one
+#[test]
+fn transfer_log_disabled() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 8012;
+    let downlink_port = 7012;
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    service_new!(service_port, downlink_port, 4096, storage_dir);
+
+    let f_config = FileProtocolConfig::new(None, 4096, 5, 1, None, 8192);
+    let f_protocol = FileProtocol::new(
+        &format!("127.0.0.1:{}", downlink_port),
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    match f_protocol.request_log(10, Duration::from_secs(1)) {
+        Err(ProtocolError::TransmissionError { error_message, .. }) => {
+            assert_eq!(error_message, "Transfer log is not enabled")
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
@@ -335,3 +335,56 @@ fn upload_single_after_bad_input() {
     // of the hash mismatch
     let _ = fs::remove_dir_all(format!("service/storage/{}", hash));
 }
+
+// Upload a file which the service already has a copy of.
+// The second transfer should be short-circuited and the existing copy used
+#[test]
+fn upload_already_present() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let dest_copy = format!("{}/dest_copy", test_dir_str);
+    let service_port = 7008;
+    let downlink_port = 6008;
+
+    let contents = "upload_already_present".as_bytes();
+
+    let hash = create_test_file(&source, &contents);
+
+    let storage_dir = format!("{}/service", test_dir_str);
+    let service_dir = storage_dir.clone();
+    service_new!(service_port, downlink_port, 4096, service_dir);
+
+    upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    )
+    .unwrap();
+
+    // The service should have recorded where the file was exported to
+    assert!(fs::metadata(format!("{}/exports/{}", storage_dir, hash)).is_ok());
+
+    upload(
+        "127.0.0.1",
+        downlink_port,
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest_copy,
+        Some(format!("{}/client", test_dir_str)),
+        4096,
+    )
+    .unwrap();
+
+    // Verify the copied file's contents
+    let dest_contents = fs::read(dest_copy).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+
+    // No chunks should have been stored for the second transfer
+    assert!(fs::metadata(format!("{}/storage/{}", storage_dir, hash)).is_err());
+}
@@ -82,6 +82,7 @@ fn main() -> EpsResult<()> {
             err
         })
         .unwrap();
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
 
     let bus = config
         .get("bus")
@@ -104,6 +104,8 @@ use kubos_service::{Config, Logger, Service};
 fn main() {
     Logger::init("iobc-supervisor-service").unwrap();
 
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
     Service::new(
         Config::new("iobc-supervisor-service")
             .map_err(|err| {
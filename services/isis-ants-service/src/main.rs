@@ -375,6 +375,7 @@ fn main() -> AntSResult<()> {
             err
         })
         .unwrap();
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
 
     let bus = config
         .get("bus")
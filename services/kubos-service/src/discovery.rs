@@ -0,0 +1,173 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Service discovery
+//!
+//! Every service's `[service-name.addr]` section lives in the shared system configuration
+//! file, so that file doubles as a registry of the flight software stack. Services can expose
+//! it to apps and ground tooling by adding a `services` query to their schema:
+//!
+//! ```rust,ignore
+//! use kubos_service::discovery::{self, ServiceInfo};
+//!
+//! field services(&executor) -> FieldResult<Vec<ServiceInfo>> {
+//!     Ok(discovery::services("example-service")?)
+//! }
+//! ```
+//!
+//! Each service's health is checked by sending it a `ping` query over UDP. Services which
+//! don't reply within the timeout are reported as unhealthy. The timeout can be changed in
+//! the shared `[discovery]` section:
+//!
+//! ```toml
+//! [discovery]
+//! # Milliseconds to wait for services to answer a ping
+//! ping_timeout = 200
+//! ```
+
+use juniper::GraphQLObject;
+use kubos_system::{service_addresses, Config};
+use std::collections::HashSet;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Name of the shared config section holding the discovery settings
+pub const DISCOVERY_SECTION: &str = "discovery";
+/// Default time to wait for services to answer a ping, in milliseconds
+pub const DEFAULT_PING_TIMEOUT: u64 = 200;
+
+const PING_QUERY: &str = "{ ping }";
+
+/// A service found in the system configuration file
+#[derive(Clone, Debug, GraphQLObject, PartialEq)]
+pub struct ServiceInfo {
+    /// Name of the service's config section
+    pub name: String,
+    /// IP address the service listens on
+    pub ip: String,
+    /// Port the service listens on
+    pub port: i32,
+    /// Whether the service answered a ping
+    pub healthy: bool,
+}
+
+/// Lists all of the services in the system configuration file, along with their health
+///
+/// # Arguments
+///
+/// `own_name` - Config section name of the calling service. The calling service is always
+///              reported as healthy, since it can't answer a ping while it's handling this request.
+pub fn services(own_name: &str) -> Result<Vec<ServiceInfo>, String> {
+    let addresses =
+        service_addresses().map_err(|err| format!("Failed to read services: {}", err))?;
+
+    let targets = addresses
+        .iter()
+        .filter(|(name, _)| name != own_name)
+        .map(|(_, addr)| (addr.ip().to_owned(), addr.port()))
+        .collect();
+    let responsive = ping(targets, ping_timeout())?;
+
+    Ok(addresses
+        .into_iter()
+        .map(|(name, addr)| ServiceInfo {
+            healthy: name == own_name || responsive.contains(&addr.port()),
+            port: i32::from(addr.port()),
+            ip: addr.ip().to_owned(),
+            name,
+        })
+        .collect())
+}
+
+fn ping_timeout() -> Duration {
+    let millis = Config::new(DISCOVERY_SECTION)
+        .ok()
+        .and_then(|config| config.get("ping_timeout"))
+        .and_then(|val| val.as_integer())
+        .map(|val| val as u64)
+        .unwrap_or(DEFAULT_PING_TIMEOUT);
+    Duration::from_millis(millis)
+}
+
+// Pings all of the services at once, returning the ports which replied within the timeout.
+// Services are matched by port alone, since a service bound to 0.0.0.0 will reply from
+// whichever address the ping arrived on.
+fn ping(targets: Vec<(String, u16)>, timeout: Duration) -> Result<HashSet<u16>, String> {
+    let mut responsive = HashSet::new();
+    if targets.is_empty() {
+        return Ok(responsive);
+    }
+
+    let socket =
+        UdpSocket::bind("0.0.0.0:0").map_err(|err| format!("Failed to bind socket: {}", err))?;
+
+    for (ip, port) in &targets {
+        let ip = if ip == "0.0.0.0" { "127.0.0.1" } else { ip };
+        // A failed send just means the service won't be reported as healthy
+        let _ = socket.send_to(PING_QUERY.as_bytes(), (ip, *port));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 4096];
+    while responsive.len() < targets.len() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket
+            .set_read_timeout(Some(deadline - now))
+            .map_err(|err| format!("Failed to set socket timeout: {}", err))?;
+
+        match socket.recv_from(&mut buf) {
+            Ok((_, peer)) => {
+                responsive.insert(peer.port());
+            }
+            Err(_) => break,
+        }
+    }
+
+    Ok(responsive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_responsive() {
+        let service = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = service.local_addr().unwrap().port();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_port = silent.local_addr().unwrap().port();
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            let (size, peer) = service.recv_from(&mut buf).unwrap();
+            assert_eq!(PING_QUERY.as_bytes(), &buf[0..size]);
+            service.send_to(b"pong", peer).unwrap();
+        });
+
+        let targets = vec![
+            ("127.0.0.1".to_owned(), port),
+            ("127.0.0.1".to_owned(), silent_port),
+        ];
+        let responsive = ping(targets, Duration::from_millis(200)).unwrap();
+        handle.join().unwrap();
+
+        assert!(responsive.contains(&port));
+        assert!(!responsive.contains(&silent_port));
+    }
+}
@@ -0,0 +1,194 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Standard GraphQL error codes
+//!
+//! Error messages differ from service to service, and are written for people rather than for
+//! ground automation. So that automation can tell why a request failed without parsing those
+//! messages, services attach one of a common set of codes to the `extensions` of the errors
+//! they return:
+//!
+//! ```json
+//! {
+//!     "errors": [
+//!         {
+//!             "message": "No response from radio within 500ms",
+//!             "locations": [{ "line": 1, "column": 3 }],
+//!             "path": ["noop"],
+//!             "extensions": { "code": "HW_TIMEOUT" }
+//!         }
+//!     ],
+//!     "data": null
+//! }
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use juniper::FieldResult;
+//! use kubos_service::errors::{error_code, field_error, ErrorCode};
+//!
+//! fn lookup(name: &str) -> FieldResult<i32> {
+//!     Err(field_error(ErrorCode::NotFound, format!("No parameter named {}", name)))
+//! }
+//!
+//! let err = lookup("voltage").unwrap_err();
+//! assert_eq!(err.message(), "No parameter named voltage");
+//! assert_eq!(error_code(&err), Some(ErrorCode::NotFound));
+//! ```
+
+use juniper::{DefaultScalarValue, FieldError, Object, Value};
+use std::fmt;
+use std::str::FromStr;
+
+/// Key of the code in an error's extensions
+pub const CODE_EXTENSION: &str = "code";
+
+/// Machine-readable reason for a GraphQL error
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The requested item (file, task list, process, telemetry parameter, ...) doesn't exist
+    NotFound,
+    /// An argument is missing, malformed or out of range
+    InvalidArg,
+    /// The hardware didn't respond in time. Retrying may succeed
+    HwTimeout,
+    /// The hardware responded with an error, or couldn't be communicated with at all
+    HwError,
+    /// The service or hardware is busy with another request. Retrying later may succeed
+    Busy,
+    /// The request isn't allowed in the service's current state or configuration
+    /// (eg. the service is read-only or on standby)
+    NotPermitted,
+    /// The service doesn't support the request on this hardware
+    NotImplemented,
+    /// The response was too large to be sent back
+    ResponseTooLarge,
+    /// Any other failure within the service
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code as it appears in an error's extensions
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::InvalidArg => "INVALID_ARG",
+            ErrorCode::HwTimeout => "HW_TIMEOUT",
+            ErrorCode::HwError => "HW_ERROR",
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::NotPermitted => "NOT_PERMITTED",
+            ErrorCode::NotImplemented => "NOT_IMPLEMENTED",
+            ErrorCode::ResponseTooLarge => "RESPONSE_TOO_LARGE",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+
+    /// The extensions of an error with this code
+    pub fn extensions(self) -> Value<DefaultScalarValue> {
+        let mut extensions = Object::with_capacity(1);
+        extensions.add_field(CODE_EXTENSION, Value::scalar(self.as_str()));
+        Value::Object(extensions)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = String;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Ok(match code {
+            "NOT_FOUND" => ErrorCode::NotFound,
+            "INVALID_ARG" => ErrorCode::InvalidArg,
+            "HW_TIMEOUT" => ErrorCode::HwTimeout,
+            "HW_ERROR" => ErrorCode::HwError,
+            "BUSY" => ErrorCode::Busy,
+            "NOT_PERMITTED" => ErrorCode::NotPermitted,
+            "NOT_IMPLEMENTED" => ErrorCode::NotImplemented,
+            "RESPONSE_TOO_LARGE" => ErrorCode::ResponseTooLarge,
+            "INTERNAL" => ErrorCode::Internal,
+            other => return Err(format!("Unknown error code {}", other)),
+        })
+    }
+}
+
+/// Builds a GraphQL error with the given code attached
+pub fn field_error<T: fmt::Display>(code: ErrorCode, message: T) -> FieldError {
+    FieldError::new(message, code.extensions())
+}
+
+/// The code attached to a GraphQL error, if it has a known one
+pub fn error_code(error: &FieldError) -> Option<ErrorCode> {
+    code_from_extensions(error.extensions())
+}
+
+/// The code in the `extensions` of a GraphQL error in a response, if it has a known one
+pub fn code_from_extensions(extensions: &Value<DefaultScalarValue>) -> Option<ErrorCode> {
+    extensions
+        .as_object_value()?
+        .get_field_value(CODE_EXTENSION)?
+        .as_scalar_value::<String>()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODES: &[ErrorCode] = &[
+        ErrorCode::NotFound,
+        ErrorCode::InvalidArg,
+        ErrorCode::HwTimeout,
+        ErrorCode::HwError,
+        ErrorCode::Busy,
+        ErrorCode::NotPermitted,
+        ErrorCode::NotImplemented,
+        ErrorCode::ResponseTooLarge,
+        ErrorCode::Internal,
+    ];
+
+    #[test]
+    fn codes_round_trip() {
+        for code in CODES {
+            assert_eq!(code.as_str().parse::<ErrorCode>(), Ok(*code));
+            assert_eq!(error_code(&field_error(*code, "failed")), Some(*code));
+        }
+    }
+
+    #[test]
+    fn unknown_code() {
+        assert!("TEAPOT".parse::<ErrorCode>().is_err());
+        assert_eq!(error_code(&FieldError::new("failed", Value::null())), None);
+
+        let mut extensions = Object::with_capacity(1);
+        extensions.add_field(CODE_EXTENSION, Value::scalar("TEAPOT"));
+        assert_eq!(code_from_extensions(&Value::Object(extensions)), None);
+    }
+
+    #[test]
+    fn error_serialization() {
+        let err = juniper::ExecutionError::at_origin(field_error(ErrorCode::Busy, "In use"));
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(json["message"], "In use");
+        assert_eq!(json["extensions"]["code"], "BUSY");
+    }
+}
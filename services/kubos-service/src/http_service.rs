@@ -14,15 +14,24 @@
 // limitations under the License.
 //
 
+use crate::requests::{RequestLog, RequestRecord};
+use crate::signals::{self, Reload, Signals, SIGNAL_POLL_INTERVAL};
 use juniper::{Context as JuniperContext, GraphQLType, RootNode};
 use kubos_system::Config;
 use log::info;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::thread;
 use std::time::Duration;
+use tokio::prelude::Future;
+use tokio::sync::oneshot;
 use warp::{filters::BoxedFilter, Filter};
 
+// Header which requests can use to pass a trace ID, as an HTTP request has no room for the
+// comment line used over UDP
+const TRACE_HEADER: &str = "x-trace-id";
+
 /// Context struct used by a service to provide Juniper context,
 /// subsystem access and persistent storage.
 #[derive(Clone)]
@@ -31,6 +40,10 @@ pub struct Context<T> {
     pub subsystem: T,
     ///
     pub storage: Arc<RwLock<HashMap<String, String>>>,
+    /// Trace ID of the request being handled, if it was sent with one
+    pub trace_id: Option<String>,
+    /// The last few requests the service answered. Requests received over HTTP aren't logged
+    pub requests: RequestLog,
 }
 
 impl<T> JuniperContext for Context<T> {}
@@ -41,6 +54,21 @@ impl<T> Context<T> {
         &self.subsystem
     }
 
+    /// Returns the trace ID of the request being handled, if it was sent with one
+    ///
+    /// Resolvers can include this in their log messages so that they can be matched up with
+    /// the logs of the services which the request passed through
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_ref().map(|id| id.as_str())
+    }
+
+    /// Returns the last few requests the service answered, newest first
+    ///
+    /// Requests received over HTTP aren't logged, so this is always empty
+    pub fn recent_requests(&self) -> Vec<RequestRecord> {
+        self.requests.recent()
+    }
+
     /// Attempts to get a value from the context's storage
     ///
     /// # Arguments
@@ -102,13 +130,20 @@ impl<T> Context<T> {
 ///     schema::MutationRoot,
 /// ).start();
 /// ```
-pub struct Service {
+pub struct Service<S> {
     config: Config,
     ///
     pub filter: BoxedFilter<(warp::http::response::Response<std::vec::Vec<u8>>,)>,
+    // The subsystem is owned by the filter, so a copy is kept for reloading the config
+    subsystem: S,
+    reload: Option<Reload<S>>,
+    shutdown: Option<Box<dyn FnOnce(&S) + Send>>,
 }
 
-impl Service {
+impl<S> Service<S>
+where
+    S: Send + Sync + Clone + 'static,
+{
     /// Creates a new service instance
     ///
     /// # Arguments
@@ -117,7 +152,7 @@ impl Service {
     /// `subsystem` - An instance of the subsystem struct. This one instance will be used by all queries.
     /// `query` - The root query struct holding all other GraphQL queries.
     /// `mutation` - The root mutation struct holding all other GraphQL mutations.
-    pub fn new<Query, Mutation, S>(
+    pub fn new<Query, Mutation>(
         config: Config,
         subsystem: S,
         query: Query,
@@ -126,16 +161,28 @@ impl Service {
     where
         Query: GraphQLType<Context = Context<S>, TypeInfo = ()> + Send + Sync + 'static,
         Mutation: GraphQLType<Context = Context<S>, TypeInfo = ()> + Send + Sync + 'static,
-        S: Send + Sync + Clone + 'static,
     {
         let root_node = RootNode::new(query, mutation);
         let context = Context {
-            subsystem,
+            subsystem: subsystem.clone(),
             storage: Arc::new(RwLock::new(HashMap::new())),
+            trace_id: None,
+            requests: RequestLog::default(),
         };
 
-        // Make the subsystem and other persistent data available to all endpoints
-        let context = warp::any().map(move || context.clone()).boxed();
+        // Make the subsystem and other persistent data available to all endpoints, along with
+        // the trace ID of each request
+        let context = warp::header::optional::<String>(TRACE_HEADER)
+            .map(move |trace_id: Option<String>| {
+                if let Some(id) = &trace_id {
+                    info!("[{}] Request received", id);
+                }
+                Context {
+                    trace_id,
+                    ..context.clone()
+                }
+            })
+            .boxed();
 
         let graphql_filter = juniper_warp::make_graphql_filter(root_node, context);
 
@@ -148,11 +195,52 @@ impl Service {
             .unify()
             .boxed();
 
-        Service { config, filter }
+        Service {
+            config,
+            filter,
+            subsystem,
+            reload: None,
+            shutdown: None,
+        }
     }
 
-    /// Starts the service's GraphQL/UDP server. This function runs
-    /// without return.
+    /// Marks config parameters which can be changed without restarting the service
+    ///
+    /// When the service receives SIGHUP it re-reads its config file and, if any of the
+    /// given parameters have changed, passes the new config to `handler` to apply them to
+    /// the subsystem. Changes to any other parameters are logged and otherwise ignored
+    /// until the service is restarted.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the service's config section
+    /// `keys` - The parameters which can be reloaded
+    /// `handler` - Applies the new config to the subsystem
+    pub fn reloadable<F>(mut self, name: &str, keys: &[&str], handler: F) -> Self
+    where
+        F: Fn(&S, &Config) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.reload = Some(Reload::new(name, keys, Box::new(handler)));
+        self
+    }
+
+    /// Registers a function to run once the service has stopped
+    ///
+    /// When the service receives SIGTERM, `hook` is called with the subsystem after the
+    /// requests in progress have finished, so that it can save any state which would
+    /// otherwise be lost (eg. flushing buffered data to disk).
+    pub fn on_shutdown<F>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(&S) + Send + 'static,
+    {
+        self.shutdown = Some(Box::new(hook));
+        self
+    }
+
+    /// Starts the service's GraphQL/HTTP server. This function runs
+    /// until the service receives SIGTERM, at which point any requests
+    /// which are in progress are finished and the shutdown hook is run
+    /// before it returns.
     ///
     /// # Panics
     ///
@@ -175,6 +263,35 @@ impl Service {
                 err
             })
             .unwrap();
+        let signals = Signals::register()
+            .map_err(|err| {
+                log::error!("Failed to register signal handlers: {:?}", err);
+                err
+            })
+            .unwrap();
+
+        // Watch for signals on a separate thread, since the server doesn't return until it
+        // has shut down
+        let (shutdown, shutdown_signal) = oneshot::channel();
+        let mut config = self.config;
+        let reload = self.reload;
+        let subsystem = self.subsystem.clone();
+        thread::spawn(move || loop {
+            if signals.terminate_requested() {
+                info!("Shutting down");
+                let _ = shutdown.send(());
+                break;
+            }
+
+            if signals.take_reload() {
+                if let Some(new) = signals::reload(&reload, &config, &subsystem) {
+                    config = new;
+                }
+            }
+
+            thread::sleep(SIGNAL_POLL_INTERVAL);
+        });
+
         info!("Listening on: {}", addr);
 
         let rt = tokio::runtime::Builder::new()
@@ -185,8 +302,13 @@ impl Service {
             .build()
             .unwrap();
 
-        let server_instance = warp::serve(self.filter).bind(addr);
+        let (_, server_instance) = warp::serve(self.filter)
+            .bind_with_graceful_shutdown(addr, shutdown_signal.map_err(|_| ()));
 
         rt.block_on_all(server_instance).unwrap();
+
+        if let Some(hook) = self.shutdown {
+            hook(&self.subsystem);
+        }
     }
 }
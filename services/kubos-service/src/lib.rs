@@ -104,8 +104,76 @@
 //! ```bash
 //! $ ./example-service -c config.toml
 //! ```
+//!
+//! # Dumping a service's GraphQL schema (SDL) without starting it.
+//!
+//! ```bash
+//! $ ./example-service --schema example-service.graphql
+//! ```
+//!
+//! The service has to check for the flag once its config is loaded, before creating
+//! its subsystem:
+//!
+//! ```rust,ignore
+//! let config = Config::new("example-service").unwrap();
+//! kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+//! ```
+//!
+//! ## Service Discovery
+//!
+//! Since every service's `[service-name.addr]` section lives in the same configuration
+//! file, the [`discovery`](discovery/index.html) module can use it to list the services
+//! in the system, along with whether they are currently answering queries.
+//!
+//! ## Error Codes
+//!
+//! Services attach a standard [`ErrorCode`](errors/enum.ErrorCode.html) (such as `NOT_FOUND`
+//! or `HW_TIMEOUT`) to the extensions of the GraphQL errors they return, with
+//! [`field_error`](errors/fn.field_error.html), so that ground automation can act on why a
+//! request failed without parsing the error message.
+//!
+//! ## Signals
+//!
+//! Services stop gracefully on `SIGTERM` (or `SIGINT`): requests which have already arrived are
+//! answered, and then the hook registered with
+//! [`Service::on_shutdown`](struct.Service.html#method.on_shutdown) is run, before
+//! [`Service::start`](struct.Service.html#method.start) returns. A second signal stops the
+//! service straight away, without running the hook.
+//!
+//! On `SIGHUP`, a service re-reads its configuration file and applies any changes to the
+//! parameters it has marked as reloadable with
+//! [`Service::reloadable`](struct.Service.html#method.reloadable). Changes to any other
+//! parameters only take effect once the service is restarted.
+//!
+//! ## Trace IDs
+//!
+//! A request may carry a trace ID, either as a leading `# trace-id: <id>` comment line (see
+//! `kubos_system::trace`) or, over HTTP, in an `X-Trace-Id` header. The service logs the ID
+//! with the request and makes it available to resolvers through
+//! [`Context::trace_id`](struct.Context.html#method.trace_id), so that a command can be
+//! followed through the logs of each service it passes through.
+//!
+//! ## Chunked Responses
+//!
+//! A response normally has to fit in a single UDP datagram, so larger results have to be
+//! written to a file and fetched with the file transfer service. A request with a leading
+//! `# chunked: <size>` comment line (see `kubos_system::chunked`) has its response split across
+//! as many datagrams of at most `<size>` bytes as it needs, up to 1 MiB in total.
+//!
+//! ## Recent Requests
+//!
+//! A service whose config section sets `recent_requests = <N>` keeps the last `N` requests it
+//! received over UDP, with their timestamps, sizes, durations and errors, in a
+//! [`RequestLog`](requests/struct.RequestLog.html). Adding a `recentRequests` field which returns
+//! [`Context::recent_requests`](struct.Context.html#method.recent_requests) to the service's
+//! query root lets the ground see exactly what the service was sent.
 
+pub mod discovery;
+pub mod errors;
 mod macros;
+pub mod requests;
+pub mod schema;
+mod signals;
 
 #[cfg(all(feature = "http", not(feature = "udp")))]
 mod http_service;
@@ -117,5 +185,6 @@ mod udp_service;
 #[cfg(feature = "udp")]
 pub use crate::udp_service::{Context, Service};
 
+pub use crate::requests::RequestRecord;
 pub use kubos_system::logger as Logger;
 pub use kubos_system::Config;
@@ -0,0 +1,235 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Recent request log
+//!
+//! A service can keep the last few GraphQL requests it answered, along with when they arrived,
+//! how large they and their responses were, how long they took and any errors they returned.
+//! This shows what the ground actually sent to the service, without needing a packet capture.
+//!
+//! The log is off by default. It's turned on by setting `recent_requests` in the service's
+//! config section to the number of requests to keep (at most `MAX_RECENT_REQUESTS`):
+//!
+//! ```toml,ignore
+//! [example-service]
+//! recent_requests = 20
+//! ```
+//!
+//! Services make the log queryable by adding a `recentRequests` field to their query root,
+//! which returns [`Context::recent_requests`](../struct.Context.html#method.recent_requests):
+//!
+//! ```rust,ignore
+//! field recent_requests(&executor) -> Vec<RequestRecord> {
+//!     executor.context().recent_requests()
+//! }
+//! ```
+//!
+//! Only requests received over UDP are logged. A request is added to the log once its response
+//! has been sent, so a `recentRequests` query never sees itself.
+
+use juniper::GraphQLObject;
+use kubos_system::Config;
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Config key holding the number of requests a service keeps in its log
+pub const RECENT_REQUESTS_KEY: &str = "recent_requests";
+/// Largest number of requests a service can keep in its log
+pub const MAX_RECENT_REQUESTS: usize = 1000;
+
+/// A GraphQL request which a service answered
+#[derive(Clone, Debug, GraphQLObject, PartialEq)]
+pub struct RequestRecord {
+    /// When the request arrived, in seconds since the UNIX epoch
+    pub timestamp: f64,
+    /// Address the request came from
+    pub peer: String,
+    /// Trace ID the request was sent with, if any
+    pub trace_id: Option<String>,
+    /// The request, as it was received
+    pub request: String,
+    /// Size of the request, in bytes
+    pub request_size: i32,
+    /// Size of the response, in bytes
+    pub response_size: i32,
+    /// How long the request took to run and answer, in milliseconds
+    pub duration: f64,
+    /// Messages of any errors the response contained
+    pub errors: Vec<String>,
+}
+
+impl RequestRecord {
+    /// Creates a record of a request which arrived at `received` and took `duration` to answer
+    pub fn new(
+        received: SystemTime,
+        duration: Duration,
+        peer: String,
+        trace_id: Option<String>,
+        request: String,
+        response_size: usize,
+        errors: Vec<String>,
+    ) -> Self {
+        let timestamp = received
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs_f64())
+            .unwrap_or(0.0);
+
+        RequestRecord {
+            timestamp,
+            peer,
+            trace_id,
+            request_size: request.len() as i32,
+            request,
+            response_size: response_size as i32,
+            duration: duration.as_secs_f64() * 1000.0,
+            errors,
+        }
+    }
+}
+
+/// The last few requests a service answered
+///
+/// Clones share the same log.
+#[derive(Clone, Debug, Default)]
+pub struct RequestLog {
+    records: Arc<Mutex<VecDeque<RequestRecord>>>,
+    capacity: usize,
+}
+
+impl RequestLog {
+    /// Creates a log which keeps the last `capacity` requests.
+    /// A capacity of zero turns the log off
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.min(MAX_RECENT_REQUESTS);
+        RequestLog {
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Creates a log sized by the `recent_requests` setting in the service's config.
+    /// The log is off if the setting is missing or invalid
+    pub fn from_config(config: &Config) -> Self {
+        let capacity = match config.get(RECENT_REQUESTS_KEY) {
+            Some(value) => match value.as_integer() {
+                Some(capacity) if capacity >= 0 => capacity as usize,
+                _ => {
+                    warn!("Ignoring invalid {} value: {}", RECENT_REQUESTS_KEY, value);
+                    0
+                }
+            },
+            None => 0,
+        };
+
+        RequestLog::new(capacity)
+    }
+
+    /// Whether requests are being logged
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Adds a request to the log, dropping the oldest one if the log is full
+    pub fn record(&self, record: RequestRecord) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        if let Ok(mut records) = self.records.lock() {
+            if records.len() == self.capacity {
+                records.pop_front();
+            }
+            records.push_back(record);
+        }
+    }
+
+    /// Returns the logged requests, newest first
+    pub fn recent(&self) -> Vec<RequestRecord> {
+        self.records
+            .lock()
+            .map(|records| records.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(request: &str) -> RequestRecord {
+        RequestRecord::new(
+            UNIX_EPOCH + Duration::from_millis(1500),
+            Duration::from_micros(2500),
+            "127.0.0.1:9000".to_owned(),
+            None,
+            request.to_owned(),
+            10,
+            vec![],
+        )
+    }
+
+    #[test]
+    fn record_fields() {
+        let record = record("{ ping }");
+        assert_eq!(record.timestamp, 1.5);
+        assert_eq!(record.duration, 2.5);
+        assert_eq!(record.request_size, 8);
+        assert_eq!(record.response_size, 10);
+    }
+
+    #[test]
+    fn keeps_newest_requests() {
+        let log = RequestLog::new(2);
+        log.record(record("one"));
+        log.record(record("two"));
+        log.record(record("three"));
+
+        let requests: Vec<String> = log.recent().into_iter().map(|r| r.request).collect();
+        assert_eq!(requests, vec!["three", "two"]);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let config = Config::new_from_str("example-service", "[example-service]\n").unwrap();
+        let log = RequestLog::from_config(&config);
+        log.record(record("one"));
+
+        assert!(!log.is_enabled());
+        assert!(log.recent().is_empty());
+    }
+
+    #[test]
+    fn capacity_from_config() {
+        let config = Config::new_from_str(
+            "example-service",
+            "[example-service]\nrecent_requests = 5000\n",
+        )
+        .unwrap();
+        assert_eq!(
+            RequestLog::from_config(&config).capacity,
+            MAX_RECENT_REQUESTS
+        );
+
+        let config = Config::new_from_str(
+            "example-service",
+            "[example-service]\nrecent_requests = -1\n",
+        )
+        .unwrap();
+        assert!(!RequestLog::from_config(&config).is_enabled());
+    }
+}
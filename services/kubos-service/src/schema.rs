@@ -0,0 +1,432 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! GraphQL schema export
+//!
+//! Any service built on this crate can dump its schema in the GraphQL schema definition
+//! language (SDL) by passing the `--schema` flag, optionally followed by an output file path:
+//!
+//! ```bash
+//! $ ./example-service --schema
+//! $ ./example-service --schema example-service.graphql
+//! ```
+//!
+//! Services check for the flag with [`exit_if_requested`](fn.exit_if_requested.html) as soon
+//! as they have loaded their config, before creating their subsystem:
+//!
+//! ```rust,ignore
+//! let config = Config::new("example-service")?;
+//! kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+//!
+//! let subsystem = Subsystem::new(&config)?;
+//! ```
+//!
+//! The service exits once the schema has been written, without binding any sockets,
+//! touching hardware or creating any files, so ground tooling can generate typed clients
+//! for every service in an image.
+
+use juniper::meta::MetaType;
+use juniper::{
+    introspect, DefaultScalarValue, GraphQLType, IntrospectionFormat, Object, Registry, RootNode,
+    Value,
+};
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+/// Command line flag which requests a schema dump
+pub const SCHEMA_FLAG: &str = "--schema";
+
+// Types which are generated by GraphQL itself and shouldn't be included in the SDL output
+const BUILTIN_TYPES: &[&str] = &["String", "Int", "Float", "Boolean", "ID"];
+
+/// Where the schema should be written when the `--schema` flag is present
+#[derive(Debug, PartialEq)]
+pub enum SchemaTarget {
+    /// Write the schema to stdout
+    Stdout,
+    /// Write the schema to the given file path
+    File(String),
+}
+
+/// Checks the command line arguments for the `--schema` flag
+///
+/// Doing it this way (rather than with a full argument parser) so that services which use
+/// this crate can have any number of additional command arguments
+pub fn schema_target() -> Option<SchemaTarget> {
+    let mut args = env::args();
+
+    args.position(|arg| arg == SCHEMA_FLAG)?;
+
+    match args.next() {
+        Some(ref path) if !path.starts_with('-') => Some(SchemaTarget::File(path.to_owned())),
+        _ => Some(SchemaTarget::Stdout),
+    }
+}
+
+// Stands in for one of a service's root types so its schema can be introspected without an
+// instance of the service's context (and so, without its subsystem). It registers exactly the
+// same types as the root it wraps, and introspection never resolves any of their fields
+struct SchemaOnly<T>(PhantomData<T>);
+
+impl<T> GraphQLType for SchemaOnly<T>
+where
+    T: GraphQLType<TypeInfo = ()>,
+{
+    type Context = ();
+    type TypeInfo = ();
+
+    fn name(info: &()) -> Option<&str> {
+        T::name(info)
+    }
+
+    fn meta<'r>(info: &(), registry: &mut Registry<'r>) -> MetaType<'r>
+    where
+        DefaultScalarValue: 'r,
+    {
+        T::meta(info, registry)
+    }
+}
+
+/// Generates the SDL representation of a service's schema
+///
+/// Only the types of the service's root query and mutation are needed, so the schema can
+/// be generated before the service's subsystem exists
+pub fn schema_sdl<Query, Mutation>() -> Result<String, String>
+where
+    Query: GraphQLType<TypeInfo = ()>,
+    Mutation: GraphQLType<TypeInfo = ()>,
+{
+    let root_node = RootNode::new(
+        SchemaOnly::<Query>(PhantomData),
+        SchemaOnly::<Mutation>(PhantomData),
+    );
+
+    let (value, errors) = introspect(&root_node, &(), IntrospectionFormat::All)
+        .map_err(|err| format!("Introspection failed: {:?}", err))?;
+
+    if !errors.is_empty() {
+        return Err(format!("Introspection failed: {:?}", errors));
+    }
+
+    let schema = value
+        .as_object_value()
+        .and_then(|obj| obj.get_field_value("__schema"))
+        .and_then(|schema| schema.as_object_value())
+        .ok_or_else(|| "Introspection result missing __schema".to_owned())?;
+
+    Ok(print_schema(schema))
+}
+
+/// Writes the SDL representation of a service's schema to the requested target
+pub fn write_schema<Query, Mutation>(target: &SchemaTarget) -> Result<(), String>
+where
+    Query: GraphQLType<TypeInfo = ()>,
+    Mutation: GraphQLType<TypeInfo = ()>,
+{
+    let sdl = schema_sdl::<Query, Mutation>()?;
+
+    match target {
+        SchemaTarget::Stdout => io::stdout()
+            .write_all(sdl.as_bytes())
+            .map_err(|err| format!("Failed to write schema: {}", err)),
+        SchemaTarget::File(path) => File::create(path)
+            .and_then(|mut file| file.write_all(sdl.as_bytes()))
+            .map_err(|err| format!("Failed to write schema to {}: {}", path, err)),
+    }
+}
+
+/// Writes the service's schema and exits if the `--schema` flag was passed to the service
+///
+/// Should be called right after the service's config has been loaded, before its subsystem
+/// is created, so that dumping the schema never opens hardware buses or creates files
+pub fn exit_if_requested<Query, Mutation>()
+where
+    Query: GraphQLType<TypeInfo = ()>,
+    Mutation: GraphQLType<TypeInfo = ()>,
+{
+    if let Some(target) = schema_target() {
+        match write_schema::<Query, Mutation>(&target) {
+            Ok(()) => std::process::exit(0),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+type Obj = Object<DefaultScalarValue>;
+type Val = Value<DefaultScalarValue>;
+
+fn get_str<'a>(obj: &'a Obj, key: &str) -> Option<&'a str> {
+    obj.get_field_value(key)
+        .and_then(|val| val.as_scalar_value::<String>())
+        .map(|val| val.as_str())
+}
+
+fn get_list<'a>(obj: &'a Obj, key: &str) -> Vec<&'a Obj> {
+    obj.get_field_value(key)
+        .and_then(|val| val.as_list_value())
+        .map(|list| list.iter().filter_map(|v| v.as_object_value()).collect())
+        .unwrap_or_default()
+}
+
+fn root_name<'a>(schema: &'a Obj, key: &str) -> Option<&'a str> {
+    schema
+        .get_field_value(key)
+        .and_then(|val| val.as_object_value())
+        .and_then(|obj| get_str(obj, "name"))
+}
+
+fn print_schema(schema: &Obj) -> String {
+    let mut sdl = String::new();
+
+    let query = root_name(schema, "queryType");
+    let mutation = root_name(schema, "mutationType");
+
+    sdl.push_str("schema {\n");
+    if let Some(query) = query {
+        sdl.push_str(&format!("  query: {}\n", query));
+    }
+    if let Some(mutation) = mutation {
+        sdl.push_str(&format!("  mutation: {}\n", mutation));
+    }
+    sdl.push_str("}\n");
+
+    let mut types = get_list(schema, "types");
+    types.sort_by(|a, b| get_str(a, "name").cmp(&get_str(b, "name")));
+
+    for ty in types {
+        let name = get_str(ty, "name").unwrap_or("");
+        if name.starts_with("__") || BUILTIN_TYPES.contains(&name) {
+            continue;
+        }
+
+        sdl.push('\n');
+        print_description(&mut sdl, ty, "");
+
+        match get_str(ty, "kind").unwrap_or("") {
+            "SCALAR" => sdl.push_str(&format!("scalar {}\n", name)),
+            "OBJECT" => {
+                let interfaces: Vec<&str> = get_list(ty, "interfaces")
+                    .iter()
+                    .filter_map(|i| get_str(i, "name"))
+                    .collect();
+                if interfaces.is_empty() {
+                    sdl.push_str(&format!("type {} {{\n", name));
+                } else {
+                    sdl.push_str(&format!(
+                        "type {} implements {} {{\n",
+                        name,
+                        interfaces.join(" & ")
+                    ));
+                }
+                print_fields(&mut sdl, ty, "fields");
+                sdl.push_str("}\n");
+            }
+            "INTERFACE" => {
+                sdl.push_str(&format!("interface {} {{\n", name));
+                print_fields(&mut sdl, ty, "fields");
+                sdl.push_str("}\n");
+            }
+            "INPUT_OBJECT" => {
+                sdl.push_str(&format!("input {} {{\n", name));
+                print_fields(&mut sdl, ty, "inputFields");
+                sdl.push_str("}\n");
+            }
+            "UNION" => {
+                let members: Vec<&str> = get_list(ty, "possibleTypes")
+                    .iter()
+                    .filter_map(|i| get_str(i, "name"))
+                    .collect();
+                sdl.push_str(&format!("union {} = {}\n", name, members.join(" | ")));
+            }
+            "ENUM" => {
+                sdl.push_str(&format!("enum {} {{\n", name));
+                for value in get_list(ty, "enumValues") {
+                    print_description(&mut sdl, value, "  ");
+                    sdl.push_str(&format!("  {}\n", get_str(value, "name").unwrap_or("")));
+                }
+                sdl.push_str("}\n");
+            }
+            _ => {}
+        }
+    }
+
+    sdl
+}
+
+fn print_description(sdl: &mut String, obj: &Obj, indent: &str) {
+    if let Some(desc) = get_str(obj, "description") {
+        if !desc.is_empty() {
+            sdl.push_str(&format!(
+                "{}\"\"\"{}\"\"\"\n",
+                indent,
+                desc.replace("\"\"\"", "\\\"\"\"")
+            ));
+        }
+    }
+}
+
+fn print_fields(sdl: &mut String, ty: &Obj, key: &str) {
+    for field in get_list(ty, key) {
+        print_description(sdl, field, "  ");
+
+        let args: Vec<String> = get_list(field, "args")
+            .iter()
+            .map(|arg| print_input_value(arg))
+            .collect();
+
+        let args = if args.is_empty() {
+            String::new()
+        } else {
+            format!("({})", args.join(", "))
+        };
+
+        let field_type = field.get_field_value("type").map(print_type_ref);
+
+        sdl.push_str(&format!(
+            "  {}{}: {}",
+            get_str(field, "name").unwrap_or(""),
+            args,
+            field_type.unwrap_or_default()
+        ));
+
+        if let Some(default) = get_str(field, "defaultValue") {
+            sdl.push_str(&format!(" = {}", default));
+        }
+
+        if let Some(Value::Scalar(DefaultScalarValue::Boolean(true))) =
+            field.get_field_value("isDeprecated")
+        {
+            match get_str(field, "deprecationReason") {
+                Some(reason) => sdl.push_str(&format!(" @deprecated(reason: {:?})", reason)),
+                None => sdl.push_str(" @deprecated"),
+            }
+        }
+
+        sdl.push('\n');
+    }
+}
+
+fn print_input_value(arg: &Obj) -> String {
+    let mut out = format!(
+        "{}: {}",
+        get_str(arg, "name").unwrap_or(""),
+        arg.get_field_value("type")
+            .map(print_type_ref)
+            .unwrap_or_default()
+    );
+    if let Some(default) = get_str(arg, "defaultValue") {
+        out.push_str(&format!(" = {}", default));
+    }
+    out
+}
+
+fn print_type_ref(val: &Val) -> String {
+    let obj = match val.as_object_value() {
+        Some(obj) => obj,
+        None => return String::new(),
+    };
+
+    let inner = || {
+        obj.get_field_value("ofType")
+            .map(print_type_ref)
+            .unwrap_or_default()
+    };
+
+    match get_str(obj, "kind") {
+        Some("NON_NULL") => format!("{}!", inner()),
+        Some("LIST") => format!("[{}]", inner()),
+        _ => get_str(obj, "name").unwrap_or("").to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::{EmptyMutation, FieldResult};
+
+    struct Query;
+
+    #[juniper::object]
+    impl Query {
+        /// Test service query
+        fn ping() -> FieldResult<String> {
+            Ok(String::from("pong"))
+        }
+
+        fn echo(value: i32, repeat: Option<i32>) -> Vec<i32> {
+            vec![value; repeat.unwrap_or(1) as usize]
+        }
+    }
+
+    #[test]
+    fn sdl_contains_queries() {
+        let sdl = schema_sdl::<Query, EmptyMutation<()>>().unwrap();
+
+        assert!(sdl.contains("schema {\n  query: Query\n"));
+        assert!(sdl.contains("type Query {\n"));
+        assert!(sdl.contains("  \"\"\"Test service query\"\"\"\n  ping: String!\n"));
+        assert!(sdl.contains("  echo(value: Int!, repeat: Int): [Int!]!\n"));
+    }
+
+    #[test]
+    fn sdl_skips_introspection_types() {
+        let sdl = schema_sdl::<Query, EmptyMutation<()>>().unwrap();
+
+        assert!(!sdl.contains("__Schema"));
+        assert!(!sdl.contains("scalar String"));
+    }
+
+    // A service's context holds its subsystem, so the schema has to be generated without one
+    struct Subsystem {
+        value: i32,
+    }
+
+    impl juniper::Context for Subsystem {}
+
+    struct SubsystemQuery;
+
+    #[juniper::object(Context = Subsystem)]
+    impl SubsystemQuery {
+        fn value(context: &Subsystem) -> i32 {
+            context.value
+        }
+    }
+
+    struct SubsystemMutation;
+
+    #[juniper::object(Context = Subsystem)]
+    impl SubsystemMutation {
+        fn set(context: &Subsystem, value: i32) -> i32 {
+            context.value + value
+        }
+    }
+
+    #[test]
+    fn sdl_without_context() {
+        let sdl = schema_sdl::<SubsystemQuery, SubsystemMutation>().unwrap();
+
+        assert!(
+            sdl.contains("schema {\n  query: SubsystemQuery\n  mutation: SubsystemMutation\n}\n")
+        );
+        assert!(sdl.contains("type SubsystemMutation {\n  set(value: Int!): Int!\n}\n"));
+        assert!(sdl.contains("type SubsystemQuery {\n  value: Int!\n}\n"));
+    }
+}
@@ -0,0 +1,216 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Signal handling shared by the UDP and HTTP services.
+//
+// SIGTERM (or SIGINT) asks the service to stop once the requests it has already received are
+// answered. A second one stops it straight away, in case a request is stuck on hardware.
+// SIGHUP asks the service to re-read its config file and apply any changes to the
+// parameters it has marked as reloadable.
+
+use kubos_system::Config;
+use log::{error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often a service checks whether it has been signalled
+pub(crate) const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Function which applies a reloaded config to a service's subsystem
+pub(crate) type ReloadHandler<S> = Box<dyn Fn(&S, &Config) -> Result<(), String> + Send + Sync>;
+
+// Records which signals have been received, until the service gets round to handling them
+pub(crate) struct Signals {
+    terminate: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl Signals {
+    pub(crate) fn register() -> io::Result<Signals> {
+        let terminate = Arc::new(AtomicBool::new(false));
+        let reload = Arc::new(AtomicBool::new(false));
+
+        // Registered first, so that they only fire if an earlier signal has already set the flag
+        for signal in &[SIGTERM, SIGINT] {
+            flag::register_conditional_shutdown(*signal, 1, terminate.clone())?;
+        }
+        for signal in &[SIGTERM, SIGINT] {
+            flag::register(*signal, terminate.clone())?;
+        }
+        flag::register(SIGHUP, reload.clone())?;
+
+        Ok(Signals { terminate, reload })
+    }
+
+    pub(crate) fn terminate_requested(&self) -> bool {
+        self.terminate.load(Ordering::SeqCst)
+    }
+
+    // Returns whether a reload was requested since the last call
+    pub(crate) fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+}
+
+// The config parameters a service can change while running, and how to apply them
+pub(crate) struct Reload<S> {
+    name: String,
+    keys: Vec<String>,
+    handler: ReloadHandler<S>,
+}
+
+impl<S> Reload<S> {
+    pub(crate) fn new(name: &str, keys: &[&str], handler: ReloadHandler<S>) -> Self {
+        Reload {
+            name: name.to_owned(),
+            keys: keys.iter().map(|key| (*key).to_owned()).collect(),
+            handler,
+        }
+    }
+
+    // Re-reads the service's config file, returning the new config if it was applied
+    pub(crate) fn reload(&self, current: &Config, subsystem: &S) -> Option<Config> {
+        match Config::new(&self.name) {
+            Ok(new) => self.apply(current, new, subsystem),
+            Err(err) => {
+                error!("Failed to reload config: {}", err);
+                None
+            }
+        }
+    }
+
+    fn apply(&self, current: &Config, new: Config, subsystem: &S) -> Option<Config> {
+        let (reloadable, fixed): (Vec<String>, Vec<String>) = changed_keys(current, &new)
+            .into_iter()
+            .partition(|key| self.keys.contains(key));
+
+        for key in fixed {
+            warn!(
+                "Config parameter '{}' has changed, but won't take effect until the service restarts",
+                key
+            );
+        }
+
+        if reloadable.is_empty() {
+            info!("No reloadable config parameters have changed");
+            return None;
+        }
+
+        match (self.handler)(subsystem, &new) {
+            Ok(()) => {
+                info!("Reloaded config parameters: {}", reloadable.join(", "));
+                Some(new)
+            }
+            Err(err) => {
+                error!("Failed to reload config: {}", err);
+                None
+            }
+        }
+    }
+}
+
+// Handles a reload request for a service which may not have any reloadable parameters
+pub(crate) fn reload<S>(
+    reload: &Option<Reload<S>>,
+    current: &Config,
+    subsystem: &S,
+) -> Option<Config> {
+    info!("Reloading config");
+    match reload {
+        Some(reload) => reload.reload(current, subsystem),
+        None => {
+            info!("Service has no reloadable config parameters");
+            None
+        }
+    }
+}
+
+// Lists the top-level parameters whose values differ between the two configs
+fn changed_keys(old: &Config, new: &Config) -> Vec<String> {
+    let mut keys = BTreeSet::new();
+    for config in &[old, new] {
+        if let Some(table) = config.raw().as_table() {
+            keys.extend(table.keys().cloned());
+        }
+    }
+
+    keys.into_iter()
+        .filter(|key| old.get(key) != new.get(key))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn config(bus: &str, timeout: i64) -> Config {
+        Config::new_from_str(
+            "example-service",
+            &format!(
+                r#"
+                [example-service]
+                bus = "{}"
+                timeout = {}
+                [example-service.addr]
+                ip = "127.0.0.1"
+                port = 8000
+                "#,
+                bus, timeout
+            ),
+        )
+        .unwrap()
+    }
+
+    fn reload() -> Reload<Mutex<Option<i64>>> {
+        Reload::new(
+            "example-service",
+            &["timeout"],
+            Box::new(|subsystem: &Mutex<Option<i64>>, config: &Config| {
+                *subsystem.lock().unwrap() = config.get("timeout").and_then(|val| val.as_integer());
+                Ok(())
+            }),
+        )
+    }
+
+    #[test]
+    fn changed_keys_lists_differences() {
+        assert_eq!(
+            changed_keys(&config("I2C1", 5), &config("I2C2", 10)),
+            vec!["bus".to_owned(), "timeout".to_owned()]
+        );
+        assert!(changed_keys(&config("I2C1", 5), &config("I2C1", 5)).is_empty());
+    }
+
+    #[test]
+    fn reload_applies_reloadable_changes() {
+        let subsystem = Mutex::new(None);
+
+        assert!(reload()
+            .apply(&config("I2C1", 5), config("I2C2", 5), &subsystem)
+            .is_none());
+        assert_eq!(*subsystem.lock().unwrap(), None);
+
+        let new = reload().apply(&config("I2C1", 5), config("I2C1", 10), &subsystem);
+        assert_eq!(new.and_then(|new| new.get("timeout")), Some(10.into()));
+        assert_eq!(*subsystem.lock().unwrap(), Some(10));
+    }
+}
@@ -13,16 +13,25 @@
 // limitations under the License.
 //
 
-use juniper::{execute, Context as JuniperContext, GraphQLType, RootNode, Variables};
-use kubos_system::Config;
+use crate::errors::{field_error, ErrorCode};
+use crate::requests::{RequestLog, RequestRecord};
+use crate::signals::{self, Reload, Signals, SIGNAL_POLL_INTERVAL};
+use juniper::{execute, Context as JuniperContext, GraphQLError, GraphQLType, RootNode, Variables};
+use kubos_system::{chunked, trace, Config};
 use log::{error, info};
 use serde::Serialize;
 use std::{
     collections::HashMap,
     net::{SocketAddr, UdpSocket},
     sync::{Arc, RwLock},
+    time::{Instant, SystemTime},
 };
 
+// Largest response which can be sent back in a single datagram
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+// Largest response which can be sent back in a chunked response
+const MAX_CHUNKED_RESPONSE_SIZE: usize = 1024 * 1024;
+
 /// Context struct used by a service to provide Juniper context,
 /// subsystem access and persistent storage.
 #[derive(Clone)]
@@ -31,6 +40,10 @@ pub struct Context<T> {
     pub subsystem: T,
     ///
     pub storage: Arc<RwLock<HashMap<String, String>>>,
+    /// Trace ID of the request being handled, if it was sent with one
+    pub trace_id: Option<String>,
+    /// The last few requests the service answered, if it's configured to keep them
+    pub requests: RequestLog,
 }
 
 impl<T> JuniperContext for Context<T> {}
@@ -41,6 +54,21 @@ impl<T> Context<T> {
         &self.subsystem
     }
 
+    /// Returns the trace ID of the request being handled, if it was sent with one
+    ///
+    /// Resolvers can include this in their log messages so that they can be matched up with
+    /// the logs of the services which the request passed through
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_ref().map(|id| id.as_str())
+    }
+
+    /// Returns the last few requests the service answered, newest first
+    ///
+    /// This is empty unless the service's `recent_requests` config parameter is set
+    pub fn recent_requests(&self) -> Vec<RequestRecord> {
+        self.requests.recent()
+    }
+
     /// Attempts to get a value from the context's storage
     ///
     /// # Arguments
@@ -111,6 +139,8 @@ where
     config: Config,
     context: Context<S>,
     root_node: RootNode<'a, Query, Mutation>,
+    reload: Option<Reload<S>>,
+    shutdown: Option<Box<dyn FnOnce(&S) + Send>>,
 }
 
 impl<'a, Query, Mutation, S> Service<'a, Query, Mutation, S>
@@ -132,24 +162,81 @@ where
         let context = Context {
             subsystem,
             storage: Arc::new(RwLock::new(HashMap::new())),
+            trace_id: None,
+            requests: RequestLog::from_config(&config),
         };
 
         Service {
             config,
             context,
             root_node,
+            reload: None,
+            shutdown: None,
         }
     }
 
+    /// Marks config parameters which can be changed without restarting the service
+    ///
+    /// When the service receives SIGHUP it re-reads its config file and, if any of the
+    /// given parameters have changed, passes the new config to `handler` to apply them to
+    /// the subsystem. Changes to any other parameters are logged and otherwise ignored
+    /// until the service is restarted.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the service's config section
+    /// `keys` - The parameters which can be reloaded
+    /// `handler` - Applies the new config to the subsystem
+    ///
+    /// ### Examples
+    ///
+    /// ```rust,ignore
+    /// Service::new(config, subsystem, QueryRoot, MutationRoot)
+    ///     .reloadable("example-service", &["timeout"], |subsystem, config| {
+    ///         subsystem.set_timeout(config.get("timeout"))
+    ///     })
+    ///     .start();
+    /// ```
+    pub fn reloadable<F>(mut self, name: &str, keys: &[&str], handler: F) -> Self
+    where
+        F: Fn(&S, &Config) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.reload = Some(Reload::new(name, keys, Box::new(handler)));
+        self
+    }
+
+    /// Registers a function to run once the service has stopped
+    ///
+    /// When the service receives SIGTERM, `hook` is called with the subsystem after the
+    /// pending requests have been answered, so that it can save any state which would
+    /// otherwise be lost (eg. flushing buffered data to disk).
+    ///
+    /// ### Examples
+    ///
+    /// ```rust,ignore
+    /// Service::new(config, subsystem, QueryRoot, MutationRoot)
+    ///     .on_shutdown(|subsystem| subsystem.flush())
+    ///     .start();
+    /// ```
+    pub fn on_shutdown<F>(mut self, hook: F) -> Self
+    where
+        F: FnOnce(&S) + Send + 'static,
+    {
+        self.shutdown = Some(Box::new(hook));
+        self
+    }
+
     /// Starts the service's GraphQL/UDP server. This function runs
-    /// without return.
+    /// until the service receives SIGTERM, at which point any requests
+    /// which have already arrived are answered and the shutdown hook is run
+    /// before it returns.
     ///
     /// # Panics
     ///
     /// The UDP interface will panic if the ip address and port provided
     /// cannot be bound (like if they are already in use), or if for some reason the socket fails
     /// to receive a message.
-    pub fn start(self) {
+    pub fn start(mut self) {
         let hosturl = self
             .config
             .hosturl()
@@ -167,48 +254,150 @@ where
             })
             .unwrap();
 
+        let signals = Signals::register()
+            .map_err(|err| {
+                error!("Failed to register signal handlers: {:?}", err);
+                err
+            })
+            .unwrap();
+
         let socket = UdpSocket::bind(&addr).unwrap();
+        // Wake up every so often to check for signals
+        socket.set_read_timeout(Some(SIGNAL_POLL_INTERVAL)).unwrap();
         info!("Listening on: {}", addr);
 
         let mut buf = vec![0; 4096].into_boxed_slice();
         loop {
+            if signals.terminate_requested() {
+                break;
+            }
+
+            if signals.take_reload() {
+                if let Some(config) =
+                    signals::reload(&self.reload, &self.config, &self.context.subsystem)
+                {
+                    self.config = config;
+                }
+            }
+
+            // A timeout just means there's nothing to do before checking for signals again
             if let Ok((size, peer)) = socket.recv_from(&mut buf) {
-                if let Ok(query) = String::from_utf8(buf[0..size].to_vec()) {
-                    let mut resp = match execute(
-                        &query,
-                        None,
-                        &self.root_node,
-                        &Variables::new(),
-                        &self.context,
-                    ) {
-                        Ok((val, errs)) => serde_cbor::to_vec(&CborGQLResponse {
-                            data: val,
-                            errors: errs,
-                        })
-                        .unwrap(),
-                        Err(e) => serde_cbor::to_vec(&CborGQLErrors { errors: e }).unwrap(),
-                    };
-
-                    if resp.len() > 64 * 1024 {
-                        error!("Graphql Response too large");
-                        resp = serde_cbor::to_vec(&CborGQLResponse {
-                            data: juniper::Value::Null,
-                            errors: vec![juniper::ExecutionError::at_origin(
-                                juniper::FieldError::new(
-                                    "CBOR Response too large",
-                                    juniper::Value::Null,
-                                ),
-                            )],
-                        })
-                        .unwrap();
-                    }
-
-                    if let Err(e) = socket.send_to(&resp, &peer) {
-                        error!("Failed to send udp response: {:?}", e);
-                    };
+                self.handle_request(&socket, &buf[0..size], peer);
+            }
+        }
+
+        // Answer anything which arrived before the service was told to stop
+        info!("Shutting down");
+        match socket.set_nonblocking(true) {
+            Ok(()) => {
+                while let Ok((size, peer)) = socket.recv_from(&mut buf) {
+                    self.handle_request(&socket, &buf[0..size], peer);
+                }
+            }
+            Err(err) => error!("Failed to drain pending requests: {:?}", err),
+        }
+
+        if let Some(hook) = self.shutdown.take() {
+            hook(&self.context.subsystem);
+        }
+    }
+
+    // Runs a single GraphQL request and sends the response back to the requester
+    fn handle_request(&mut self, socket: &UdpSocket, request: &[u8], peer: SocketAddr) {
+        let received = SystemTime::now();
+        let started = Instant::now();
+        if let Ok(query) = String::from_utf8(request.to_vec()) {
+            // Requests are handled one at a time, so the context only ever holds the trace ID
+            // of the current request
+            self.context.trace_id = trace::trace_id(&query).map(|id| id.to_owned());
+            let trace_id = self.context.trace_id.as_ref().map_or("-", |id| id.as_str());
+            if self.context.trace_id.is_some() {
+                info!("[{}] Request from {}", trace_id, peer);
+            }
+
+            let (mut resp, mut errors) = match execute(
+                &query,
+                None,
+                &self.root_node,
+                &Variables::new(),
+                &self.context,
+            ) {
+                Ok((val, errs)) => {
+                    let messages = errs
+                        .iter()
+                        .map(|err| err.error().message().to_owned())
+                        .collect();
+                    let resp = serde_cbor::to_vec(&CborGQLResponse {
+                        data: val,
+                        errors: errs,
+                    })
+                    .unwrap();
+                    (resp, messages)
                 }
+                Err(e) => {
+                    let messages = error_messages(&e);
+                    (
+                        serde_cbor::to_vec(&CborGQLErrors { errors: e }).unwrap(),
+                        messages,
+                    )
+                }
+            };
+
+            // Requests may ask for their response to be split across several datagrams
+            let datagram_size = chunked::datagram_size(&query);
+            let max_size = match datagram_size {
+                Some(_) => MAX_CHUNKED_RESPONSE_SIZE,
+                None => MAX_RESPONSE_SIZE,
+            };
+
+            if resp.len() > max_size {
+                error!("[{}] Graphql Response too large", trace_id);
+                errors = vec!["CBOR Response too large".to_owned()];
+                resp = serde_cbor::to_vec(&CborGQLResponse {
+                    data: juniper::Value::Null,
+                    errors: vec![juniper::ExecutionError::at_origin(field_error(
+                        ErrorCode::ResponseTooLarge,
+                        "CBOR Response too large",
+                    ))],
+                })
+                .unwrap();
+            }
+
+            let response_size = resp.len();
+            let datagrams = match datagram_size.and_then(|size| chunked::split(&resp, size)) {
+                Some(datagrams) => datagrams,
+                None => vec![resp],
+            };
+            for datagram in datagrams {
+                if let Err(e) = socket.send_to(&datagram, &peer) {
+                    error!("[{}] Failed to send udp response: {:?}", trace_id, e);
+                    break;
+                };
             }
+
+            if self.context.requests.is_enabled() {
+                self.context.requests.record(RequestRecord::new(
+                    received,
+                    started.elapsed(),
+                    peer.to_string(),
+                    self.context.trace_id.clone(),
+                    query,
+                    response_size,
+                    errors,
+                ));
+            }
+        }
+    }
+}
+
+// Gets the messages of the errors which stopped a request from running at all
+fn error_messages(error: &GraphQLError) -> Vec<String> {
+    match error {
+        GraphQLError::ParseError(err) => vec![err.item.to_string()],
+        GraphQLError::ValidationError(errs) => {
+            errs.iter().map(|err| err.message().to_owned()).collect()
         }
+        other => vec![format!("{:?}", other)],
     }
 }
 
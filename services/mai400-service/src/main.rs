@@ -426,6 +426,8 @@ use std::sync::Arc;
 fn main() -> MAIResult<()> {
     Logger::init("mai400-service").unwrap();
 
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
     Service::new(
         Config::new("mai400-service")
             .map_err(|err| {
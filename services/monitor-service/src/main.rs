@@ -17,7 +17,7 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
-//! Service for monitoring KubOS Linux processes, memory, and CPU usage
+//! Service for monitoring KubOS Linux processes, memory, CPU and network usage
 //!
 //! # GraphQL Schema
 //!
@@ -30,6 +30,8 @@
 //!     ping: String!
 //!     memInfo: MemInfo!
 //!     ps(pids: [Int!] = null): [ProcInfo!]!
+//!     interfaces: [NetInterface!]!
+//!     udpSockets(port: Int = null): [UdpSocket!]!
 //! }
 //!
 //! type MemInfo {
@@ -52,6 +54,30 @@
 //!     threads: Int
 //!     cmd: String
 //! }
+//!
+//! type NetInterface {
+//!     name: String!
+//!     rxBytes: Int!
+//!     rxPackets: Int!
+//!     rxErrors: Int!
+//!     rxDropped: Int!
+//!     txBytes: Int!
+//!     txPackets: Int!
+//!     txErrors: Int!
+//!     txDropped: Int!
+//! }
+//!
+//! type UdpSocket {
+//!     localAddress: String!
+//!     localPort: Int!
+//!     remoteAddress: String!
+//!     remotePort: Int!
+//!     txQueue: Int!
+//!     rxQueue: Int!
+//!     drops: Int!
+//!     pid: Int
+//!     process: String
+//! }
 //! ```
 
 #[macro_use]
@@ -60,14 +86,20 @@ extern crate juniper;
 use crate::schema::{MutationRoot, QueryRoot};
 use kubos_service::{Config, Logger, Service};
 use log::error;
+use std::time::Duration;
 
 mod meminfo;
 mod objects;
 #[macro_use]
 mod process;
+mod netstat;
 mod schema;
+mod telemetry;
 mod userinfo;
 
+// Seconds between forwarding network statistics to the telemetry service
+const DEFAULT_TELEMETRY_INTERVAL: u64 = 60;
+
 fn main() {
     Logger::init("kubos-monitor-service").unwrap();
 
@@ -77,6 +109,22 @@ fn main() {
             err
         })
         .unwrap();
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
+    // Network statistics are forwarded unless the interval is 0
+    let telemetry_interval = match config.get("telemetry_interval") {
+        Some(interval) => match interval.as_integer() {
+            Some(interval) if interval >= 0 => interval as u64,
+            _ => {
+                error!("Failed to parse telemetry_interval, using the default");
+                DEFAULT_TELEMETRY_INTERVAL
+            }
+        },
+        None => DEFAULT_TELEMETRY_INTERVAL,
+    };
+    if telemetry_interval > 0 {
+        telemetry::start(Duration::from_secs(telemetry_interval));
+    }
 
     Service::new(config, (), QueryRoot, MutationRoot).start();
 }
@@ -0,0 +1,352 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Network interface statistics and the UDP socket table
+//!
+//! Interface counters are read from /proc/net/dev, and UDP sockets from /proc/net/udp and
+//! /proc/net/udp6. The process which owns a socket is found by looking for the socket's inode
+//! among the `socket:[inode]` links in each process' /proc/[pid]/fd directory, so sockets owned by
+//! processes whose fds can't be read (eg. another user's, unless running as root) have no owner.
+
+use failure::format_err;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::process::{root_dir, running_pids};
+
+// Start of the target of a process' fd link to a socket, which is followed by the inode and "]"
+const SOCKET_LINK: &str = "socket:[";
+
+/// Counters for a network interface, as provided by the Linux /proc/net/dev file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InterfaceStats {
+    name: String,
+    rx_bytes: u64,
+    rx_packets: u64,
+    rx_errors: u64,
+    rx_dropped: u64,
+    tx_bytes: u64,
+    tx_packets: u64,
+    tx_errors: u64,
+    tx_dropped: u64,
+}
+
+impl InterfaceStats {
+    /// Convenience function that parses /proc/net/dev
+    pub fn from_proc() -> Result<Vec<Self>, failure::Error> {
+        let file = File::open(root_path!("proc", "net", "dev"))?;
+        Self::parse(BufReader::new(file))
+    }
+
+    /// Parse a String with the format of /proc/net/dev: two header lines, then a line for each
+    /// interface with its name and eight receive counters followed by eight transmit counters
+    pub fn parse<R>(dev: R) -> Result<Vec<Self>, failure::Error>
+    where
+        R: BufRead,
+    {
+        let mut interfaces = vec![];
+        for line in dev.lines().skip(2) {
+            let line = line?;
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or_default().trim();
+            let counters = parts
+                .next()
+                .ok_or_else(|| format_err!("Invalid interface format"))?
+                .split_whitespace()
+                .map(u64::from_str)
+                .collect::<Result<Vec<_>, _>>()?;
+            if counters.len() < 12 {
+                return Err(format_err!("Invalid interface format"));
+            }
+
+            interfaces.push(InterfaceStats {
+                name: name.to_owned(),
+                rx_bytes: counters[0],
+                rx_packets: counters[1],
+                rx_errors: counters[2],
+                rx_dropped: counters[3],
+                tx_bytes: counters[8],
+                tx_packets: counters[9],
+                tx_errors: counters[10],
+                tx_dropped: counters[11],
+            });
+        }
+        Ok(interfaces)
+    }
+
+    /// Name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Bytes received
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes
+    }
+    /// Packets received
+    pub fn rx_packets(&self) -> u64 {
+        self.rx_packets
+    }
+    /// Receive errors detected by the driver
+    pub fn rx_errors(&self) -> u64 {
+        self.rx_errors
+    }
+    /// Received packets dropped, eg. for lack of buffer space
+    pub fn rx_dropped(&self) -> u64 {
+        self.rx_dropped
+    }
+    /// Bytes transmitted
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes
+    }
+    /// Packets transmitted
+    pub fn tx_packets(&self) -> u64 {
+        self.tx_packets
+    }
+    /// Transmit errors detected by the driver
+    pub fn tx_errors(&self) -> u64 {
+        self.tx_errors
+    }
+    /// Packets dropped before they could be transmitted
+    pub fn tx_dropped(&self) -> u64 {
+        self.tx_dropped
+    }
+}
+
+/// An entry in the Linux /proc/net/udp or /proc/net/udp6 socket table
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdpSocketInfo {
+    local: SocketAddr,
+    remote: SocketAddr,
+    tx_queue: u64,
+    rx_queue: u64,
+    inode: u64,
+    drops: u64,
+    pid: Option<i32>,
+}
+
+impl UdpSocketInfo {
+    /// Reads the IPv4 and IPv6 UDP socket tables, along with the process owning each socket
+    pub fn from_proc() -> Result<Vec<Self>, failure::Error> {
+        let mut sockets = vec![];
+        for table in &["udp", "udp6"] {
+            match File::open(root_path!("proc", "net", table)) {
+                Ok(file) => sockets.extend(Self::parse(BufReader::new(file))?),
+                // The kernel may have been built without IPv6
+                Err(_) if *table == "udp6" => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let owners = socket_owners();
+        for socket in sockets.iter_mut() {
+            socket.pid = owners.get(&socket.inode).cloned();
+        }
+        Ok(sockets)
+    }
+
+    /// Parse a String with the format of /proc/net/udp or /proc/net/udp6: a header line, then
+    /// a line for each socket. The owning process isn't known from the table itself.
+    /// See http://man7.org/linux/man-pages/man5/proc.5.html for more information
+    pub fn parse<R>(table: R) -> Result<Vec<Self>, failure::Error>
+    where
+        R: BufRead,
+    {
+        let mut sockets = vec![];
+        for line in table.lines().skip(1) {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 13 {
+                return Err(format_err!("Invalid socket format"));
+            }
+
+            let mut queues = fields[4].splitn(2, ':');
+            let tx_queue = u64::from_str_radix(queues.next().unwrap_or_default(), 16)?;
+            let rx_queue = u64::from_str_radix(queues.next().unwrap_or_default(), 16)?;
+            sockets.push(UdpSocketInfo {
+                local: parse_address(fields[1])?,
+                remote: parse_address(fields[2])?,
+                tx_queue,
+                rx_queue,
+                inode: u64::from_str(fields[9])?,
+                drops: u64::from_str(fields[12])?,
+                pid: None,
+            });
+        }
+        Ok(sockets)
+    }
+
+    /// Address and port the socket is bound to
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+    /// Address and port the socket is connected to. Unspecified (port 0) if it isn't connected
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+    /// Bytes waiting to be sent
+    pub fn tx_queue(&self) -> u64 {
+        self.tx_queue
+    }
+    /// Bytes received, but not yet read by the owning process
+    pub fn rx_queue(&self) -> u64 {
+        self.rx_queue
+    }
+    /// Datagrams dropped because the socket's receive buffer was full
+    pub fn drops(&self) -> u64 {
+        self.drops
+    }
+    /// ID of the process which owns the socket, if it could be found
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+}
+
+// Addresses are given as the hex digits of each of their 32-bit words, in host byte order,
+// followed by the port in hex
+fn parse_address(field: &str) -> Result<SocketAddr, failure::Error> {
+    let mut parts = field.splitn(2, ':');
+    let address = parts.next().unwrap_or_default();
+    let port = u16::from_str_radix(parts.next().unwrap_or_default(), 16)?;
+    if !address.is_ascii() || address.len() % 8 != 0 {
+        return Err(format_err!("Invalid socket address {}", field));
+    }
+
+    let mut bytes = vec![];
+    for index in (0..address.len()).step_by(8) {
+        let word = u32::from_str_radix(&address[index..index + 8], 16)?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(format_err!("Invalid socket address {}", field)),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Maps the inode of each socket held open by a running process to the process' ID
+fn socket_owners() -> HashMap<u64, i32> {
+    let mut owners = HashMap::new();
+    for pid in running_pids().unwrap_or_default() {
+        let fds = match fs::read_dir(root_path!("proc", pid, "fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            let target = match fs::read_link(fd.path()) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            let target = target.to_string_lossy();
+            if !target.starts_with(SOCKET_LINK) || !target.ends_with(']') {
+                continue;
+            }
+            if let Ok(inode) = u64::from_str(&target[SOCKET_LINK.len()..target.len() - 1]) {
+                owners.insert(inode, pid);
+            }
+        }
+    }
+    owners
+}
+
+#[cfg(test)]
+#[allow(clippy::unreadable_literal)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interfaces_from_proc() {
+        let interfaces = InterfaceStats::from_proc().unwrap();
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].name(), "lo");
+        assert_eq!(
+            interfaces[1],
+            InterfaceStats {
+                name: "eth0".into(),
+                rx_bytes: 1362245104,
+                rx_packets: 1033891,
+                rx_errors: 3,
+                rx_dropped: 17,
+                tx_bytes: 96841320,
+                tx_packets: 412003,
+                tx_errors: 1,
+                tx_dropped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn interfaces_invalid() {
+        let dev: &[u8] = b"Inter-|\n face |\n  eth0: 1 2 3\n";
+        assert!(InterfaceStats::parse(dev).is_err());
+    }
+
+    #[test]
+    fn parse_addresses() {
+        let v4 = format!("{:08X}:0050", u32::from_ne_bytes([10, 0, 0, 7]));
+        assert_eq!(parse_address(&v4).unwrap(), "10.0.0.7:80".parse().unwrap());
+
+        let v6 = format!("{:024X}{:08X}:1FA3", 0, u32::from_ne_bytes([0, 0, 0, 1]));
+        assert_eq!(parse_address(&v6).unwrap(), "[::1]:8099".parse().unwrap());
+
+        assert!(parse_address("7F0001:0050").is_err());
+        assert!(parse_address("0100007F").is_err());
+    }
+
+    // The test tables were taken from a little-endian system
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn udp_sockets_from_proc() {
+        let sockets = UdpSocketInfo::from_proc().unwrap();
+        assert_eq!(sockets.len(), 4);
+        assert_eq!(
+            sockets[0],
+            UdpSocketInfo {
+                local: "127.0.0.1:8081".parse().unwrap(),
+                remote: "0.0.0.0:0".parse().unwrap(),
+                tx_queue: 0,
+                rx_queue: 0,
+                inode: 15823,
+                drops: 0,
+                pid: Some(761),
+            }
+        );
+
+        assert_eq!(sockets[1].local(), "0.0.0.0:68".parse().unwrap());
+        assert_eq!(sockets[1].rx_queue(), 832);
+        assert_eq!(sockets[1].drops(), 12);
+        assert_eq!(sockets[1].pid(), Some(1492));
+
+        assert_eq!(sockets[2].remote(), "127.0.0.1:8081".parse().unwrap());
+        assert_eq!(sockets[2].pid(), None);
+
+        assert_eq!(sockets[3].local(), "[::1]:8099".parse().unwrap());
+        assert_eq!(sockets[3].inode, 17000);
+    }
+
+    #[test]
+    fn udp_sockets_invalid() {
+        let table: &[u8] = b"  sl  local_address\n  1: 0100007F:1F91 00000000:0000 07\n";
+        assert!(UdpSocketInfo::parse(table).is_err());
+    }
+}
@@ -14,6 +14,7 @@
 // limitations under the License.
 //
 use crate::meminfo::MemInfo;
+use crate::netstat::{InterfaceStats, UdpSocketInfo};
 use crate::process::ProcStat;
 use crate::userinfo::UserInfo;
 
@@ -102,3 +103,98 @@ graphql_object!(PSResponse: () |&self| {
         })
     }
 });
+
+pub struct InterfaceResponse {
+    pub stats: InterfaceStats,
+}
+
+graphql_object!(InterfaceResponse: () |&self| {
+    field name(&executor) -> String {
+        self.stats.name().to_owned()
+    }
+
+    field rx_bytes(&executor) -> i32 {
+        self.stats.rx_bytes() as i32
+    }
+
+    field rx_packets(&executor) -> i32 {
+        self.stats.rx_packets() as i32
+    }
+
+    field rx_errors(&executor) -> i32 {
+        self.stats.rx_errors() as i32
+    }
+
+    field rx_dropped(&executor) -> i32 {
+        self.stats.rx_dropped() as i32
+    }
+
+    field tx_bytes(&executor) -> i32 {
+        self.stats.tx_bytes() as i32
+    }
+
+    field tx_packets(&executor) -> i32 {
+        self.stats.tx_packets() as i32
+    }
+
+    field tx_errors(&executor) -> i32 {
+        self.stats.tx_errors() as i32
+    }
+
+    field tx_dropped(&executor) -> i32 {
+        self.stats.tx_dropped() as i32
+    }
+});
+
+pub struct UdpSocketResponse {
+    pub socket: UdpSocketInfo,
+    pub process: Option<String>,
+}
+
+impl UdpSocketResponse {
+    pub fn new(socket: UdpSocketInfo) -> UdpSocketResponse {
+        let process = socket
+            .pid()
+            .and_then(|pid| ProcStat::from_pid(pid).ok())
+            .map(|stat| stat.name().to_owned());
+        UdpSocketResponse { socket, process }
+    }
+}
+
+graphql_object!(UdpSocketResponse: () |&self| {
+    field local_address(&executor) -> String {
+        self.socket.local().ip().to_string()
+    }
+
+    field local_port(&executor) -> i32 {
+        i32::from(self.socket.local().port())
+    }
+
+    field remote_address(&executor) -> String {
+        self.socket.remote().ip().to_string()
+    }
+
+    field remote_port(&executor) -> i32 {
+        i32::from(self.socket.remote().port())
+    }
+
+    field tx_queue(&executor) -> i32 {
+        self.socket.tx_queue() as i32
+    }
+
+    field rx_queue(&executor) -> i32 {
+        self.socket.rx_queue() as i32
+    }
+
+    field drops(&executor) -> i32 {
+        self.socket.drops() as i32
+    }
+
+    field pid(&executor) -> Option<i32> {
+        self.socket.pid()
+    }
+
+    field process(&executor) -> Option<String> {
+        self.process.clone()
+    }
+});
@@ -211,6 +211,11 @@ impl ProcStat {
         self.state
     }
 
+    /// The filename of the executable, as known to the kernel (at most 15 characters)
+    pub fn name(&self) -> &str {
+        &self.comm
+    }
+
     /// The PID of the parent of this process
     pub fn parent_pid(&self) -> i32 {
         self.ppid
@@ -359,6 +364,7 @@ mod tests {
         assert!(stat.is_ok());
 
         let stat = stat.unwrap();
+        assert_eq!(stat.name(), "sh");
         assert_eq!(stat.state(), 'S');
         assert_eq!(stat.parent_pid(), 1);
         assert_eq!(stat.mem_usage(), 2981888);
@@ -14,10 +14,13 @@
 // limitations under the License.
 //
 
-use juniper::{self, FieldError, FieldResult};
+use juniper::FieldResult;
 use kubos_service;
+use kubos_service::errors::{field_error, ErrorCode};
+use kubos_service::RequestRecord;
 
 use crate::meminfo;
+use crate::netstat;
 use crate::objects::*;
 use crate::process;
 
@@ -36,7 +39,7 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
 
         meminfo::MemInfo::from_proc()
             .map(|info| MemInfoResponse { info })
-            .map_err(|err| FieldError::new(err, juniper::Value::null()))
+            .map_err(|err| field_error(ErrorCode::Internal, err))
     }
 
     field ps(&executor, pids: Option<Vec<i32>>) -> FieldResult<Vec<PSResponse>>
@@ -48,6 +51,31 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
 
         Ok(pids_vec.into_iter().map(PSResponse::new).collect())
     }
+
+    field interfaces(&executor) -> FieldResult<Vec<InterfaceResponse>>
+    {
+        let interfaces = netstat::InterfaceStats::from_proc()
+            .map_err(|err| field_error(ErrorCode::Internal, err))?;
+
+        Ok(interfaces.into_iter().map(|stats| InterfaceResponse { stats }).collect())
+    }
+
+    field udp_sockets(&executor, port: Option<i32>) -> FieldResult<Vec<UdpSocketResponse>>
+    {
+        let sockets = netstat::UdpSocketInfo::from_proc()
+            .map_err(|err| field_error(ErrorCode::Internal, err))?;
+
+        Ok(sockets
+            .into_iter()
+            .filter(|socket| port.map_or(true, |port| i32::from(socket.local().port()) == port))
+            .map(UdpSocketResponse::new)
+            .collect())
+    }
+
+    field recent_requests(&executor) -> FieldResult<Vec<RequestRecord>>
+    {
+        Ok(executor.context().recent_requests())
+    }
 });
 
 pub struct MutationRoot;
@@ -0,0 +1,162 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Forwarding of network statistics to the telemetry service
+//!
+//! Every `telemetry_interval` seconds, the service sends the telemetry service's `direct_port`
+//! the counters of each network interface, under the `net-interfaces` subsystem with parameters
+//! named `{interface}.{counter}` (eg. `eth0.rx_errors`), and a summary of each bound UDP port,
+//! under the `net-udp` subsystem:
+//!
+//! - `{port}.sockets` - The number of sockets bound to the port. More than one usually means
+//!   two services were configured with the same port
+//! - `{port}.pid` - The ID of the process owning the first of them, or -1 if it's unknown
+//! - `{port}.drops` - The number of datagrams the port's sockets have dropped
+
+use chrono::{DateTime, Utc};
+use kubos_service::Config;
+use log::{debug, error, info};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use crate::netstat::{InterfaceStats, UdpSocketInfo};
+
+const INTERFACES_SUBSYSTEM: &str = "net-interfaces";
+const UDP_SUBSYSTEM: &str = "net-udp";
+
+// A data point in the form the telemetry service's direct_port expects:
+// timestamp, subsystem, parameter and value
+#[derive(Serialize)]
+struct DataPoint(DateTime<Utc>, &'static str, String, i32);
+
+// Port the telemetry service accepts direct UDP DataPoints on, if it's configured
+fn telemetry_port() -> Option<u16> {
+    let config = Config::new("telemetry-service").ok()?;
+    config
+        .get("direct_port")
+        .and_then(|port| port.as_integer())
+        .map(|port| port as u16)
+}
+
+fn interface_points(now: DateTime<Utc>, points: &mut Vec<DataPoint>) {
+    let interfaces = match InterfaceStats::from_proc() {
+        Ok(interfaces) => interfaces,
+        Err(err) => {
+            debug!("Couldn't read interface stats: {}", err);
+            return;
+        }
+    };
+
+    for stats in interfaces {
+        let counters = [
+            ("rx_bytes", stats.rx_bytes()),
+            ("rx_packets", stats.rx_packets()),
+            ("rx_errors", stats.rx_errors()),
+            ("rx_dropped", stats.rx_dropped()),
+            ("tx_bytes", stats.tx_bytes()),
+            ("tx_packets", stats.tx_packets()),
+            ("tx_errors", stats.tx_errors()),
+            ("tx_dropped", stats.tx_dropped()),
+        ];
+        for (counter, value) in counters.iter() {
+            let parameter = format!("{}.{}", stats.name(), counter);
+            points.push(DataPoint(
+                now,
+                INTERFACES_SUBSYSTEM,
+                parameter,
+                *value as i32,
+            ));
+        }
+    }
+}
+
+fn udp_points(now: DateTime<Utc>, points: &mut Vec<DataPoint>) {
+    let sockets = match UdpSocketInfo::from_proc() {
+        Ok(sockets) => sockets,
+        Err(err) => {
+            debug!("Couldn't read UDP socket table: {}", err);
+            return;
+        }
+    };
+
+    // Number of sockets, first owner and total drops of each port
+    let mut ports: BTreeMap<u16, (i32, i32, u64)> = BTreeMap::new();
+    for socket in sockets {
+        let port = ports
+            .entry(socket.local().port())
+            .or_insert((0, socket.pid().unwrap_or(-1), 0));
+        port.0 += 1;
+        port.2 += socket.drops();
+    }
+
+    for (port, (sockets, pid, drops)) in ports {
+        points.push(DataPoint(
+            now,
+            UDP_SUBSYSTEM,
+            format!("{}.sockets", port),
+            sockets,
+        ));
+        points.push(DataPoint(now, UDP_SUBSYSTEM, format!("{}.pid", port), pid));
+        points.push(DataPoint(
+            now,
+            UDP_SUBSYSTEM,
+            format!("{}.drops", port),
+            drops as i32,
+        ));
+    }
+}
+
+// Starts a thread which forwards the network statistics every `interval`. Failures are only
+// logged, since the statistics can still be queried if the telemetry service is unavailable
+pub fn start(interval: Duration) {
+    let port = match telemetry_port() {
+        Some(port) => port,
+        None => {
+            info!("Telemetry direct_port not configured, network statistics won't be forwarded");
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("Couldn't create socket for network statistics: {}", err);
+                return;
+            }
+        };
+
+        loop {
+            thread::sleep(interval);
+
+            let now = Utc::now();
+            let mut points = vec![];
+            interface_points(now, &mut points);
+            udp_points(now, &mut points);
+            match serde_cbor::to_vec(&points) {
+                Ok(buf) => {
+                    if let Err(err) = socket.send_to(&buf, ("0.0.0.0", port)) {
+                        debug!("Couldn't send network statistics to telemetry: {}", err);
+                    }
+                }
+                Err(_) => debug!("Couldn't serialize network statistics"),
+            }
+        }
+    });
+}
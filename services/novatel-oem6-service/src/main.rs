@@ -341,6 +341,8 @@ fn main() -> OEMResult<()> {
             err
         })
         .unwrap();
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
+
     let bus = config
         .get("bus")
         .ok_or_else(|| {
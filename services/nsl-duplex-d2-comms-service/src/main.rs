@@ -243,6 +243,7 @@ fn main() -> NslDuplexCommsResult<()> {
         error!("Failed to load service config: {:?}", err);
         err
     })?;
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
 
     let bus = service_config
         .get("bus")
@@ -290,12 +291,15 @@ fn main() -> NslDuplexCommsResult<()> {
     // Initialize new `CommsTelemetry` object.
     let telem = Arc::new(Mutex::new(CommsTelemetry::default()));
 
+    // Keep hold of the port controls, so ports can be disabled over GraphQL.
+    let ports = controls.ports.clone();
+
     // Start communication service.
     info!("NSL Duplex Communications Service starting on {}", bus);
     CommsService::start::<Arc<Mutex<DuplexComms>>, SpacePacket>(controls, &telem.clone())?;
 
     // Start up graphql server
-    let subsystem = Subsystem::new(telem, duplex_comms);
+    let subsystem = Subsystem::new(telem, ports, duplex_comms);
     Service::new(service_config, subsystem, QueryRoot, MutationRoot).start();
 
     Ok(())
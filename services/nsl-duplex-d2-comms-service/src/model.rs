@@ -20,10 +20,14 @@
 //!
 
 use crate::comms::DuplexComms;
-use comms_service::CommsTelemetry;
+use comms_service::{
+    reset_telemetry, snapshot_telemetry, CommsTelemetry, DestinationTelemetry, ErrorCount,
+    ErrorRecord, PortControls, SourceTelemetry, WriterTelemetry,
+};
 use nsl_duplex_d2::{GeoRecord, StateOfHealth};
 use std::convert::From;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(GraphQLObject)]
 pub struct GeoRecordResponse {
@@ -89,15 +93,72 @@ impl From<StateOfHealth> for StateOfHealthResponse {
     }
 }
 
+// Telemetry counts at a single point in time
+#[derive(GraphQLObject)]
+pub struct TelemetrySnapshot {
+    failed_packets_up: i32,
+    failed_packets_down: i32,
+    packets_up: i32,
+    packets_down: i32,
+    cached_responses: i32,
+    shed_packets: i32,
+    muted_packets_up: i32,
+    muted_packets_down: i32,
+    uplink_disabled: bool,
+    uplink_disabled_until: Option<f64>,
+    disabled_downlink_ports: Vec<i32>,
+    fec_corrected_blocks: i32,
+    fec_uncorrectable_blocks: i32,
+    destinations: Vec<DestinationTelemetry>,
+    writers: Vec<WriterTelemetry>,
+    sources: Vec<SourceTelemetry>,
+    errors: Vec<ErrorRecord>,
+    error_counts: Vec<ErrorCount>,
+}
+
+impl From<CommsTelemetry> for TelemetrySnapshot {
+    fn from(item: CommsTelemetry) -> TelemetrySnapshot {
+        TelemetrySnapshot {
+            failed_packets_up: item.failed_packets_up,
+            failed_packets_down: item.failed_packets_down,
+            packets_up: item.packets_up,
+            packets_down: item.packets_down,
+            cached_responses: item.cached_responses,
+            shed_packets: item.shed_packets,
+            muted_packets_up: item.muted_packets_up,
+            muted_packets_down: item.muted_packets_down,
+            uplink_disabled: item.uplink_disabled,
+            uplink_disabled_until: item.uplink_disabled_until,
+            disabled_downlink_ports: item.disabled_downlink_ports,
+            fec_corrected_blocks: item.fec_corrected_blocks,
+            fec_uncorrectable_blocks: item.fec_uncorrectable_blocks,
+            destinations: item.destinations,
+            writers: item.writers,
+            sources: item.sources,
+            errors: item.errors,
+            error_counts: item.error_counts,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Subsystem {
     telem: Arc<Mutex<CommsTelemetry>>,
+    ports: PortControls,
     pub duplex: Arc<Mutex<DuplexComms>>,
 }
 
 impl Subsystem {
-    pub fn new(telem: Arc<Mutex<CommsTelemetry>>, duplex: Arc<Mutex<DuplexComms>>) -> Subsystem {
-        Subsystem { telem, duplex }
+    pub fn new(
+        telem: Arc<Mutex<CommsTelemetry>>,
+        ports: PortControls,
+        duplex: Arc<Mutex<DuplexComms>>,
+    ) -> Subsystem {
+        Subsystem {
+            telem,
+            ports,
+            duplex,
+        }
     }
 
     pub fn failed_packets_up(&self) -> Result<i32, String> {
@@ -128,13 +189,49 @@ impl Subsystem {
         }
     }
 
-    pub fn errors(&self) -> Result<Vec<String>, String> {
+    pub fn errors(&self) -> Result<Vec<ErrorRecord>, String> {
         match self.telem.lock() {
             Ok(data) => Ok(data.errors.to_owned()),
             Err(_) => Err("Failed to lock telemetry".to_owned()),
         }
     }
 
+    pub fn telemetry(&self) -> Result<TelemetrySnapshot, String> {
+        snapshot_telemetry(&self.telem)
+            .map(TelemetrySnapshot::from)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn reset_telemetry(&self) -> Result<TelemetrySnapshot, String> {
+        reset_telemetry(&self.telem)
+            .map(TelemetrySnapshot::from)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn set_uplink_enabled(
+        &self,
+        enabled: bool,
+        duration_secs: Option<i32>,
+    ) -> Result<bool, String> {
+        let result = if enabled {
+            self.ports.enable_uplink()
+        } else {
+            let duration = duration_secs.map(|secs| Duration::from_secs(secs.max(0) as u64));
+            self.ports.disable_uplink(duration)
+        };
+        result.map(|_| true).map_err(|err| err.to_string())
+    }
+
+    pub fn set_downlink_port_enabled(&self, port: i32, enabled: bool) -> Result<bool, String> {
+        if port < 0 || port > i32::from(u16::max_value()) {
+            return Err(format!("Invalid port: {}", port));
+        }
+        self.ports
+            .set_downlink_enabled(port as u16, enabled)
+            .map(|_| true)
+            .map_err(|err| err.to_string())
+    }
+
     pub fn get_alive(&self) -> Result<bool, String> {
         match self.duplex.lock() {
             Ok(duplex) => Ok(duplex.radio.get_alive().map_err(|e| e.to_string())?),
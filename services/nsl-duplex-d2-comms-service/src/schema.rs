@@ -19,7 +19,8 @@
 //! telemetry information.
 //!
 
-use crate::model::{GeoRecordResponse, StateOfHealthResponse, Subsystem};
+use crate::model::{GeoRecordResponse, StateOfHealthResponse, Subsystem, TelemetrySnapshot};
+use comms_service::ErrorRecord;
 use juniper::FieldResult;
 
 type Context = kubos_service::Context<Subsystem>;
@@ -133,27 +134,145 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
         Ok(executor.context().subsystem().packets_down()?)
     }
 
-    // Request errors that have occured
+    // Request the most recent errors that have occured, oldest first.
+    // Repeats of the same error are counted in a single record.
     //
     // Query
     //
     // {
-    //     errors
+    //     errors {
+    //         timestamp
+    //         kind
+    //         message
+    //         count
+    //     }
     // }
     //
     // Response
     //
     // {
     //     "data":{
-    //                "errors" : ["A UDP header was unable to be correctly parsed"]
+    //                "errors" : [
+    //                    {
+    //                        "timestamp" : 1590000000.25,
+    //                        "kind" : "HeaderParsing",
+    //                        "message" : "A UDP header was unable to be correctly parsed.",
+    //                        "count" : 3
+    //                    }
+    //                ]
     //            },
     //     "errors" : ""
     // }
-    field errors(&executor) -> FieldResult<Vec<String>>
+    field errors(&executor) -> FieldResult<Vec<ErrorRecord>>
     {
         Ok(executor.context().subsystem().errors()?)
     }
 
+    // Request all telemetry counts at once, so they're consistent with each other
+    //
+    // Query
+    //
+    // {
+    //     telemetry {
+    //         packetsUp
+    //         packetsDown
+    //         failedPacketsUp
+    //         failedPacketsDown
+    //         cachedResponses
+    //         shedPackets
+    //         mutedPacketsUp
+    //         mutedPacketsDown
+    //         uplinkDisabled
+    //         uplinkDisabledUntil
+    //         disabledDownlinkPorts
+    //         fecCorrectedBlocks
+    //         fecUncorrectableBlocks
+    //         destinations {
+    //             port
+    //             reachable
+    //             consecutiveFailures
+    //             rejected
+    //         }
+    //         writers {
+    //             index
+    //             packetsDown
+    //             failedPacketsDown
+    //         }
+    //         sources {
+    //             port
+    //             address
+    //             packetsDown
+    //             bytesDown
+    //             failedPacketsDown
+    //             shedPackets
+    //         }
+    //         errors {
+    //             timestamp
+    //             kind
+    //             message
+    //             count
+    //         }
+    //         errorCounts {
+    //             kind
+    //             count
+    //         }
+    //     }
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "telemetry" : {
+    //                    "packetsUp" : 12,
+    //                    "packetsDown" : 10,
+    //                    "failedPacketsUp" : 0,
+    //                    "failedPacketsDown" : 0,
+    //                    "cachedResponses" : 2,
+    //                    "shedPackets" : 0,
+    //                    "mutedPacketsUp" : 0,
+    //                    "mutedPacketsDown" : 4,
+    //                    "uplinkDisabled" : false,
+    //                    "uplinkDisabledUntil" : null,
+    //                    "disabledDownlinkPorts" : [14012],
+    //                    "fecCorrectedBlocks" : 0,
+    //                    "fecUncorrectableBlocks" : 0,
+    //                    "destinations" : [
+    //                        {
+    //                            "port" : 8000,
+    //                            "reachable" : true,
+    //                            "consecutiveFailures" : 0,
+    //                            "rejected" : 0
+    //                        }
+    //                    ],
+    //                    "writers" : [
+    //                        {
+    //                            "index" : 0,
+    //                            "packetsDown" : 10,
+    //                            "failedPacketsDown" : 0
+    //                        }
+    //                    ],
+    //                    "sources" : [
+    //                        {
+    //                            "port" : 14011,
+    //                            "address" : "127.0.0.1:41234",
+    //                            "packetsDown" : 8,
+    //                            "bytesDown" : 2048,
+    //                            "failedPacketsDown" : 0,
+    //                            "shedPackets" : 0
+    //                        }
+    //                    ],
+    //                    "errors" : [],
+    //                    "errorCounts" : []
+    //                }
+    //            },
+    //     "errors" : ""
+    // }
+    field telemetry(&executor) -> FieldResult<TelemetrySnapshot>
+    {
+        Ok(executor.context().subsystem().telemetry()?)
+    }
+
     // Request current modem health information
     //
     // Query
@@ -277,6 +396,34 @@ pub struct MutationRoot;
 
 // Base GraphQL mutation model
 graphql_object!(MutationRoot: Context as "Mutation" |&self| {
+    // Fetch all telemetry counts and reset them to zero, in one step.
+    // Sending this at the end of each pass gives that pass's statistics.
+    //
+    // Mutation
+    //
+    // mutation {
+    //     resetTelemetry {
+    //         packetsUp
+    //         packetsDown
+    //     }
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "resetTelemetry" : {
+    //                    "packetsUp" : 12,
+    //                    "packetsDown" : 10
+    //                }
+    //            },
+    //     "errors" : ""
+    // }
+    field reset_telemetry(&executor) -> FieldResult<TelemetrySnapshot>
+    {
+        Ok(executor.context().subsystem().reset_telemetry()?)
+    }
+
     // Execute a trivial command against the system
     //
     // Mutation
@@ -297,4 +444,52 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
     {
         Ok(executor.context().subsystem().get_alive()?)
     }
+
+    // Disable or enable the uplink. While it's disabled, frames received by the radio are
+    // dropped. Disabling it cuts the ground off, so it can be given a duration (in seconds)
+    // after which it's enabled again by itself. The setting is kept across restarts if
+    // `port_state_file` is configured.
+    //
+    // Mutation
+    //
+    // mutation {
+    //     setUplinkEnabled(enabled: false, durationSecs: 600)
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "setUplinkEnabled": true
+    //            },
+    //     "errors" : ""
+    // }
+    field set_uplink_enabled(&executor, enabled: bool, duration_secs: Option<i32>)
+        -> FieldResult<bool>
+    {
+        Ok(executor.context().subsystem().set_uplink_enabled(enabled, duration_secs)?)
+    }
+
+    // Disable or enable one of the downlink ports. While a port is disabled, packets sent to
+    // it are dropped rather than downlinked. The setting is kept across restarts if
+    // `port_state_file` is configured.
+    //
+    // Mutation
+    //
+    // mutation {
+    //     setDownlinkPortEnabled(port: 14011, enabled: false)
+    // }
+    //
+    // Response
+    //
+    // {
+    //     "data":{
+    //                "setDownlinkPortEnabled": true
+    //            },
+    //     "errors" : ""
+    // }
+    field set_downlink_port_enabled(&executor, port: i32, enabled: bool) -> FieldResult<bool>
+    {
+        Ok(executor.context().subsystem().set_downlink_port_enabled(port, enabled)?)
+    }
 });
@@ -18,17 +18,49 @@
 //! Definitions and functions for dealing with scheduled app execution
 //!
 
+use crate::event::telemetry_port;
+use crate::process::TaskProcesses;
 use flat_db::DataPoint;
 use juniper::GraphQLObject;
-use kubos_service::Config;
+use kubos_system::rlimit::set_rlimit;
 use log::{debug, error, info, warn};
 // use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::time::{Duration, Instant};
+use std::{mem, ptr};
 use tokio::net::UdpSocket;
 use tokio::process::Command;
+use tokio::select;
 use tokio::time::delay_for;
 
+// Times an app is attempted before giving up on it
+const APP_ATTEMPTS: i32 = 3;
+// How often a running app's peak memory use is read. Once the app has been reaped,
+// its entry in /proc is gone
+const RSS_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Telemetry subsystems the details of each app exit are stored under, with the task's ID
+// as the parameter
+const EXIT_SUBSYSTEM: &str = "app-exit";
+const RUNTIME_SUBSYSTEM: &str = "app-runtime";
+const PEAK_RSS_SUBSYSTEM: &str = "app-peak-rss";
+const RETRIES_SUBSYSTEM: &str = "app-retries";
+
+// How a run of an app went, as stored in telemetry
+struct ExitDetails {
+    code: i32,
+    runtime: Duration,
+    // Peak resident set size, in kilobytes, if it could be read while the app was running
+    peak_rss: Option<u64>,
+    // Failed attempts before the app ran
+    retries: i32,
+}
+
 // Configuration used for execution of an app
 #[derive(Clone, Debug, GraphQLObject, Serialize, Deserialize)]
 pub struct App {
@@ -37,11 +69,123 @@ pub struct App {
     pub config: Option<String>,
 }
 
+// Restrictions on the process an app is run in
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecLimits {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub nice: Option<i32>,
+    // Maximum address space, in bytes
+    pub max_memory: Option<u64>,
+    // Maximum CPU time, in seconds
+    pub max_cpu: Option<u64>,
+}
+
+impl ExecLimits {
+    fn apply(&self, cmd: &mut std::process::Command) {
+        // The group has to be set first, while the child still has permission to change it
+        if let Some(gid) = self.gid {
+            cmd.gid(gid);
+        }
+        if let Some(uid) = self.uid {
+            cmd.uid(uid);
+        }
+
+        if self.nice.is_none() && self.max_memory.is_none() && self.max_cpu.is_none() {
+            return;
+        }
+
+        // Runs in the child, after the user has been switched, just before the app is executed.
+        // Only async-signal-safe calls can be made here.
+        let limits = self.clone();
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(nice) = limits.nice {
+                    if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(bytes) = limits.max_memory {
+                    set_rlimit(libc::RLIMIT_AS, bytes)?;
+                }
+                if let Some(secs) = limits.max_cpu {
+                    set_rlimit(libc::RLIMIT_CPU, secs)?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// Looks up a user by name or numeric ID, returning their UID and primary GID
+pub fn lookup_user(user: &str) -> Result<(u32, u32), String> {
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+
+    let ret = match user.parse::<u32>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| format!("Invalid user '{}'", user))?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+
+    if ret != 0 || result.is_null() {
+        return Err(format!("User '{}' not found", user));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+// Looks up a group by name or numeric ID, returning its GID
+pub fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::group = ptr::null_mut();
+    let name = CString::new(group).map_err(|_| format!("Invalid group '{}'", group))?;
+
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if ret != 0 || result.is_null() {
+        return Err(format!("Group '{}' not found", group));
+    }
+    Ok(grp.gr_gid)
+}
+
 impl App {
-    pub async fn execute(&self, id: Option<i32>) {
+    // Runs the app, with the config variables of the mode in effect exported to it
+    pub async fn execute(
+        &self,
+        id: Option<i32>,
+        limits: &ExecLimits,
+        variables: &BTreeMap<String, String>,
+        processes: &TaskProcesses,
+    ) {
         info!("Start app {:?} {}", &id, self.name);
 
-        let mut retry = 3;
+        let mut retry = APP_ATTEMPTS;
 
         loop {
             if retry <= 0 {
@@ -49,19 +193,42 @@ impl App {
                 break;
             }
 
-            let mut cmd = Command::new(self.name.clone());
+            let mut std_cmd = std::process::Command::new(self.name.clone());
+            std_cmd.envs(variables);
 
             let path_var =
                 std::env::var("PATH").unwrap_or(String::from("/sbin:/usr/sbin:/bin:/usr/bin"));
             let new_path = format!("{}:/usr/local/sbin/", path_var);
-            cmd.env("PATH", new_path);
+            std_cmd.env("PATH", new_path);
+            limits.apply(&mut std_cmd);
+
+            let mut cmd = Command::from(std_cmd);
 
             if let Some(args) = &self.args {
                 // let cmd_args: Vec<String> = args.iter().map(|x| format!("{}", x)).collect();
                 cmd.args(args);
             };
 
-            match cmd.status().await {
+            // Keep track of the running process so it can be killed if the task is aborted
+            let started = Instant::now();
+            let mut peak_rss = None;
+            let status = match cmd.spawn() {
+                Ok(mut child) => {
+                    let pid = child.id();
+                    let _guard = processes.track(pid, id);
+                    loop {
+                        peak_rss = read_peak_rss(pid).max(peak_rss);
+                        select! {
+                            status = &mut child => break status,
+                            _ = delay_for(RSS_SAMPLE_INTERVAL) => {}
+                        }
+                    }
+                }
+                Err(err) => Err(err),
+            };
+            let runtime = started.elapsed();
+
+            match status {
                 Ok(status) => {
                     let code = match status.code() {
                         Some(a) => a,
@@ -77,7 +244,13 @@ impl App {
                     };
                     info!("App {:?} returned code {} {:?}", id, code, status.code());
                     if let Some(id) = id {
-                        log_status_code_to_telemetry(id, code).await;
+                        let details = ExitDetails {
+                            code,
+                            runtime,
+                            peak_rss,
+                            retries: APP_ATTEMPTS - retry,
+                        };
+                        log_status_code_to_telemetry(id, &details).await;
                     }
 
                     break;
@@ -98,26 +271,42 @@ impl App {
     }
 }
 
-async fn log_status_code_to_telemetry(id: i32, code: i32) {
-    let config = match Config::new("telemetry-service") {
-        Ok(c) => c,
-        Err(_) => {
-            debug!("Telemetry service config not found");
-            return;
-        }
-    };
+// Reads the high water mark of a process's resident set size, in kilobytes
+fn read_peak_rss(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
 
-    let port = match config.get("direct_port").map(|p| p.as_integer()).flatten() {
-        Some(port) => port as u16,
-        None => {
-            debug!("Telemetry direct_port not found");
-            return;
-        }
+// Sends the exit code, runtime (in milliseconds), peak RSS (in kilobytes) and retry count of
+// an app's run to the telemetry service, in a single datagram
+async fn log_status_code_to_telemetry(id: i32, details: &ExitDetails) {
+    let port = match telemetry_port() {
+        Some(port) => port,
+        None => return,
     };
 
+    let parameter = id.to_string();
+    let runtime = details.runtime.as_millis().min(i32::max_value() as u128) as i32;
+    let mut dps = vec![
+        DataPoint::now(EXIT_SUBSYSTEM, &parameter, details.code.into()),
+        DataPoint::now(RUNTIME_SUBSYSTEM, &parameter, runtime.into()),
+        DataPoint::now(RETRIES_SUBSYSTEM, &parameter, details.retries.into()),
+    ];
+    if let Some(peak_rss) = details.peak_rss {
+        let peak_rss = peak_rss.min(i32::max_value() as u64) as i32;
+        dps.push(DataPoint::now(
+            PEAK_RSS_SUBSYSTEM,
+            &parameter,
+            peak_rss.into(),
+        ));
+    }
+
     if let Ok(mut socket) = UdpSocket::bind("0.0.0.0:0").await {
-        let dp = DataPoint::now("app-exit", &format!("{}", id), code.into());
-        if let Ok(buf) = serde_cbor::to_vec(&dp) {
+        if let Ok(buf) = serde_cbor::to_vec(&dps) {
             if let Err(e) = socket.send_to(&buf, ("0.0.0.0", port)).await {
                 debug!("Couldn't send DataPoint to Telemetry service:{:?}", e);
             }
@@ -0,0 +1,257 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//!
+//! Boot counter and cumulative uptime, which tasks can be limited by
+//!
+
+use crate::error::SchedulerError;
+use chrono::Duration;
+use juniper::GraphQLObject;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+// Name of the file in the schedules dir which the boot record is kept in
+pub const BOOT_FILE: &str = "boot.json";
+// Changes on every boot of the kernel, but not when the service restarts
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const UPTIME_PATH: &str = "/proc/uptime";
+// How often the uptime of the current boot is saved. Up to this much uptime is lost
+// whenever the OBC resets
+const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Boot count and uptime, as saved in the schedules dir
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BootRecord {
+    // Number of boots seen, including the current one
+    pub count: u32,
+    // ID of the boot which `boot_uptime` belongs to, if the kernel provides one
+    pub boot_id: Option<String>,
+    // Total uptime of all earlier boots, in seconds
+    pub previous_uptime: u64,
+    // Uptime of the recorded boot when it was last saved, in seconds
+    pub boot_uptime: u64,
+}
+
+impl BootRecord {
+    // Records a boot with the given ID, unless it's the one already recorded.
+    // Without an ID, every start of the service counts as a boot.
+    // Returns whether a new boot was recorded.
+    pub fn boot(&mut self, boot_id: Option<String>) -> bool {
+        if boot_id.is_some() && boot_id == self.boot_id {
+            return false;
+        }
+        self.count = self.count.saturating_add(1);
+        self.boot_id = boot_id;
+        self.previous_uptime += self.boot_uptime;
+        self.boot_uptime = 0;
+        true
+    }
+
+    // Uptime over all boots, in seconds
+    pub fn uptime(&self) -> u64 {
+        self.previous_uptime + self.boot_uptime
+    }
+}
+
+// Boot count and cumulative uptime at a moment in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootStatus {
+    // Number of boots seen, including the current one
+    pub count: u32,
+    // Uptime over all boots
+    pub uptime: Duration,
+}
+
+// Boot count and cumulative uptime, as returned by the boot query
+#[derive(Clone, Debug, GraphQLObject)]
+pub struct BootInfo {
+    // Number of boots seen, including the current one
+    pub count: i32,
+    // Uptime over all boots, in seconds
+    pub uptime: f64,
+}
+
+impl From<BootStatus> for BootInfo {
+    fn from(status: BootStatus) -> Self {
+        BootInfo {
+            count: status.count.min(i32::MAX as u32) as i32,
+            uptime: status.uptime.num_seconds() as f64,
+        }
+    }
+}
+
+// Tracks the boot record of this OBC, keeping the saved copy up to date
+#[derive(Clone)]
+pub struct BootCounter {
+    path: PathBuf,
+    record: Arc<Mutex<BootRecord>>,
+    // Uptime of the current boot when the service started, in seconds, and the time it was
+    // read at. Used to count the uptime when the kernel doesn't report it
+    origin_uptime: u64,
+    origin: Instant,
+}
+
+impl BootCounter {
+    // Loads the boot record from the schedules dir and records the current boot in it.
+    // A record which can't be read is an error rather than a fresh count, so that tasks
+    // limited to the first boots aren't repeated.
+    pub fn open(scheduler_dir: &str) -> Result<BootCounter, SchedulerError> {
+        let path = Path::new(scheduler_dir).join(BOOT_FILE);
+        let mut record = if path.exists() {
+            let contents = fs::read_to_string(&path).map_err(|e| SchedulerError::BootError {
+                err: format!("Failed to read {}: {}", BOOT_FILE, e),
+            })?;
+            serde_json::from_str(&contents).map_err(|e| SchedulerError::BootError {
+                err: format!("Failed to parse {}: {}", BOOT_FILE, e),
+            })?
+        } else {
+            BootRecord::default()
+        };
+
+        let boot_id = fs::read_to_string(BOOT_ID_PATH)
+            .ok()
+            .map(|id| id.trim().to_owned());
+        if record.boot(boot_id) {
+            info!("Boot {} of this OBC", record.count);
+        }
+
+        let counter = BootCounter {
+            path,
+            origin_uptime: record.boot_uptime,
+            record: Arc::new(Mutex::new(record)),
+            origin: Instant::now(),
+        };
+        counter.save()?;
+        Ok(counter)
+    }
+
+    // Uptime of the current boot, in seconds
+    fn boot_uptime(&self) -> u64 {
+        fs::read_to_string(UPTIME_PATH)
+            .ok()
+            .and_then(|uptime| {
+                uptime
+                    .split_whitespace()
+                    .next()
+                    .and_then(|secs| secs.parse::<f64>().ok())
+            })
+            .map(|secs| secs as u64)
+            .unwrap_or_else(|| self.origin_uptime + self.origin.elapsed().as_secs())
+    }
+
+    // Brings the uptime of the current boot in the record up to date
+    fn update(&self) -> MutexGuard<'_, BootRecord> {
+        let boot_uptime = self.boot_uptime();
+        let mut record = self.record.lock().unwrap();
+        // Uptime can't go backwards within a boot
+        record.boot_uptime = record.boot_uptime.max(boot_uptime);
+        record
+    }
+
+    // Current boot count and cumulative uptime
+    pub fn status(&self) -> BootStatus {
+        let record = self.update();
+        BootStatus {
+            count: record.count,
+            uptime: Duration::seconds(record.uptime() as i64),
+        }
+    }
+
+    // Saves the current boot record. It's written to a temporary file first, so a reset
+    // part way through can't lose the count
+    pub fn save(&self) -> Result<(), SchedulerError> {
+        let contents = serde_json::to_string(&*self.update())
+            .map_err(|e| SchedulerError::BootError { err: e.to_string() })?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, contents)
+            .and_then(|_| fs::File::open(&temp).and_then(|file| file.sync_all()))
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|e| SchedulerError::BootError {
+                err: format!("Failed to save {}: {}", BOOT_FILE, e),
+            })
+    }
+
+    // Starts a thread which periodically saves the uptime of the current boot
+    pub fn track(&self) -> Result<(), SchedulerError> {
+        let counter = self.clone();
+        thread::Builder::new()
+            .spawn(move || loop {
+                thread::sleep(SAVE_INTERVAL);
+                if let Err(e) = counter.save() {
+                    warn!("{}", e);
+                }
+            })
+            .map_err(|e| SchedulerError::StartError {
+                err: format!("Failed to start uptime thread: {:?}", e),
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_boot() {
+        let mut record = BootRecord {
+            count: 2,
+            boot_id: Some("a".to_owned()),
+            previous_uptime: 100,
+            boot_uptime: 50,
+        };
+        assert!(record.boot(Some("b".to_owned())));
+        assert_eq!(
+            record,
+            BootRecord {
+                count: 3,
+                boot_id: Some("b".to_owned()),
+                previous_uptime: 150,
+                boot_uptime: 0,
+            }
+        );
+        assert_eq!(record.uptime(), 150);
+    }
+
+    #[test]
+    fn test_service_restart() {
+        let mut record = BootRecord {
+            count: 2,
+            boot_id: Some("a".to_owned()),
+            previous_uptime: 100,
+            boot_uptime: 50,
+        };
+        assert!(!record.boot(Some("a".to_owned())));
+        assert_eq!(record.count, 2);
+        assert_eq!(record.uptime(), 150);
+    }
+
+    #[test]
+    fn test_no_boot_id() {
+        let mut record = BootRecord::default();
+        assert!(record.boot(None));
+        record.boot_uptime = 30;
+        assert!(record.boot(None));
+        assert_eq!(record.count, 2);
+        assert_eq!(record.previous_uptime, 30);
+    }
+}
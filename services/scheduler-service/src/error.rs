@@ -23,6 +23,12 @@ use failure::Fail;
 /// Errors which occur when using the scheduler
 #[derive(Debug, Eq, Fail, PartialEq)]
 pub enum SchedulerError {
+    // An error was raised while aborting tasks
+    #[fail(display = "Failed to abort tasks: {}", err)]
+    AbortError {
+        /// The specific error encountered
+        err: String,
+    },
     /// An error was raised while activating a mode
     #[fail(display = "Failed to activate '{}': {}", name, err)]
     ActivateError {
@@ -31,6 +37,12 @@ pub enum SchedulerError {
         /// Mode which failed activation
         name: String,
     },
+    // An error was raised while loading or saving the boot record
+    #[fail(display = "Boot record error: {}", err)]
+    BootError {
+        /// The specific error encountered
+        err: String,
+    },
     // An error was raised when creating a file or directory
     #[fail(display = "Failed to create '{}': {}", path, err)]
     CreateError {
@@ -90,6 +102,12 @@ pub enum SchedulerError {
         /// Name of task or mode removed
         name: String,
     },
+    // An error was raised while exchanging state with the peer scheduler
+    #[fail(display = "Standby error: {}", err)]
+    StandbyError {
+        /// The error encountered
+        err: String,
+    },
     // An error was raised when starting up the scheduler
     #[fail(display = "Scheduler failed to start: {}", err)]
     StartError {
@@ -123,6 +141,12 @@ pub enum SchedulerError {
         /// The description of task that failed to parse
         description: String,
     },
+    // An error was raised while kicking the watchdog
+    #[fail(display = "Watchdog error: {}", err)]
+    WatchdogError {
+        /// The error encountered
+        err: String,
+    },
 }
 
 impl From<String> for SchedulerError {
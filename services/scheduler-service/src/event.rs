@@ -0,0 +1,178 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//!
+//! Notifications sent to other services when the scheduler enters safe mode, and the record of
+//! the last failover to it
+//!
+
+use crate::error::SchedulerError;
+use chrono::Utc;
+use flat_db::DataPoint;
+use juniper::GraphQLObject;
+use kubos_service::Config;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::UdpSocket;
+use std::path::Path;
+
+// Name of the file in the schedules dir which the last failover to safe mode is kept in
+pub const FAILOVER_FILE: &str = "failover.json";
+
+// Telemetry subsystem and parameter used to record safe mode entries
+const TELEMETRY_SUBSYSTEM: &str = "scheduler";
+const TELEMETRY_PARAMETER: &str = "safe-mode";
+
+// Event sent to each of the configured safe mode ports, as JSON
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SafeModeEvent {
+    // Always "safe_mode", so receivers can tell scheduler events apart
+    pub event: &'static str,
+    // Mode which was active before safe mode was entered
+    pub previous_mode: Option<String>,
+    // Whether safe mode was requested with the safeMode mutation, rather than failed over to
+    pub commanded: bool,
+    // Why safe mode was entered
+    pub reason: String,
+    pub time: String,
+}
+
+impl SafeModeEvent {
+    pub fn new(previous_mode: Option<String>, commanded: bool, reason: &str) -> Self {
+        SafeModeEvent {
+            event: "safe_mode",
+            previous_mode,
+            commanded,
+            reason: reason.to_owned(),
+            time: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+// The most recent automatic failover to safe mode, as saved in the schedules dir and returned
+// by the lastFailover query
+#[derive(Clone, Debug, Deserialize, GraphQLObject, PartialEq, Serialize)]
+pub struct Failover {
+    // Why safe mode was failed over to
+    pub reason: String,
+    // When the failover happened, in UTC
+    pub time: String,
+    // Mode which was active before the failover
+    pub previous_mode: Option<String>,
+}
+
+impl From<&SafeModeEvent> for Failover {
+    fn from(event: &SafeModeEvent) -> Self {
+        Failover {
+            reason: event.reason.clone(),
+            time: event.time.clone(),
+            previous_mode: event.previous_mode.clone(),
+        }
+    }
+}
+
+// Saves a failover so it can still be queried after the service restarts. It's written to a
+// temporary file first, so a reset part way through can't corrupt the record
+pub fn save_failover(scheduler_dir: &str, failover: &Failover) -> Result<(), SchedulerError> {
+    let path = Path::new(scheduler_dir).join(FAILOVER_FILE);
+    let contents = serde_json::to_string(failover)
+        .map_err(|e| SchedulerError::GenericError { err: e.to_string() })?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, contents)
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|e| SchedulerError::GenericError {
+            err: format!("Failed to save {}: {}", FAILOVER_FILE, e),
+        })
+}
+
+// Loads the last failover saved in the schedules dir, if there's been one
+pub fn last_failover(scheduler_dir: &str) -> Result<Option<Failover>, SchedulerError> {
+    let path = Path::new(scheduler_dir).join(FAILOVER_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).map_err(|e| SchedulerError::GenericError {
+        err: format!("Failed to read {}: {}", FAILOVER_FILE, e),
+    })?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| SchedulerError::GenericError {
+            err: format!("Failed to parse {}: {}", FAILOVER_FILE, e),
+        })
+}
+
+// Port the telemetry service accepts direct UDP DataPoints on, if it's configured
+pub fn telemetry_port() -> Option<u16> {
+    let config = match Config::new("telemetry-service") {
+        Ok(c) => c,
+        Err(_) => {
+            debug!("Telemetry service config not found");
+            return None;
+        }
+    };
+
+    match config.get("direct_port").and_then(|p| p.as_integer()) {
+        Some(port) => Some(port as u16),
+        None => {
+            debug!("Telemetry direct_port not found");
+            None
+        }
+    }
+}
+
+// Tells the services listening on `ports` (on this host) and the telemetry service that
+// safe mode was entered. Failures are only logged, since nothing should stop the scheduler
+// from getting into safe mode.
+pub fn broadcast_safe_mode(ports: &[u16], event: &SafeModeEvent) {
+    info!(
+        "Broadcasting safe mode entry (previous mode: {:?}, reason: {})",
+        event.previous_mode, event.reason
+    );
+
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Couldn't create socket for safe mode broadcast: {}", e);
+            return;
+        }
+    };
+
+    match serde_json::to_vec(event) {
+        Ok(buf) => {
+            for port in ports {
+                if let Err(e) = socket.send_to(&buf, ("127.0.0.1", *port)) {
+                    warn!("Couldn't send safe mode event to port {}: {}", port, e);
+                }
+            }
+        }
+        Err(e) => warn!("Couldn't serialize safe mode event: {}", e),
+    }
+
+    // 0 = commanded, 1 = failover
+    if let Some(port) = telemetry_port() {
+        let value: i32 = if event.commanded { 0 } else { 1 };
+        let dp = DataPoint::now(TELEMETRY_SUBSYSTEM, TELEMETRY_PARAMETER, value.into());
+        match serde_cbor::to_vec(&dp) {
+            Ok(buf) => {
+                if let Err(e) = socket.send_to(&buf, ("0.0.0.0", port)) {
+                    debug!("Couldn't send DataPoint to Telemetry service:{:?}", e);
+                }
+            }
+            Err(_) => debug!("Couldn't serialize datapoint"),
+        }
+    }
+}
@@ -1,9 +1,16 @@
 mod app;
+mod boot;
 mod error;
+mod event;
 mod mode;
+mod process;
 mod scheduler;
 mod schema;
+mod simulation;
+mod standby;
 mod task;
 mod task_list;
+mod timer;
+mod watchdog;
 
 pub use mode::ScheduleMode;
@@ -20,18 +20,32 @@
 #![deny(missing_docs)]
 
 mod app;
+mod boot;
 mod error;
+mod event;
 mod mode;
+mod process;
 mod scheduler;
 mod schema;
+mod simulation;
+mod standby;
 mod task;
 mod task_list;
+mod timer;
+mod watchdog;
 
+use crate::boot::BootCounter;
 use crate::error::SchedulerError;
+use chrono::NaiveDateTime;
 use kubos_service::{Config, Logger, Service};
-use log::{error, info};
+use log::{error, info, warn};
 use scheduler::{Scheduler, DEFAULT_SCHEDULES_DIR};
 use schema::{MutationRoot, QueryRoot};
+use simulation::Simulation;
+use standby::StandbyConfig;
+use std::net::SocketAddr;
+use std::time::Duration;
+use watchdog::{WatchdogConfig, WatchdogTarget};
 
 fn main() -> Result<(), SchedulerError> {
     Logger::init("kubos-scheduler-service").unwrap();
@@ -42,6 +56,7 @@ fn main() -> Result<(), SchedulerError> {
             err: format!("Failed to load service config: {}", err),
         }
     })?;
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
 
     let scheduler_dir = if let Some(s_dir) = config.get("schedules_dir") {
         String::from(s_dir.as_str().ok_or_else(|| SchedulerError::StartError {
@@ -51,17 +66,196 @@ fn main() -> Result<(), SchedulerError> {
         String::from(DEFAULT_SCHEDULES_DIR)
     };
 
-    let scheduler = Scheduler::new(&scheduler_dir)?;
+    // Local ports of the services to tell when safe mode is entered
+    let safe_mode_ports = match config.get("safe_mode_ports") {
+        Some(ports) => ports
+            .as_array()
+            .and_then(|ports| {
+                ports
+                    .iter()
+                    .map(|port| port.as_integer().map(|port| port as u16))
+                    .collect::<Option<Vec<u16>>>()
+            })
+            .ok_or_else(|| SchedulerError::StartError {
+                err: "Error parsing safe mode ports".to_owned(),
+            })?,
+        None => vec![],
+    };
+
+    // Directory which uploaded task lists are automatically imported from
+    let import_dir = match config.get("import_dir") {
+        Some(dir) => Some(
+            dir.as_str()
+                .ok_or_else(|| SchedulerError::StartError {
+                    err: "Error parsing import dir path".to_owned(),
+                })?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    // Accelerated clock for validating schedules on the ground
+    let simulation = match config.get("simulation") {
+        Some(simulation) => {
+            let parse_err = |err: &str| SchedulerError::StartError {
+                err: format!("Error parsing simulation config: {}", err),
+            };
+            let speed = simulation
+                .get("speed")
+                .and_then(|speed| speed.as_integer())
+                .filter(|speed| *speed > 0 && *speed <= i64::from(i32::MAX))
+                .ok_or_else(|| parse_err("speed must be a positive integer"))?;
+            let start = match simulation.get("start") {
+                Some(start) => Some(
+                    start
+                        .as_str()
+                        .and_then(|start| {
+                            NaiveDateTime::parse_from_str(start, "%Y-%m-%d %H:%M:%S").ok()
+                        })
+                        .ok_or_else(|| parse_err("start must be in yyyy-mm-dd hh:mm:ss format"))?,
+                ),
+                None => None,
+            };
+            let stub_command = match simulation.get("stub_command") {
+                Some(stub) => Some(
+                    stub.as_str()
+                        .ok_or_else(|| parse_err("stub_command must be a string"))?
+                        .to_owned(),
+                ),
+                None => None,
+            };
+            warn!(
+                "Simulating schedules at {}x speed, apps will not be run",
+                speed
+            );
+            Some(Simulation::new(speed as u32, start, stub_command))
+        }
+        None => None,
+    };
+
+    // Peer scheduler on the other OBC, which this instance shadows or is shadowed by
+    let standby = match config.get("standby") {
+        Some(standby) => {
+            let parse_err = |err: &str| SchedulerError::StartError {
+                err: format!("Error parsing standby config: {}", err),
+            };
+            let address = |key: &str| {
+                standby
+                    .get(key)
+                    .and_then(|addr| addr.as_str())
+                    .and_then(|addr| addr.parse::<SocketAddr>().ok())
+                    .ok_or_else(|| parse_err(&format!("{} must be in ip:port format", key)))
+            };
+            let millis = |key: &str, default: u64| match standby.get(key) {
+                Some(ms) => ms
+                    .as_integer()
+                    .filter(|ms| *ms > 0)
+                    .map(|ms| Duration::from_millis(ms as u64))
+                    .ok_or_else(|| parse_err(&format!("{} must be a positive integer", key))),
+                None => Ok(Duration::from_millis(default)),
+            };
+            let priority = standby
+                .get("priority")
+                .and_then(|priority| priority.as_integer())
+                .filter(|priority| *priority >= 0 && *priority <= 255)
+                .ok_or_else(|| parse_err("priority must be an integer from 0 to 255"))?;
+            Some(StandbyConfig {
+                listen: address("listen")?,
+                peer: address("peer")?,
+                priority: priority as u8,
+                heartbeat_interval: millis("heartbeat_ms", 1000)?,
+                takeover_timeout: millis("takeover_ms", 5000)?,
+            })
+        }
+        None => None,
+    };
+
+    // Watchdog which is kicked for as long as the scheduler is healthy
+    let watchdog = match config.get("watchdog") {
+        Some(watchdog) => {
+            let parse_err = |err: &str| SchedulerError::StartError {
+                err: format!("Error parsing watchdog config: {}", err),
+            };
+            let string = |key: &str| {
+                watchdog
+                    .get(key)
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .map(|value| value.to_owned())
+                            .ok_or_else(|| parse_err(&format!("{} must be a string", key)))
+                    })
+                    .transpose()
+            };
+            let target = match (string("device")?, string("udp")?, string("command")?) {
+                (Some(device), None, None) => WatchdogTarget::Device(device),
+                (None, Some(udp), None) => WatchdogTarget::Udp(
+                    udp.parse()
+                        .map_err(|_| parse_err("udp must be in ip:port format"))?,
+                ),
+                (None, None, Some(command)) => WatchdogTarget::Command(command),
+                _ => {
+                    return Err(parse_err(
+                        "exactly one of device, udp or command must be given",
+                    ))
+                }
+            };
+            let millis = |key: &str, default: u64| match watchdog.get(key) {
+                Some(ms) => ms
+                    .as_integer()
+                    .filter(|ms| *ms > 0)
+                    .map(|ms| Duration::from_millis(ms as u64))
+                    .ok_or_else(|| parse_err(&format!("{} must be a positive integer", key))),
+                None => Ok(Duration::from_millis(default)),
+            };
+            Some(WatchdogConfig {
+                target,
+                kick_interval: millis("kick_ms", 1000)?,
+                stall_timeout: millis("stall_ms", 10000)?,
+            })
+        }
+        None => None,
+    };
+
+    let scheduler = Scheduler::new(&scheduler_dir)?
+        .with_safe_mode_ports(safe_mode_ports)
+        .with_import_dir(import_dir)
+        .with_simulation(simulation);
 
     info!("Starting scheduler-service - {:?}", scheduler.scheduler_dir);
 
     scheduler.init()?;
 
-    // For now we will only kick off scheduling when the scheduler comes up
-    if let Err(e) = scheduler.start() {
+    // Tasks limited by boot count or uptime aren't run if the boot record can't be loaded
+    let boot = match BootCounter::open(&scheduler.scheduler_dir) {
+        Ok(boot) => {
+            boot.track()?;
+            Some(boot)
+        }
+        Err(e) => {
+            error!("{}. Tasks limited by boot count or uptime will not be run", e);
+            None
+        }
+    };
+    let scheduler = scheduler.with_boot_counter(boot);
+
+    if let Some(standby) = standby {
+        // Tasks are only scheduled once this instance takes over from its peer
+        standby::start(&scheduler, standby)?;
+    } else if let Err(e) = scheduler.start() {
+        // For now we will only kick off scheduling when the scheduler comes up
         error!("Failed to schedule tasks: {:?}", e);
     }
 
+    scheduler.watch_import_dir()?;
+
+    // Without its watchdog the scheduler still runs, it just can't be reset if it wedges
+    if let Some(watchdog) = watchdog {
+        if let Err(e) = watchdog::start(&scheduler, watchdog) {
+            error!("Failed to start watchdog kicking: {}", e);
+        }
+    }
+
     Service::new(config, scheduler, QueryRoot, MutationRoot).start();
 
     Ok(())
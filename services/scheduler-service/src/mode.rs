@@ -21,11 +21,14 @@
 use crate::error::SchedulerError;
 use crate::scheduler::SAFE_MODE;
 use crate::task_list::{get_mode_task_lists, TaskList};
+use std::collections::{BTreeMap, HashSet};
 use chrono::offset::TimeZone;
 use chrono::{DateTime, Utc};
-use juniper::GraphQLObject;
+use juniper::{GraphQLInputObject, GraphQLObject};
 use log::{error, info, warn};
+use serde_json::Value;
 use std::fs;
+use std::io::{self, Write};
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 
@@ -37,8 +40,33 @@ pub struct ScheduleMode {
     pub last_revised: String,
     pub schedule: Vec<TaskList>,
     pub active: bool,
+    // Mode whose task lists are inherited, if any
+    pub parent: Option<String>,
+    // Config variables set by the mode itself, not including inherited ones
+    pub variables: Vec<ModeVariable>,
 }
 
+// A config variable, exported as an environment variable to the apps run while its mode is
+// in effect
+#[derive(Clone, Debug, GraphQLObject)]
+pub struct ModeVariable {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, GraphQLInputObject)]
+pub struct ModeVariableInput {
+    pub name: String,
+    pub value: String,
+}
+
+// Name of the file in a mode's directory which holds the name of its parent mode
+const PARENT_FILE: &str = "parent";
+
+// Name of the file in a mode's directory which holds its config variables, as a JSON object.
+// It has no extension so that it isn't mistaken for a task list
+pub const VARIABLES_FILE: &str = "variables";
+
 impl ScheduleMode {
     pub fn from_path(path_obj: &Path) -> Result<ScheduleMode, SchedulerError> {
         let path = path_obj
@@ -81,12 +109,26 @@ impl ScheduleMode {
 
         let active = false;
 
+        let parent = read_parent(path_obj);
+
+        // Invalid variables stop the mode from starting, rather than from being listed
+        let variables = read_variables(path_obj)
+            .unwrap_or_else(|e| {
+                warn!("{}", e);
+                BTreeMap::new()
+            })
+            .into_iter()
+            .map(|(name, value)| ModeVariable { name, value })
+            .collect();
+
         Ok(ScheduleMode {
             name,
             path,
             last_revised,
             schedule: task_lists,
             active,
+            parent,
+            variables,
         })
     }
 }
@@ -194,6 +236,13 @@ pub fn remove_mode(scheduler_dir: &str, name: &str) -> Result<(), SchedulerError
         });
     }
 
+    if is_mode_in_effect(&scheduler_dir, &name) {
+        return Err(SchedulerError::RemoveError {
+            err: "Cannot remove mode inherited by the active mode".to_owned(),
+            name: name.to_owned(),
+        });
+    }
+
     let mode_dir = format!("{}/{}", scheduler_dir, name);
     Ok(
         fs::remove_dir_all(mode_dir).map_err(|e| SchedulerError::RemoveError {
@@ -238,3 +287,254 @@ pub fn activate_mode(scheduler_dir: &str, name: &str) -> Result<(), SchedulerErr
     info!("Activated mode {}", name);
     Ok(())
 }
+
+// Read the parent mode name out of a mode directory
+fn read_parent(mode_path: &Path) -> Option<String> {
+    fs::read_to_string(mode_path.join(PARENT_FILE))
+        .ok()
+        .map(|parent| parent.trim().to_lowercase())
+        .filter(|parent| !parent.is_empty())
+}
+
+// Retrieve the chain of modes a mode inherits from, starting with the mode itself
+pub fn get_mode_lineage(scheduler_dir: &str, name: &str) -> Result<Vec<String>, SchedulerError> {
+    let mut lineage = vec![name.to_lowercase()];
+    let mut seen = HashSet::new();
+    seen.insert(name.to_lowercase());
+
+    while let Some(parent) = read_parent(&Path::new(scheduler_dir).join(lineage.last().unwrap()))
+    {
+        if !seen.insert(parent.clone()) {
+            return Err(SchedulerError::LoadModeError {
+                err: format!("Mode inheritance loop found at '{}'", parent),
+                path: name.to_owned(),
+            });
+        }
+        if !Path::new(scheduler_dir).join(&parent).is_dir() {
+            return Err(SchedulerError::LoadModeError {
+                err: format!("Parent mode '{}' not found", parent),
+                path: name.to_owned(),
+            });
+        }
+        lineage.push(parent);
+    }
+
+    Ok(lineage)
+}
+
+// Retrieve the task lists which are in effect for a mode: its own task lists, plus any
+// inherited from its ancestors which haven't been overridden by a same-named list
+pub fn get_effective_task_lists(
+    scheduler_dir: &str,
+    name: &str,
+) -> Result<Vec<TaskList>, SchedulerError> {
+    let mut lists: Vec<TaskList> = vec![];
+
+    for mode in get_mode_lineage(scheduler_dir, name)? {
+        let mode_path = format!("{}/{}", scheduler_dir, mode);
+        for list in get_mode_task_lists(&mode_path)? {
+            if !lists.iter().any(|l| l.filename == list.filename) {
+                lists.push(list);
+            }
+        }
+    }
+
+    Ok(lists)
+}
+
+// Set (or clear) the parent of a mode
+pub fn set_mode_parent(
+    scheduler_dir: &str,
+    name: &str,
+    parent: Option<String>,
+) -> Result<(), SchedulerError> {
+    let name = name.to_lowercase();
+    let mode_dir = Path::new(scheduler_dir).join(&name);
+    let parent_file = mode_dir.join(PARENT_FILE);
+
+    if !mode_dir.is_dir() {
+        return Err(SchedulerError::GenericError {
+            err: format!("Mode '{}' not found", name),
+        });
+    }
+
+    let parent = match parent.map(|p| p.to_lowercase()) {
+        Some(parent) => parent,
+        None => {
+            info!("Clearing parent of mode {}", name);
+            if parent_file.is_file() {
+                fs::remove_file(&parent_file).map_err(|e| SchedulerError::RemoveError {
+                    err: e.to_string(),
+                    name: name.to_owned(),
+                })?;
+            }
+            return Ok(());
+        }
+    };
+
+    if name == SAFE_MODE {
+        return Err(SchedulerError::GenericError {
+            err: "The safe mode cannot inherit from another mode".to_owned(),
+        });
+    }
+
+    if !Path::new(scheduler_dir).join(&parent).is_dir() {
+        return Err(SchedulerError::GenericError {
+            err: format!("Parent mode '{}' not found", parent),
+        });
+    }
+
+    // The new parent (or any of its ancestors) can't be this mode
+    if get_mode_lineage(scheduler_dir, &parent)?.contains(&name) {
+        return Err(SchedulerError::GenericError {
+            err: format!("Mode '{}' already inherits from '{}'", parent, name),
+        });
+    }
+
+    info!("Setting parent of mode {} to {}", name, parent);
+
+    fs::File::create(&parent_file)
+        .and_then(|mut file| file.write_all(parent.as_bytes()))
+        .map_err(|e| SchedulerError::CreateError {
+            err: e.to_string(),
+            path: parent_file.to_string_lossy().into_owned(),
+        })?;
+
+    Ok(())
+}
+
+// Check whether a mode is the active mode or is inherited by it
+pub fn is_mode_in_effect(scheduler_dir: &str, name: &str) -> bool {
+    let name = name.to_lowercase();
+    match get_active_mode(scheduler_dir) {
+        Ok(Some(active_mode)) => get_mode_lineage(scheduler_dir, &active_mode.name)
+            .map(|lineage| lineage.contains(&name))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Check that a config variable can be exported as an environment variable
+fn check_variable(name: &str, value: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return Err(format!("Invalid variable name '{}'", name));
+    }
+    if value.contains('\0') {
+        return Err(format!("Variable '{}' contains a NUL character", name));
+    }
+    Ok(())
+}
+
+// Read the config variables out of a mode directory.
+// Values may be strings, numbers or booleans, and are exported as strings
+fn read_variables(mode_path: &Path) -> Result<BTreeMap<String, String>, SchedulerError> {
+    let path = mode_path.join(VARIABLES_FILE);
+    let load_err = |err: String| SchedulerError::LoadModeError {
+        err,
+        path: path.to_string_lossy().into_owned(),
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(load_err(format!("Failed to read variables: {}", e))),
+    };
+    let variables: BTreeMap<String, Value> = serde_json::from_str(&contents)
+        .map_err(|e| load_err(format!("Failed to parse variables: {}", e)))?;
+
+    variables
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                _ => {
+                    return Err(load_err(format!(
+                        "Variable '{}' must be a string, number or boolean",
+                        name
+                    )))
+                }
+            };
+            check_variable(&name, &value).map_err(&load_err)?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+// Retrieve the config variables in effect for a mode: its own variables, plus any inherited
+// from its ancestors which it doesn't set itself
+pub fn get_mode_variables(
+    scheduler_dir: &str,
+    name: &str,
+) -> Result<BTreeMap<String, String>, SchedulerError> {
+    let mut variables = BTreeMap::new();
+
+    for mode in get_mode_lineage(scheduler_dir, name)?.iter().rev() {
+        variables.extend(read_variables(&Path::new(scheduler_dir).join(mode))?);
+    }
+
+    Ok(variables)
+}
+
+// Replace the config variables of a mode. An empty list removes them all
+pub fn set_mode_variables(
+    scheduler_dir: &str,
+    name: &str,
+    variables: Vec<ModeVariableInput>,
+) -> Result<(), SchedulerError> {
+    let name = name.to_lowercase();
+    let mode_dir = Path::new(scheduler_dir).join(&name);
+    let variables_file = mode_dir.join(VARIABLES_FILE);
+
+    if !mode_dir.is_dir() {
+        return Err(SchedulerError::GenericError {
+            err: format!("Mode '{}' not found", name),
+        });
+    }
+
+    if variables.is_empty() {
+        info!("Clearing variables of mode {}", name);
+        if variables_file.is_file() {
+            fs::remove_file(&variables_file).map_err(|e| SchedulerError::RemoveError {
+                err: e.to_string(),
+                name: name.to_owned(),
+            })?;
+        }
+        return Ok(());
+    }
+
+    let mut contents = BTreeMap::new();
+    for variable in variables {
+        check_variable(&variable.name, &variable.value)
+            .map_err(|err| SchedulerError::GenericError { err })?;
+        if contents
+            .insert(variable.name.clone(), variable.value)
+            .is_some()
+        {
+            return Err(SchedulerError::GenericError {
+                err: format!("Variable '{}' given more than once", variable.name),
+            });
+        }
+    }
+
+    info!(
+        "Setting variables of mode {}: {:?}",
+        name,
+        contents.keys().collect::<Vec<_>>()
+    );
+
+    let contents = serde_json::to_string_pretty(&contents).map_err(|e| {
+        SchedulerError::GenericError {
+            err: format!("Failed to serialize variables: {}", e),
+        }
+    })?;
+    fs::File::create(&variables_file)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| SchedulerError::CreateError {
+            err: e.to_string(),
+            path: variables_file.to_string_lossy().into_owned(),
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//!
+//! Tracking of the processes started by scheduled tasks, so they can be aborted
+//!
+
+use crate::error::SchedulerError;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// Child processes of running tasks, along with a channel used to tell
+// individual tasks to stop scheduling
+#[derive(Clone)]
+pub struct TaskProcesses {
+    // Map of running process IDs to the ID of the task which started them
+    pids: Arc<Mutex<HashMap<u32, Option<i32>>>>,
+    // Sender for the IDs of tasks which have been aborted
+    aborter: broadcast::Sender<i32>,
+}
+
+impl Default for TaskProcesses {
+    fn default() -> Self {
+        TaskProcesses::new()
+    }
+}
+
+impl TaskProcesses {
+    pub fn new() -> TaskProcesses {
+        let (aborter, _) = broadcast::channel::<i32>(8);
+        TaskProcesses {
+            pids: Arc::new(Mutex::new(HashMap::new())),
+            aborter,
+        }
+    }
+
+    // Records a process started by a task. The process is forgotten when the returned
+    // guard is dropped, so that an exited process's (possibly reused) ID is never signalled.
+    pub fn track(&self, pid: u32, id: Option<i32>) -> ProcessGuard {
+        self.pids.lock().unwrap().insert(pid, id);
+        ProcessGuard {
+            pid,
+            pids: self.pids.clone(),
+        }
+    }
+
+    // Receiver for the IDs of aborted tasks
+    pub fn subscribe(&self) -> broadcast::Receiver<i32> {
+        self.aborter.subscribe()
+    }
+
+    // Tells the task with this ID to stop scheduling executions
+    pub fn stop(&self, id: i32) {
+        // An error just means no tasks are currently scheduled
+        let _ = self.aborter.send(id);
+    }
+
+    // Kills the running processes of the task with this ID, or of all tasks if no ID is given.
+    // Returns the number of processes which were killed.
+    pub fn kill(&self, id: Option<i32>) -> Result<usize, SchedulerError> {
+        let pids: Vec<u32> = self
+            .pids
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, task)| id.is_none() || **task == id)
+            .map(|(pid, _)| *pid)
+            .collect();
+
+        let mut killed = 0;
+        let mut errors = vec![];
+        for pid in pids {
+            info!("Killing process {}", pid);
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } == 0 {
+                killed += 1;
+                continue;
+            }
+
+            let err = io::Error::last_os_error();
+            // The process exited on its own before we got to it
+            if err.raw_os_error() == Some(libc::ESRCH) {
+                continue;
+            }
+            warn!("Failed to kill process {}: {}", pid, err);
+            errors.push(format!("process {}: {}", pid, err));
+        }
+
+        if errors.is_empty() {
+            Ok(killed)
+        } else {
+            Err(SchedulerError::AbortError {
+                err: errors.join(", "),
+            })
+        }
+    }
+}
+
+// Removes a process from the tracked processes when dropped
+pub struct ProcessGuard {
+    pid: u32,
+    pids: Arc<Mutex<HashMap<u32, Option<i32>>>>,
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        self.pids.lock().unwrap().remove(&self.pid);
+    }
+}
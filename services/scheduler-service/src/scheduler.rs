@@ -18,34 +18,80 @@
 //! Structures and functions concerning the actual running of a schedule
 //!
 
+use crate::boot::{BootCounter, BootStatus};
 use crate::error::SchedulerError;
+use crate::event::{broadcast_safe_mode, last_failover, save_failover, Failover, SafeModeEvent};
 use crate::mode::{
-    activate_mode, create_mode, get_active_mode, get_available_modes, is_mode_active,
+    activate_mode, create_mode, get_active_mode, get_available_modes, get_effective_task_lists,
+    get_mode_variables, is_mode_in_effect,
 };
-use crate::task_list::{get_mode_task_lists, validate_task_list, TaskList};
+use crate::process::TaskProcesses;
+use crate::simulation::{Clock, Simulation, SimulationStatus};
+use crate::standby::Role;
+use crate::task::{Task, UpcomingTask};
+use crate::task_list::{import_uploaded_task_list, validate_task_list, TaskList};
+#[cfg(test)]
+use crate::timer::mock::MockTimer;
+use chrono::NaiveDateTime;
 use clock_timer::RealTimer;
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::park_timeout;
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::runtime::{Builder, Handle};
 use tokio::sync::broadcast;
+use tokio::task::JoinHandle as TaskHandle;
 use tokio::time::interval;
 
 #[allow(unused)]
 pub const DEFAULT_SCHEDULES_DIR: &str = "/home/system/etc/schedules";
 pub const SAFE_MODE: &str = "safe";
+// Most executions the upcoming query returns, however large a limit it's given. Every task's
+// next `limit` executions are worked out while the task lists are locked
+pub const MAX_UPCOMING_LIMIT: usize = 1000;
+
+// How often the import directory is checked for new task lists
+const IMPORT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Uploads modified more recently than this might still be being written
+const IMPORT_SETTLE_TIME: Duration = Duration::from_secs(2);
+// Name of the file in the schedules dir whose presence means the scheduler is disarmed
+const DISARMED_FILE: &str = "disarmed";
+// How often the timer runtime records that it's still running tasks
+const RUNTIME_TICK_INTERVAL: Duration = Duration::from_secs(1);
+// How long stopping task lists waits for their tasks to finish
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 // Handle to primitives controlling scheduler runtime context
-#[derive(Clone)]
 pub struct SchedulerHandle {
     // Sender for stopping scheduler runtime/thread
     pub stopper: broadcast::Sender<()>,
+    // Handles of the spawned tasks, which finish once they've been stopped
+    pub runs: Vec<TaskHandle<()>>,
+    // Mode the running task list was loaded from
+    pub mode: String,
+    // IDs of the tasks in the running task list
+    pub task_ids: Vec<i32>,
+    // Tasks in the running task list which haven't been aborted
+    pub tasks: Vec<Task>,
+    // Time at which the tasks were scheduled, which delays are relative to
+    pub started: NaiveDateTime,
+}
+
+impl SchedulerHandle {
+    // Tells the task list's tasks to stop, returning their handles so they can be waited for
+    fn stop(self, name: &str) -> Vec<TaskHandle<()>> {
+        info!("Stopping {}'s tasks", name);
+        if self.stopper.send(()).is_err() {
+            error!("Failed to send stop to {}'s tasks", name);
+        }
+        self.runs
+    }
 }
 
 #[derive(Clone)]
@@ -55,10 +101,25 @@ pub struct Scheduler {
     // Map of active task list names and scheduler handles. This allows us to
     // start/stop tasks associated with individual task lists
     scheduler_map: Arc<Mutex<HashMap<String, SchedulerHandle>>>,
+    // Processes started by running tasks
+    processes: TaskProcesses,
+    // Local UDP ports which are told whenever safe mode is entered
+    safe_mode_ports: Vec<u16>,
+    // Directory which uploaded task lists are automatically imported from
+    import_dir: Option<String>,
 
     tokio_handle: Handle,
     thread_handle: Arc<JoinHandle<()>>,
-    real_timer: RealTimer,
+    // Clock which tasks are scheduled against
+    clock: Clock,
+    // Whether due tasks are run. While disarmed they're only logged
+    armed: Arc<AtomicBool>,
+    // Set while shadowing a peer scheduler, when no tasks are scheduled
+    standby: Arc<AtomicBool>,
+    // Boot count and cumulative uptime, which tasks can be limited by
+    boot: Option<BootCounter>,
+    // Last time a task spawned on the timer runtime ran
+    runtime_tick: Arc<Mutex<Instant>>,
 }
 
 impl Scheduler {
@@ -115,7 +176,23 @@ impl Scheduler {
 
         let thread_handle = Arc::new(thread_handle);
 
-        let real_timer = RealTimer::create();
+        // Ticks for as long as the runtime is running tasks, so a wedged runtime can be detected
+        let runtime_tick = Arc::new(Mutex::new(Instant::now()));
+        let tick_ref = runtime_tick.clone();
+        tokio_handle.spawn(async move {
+            let mut tick = interval(RUNTIME_TICK_INTERVAL);
+            loop {
+                tick.tick().await;
+                *tick_ref.lock().unwrap() = Instant::now();
+            }
+        });
+
+        let clock = Clock::Real(RealTimer::create());
+
+        let armed = !Path::new(&scheduler_dir).join(DISARMED_FILE).exists();
+        if !armed {
+            warn!("Scheduler is disarmed, tasks will not be run");
+        }
 
         debug!("Parked Main Thread");
         // park();
@@ -125,12 +202,169 @@ impl Scheduler {
         Ok(Scheduler {
             scheduler_dir,
             scheduler_map: Arc::new(Mutex::new(HashMap::<String, SchedulerHandle>::new())),
+            processes: TaskProcesses::new(),
+            safe_mode_ports: vec![],
+            import_dir: None,
             tokio_handle,
             thread_handle,
-            real_timer,
+            clock,
+            armed: Arc::new(AtomicBool::new(armed)),
+            standby: Arc::new(AtomicBool::new(false)),
+            boot: None,
+            runtime_tick,
         })
     }
 
+    // Set the local UDP ports which are sent an event whenever safe mode is entered
+    pub fn with_safe_mode_ports(mut self, ports: Vec<u16>) -> Self {
+        self.safe_mode_ports = ports;
+        self
+    }
+
+    // Set the directory which uploaded task lists are automatically imported from
+    pub fn with_import_dir(mut self, import_dir: Option<String>) -> Self {
+        self.import_dir = import_dir;
+        self
+    }
+
+    // Run against an accelerated clock, recording executions rather than running apps
+    pub fn with_simulation(mut self, simulation: Option<Simulation>) -> Self {
+        if let Some(simulation) = simulation {
+            self.clock = Clock::Simulated(simulation);
+        }
+        self
+    }
+
+    // Run against a mock timer, which only moves forward when a test tells it to
+    #[cfg(test)]
+    pub fn with_timer(mut self, timer: MockTimer) -> Self {
+        self.clock = Clock::Mock(timer);
+        self
+    }
+
+    // Set the boot record which tasks limited by boot count or uptime are checked against
+    pub fn with_boot_counter(mut self, boot: Option<BootCounter>) -> Self {
+        self.boot = boot;
+        self
+    }
+
+    // Boot count and cumulative uptime, if the boot record could be loaded
+    pub fn boot_status(&self) -> Option<BootStatus> {
+        self.boot.as_ref().map(|boot| boot.status())
+    }
+
+    // Time since the timer runtime last ran a task. Grows while the runtime is wedged
+    pub fn runtime_idle(&self) -> Duration {
+        self.runtime_tick.lock().unwrap().elapsed()
+    }
+
+    // Checks that the active mode is still in place and that the running task lists
+    // can be reached
+    pub fn check_state(&self) -> Result<(), SchedulerError> {
+        // The active mode link is followed to the mode's directory
+        let active_path = Path::new(&self.scheduler_dir).join("active");
+        if !active_path.is_dir() {
+            return Err(SchedulerError::LoadModeError {
+                err: "Active mode link is missing or broken".to_owned(),
+                path: active_path.to_string_lossy().into_owned(),
+            });
+        }
+
+        // Blocks if whatever holds the lock is wedged
+        match self.scheduler_map.lock() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(SchedulerError::GenericError {
+                err: "Running task lists lock poisoned".to_owned(),
+            }),
+        }
+    }
+
+    // State of the simulation, if the scheduler is being simulated
+    pub fn simulation(&self) -> Option<SimulationStatus> {
+        match &self.clock {
+            Clock::Simulated(simulation) => Some(simulation.status()),
+            _ => None,
+        }
+    }
+
+    // Whether due tasks are run
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::SeqCst)
+    }
+
+    // Arms or disarms the scheduler. The state is kept in the schedules dir,
+    // so it survives restarts
+    pub fn set_armed(&self, armed: bool) -> Result<(), SchedulerError> {
+        let path = Path::new(&self.scheduler_dir).join(DISARMED_FILE);
+        if armed {
+            if path.exists() {
+                fs::remove_file(&path).map_err(|e| SchedulerError::RemoveError {
+                    err: e.to_string(),
+                    name: DISARMED_FILE.to_owned(),
+                })?;
+            }
+        } else {
+            fs::write(&path, "").map_err(|e| SchedulerError::CreateError {
+                err: e.to_string(),
+                path: path.to_string_lossy().into_owned(),
+            })?;
+        }
+
+        if self.armed.swap(armed, Ordering::SeqCst) != armed {
+            info!("Scheduler {}", if armed { "armed" } else { "disarmed" });
+        }
+        Ok(())
+    }
+
+    // Whether this instance is running tasks or shadowing a peer scheduler
+    pub fn role(&self) -> Role {
+        if self.standby.load(Ordering::SeqCst) {
+            Role::Standby
+        } else {
+            Role::Active
+        }
+    }
+
+    // Sets whether this instance runs tasks. Any running tasks are left to the caller to stop
+    pub fn set_role(&self, role: Role) {
+        if self.standby.swap(role == Role::Standby, Ordering::SeqCst) != (role == Role::Standby) {
+            info!("Scheduler is now {:?}", role);
+        }
+    }
+
+    // Let other services know that safe mode has been entered. Failovers are also saved, so
+    // operators can find out why the scheduler is in safe mode
+    pub fn notify_safe_mode(&self, previous_mode: Option<String>, commanded: bool, reason: &str) {
+        let event = SafeModeEvent::new(previous_mode, commanded, reason);
+        if !commanded {
+            if let Err(e) = save_failover(&self.scheduler_dir, &Failover::from(&event)) {
+                warn!("{}", e);
+            }
+        }
+        broadcast_safe_mode(&self.safe_mode_ports, &event);
+    }
+
+    // The most recent automatic failover to safe mode, if there's been one
+    pub fn last_failover(&self) -> Result<Option<Failover>, SchedulerError> {
+        last_failover(&self.scheduler_dir)
+    }
+
+    // Name of the currently active mode, if there is one
+    pub fn active_mode_name(&self) -> Option<String> {
+        get_active_mode(&self.scheduler_dir)
+            .ok()
+            .flatten()
+            .map(|mode| mode.name)
+    }
+
+    // Config variables in effect for the active mode, which are exported to the apps it runs
+    pub fn mode_variables(&self) -> Result<BTreeMap<String, String>, SchedulerError> {
+        match get_active_mode(&self.scheduler_dir)? {
+            Some(mode) => get_mode_variables(&self.scheduler_dir, &mode.name),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
     // Ensure that conditions are good for starting the scheduler
     #[allow(unused)]
     pub fn init(&self) -> Result<(), SchedulerError> {
@@ -164,7 +398,121 @@ impl Scheduler {
         Ok(())
     }
 
-    // Checks if task list is in active mode and schedules tasks if needed
+    // Imports a task list which has already been uploaded, then starts it if its mode is in effect
+    pub fn import_uploaded(
+        &self,
+        name: &str,
+        path: &str,
+        mode: &str,
+        version: Option<&str>,
+    ) -> Result<(), SchedulerError> {
+        import_uploaded_task_list(&self.scheduler_dir, name, path, mode, version)?;
+        self.check_stop_task_list(name, mode)?;
+        self.check_start_task_list(name, mode)
+    }
+
+    // Starts a thread which periodically imports task lists from the import directory,
+    // if one is configured
+    pub fn watch_import_dir(&self) -> Result<(), SchedulerError> {
+        let import_dir = match &self.import_dir {
+            Some(import_dir) => import_dir,
+            None => return Ok(()),
+        };
+
+        fs::create_dir_all(import_dir).map_err(|e| SchedulerError::CreateError {
+            err: e.to_string(),
+            path: import_dir.to_owned(),
+        })?;
+
+        info!("Watching {} for task lists to import", import_dir);
+        let scheduler = self.clone();
+        thread::Builder::new()
+            .spawn(move || loop {
+                scheduler.check_import_dir();
+                thread::sleep(IMPORT_POLL_INTERVAL);
+            })
+            .map_err(|e| SchedulerError::StartError {
+                err: format!("Failed to start import thread: {:?}", e),
+            })?;
+        Ok(())
+    }
+
+    // Imports any task lists waiting in the import directory.
+    // Lists are uploaded to `{import_dir}/{mode}/{name}.json`. Any which fail to import are
+    // renamed to `{name}.json.rejected`, so they aren't retried.
+    fn check_import_dir(&self) {
+        let import_dir = match &self.import_dir {
+            Some(import_dir) => import_dir,
+            None => return,
+        };
+        // Uploads are left for the active scheduler, whose state the standby copies
+        if self.role() == Role::Standby {
+            return;
+        }
+
+        let mode_dirs = match fs::read_dir(import_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir()),
+            Err(e) => {
+                warn!("Failed to read import directory {}: {}", import_dir, e);
+                return;
+            }
+        };
+
+        for mode_dir in mode_dirs {
+            let mode = match mode_dir.file_name().and_then(|mode| mode.to_str()) {
+                Some(mode) => mode.to_owned(),
+                None => continue,
+            };
+            let uploads = match fs::read_dir(&mode_dir) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path()),
+                Err(_) => continue,
+            };
+
+            for upload in uploads {
+                if upload.extension().map(|ext| ext == "json") != Some(true) {
+                    continue;
+                }
+                // Leave files which are still being written for the next pass
+                let settled = upload
+                    .metadata()
+                    .and_then(|data| data.modified())
+                    .map(|modified| {
+                        modified
+                            .elapsed()
+                            .map(|age| age >= IMPORT_SETTLE_TIME)
+                            .unwrap_or(false)
+                    })
+                    .unwrap_or(false);
+                if !settled {
+                    continue;
+                }
+
+                let (name, path) = match (
+                    upload.file_stem().and_then(|name| name.to_str()),
+                    upload.to_str(),
+                ) {
+                    (Some(name), Some(path)) => (name, path),
+                    _ => continue,
+                };
+
+                if let Err(e) = self.import_uploaded(name, path, &mode, None) {
+                    error!("Failed to import uploaded task list {}: {}", path, e);
+                    if upload.exists() {
+                        let _ = fs::rename(&upload, format!("{}.rejected", path));
+                    }
+                }
+            }
+        }
+    }
+
+    // Checks if task list is in effect for the active mode (either directly or inherited)
+    // and schedules tasks if needed. If the list in effect is disabled, any tasks running
+    // under its name are stopped instead
     pub fn check_start_task_list(
         &self,
         raw_name: &str,
@@ -173,31 +521,117 @@ impl Scheduler {
         let name = raw_name.to_lowercase();
         let mode = raw_mode.to_lowercase();
 
-        if is_mode_active(&self.scheduler_dir, &mode) {
-            let list_path = format!("{}/{}/{}.json", self.scheduler_dir, mode, name);
-            let list_path = Path::new(&list_path);
-            let list = TaskList::from_path(&list_path)?;
+        if !is_mode_in_effect(&self.scheduler_dir, &mode) {
+            return Ok(());
+        }
 
-            Ok(self.start_task_list(list)?)
-        } else {
-            Ok(())
+        let active_mode = match get_active_mode(&self.scheduler_dir)? {
+            Some(active_mode) => active_mode,
+            None => return Ok(()),
+        };
+
+        // Find the version of the task list which wins after inheritance is applied
+        let list = match get_effective_task_lists(&self.scheduler_dir, &active_mode.name)?
+            .into_iter()
+            .find(|list| list.filename == name)
+        {
+            Some(list) => list,
+            None => return Ok(()),
+        };
+
+        // The running list is checked and replaced under one lock, so that concurrent
+        // imports can't both start it
+        let stopped = {
+            let mut schedules_map = self.scheduler_map.lock().unwrap();
+            if !list.enabled {
+                // A disabled list still overrides any inherited list of the same name,
+                // so nothing by that name is run
+                schedules_map.remove(&name)
+            } else if schedules_map
+                .get(&name)
+                .map(|handle| handle.mode == list.mode())
+                .unwrap_or(false)
+            {
+                // Don't restart the list if the same version is already running
+                None
+            } else {
+                self.schedule_task_list(&mut schedules_map, list)?
+            }
+        };
+
+        if let Some(handle) = stopped {
+            self.join_tasks(handle.stop(&name));
         }
+        Ok(())
     }
 
-    // Schedules tasks associated with task list
+    // Schedules tasks associated with task list, replacing any running version of it
     fn start_task_list(&self, list: TaskList) -> Result<(), SchedulerError> {
-        let mut schedules_map = self.scheduler_map.lock().unwrap();
-        let scheduler_handle =
-            list.schedule_tasks(self.real_timer.clone(), self.tokio_handle.clone())?;
-        schedules_map.insert(list.filename, scheduler_handle);
+        let name = list.filename.to_owned();
+        let replaced = self.schedule_task_list(&mut self.scheduler_map.lock().unwrap(), list)?;
+        if let Some(old) = replaced {
+            self.join_tasks(old.stop(&name));
+        }
         Ok(())
     }
 
-    // Iterate through the active mode and kick off scheduling tasks
+    // Schedules tasks associated with task list and adds its handle to the map. The handle of
+    // any running version of the list is returned, for the caller to stop once the map is
+    // unlocked
+    fn schedule_task_list(
+        &self,
+        schedules_map: &mut HashMap<String, SchedulerHandle>,
+        list: TaskList,
+    ) -> Result<Option<SchedulerHandle>, SchedulerError> {
+        if self.role() == Role::Standby {
+            debug!("On standby, not scheduling {}", list.filename);
+            return Ok(None);
+        }
+        let scheduler_handle = list.schedule_tasks(
+            self,
+            self.clock.clone(),
+            self.tokio_handle.clone(),
+            &self.processes,
+        )?;
+        Ok(schedules_map.insert(list.filename.to_owned(), scheduler_handle))
+    }
+
+    // Waits for stopped tasks to finish, giving up after STOP_TIMEOUT.
+    // Mode change tasks stop task lists from the timer runtime, which can't run the stopped
+    // tasks (or finish the mode change task itself) while it's blocked, so they aren't waited for.
+    fn join_tasks(&self, runs: Vec<TaskHandle<()>>) {
+        if runs.is_empty() || Handle::try_current().is_ok() {
+            return;
+        }
+
+        let (done, finished) = mpsc::channel();
+        self.tokio_handle.spawn(async move {
+            for run in runs {
+                // An error means the task panicked, which has already been reported
+                let _ = run.await;
+            }
+            let _ = done.send(());
+        });
+        if finished.recv_timeout(STOP_TIMEOUT).is_err() {
+            warn!(
+                "Stopped tasks still running after {} seconds",
+                STOP_TIMEOUT.as_secs()
+            );
+        }
+    }
+
+    // Iterate through the active mode (and any modes it inherits from)
+    // and kick off scheduling tasks
     // Validation and error returning is done here and caught in
     // start() for fail over.
-    fn check_start(&self, mode_path: &str) -> Result<(), SchedulerError> {
-        for list in get_mode_task_lists(&mode_path)? {
+    fn check_start(&self, mode: &str) -> Result<(), SchedulerError> {
+        // Checked up front, since the mode's apps can't be run without them
+        get_mode_variables(&self.scheduler_dir, mode)?;
+        for list in get_effective_task_lists(&self.scheduler_dir, mode)? {
+            if !list.enabled {
+                info!("Task list '{}' is disabled, not scheduling it", list.filename);
+                continue;
+            }
             match validate_task_list(&list.path) {
                 Err(SchedulerError::TaskTimeError { description, .. }) => warn!(
                     "Found task '{}' in task list '{}' with out of bounds time",
@@ -213,8 +647,13 @@ impl Scheduler {
 
     // Iterate through the active mode and kick off scheduling tasks
     pub fn start(&self) -> Result<(), SchedulerError> {
+        if self.role() == Role::Standby {
+            debug!("On standby, not scheduling tasks");
+            return Ok(());
+        }
+
         if let Some(active_mode) = get_active_mode(&self.scheduler_dir)? {
-            if let Err(err) = self.check_start(&active_mode.path) {
+            if let Err(err) = self.check_start(&active_mode.name) {
                 if active_mode.name == SAFE_MODE {
                     error!("Failed to start safe mode: {}", err);
                     panic!("Failed to start safe mode: {}", err);
@@ -225,6 +664,7 @@ impl Scheduler {
                     );
                     activate_mode(&self.scheduler_dir, &SAFE_MODE)?;
                     self.start()?;
+                    self.notify_safe_mode(Some(active_mode.name), false, &err.to_string());
                 }
             }
             Ok(())
@@ -234,19 +674,127 @@ impl Scheduler {
         }
     }
 
-    // Stops all running tasks and clears of list of scheduler handles
+    // Activates a mode on behalf of a mode change task, then schedules the new mode's tasks.
+    // Like the activateMode mutation, activating a mode which doesn't exist fails over to
+    // safe mode.
+    pub fn change_mode(&self, name: &str) -> Result<(), SchedulerError> {
+        let previous_mode = self.active_mode_name();
+        let activated = activate_mode(&self.scheduler_dir, name);
+        match &activated {
+            Ok(()) => {}
+            Err(SchedulerError::FailoverError { err }) => {
+                self.notify_safe_mode(previous_mode.clone(), false, err)
+            }
+            Err(_) => return activated,
+        }
+
+        self.stop()?;
+        self.start()?;
+
+        if activated.is_ok()
+            && name.to_lowercase() == SAFE_MODE
+            && previous_mode.as_deref() != Some(SAFE_MODE)
+        {
+            self.notify_safe_mode(previous_mode, true, "mode_change task");
+        }
+        activated
+    }
+
+    // Stops all running tasks and clears the list of scheduler handles, then waits for the
+    // tasks to finish
     pub fn stop(&self) -> Result<(), SchedulerError> {
-        let mut schedules_map = self.scheduler_map.lock().unwrap();
-        for (name, handle) in schedules_map.drain().take(1) {
-            info!("Stopping {}'s tasks", name);
-            if let Err(_) = handle.stopper.send(()) {
-                error!("Failed to send stop to {}'s tasks", name);
+        let runs: Vec<TaskHandle<()>> = self
+            .scheduler_map
+            .lock()
+            .unwrap()
+            .drain()
+            .flat_map(|(name, handle)| handle.stop(&name))
+            .collect();
+        self.join_tasks(runs);
+        Ok(())
+    }
+
+    // Stops scheduling the task with this ID and kills any of its running processes
+    pub fn abort_task(&self, id: i32) -> Result<(), SchedulerError> {
+        let scheduled = self
+            .scheduler_map
+            .lock()
+            .unwrap()
+            .values()
+            .any(|handle| handle.task_ids.contains(&id));
+        if !scheduled {
+            return Err(SchedulerError::AbortError {
+                err: format!("Task {} is not scheduled", id),
+            });
+        }
+
+        info!("Aborting task {}", id);
+        for handle in self.scheduler_map.lock().unwrap().values_mut() {
+            handle.tasks.retain(|task| task.id != Some(id));
+        }
+        // Kill first, while the task is still tracking its process
+        let killed = self.processes.kill(Some(id));
+        self.processes.stop(id);
+        info!("Killed {} processes of task {}", killed?, id);
+        Ok(())
+    }
+
+    // Merges the running task lists into a timeline of their next `limit` executions,
+    // up to `MAX_UPCOMING_LIMIT`
+    pub fn upcoming(&self, limit: usize) -> Vec<UpcomingTask> {
+        let limit = limit.min(MAX_UPCOMING_LIMIT);
+        let now = self.clock.now();
+        let boot = self.boot_status();
+        let mut runs: Vec<(NaiveDateTime, UpcomingTask)> = vec![];
+
+        for (list, handle) in self.scheduler_map.lock().unwrap().iter() {
+            for task in &handle.tasks {
+                let times = match task.earliest_run(boot, now).and_then(|earliest| {
+                    // Tasks which don't run on this boot have no upcoming executions
+                    earliest.map_or(Ok(vec![]), |earliest| {
+                        task.upcoming_runs_after(handle.started, now, earliest, limit)
+                    })
+                }) {
+                    Ok(times) => times,
+                    Err(e) => {
+                        warn!(
+                            "Skipping task '{}' in list '{}': {}",
+                            task.name(), list, e
+                        );
+                        continue;
+                    }
+                };
+                runs.extend(times.into_iter().map(|time| {
+                    let upcoming = UpcomingTask {
+                        id: task.id,
+                        name: task.name(),
+                        list: list.to_owned(),
+                        mode: handle.mode.to_owned(),
+                        time: time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    };
+                    (time, upcoming)
+                }));
             }
         }
+
+        runs.sort_by(|a, b| (a.0, &a.1.list).cmp(&(b.0, &b.1.list)));
+        runs.into_iter()
+            .take(limit)
+            .map(|(_, upcoming)| upcoming)
+            .collect()
+    }
+
+    // Stops scheduling all tasks and kills all of their running processes.
+    // Tasks won't be scheduled again until a mode is activated or the service restarts.
+    pub fn abort_all_tasks(&self) -> Result<(), SchedulerError> {
+        info!("Aborting all tasks");
+        let killed = self.processes.kill(None);
+        self.stop()?;
+        info!("Killed {} processes", killed?);
         Ok(())
     }
 
-    // Checks if a task list exists in an active mode and stops its scheduler if needed
+    // Checks if a task list from this mode is running and stops its scheduler if needed
     pub fn check_stop_task_list(
         &self,
         raw_name: &str,
@@ -255,13 +803,22 @@ impl Scheduler {
         let name = raw_name.to_lowercase();
         let mode = raw_mode.to_lowercase();
 
-        if is_mode_active(&self.scheduler_dir, &mode) {
-            let mut schedules_map = self.scheduler_map.lock().unwrap();
-            if let Some(handle) = schedules_map.remove(&name) {
-                info!("Stopping {}'s tasks", name);
-                if let Err(_) = handle.stopper.send(()) {
-                    error!("Failed to send stop to {}'s tasks", name);
+        if is_mode_in_effect(&self.scheduler_dir, &mode) {
+            let stopped = {
+                let mut schedules_map = self.scheduler_map.lock().unwrap();
+                // A same-named list from another mode overrides (or is overridden by) this one,
+                // so leave it alone
+                let from_mode = schedules_map
+                    .get(&name)
+                    .map(|handle| handle.mode == mode)
+                    .unwrap_or(false);
+                if !from_mode {
+                    return Ok(());
                 }
+                schedules_map.remove(&name)
+            };
+            if let Some(handle) = stopped {
+                self.join_tasks(handle.stop(&name));
             }
             Ok(())
         } else {
@@ -18,17 +18,31 @@
 //! GraphQL schema for scheduler service's public interface
 //!
 
+use crate::boot::BootInfo;
+use crate::error::SchedulerError;
+use crate::event::Failover;
 use crate::mode::*;
 use crate::scheduler::{Scheduler, SAFE_MODE};
-use crate::task_list::{import_raw_task_list, import_task_list, remove_task_list};
+use crate::simulation::SimulationStatus;
+use crate::standby::Role;
+use crate::task::UpcomingTask;
+use crate::task_list::{
+    diff_task_list, get_task_list_versions, import_raw_task_list, import_task_list,
+    remove_task_list, set_task_list_enabled, TaskListDiff, TaskListVersion,
+};
 use git_version::git_version;
 use juniper::FieldResult;
 use juniper::{graphql_object, GraphQLObject};
 use kubos_service;
+use kubos_service::discovery::{self, ServiceInfo};
+use log::info;
 use serde::Deserialize;
 
 type Context = kubos_service::Context<Scheduler>;
 
+// Number of executions returned by the upcoming query when no limit is given
+const DEFAULT_UPCOMING_LIMIT: i32 = 10;
+
 // Generic GraphQL Response
 #[derive(Debug, Deserialize, GraphQLObject)]
 pub struct GenericResponse {
@@ -36,6 +50,27 @@ pub struct GenericResponse {
     pub errors: String,
 }
 
+// Logs a mutation which changes what the scheduler runs, tagged with the trace ID of the
+// request so that it can be matched with the command which was uplinked
+fn log_mutation(context: &Context, mutation: &str) {
+    if let Some(trace_id) = context.trace_id() {
+        info!("[{}] {}", trace_id, mutation);
+    }
+}
+
+// Rejects mutations which change the schedule while on standby, since the standby's
+// schedule is a copy of the active instance's and would be overwritten
+fn on_standby(context: &Context) -> Option<GenericResponse> {
+    if context.subsystem().role() == Role::Standby {
+        Some(GenericResponse {
+            success: false,
+            errors: "Scheduler is on standby".to_owned(),
+        })
+    } else {
+        None
+    }
+}
+
 pub struct QueryRoot;
 
 // Base GraphQL query model
@@ -52,6 +87,22 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
         Ok(String::from("pong"))
     }
 
+    // Returns the services listed in the system configuration file,
+    // along with whether they answered a ping
+    //
+    // {
+    //     services {
+    //         name: String,
+    //         ip: String,
+    //         port: Int,
+    //         healthy: Boolean
+    //     }
+    // }
+    field services() -> FieldResult<Vec<ServiceInfo>>
+    {
+        Ok(discovery::services("scheduler-service")?)
+    }
+
     // Returns information on the currently active mode
     // {
     //     activeMode: {
@@ -59,7 +110,9 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
     //         path: String,
     //         lastRevised: String,
     //         schedule: [TaskList],
-    //         active: Boolean
+    //         active: Boolean,
+    //         parent: String,
+    //         variables: [{ name: String, value: String }]
     //     }
     // }
     field active_mode(&executor) -> FieldResult<Option<ScheduleMode>> as "Active Mode"
@@ -75,7 +128,9 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
     //             path: String,
     //             lastRevised: String,
     //             schedule: [TaskList],
-    //             active: Boolean
+    //             active: Boolean,
+    //             parent: String,
+    //             variables: [{ name: String, value: String }]
     //         }
     //     ]
     // }
@@ -84,6 +139,116 @@ graphql_object!(QueryRoot: Context as "Query" |&self| {
         Ok(get_available_modes(&executor.context().subsystem().scheduler_dir, name)?)
     }
 
+    // Returns the version of each task list in a mode, or in every mode if none is given.
+    // The hash is the SHA-256 digest of the task list as it was uploaded, so the ground can
+    // check it against its own copy. Both are null for task lists imported before versions
+    // were recorded
+    // {
+    //     taskListVersions(mode: String): [
+    //         {
+    //             mode: String,
+    //             name: String,
+    //             version: String,
+    //             hash: String
+    //         }
+    //     ]
+    // }
+    field task_list_versions(&executor, mode: Option<String>) -> FieldResult<Vec<TaskListVersion>>
+    {
+        Ok(get_task_list_versions(&executor.context().subsystem().scheduler_dir, mode)?)
+    }
+
+    // Returns the next executions of the tasks in all running task lists,
+    // in the order they will happen. Defaults to the next 10 executions,
+    // and returns at most 1000.
+    // {
+    //     upcoming(limit: Int): [
+    //         {
+    //             id: Int,
+    //             name: String,
+    //             list: String,
+    //             mode: String,
+    //             time: String
+    //         }
+    //     ]
+    // }
+    field upcoming(&executor, limit: Option<i32>) -> FieldResult<Vec<UpcomingTask>>
+    {
+        let limit = limit.unwrap_or(DEFAULT_UPCOMING_LIMIT);
+        if limit < 0 {
+            return Err(format!("Invalid limit: {}", limit).into());
+        }
+        Ok(executor.context().subsystem().upcoming(limit as usize))
+    }
+
+    // Returns the state of the simulated clock and the executions which would have
+    // happened so far, or null if the scheduler isn't being simulated
+    // {
+    //     simulation: {
+    //         speed: Int,
+    //         time: String,
+    //         stubCommand: String,
+    //         runs: [
+    //             {
+    //                 id: Int,
+    //                 name: String,
+    //                 time: String
+    //             }
+    //         ]
+    //     }
+    // }
+    field simulation(&executor) -> FieldResult<Option<SimulationStatus>>
+    {
+        Ok(executor.context().subsystem().simulation())
+    }
+
+    // Returns whether due tasks are run. While disarmed, they're only logged
+    // {
+    //     armed: Boolean
+    // }
+    field armed(&executor) -> FieldResult<bool>
+    {
+        Ok(executor.context().subsystem().is_armed())
+    }
+
+    // Returns the number of boots of this OBC, including the current one, and its uptime in
+    // seconds over all boots. Null if the boot record couldn't be loaded
+    // {
+    //     boot: {
+    //         count: Int,
+    //         uptime: Float
+    //     }
+    // }
+    field boot(&executor) -> FieldResult<Option<BootInfo>>
+    {
+        Ok(executor.context().subsystem().boot_status().map(BootInfo::from))
+    }
+
+    // Returns why, when and from which mode the scheduler last failed over to safe mode
+    // automatically. Null if it never has. Entering safe mode with the safeMode mutation or a
+    // mode change task isn't a failover, so doesn't replace the record
+    // {
+    //     lastFailover: {
+    //         reason: String,
+    //         time: String,
+    //         previousMode: String
+    //     }
+    // }
+    field last_failover(&executor) -> FieldResult<Option<Failover>>
+    {
+        Ok(executor.context().subsystem().last_failover()?)
+    }
+
+    // Returns whether this instance is running tasks or shadowing a peer scheduler.
+    // Instances without a standby peer are always active
+    // {
+    //     role: Role
+    // }
+    field role(&executor) -> FieldResult<Role>
+    {
+        Ok(executor.context().subsystem().role())
+    }
+
     field git() -> ServiceGitHash {
         ServiceGitHash {
             name: "scheduler-service",
@@ -112,6 +277,9 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
     //    }
     // }
     field create_mode(&executor, name: String) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
         Ok(match create_mode(&executor.context().subsystem().scheduler_dir, &name) {
             Ok(_) => {
                 GenericResponse { success: true, errors: "".to_owned() }
@@ -129,6 +297,9 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
     //    }
     // }
     field remove_mode(&executor, name: String) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
         Ok(match remove_mode(&executor.context().subsystem().scheduler_dir, &name) {
             Ok(_) => {
                 GenericResponse { success: true, errors: "".to_owned() }
@@ -146,10 +317,22 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
     //    }
     // }
     field activate_mode(&executor, name: String) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
         if name == SAFE_MODE {
             return Ok(GenericResponse { success: false, errors: "Must use safeMode to activate safe".to_owned() });
         }
+        log_mutation(executor.context(), &format!("Activating mode {}", name));
+        let previous_mode = executor.context().subsystem().active_mode_name();
         Ok(match activate_mode(&executor.context().subsystem().scheduler_dir, &name)
+        .map_err(|error| {
+            // Activating a mode which doesn't exist fails over to safe mode
+            if let SchedulerError::FailoverError { .. } = error {
+                executor.context().subsystem().notify_safe_mode(previous_mode, false, &error.to_string());
+            }
+            error
+        })
         .and_then(|_| executor.context().subsystem().stop())
         .and_then(|_| executor.context().subsystem().start()) {
             Ok(_) => {
@@ -159,6 +342,58 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
         })
     }
 
+    // Sets the mode whose task lists are inherited by a mode.
+    // Task lists in the mode override any same-named task lists inherited from the parent.
+    // Omitting the parent clears it.
+    //
+    // mutation {
+    //     setModeParent(name: String!, parent: String): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field set_mode_parent(&executor, name: String, parent: Option<String>) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        let scheduler_dir = &executor.context().subsystem().scheduler_dir;
+        // Checked before the change too, since the mode may be leaving the active lineage
+        let was_in_effect = is_mode_in_effect(scheduler_dir, &name);
+        Ok(match set_mode_parent(scheduler_dir, &name, parent)
+        .and_then(|_| {
+            if was_in_effect || is_mode_in_effect(scheduler_dir, &name) {
+                executor.context().subsystem().stop()
+                    .and_then(|_| executor.context().subsystem().start())
+            } else {
+                Ok(())
+            }
+        }) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
+    // Replaces the config variables of a mode, which are exported as environment variables
+    // to every app run while the mode is in effect. Variables inherited from the parent mode
+    // can be overridden. An empty list clears them.
+    //
+    // mutation {
+    //     setModeVariables(name: String!, variables: [{ name: String!, value: String! }]!): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field set_mode_variables(&executor, name: String, variables: Vec<ModeVariableInput>) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Setting variables of mode {}", name));
+        Ok(match set_mode_variables(&executor.context().subsystem().scheduler_dir, &name, variables) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
     // Activates the safe mode
     //
     // mutation {
@@ -168,26 +403,59 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
     //    }
     // }
     field safe_mode(&executor) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), "Activating safe mode");
+        let previous_mode = executor.context().subsystem().active_mode_name();
         Ok(match activate_mode(&executor.context().subsystem().scheduler_dir, SAFE_MODE)
         .and_then(|_| executor.context().subsystem().stop())
         .and_then(|_| executor.context().subsystem().start()) {
             Ok(_) => {
+                if previous_mode.as_ref().map(|mode| mode != SAFE_MODE).unwrap_or(true) {
+                    executor.context().subsystem().notify_safe_mode(previous_mode, true, "safeMode requested");
+                }
                 GenericResponse { success: true, errors: "".to_owned() }
             },
             Err(error) => GenericResponse { success: false, errors: error.to_string() }
         })
     }
 
-    // Imports a new task list into a mode
+    // Arms or disarms the scheduler. While disarmed, tasks are still scheduled and
+    // logged when they're due, but aren't run. The state persists across restarts.
+    //
+    // mutation {
+    //     setArmed(armed: Boolean!): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field set_armed(&executor, armed: bool) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), if armed { "Arming" } else { "Disarming" });
+        Ok(match executor.context().subsystem().set_armed(armed) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
+    // Imports a new task list into a mode. The optional version string is recorded with
+    // the task list's hash
     //
     // mutation {
-    //     importTaskList(name: String!, path: String!, mode: String!): {
+    //     importTaskList(name: String!, path: String!, mode: String!, version: String): {
     //         errors: String,
     //         success: Boolean
     //    }
     // }
-    field import_task_list(&executor, name: String, path: String, mode: String) -> FieldResult<GenericResponse> {
-        Ok(match import_task_list(&executor.context().subsystem().scheduler_dir, &name, &path, &mode)
+    field import_task_list(&executor, name: String, path: String, mode: String, version: Option<String>) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Importing task list {} into {}", name, mode));
+        Ok(match import_task_list(&executor.context().subsystem().scheduler_dir, &name, &path, &mode, version.as_deref())
         .and_then(|_| executor.context().subsystem().check_stop_task_list(&name, &mode))
         .and_then(|_| executor.context().subsystem().check_start_task_list(&name, &mode)) {
             Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
@@ -195,6 +463,27 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
         })
     }
 
+    // Imports a task list which has already been uploaded (eg. with the file service) into a mode.
+    // The list is validated before it's moved into place, and the uploaded file is only removed
+    // if the import succeeds.
+    //
+    // mutation {
+    //     importUploaded(name: String!, path: String!, mode: String!, version: String): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field import_uploaded(&executor, name: String, path: String, mode: String, version: Option<String>) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Importing task list {} into {}", name, mode));
+        Ok(match executor.context().subsystem().import_uploaded(&name, &path, &mode, version.as_deref()) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
     // Removes a task list from a mode
     //
     // mutation {
@@ -204,8 +493,14 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
     //    }
     // }
     field remove_task_list(&executor, name: String, mode: String) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Removing task list {} from {}", name, mode));
         Ok(match remove_task_list(&executor.context().subsystem().scheduler_dir, &name, &mode)
-        .and_then(|_| executor.context().subsystem().check_stop_task_list(&name, &mode)) {
+        .and_then(|_| executor.context().subsystem().check_stop_task_list(&name, &mode))
+        // Start any inherited task list which was being overridden by the removed one
+        .and_then(|_| executor.context().subsystem().check_start_task_list(&name, &mode)) {
             Ok(_) => {
                 GenericResponse { success: true, errors: "".to_owned() }
             },
@@ -213,20 +508,127 @@ graphql_object!(MutationRoot: Context as "Mutation" |&self| {
         })
     }
 
+    // Disables a task list in a mode without removing it. If the mode is in effect, the
+    // list's tasks are stopped. The list stays disabled across restarts and re-imports.
+    //
+    // mutation {
+    //     disableTaskList(name: String!, mode: String!): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field disable_task_list(&executor, name: String, mode: String) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Disabling task list {} in {}", name, mode));
+        Ok(match set_task_list_enabled(&executor.context().subsystem().scheduler_dir, &name, &mode, false)
+        .and_then(|_| executor.context().subsystem().check_start_task_list(&name, &mode)) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
+    // Re-enables a disabled task list. If the mode is in effect, the list's tasks are
+    // scheduled again.
+    //
+    // mutation {
+    //     enableTaskList(name: String!, mode: String!): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field enable_task_list(&executor, name: String, mode: String) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Enabling task list {} in {}", name, mode));
+        Ok(match set_task_list_enabled(&executor.context().subsystem().scheduler_dir, &name, &mode, true)
+        .and_then(|_| executor.context().subsystem().check_start_task_list(&name, &mode)) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
     // Imports a raw task list into a mode
     //
     // mutation {
-    //     importRawTaskList(path: String!, name: String!, mode: String!): {
+    //     importRawTaskList(path: String!, name: String!, mode: String!, version: String): {
     //         errors: String,
     //         success: Boolean
     //    }
     // }
-    field import_raw_task_list(&executor, name: String, mode: String, json: String) -> FieldResult<GenericResponse> {
-        Ok(match import_raw_task_list(&executor.context().subsystem().scheduler_dir, &name, &mode, &json)
+    field import_raw_task_list(&executor, name: String, mode: String, json: String, version: Option<String>) -> FieldResult<GenericResponse> {
+        if let Some(response) = on_standby(executor.context()) {
+            return Ok(response);
+        }
+        log_mutation(executor.context(), &format!("Importing task list {} into {}", name, mode));
+        Ok(match import_raw_task_list(&executor.context().subsystem().scheduler_dir, &name, &mode, &json, version.as_deref())
         .and_then(|_| executor.context().subsystem().check_stop_task_list(&name, &mode))
         .and_then(|_| executor.context().subsystem().check_start_task_list(&name, &mode)) {
             Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
             Err(error) => GenericResponse { success: false, errors: error.to_string() }
         })
     }
+
+    // Compares a task list in a mode with a reference copy uploaded to the given path, and
+    // reports the tasks which differ. Nothing is changed
+    //
+    // mutation {
+    //     diffTaskList(name: String!, mode: String!, path: String!): {
+    //         errors: String,
+    //         success: Boolean,
+    //         matches: Boolean,
+    //         onboardHash: String,
+    //         referenceHash: String,
+    //         differences: [{ task: String, kind: DifferenceKind, fields: [String] }]
+    //    }
+    // }
+    field diff_task_list(&executor, name: String, mode: String, path: String) -> FieldResult<TaskListDiff> {
+        Ok(match diff_task_list(&executor.context().subsystem().scheduler_dir, &name, &mode, &path) {
+            Ok(diff) => diff,
+            Err(error) => TaskListDiff {
+                success: false,
+                errors: error.to_string(),
+                matches: false,
+                onboard_hash: None,
+                reference_hash: None,
+                differences: vec![],
+            }
+        })
+    }
+
+    // Stops scheduling a task and kills its running app, if any.
+    // Other tasks in the same task list are unaffected.
+    //
+    // mutation {
+    //     abortTask(id: Int!): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field abort_task(&executor, id: i32) -> FieldResult<GenericResponse> {
+        log_mutation(executor.context(), &format!("Aborting task {}", id));
+        Ok(match executor.context().subsystem().abort_task(id) {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
+
+    // Stops scheduling all tasks and kills all running apps.
+    // Tasks remain stopped until a mode is activated or the service restarts.
+    //
+    // mutation {
+    //     abortAllTasks(): {
+    //         errors: String,
+    //         success: Boolean
+    //    }
+    // }
+    field abort_all_tasks(&executor) -> FieldResult<GenericResponse> {
+        log_mutation(executor.context(), "Aborting all tasks");
+        Ok(match executor.context().subsystem().abort_all_tasks() {
+            Ok(_) => GenericResponse { success: true, errors: "".to_owned() },
+            Err(error) => GenericResponse { success: false, errors: error.to_string() }
+        })
+    }
 });
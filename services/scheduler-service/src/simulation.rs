@@ -0,0 +1,203 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//!
+//! Clocks which tasks are scheduled against, including the accelerated clock used to
+//! validate schedules on the ground
+//!
+
+use crate::app::{App, ExecLimits};
+use crate::process::TaskProcesses;
+#[cfg(test)]
+use crate::timer::mock::MockTimer;
+use crate::timer::{Timer, TimerFuture};
+use chrono::{Duration, NaiveDateTime, Utc};
+use clock_timer::RealTimer;
+use juniper::GraphQLObject;
+use log::info;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::time::delay_for;
+
+// Number of simulated executions kept for the simulation query. Older ones are dropped
+const MAX_RECORDED_RUNS: usize = 10_000;
+
+// Source of the time which tasks are scheduled against
+#[derive(Clone)]
+pub enum Clock {
+    // The system clock. Apps are run when their tasks are due
+    Real(RealTimer),
+    // An accelerated clock. Apps aren't run, their executions are only recorded
+    Simulated(Simulation),
+    // A virtual clock which unit tests move forward by hand. Apps aren't run either
+    #[cfg(test)]
+    Mock(MockTimer),
+}
+
+impl Clock {
+    // The timer behind this clock
+    fn timer(&self) -> &dyn Timer {
+        match self {
+            Clock::Real(timer) => timer,
+            Clock::Simulated(simulation) => simulation,
+            #[cfg(test)]
+            Clock::Mock(timer) => timer,
+        }
+    }
+
+    // Current UTC time according to this clock
+    pub fn now(&self) -> NaiveDateTime {
+        self.timer().now()
+    }
+
+    // Waits until the given time according to this clock
+    pub async fn at(&self, when: NaiveDateTime) {
+        self.timer().at(when).await
+    }
+}
+
+// An execution which would have happened if the scheduler wasn't being simulated
+#[derive(Clone, Debug, GraphQLObject)]
+pub struct SimulatedRun {
+    // ID of the task, if it has one
+    pub id: Option<i32>,
+    // Name of the app the task would have run, or `mode_change:{mode}` for mode changes
+    pub name: String,
+    // Simulated UTC time of the execution, in yyyy-mm-dd hh:mm:ss format
+    pub time: String,
+}
+
+// Current state of a simulation
+#[derive(Clone, Debug, GraphQLObject)]
+pub struct SimulationStatus {
+    // Number of simulated seconds which pass each second
+    pub speed: i32,
+    // Current simulated UTC time, in yyyy-mm-dd hh:mm:ss format
+    pub time: String,
+    // Command run in place of each app, if there is one
+    pub stub_command: Option<String>,
+    // The executions which would have happened so far, oldest first
+    pub runs: Vec<SimulatedRun>,
+}
+
+// Clock which runs `speed` times faster than the system clock, from `start`.
+// Lets a schedule covering days be checked in minutes on a flatsat.
+#[derive(Clone)]
+pub struct Simulation {
+    speed: u32,
+    // Simulated time at which the simulation began
+    start: NaiveDateTime,
+    // Real time at which the simulation began
+    origin: Instant,
+    // Command run in place of each app, with the app's name and arguments as its arguments
+    stub_command: Option<String>,
+    runs: Arc<Mutex<VecDeque<SimulatedRun>>>,
+}
+
+impl Simulation {
+    // Starts a simulation at the given time, or the current time if there isn't one
+    pub fn new(speed: u32, start: Option<NaiveDateTime>, stub_command: Option<String>) -> Self {
+        Simulation {
+            speed: speed.max(1),
+            start: start.unwrap_or_else(|| Utc::now().naive_utc()),
+            origin: Instant::now(),
+            stub_command,
+            runs: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn now(&self) -> NaiveDateTime {
+        let elapsed = self.origin.elapsed().as_millis() as i64;
+        self.start + Duration::milliseconds(elapsed * i64::from(self.speed))
+    }
+
+    async fn at(&self, when: NaiveDateTime) {
+        let remaining = when - self.now();
+        if remaining <= Duration::zero() {
+            return;
+        }
+        if let Ok(real) = (remaining / self.speed as i32).to_std() {
+            delay_for(real).await;
+        }
+    }
+
+    // Records that the app would have been run at `when`, running the stub command if
+    // there is one
+    pub async fn execute(
+        &self,
+        id: Option<i32>,
+        app: &App,
+        when: NaiveDateTime,
+        variables: &BTreeMap<String, String>,
+        processes: &TaskProcesses,
+    ) {
+        self.record(id, &app.name, when);
+
+        if let Some(stub_command) = &self.stub_command {
+            let mut args = vec![app.name.to_owned()];
+            args.extend(app.args.iter().flatten().cloned());
+            let stub = App {
+                name: stub_command.to_owned(),
+                args: Some(args),
+                config: None,
+            };
+            stub.execute(id, &ExecLimits::default(), variables, processes)
+                .await;
+        }
+    }
+
+    // Records an execution of the named task at `when`
+    pub fn record(&self, id: Option<i32>, name: &str, when: NaiveDateTime) {
+        let time = when.format("%Y-%m-%d %H:%M:%S").to_string();
+        info!("Simulated task {:?} {} at {}", id, name, time);
+
+        if let Ok(mut runs) = self.runs.lock() {
+            if runs.len() >= MAX_RECORDED_RUNS {
+                runs.pop_front();
+            }
+            runs.push_back(SimulatedRun {
+                id,
+                name: name.to_owned(),
+                time,
+            });
+        }
+    }
+
+    pub fn status(&self) -> SimulationStatus {
+        SimulationStatus {
+            speed: self.speed as i32,
+            time: self.now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            stub_command: self.stub_command.clone(),
+            runs: self
+                .runs
+                .lock()
+                .map(|runs| runs.iter().cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Timer for Simulation {
+    fn now(&self) -> NaiveDateTime {
+        Simulation::now(self)
+    }
+
+    fn at(&self, when: NaiveDateTime) -> TimerFuture {
+        let simulation = self.clone();
+        Box::pin(async move { simulation.at(when).await })
+    }
+}
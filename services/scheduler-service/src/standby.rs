@@ -0,0 +1,464 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//!
+//! Warm-standby redundancy between the schedulers of two OBCs
+//!
+//! Both instances send each other a heartbeat over UDP every heartbeat interval. The active
+//! instance runs tasks as usual. The standby runs nothing, but keeps a copy of the active
+//! instance's modes, task lists, active mode and arming state: each heartbeat carries a digest
+//! of that state, and when the standby's copy doesn't match it asks for the state to be sent.
+//!
+//! If the standby stops hearing from an active peer for the takeover timeout, it becomes active
+//! and starts the copied schedule. The timeout is extended by one heartbeat interval for each
+//! step of priority, so that when neither instance is active (eg. when both OBCs boot), the
+//! preferred instance takes over first. Should both instances end up active (eg. after the link
+//! between them recovers), the one with the higher priority value goes back to standby.
+//!
+
+use crate::error::SchedulerError;
+use crate::mode::{activate_mode, VARIABLES_FILE};
+use crate::scheduler::Scheduler;
+use crate::task_list::{DISABLED_EXTENSION, VERSION_EXTENSION};
+use juniper::GraphQLEnum;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the socket is checked for messages
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Largest datagram which can be received
+const MAX_MESSAGE_SIZE: usize = 65_507;
+// File in a mode directory which holds the name of its parent mode
+const PARENT_FILE: &str = "parent";
+
+// Role of this scheduler instance
+#[derive(Clone, Copy, Debug, Eq, GraphQLEnum, PartialEq)]
+pub enum Role {
+    // Running tasks
+    Active,
+    // Shadowing the active instance, ready to take over
+    Standby,
+}
+
+// Settings for warm-standby redundancy, from the `[scheduler-service.standby]` config section
+#[derive(Clone, Debug)]
+pub struct StandbyConfig {
+    // Local address the peer's messages are received on
+    pub listen: SocketAddr,
+    // Address the peer receives messages on
+    pub peer: SocketAddr,
+    // The instance with the lower priority is preferred. The two instances' priorities must differ
+    pub priority: u8,
+    // Time between heartbeats
+    pub heartbeat_interval: Duration,
+    // Time without hearing from an active peer before the standby takes over
+    pub takeover_timeout: Duration,
+}
+
+// Messages exchanged between the instances, as CBOR
+#[derive(Debug, Deserialize, Serialize)]
+enum Message {
+    // Sent by both instances every heartbeat interval
+    Heartbeat {
+        priority: u8,
+        active: bool,
+        // Digest of the instance's state
+        digest: u64,
+    },
+    // Asks the active instance for its state
+    SyncRequest,
+    // One of the active instance's task list, disabled flag, parent or variables files, by path
+    // relative to the schedules dir
+    File {
+        path: String,
+        contents: String,
+    },
+    // Follows the files, listing everything the standby should hold
+    Manifest {
+        modes: Vec<String>,
+        files: Vec<String>,
+        active_mode: Option<String>,
+        armed: bool,
+    },
+}
+
+// State which the standby copies from the active instance
+#[derive(Debug, Default, PartialEq)]
+struct Snapshot {
+    modes: Vec<String>,
+    // Task list, disabled flag, parent and variables files, by path relative to the
+    // schedules dir
+    files: BTreeMap<String, String>,
+    active_mode: Option<String>,
+    armed: bool,
+}
+
+impl Snapshot {
+    fn read(scheduler: &Scheduler) -> Result<Self, SchedulerError> {
+        let dir = Path::new(&scheduler.scheduler_dir);
+        let read_err = |e: std::io::Error| SchedulerError::StandbyError {
+            err: format!("Failed to read schedules dir: {}", e),
+        };
+
+        let mut snapshot = Snapshot {
+            armed: scheduler.is_armed(),
+            active_mode: fs::read_link(dir.join("active"))
+                .ok()
+                .and_then(|path| path.file_name()?.to_str().map(|name| name.to_owned())),
+            ..Default::default()
+        };
+
+        for entry in fs::read_dir(dir).map_err(read_err)? {
+            let entry = entry.map_err(read_err)?;
+            // The active mode link isn't a directory of its own
+            if !entry.file_type().map_err(read_err)?.is_dir() {
+                continue;
+            }
+            let mode = match entry.file_name().to_str() {
+                Some(mode) if is_mode_name(mode) => mode.to_owned(),
+                _ => continue,
+            };
+
+            for file in fs::read_dir(entry.path()).map_err(read_err)? {
+                let file = file.map_err(read_err)?;
+                let name = match file.file_name().to_str() {
+                    Some(name) if is_synced_file(name) => name.to_owned(),
+                    _ => continue,
+                };
+                if file.file_type().map_err(read_err)?.is_file() {
+                    let contents = fs::read_to_string(file.path()).map_err(read_err)?;
+                    snapshot
+                        .files
+                        .insert(format!("{}/{}", mode, name), contents);
+                }
+            }
+            snapshot.modes.push(mode);
+        }
+        snapshot.modes.sort();
+
+        Ok(snapshot)
+    }
+
+    // FNV-1a hash of the state, which both instances compute the same way
+    fn digest(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut add = |bytes: &[u8]| {
+            for byte in bytes.iter().chain(&[0u8]) {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        };
+
+        for mode in &self.modes {
+            add(mode.as_bytes());
+        }
+        for (path, contents) in &self.files {
+            add(path.as_bytes());
+            add(contents.as_bytes());
+        }
+        add(self.active_mode.as_deref().unwrap_or("").as_bytes());
+        add(&[self.armed as u8]);
+        hash
+    }
+}
+
+// Task lists, their disabled flags and version files, parent and variables files are copied.
+// Anything else (eg. staged imports) is left alone
+fn is_synced_file(name: &str) -> bool {
+    !name.starts_with('.')
+        && (name == PARENT_FILE
+            || name == VARIABLES_FILE
+            || name.ends_with(".json")
+            || Path::new(name).extension().map_or(false, |ext| {
+                ext == DISABLED_EXTENSION || ext == VERSION_EXTENSION
+            }))
+}
+
+// Mode directories are copied. Anything else (eg. the active mode link) is left alone
+fn is_mode_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/') && name != "active"
+}
+
+// Checks that a path sent by the peer names a file directly inside a mode directory
+fn checked_path(path: &str) -> Result<(&str, &str), SchedulerError> {
+    let mut parts = path.split('/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(mode), Some(name), None) if is_mode_name(mode) && is_synced_file(name) => {
+            Ok((mode, name))
+        }
+        _ => Err(SchedulerError::StandbyError {
+            err: format!("Invalid path from peer: {}", path),
+        }),
+    }
+}
+
+// Exchanges heartbeats and state with the peer scheduler
+struct Standby {
+    scheduler: Scheduler,
+    config: StandbyConfig,
+    socket: UdpSocket,
+    // When an active peer was last heard from
+    peer_active: Instant,
+    // When the last heartbeat was sent
+    heartbeat_sent: Option<Instant>,
+}
+
+impl Standby {
+    fn run(mut self) {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            if self.heartbeat_sent.map_or(true, |sent| {
+                sent.elapsed() >= self.config.heartbeat_interval
+            }) {
+                self.send_heartbeat();
+            }
+
+            // A timeout just means it's time to check the heartbeats again
+            if let Ok((size, peer)) = self.socket.recv_from(&mut buf) {
+                if peer.ip() != self.config.peer.ip() {
+                    debug!("Ignoring standby message from {}", peer);
+                    continue;
+                }
+                match serde_cbor::from_slice(&buf[0..size]) {
+                    Ok(message) => {
+                        if let Err(e) = self.handle(message) {
+                            error!("{}", e);
+                        }
+                    }
+                    Err(e) => warn!("Invalid standby message from {}: {}", peer, e),
+                }
+            }
+
+            if self.scheduler.role() == Role::Standby
+                && self.peer_active.elapsed() >= self.takeover_timeout()
+            {
+                self.take_over();
+            }
+        }
+    }
+
+    // The preferred instance takes over first
+    fn takeover_timeout(&self) -> Duration {
+        self.config.takeover_timeout
+            + self.config.heartbeat_interval * u32::from(self.config.priority)
+    }
+
+    fn send(&self, message: &Message) {
+        let sent = serde_cbor::to_vec(message)
+            .map_err(|e| e.to_string())
+            .and_then(|buf| {
+                self.socket
+                    .send_to(&buf, self.config.peer)
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = sent {
+            debug!(
+                "Failed to send standby message to {}: {}",
+                self.config.peer, e
+            );
+        }
+    }
+
+    fn send_heartbeat(&mut self) {
+        self.heartbeat_sent = Some(Instant::now());
+        match Snapshot::read(&self.scheduler) {
+            Ok(snapshot) => self.send(&Message::Heartbeat {
+                priority: self.config.priority,
+                active: self.scheduler.role() == Role::Active,
+                digest: snapshot.digest(),
+            }),
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    fn handle(&mut self, message: Message) -> Result<(), SchedulerError> {
+        let role = self.scheduler.role();
+        match message {
+            Message::Heartbeat {
+                priority,
+                active: true,
+                digest,
+            } => {
+                self.peer_active = Instant::now();
+                match role {
+                    Role::Active if priority < self.config.priority => self.stand_down(),
+                    Role::Active if priority == self.config.priority => {
+                        error!("Both schedulers are active with priority {}", priority)
+                    }
+                    Role::Active => {}
+                    Role::Standby => {
+                        if Snapshot::read(&self.scheduler)?.digest() != digest {
+                            debug!("Requesting state from active scheduler");
+                            self.send(&Message::SyncRequest);
+                        }
+                    }
+                }
+            }
+            Message::Heartbeat { active: false, .. } => {}
+            Message::SyncRequest if role == Role::Active => self.send_state()?,
+            Message::File { path, contents } if role == Role::Standby => {
+                self.write_file(&path, &contents)?
+            }
+            Message::Manifest {
+                modes,
+                files,
+                active_mode,
+                armed,
+            } if role == Role::Standby => self.apply_manifest(modes, files, active_mode, armed)?,
+            _ => debug!("Ignoring standby message while {:?}", role),
+        }
+        Ok(())
+    }
+
+    fn send_state(&self) -> Result<(), SchedulerError> {
+        let snapshot = Snapshot::read(&self.scheduler)?;
+        for (path, contents) in &snapshot.files {
+            self.send(&Message::File {
+                path: path.to_owned(),
+                contents: contents.to_owned(),
+            });
+        }
+        self.send(&Message::Manifest {
+            modes: snapshot.modes,
+            files: snapshot.files.keys().cloned().collect(),
+            active_mode: snapshot.active_mode,
+            armed: snapshot.armed,
+        });
+        Ok(())
+    }
+
+    // Writes a copy of one of the active instance's files. The file is written next to its
+    // destination and then renamed into place, so a half-written task list is never seen
+    fn write_file(&self, path: &str, contents: &str) -> Result<(), SchedulerError> {
+        let (mode, name) = checked_path(path)?;
+        let mode_dir = Path::new(&self.scheduler.scheduler_dir).join(mode);
+        let temp = mode_dir.join(format!(".{}.sync", name));
+        fs::create_dir_all(&mode_dir)
+            .and_then(|_| fs::write(&temp, contents))
+            .and_then(|_| fs::rename(&temp, mode_dir.join(name)))
+            .map_err(|e| SchedulerError::StandbyError {
+                err: format!("Failed to write {}: {}", path, e),
+            })
+    }
+
+    // Removes anything the active instance doesn't have, then matches its active mode and
+    // arming state. Any files which didn't arrive are sent again after the next heartbeat,
+    // since the digests won't match
+    fn apply_manifest(
+        &self,
+        modes: Vec<String>,
+        files: Vec<String>,
+        active_mode: Option<String>,
+        armed: bool,
+    ) -> Result<(), SchedulerError> {
+        let dir = Path::new(&self.scheduler.scheduler_dir);
+        let local = Snapshot::read(&self.scheduler)?;
+        let remove_err = |name: &str, e: std::io::Error| SchedulerError::StandbyError {
+            err: format!("Failed to remove {}: {}", name, e),
+        };
+
+        for path in local.files.keys().filter(|path| !files.contains(path)) {
+            fs::remove_file(dir.join(path)).map_err(|e| remove_err(path, e))?;
+        }
+        for mode in local.modes.iter().filter(|mode| !modes.contains(mode)) {
+            fs::remove_dir_all(dir.join(mode)).map_err(|e| remove_err(mode, e))?;
+        }
+        for mode in &modes {
+            if !is_mode_name(mode) {
+                return Err(SchedulerError::StandbyError {
+                    err: format!("Invalid mode from peer: {}", mode),
+                });
+            }
+            fs::create_dir_all(dir.join(mode)).map_err(|e| SchedulerError::StandbyError {
+                err: format!("Failed to create mode {}: {}", mode, e),
+            })?;
+        }
+
+        if let Some(active_mode) = active_mode {
+            if local.active_mode.as_ref() != Some(&active_mode) {
+                activate_mode(&self.scheduler.scheduler_dir, &active_mode)?;
+            }
+        }
+        if local.armed != armed {
+            self.scheduler.set_armed(armed)?;
+        }
+        Ok(())
+    }
+
+    fn take_over(&mut self) {
+        warn!(
+            "No heartbeat from an active scheduler at {} for {:?}, taking over",
+            self.config.peer,
+            self.peer_active.elapsed()
+        );
+        self.scheduler.set_role(Role::Active);
+        if let Err(e) = self.scheduler.start() {
+            error!("Failed to schedule tasks: {:?}", e);
+        }
+        // Let the peer know straight away
+        self.send_heartbeat();
+    }
+
+    fn stand_down(&mut self) {
+        warn!(
+            "Scheduler at {} is active with a lower priority, going to standby",
+            self.config.peer
+        );
+        self.scheduler.set_role(Role::Standby);
+        if let Err(e) = self.scheduler.stop() {
+            error!("Failed to stop tasks: {:?}", e);
+        }
+    }
+}
+
+// Starts exchanging heartbeats with the peer scheduler. The scheduler starts on standby,
+// and takes over if no active peer is heard from
+pub fn start(scheduler: &Scheduler, config: StandbyConfig) -> Result<(), SchedulerError> {
+    let start_err = |e: std::io::Error| SchedulerError::StartError {
+        err: format!("Failed to start standby socket: {}", e),
+    };
+    let socket = UdpSocket::bind(config.listen).map_err(start_err)?;
+    socket
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(start_err)?;
+
+    scheduler.set_role(Role::Standby);
+    info!(
+        "Starting on standby, with peer scheduler at {}",
+        config.peer
+    );
+
+    let standby = Standby {
+        scheduler: scheduler.clone(),
+        config,
+        socket,
+        peer_active: Instant::now(),
+        heartbeat_sent: None,
+    };
+    thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || standby.run())
+        .map_err(|e| SchedulerError::StartError {
+            err: format!("Failed to start standby thread: {:?}", e),
+        })?;
+    Ok(())
+}
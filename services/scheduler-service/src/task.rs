@@ -18,19 +18,25 @@
 //! Definitions and functions for dealing with tasks & scheduling
 //!
 
-use crate::app::App;
+use crate::app::{lookup_group, lookup_user, App, ExecLimits};
+use crate::boot::BootStatus;
 use crate::error::SchedulerError;
+use crate::process::TaskProcesses;
+use crate::scheduler::Scheduler;
+use crate::simulation::Clock;
 use chrono::offset::TimeZone;
 use chrono::Duration;
+use chrono::NaiveDate;
 use chrono::NaiveDateTime;
 use chrono::Utc;
-use clock_timer::RealTimer;
+use futures::future;
 use juniper::GraphQLObject;
-use log::error;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::Arc;
 use tokio::select;
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::{Receiver, RecvError};
 
 // Configuration used to schedule app execution
 #[derive(Clone, Debug, GraphQLObject, Serialize, Deserialize)]
@@ -46,52 +52,140 @@ pub struct Task {
     // Used by recurring tasks
     pub period: Option<String>,
     // Details of the app to be executed
-    pub app: App,
+    pub app: Option<App>,
+    // Mode to activate when the task is due, in place of running an app
+    pub mode_change: Option<String>,
+    // User to run the app as, by name or UID. Requires the scheduler to be running as root
+    pub user: Option<String>,
+    // Group to run the app as, by name or GID. Defaults to the user's primary group
+    pub group: Option<String>,
+    // Niceness to run the app with, from -20 (highest priority) to 19 (lowest)
+    pub nice: Option<i32>,
+    // Maximum memory the app may use, in kilobytes
+    pub max_memory_kb: Option<i32>,
+    // Maximum CPU time the app may use, in seconds
+    pub max_cpu_secs: Option<i32>,
+    // Fix the execution time of a one-shot delay task when its task list is imported,
+    // rather than each time it is scheduled, so that it survives a restart
+    pub persist: Option<bool>,
+    // Execution time of a persisted delay task in yyyy-mm-dd hh:mm:ss format.
+    // Recorded by the scheduler when the task list is imported
+    pub due: Option<String>,
+    // Only schedule the task on the first N boots of the OBC
+    pub max_boots: Option<i32>,
+    // Cumulative uptime over all boots the task waits for before running,
+    // specified in Xh Ym Zs format
+    pub min_uptime: Option<String>,
+    // UTC dates and time ranges during which a recurring task's executions are skipped.
+    // Either a date in yyyy-mm-dd format, covering the whole day, or a start and end
+    // separated by '/', each a date or a time in yyyy-mm-dd hh:mm:ss format.
+    // An end date covers the whole of that day
+    pub except: Option<Vec<String>>,
+}
+
+// A span of time during which a recurring task isn't run, from `start` up to (but not
+// including) `end`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exclusion {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+impl Exclusion {
+    fn contains(&self, time: NaiveDateTime) -> bool {
+        self.start <= time && time < self.end
+    }
 }
 
 impl Task {
     fn description(&self) -> String {
         if let Some(id) = self.id {
-            format!("{}: {}", id, self.app.name)
+            format!("{}: {}", id, self.name())
         } else {
-            format!("{}", self.app.name)
+            self.name()
         }
     }
 
-    // Parse timer delay duration from either delay or time fields
-    pub fn get_absolute(&self) -> Result<NaiveDateTime, SchedulerError> {
-        if self.delay.is_some() && self.time.is_some() {
-            return Err(SchedulerError::TaskParseError {
-                err: "Both delay and time defined".to_owned(),
-                description: self.description(),
-            });
+    // Name of the app the task runs, or `mode_change:{mode}` for mode changes
+    pub fn name(&self) -> String {
+        match (&self.app, &self.mode_change) {
+            (Some(app), _) => app.name.to_owned(),
+            (None, Some(mode)) => format!("mode_change:{}", mode),
+            (None, None) => String::new(),
         }
-        if let Some(delay) = &self.delay {
-            Ok(parse_hms_field(delay.to_owned()).map(|d| Utc::now().naive_utc() + d)?)
-        } else if let Some(time) = &self.time {
-            let run_time = Utc
-                .datetime_from_str(&time, "%Y-%m-%d %H:%M:%S%.3f")
-                .map_err(|e| SchedulerError::TaskParseError {
-                    err: format!("Failed to parse time field '{}': {}", time, e),
-                    description: self.description(),
-                })?;
-            let now = chrono::Utc::now();
+    }
+
+    // Check that the task either runs an app or changes the mode
+    pub fn check_action(&self) -> Result<(), SchedulerError> {
+        let err = match (&self.app, &self.mode_change) {
+            (Some(_), None) | (None, Some(_)) => return Ok(()),
+            (Some(_), Some(_)) => "Both app and mode_change defined",
+            (None, None) => "No app or mode_change defined",
+        };
+        Err(SchedulerError::TaskParseError {
+            err: err.to_owned(),
+            description: self.description(),
+        })
+    }
 
-            if run_time < now {
-                Err(SchedulerError::TaskTimeError {
-                    err: format!("Task scheduled for past time: {}", time),
-                    description: self.app.name.to_owned(),
+    // Check that the mode a mode change task activates exists
+    pub fn check_mode_change(&self, scheduler_dir: &str) -> Result<(), SchedulerError> {
+        match &self.mode_change {
+            Some(mode) if !Path::new(scheduler_dir).join(mode.to_lowercase()).is_dir() => {
+                Err(SchedulerError::TaskParseError {
+                    err: format!("Mode '{}' not found", mode),
+                    description: self.description(),
                 })
             }
-            // else if (run_time - now) > chrono::Duration::days(90) {
+            _ => Ok(()),
+        }
+    }
+
+    // Parse timer delay duration from either delay or time fields
+    pub fn get_absolute(&self, now: NaiveDateTime) -> Result<NaiveDateTime, SchedulerError> {
+        let run_time = self.first_run(now)?;
+
+        match self.time.as_ref().or_else(|| self.due.as_ref()) {
+            Some(time) if run_time < now => Err(SchedulerError::TaskTimeError {
+                err: format!("Task scheduled for past time: {}", time),
+                description: self.name(),
+            }),
+            // Some(time) if (run_time - now) > chrono::Duration::days(90) => {
             //     Err(SchedulerError::TaskTimeError {
             //         err: format!("Task scheduled beyond 90 days in the future: {}", time),
             //         description: self.description(),
             //     })
             // }
-            else {
-                Ok(run_time.naive_utc())
+            _ => Ok(run_time),
+        }
+    }
+
+    // Time of the first execution of this task, if it was scheduled at `started`
+    fn first_run(&self, started: NaiveDateTime) -> Result<NaiveDateTime, SchedulerError> {
+        if self.delay.is_some() && self.time.is_some() {
+            return Err(SchedulerError::TaskParseError {
+                err: "Both delay and time defined".to_owned(),
+                description: self.description(),
+            });
+        }
+        if self.persist == Some(true) && (self.delay.is_none() || self.period.is_some()) {
+            return Err(SchedulerError::TaskParseError {
+                err: "Only one-shot delay tasks can be persisted".to_owned(),
+                description: self.description(),
+            });
+        }
+        if let Some(due) = &self.due {
+            if self.persist != Some(true) {
+                return Err(SchedulerError::TaskParseError {
+                    err: "Due time defined for a task which isn't persisted".to_owned(),
+                    description: self.description(),
+                });
             }
+            self.parse_time(due)
+        } else if let Some(delay) = &self.delay {
+            Ok(parse_hms_field(delay.to_owned()).map(|d| started + d)?)
+        } else if let Some(time) = &self.time {
+            self.parse_time(time)
         } else {
             Err(SchedulerError::TaskParseError {
                 err: "No delay or time defined".to_owned(),
@@ -100,6 +194,218 @@ impl Task {
         }
     }
 
+    fn parse_time(&self, time: &str) -> Result<NaiveDateTime, SchedulerError> {
+        let run_time = Utc
+            .datetime_from_str(time, "%Y-%m-%d %H:%M:%S%.3f")
+            .map_err(|e| SchedulerError::TaskParseError {
+                err: format!("Failed to parse time field '{}': {}", time, e),
+                description: self.description(),
+            })?;
+        Ok(run_time.naive_utc())
+    }
+
+    // Execution time to record for a persisted delay task which is imported at `now`.
+    // Tasks which already have a due time keep it, so that moving a task list between
+    // schedulers doesn't restart its countdown.
+    pub fn due_time(&self, now: NaiveDateTime) -> Result<Option<String>, SchedulerError> {
+        if self.persist == Some(true) && self.due.is_none() {
+            let due = self.first_run(now)?;
+            Ok(Some(due.format("%Y-%m-%d %H:%M:%S").to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Times of the next `limit` executions of this task which fall at or after `now`,
+    // if it was scheduled at `started`. Executions which are excluded are left out
+    pub fn upcoming_runs(
+        &self,
+        started: NaiveDateTime,
+        now: NaiveDateTime,
+        limit: usize,
+    ) -> Result<Vec<NaiveDateTime>, SchedulerError> {
+        let first = self.first_run(started)?;
+
+        match self.get_period()? {
+            Some(period) if period > Duration::zero() => {
+                let exclusions = self.get_exclusions()?;
+                // Skip over the executions which have already happened
+                let mut next = first_at_or_after(first, period, now);
+                let mut runs = vec![];
+                while runs.len() < limit {
+                    match exclusions.iter().find(|exclusion| exclusion.contains(next)) {
+                        Some(exclusion) => next = first_at_or_after(first, period, exclusion.end),
+                        None => {
+                            runs.push(next);
+                            next = next + period;
+                        }
+                    }
+                }
+                Ok(runs)
+            }
+            _ if first >= now && limit > 0 => Ok(vec![first]),
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Resolve the user, group and resource limits the app should be run with
+    pub fn exec_limits(&self) -> Result<ExecLimits, SchedulerError> {
+        let parse_err = |err: String| SchedulerError::TaskParseError {
+            err,
+            description: self.description(),
+        };
+
+        let mut limits = ExecLimits::default();
+
+        if let Some(user) = &self.user {
+            let (uid, gid) = lookup_user(user).map_err(parse_err)?;
+            limits.uid = Some(uid);
+            limits.gid = Some(gid);
+        }
+        if let Some(group) = &self.group {
+            limits.gid = Some(lookup_group(group).map_err(parse_err)?);
+        }
+
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(parse_err(format!(
+                    "nice must be between -20 and 19, got {}",
+                    nice
+                )));
+            }
+            // The priority is set after switching user, when it can no longer be raised
+            if nice < 0 && limits.uid.map_or(false, |uid| uid != 0) {
+                return Err(parse_err(
+                    "Negative nice values can only be used by root".to_owned(),
+                ));
+            }
+            limits.nice = Some(nice);
+        }
+
+        if let Some(kb) = self.max_memory_kb {
+            if kb <= 0 {
+                return Err(parse_err("max_memory_kb must be positive".to_owned()));
+            }
+            limits.max_memory = Some(kb as u64 * 1024);
+        }
+        if let Some(secs) = self.max_cpu_secs {
+            if secs <= 0 {
+                return Err(parse_err("max_cpu_secs must be positive".to_owned()));
+            }
+            limits.max_cpu = Some(secs as u64);
+        }
+
+        Ok(limits)
+    }
+
+    // Check that the task's boot count and uptime limits are valid
+    pub fn check_boot_limits(&self) -> Result<(), SchedulerError> {
+        if let Some(max) = self.max_boots {
+            if max <= 0 {
+                return Err(SchedulerError::TaskParseError {
+                    err: "max_boots must be positive".to_owned(),
+                    description: self.description(),
+                });
+            }
+        }
+        self.get_min_uptime().map(|_| ())
+    }
+
+    pub fn get_min_uptime(&self) -> Result<Option<Duration>, SchedulerError> {
+        if let Some(min_uptime) = &self.min_uptime {
+            Ok(Some(parse_hms_field(min_uptime.to_owned())?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Earliest time the task can run on this boot, or None if it isn't run on this boot at all.
+    // `now` is the time at which the boot status was read. Tasks limited by boot count or
+    // uptime can't be run without a boot status.
+    pub fn earliest_run(
+        &self,
+        boot: Option<BootStatus>,
+        now: NaiveDateTime,
+    ) -> Result<Option<NaiveDateTime>, SchedulerError> {
+        self.check_boot_limits()?;
+        let min_uptime = self.get_min_uptime()?;
+        if self.max_boots.is_none() && min_uptime.is_none() {
+            return Ok(Some(now));
+        }
+
+        let boot = boot.ok_or_else(|| SchedulerError::BootError {
+            err: format!(
+                "No boot record to check the limits of task '{}' against",
+                self.description()
+            ),
+        })?;
+        match (self.max_boots, min_uptime) {
+            (Some(max), _) if boot.count > max as u32 => Ok(None),
+            (_, Some(min)) if min > boot.uptime => Ok(Some(now + (min - boot.uptime))),
+            _ => Ok(Some(now)),
+        }
+    }
+
+    // Like `upcoming_runs`, for a task which can't run before `earliest`. One-shot tasks
+    // which are due sooner wait for it, and earlier executions of recurring tasks are skipped
+    pub fn upcoming_runs_after(
+        &self,
+        started: NaiveDateTime,
+        now: NaiveDateTime,
+        earliest: NaiveDateTime,
+        limit: usize,
+    ) -> Result<Vec<NaiveDateTime>, SchedulerError> {
+        match self.get_period()? {
+            Some(period) if period > Duration::zero() => {
+                self.upcoming_runs(started, now.max(earliest), limit)
+            }
+            // A one-shot task which is still waiting for its uptime hasn't run yet
+            _ if earliest > now && limit > 0 => Ok(vec![self.first_run(started)?.max(earliest)]),
+            _ => self.upcoming_runs(started, now, limit),
+        }
+    }
+
+    // Parse the spans of time during which the task's executions are skipped
+    pub fn get_exclusions(&self) -> Result<Vec<Exclusion>, SchedulerError> {
+        let except = match &self.except {
+            Some(except) => except,
+            None => return Ok(vec![]),
+        };
+        let parse_err = |err: String| SchedulerError::TaskParseError {
+            err,
+            description: self.description(),
+        };
+        if self.period.is_none() {
+            return Err(parse_err(
+                "Exclusions can only be defined for recurring tasks".to_owned(),
+            ));
+        }
+
+        except
+            .iter()
+            .map(|entry| {
+                let mut bounds = entry.splitn(2, '/').map(|bound| bound.trim());
+                let start = bounds.next().unwrap_or_default();
+                let end = bounds.next().unwrap_or(start);
+                match (
+                    parse_exclusion_bound(start, false),
+                    parse_exclusion_bound(end, true),
+                ) {
+                    (Some(start), Some(end)) if start < end => Ok(Exclusion { start, end }),
+                    (Some(_), Some(_)) => Err(parse_err(format!(
+                        "Exclusion '{}' ends before it starts",
+                        entry
+                    ))),
+                    _ => Err(parse_err(format!(
+                        "Failed to parse exclusion '{}'. Expected yyyy-mm-dd or \
+                         yyyy-mm-dd hh:mm:ss dates, optionally as a start/end range",
+                        entry
+                    ))),
+                }
+            })
+            .collect()
+    }
+
     pub fn get_period(&self) -> Result<Option<Duration>, SchedulerError> {
         if let Some(period) = &self.period {
             Ok(Some(parse_hms_field(period.to_owned())?))
@@ -108,9 +414,16 @@ impl Task {
         }
     }
 
-    pub async fn schedule(self: Arc<Self>, real_timer: RealTimer, mut stop: Receiver<()>) {
-        let name = self.app.name.to_owned();
-        let when = match self.get_absolute() {
+    pub async fn schedule(
+        self: Arc<Self>,
+        scheduler: Scheduler,
+        clock: Clock,
+        mut stop: Receiver<()>,
+        processes: TaskProcesses,
+    ) {
+        let mut abort = processes.subscribe();
+        let name = self.name();
+        let when = match self.get_absolute(clock.now()) {
             Ok(d) => d,
             Err(e) => {
                 error!(
@@ -121,16 +434,82 @@ impl Task {
             }
         };
 
+        let limits = match self.exec_limits() {
+            Ok(limits) => limits,
+            Err(e) => {
+                error!(
+                    "Failed to resolve execution limits for task {:?} '{}': {}",
+                    self.id, name, e
+                );
+                return;
+            }
+        };
+
+        let earliest = match self.earliest_run(scheduler.boot_status(), clock.now()) {
+            Ok(Some(earliest)) => earliest,
+            Ok(None) => {
+                info!(
+                    "Not scheduling task {:?} '{}', which only runs on the first {} boots",
+                    self.id,
+                    name,
+                    self.max_boots.unwrap_or_default()
+                );
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to check boot limits for task {:?} '{}': {}",
+                    self.id, name, e
+                );
+                return;
+            }
+        };
+
+        let exclusions = match self.get_exclusions() {
+            Ok(exclusions) => exclusions,
+            Err(e) => {
+                error!(
+                    "Failed to parse exclusions for task {:?} '{}': {}",
+                    self.id, name, e
+                );
+                return;
+            }
+        };
+
         let period = self.get_period();
-        let app = self.app.clone();
 
         match period {
             Ok(Some(period)) => {
-                let mut interval = real_timer.interval_at(when, period);
+                // Only the real clock has intervals, so otherwise each execution is waited for
+                // in turn
+                let mut interval = match &clock {
+                    Clock::Real(timer) => Some(timer.interval_at(when, period)),
+                    _ => None,
+                };
+                let mut next = when;
                 loop {
                     let task = async {
-                        interval.tick().await;
-                        app.execute(self.id).await;
+                        match interval.as_mut() {
+                            Some(interval) => {
+                                interval.tick().await;
+                            }
+                            None => clock.at(next).await,
+                        }
+                        let excluded = exclusions.iter().find(|exclusion| exclusion.contains(next));
+                        if next < earliest {
+                            info!(
+                                "Task {:?} '{}' waiting for uptime until {}, skipping execution",
+                                self.id, name, earliest
+                            );
+                        } else if let Some(exclusion) = excluded {
+                            info!(
+                                "Task {:?} '{}' excluded from {} until {}, skipping execution at {}",
+                                self.id, name, exclusion.start, exclusion.end, next
+                            );
+                        } else {
+                            self.run(&scheduler, &clock, next, &limits, &processes)
+                                .await;
+                        }
                     };
 
                     select! {
@@ -138,13 +517,20 @@ impl Task {
                         _ = stop.recv() => {
                             return;
                         }
+                        _ = aborted(&mut abort, self.id) => {
+                            return;
+                        }
                     };
+                    next = next + period;
                 }
             }
             _ => {
+                // A one-shot task which is due before its minimum uptime waits for it
+                let when = when.max(earliest);
                 let task = async {
-                    real_timer.at(when).await;
-                    app.execute(self.id).await;
+                    clock.at(when).await;
+                    self.run(&scheduler, &clock, when, &limits, &processes)
+                        .await;
                 };
 
                 select! {
@@ -152,10 +538,125 @@ impl Task {
                     _ = stop.recv() => {
                         return;
                     }
+                    _ = aborted(&mut abort, self.id) => {
+                        return;
+                    }
                 };
             }
         }
     }
+
+    // Runs the app, or only records that it would have run at `when` if the clock is simulated.
+    // Mode changes are made either way, so that simulations follow the mode sequence.
+    // Nothing is run while the scheduler is disarmed.
+    async fn run(
+        &self,
+        scheduler: &Scheduler,
+        clock: &Clock,
+        when: NaiveDateTime,
+        limits: &ExecLimits,
+        processes: &TaskProcesses,
+    ) {
+        if !scheduler.is_armed() {
+            info!(
+                "Scheduler disarmed, not running task {:?} '{}' due at {}",
+                self.id,
+                self.name(),
+                when
+            );
+            return;
+        }
+
+        if let Some(mode) = &self.mode_change {
+            match clock {
+                Clock::Real(_) => {}
+                Clock::Simulated(simulation) => simulation.record(self.id, &self.name(), when),
+                #[cfg(test)]
+                Clock::Mock(timer) => timer.record(self.id, &self.name(), when),
+            }
+            info!("Task {:?} changing mode to '{}'", self.id, mode);
+            if let Err(e) = scheduler.change_mode(mode) {
+                error!(
+                    "Task {:?} failed to change mode to '{}': {}",
+                    self.id, mode, e
+                );
+            }
+            return;
+        }
+
+        if let Some(app) = &self.app {
+            let variables = match scheduler.mode_variables() {
+                Ok(variables) => variables,
+                Err(e) => {
+                    error!(
+                        "Not running task {:?} '{}', failed to load mode variables: {}",
+                        self.id,
+                        self.name(),
+                        e
+                    );
+                    return;
+                }
+            };
+            match clock {
+                Clock::Real(_) => app.execute(self.id, limits, &variables, processes).await,
+                Clock::Simulated(simulation) => {
+                    simulation
+                        .execute(self.id, app, when, &variables, processes)
+                        .await
+                }
+                #[cfg(test)]
+                Clock::Mock(timer) => timer.record(self.id, &app.name, when),
+            }
+        }
+    }
+}
+
+// A future execution of a scheduled task
+#[derive(Clone, Debug, GraphQLObject)]
+pub struct UpcomingTask {
+    // ID of the task, if it has one
+    pub id: Option<i32>,
+    // Name of the app the task will run, or `mode_change:{mode}` for mode changes
+    pub name: String,
+    // Task list the task belongs to
+    pub list: String,
+    // Mode the task list was loaded from
+    pub mode: String,
+    // UTC time of the execution, in yyyy-mm-dd hh:mm:ss format
+    pub time: String,
+}
+
+// Resolves once the task with this ID is aborted.
+// Tasks without an ID can only be aborted along with all other tasks.
+async fn aborted(abort: &mut Receiver<i32>, id: Option<i32>) {
+    loop {
+        match abort.recv().await {
+            Ok(aborted) if Some(aborted) == id => return,
+            Err(RecvError::Closed) => future::pending::<()>().await,
+            _ => {}
+        }
+    }
+}
+
+// First execution of a recurring task at or after `time`
+fn first_at_or_after(first: NaiveDateTime, period: Duration, time: NaiveDateTime) -> NaiveDateTime {
+    if first >= time {
+        return first;
+    }
+    let step = period.num_milliseconds();
+    let missed = ((time - first).num_milliseconds() + step - 1) / step;
+    first + Duration::milliseconds(missed * step)
+}
+
+// Parse one end of an exclusion. A date on its own is the start of the day, or for the end of
+// an exclusion, the start of the next day
+fn parse_exclusion_bound(bound: &str, end: bool) -> Option<NaiveDateTime> {
+    if let Ok(time) = NaiveDateTime::parse_from_str(bound, "%Y-%m-%d %H:%M:%S") {
+        return Some(time);
+    }
+    let day = NaiveDate::parse_from_str(bound, "%Y-%m-%d").ok()?;
+    let day = if end { day.succ_opt()? } else { day };
+    Some(day.and_hms(0, 0, 0))
 }
 
 fn parse_hms_field(field: String) -> Result<Duration, SchedulerError> {
@@ -257,4 +758,286 @@ mod tests {
             Ok(Duration::from_secs(7322))
         );
     }
+
+    fn task(delay: Option<&str>, time: Option<&str>, period: Option<&str>) -> Task {
+        Task {
+            id: Some(1),
+            delay: delay.map(|d| d.to_owned()),
+            time: time.map(|t| t.to_owned()),
+            period: period.map(|p| p.to_owned()),
+            app: Some(App {
+                name: "basic-app".to_owned(),
+                args: None,
+                config: None,
+            }),
+            mode_change: None,
+            user: None,
+            group: None,
+            nice: None,
+            max_memory_kb: None,
+            max_cpu_secs: None,
+            persist: None,
+            due: None,
+            max_boots: None,
+            min_uptime: None,
+            except: None,
+        }
+    }
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_upcoming_init() {
+        let task = task(Some("10s"), None, None);
+        let started = at("2020-01-01 00:00:00");
+        assert_eq!(
+            task.upcoming_runs(started, at("2020-01-01 00:00:05"), 5),
+            Ok(vec![at("2020-01-01 00:00:10")])
+        );
+        assert_eq!(
+            task.upcoming_runs(started, at("2020-01-01 00:00:11"), 5),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_upcoming_onetime() {
+        let task = task(None, Some("2020-01-02 12:00:00"), None);
+        let started = at("2020-01-01 00:00:00");
+        assert_eq!(
+            task.upcoming_runs(started, started, 5),
+            Ok(vec![at("2020-01-02 12:00:00")])
+        );
+        assert_eq!(task.upcoming_runs(started, started, 0), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_upcoming_recurring() {
+        let task = task(Some("10s"), None, Some("1m"));
+        let started = at("2020-01-01 00:00:00");
+        assert_eq!(
+            task.upcoming_runs(started, at("2020-01-01 00:01:30"), 3),
+            Ok(vec![
+                at("2020-01-01 00:02:10"),
+                at("2020-01-01 00:03:10"),
+                at("2020-01-01 00:04:10")
+            ])
+        );
+        assert_eq!(
+            task.upcoming_runs(started, at("2020-01-01 00:01:10"), 1),
+            Ok(vec![at("2020-01-01 00:01:10")])
+        );
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let mut task = task(Some("10s"), None, Some("1m"));
+        task.except = Some(vec![
+            "2020-01-02".to_owned(),
+            "2020-01-03 12:00:00/2020-01-03 13:30:00".to_owned(),
+            "2020-01-04 06:00:00/2020-01-05".to_owned(),
+        ]);
+        assert_eq!(
+            task.get_exclusions(),
+            Ok(vec![
+                Exclusion {
+                    start: at("2020-01-02 00:00:00"),
+                    end: at("2020-01-03 00:00:00"),
+                },
+                Exclusion {
+                    start: at("2020-01-03 12:00:00"),
+                    end: at("2020-01-03 13:30:00"),
+                },
+                Exclusion {
+                    start: at("2020-01-04 06:00:00"),
+                    end: at("2020-01-06 00:00:00"),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_exclusions_invalid() {
+        let mut backwards = task(Some("10s"), None, Some("1m"));
+        backwards.except = Some(vec!["2020-01-03/2020-01-02".to_owned()]);
+        assert!(backwards.get_exclusions().is_err());
+
+        let mut malformed = task(Some("10s"), None, Some("1m"));
+        malformed.except = Some(vec!["tomorrow".to_owned()]);
+        assert!(malformed.get_exclusions().is_err());
+
+        let mut one_shot = task(Some("10s"), None, None);
+        one_shot.except = Some(vec!["2020-01-02".to_owned()]);
+        assert!(one_shot.get_exclusions().is_err());
+    }
+
+    #[test]
+    fn test_upcoming_excluded() {
+        let mut task = task(Some("10s"), None, Some("1m"));
+        task.except = Some(vec![
+            "2020-01-01 00:01:00/2020-01-01 00:03:00".to_owned(),
+            "2020-01-01 00:03:10/2020-01-01 00:03:11".to_owned(),
+        ]);
+        let started = at("2020-01-01 00:00:00");
+        assert_eq!(
+            task.upcoming_runs(started, started, 3),
+            Ok(vec![
+                at("2020-01-01 00:00:10"),
+                at("2020-01-01 00:04:10"),
+                at("2020-01-01 00:05:10")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_exec_limits() {
+        let mut limited = task(Some("10s"), None, None);
+        assert_eq!(limited.exec_limits(), Ok(ExecLimits::default()));
+
+        limited.user = Some("root".to_owned());
+        limited.nice = Some(-5);
+        limited.max_memory_kb = Some(2048);
+        limited.max_cpu_secs = Some(30);
+        assert_eq!(
+            limited.exec_limits(),
+            Ok(ExecLimits {
+                uid: Some(0),
+                gid: Some(0),
+                nice: Some(-5),
+                max_memory: Some(2048 * 1024),
+                max_cpu: Some(30),
+            })
+        );
+    }
+
+    #[test]
+    fn test_exec_limits_invalid() {
+        let mut unknown_user = task(Some("10s"), None, None);
+        unknown_user.user = Some("no-such-user".to_owned());
+        assert!(unknown_user.exec_limits().is_err());
+
+        let mut bad_nice = task(Some("10s"), None, None);
+        bad_nice.nice = Some(20);
+        assert!(bad_nice.exec_limits().is_err());
+
+        let mut no_cpu = task(Some("10s"), None, None);
+        no_cpu.max_cpu_secs = Some(0);
+        assert!(no_cpu.exec_limits().is_err());
+    }
+
+    #[test]
+    fn test_persisted_delay() {
+        let mut countdown = task(Some("1h"), None, None);
+        countdown.persist = Some(true);
+        let imported = at("2020-01-01 00:00:00");
+        assert_eq!(
+            countdown.due_time(imported),
+            Ok(Some("2020-01-01 01:00:00".to_owned()))
+        );
+
+        // Once the due time is recorded, restarting the scheduler doesn't move it
+        countdown.due = Some("2020-01-01 01:00:00".to_owned());
+        assert_eq!(countdown.due_time(at("2020-01-01 00:30:00")), Ok(None));
+        assert_eq!(
+            countdown.upcoming_runs(at("2020-01-01 00:30:00"), at("2020-01-01 00:30:00"), 5),
+            Ok(vec![at("2020-01-01 01:00:00")])
+        );
+        assert_eq!(
+            countdown.upcoming_runs(at("2020-01-01 01:30:00"), at("2020-01-01 01:30:00"), 5),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_persisted_delay_invalid() {
+        let mut recurring = task(Some("1h"), None, Some("1h"));
+        recurring.persist = Some(true);
+        assert!(recurring.due_time(at("2020-01-01 00:00:00")).is_err());
+
+        let mut onetime = task(None, Some("2020-01-02 12:00:00"), None);
+        onetime.persist = Some(true);
+        assert!(onetime.due_time(at("2020-01-01 00:00:00")).is_err());
+
+        let mut not_persisted = task(Some("1h"), None, None);
+        not_persisted.due = Some("2020-01-01 01:00:00".to_owned());
+        assert!(not_persisted
+            .upcoming_runs(at("2020-01-01 00:00:00"), at("2020-01-01 00:00:00"), 1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_boot_limits() {
+        let now = at("2020-01-01 00:00:00");
+        let boot = |count, uptime| {
+            Some(BootStatus {
+                count,
+                uptime: Duration::seconds(uptime),
+            })
+        };
+
+        let mut first_boots = task(Some("10s"), None, None);
+        first_boots.max_boots = Some(2);
+        assert_eq!(first_boots.earliest_run(boot(2, 0), now), Ok(Some(now)));
+        assert_eq!(first_boots.earliest_run(boot(3, 0), now), Ok(None));
+        assert!(first_boots.earliest_run(None, now).is_err());
+
+        let mut inhibited = task(Some("10s"), None, None);
+        inhibited.min_uptime = Some("30m".to_owned());
+        assert_eq!(
+            inhibited.earliest_run(boot(1, 600), now),
+            Ok(Some(at("2020-01-01 00:20:00")))
+        );
+        assert_eq!(inhibited.earliest_run(boot(4, 3600), now), Ok(Some(now)));
+
+        // Tasks without limits don't need a boot record
+        let unlimited = task(Some("10s"), None, None);
+        assert_eq!(unlimited.earliest_run(None, now), Ok(Some(now)));
+
+        let mut no_boots = task(Some("10s"), None, None);
+        no_boots.max_boots = Some(0);
+        assert!(no_boots.check_boot_limits().is_err());
+    }
+
+    #[test]
+    fn test_upcoming_after_uptime() {
+        let started = at("2020-01-01 00:00:00");
+        let earliest = at("2020-01-01 00:20:00");
+
+        let init = task(Some("10s"), None, None);
+        assert_eq!(
+            init.upcoming_runs_after(started, at("2020-01-01 00:05:00"), earliest, 5),
+            Ok(vec![earliest])
+        );
+
+        let recurring = task(Some("10s"), None, Some("15m"));
+        assert_eq!(
+            recurring.upcoming_runs_after(started, at("2020-01-01 00:05:00"), earliest, 2),
+            Ok(vec![at("2020-01-01 00:30:10"), at("2020-01-01 00:45:10")])
+        );
+    }
+
+    #[test]
+    fn test_mode_change_action() {
+        let mut mode_change = task(Some("10s"), None, None);
+        mode_change.app = None;
+        mode_change.mode_change = Some("imaging".to_owned());
+        assert_eq!(mode_change.check_action(), Ok(()));
+        assert_eq!(mode_change.name(), "mode_change:imaging");
+
+        let mut both = task(Some("10s"), None, None);
+        both.mode_change = Some("imaging".to_owned());
+        assert_eq!(
+            both.check_action(),
+            Err(SchedulerError::TaskParseError {
+                err: "Both app and mode_change defined".to_owned(),
+                description: "1: basic-app".to_owned(),
+            })
+        );
+
+        let mut neither = task(Some("10s"), None, None);
+        neither.app = None;
+        assert!(neither.check_action().is_err());
+    }
 }
@@ -19,13 +19,18 @@
 //!
 
 use crate::error::SchedulerError;
-use crate::scheduler::SchedulerHandle;
+use crate::mode::get_available_modes;
+use crate::process::TaskProcesses;
+use crate::scheduler::{Scheduler, SchedulerHandle};
+use crate::simulation::Clock;
 use crate::task::Task;
 use chrono::{DateTime, Utc};
-use clock_timer::RealTimer;
-use juniper::GraphQLObject;
-use log::info;
+use juniper::{GraphQLEnum, GraphQLObject};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -33,6 +38,23 @@ use std::sync::Arc;
 use tokio::runtime::Handle;
 use tokio::sync::broadcast;
 
+// Extension of the flag file which marks the task list of the same name as disabled.
+// Disabled task lists are kept in their mode, but their tasks aren't scheduled
+pub const DISABLED_EXTENSION: &str = "disabled";
+
+// Extension of the file which holds the version of the task list of the same name, so that
+// the ground can check the onboard schedule is the one it uploaded
+pub const VERSION_EXTENSION: &str = "version";
+
+// Contents of a task list's version file
+#[derive(Debug, Deserialize, Serialize)]
+struct VersionFile {
+    // Version string given when the task list was imported, if any
+    version: Option<String>,
+    // SHA-256 digest of the task list as it was uploaded, hex-encoded
+    hash: String,
+}
+
 // Task list's contents
 #[derive(Debug, GraphQLObject, Serialize, Deserialize)]
 pub struct ListContents {
@@ -46,6 +68,56 @@ pub struct TaskList {
     pub path: String,
     pub filename: String,
     pub time_imported: String,
+    // Whether the task list's tasks are scheduled while its mode is in effect
+    pub enabled: bool,
+    // Version string given when the task list was imported, if any
+    pub version: Option<String>,
+    // SHA-256 digest of the task list as it was uploaded, hex-encoded.
+    // Null for task lists imported before versions were recorded
+    pub hash: Option<String>,
+}
+
+// Version of a task list in a mode
+#[derive(Debug, GraphQLObject)]
+pub struct TaskListVersion {
+    pub mode: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub hash: Option<String>,
+}
+
+// How a task in an onboard task list differs from the reference it was compared with
+#[derive(Clone, Copy, Debug, GraphQLEnum, PartialEq)]
+pub enum DifferenceKind {
+    // The task is only in the reference
+    Missing,
+    // The task is only onboard
+    Extra,
+    // The task is in both, with different fields
+    Changed,
+}
+
+// A task which differs between an onboard task list and a reference
+#[derive(Debug, GraphQLObject)]
+pub struct TaskDifference {
+    // The task's ID, or its name if it has none. Tasks without IDs are matched by name, in
+    // order, so repeats of a name after the first are suffixed with their occurrence (eg. `#2`)
+    pub task: String,
+    pub kind: DifferenceKind,
+    // Names of the fields which differ, for changed tasks
+    pub fields: Vec<String>,
+}
+
+// Result of comparing an onboard task list with a reference
+#[derive(Debug, GraphQLObject)]
+pub struct TaskListDiff {
+    pub success: bool,
+    pub errors: String,
+    // Whether the task lists have the same tasks
+    pub matches: bool,
+    pub onboard_hash: Option<String>,
+    pub reference_hash: Option<String>,
+    pub differences: Vec<TaskDifference>,
 }
 
 impl TaskList {
@@ -97,30 +169,63 @@ impl TaskList {
         })?;
 
         let tasks = list_contents.tasks;
+        let enabled = !path_obj.with_extension(DISABLED_EXTENSION).exists();
+        let (version, hash) = match read_version(path_obj) {
+            Some(file) => (file.version, Some(file.hash)),
+            None => (None, None),
+        };
 
         Ok(TaskList {
             path,
             filename,
             tasks,
             time_imported,
+            enabled,
+            version,
+            hash,
         })
     }
 
     // Schedules the tasks contained in this task list
     pub fn schedule_tasks(
         &self,
-        real_timer: RealTimer,
+        scheduler: &Scheduler,
+        clock: Clock,
         tokio_handle: Handle,
+        processes: &TaskProcesses,
     ) -> Result<SchedulerHandle, SchedulerError> {
         let (stopper, _) = broadcast::channel::<()>(1);
         let tasks: Vec<Arc<Task>> = self.tasks.iter().map(|t| Arc::new(t.to_owned())).collect();
 
+        let mut runs = vec![];
         for task in tasks {
-            info!("Scheduling task '{}'", &task.app.name);
-            tokio_handle.spawn(task.schedule(real_timer.clone(), stopper.subscribe()));
+            info!("Scheduling task '{}'", task.name());
+            runs.push(tokio_handle.spawn(task.schedule(
+                scheduler.clone(),
+                clock.clone(),
+                stopper.subscribe(),
+                processes.clone(),
+            )));
         }
 
-        Ok(SchedulerHandle { stopper })
+        Ok(SchedulerHandle {
+            stopper,
+            runs,
+            mode: self.mode(),
+            task_ids: self.tasks.iter().filter_map(|t| t.id).collect(),
+            tasks: self.tasks.clone(),
+            started: clock.now(),
+        })
+    }
+
+    // Name of the mode this task list belongs to
+    pub fn mode(&self) -> String {
+        Path::new(&self.path)
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_owned())
+            .unwrap_or_default()
     }
 }
 
@@ -130,6 +235,7 @@ pub fn import_task_list(
     raw_name: &str,
     path: &str,
     raw_mode: &str,
+    version: Option<&str>,
 ) -> Result<(), SchedulerError> {
     let name = raw_name.to_lowercase();
     let mode = raw_mode.to_lowercase();
@@ -146,16 +252,92 @@ pub fn import_task_list(
         });
     }
 
-    fs::copy(path, &schedule_dest).map_err(|e| SchedulerError::ImportError {
-        err: e.to_string(),
-        name: name.to_owned(),
-    })?;
+    let uploaded = fs::read(path)
+        .and_then(|contents| fs::write(&schedule_dest, &contents).map(|_| contents))
+        .map_err(|e| SchedulerError::ImportError {
+            err: e.to_string(),
+            name: name.to_owned(),
+        })?;
 
-    if let Err(e) = validate_task_list(&schedule_dest) {
+    if let Err(e) = validate_task_list(&schedule_dest)
+        .and_then(|_| validate_mode_changes(scheduler_dir, &schedule_dest))
+    {
         let _ = fs::remove_file(&schedule_dest);
         return Err(e);
     }
 
+    if let Err(e) = persist_due_times(&schedule_dest) {
+        let _ = fs::remove_file(&schedule_dest);
+        return Err(e);
+    }
+
+    record_version(&schedule_dest, &uploaded, version);
+    Ok(())
+}
+
+// Move a task list which has already been uploaded (eg. with the file service) into a
+// mode directory. The list is checked in a staging file next to its destination and then
+// renamed into place, so it only appears in the mode once it's known to be valid.
+// The uploaded file is left alone unless the import succeeds.
+pub fn import_uploaded_task_list(
+    scheduler_dir: &str,
+    raw_name: &str,
+    path: &str,
+    raw_mode: &str,
+    version: Option<&str>,
+) -> Result<(), SchedulerError> {
+    let name = raw_name.to_lowercase();
+    let mode = raw_mode.to_lowercase();
+    info!(
+        "Importing uploaded task list '{}': {} into mode '{}'",
+        name, path, mode
+    );
+    let mode_dir = format!("{}/{}", scheduler_dir, mode);
+    let schedule_dest = format!("{}/{}.json", mode_dir, name);
+    // Task lists are only read from the top of the mode directory, so nothing will pick up
+    // the staged copy before it's moved into place
+    let staging_dir = format!("{}/.importing", mode_dir);
+    let staged = format!("{}/{}.json", staging_dir, name);
+
+    if !Path::new(&mode_dir).is_dir() {
+        return Err(SchedulerError::ImportError {
+            err: "Mode not found".to_owned(),
+            name: name.to_owned(),
+        });
+    }
+
+    let import_err = |e: std::io::Error| SchedulerError::ImportError {
+        err: e.to_string(),
+        name: name.to_owned(),
+    };
+
+    let uploaded = fs::create_dir_all(&staging_dir)
+        .and_then(|_| fs::read(path))
+        .and_then(|contents| fs::write(&staged, &contents).map(|_| contents))
+        .map_err(import_err)?;
+
+    let checked = validate_task_list(&staged)
+        .and_then(|_| validate_mode_changes(scheduler_dir, &staged))
+        .and_then(|_| persist_due_times(&staged))
+        .and_then(|_| {
+            fs::File::open(&staged)
+                .and_then(|file| file.sync_all())
+                .map_err(import_err)
+        })
+        .and_then(|_| fs::rename(&staged, &schedule_dest).map_err(import_err));
+    if let Err(e) = checked {
+        let _ = fs::remove_file(&staged);
+        return Err(e);
+    }
+    record_version(&schedule_dest, &uploaded, version);
+
+    if let Err(e) = fs::remove_file(path) {
+        warn!(
+            "Imported task list '{}', but failed to remove {}: {}",
+            name, path, e
+        );
+    }
+
     Ok(())
 }
 
@@ -165,6 +347,7 @@ pub fn import_raw_task_list(
     name: &str,
     mode: &str,
     json: &str,
+    version: Option<&str>,
 ) -> Result<(), SchedulerError> {
     let name = name.to_lowercase();
     let mode = mode.to_lowercase();
@@ -196,11 +379,19 @@ pub fn import_raw_task_list(
             name: name.to_owned(),
         })?;
 
-    if let Err(e) = validate_task_list(&schedule_dest) {
+    if let Err(e) = validate_task_list(&schedule_dest)
+        .and_then(|_| validate_mode_changes(scheduler_dir, &schedule_dest))
+    {
         let _ = fs::remove_file(&schedule_dest);
         return Err(e);
     }
 
+    if let Err(e) = persist_due_times(&schedule_dest) {
+        let _ = fs::remove_file(&schedule_dest);
+        return Err(e);
+    }
+
+    record_version(&schedule_dest, json.as_bytes(), version);
     Ok(())
 }
 
@@ -230,10 +421,65 @@ pub fn remove_task_list(scheduler_dir: &str, name: &str, mode: &str) -> Result<(
         name: name.to_owned(),
     })?;
 
+    // A task list imported later under the same name starts out enabled, and unversioned
+    for extension in &[DISABLED_EXTENSION, VERSION_EXTENSION] {
+        let flag = Path::new(&sched_path).with_extension(extension);
+        if flag.exists() {
+            if let Err(e) = fs::remove_file(&flag) {
+                warn!("Failed to remove {}: {}", flag.display(), e);
+            }
+        }
+    }
+
     info!("Removed task list '{}'", name);
     Ok(())
 }
 
+// Disable or re-enable a task list in a mode's directory, without removing it.
+// The list is disabled by a flag file next to it, so it stays disabled across restarts
+// and re-imports
+pub fn set_task_list_enabled(
+    scheduler_dir: &str,
+    name: &str,
+    mode: &str,
+    enabled: bool,
+) -> Result<(), SchedulerError> {
+    let name = name.to_lowercase();
+    let mode = mode.to_lowercase();
+    let action = if enabled { "enable" } else { "disable" };
+    info!(
+        "Setting task list '{}' in mode '{}' to {}d",
+        name, mode, action
+    );
+    let mode_dir = format!("{}/{}", scheduler_dir, mode);
+    let sched_path = format!("{}/{}.json", mode_dir, name);
+    let flag = Path::new(&sched_path).with_extension(DISABLED_EXTENSION);
+
+    if !Path::new(&mode_dir).is_dir() {
+        return Err(SchedulerError::GenericError {
+            err: format!("Mode '{}' not found", mode),
+        });
+    }
+
+    if !Path::new(&sched_path).is_file() {
+        return Err(SchedulerError::GenericError {
+            err: format!("Task list '{}' not found in mode '{}'", name, mode),
+        });
+    }
+
+    let result = if enabled {
+        match fs::remove_file(&flag) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    } else {
+        fs::File::create(&flag).and_then(|file| file.sync_all())
+    };
+    result.map_err(|e| SchedulerError::GenericError {
+        err: format!("Failed to {} task list '{}': {}", action, name, e),
+    })
+}
+
 // Retrieve list of the task lists in a mode's directory
 pub fn get_mode_task_lists(mode_path: &str) -> Result<Vec<TaskList>, SchedulerError> {
     let mut schedules = vec![];
@@ -248,6 +494,8 @@ pub fn get_mode_task_lists(mode_path: &str) -> Result<Vec<TaskList>, SchedulerEr
         .map(|entry| entry.path())
         // Filter out non-directories
         .filter(|entry| entry.is_file())
+        // Filter out non-task list files (eg. the mode's parent file)
+        .filter(|entry| entry.extension().map(|ext| ext == "json").unwrap_or(false))
         .collect();
     // Sort into predictable order
     files_list.sort();
@@ -259,17 +507,244 @@ pub fn get_mode_task_lists(mode_path: &str) -> Result<Vec<TaskList>, SchedulerEr
     Ok(schedules)
 }
 
+// Retrieve the versions of the task lists in a mode (or all modes, if none is given)
+pub fn get_task_list_versions(
+    scheduler_dir: &str,
+    mode: Option<String>,
+) -> Result<Vec<TaskListVersion>, SchedulerError> {
+    let mut versions = vec![];
+    for mode in get_available_modes(scheduler_dir, mode)? {
+        for list in mode.schedule {
+            versions.push(TaskListVersion {
+                mode: mode.name.to_owned(),
+                name: list.filename,
+                version: list.version,
+                hash: list.hash,
+            });
+        }
+    }
+    Ok(versions)
+}
+
+// Compare a task list in a mode with a reference copy (eg. the ground's, uploaded with the
+// file service), reporting the tasks which differ. Execution times recorded by the scheduler
+// for persisted tasks aren't counted as differences
+pub fn diff_task_list(
+    scheduler_dir: &str,
+    name: &str,
+    mode: &str,
+    path: &str,
+) -> Result<TaskListDiff, SchedulerError> {
+    let name = name.to_lowercase();
+    let mode = mode.to_lowercase();
+    let sched_path = format!("{}/{}/{}.json", scheduler_dir, mode, name);
+    if !Path::new(&sched_path).is_file() {
+        return Err(SchedulerError::GenericError {
+            err: format!("Task list '{}' not found in mode '{}'", name, mode),
+        });
+    }
+
+    let onboard = TaskList::from_path(Path::new(&sched_path))?;
+    let reference = fs::read(path).map_err(|e| SchedulerError::GenericError {
+        err: format!("Failed to read reference task list {}: {}", path, e),
+    })?;
+    let reference_tasks = serde_json::from_slice::<ListContents>(&reference)
+        .map_err(|e| SchedulerError::GenericError {
+            err: format!("Failed to parse reference task list {}: {}", path, e),
+        })?
+        .tasks;
+
+    let onboard_tasks = keyed_tasks(&onboard.tasks);
+    let reference_tasks = keyed_tasks(&reference_tasks);
+    let mut differences = vec![];
+    for (key, task) in &reference_tasks {
+        match onboard_tasks
+            .iter()
+            .find(|(onboard_key, _)| onboard_key == key)
+        {
+            Some((_, onboard_task)) => {
+                let fields = changed_fields(onboard_task, task);
+                if !fields.is_empty() {
+                    differences.push(TaskDifference {
+                        task: key.to_owned(),
+                        kind: DifferenceKind::Changed,
+                        fields,
+                    });
+                }
+            }
+            None => differences.push(TaskDifference {
+                task: key.to_owned(),
+                kind: DifferenceKind::Missing,
+                fields: vec![],
+            }),
+        }
+    }
+    for (key, _) in &onboard_tasks {
+        if !reference_tasks
+            .iter()
+            .any(|(reference_key, _)| reference_key == key)
+        {
+            differences.push(TaskDifference {
+                task: key.to_owned(),
+                kind: DifferenceKind::Extra,
+                fields: vec![],
+            });
+        }
+    }
+
+    Ok(TaskListDiff {
+        success: true,
+        errors: "".to_owned(),
+        matches: differences.is_empty(),
+        onboard_hash: onboard.hash,
+        reference_hash: Some(hash_contents(&reference)),
+        differences,
+    })
+}
+
+// Key each task by its ID, or by its name and occurrence if it has none, in their original order
+fn keyed_tasks(tasks: &[Task]) -> Vec<(String, Map<String, Value>)> {
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    tasks
+        .iter()
+        .map(|task| {
+            let key = match task.id {
+                Some(id) => id.to_string(),
+                None => {
+                    let count = occurrences.entry(task.name()).or_insert(0);
+                    *count += 1;
+                    if *count == 1 {
+                        task.name()
+                    } else {
+                        format!("{} #{}", task.name(), count)
+                    }
+                }
+            };
+            let mut fields = match serde_json::to_value(task) {
+                Ok(Value::Object(fields)) => fields,
+                _ => Map::new(),
+            };
+            fields.remove("due");
+            (key, fields)
+        })
+        .collect()
+}
+
+// Names of the fields which differ between two tasks, in order
+fn changed_fields(onboard: &Map<String, Value>, reference: &Map<String, Value>) -> Vec<String> {
+    let mut fields: Vec<String> = onboard
+        .keys()
+        .chain(reference.keys())
+        .filter(|field| onboard.get(*field) != reference.get(*field))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+fn hash_contents(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Read the version file of a task list, if it has one
+fn read_version(path: &Path) -> Option<VersionFile> {
+    let contents = fs::read(path.with_extension(VERSION_EXTENSION)).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// Record the version of a task list which has just been imported. Failing to do so doesn't
+// fail the import, but any old version file is removed so that it can't be mistaken for the
+// new list's
+fn record_version(path: &str, uploaded: &[u8], version: Option<&str>) {
+    let version_path = Path::new(path).with_extension(VERSION_EXTENSION);
+    let file = VersionFile {
+        version: version.map(|version| version.to_owned()),
+        hash: hash_contents(uploaded),
+    };
+
+    let written = serde_json::to_vec(&file)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| {
+            fs::File::create(&version_path)
+                .and_then(|mut out| {
+                    out.write_all(&contents)?;
+                    out.sync_all()
+                })
+                .map_err(|e| e.to_string())
+        });
+    match written {
+        Ok(()) => info!("Task list {} has hash {}", path, file.hash),
+        Err(e) => {
+            warn!("Failed to record version of task list {}: {}", path, e);
+            let _ = fs::remove_file(&version_path);
+        }
+    }
+}
+
 // Validate the format and content of a task list
 pub fn validate_task_list(path: &str) -> Result<(), SchedulerError> {
     let task_path = Path::new(path);
     let task_list = TaskList::from_path(task_path)?;
     for task in task_list.tasks {
-        let _ = match task.get_absolute() {
+        let _ = match task.get_absolute(Utc::now().naive_utc()) {
             Ok(_) => Ok(()),
             Err(SchedulerError::TaskTimeError { .. }) => Ok(()),
             Err(e) => Err(e),
         }?;
         let _ = task.get_period()?;
+        let _ = task.exec_limits()?;
+        task.check_boot_limits()?;
+        task.check_action()?;
+        let _ = task.get_exclusions()?;
+    }
+    Ok(())
+}
+
+// Check that the modes activated by a task list's mode change tasks exist.
+// Only done on import, so that removing a mode doesn't stop other modes from starting.
+pub fn validate_mode_changes(scheduler_dir: &str, path: &str) -> Result<(), SchedulerError> {
+    let task_list = TaskList::from_path(Path::new(path))?;
+    for task in task_list.tasks {
+        task.check_mode_change(scheduler_dir)?;
     }
     Ok(())
 }
+
+// Record the execution times of any persisted delay tasks in an imported task list.
+// The file is edited as raw json so that any fields the scheduler doesn't use are kept.
+fn persist_due_times(path: &str) -> Result<(), SchedulerError> {
+    let task_list = TaskList::from_path(Path::new(path))?;
+    let now = Utc::now().naive_utc();
+    let mut due_times = vec![];
+    for task in &task_list.tasks {
+        due_times.push(task.due_time(now)?);
+    }
+    if due_times.iter().all(|due| due.is_none()) {
+        return Ok(());
+    }
+
+    let import_err = |err: String| SchedulerError::ImportError {
+        err,
+        name: task_list.filename.to_owned(),
+    };
+
+    let contents = fs::read_to_string(path).map_err(|e| import_err(e.to_string()))?;
+    let mut raw: Value = serde_json::from_str(&contents).map_err(|e| import_err(e.to_string()))?;
+    if let Some(tasks) = raw["tasks"].as_array_mut() {
+        for ((raw_task, task), due) in tasks.iter_mut().zip(&task_list.tasks).zip(due_times) {
+            if let Some(due) = due {
+                info!("Task '{}' is due at {}", task.name(), due);
+                raw_task["due"] = Value::String(due);
+            }
+        }
+    }
+
+    let mut file = fs::File::create(path).map_err(|e| import_err(e.to_string()))?;
+    file.write_all(raw.to_string().as_bytes())
+        .map_err(|e| import_err(e.to_string()))?;
+    file.sync_all().map_err(|e| import_err(e.to_string()))
+}
@@ -0,0 +1,451 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//!
+//! Timers behind the clocks which tasks are scheduled against, including the mock timer
+//! which unit tests move forward by hand
+//!
+
+use chrono::{NaiveDateTime, Utc};
+use clock_timer::RealTimer;
+use std::future::Future;
+use std::pin::Pin;
+
+// Future which resolves once a timer reaches a given time
+pub type TimerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Source of the current time, and of waits until later times
+pub trait Timer {
+    // Current UTC time according to this timer
+    fn now(&self) -> NaiveDateTime;
+
+    // Waits until the given time according to this timer
+    fn at(&self, when: NaiveDateTime) -> TimerFuture;
+}
+
+impl Timer for RealTimer {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+
+    fn at(&self, when: NaiveDateTime) -> TimerFuture {
+        let timer = self.clone();
+        Box::pin(async move { RealTimer::at(&timer, when).await })
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::{Timer, TimerFuture};
+    use crate::mode::{activate_mode, create_mode};
+    use crate::scheduler::Scheduler;
+    use crate::simulation::SimulatedRun;
+    use crate::task_list::import_raw_task_list;
+    use chrono::{Duration, NaiveDateTime};
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::Instant;
+    use tempfile::TempDir;
+    use tokio::sync::oneshot;
+
+    // Real time waited between checks for the scheduler's tasks to settle
+    const SETTLE_POLL: std::time::Duration = std::time::Duration::from_millis(5);
+    // Longest real time waited for the scheduler's tasks to settle after time moves forward
+    const SETTLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+    struct MockState {
+        now: NaiveDateTime,
+        // Waits which haven't been reached yet
+        waiters: Vec<(NaiveDateTime, oneshot::Sender<()>)>,
+        // Waits which have been reached, but whose tasks haven't resumed yet
+        woken: usize,
+        runs: Vec<SimulatedRun>,
+    }
+
+    // Timer which only moves forward when told to. Like a simulation, apps aren't run,
+    // their executions are only recorded. Clones share their time and recorded runs.
+    #[derive(Clone)]
+    pub struct MockTimer {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    impl MockTimer {
+        pub fn new(start: NaiveDateTime) -> Self {
+            MockTimer {
+                state: Arc::new(Mutex::new(MockState {
+                    now: start,
+                    waiters: vec![],
+                    woken: 0,
+                    runs: vec![],
+                })),
+            }
+        }
+
+        // Moves the time forward (never back) to `now`, waking the tasks waiting for it
+        pub fn set(&self, now: NaiveDateTime) {
+            let mut state = self.state();
+            if now < state.now {
+                return;
+            }
+            state.now = now;
+
+            let (due, waiting) = state
+                .waiters
+                .drain(..)
+                .partition::<Vec<_>, _>(|(when, _)| *when <= now);
+            state.waiters = waiting;
+            // Waits whose tasks were stopped have nobody to wake
+            state.woken += due
+                .into_iter()
+                .filter_map(|(_, waiter)| waiter.send(()).ok())
+                .count();
+        }
+
+        // Earliest time a task is waiting for
+        pub fn next_wait(&self) -> Option<NaiveDateTime> {
+            self.state().waiters.iter().map(|(when, _)| *when).min()
+        }
+
+        // Number of tasks waiting for a later time. Stopped tasks no longer wait
+        pub fn waiting(&self) -> usize {
+            self.state()
+                .waiters
+                .iter()
+                .filter(|(_, waiter)| !waiter.is_closed())
+                .count()
+        }
+
+        // Number of tasks which have been woken, but haven't resumed yet
+        pub fn woken(&self) -> usize {
+            self.state().woken
+        }
+
+        // Records an execution of the named task at `when`
+        pub fn record(&self, id: Option<i32>, name: &str, when: NaiveDateTime) {
+            self.state().runs.push(SimulatedRun {
+                id,
+                name: name.to_owned(),
+                time: when.format("%Y-%m-%d %H:%M:%S").to_string(),
+            });
+        }
+
+        // The executions recorded so far, oldest first
+        pub fn runs(&self) -> Vec<SimulatedRun> {
+            self.state().runs.clone()
+        }
+
+        // A panicking test can't leave the state inconsistent, so poisoning is ignored
+        fn state(&self) -> MutexGuard<MockState> {
+            self.state
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+    }
+
+    impl Timer for MockTimer {
+        fn now(&self) -> NaiveDateTime {
+            self.state().now
+        }
+
+        fn at(&self, when: NaiveDateTime) -> TimerFuture {
+            let waiter = {
+                let mut state = self.state();
+                if when <= state.now {
+                    None
+                } else {
+                    let (sender, receiver) = oneshot::channel();
+                    state.waiters.push((when, sender));
+                    Some(receiver)
+                }
+            };
+
+            let timer = self.clone();
+            Box::pin(async move {
+                if let Some(waiter) = waiter {
+                    if waiter.await.is_ok() {
+                        timer.state().woken -= 1;
+                    }
+                }
+            })
+        }
+    }
+
+    // A scheduler running against a mock timer, in its own temporary schedules dir.
+    //
+    // Time only passes when `advance` is called. It stops at each time a task is waiting for,
+    // and lets the woken tasks run before moving on, so runs happen in the same order as they
+    // would in real time, but without waiting for them.
+    pub struct TimerHarness {
+        pub scheduler: Scheduler,
+        pub timer: MockTimer,
+        _dir: TempDir,
+    }
+
+    impl TimerHarness {
+        // Starts a scheduler in safe mode, at the given time in yyyy-mm-dd hh:mm:ss format
+        pub fn new(start: &str) -> Self {
+            let start = NaiveDateTime::parse_from_str(start, "%Y-%m-%d %H:%M:%S").unwrap();
+            let timer = MockTimer::new(start);
+            let dir = TempDir::new().unwrap();
+            let scheduler = Scheduler::new(&dir.path().to_string_lossy())
+                .unwrap()
+                .with_timer(timer.clone());
+            scheduler.init().unwrap();
+            scheduler.start().unwrap();
+
+            TimerHarness {
+                scheduler,
+                timer,
+                _dir: dir,
+            }
+        }
+
+        // Imports a task list into the mode, creating the mode if it doesn't exist yet.
+        // Task lists imported into the active mode aren't scheduled until it's activated again.
+        pub fn import(&self, name: &str, mode: &str, task_list: Value) {
+            let dir = &self.scheduler.scheduler_dir;
+            let _ = create_mode(dir, mode);
+            import_raw_task_list(dir, name, mode, &task_list.to_string(), None).unwrap();
+        }
+
+        // Activates the mode and schedules its tasks, as the activateMode mutation does
+        pub fn activate(&self, mode: &str) {
+            activate_mode(&self.scheduler.scheduler_dir, mode).unwrap();
+            self.scheduler.stop().unwrap();
+            self.scheduler.start().unwrap();
+            self.settle();
+        }
+
+        // Moves time forward, stopping at each time a task is waiting for
+        pub fn advance(&self, duration: Duration) {
+            let target = self.timer.now() + duration;
+            self.settle();
+            while let Some(next) = self.timer.next_wait().filter(|next| *next <= target) {
+                self.timer.set(next);
+                self.settle();
+            }
+            self.timer.set(target);
+            self.settle();
+        }
+
+        // Names and times of the executions recorded so far, oldest first
+        pub fn runs(&self) -> Vec<(String, String)> {
+            self.timer
+                .runs()
+                .into_iter()
+                .map(|run| (run.name, run.time))
+                .collect()
+        }
+
+        // Waits until the woken tasks have resumed and newly scheduled tasks have started
+        // waiting, so that the next wait is known before time moves on
+        fn settle(&self) {
+            let started = Instant::now();
+            let mut waiting = None;
+            while started.elapsed() < SETTLE_TIMEOUT {
+                thread::sleep(SETTLE_POLL);
+                let now_waiting = self.timer.waiting();
+                if self.timer.woken() == 0 && waiting == Some(now_waiting) {
+                    return;
+                }
+                waiting = Some(now_waiting);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::TimerHarness;
+    use super::Timer;
+    use crate::scheduler::MAX_UPCOMING_LIMIT;
+    use chrono::Duration;
+    use serde_json::json;
+    use std::fs;
+
+    fn runs(runs: &[(&str, &str)]) -> Vec<(String, String)> {
+        runs.iter()
+            .map(|(name, time)| (name.to_string(), time.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_onetime_task() {
+        let harness = TimerHarness::new("2020-01-01 00:00:00");
+        harness.import(
+            "experiment",
+            "operational",
+            json!({ "tasks": [{ "delay": "90m", "app": { "name": "experiment-app" } }] }),
+        );
+        harness.activate("operational");
+
+        harness.advance(Duration::hours(1));
+        assert!(harness.runs().is_empty());
+
+        harness.advance(Duration::hours(1));
+        assert_eq!(
+            harness.runs(),
+            runs(&[("experiment-app", "2020-01-01 01:30:00")])
+        );
+        assert_eq!(
+            harness.timer.now().to_string(),
+            "2020-01-01 02:00:00".to_owned()
+        );
+    }
+
+    #[test]
+    fn test_recurring_task() {
+        let harness = TimerHarness::new("2020-01-01 00:00:00");
+        harness.import(
+            "beacon",
+            "operational",
+            json!({
+                "tasks": [{ "delay": "10m", "period": "1h", "app": { "name": "beacon-app" } }]
+            }),
+        );
+        harness.activate("operational");
+
+        // A day of executions, without waiting a day
+        harness.advance(Duration::days(1));
+        let times: Vec<String> = harness.runs().into_iter().map(|(_, time)| time).collect();
+        assert_eq!(times.len(), 24);
+        assert_eq!(times[0], "2020-01-01 00:10:00");
+        assert_eq!(times[23], "2020-01-01 23:10:00");
+    }
+
+    #[test]
+    fn test_stop_all_task_lists() {
+        let harness = TimerHarness::new("2020-01-01 00:00:00");
+        for name in &["beacon", "imaging", "telemetry"] {
+            harness.import(
+                name,
+                "operational",
+                json!({
+                    "tasks": [{ "delay": "10m", "period": "1h", "app": { "name": name } }]
+                }),
+            );
+        }
+        harness.activate("operational");
+        assert_eq!(harness.scheduler.upcoming(10).len(), 10);
+
+        // Every list's tasks have finished by the time stop returns
+        assert_eq!(harness.timer.waiting(), 3);
+        harness.scheduler.stop().unwrap();
+        assert_eq!(harness.timer.waiting(), 0);
+        assert!(harness.scheduler.upcoming(10).is_empty());
+        harness.advance(Duration::hours(2));
+        assert!(harness.runs().is_empty());
+    }
+
+    #[test]
+    fn test_upcoming_limit_clamped() {
+        let harness = TimerHarness::new("2020-01-01 00:00:00");
+        harness.import(
+            "beacon",
+            "operational",
+            json!({ "tasks": [{ "delay": "1s", "period": "1s", "app": { "name": "beacon" } }] }),
+        );
+        harness.activate("operational");
+
+        let upcoming = harness.scheduler.upcoming(std::i32::MAX as usize);
+        assert_eq!(upcoming.len(), MAX_UPCOMING_LIMIT);
+        assert_eq!(upcoming[0].time, "2020-01-01 00:00:01");
+        assert_eq!(upcoming[MAX_UPCOMING_LIMIT - 1].time, "2020-01-01 00:16:40");
+    }
+
+    #[test]
+    fn test_mode_change_task() {
+        let harness = TimerHarness::new("2020-01-01 00:00:00");
+        harness.import(
+            "pass",
+            "downlink",
+            json!({
+                "tasks": [{ "delay": "5m", "period": "10m", "app": { "name": "downlink-app" } }]
+            }),
+        );
+        harness.import(
+            "switch",
+            "operational",
+            json!({
+                "tasks": [
+                    { "delay": "30m", "period": "1h", "app": { "name": "payload-app" } },
+                    { "delay": "1h", "mode_change": "downlink" }
+                ]
+            }),
+        );
+        harness.activate("operational");
+
+        // The operational mode's tasks stop once the downlink mode takes over
+        harness.advance(Duration::minutes(80));
+        assert_eq!(
+            harness.runs(),
+            runs(&[
+                ("payload-app", "2020-01-01 00:30:00"),
+                ("mode_change:downlink", "2020-01-01 01:00:00"),
+                ("downlink-app", "2020-01-01 01:05:00"),
+                ("downlink-app", "2020-01-01 01:15:00"),
+            ])
+        );
+        assert_eq!(
+            harness.scheduler.active_mode_name(),
+            Some("downlink".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_failover_task() {
+        let harness = TimerHarness::new("2020-01-01 00:00:00");
+        harness.import(
+            "recovery",
+            "safe",
+            json!({
+                "tasks": [{ "delay": "1m", "period": "30m", "app": { "name": "recovery-app" } }]
+            }),
+        );
+        harness.import("pass", "downlink", json!({ "tasks": [] }));
+        harness.import(
+            "switch",
+            "operational",
+            json!({ "tasks": [{ "delay": "10m", "mode_change": "downlink" }] }),
+        );
+        harness.activate("operational");
+
+        // The mode is removed after the mode change task was imported, so it fails over
+        fs::remove_dir_all(format!("{}/downlink", harness.scheduler.scheduler_dir)).unwrap();
+        harness.advance(Duration::hours(1));
+        assert_eq!(
+            harness.runs(),
+            runs(&[
+                ("mode_change:downlink", "2020-01-01 00:10:00"),
+                ("recovery-app", "2020-01-01 00:11:00"),
+                ("recovery-app", "2020-01-01 00:41:00"),
+            ])
+        );
+        assert_eq!(
+            harness.scheduler.active_mode_name(),
+            Some("safe".to_owned())
+        );
+        assert_eq!(
+            harness
+                .scheduler
+                .last_failover()
+                .unwrap()
+                .map(|failover| failover.previous_mode),
+            Some(Some("operational".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright (C) 2020 Kubos Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//!
+//! Watchdog kicking, so that a wedged scheduler resets the system
+//!
+//! The watchdog thread kicks the configured watchdog every kick interval, but only while the
+//! scheduler is healthy: the timer runtime must have run within the stall timeout and the active
+//! mode must still be in place. Once either check fails the kicks stop, leaving the watchdog to
+//! reset the system when its own timeout expires. Kicks resume if the scheduler recovers first.
+//!
+
+use crate::error::SchedulerError;
+use crate::scheduler::Scheduler;
+use log::{error, info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+// Sent to a software watchdog with each kick
+const KICK_MESSAGE: &[u8] = b"scheduler-service";
+
+// Something which resets the system unless it's kicked often enough
+pub trait Watchdog: Send {
+    fn kick(&mut self) -> Result<(), SchedulerError>;
+}
+
+// A watchdog driver device, such as `/dev/watchdog`. The device is never closed with the magic
+// character, so the watchdog stays armed if the service exits
+pub struct DeviceWatchdog {
+    file: File,
+}
+
+impl DeviceWatchdog {
+    // Opening the device starts the watchdog
+    pub fn open(path: &str) -> Result<Self, SchedulerError> {
+        let file = OpenOptions::new().write(true).open(path).map_err(|e| {
+            SchedulerError::WatchdogError {
+                err: format!("Failed to open {}: {}", path, e),
+            }
+        })?;
+        Ok(DeviceWatchdog { file })
+    }
+}
+
+impl Watchdog for DeviceWatchdog {
+    fn kick(&mut self) -> Result<(), SchedulerError> {
+        self.file
+            .write_all(b"\0")
+            .and_then(|_| self.file.flush())
+            .map_err(|e| SchedulerError::WatchdogError {
+                err: format!("Failed to kick device: {}", e),
+            })
+    }
+}
+
+// A software watchdog, such as a monitor service, which is sent a datagram with each kick
+pub struct UdpWatchdog {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl UdpWatchdog {
+    pub fn open(target: SocketAddr) -> Result<Self, SchedulerError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| SchedulerError::WatchdogError {
+            err: format!("Failed to bind socket: {}", e),
+        })?;
+        Ok(UdpWatchdog { socket, target })
+    }
+}
+
+impl Watchdog for UdpWatchdog {
+    fn kick(&mut self) -> Result<(), SchedulerError> {
+        self.socket
+            .send_to(KICK_MESSAGE, self.target)
+            .map(|_| ())
+            .map_err(|e| SchedulerError::WatchdogError {
+                err: format!("Failed to kick {}: {}", self.target, e),
+            })
+    }
+}
+
+// A command which kicks the watchdog each time it's run, for watchdogs without a driver
+pub struct CommandWatchdog {
+    command: String,
+}
+
+impl Watchdog for CommandWatchdog {
+    fn kick(&mut self) -> Result<(), SchedulerError> {
+        match Command::new(&self.command).status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(SchedulerError::WatchdogError {
+                err: format!("{} failed: {}", self.command, status),
+            }),
+            Err(e) => Err(SchedulerError::WatchdogError {
+                err: format!("Failed to run {}: {}", self.command, e),
+            }),
+        }
+    }
+}
+
+// Which watchdog is kicked
+#[derive(Clone, Debug)]
+pub enum WatchdogTarget {
+    Device(String),
+    Udp(SocketAddr),
+    Command(String),
+}
+
+impl WatchdogTarget {
+    fn open(&self) -> Result<Box<dyn Watchdog>, SchedulerError> {
+        Ok(match self {
+            WatchdogTarget::Device(path) => Box::new(DeviceWatchdog::open(path)?),
+            WatchdogTarget::Udp(target) => Box::new(UdpWatchdog::open(*target)?),
+            WatchdogTarget::Command(command) => Box::new(CommandWatchdog {
+                command: command.to_owned(),
+            }),
+        })
+    }
+}
+
+// Settings for watchdog kicking, from the `[scheduler-service.watchdog]` config section
+#[derive(Clone, Debug)]
+pub struct WatchdogConfig {
+    pub target: WatchdogTarget,
+    // Time between kicks. Must be well within the watchdog's own timeout
+    pub kick_interval: Duration,
+    // Time without the timer runtime running before the scheduler is considered wedged
+    pub stall_timeout: Duration,
+}
+
+// Why the watchdog shouldn't be kicked, if the scheduler is unhealthy
+fn check_health(scheduler: &Scheduler, stall_timeout: Duration) -> Result<(), SchedulerError> {
+    let idle = scheduler.runtime_idle();
+    if idle > stall_timeout {
+        return Err(SchedulerError::WatchdogError {
+            err: format!("Timer runtime hasn't run for {}ms", idle.as_millis()),
+        });
+    }
+    scheduler.check_state()
+}
+
+// Opens the watchdog and starts a thread which kicks it while the scheduler is healthy
+pub fn start(scheduler: &Scheduler, config: WatchdogConfig) -> Result<(), SchedulerError> {
+    let mut watchdog = config.target.open()?;
+    info!(
+        "Kicking watchdog {:?} every {}ms",
+        config.target,
+        config.kick_interval.as_millis()
+    );
+
+    let scheduler = scheduler.clone();
+    thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || {
+            let mut healthy = true;
+            loop {
+                match check_health(&scheduler, config.stall_timeout) {
+                    Ok(()) => {
+                        if !healthy {
+                            info!("Scheduler has recovered, resuming watchdog kicks");
+                            healthy = true;
+                        }
+                        if let Err(e) = watchdog.kick() {
+                            warn!("{}", e);
+                        }
+                    }
+                    Err(e) => {
+                        if healthy {
+                            error!("{}. Watchdog kicks stopped", e);
+                            healthy = false;
+                        }
+                    }
+                }
+                thread::sleep(config.kick_interval);
+            }
+        })
+        .map_err(|e| SchedulerError::StartError {
+            err: format!("Failed to start watchdog thread: {:?}", e),
+        })?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+fn sleep_task_list() -> String {
+    json!({
+        "tasks": [
+            {
+                "id": 1,
+                "delay": "0s",
+                "app": {
+                    "name": "sleep",
+                    "args": ["60"]
+                }
+            }
+        ]
+    })
+    .to_string()
+}
+
+#[test]
+fn abort_running_task() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8020);
+
+    fixture.create_mode("operational");
+    let schedule_path = fixture.create_task_list(Some(sleep_task_list()));
+    fixture.import_task_list("sleepy", &schedule_path, "operational");
+    fixture.activate_mode("operational");
+
+    // Give the task a chance to start
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(
+        fixture.abort_task(1),
+        json!({
+            "data" : {
+                "abortTask": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn abort_unscheduled_task() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8021);
+
+    assert_eq!(
+        fixture.abort_task(2),
+        json!({
+            "data" : {
+                "abortTask": {
+                    "errors": "Failed to abort tasks: Task 2 is not scheduled",
+                    "success": false
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn abort_all_tasks() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8022);
+
+    fixture.create_mode("operational");
+    let schedule_path = fixture.create_task_list(Some(sleep_task_list()));
+    fixture.import_task_list("sleepy", &schedule_path, "operational");
+    fixture.activate_mode("operational");
+
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(
+        fixture.abort_all_tasks(),
+        json!({
+            "data" : {
+                "abortAllTasks": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+}
@@ -0,0 +1,88 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+use utils::testing::ServiceListener;
+
+fn set_armed(fixture: &SchedulerFixture, armed: bool) -> serde_json::Value {
+    fixture.query(&format!(
+        r#"mutation {{ setArmed(armed: {}) {{ errors, success }} }}"#,
+        armed
+    ))
+}
+
+#[test]
+fn disarmed_tasks_not_run() {
+    let listener = ServiceListener::spawn("127.0.0.1", 9023);
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8023);
+
+    assert_eq!(
+        fixture.query(r#"{ armed }"#),
+        json!({ "data": { "armed": true } })
+    );
+    assert_eq!(
+        set_armed(&fixture, false),
+        json!({
+            "data": {
+                "setArmed": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    fixture.create_mode("init");
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "basic-task",
+                "delay": "1s",
+                "app": {
+                    "name": "basic-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    fixture.import_task_list("imaging", &schedule_path, "init");
+    fixture.activate_mode("init");
+
+    thread::sleep(Duration::from_millis(2000));
+    assert_eq!(listener.get_request(), None);
+
+    // The disarmed state survives a restart
+    fixture.restart();
+    assert_eq!(
+        fixture.query(r#"{ armed }"#),
+        json!({ "data": { "armed": false } })
+    );
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(listener.get_request(), None);
+
+    // Once armed, the task list's tasks run
+    set_armed(&fixture, true);
+    fixture.activate_mode("init");
+    thread::sleep(Duration::from_millis(2000));
+
+    let query = r#"{"query":"mutation { startApp(name: \"basic-app\") { success, errors } }"}"#;
+    assert_eq!(listener.get_request(), Some(query.to_owned()));
+}
@@ -0,0 +1,93 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use chrono::{NaiveDateTime, Utc};
+use serde_json::json;
+use util::SchedulerFixture;
+
+#[test]
+fn boot_limited_tasks() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8041);
+    fixture.create_mode("operational");
+
+    let boot = fixture.query(r#"{ boot { count, uptime } }"#)["data"]["boot"].clone();
+    assert_eq!(boot["count"], json!(1));
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "deploy-task",
+                "delay": "10s",
+                "max_boots": 1,
+                "min_uptime": "100000h",
+                "app": {
+                    "name": "deploy-app"
+                }
+            },
+            {
+                "description": "init-task",
+                "delay": "1h",
+                "app": {
+                    "name": "init-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    fixture.import_task_list("deploy", &schedule_path, "operational");
+    fixture.activate_mode("operational");
+
+    // The deploy task waits until the OBC has been up for long enough, however long the
+    // test machine has been up
+    let upcoming = fixture.query(r#"{ upcoming { name, time } }"#)["data"]["upcoming"].clone();
+    assert_eq!(upcoming[0]["name"], json!("init-app"));
+    assert_eq!(upcoming[1]["name"], json!("deploy-app"));
+    let deploy_time =
+        NaiveDateTime::parse_from_str(upcoming[1]["time"].as_str().unwrap(), "%Y-%m-%d %H:%M:%S")
+            .unwrap();
+    let remaining = deploy_time - Utc::now().naive_utc();
+    let uptime = chrono::Duration::seconds(boot["uptime"].as_f64().unwrap() as i64);
+    assert!(remaining <= chrono::Duration::hours(100000) - uptime);
+
+    // Restarting the service isn't another boot
+    fixture.restart();
+    let boot = fixture.query(r#"{ boot { count } }"#)["data"]["boot"].clone();
+    assert_eq!(boot["count"], json!(1));
+}
+
+#[test]
+fn invalid_boot_limits() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8042);
+    fixture.create_mode("operational");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "never-task",
+                "delay": "10s",
+                "max_boots": 0,
+                "app": {
+                    "name": "never-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    let result = fixture.import_task_list("never", &schedule_path, "operational");
+    assert_eq!(result["data"]["importTaskList"]["success"], json!(false));
+}
@@ -0,0 +1,116 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use util::SchedulerFixture;
+
+fn upcoming_lists(fixture: &SchedulerFixture) -> Vec<String> {
+    let result = fixture.query(r#"{ upcoming(limit: 2) { list } }"#);
+    result["data"]["upcoming"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|run| run["list"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn disable_and_enable_task_list() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8044);
+    fixture.create_mode("operational");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "recurring-task",
+                "delay": "1h",
+                "period": "1h",
+                "app": {
+                    "name": "experiment-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    fixture.import_task_list("experiment", &schedule_path, "operational");
+    fixture.activate_mode("operational");
+    assert_eq!(upcoming_lists(&fixture), vec!["experiment", "experiment"]);
+
+    assert_eq!(
+        fixture.disable_task_list("experiment", "operational"),
+        json!({
+            "data": {
+                "disableTaskList": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+    assert!(upcoming_lists(&fixture).is_empty());
+    assert_eq!(
+        fixture.query(r#"{ activeMode { schedule { filename, enabled } } }"#),
+        json!({
+            "data": {
+                "activeMode": {
+                    "schedule": [
+                        {
+                            "filename": "experiment",
+                            "enabled": false
+                        }
+                    ]
+                }
+            }
+        })
+    );
+
+    // The list stays disabled across restarts
+    fixture.restart();
+    assert!(upcoming_lists(&fixture).is_empty());
+
+    assert_eq!(
+        fixture.enable_task_list("experiment", "operational"),
+        json!({
+            "data": {
+                "enableTaskList": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+    assert_eq!(upcoming_lists(&fixture), vec!["experiment", "experiment"]);
+}
+
+#[test]
+fn disable_missing_task_list() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8045);
+    fixture.create_mode("operational");
+
+    assert_eq!(
+        fixture.disable_task_list("missing", "operational"),
+        json!({
+            "data": {
+                "disableTaskList": {
+                    "errors": "Scheduler error encountered: Task list 'missing' not found in mode 'operational'",
+                    "success": false
+                }
+            }
+        })
+    );
+}
@@ -0,0 +1,155 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use util::SchedulerFixture;
+
+#[test]
+fn import_uploaded_schedule() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8020);
+    fixture.create_mode("operational");
+
+    let upload_dir = TempDir::new().unwrap();
+    let upload_path = upload_dir.path().join("first.json");
+    fs::write(&upload_path, json!({ "tasks": [ ] }).to_string()).unwrap();
+    let upload_path = upload_path.to_str().unwrap();
+
+    assert_eq!(
+        fixture.import_uploaded("first", upload_path, "operational"),
+        json!({
+            "data" : {
+                "importUploaded": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    // The upload is moved into the mode, rather than copied
+    assert!(!Path::new(upload_path).exists());
+
+    assert_eq!(
+        fixture.query(r#"{ availableModes(name: "operational") { name, schedule { filename } } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "operational",
+                        "schedule": [
+                            {
+                                "filename": "first"
+                            }
+                        ]
+                    }
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn import_uploaded_invalid_schedule() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8021);
+    fixture.create_mode("operational");
+
+    let upload_dir = TempDir::new().unwrap();
+    let upload_path = upload_dir.path().join("first.json");
+    fs::write(&upload_path, json!({ "init": [ ] }).to_string()).unwrap();
+    let upload_path = upload_path.to_str().unwrap();
+
+    assert_eq!(
+        fixture.import_uploaded("first", upload_path, "operational"),
+        json!({
+            "data" : {
+                "importUploaded": {
+                    "errors": "Failed to parse task list 'first': Failed to parse json: missing field `tasks` at line 1 column 11",
+                    "success": false
+                }
+            }
+        })
+    );
+
+    // Nothing is left behind in the mode, and the upload is kept so it can be fixed
+    assert!(Path::new(upload_path).exists());
+
+    assert_eq!(
+        fixture.query(r#"{ availableModes(name: "operational") { name, schedule { filename } } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "operational",
+                        "schedule": [ ]
+                    }
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn import_uploaded_from_import_dir() {
+    let import_dir = TempDir::new().unwrap();
+    let import_dir_path = import_dir.path().to_str().unwrap();
+
+    let fixture = SchedulerFixture::spawn_with_config(
+        "127.0.0.1",
+        8022,
+        &format!(r#"import_dir = "{}""#, import_dir_path),
+    );
+    fixture.create_mode("operational");
+
+    let mode_dir = import_dir.path().join("operational");
+    fs::create_dir(&mode_dir).unwrap();
+    fs::write(
+        mode_dir.join("first.json"),
+        json!({ "tasks": [ ] }).to_string(),
+    )
+    .unwrap();
+    fs::write(mode_dir.join("second.json"), "not json").unwrap();
+
+    // Give the files time to settle and the scheduler time to notice them
+    thread::sleep(Duration::from_secs(8));
+
+    assert!(!mode_dir.join("first.json").exists());
+    assert!(mode_dir.join("second.json.rejected").exists());
+
+    assert_eq!(
+        fixture.query(r#"{ availableModes(name: "operational") { name, schedule { filename } } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "operational",
+                        "schedule": [
+                            {
+                                "filename": "first"
+                            }
+                        ]
+                    }
+                ]
+            }
+        })
+    );
+}
@@ -60,7 +60,7 @@ fn validate_no_task_app() {
         json!({
             "data" : {
                 "importTaskList": {
-                    "errors": "Failed to parse task list \'first\': Failed to parse json: missing field `app` at line 1 column 52",
+                    "errors": "Failed to parse task \'\': No app or mode_change defined",
                     "success": false
                 }
             }
@@ -0,0 +1,151 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use util::SchedulerFixture;
+
+#[test]
+fn set_mode_parent() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8020);
+
+    fixture.create_mode("nominal");
+    fixture.create_mode("imaging");
+
+    assert_eq!(
+        fixture.set_mode_parent("imaging", Some("nominal")),
+        json!({
+            "data" : {
+                "setModeParent": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    assert_eq!(
+        fixture.query(r#"{ availableModes(name: "imaging") { name, parent } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "imaging",
+                        "parent": "nominal"
+                    }
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn clear_mode_parent() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8021);
+
+    fixture.create_mode("nominal");
+    fixture.create_mode("imaging");
+    fixture.set_mode_parent("imaging", Some("nominal"));
+
+    assert_eq!(
+        fixture.set_mode_parent("imaging", None),
+        json!({
+            "data" : {
+                "setModeParent": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    assert_eq!(
+        fixture.query(r#"{ availableModes(name: "imaging") { name, parent } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "imaging",
+                        "parent": null
+                    }
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn set_mode_parent_nonexistent() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8022);
+
+    fixture.create_mode("imaging");
+
+    assert_eq!(
+        fixture.set_mode_parent("imaging", Some("nominal")),
+        json!({
+            "data" : {
+                "setModeParent": {
+                    "errors": "Scheduler error encountered: Parent mode 'nominal' not found",
+                    "success": false
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn set_mode_parent_loop() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8023);
+
+    fixture.create_mode("nominal");
+    fixture.create_mode("imaging");
+    fixture.set_mode_parent("imaging", Some("nominal"));
+
+    assert_eq!(
+        fixture.set_mode_parent("nominal", Some("imaging")),
+        json!({
+            "data" : {
+                "setModeParent": {
+                    "errors": "Scheduler error encountered: Mode 'imaging' already inherits from 'nominal'",
+                    "success": false
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn remove_inherited_mode() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8024);
+
+    fixture.create_mode("nominal");
+    fixture.create_mode("imaging");
+    fixture.set_mode_parent("imaging", Some("nominal"));
+    fixture.activate_mode("imaging");
+
+    assert_eq!(
+        fixture.remove_mode("nominal"),
+        json!({
+            "data" : {
+                "removeMode": {
+                    "errors": "Failed to remove 'nominal': Cannot remove mode inherited by the active mode",
+                    "success": false
+                }
+            }
+        })
+    );
+}
@@ -0,0 +1,127 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+#[test]
+fn mode_change_activates_mode() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8020);
+
+    fixture.create_mode("operational");
+    fixture.create_mode("imaging");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "start-imaging",
+                "delay": "1s",
+                "mode_change": "imaging"
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    assert_eq!(
+        fixture.import_task_list("sequence", &schedule_path, "operational"),
+        json!({
+            "data": {
+                "importTaskList": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+    fixture.activate_mode("operational");
+
+    thread::sleep(Duration::from_millis(2000));
+
+    assert_eq!(
+        fixture.query(r#"{ activeMode { name } }"#),
+        json!({
+            "data": {
+                "activeMode": {
+                    "name": "imaging"
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn mode_change_missing_mode() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8021);
+
+    fixture.create_mode("operational");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "start-imaging",
+                "delay": "1s",
+                "mode_change": "imaging"
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    assert_eq!(
+        fixture.import_task_list("sequence", &schedule_path, "operational"),
+        json!({
+            "data": {
+                "importTaskList": {
+                    "errors": "Failed to parse task \'mode_change:imaging\': Mode \'imaging\' not found",
+                    "success": false
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn mode_change_with_app() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8022);
+
+    fixture.create_mode("operational");
+    fixture.create_mode("imaging");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "start-imaging",
+                "delay": "1s",
+                "mode_change": "imaging",
+                "app": {
+                    "name": "imaging-app"
+                }
+            }
+        ]
+    });
+    assert_eq!(
+        fixture.import_raw_task_list("sequence", "operational", &schedule.to_string()),
+        json!({
+            "data": {
+                "importRawTaskList": {
+                    "errors": "Failed to parse task \'imaging-app\': Both app and mode_change defined",
+                    "success": false
+                }
+            }
+        })
+    );
+}
@@ -0,0 +1,110 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use util::SchedulerFixture;
+
+#[test]
+fn set_mode_variables() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8038);
+
+    fixture.create_mode("experiment");
+
+    assert_eq!(
+        fixture.set_mode_variables("experiment", &[("EXPOSURE", "20"), ("CAMERA", "wide")]),
+        json!({
+            "data" : {
+                "setModeVariables": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    assert_eq!(
+        fixture
+            .query(r#"{ availableModes(name: "experiment") { name, variables { name, value } } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "experiment",
+                        "variables": [
+                            { "name": "CAMERA", "value": "wide" },
+                            { "name": "EXPOSURE", "value": "20" }
+                        ]
+                    }
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn clear_mode_variables() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8039);
+
+    fixture.create_mode("experiment");
+    fixture.set_mode_variables("experiment", &[("EXPOSURE", "20")]);
+
+    assert_eq!(
+        fixture.set_mode_variables("experiment", &[]),
+        json!({
+            "data" : {
+                "setModeVariables": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    assert_eq!(
+        fixture.query(r#"{ availableModes(name: "experiment") { name, variables { name } } }"#),
+        json!({
+            "data": {
+                "availableModes": [
+                    {
+                        "name": "experiment",
+                        "variables": []
+                    }
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn set_mode_variables_invalid_name() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8040);
+
+    fixture.create_mode("experiment");
+
+    assert_eq!(
+        fixture.set_mode_variables("experiment", &[("EXPOSURE=20", "20")]),
+        json!({
+            "data" : {
+                "setModeVariables": {
+                    "errors": "Scheduler error encountered: Invalid variable name 'EXPOSURE=20'",
+                    "success": false
+                }
+            }
+        })
+    );
+}
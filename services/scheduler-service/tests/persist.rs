@@ -0,0 +1,67 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+fn upcoming(fixture: &SchedulerFixture) -> serde_json::Value {
+    fixture.query(r#"{ upcoming { name, time } }"#)["data"]["upcoming"].clone()
+}
+
+#[test]
+fn persisted_delay_survives_restart() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8033);
+    fixture.create_mode("operational");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "countdown-task",
+                "delay": "1h",
+                "persist": true,
+                "app": {
+                    "name": "countdown-app"
+                }
+            },
+            {
+                "description": "init-task",
+                "delay": "2h",
+                "app": {
+                    "name": "init-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    fixture.import_task_list("countdown", &schedule_path, "operational");
+    fixture.activate_mode("operational");
+
+    let before = upcoming(&fixture);
+    assert_eq!(before[0]["name"], json!("countdown-app"));
+
+    // Make sure the init task's delay is re-baselined to a different second
+    thread::sleep(Duration::from_millis(1500));
+    fixture.restart();
+
+    let after = upcoming(&fixture);
+    assert_eq!(after[0], before[0]);
+    assert_eq!(after[1]["name"], json!("init-app"));
+    assert_ne!(after[1]["time"], before[1]["time"]);
+}
@@ -0,0 +1,133 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::net::UdpSocket;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+// Binds a socket for the scheduler to send safe mode events to
+fn listener() -> (UdpSocket, u16) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+    (socket, port)
+}
+
+fn recv_event(socket: &UdpSocket) -> serde_json::Value {
+    let mut buf = [0; 1024];
+    let size = socket.recv(&mut buf).expect("No safe mode event received");
+    serde_json::from_slice(&buf[0..size]).unwrap()
+}
+
+#[test]
+fn failover_broadcast() {
+    let (socket, port) = listener();
+    let fixture = SchedulerFixture::spawn_with_config(
+        "127.0.0.1",
+        8020,
+        &format!("safe_mode_ports = [{}]", port),
+    );
+
+    fixture.create_mode("operational");
+    fixture.activate_mode("operational");
+
+    assert_eq!(
+        fixture.activate_mode("missing"),
+        json!({
+            "data" : {
+                "activateMode": {
+                    "errors": "Scheduler failed over to safe mode due to error: Failed to activate mode 'missing' not found",
+                    "success": false
+                }
+            }
+        })
+    );
+
+    let event = recv_event(&socket);
+    assert_eq!(event["event"], "safe_mode");
+    assert_eq!(event["previous_mode"], "operational");
+    assert_eq!(event["commanded"], false);
+    assert!(event["reason"].as_str().unwrap().contains("missing"));
+}
+
+#[test]
+fn commanded_broadcast() {
+    let (socket, port) = listener();
+    let fixture = SchedulerFixture::spawn_with_config(
+        "127.0.0.1",
+        8021,
+        &format!("safe_mode_ports = [{}]", port),
+    );
+
+    fixture.create_mode("operational");
+    fixture.activate_mode("operational");
+
+    assert_eq!(
+        fixture.activate_safe(),
+        json!({
+            "data" : {
+                "safeMode": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    let event = recv_event(&socket);
+    assert_eq!(event["event"], "safe_mode");
+    assert_eq!(event["previous_mode"], "operational");
+    assert_eq!(event["commanded"], true);
+
+    // Nothing is sent if safe mode was already active
+    fixture.activate_safe();
+    assert!(socket.recv(&mut [0; 1024]).is_err());
+}
+
+#[test]
+fn last_failover_saved() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8022);
+    let query = r#"{ lastFailover { reason, previousMode } }"#;
+    assert_eq!(
+        fixture.query(query),
+        json!({ "data": { "lastFailover": null } })
+    );
+
+    fixture.create_mode("operational");
+    fixture.activate_mode("operational");
+    fixture.activate_mode("missing");
+
+    let expected = json!({
+        "data": {
+            "lastFailover": {
+                "reason": "Scheduler failed over to safe mode due to error: Failed to activate mode 'missing' not found",
+                "previousMode": "operational"
+            }
+        }
+    });
+    assert_eq!(fixture.query(query), expected);
+
+    // Commanded entries to safe mode don't replace the failover, which survives restarts
+    fixture.activate_mode("operational");
+    fixture.activate_safe();
+    fixture.restart();
+    assert_eq!(fixture.query(query), expected);
+}
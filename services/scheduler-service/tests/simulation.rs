@@ -0,0 +1,86 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+#[test]
+fn simulation_records_runs() {
+    // An hour passes every second
+    let fixture = SchedulerFixture::spawn_with_config(
+        "127.0.0.1",
+        8034,
+        r#"
+        [scheduler-service.simulation]
+        speed = 3600
+        start = "2030-01-01 00:00:00"
+        "#,
+    );
+
+    fixture.create_mode("operational");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "onetime-task",
+                "time": "2030-01-03 12:00:00",
+                "app": {
+                    "name": "onetime-app"
+                }
+            },
+            {
+                "description": "recurring-task",
+                "delay": "1h",
+                "period": "1h",
+                "app": {
+                    "name": "recurring-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    fixture.import_task_list("imaging", &schedule_path, "operational");
+    fixture.activate_mode("operational");
+
+    thread::sleep(Duration::from_millis(2500));
+
+    let result = fixture.query(r#"{ simulation { speed, stubCommand, runs { name } } }"#);
+    assert_eq!(result["data"]["simulation"]["speed"], json!(3600));
+    assert_eq!(result["data"]["simulation"]["stubCommand"], json!(null));
+
+    // The recurring task has run at least twice, and the onetime task isn't due yet
+    let runs = result["data"]["simulation"]["runs"].as_array().unwrap();
+    assert!(runs.len() >= 2);
+    assert!(runs.iter().all(|run| run["name"] == json!("recurring-app")));
+}
+
+#[test]
+fn simulation_disabled() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8035);
+
+    assert_eq!(
+        fixture.query(r#"{ simulation { speed } }"#),
+        json!({
+            "data": {
+                "simulation": null
+            }
+        })
+    );
+}
@@ -0,0 +1,124 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+fn spawn_instance(port: u16, peer_port: u16, priority: u8) -> SchedulerFixture {
+    SchedulerFixture::spawn_with_config(
+        "127.0.0.1",
+        port,
+        &format!(
+            r#"
+        [scheduler-service.standby]
+        listen = "127.0.0.1:{}"
+        peer = "127.0.0.1:{}"
+        priority = {}
+        heartbeat_ms = 200
+        takeover_ms = 1000
+        "#,
+            port + 100,
+            peer_port + 100,
+            priority
+        ),
+    )
+}
+
+#[test]
+fn standby_shadows_and_takes_over() {
+    let active = spawn_instance(8036, 8037, 0);
+    let standby = spawn_instance(8037, 8036, 1);
+
+    // With no peer to hear from, the first instance took over
+    assert_eq!(
+        active.query(r#"{ role }"#),
+        json!({ "data": { "role": "ACTIVE" } })
+    );
+    assert_eq!(
+        standby.query(r#"{ role }"#),
+        json!({ "data": { "role": "STANDBY" } })
+    );
+
+    active.create_mode("operational");
+    let schedule = json!({
+        "tasks": [
+            {
+                "description": "basic-task",
+                "delay": "10s",
+                "app": {
+                    "name": "basic-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = active.create_task_list(Some(schedule.to_string()));
+    active.import_task_list("imaging", &schedule_path, "operational");
+    active.activate_mode("operational");
+
+    // The standby copies the active instance's schedule
+    thread::sleep(Duration::from_millis(1000));
+    let query = r#"{ activeMode { name, schedule { filename } } }"#;
+    let expected = json!({
+        "data": {
+            "activeMode": {
+                "name": "operational",
+                "schedule": [
+                    {
+                        "filename": "imaging"
+                    }
+                ]
+            }
+        }
+    });
+    assert_eq!(standby.query(query), expected);
+
+    // Its copy can't be changed directly
+    assert_eq!(
+        standby.create_mode("other"),
+        json!({
+            "data": {
+                "createMode": {
+                    "errors": "Scheduler is on standby",
+                    "success": false
+                }
+            }
+        })
+    );
+
+    // Once the active instance goes quiet, the standby takes over with the same schedule
+    active.kill();
+    thread::sleep(Duration::from_millis(2000));
+    assert_eq!(
+        standby.query(r#"{ role }"#),
+        json!({ "data": { "role": "ACTIVE" } })
+    );
+    assert_eq!(standby.query(query), expected);
+    assert_eq!(
+        standby.create_mode("other"),
+        json!({
+            "data": {
+                "createMode": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+}
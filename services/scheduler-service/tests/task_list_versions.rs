@@ -0,0 +1,129 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use serde_json::json;
+use util::SchedulerFixture;
+
+fn diff(fixture: &SchedulerFixture, path: &str) -> serde_json::Value {
+    fixture.query(&format!(
+        r#"mutation {{ diffTaskList(name: "imaging", mode: "operational", path: "{}") {{
+            success, matches, onboardHash, referenceHash, differences {{ task, kind, fields }}
+        }} }}"#,
+        path
+    ))["data"]["diffTaskList"]
+        .clone()
+}
+
+#[test]
+fn task_list_versions_and_diff() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 9033);
+    fixture.create_mode("operational");
+
+    let schedule = json!({
+        "tasks": [
+            {
+                "id": 1,
+                "delay": "1h",
+                "period": "1h",
+                "app": {
+                    "name": "camera-app"
+                }
+            },
+            {
+                "delay": "2h",
+                "app": {
+                    "name": "downlink-app"
+                }
+            }
+        ]
+    });
+    let schedule_path = fixture.create_task_list(Some(schedule.to_string()));
+    assert_eq!(
+        fixture.query(&format!(
+            r#"mutation {{ importTaskList(name: "imaging", path: "{}", mode: "operational", version: "2020-06-01a") {{ errors, success }} }}"#,
+            schedule_path
+        )),
+        json!({
+            "data": {
+                "importTaskList": {
+                    "errors": "",
+                    "success": true
+                }
+            }
+        })
+    );
+
+    let versions =
+        fixture.query(r#"{ taskListVersions(mode: "operational") { mode, name, version, hash } }"#);
+    let versions = &versions["data"]["taskListVersions"];
+    assert_eq!(versions[0]["mode"], "operational");
+    assert_eq!(versions[0]["name"], "imaging");
+    assert_eq!(versions[0]["version"], "2020-06-01a");
+    let hash = versions[0]["hash"].as_str().unwrap().to_owned();
+    assert_eq!(hash.len(), 64);
+
+    // The uploaded copy matches exactly
+    let result = diff(&fixture, &schedule_path);
+    assert_eq!(result["success"], true);
+    assert_eq!(result["matches"], true);
+    assert_eq!(result["onboardHash"], hash.as_str());
+    assert_eq!(result["referenceHash"], hash.as_str());
+    assert_eq!(result["differences"], json!([]));
+
+    let changed = json!({
+        "tasks": [
+            {
+                "id": 1,
+                "delay": "1h",
+                "period": "2h",
+                "app": {
+                    "name": "camera-app"
+                }
+            },
+            {
+                "delay": "1h",
+                "app": {
+                    "name": "payload-app"
+                }
+            }
+        ]
+    });
+    let changed_path = fixture.create_task_list(Some(changed.to_string()));
+    let result = diff(&fixture, &changed_path);
+    assert_eq!(result["matches"], false);
+    assert_ne!(result["referenceHash"], hash.as_str());
+    assert_eq!(
+        result["differences"],
+        json!([
+            { "task": "1", "kind": "CHANGED", "fields": ["period"] },
+            { "task": "payload-app", "kind": "MISSING", "fields": [] },
+            { "task": "downlink-app", "kind": "EXTRA", "fields": [] }
+        ])
+    );
+}
+
+#[test]
+fn diff_missing_task_list() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 9034);
+    fixture.create_mode("operational");
+
+    let schedule_path = fixture.create_task_list(Some(json!({ "tasks": [] }).to_string()));
+    let result = diff(&fixture, &schedule_path);
+    assert_eq!(result["success"], false);
+    assert_eq!(result["matches"], false);
+}
@@ -0,0 +1,110 @@
+//
+// Copyright (C) 2019 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use chrono::prelude::*;
+use chrono::Utc;
+use serde_json::json;
+use util::SchedulerFixture;
+
+fn import_schedules(fixture: &SchedulerFixture) -> String {
+    fixture.create_mode("operational");
+
+    let onetime_time: DateTime<Utc> = Utc::now()
+        .checked_add_signed(chrono::Duration::minutes(90))
+        .unwrap();
+    let onetime_time = onetime_time.format("%Y-%m-%d %H:%M:%S").to_string();
+
+    let imaging = json!({
+        "tasks": [
+            {
+                "description": "recurring-task",
+                "delay": "1h",
+                "period": "1h",
+                "app": {
+                    "name": "recurring-app"
+                }
+            }
+        ]
+    });
+    let comms = json!({
+        "tasks": [
+            {
+                "description": "onetime-task",
+                "time": onetime_time,
+                "app": {
+                    "name": "onetime-app"
+                }
+            }
+        ]
+    });
+
+    let imaging_path = fixture.create_task_list(Some(imaging.to_string()));
+    fixture.import_task_list("imaging", &imaging_path, "operational");
+    let comms_path = fixture.create_task_list(Some(comms.to_string()));
+    fixture.import_task_list("comms", &comms_path, "operational");
+    fixture.activate_mode("operational");
+
+    onetime_time
+}
+
+#[test]
+fn upcoming_merges_task_lists() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8020);
+    let onetime_time = import_schedules(&fixture);
+
+    let result = fixture.query(r#"{ upcoming(limit: 3) { name, list, mode, time } }"#);
+    let upcoming = result["data"]["upcoming"].as_array().unwrap();
+
+    let names: Vec<(&str, &str)> = upcoming
+        .iter()
+        .map(|run| (run["name"].as_str().unwrap(), run["list"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("recurring-app", "imaging"),
+            ("onetime-app", "comms"),
+            ("recurring-app", "imaging")
+        ]
+    );
+    assert_eq!(upcoming[1]["time"], json!(onetime_time));
+    assert_eq!(upcoming[1]["mode"], json!("operational"));
+}
+
+#[test]
+fn upcoming_empty() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8021);
+
+    assert_eq!(
+        fixture.query(r#"{ upcoming { name } }"#),
+        json!({
+            "data": {
+                "upcoming": []
+            }
+        })
+    );
+}
+
+#[test]
+fn upcoming_default_limit() {
+    let fixture = SchedulerFixture::spawn("127.0.0.1", 8022);
+    import_schedules(&fixture);
+
+    let result = fixture.query(r#"{ upcoming { name } }"#);
+    assert_eq!(result["data"]["upcoming"].as_array().unwrap().len(), 10);
+}
@@ -16,6 +16,7 @@
 
 use std::cell::RefCell;
 use std::io::Write;
+use std::path::Path;
 use std::thread;
 use std::time::Duration;
 use tempfile::{NamedTempFile, TempDir};
@@ -25,13 +26,18 @@ pub struct SchedulerFixture {
     service: RefCell<TestService>,
     ip: String,
     port: u16,
-    _schedules_dir: TempDir,
+    schedules_dir: TempDir,
     schedules_holder: RefCell<Vec<NamedTempFile>>,
 }
 
 #[allow(dead_code)]
 impl SchedulerFixture {
     pub fn spawn(ip: &str, port: u16) -> SchedulerFixture {
+        SchedulerFixture::spawn_with_config(ip, port, "")
+    }
+
+    // Spawns the service with extra settings in its config section
+    pub fn spawn_with_config(ip: &str, port: u16, extra_config: &str) -> SchedulerFixture {
         let schedules_dir = TempDir::new().unwrap();
         let schedules_dir_path = schedules_dir.path().to_str().unwrap();
 
@@ -42,10 +48,12 @@ impl SchedulerFixture {
         port = {}
         [scheduler-service]
         schedules_dir = "{}"
+        {}
         "#,
             ip,
             (port + 1000),
             schedules_dir_path,
+            extra_config,
         );
 
         let mut scheduler_service = TestService::new("scheduler-service", ip, port);
@@ -59,7 +67,7 @@ impl SchedulerFixture {
             service: RefCell::new(scheduler_service),
             ip: ip.to_owned(),
             port,
-            _schedules_dir: schedules_dir,
+            schedules_dir,
             schedules_holder: RefCell::new(vec![]),
         }
     }
@@ -71,6 +79,14 @@ impl SchedulerFixture {
         thread::sleep(Duration::from_millis(1000));
     }
 
+    pub fn kill(&self) {
+        self.service.borrow_mut().kill();
+    }
+
+    pub fn schedules_dir(&self) -> &Path {
+        self.schedules_dir.path()
+    }
+
     pub fn create_task_list(&self, contents: Option<String>) -> String {
         let mut schedule = NamedTempFile::new().unwrap();
 
@@ -115,6 +131,37 @@ impl SchedulerFixture {
         service_query(&mutation, &self.ip, self.port)
     }
 
+    // Sends setModeParent mutation to service under test
+    pub fn set_mode_parent(&self, name: &str, parent: Option<&str>) -> serde_json::Value {
+        let mutation = match parent {
+            Some(parent) => format!(
+                r#"mutation {{ setModeParent(name: "{}", parent: "{}") {{ errors, success }} }}"#,
+                name, parent
+            ),
+            None => format!(
+                r#"mutation {{ setModeParent(name: "{}") {{ errors, success }} }}"#,
+                name
+            ),
+        };
+
+        service_query(&mutation, &self.ip, self.port)
+    }
+
+    // Sends setModeVariables mutation to service under test
+    pub fn set_mode_variables(&self, name: &str, variables: &[(&str, &str)]) -> serde_json::Value {
+        let variables: Vec<String> = variables
+            .iter()
+            .map(|(name, value)| format!(r#"{{ name: "{}", value: "{}" }}"#, name, value))
+            .collect();
+        let mutation = format!(
+            r#"mutation {{ setModeVariables(name: "{}", variables: [{}]) {{ errors, success }} }}"#,
+            name,
+            variables.join(", ")
+        );
+
+        service_query(&mutation, &self.ip, self.port)
+    }
+
     pub fn activate_safe(&self) -> serde_json::Value {
         let mutation = format!(r#"mutation {{ safeMode {{ errors, success }} }}"#,);
 
@@ -139,6 +186,15 @@ impl SchedulerFixture {
         service_query(&mutation, &self.ip, self.port)
     }
 
+    pub fn import_uploaded(&self, name: &str, path: &str, mode: &str) -> serde_json::Value {
+        let mutation = format!(
+            r#"mutation {{ importUploaded(name: "{}", path: "{}", mode: "{}") {{ errors, success }} }}"#,
+            name, path, mode
+        );
+
+        service_query(&mutation, &self.ip, self.port)
+    }
+
     pub fn remove_task_list(&self, name: &str, mode: &str) -> serde_json::Value {
         let mutation = format!(
             r#"mutation {{ removeTaskList(name: "{}", mode: "{}") {{ errors, success }} }}"#,
@@ -148,6 +204,39 @@ impl SchedulerFixture {
         service_query(&mutation, &self.ip, self.port)
     }
 
+    pub fn disable_task_list(&self, name: &str, mode: &str) -> serde_json::Value {
+        let mutation = format!(
+            r#"mutation {{ disableTaskList(name: "{}", mode: "{}") {{ errors, success }} }}"#,
+            name, mode
+        );
+
+        service_query(&mutation, &self.ip, self.port)
+    }
+
+    pub fn enable_task_list(&self, name: &str, mode: &str) -> serde_json::Value {
+        let mutation = format!(
+            r#"mutation {{ enableTaskList(name: "{}", mode: "{}") {{ errors, success }} }}"#,
+            name, mode
+        );
+
+        service_query(&mutation, &self.ip, self.port)
+    }
+
+    pub fn abort_task(&self, id: i32) -> serde_json::Value {
+        let mutation = format!(
+            r#"mutation {{ abortTask(id: {}) {{ errors, success }} }}"#,
+            id
+        );
+
+        service_query(&mutation, &self.ip, self.port)
+    }
+
+    pub fn abort_all_tasks(&self) -> serde_json::Value {
+        let mutation = r#"mutation { abortAllTasks { errors, success } }"#;
+
+        service_query(mutation, &self.ip, self.port)
+    }
+
     pub fn query(&self, query: &str) -> serde_json::Value {
         service_query(&query, &self.ip, self.port)
     }
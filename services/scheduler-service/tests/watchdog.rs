@@ -0,0 +1,50 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod util;
+
+use std::fs;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+use util::SchedulerFixture;
+
+#[test]
+fn kicks_stop_when_active_mode_lost() {
+    let watchdog = UdpSocket::bind("127.0.0.1:8143").unwrap();
+    watchdog
+        .set_read_timeout(Some(Duration::from_millis(1000)))
+        .unwrap();
+    let fixture = SchedulerFixture::spawn_with_config(
+        "127.0.0.1",
+        8043,
+        r#"
+        [scheduler-service.watchdog]
+        udp = "127.0.0.1:8143"
+        kick_ms = 200
+        "#,
+    );
+
+    let mut buf = [0; 64];
+    let size = watchdog.recv(&mut buf).unwrap();
+    assert_eq!(&buf[0..size], b"scheduler-service");
+
+    // Without an active mode the scheduler is no longer healthy
+    fs::remove_file(fixture.schedules_dir().join("active")).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    while watchdog.recv(&mut buf).is_ok() {}
+    assert!(watchdog.recv(&mut buf).is_err());
+}
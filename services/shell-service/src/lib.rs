@@ -20,6 +20,7 @@ use channel_protocol::{ChannelMessage, ChannelProtocol};
 use failure::bail;
 use kubos_system::Config as ServiceConfig;
 use log::{error, info, warn};
+use shell_protocol::recording::{list_recordings, Recorder};
 use shell_protocol::{ProcessHandler, ProtocolError, ShellMessage, ShellProtocol};
 use std::collections::HashMap;
 use std::net::SocketAddr;
@@ -64,7 +65,38 @@ fn list_processes(
     Ok(())
 }
 
+// Send back the list of recorded session transcripts
+fn list_session_recordings(
+    channel_id: u32,
+    host: &str,
+    remote: &str,
+    recording_dir: &Option<String>,
+) -> Result<(), failure::Error> {
+    let chan_proto =
+        channel_protocol::ChannelProtocol::new(host, remote, shell_protocol::CHUNK_SIZE);
+
+    let message = match recording_dir {
+        Some(dir) => match list_recordings(dir) {
+            Ok(recordings) => {
+                shell_protocol::messages::recordings::to_cbor(channel_id, Some(recordings))?
+            }
+            Err(err) => shell_protocol::messages::error::to_cbor(
+                channel_id,
+                &format!("Failed to list session recordings: {}", err),
+            )?,
+        },
+        None => shell_protocol::messages::error::to_cbor(
+            channel_id,
+            "Session recording is not enabled",
+        )?,
+    };
+    chan_proto.send(&message)?;
+
+    Ok(())
+}
+
 // Spawn new process and spin up thread for handling it
+#[allow(clippy::too_many_arguments)]
 fn spawn_process(
     channel_id: u32,
     command: &str,
@@ -72,6 +104,7 @@ fn spawn_process(
     host_addr: &str,
     remote_addr: &str,
     timeout: Duration,
+    recording_dir: &Option<String>,
     shared_threads: Arc<Mutex<HashMap<u32, ThreadProcess>>>,
 ) -> Result<(u32, Sender<(ChannelMessage, SocketAddr)>), failure::Error> {
     #[allow(clippy::type_complexity)]
@@ -80,6 +113,14 @@ fn spawn_process(
         Receiver<(ChannelMessage, SocketAddr)>,
     ) = mpsc::channel();
 
+    // A session which can't be recorded still goes ahead, so the shell stays usable
+    // if the recording directory fills up
+    let recorder = recording_dir.as_ref().and_then(|dir| {
+        Recorder::create(dir, channel_id, command, &args)
+            .map_err(|err| error!("Failed to record session {}: {}", channel_id, err))
+            .ok()
+    });
+
     let proc_handle = match ProcessHandler::spawn(command, args) {
         Ok(p) => p,
         Err(e) => {
@@ -103,6 +144,7 @@ fn spawn_process(
                 channel_id,
                 timeout,
                 proc_handle,
+                recorder,
                 &shared_threads,
                 &receiver,
             )
@@ -118,10 +160,19 @@ fn thread_body(
     channel_id: u32,
     timeout: Duration,
     proc_handle: ProcessHandler,
+    recorder: Option<Recorder>,
     shared_threads: &Arc<Mutex<HashMap<u32, ThreadProcess>>>,
     receiver: &Receiver<(ChannelMessage, SocketAddr)>,
 ) {
     let mut s_protocol = ShellProtocol::new(channel_protocol, channel_id, Box::new(proc_handle));
+    if let Some(recorder) = recorder {
+        info!(
+            "Recording session {} to {}",
+            channel_id,
+            recorder.path().display()
+        );
+        s_protocol = s_protocol.with_recorder(recorder);
+    }
 
     // Receive and react to incoming shell protocol messages
     if let Err(e) = s_protocol.message_engine(
@@ -189,6 +240,10 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
         .and_then(|val| val.as_integer().map(|num| Duration::from_secs(num as u64)))
         .unwrap_or(Duration::from_millis(2));
 
+    let recording_dir = config
+        .get("recording_dir")
+        .and_then(|val| val.as_str().map(|dir| dir.to_owned()));
+
     // Setup map of channel IDs to thread channels
     let raw_threads: HashMap<u32, ThreadProcess> = HashMap::new();
     // Create thread sharable wrapper
@@ -220,6 +275,20 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                 )?;
                 continue;
             }
+            // Send back list of recorded sessions
+            ShellMessage::Recordings {
+                channel_id,
+                recordings: None,
+            } => {
+                info!("<- {{ {}, recordings }}", channel_id);
+                list_session_recordings(
+                    channel_id,
+                    &host_addr,
+                    &format!("{}", message_source),
+                    &recording_dir,
+                )?;
+                continue;
+            }
             // Spawn up a new process & thread
             ShellMessage::Spawn {
                 channel_id,
@@ -243,6 +312,7 @@ pub fn recv_loop(config: &ServiceConfig) -> Result<(), failure::Error> {
                         &host_addr,
                         &remote_addr,
                         timeout,
+                        &recording_dir,
                         threads.clone(),
                     ) {
                         threads
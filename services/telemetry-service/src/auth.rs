@@ -0,0 +1,129 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Insert authentication
+//
+// Any process which can reach the direct UDP port can otherwise insert telemetry for any
+// subsystem. When tokens are configured, each datagram must end with an HMAC-SHA256 of the rest
+// of the datagram, keyed with one of the tokens' shared secrets. The token whose secret produced
+// the tag decides which subsystems the datagram may insert telemetry for, so a misbehaving
+// payload process can only affect its own parameters.
+//
+// Payload teams sharing a bus can each be given a namespace: the subsystem prefixes their token
+// covers (eg. `cam_` for `cam_thermal` and `cam_optics`). The same tokens then gate the mutations
+// which change telemetry, whose `token` argument holds the token's secret. A namespaced token
+// can only import telemetry and register catalog entries within its namespace, and can't delete
+// database files at all, since each file holds every subsystem's telemetry. Queries stay global.
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+// Length of the HMAC-SHA256 tag at the end of each datagram
+pub const TAG_LEN: usize = 32;
+
+// A token, as given in the `insert_tokens` config array
+#[derive(Clone, Debug, Deserialize)]
+pub struct InsertToken {
+    // Name used when logging rejected telemetry
+    pub name: String,
+    // Shared secret the HMAC is keyed with
+    pub secret: String,
+    // Subsystems the token may insert telemetry for
+    pub subsystems: Option<Vec<String>>,
+    // Prefixes of the subsystems the token may insert telemetry for
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl InsertToken {
+    // Whether the token may change the subsystem's telemetry. Tokens without `subsystems` or
+    // `namespaces` may change any subsystem's
+    pub fn allows(&self, subsystem: &str) -> bool {
+        if !self.is_restricted() {
+            return true;
+        }
+
+        let listed = self.subsystems.iter().flatten().any(|s| s == subsystem);
+        let namespaced = self
+            .namespaces
+            .iter()
+            .flatten()
+            .any(|prefix| subsystem.starts_with(prefix.as_str()));
+        listed || namespaced
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.subsystems.is_some() || self.namespaces.is_some()
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length
+        Hmac::<Sha256>::new_varkey(self.secret.as_bytes()).unwrap()
+    }
+}
+
+pub struct InsertTokens(Vec<InsertToken>);
+
+impl InsertTokens {
+    pub fn new(tokens: Vec<InsertToken>) -> Result<Self, String> {
+        if let Some(token) = tokens.iter().find(|token| token.secret.is_empty()) {
+            return Err(format!("Token '{}' has an empty secret", token.name));
+        }
+        // An empty prefix would cover every subsystem, which is what leaving it out is for
+        if let Some(token) = tokens.iter().find(|token| {
+            token
+                .namespaces
+                .iter()
+                .flatten()
+                .any(|prefix| prefix.is_empty())
+        }) {
+            return Err(format!("Token '{}' has an empty namespace", token.name));
+        }
+        Ok(InsertTokens(tokens))
+    }
+
+    // Find the token with the given secret, as passed to the mutations which change telemetry.
+    // Secrets are compared in constant time, so they can't be guessed a byte at a time
+    pub fn find(&self, secret: &str) -> Option<&InsertToken> {
+        self.0.iter().find(|token| {
+            let expected = token.secret.as_bytes();
+            let given = secret.as_bytes();
+            expected.len() == given.len()
+                && expected
+                    .iter()
+                    .zip(given)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+
+    // Find the token which signed a datagram, returning it along with the datagram's payload
+    pub fn authenticate<'a>(&'a self, datagram: &'a [u8]) -> Option<(&'a InsertToken, &'a [u8])> {
+        if datagram.len() < TAG_LEN {
+            return None;
+        }
+        let (payload, tag) = datagram.split_at(datagram.len() - TAG_LEN);
+
+        self.0
+            .iter()
+            .find(|token| {
+                let mut mac = token.mac();
+                mac.input(payload);
+                mac.verify(tag).is_ok()
+            })
+            .map(|token| (token, payload))
+    }
+}
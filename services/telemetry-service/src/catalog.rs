@@ -0,0 +1,286 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Parameter metadata catalog
+//
+// The database only holds timestamped values, so ground displays would otherwise need a
+// separate dictionary to know how to present them. The catalog records the units, description,
+// data type and expected limits of each subsystem/parameter pair. It lives in a JSON file next
+// to the database, so it travels with the telemetry it describes, and is rewritten whenever an
+// entry is registered or removed.
+//
+// Entries also set how a parameter's values are stored. High-rate parameters which don't need
+// full precision can be stored as 32-bit floats, or as 32-bit integers with a scale and offset,
+// taking half the space of the 64-bit floats values are otherwise stored as. Readers use the
+// entry to convert the stored values back (`value = stored * scale + offset`).
+
+use crate::udp::{numeric_value, DataPoint};
+
+use juniper::{GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::i32;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// File in the database directory which the catalog is saved to
+pub const CATALOG_FILE: &str = ".parameters.json";
+
+/// Type of the values recorded for a parameter
+#[derive(Clone, Copy, Debug, Deserialize, Eq, GraphQLEnum, PartialEq, Serialize)]
+pub enum DataType {
+    /// Whole numbers
+    Integer,
+    /// Real numbers
+    Float,
+    /// 0 (false) or 1 (true)
+    Boolean,
+    /// Numeric codes which map to named states
+    Enumeration,
+}
+
+/// How a parameter's values are stored in the database
+#[derive(Clone, Copy, Debug, Deserialize, Eq, GraphQLEnum, PartialEq, Serialize)]
+pub enum Encoding {
+    /// 64-bit floats, as received
+    Float64,
+    /// 32-bit floats (about 7 significant digits)
+    Float32,
+    /// 32-bit integers holding `(value - offset) / scale`, rounded to the nearest integer
+    ScaledInteger,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Float64
+    }
+}
+
+/// Metadata describing a telemetry parameter
+#[derive(Clone, Debug, Deserialize, GraphQLObject, PartialEq, Serialize)]
+pub struct ParameterInfo {
+    pub subsystem: String,
+    pub parameter: String,
+    /// Units the values are recorded in (eg. "V", "degC")
+    pub units: Option<String>,
+    pub description: Option<String>,
+    pub data_type: DataType,
+    /// Lowest expected value
+    pub min: Option<f64>,
+    /// Highest expected value
+    pub max: Option<f64>,
+    /// How the values are stored
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Size of one step of a SCALED_INTEGER value
+    pub scale: Option<f64>,
+    /// Value stored as 0 for a SCALED_INTEGER parameter
+    pub offset: Option<f64>,
+}
+
+/// Metadata to register for a telemetry parameter
+#[derive(Clone, Debug, GraphQLInputObject)]
+pub struct ParameterInfoInput {
+    pub subsystem: String,
+    pub parameter: String,
+    pub units: Option<String>,
+    pub description: Option<String>,
+    /// Defaults to FLOAT
+    pub data_type: Option<DataType>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Defaults to FLOAT64
+    pub encoding: Option<Encoding>,
+    /// Required for SCALED_INTEGER
+    pub scale: Option<f64>,
+    /// Defaults to 0 for SCALED_INTEGER
+    pub offset: Option<f64>,
+}
+
+impl From<ParameterInfoInput> for ParameterInfo {
+    fn from(input: ParameterInfoInput) -> Self {
+        ParameterInfo {
+            subsystem: input.subsystem,
+            parameter: input.parameter,
+            units: input.units,
+            description: input.description,
+            data_type: input.data_type.unwrap_or(DataType::Float),
+            min: input.min,
+            max: input.max,
+            encoding: input.encoding.unwrap_or_default(),
+            scale: input.scale,
+            offset: input.offset,
+        }
+    }
+}
+
+impl ParameterInfo {
+    // Checks that the encoding has the settings it needs
+    fn check_encoding(&self) -> Result<(), String> {
+        match (self.encoding, self.scale) {
+            (Encoding::ScaledInteger, Some(scale)) if scale.is_finite() && scale != 0.0 => {
+                if self.offset.map_or(true, f64::is_finite) {
+                    Ok(())
+                } else {
+                    Err("offset must be finite".to_owned())
+                }
+            }
+            (Encoding::ScaledInteger, _) => Err("SCALED_INTEGER needs a non-zero scale".to_owned()),
+            _ if self.scale.is_some() || self.offset.is_some() => {
+                Err("scale and offset only apply to SCALED_INTEGER".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Converts a data point's value to the parameter's encoding
+    fn encode(&self, dp: DataPoint) -> DataPoint {
+        if self.encoding == Encoding::Float64 {
+            return dp;
+        }
+        let value = match numeric_value(&dp.3) {
+            Some(value) => value,
+            None => return dp,
+        };
+
+        let value = match self.encoding {
+            Encoding::Float32 => (value as f32).into(),
+            // There's no integer for these, so they're kept as they were received
+            _ if !value.is_finite() => return dp,
+            _ => {
+                let scaled = (value - self.offset.unwrap_or(0.0)) / self.scale.unwrap_or(1.0);
+                // Clamped, so that an out of range value is stored as the nearest one which fits
+                (scaled.round().max(i32::MIN.into()).min(i32::MAX.into()) as i32).into()
+            }
+        };
+        let DataPoint(timestamp, subsystem, parameter, _) = dp;
+        DataPoint(timestamp, subsystem, parameter, value)
+    }
+}
+
+type Entries = BTreeMap<(String, String), ParameterInfo>;
+
+/// Catalog of parameter metadata, saved to a file
+pub struct ParameterCatalog {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+}
+
+impl ParameterCatalog {
+    /// Opens the catalog saved in the given database directory, if there is one
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(CATALOG_FILE);
+        let list: Vec<ParameterInfo> = match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("Failed to parse parameter catalog: {}", err))?,
+            Err(_) => vec![],
+        };
+
+        let entries = list
+            .into_iter()
+            .map(|info| ((info.subsystem.clone(), info.parameter.clone()), info))
+            .collect();
+
+        Ok(ParameterCatalog {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Returns the catalog entries, optionally only those of one subsystem,
+    /// sorted by subsystem and parameter
+    pub fn list(&self, subsystem: Option<&str>) -> Result<Vec<ParameterInfo>, String> {
+        Ok(self
+            .lock()?
+            .values()
+            .filter(|info| subsystem.map_or(true, |name| info.subsystem == name))
+            .cloned()
+            .collect())
+    }
+
+    /// Adds or replaces entries. Either all of the entries are registered, or none are.
+    pub fn register(&self, infos: Vec<ParameterInfo>) -> Result<(), String> {
+        for info in &infos {
+            if info.subsystem.is_empty() || info.parameter.is_empty() {
+                return Err("Entries need a subsystem and parameter".to_owned());
+            }
+            if let (Some(min), Some(max)) = (info.min, info.max) {
+                if min > max {
+                    return Err(format!(
+                        "{}/{}: min must not be greater than max",
+                        info.subsystem, info.parameter
+                    ));
+                }
+            }
+            info.check_encoding()
+                .map_err(|err| format!("{}/{}: {}", info.subsystem, info.parameter, err))?;
+        }
+
+        let mut entries = self.lock()?;
+        let mut updated = entries.clone();
+        for info in infos {
+            updated.insert((info.subsystem.clone(), info.parameter.clone()), info);
+        }
+        self.save(&updated)?;
+        *entries = updated;
+        Ok(())
+    }
+
+    /// Removes an entry
+    pub fn remove(&self, subsystem: &str, parameter: &str) -> Result<(), String> {
+        let mut entries = self.lock()?;
+        let mut updated = entries.clone();
+        updated
+            .remove(&(subsystem.to_owned(), parameter.to_owned()))
+            .ok_or_else(|| format!("Parameter {}/{} not found", subsystem, parameter))?;
+        self.save(&updated)?;
+        *entries = updated;
+        Ok(())
+    }
+
+    /// Converts data points to the encodings of their parameters, ready to be stored.
+    /// Points for parameters without an entry, or whose values aren't numeric, are unchanged.
+    pub fn encode(&self, dps: Vec<DataPoint>) -> Vec<DataPoint> {
+        let entries = match self.lock() {
+            Ok(entries) => entries,
+            Err(_) => return dps,
+        };
+
+        dps.into_iter()
+            .map(|dp| match entries.get(&(dp.1.clone(), dp.2.clone())) {
+                Some(info) => info.encode(dp),
+                None => dp,
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Entries>, String> {
+        self.entries
+            .lock()
+            .map_err(|_| "Catalog mutex poisoned".to_owned())
+    }
+
+    // Write to a temporary file first, so a reset mid-write can't lose the whole catalog
+    fn save(&self, entries: &Entries) -> Result<(), String> {
+        let list: Vec<&ParameterInfo> = entries.values().collect();
+        let raw = serde_json::to_vec_pretty(&list).map_err(|err| err.to_string())?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, raw)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|err| format!("Failed to save parameter catalog: {}", err))
+    }
+}
@@ -0,0 +1,358 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Time range deletion of telemetry
+//
+// flat_db is append-only, so individual entries can't be removed. Instead, each database
+// file is treated as covering the time from its creation (encoded in its name by
+// `unique_db_name`) until the creation of the next file. A file is only deleted if that whole
+// span falls within the requested range. The newest file has no end, so the database
+// currently being written to is never touched.
+
+use crate::encryption::ENCRYPTED_EXTENSION;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A database file and the span of time it covers
+struct DbFile {
+    path: PathBuf,
+    start: f64,
+    count: usize,
+}
+
+// Parse the creation time out of a file name generated by `unique_db_name`
+// eg. "20200101123000.db" or "20200101123000_01.db", or either of them with the
+// ".enc" extension of an encrypted database file
+fn parse_db_name(path: &Path) -> Option<(f64, usize)> {
+    let path = if path.extension()? == ENCRYPTED_EXTENSION {
+        Path::new(path.file_stem()?)
+    } else {
+        path
+    };
+    if path.extension()? != "db" {
+        return None;
+    }
+
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.splitn(2, '_');
+    let time = parts.next()?;
+    let count = match parts.next() {
+        Some(count) => count.parse().ok()?,
+        None => 0,
+    };
+
+    let time = NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%S").ok()?;
+    let time = Utc.from_utc_datetime(&time);
+
+    Some((time.timestamp() as f64, count))
+}
+
+// List the database files in a directory, oldest first
+fn db_files(dir: &Path) -> Result<Vec<DbFile>, String> {
+    let mut files: Vec<DbFile> = fs::read_dir(dir)
+        .map_err(|e| format!("Could not read DB directory: {}", e))?
+        .filter_map(|dirent| dirent.ok())
+        .map(|dirent| dirent.path())
+        .filter(|path| path.is_file())
+        .filter_map(|path| parse_db_name(&path).map(|(start, count)| DbFile { path, start, count }))
+        .collect();
+
+    files.sort_by(|a, b| {
+        a.start
+            .partial_cmp(&b.start)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.count.cmp(&b.count))
+    });
+
+    Ok(files)
+}
+
+// A closed database file and the span of time it covers, from its creation (inclusive) until the
+// creation of the next file (exclusive)
+pub struct DbSpan {
+    pub path: PathBuf,
+    pub start: f64,
+    pub end: f64,
+}
+
+// Find all database files in the same directory as the current database which fall entirely
+// within the given time range
+pub fn spans_in_range(
+    db_path: &Path,
+    timestamp_ge: f64,
+    timestamp_le: f64,
+) -> Result<Vec<DbSpan>, String> {
+    let dir = db_path
+        .parent()
+        .ok_or_else(|| "path does not have a parent".to_owned())?;
+
+    Ok(db_files(dir)?
+        .windows(2)
+        .filter(|pair| pair[0].start >= timestamp_ge && pair[1].start <= timestamp_le)
+        .filter(|pair| pair[0].path != db_path)
+        .map(|pair| DbSpan {
+            path: pair[0].path.to_owned(),
+            start: pair[0].start,
+            end: pair[1].start,
+        })
+        .collect())
+}
+
+// Like `spans_in_range`, but only the files' paths
+pub fn files_in_range(
+    db_path: &Path,
+    timestamp_ge: f64,
+    timestamp_le: f64,
+) -> Result<Vec<PathBuf>, String> {
+    Ok(spans_in_range(db_path, timestamp_ge, timestamp_le)?
+        .into_iter()
+        .map(|span| span.path)
+        .collect())
+}
+
+// Find all database files in a directory which hold any of the given time range,
+// including the newest file
+pub fn files_overlapping(
+    dir: &Path,
+    timestamp_ge: f64,
+    timestamp_le: f64,
+) -> Result<Vec<PathBuf>, String> {
+    let files = db_files(dir)?;
+
+    Ok(files
+        .iter()
+        .enumerate()
+        .filter(|(index, file)| {
+            let end = files
+                .get(index + 1)
+                .map_or(std::f64::INFINITY, |next| next.start);
+            file.start <= timestamp_le && end > timestamp_ge
+        })
+        .map(|(_, file)| file.path.to_owned())
+        .collect())
+}
+
+// Find all database files in a directory which were created from `start` (inclusive, or from
+// the beginning if not given) up to `end` (exclusive). Only the names of `start` and `end` are
+// used, so they needn't still exist, and needn't match the files' encryption
+pub fn files_between(dir: &Path, start: Option<&Path>, end: &Path) -> Result<Vec<PathBuf>, String> {
+    let key = |path: &Path| {
+        parse_db_name(path).ok_or_else(|| format!("Invalid DB file name: {}", path.display()))
+    };
+    let end = key(end)?;
+    let start = start.map(key).transpose()?;
+
+    let before = |file: &DbFile, (start, count): (f64, usize)| {
+        file.start < start || (file.start == start && file.count < count)
+    };
+
+    Ok(db_files(dir)?
+        .iter()
+        .filter(|file| before(file, end))
+        .filter(|file| start.map_or(true, |start| !before(file, start)))
+        .map(|file| file.path.to_owned())
+        .collect())
+}
+
+// The files removed by `remove_files` (or which would have been, in a dry run)
+pub struct Removed {
+    pub files: Vec<String>,
+    // Total size of the files, in bytes
+    pub bytes: u64,
+    pub errors: Vec<String>,
+}
+
+// Delete the given files, unless this is a dry run
+pub fn remove_files(files: &[PathBuf], dry_run: bool) -> Removed {
+    let mut removed = Removed {
+        files: vec![],
+        bytes: 0,
+        errors: vec![],
+    };
+
+    for path in files {
+        let size = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        if !dry_run {
+            if let Err(err) = fs::remove_file(path) {
+                removed.errors.push(format!("{}: {}", path.display(), err));
+                continue;
+            }
+        }
+        if let Some(path) = path.to_str() {
+            removed.files.push(path.to_owned());
+            removed.bytes += size;
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-01-01 00:00:00 UTC
+    const T0: f64 = 1_577_836_800.0;
+
+    // Creates an empty directory holding the given (empty) files
+    fn db_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("telemetry-delete-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        dir
+    }
+
+    fn names(paths: Vec<PathBuf>) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parse_db_name_plain() {
+        assert_eq!(
+            parse_db_name(Path::new("/sdcard/telemetry/20200101123000.db")),
+            Some((T0 + 45000.0, 0))
+        );
+    }
+
+    #[test]
+    fn parse_db_name_count() {
+        assert_eq!(
+            parse_db_name(Path::new("20200101123000_01.db")),
+            Some((T0 + 45000.0, 1))
+        );
+    }
+
+    #[test]
+    fn parse_db_name_encrypted() {
+        assert_eq!(
+            parse_db_name(Path::new("20200101123000.db.enc")),
+            Some((T0 + 45000.0, 0))
+        );
+        assert_eq!(
+            parse_db_name(Path::new("20200101123000_02.db.enc")),
+            Some((T0 + 45000.0, 2))
+        );
+    }
+
+    #[test]
+    fn parse_db_name_invalid() {
+        assert_eq!(parse_db_name(Path::new("20200101123000")), None);
+        assert_eq!(parse_db_name(Path::new("20200101123000.txt")), None);
+        assert_eq!(parse_db_name(Path::new("20200101123000.txt.enc")), None);
+        assert_eq!(parse_db_name(Path::new("20200101.db")), None);
+        assert_eq!(parse_db_name(Path::new("telemetry.db")), None);
+        assert_eq!(parse_db_name(Path::new("20200101123000_x.db")), None);
+        assert_eq!(parse_db_name(Path::new(".parameters.json")), None);
+    }
+
+    const FILES: &[&str] = &[
+        "20200101000000.db",
+        "20200101010000.db",
+        "20200101010000_01.db",
+        "20200101020000.db.enc",
+        "20200101030000.db",
+        ".parameters.json",
+        "notes.txt",
+    ];
+
+    #[test]
+    fn files_in_range_boundaries() {
+        let dir = db_dir("boundaries", FILES);
+        let db_path = dir.join("20200101030000.db");
+
+        // Each file's span ends where the next file's starts, so the range has to reach the
+        // next file for the first one to be included
+        assert_eq!(
+            names(files_in_range(&db_path, T0, T0 + 3599.0).unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            names(files_in_range(&db_path, T0, T0 + 3600.0).unwrap()),
+            vec!["20200101000000.db", "20200101010000.db"]
+        );
+
+        // Files created in the same second are ordered by their counts
+        assert_eq!(
+            names(files_in_range(&db_path, T0 + 1.0, T0 + 7200.0).unwrap()),
+            vec!["20200101010000.db", "20200101010000_01.db"]
+        );
+
+        // Encrypted files are included like plaintext ones
+        assert_eq!(
+            names(files_in_range(&db_path, T0 + 7200.0, T0 + 10800.0).unwrap()),
+            vec!["20200101020000.db.enc"]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_in_range_skips_current() {
+        let dir = db_dir("current", FILES);
+
+        // The newest file has no end, so is never deleted
+        let db_path = dir.join("20200101030000.db");
+        assert_eq!(
+            names(files_in_range(&db_path, std::f64::NEG_INFINITY, std::f64::INFINITY).unwrap()),
+            vec![
+                "20200101000000.db",
+                "20200101010000.db",
+                "20200101010000_01.db",
+                "20200101020000.db.enc"
+            ]
+        );
+
+        // Nor is the database being written to, even if it isn't the newest
+        let db_path = dir.join("20200101000000.db");
+        assert_eq!(
+            names(files_in_range(&db_path, T0, T0 + 3600.0).unwrap()),
+            vec!["20200101010000.db"]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spans_in_range_ends() {
+        let dir = db_dir("spans", FILES);
+        let db_path = dir.join("20200101030000.db");
+
+        let spans: Vec<(f64, f64)> = spans_in_range(&db_path, T0, T0 + 10800.0)
+            .unwrap()
+            .iter()
+            .map(|span| (span.start, span.end))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (T0, T0 + 3600.0),
+                (T0 + 3600.0, T0 + 3600.0),
+                (T0 + 3600.0, T0 + 7200.0),
+                (T0 + 7200.0, T0 + 10800.0)
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
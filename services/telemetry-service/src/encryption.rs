@@ -0,0 +1,415 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Encryption of database files at rest
+//
+// flat_db can only write plaintext files, so the service encrypts each database file once the
+// database is done with it: after every rotation, and at startup for the files left by the
+// previous run (including its rollups). The encrypted file is written beside the original as
+// `{name}.db.enc`, and the plaintext file is then removed. Only the file currently being written
+// is ever stored in plaintext, so missions which need to limit how much telemetry that is should
+// rotate the database regularly.
+//
+// Files are encrypted with AES-256-GCM, in `CHUNK_SIZE` chunks so that large files never have
+// to be held in memory. An encrypted file is `MAGIC` and a random 7-byte nonce prefix, followed
+// by each chunk's ciphertext and 16-byte tag. A chunk's nonce is the prefix, the chunk's index
+// as a big-endian `u32` and a byte which is `1` for the last chunk, so chunks can't be reordered
+// and a truncated file fails to decrypt.
+//
+// The 32-byte key is given hex-encoded, either in a key file which only its owner may read, or
+// on the standard output of a command (eg. a wrapper around a hardware keystore).
+//
+// Ground tools don't need to know about any of this: `queryPlan` decrypts the encrypted files
+// it lists into `decrypt_dir`, which should be RAM-backed, and returns the decrypted copies.
+// Copies which are older than `DECRYPTED_LIFETIME` are removed the next time a plan is made.
+
+use aes_gcm::aead::consts::U12;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Extension added to the name of an encrypted database file
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+// Start of every encrypted database file
+const MAGIC: &[u8; 8] = b"KTDBENC1";
+const KEY_SIZE: usize = 32;
+const NONCE_PREFIX_SIZE: usize = 7;
+const TAG_SIZE: usize = 16;
+// Plaintext bytes in each encrypted chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+// How long a decrypted copy is kept once it's been made
+const DECRYPTED_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+fn default_decrypt_dir() -> String {
+    "/tmp/telemetry-db".to_owned()
+}
+
+// How database files are encrypted, as given in the `[telemetry-service.encryption]` config table
+#[derive(Clone, Deserialize)]
+pub struct EncryptionConfig {
+    // File holding the hex-encoded key. It must not be readable by anyone but its owner
+    pub key_file: Option<String>,
+    // Command (and its arguments) which prints the hex-encoded key
+    pub key_command: Option<Vec<String>>,
+    // Where `queryPlan` puts decrypted copies of encrypted files
+    #[serde(default = "default_decrypt_dir")]
+    pub decrypt_dir: String,
+}
+
+pub struct DbEncryption {
+    cipher: Aes256Gcm,
+    decrypt_dir: PathBuf,
+    // Rotations may finish while an earlier one's files are still being encrypted
+    lock: Mutex<()>,
+}
+
+impl fmt::Debug for DbEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the key out of the logs
+        write!(f, "DbEncryption {{ decrypt_dir: {:?} }}", self.decrypt_dir)
+    }
+}
+
+impl DbEncryption {
+    pub fn new(config: EncryptionConfig) -> Result<Self, String> {
+        let key = match (&config.key_file, &config.key_command) {
+            (Some(path), None) => read_key_file(Path::new(path))?,
+            (None, Some(command)) => run_key_command(command)?,
+            _ => return Err("Exactly one of key_file and key_command must be given".to_owned()),
+        };
+        let key =
+            parse_key(&key).ok_or_else(|| format!("Key must be {} hex-encoded bytes", KEY_SIZE))?;
+
+        Ok(DbEncryption {
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&key)),
+            decrypt_dir: PathBuf::from(config.decrypt_dir),
+            lock: Mutex::new(()),
+        })
+    }
+
+    // Encrypts every plaintext database file under the directory and its subdirectories.
+    // Only for use at startup, before any database has been opened
+    pub fn encrypt_tree(&self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Failed to read {}: {}", dir.display(), err);
+                return;
+            }
+        };
+
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.is_dir() {
+                self.encrypt_tree(&path);
+            } else if is_plaintext_db(&path) {
+                self.encrypt_logged(&path);
+            }
+        }
+    }
+
+    // Encrypts the plaintext database files in the directory, apart from the one currently
+    // being written
+    pub fn encrypt_closed(&self, dir: &Path, current: &Path) {
+        let _lock = self
+            .lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path != current && is_plaintext_db(path))
+                .collect::<Vec<PathBuf>>(),
+            Err(err) => {
+                warn!("Failed to read {}: {}", dir.display(), err);
+                return;
+            }
+        };
+
+        for path in paths {
+            self.encrypt_logged(&path);
+        }
+    }
+
+    // Where decrypted copies of database files are made
+    pub fn decrypt_dir(&self) -> &Path {
+        &self.decrypt_dir
+    }
+
+    // Swaps each encrypted file in a query plan for a decrypted copy in `decrypt_dir`.
+    // `root` is the database's directory, whose layout is kept in `decrypt_dir`
+    pub fn decrypt_for_query(
+        &self,
+        root: &Path,
+        files: Vec<String>,
+    ) -> Result<Vec<String>, String> {
+        self.remove_stale_copies(&self.decrypt_dir);
+
+        files
+            .into_iter()
+            .map(|file| {
+                let path = PathBuf::from(&file);
+                if path
+                    .extension()
+                    .map_or(true, |ext| ext != ENCRYPTED_EXTENSION)
+                {
+                    return Ok(file);
+                }
+
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                let dest = self.decrypt_dir.join(relative).with_extension("");
+                if !dest.exists() {
+                    self.decrypt_file(&path, &dest)
+                        .map_err(|err| format!("Failed to decrypt {}: {}", file, err))?;
+                }
+
+                dest.to_str()
+                    .map(|dest| dest.to_owned())
+                    .ok_or_else(|| format!("Invalid decrypted file path for {}", file))
+            })
+            .collect()
+    }
+
+    fn encrypt_logged(&self, path: &Path) {
+        match self.encrypt_file(path) {
+            Ok(dest) => info!("Encrypted {} to {}", path.display(), dest.display()),
+            Err(err) => error!("Failed to encrypt {}: {}", path.display(), err),
+        }
+    }
+
+    // Encrypts a database file, removing the plaintext once the encrypted file is in place
+    fn encrypt_file(&self, path: &Path) -> io::Result<PathBuf> {
+        let dest = encrypted_path(path);
+        if dest.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dest.display()),
+            ));
+        }
+
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        File::open("/dev/urandom")?.read_exact(&mut prefix)?;
+
+        let partial = dest.with_extension("enc.partial");
+        let result = (|| {
+            let mut source = File::open(path)?;
+            let mut output = File::create(&partial)?;
+            output.write_all(MAGIC)?;
+            output.write_all(&prefix)?;
+
+            let mut chunk = read_chunk(&mut source, CHUNK_SIZE)?;
+            let mut index = 0;
+            loop {
+                let next = read_chunk(&mut source, CHUNK_SIZE)?;
+                let last = next.is_empty();
+                let sealed = self
+                    .cipher
+                    .encrypt(&nonce(&prefix, index, last), chunk.as_slice())
+                    .map_err(|_| invalid("Encryption failed"))?;
+                output.write_all(&sealed)?;
+                if last {
+                    break;
+                }
+                chunk = next;
+                index += 1;
+            }
+
+            output.sync_all()?;
+            fs::rename(&partial, &dest)
+        })();
+
+        if let Err(err) = result {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+
+        fs::remove_file(path)?;
+        Ok(dest)
+    }
+
+    fn decrypt_file(&self, path: &Path, dest: &Path) -> io::Result<()> {
+        let mut source = File::open(path)?;
+        let mut header = [0; 8 + NONCE_PREFIX_SIZE];
+        source.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(invalid("Not an encrypted database file"));
+        }
+        let prefix = &header[8..];
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = dest.with_extension("partial");
+        let result = (|| {
+            let mut output = File::create(&partial)?;
+            let mut chunk = read_chunk(&mut source, CHUNK_SIZE + TAG_SIZE)?;
+            let mut index = 0;
+            loop {
+                let next = read_chunk(&mut source, CHUNK_SIZE + TAG_SIZE)?;
+                let last = next.is_empty();
+                let opened = self
+                    .cipher
+                    .decrypt(&nonce(prefix, index, last), chunk.as_slice())
+                    .map_err(|_| invalid("File is corrupt, truncated or used another key"))?;
+                output.write_all(&opened)?;
+                if last {
+                    break;
+                }
+                chunk = next;
+                index += 1;
+            }
+            fs::rename(&partial, dest)
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+
+    fn remove_stale_copies(&self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.is_dir() {
+                self.remove_stale_copies(&path);
+                continue;
+            }
+
+            let stale = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .map_or(false, |age| age > DECRYPTED_LIFETIME);
+            if stale {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+// Encrypts the files the database was done with after a rotation, without holding up the caller
+pub fn encrypt_rotated(encryption: &Arc<DbEncryption>, current: PathBuf) {
+    let dir = match current.parent() {
+        Some(dir) => dir.to_owned(),
+        None => return,
+    };
+    let encryption = encryption.clone();
+    let spawned = thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || encryption.encrypt_closed(&dir, &current));
+    if let Err(err) = spawned {
+        error!("Failed to start database encryption: {}", err);
+    }
+}
+
+// Path of the encrypted copy of a database file
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ENCRYPTED_EXTENSION);
+    PathBuf::from(name)
+}
+
+fn is_plaintext_db(path: &Path) -> bool {
+    path.is_file() && path.extension().map_or(false, |ext| ext == "db")
+}
+
+fn nonce(prefix: &[u8], index: u32, last: bool) -> GenericArray<u8, U12> {
+    let mut nonce = [0; 12];
+    nonce[0..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    *GenericArray::from_slice(&nonce)
+}
+
+// Reads up to `size` bytes, stopping early only at the end of the file
+fn read_chunk(source: &mut File, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    source.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn read_key_file(path: &Path) -> Result<String, String> {
+    let mode = fs::metadata(path)
+        .map_err(|err| format!("Failed to read key file {}: {}", path.display(), err))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "Key file {} must only be accessible by its owner",
+            path.display()
+        ));
+    }
+
+    fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read key file {}: {}", path.display(), err))
+}
+
+fn run_key_command(command: &[String]) -> Result<String, String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| "key_command must not be empty".to_owned())?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| format!("Failed to run key command {}: {}", program, err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Key command {} failed with {}",
+            program, output.status
+        ));
+    }
+
+    String::from_utf8(output.stdout).map_err(|_| "Key command output isn't text".to_owned())
+}
+
+fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    let hex = hex.trim();
+    if hex.len() != KEY_SIZE * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut key = [0; KEY_SIZE];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,383 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Compressed exports of database files
+//
+// `queryPlan` and `snapshotExport` list the database files holding the telemetry the ground
+// wants, which can run to hundreds of megabytes. The `exportFiles` mutation packs them into a
+// single tar archive compressed with gzip or zstd. Each file is streamed through the compressor
+// a buffer at a time, straight into the output file, so memory use is bounded by the
+// compressor's window no matter how large the export is.
+//
+// Downlink time has to be budgeted before the archive is requested, so every export returns an
+// estimate of its size, and a dry run returns only the estimate. The first `SAMPLE_SIZE` bytes of
+// each file are compressed, and the ratio achieved is applied to the size of the whole archive,
+// so estimating costs a bounded amount of work per file rather than a full compression.
+
+use flate2::write::GzEncoder;
+use juniper::GraphQLEnum;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Bytes at the start of each file compressed to estimate an export's size
+const SAMPLE_SIZE: u64 = 64 * 1024;
+// Size of a tar header, which each file's contents are also padded to a multiple of
+const TAR_BLOCK: u64 = 512;
+// Higher zstd levels are too slow on the OBC for exports of this size
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression applied to an exported archive
+#[derive(Clone, Copy, Debug, Eq, GraphQLEnum, PartialEq)]
+pub enum ExportFormat {
+    /// A gzip-compressed tar archive (`.tar.gz`)
+    Gzip,
+    /// A zstd-compressed tar archive (`.tar.zst`)
+    Zstd,
+}
+
+// Sizes of an export, in bytes
+#[derive(Debug, PartialEq)]
+pub struct Exported {
+    // Total size of the exported files
+    pub raw_bytes: u64,
+    // Estimated size of the archive
+    pub estimated_bytes: u64,
+    // Size of the archive written, or zero for a dry run
+    pub bytes: u64,
+}
+
+// A file to export, and its name in the archive
+struct ExportFile {
+    path: PathBuf,
+    name: PathBuf,
+    size: u64,
+}
+
+// Compressor for an export, writing to `W`
+enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(format: ExportFormat, writer: W) -> io::Result<Self> {
+        Ok(match format {
+            ExportFormat::Gzip => {
+                Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            ExportFormat::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(writer, ZSTD_LEVEL)?)
+            }
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Discards everything written to it, counting the bytes
+#[derive(Default)]
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Packs the files into a compressed tar archive at `output`, or only estimates the archive's
+// size for a dry run. Relative paths are taken from the first of `roots`, and every file must be
+// inside one of them. Each file is named in the archive by its path within its root
+pub fn export(
+    roots: &[&Path],
+    files: &[String],
+    output: &Path,
+    format: ExportFormat,
+    dry_run: bool,
+) -> Result<Exported, String> {
+    let files = files
+        .iter()
+        .map(|file| resolve(roots, file))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let estimated_bytes =
+        estimate(&files, format).map_err(|err| format!("Failed to estimate export: {}", err))?;
+
+    let bytes = if dry_run {
+        0
+    } else {
+        write_archive(&files, output, format).map_err(|err| {
+            let _ = fs::remove_file(output);
+            format!("Failed to write {}: {}", output.display(), err)
+        })?
+    };
+
+    Ok(Exported {
+        raw_bytes: files.iter().map(|file| file.size).sum(),
+        estimated_bytes,
+        bytes,
+    })
+}
+
+fn resolve(roots: &[&Path], file: &str) -> Result<ExportFile, String> {
+    let path = roots
+        .first()
+        .map_or_else(|| PathBuf::from(file), |root| root.join(file))
+        .canonicalize()
+        .map_err(|err| format!("{}: {}", file, err))?;
+
+    let name = roots
+        .iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .find_map(|root| path.strip_prefix(root).ok().map(|name| name.to_owned()))
+        .ok_or_else(|| format!("{} is not in the database directory", file))?;
+
+    let metadata = fs::metadata(&path).map_err(|err| format!("{}: {}", file, err))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", file));
+    }
+
+    Ok(ExportFile {
+        path,
+        name,
+        size: metadata.len(),
+    })
+}
+
+// Size of the uncompressed tar archive holding the files
+fn archive_size(files: &[ExportFile]) -> u64 {
+    let contents: u64 = files
+        .iter()
+        .map(|file| TAR_BLOCK + file.size + (TAR_BLOCK - file.size % TAR_BLOCK) % TAR_BLOCK)
+        .sum();
+
+    // The archive ends with two empty blocks
+    contents + 2 * TAR_BLOCK
+}
+
+fn estimate(files: &[ExportFile], format: ExportFormat) -> io::Result<u64> {
+    let mut encoder = Encoder::new(format, Counter::default())?;
+    let mut sampled = 0;
+    for file in files {
+        sampled += io::copy(&mut File::open(&file.path)?.take(SAMPLE_SIZE), &mut encoder)?;
+    }
+    let compressed = encoder.finish()?.0;
+
+    let size = archive_size(files);
+    if sampled == 0 {
+        return Ok(size);
+    }
+    Ok((size as f64 * compressed as f64 / sampled as f64).ceil() as u64)
+}
+
+// Streams the files into the archive, returning the archive's size
+fn write_archive(files: &[ExportFile], output: &Path, format: ExportFormat) -> io::Result<u64> {
+    let mut archive = tar::Builder::new(Encoder::new(format, File::create(output)?)?);
+
+    for file in files {
+        let source = File::open(&file.path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&source.metadata()?);
+        // The file being written may have grown since it was listed, so only the bytes it held
+        // then are archived, keeping the header's size right
+        header.set_size(file.size);
+        archive.append_data(&mut header, &file.name, source.take(file.size))?;
+    }
+
+    archive.into_inner()?.finish()?.sync_all()?;
+    Ok(fs::metadata(output)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates an empty directory for an export test
+    fn export_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("telemetry-export-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Database-like contents: many similar records, larger than the sample
+    fn records(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|index| {
+                let mut record = (index as u64).to_le_bytes().to_vec();
+                record.extend_from_slice(&(3.3 + (index % 7) as f64).to_le_bytes());
+                record
+            })
+            .collect()
+    }
+
+    fn unpack(path: &Path, format: ExportFormat) -> Vec<(String, Vec<u8>)> {
+        let file = File::open(path).unwrap();
+        let reader: Box<dyn Read> = match format {
+            ExportFormat::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+            ExportFormat::Zstd => Box::new(zstd::stream::read::Decoder::new(file).unwrap()),
+        };
+
+        tar::Archive::new(reader)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_str().unwrap().to_owned();
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+                (name, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn export_round_trip() {
+        for &format in &[ExportFormat::Gzip, ExportFormat::Zstd] {
+            let dir = export_dir(&format!("{:?}", format));
+            let first = records(20_000);
+            let second = records(100);
+            fs::create_dir_all(dir.join("rollups/1m/mean")).unwrap();
+            fs::write(dir.join("20200101000000.db"), &first).unwrap();
+            fs::write(dir.join("rollups/1m/mean/20200101000000.db"), &second).unwrap();
+            let output = dir.join("export.tar");
+
+            let files = vec![
+                dir.join("20200101000000.db").to_str().unwrap().to_owned(),
+                "rollups/1m/mean/20200101000000.db".to_owned(),
+            ];
+            let exported = export(&[&dir], &files, &output, format, false).unwrap();
+
+            assert_eq!(exported.raw_bytes, (first.len() + second.len()) as u64);
+            assert_eq!(exported.bytes, fs::metadata(&output).unwrap().len());
+            assert!(exported.bytes < exported.raw_bytes / 2);
+            assert!(exported.estimated_bytes < exported.bytes * 2);
+            assert!(exported.estimated_bytes > exported.bytes / 2);
+
+            assert_eq!(
+                unpack(&output, format),
+                vec![
+                    ("20200101000000.db".to_owned(), first),
+                    ("rollups/1m/mean/20200101000000.db".to_owned(), second),
+                ]
+            );
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn export_dry_run() {
+        let dir = export_dir("dry-run");
+        fs::write(dir.join("20200101000000.db"), records(1000)).unwrap();
+        let output = dir.join("export.tar.gz");
+
+        let files = vec!["20200101000000.db".to_owned()];
+        let exported = export(&[&dir], &files, &output, ExportFormat::Gzip, true).unwrap();
+
+        assert_eq!(exported.raw_bytes, 16_000);
+        assert_eq!(exported.bytes, 0);
+        assert!(exported.estimated_bytes > 0);
+        assert!(!output.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn export_decrypted_copies() {
+        let dir = export_dir("decrypted");
+        let decrypted = export_dir("decrypted-copies");
+        fs::write(decrypted.join("20200101000000.db"), records(10)).unwrap();
+        let output = dir.join("export.tar.zst");
+
+        // Decrypted copies keep their names within the database directory
+        let files = vec![decrypted
+            .join("20200101000000.db")
+            .to_str()
+            .unwrap()
+            .to_owned()];
+        export(
+            &[&dir, &decrypted],
+            &files,
+            &output,
+            ExportFormat::Zstd,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            unpack(&output, ExportFormat::Zstd),
+            vec![("20200101000000.db".to_owned(), records(10))]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(decrypted).unwrap();
+    }
+
+    #[test]
+    fn export_outside_roots() {
+        let dir = export_dir("outside");
+        let other = export_dir("outside-other");
+        fs::create_dir_all(dir.join("rollups")).unwrap();
+        fs::write(other.join("secret"), b"secret").unwrap();
+        let output = dir.join("export.tar.gz");
+
+        let export_one = |file: String| {
+            export(&[&dir], &[file], &output, ExportFormat::Gzip, false).unwrap_err()
+        };
+        assert!(
+            export_one(other.join("secret").to_str().unwrap().to_owned())
+                .ends_with("is not in the database directory")
+        );
+        assert!(export_one(format!(
+            "../{}/secret",
+            other.file_name().unwrap().to_str().unwrap()
+        ))
+        .ends_with("is not in the database directory"));
+        assert!(export_one("rollups".to_owned()).ends_with("is not a file"));
+        assert!(export_one("missing.db".to_owned()).starts_with("missing.db: "));
+        assert!(!output.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(other).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Flushing buffered telemetry to storage
+//
+// The flat database buffers inserted points in memory, so anything inserted since the last flush
+// is lost if the OBC loses power. Flushes happen when the service is stopped, when the `flush`
+// mutation is run (eg. before a planned power cycle), and periodically if `flush_interval` is
+// configured. The number of points inserted since the last flush is tracked so that a flush can
+// report how many were persisted.
+
+use crate::rollups::RollupManager;
+use flat_db::Database;
+use log::{debug, error};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub struct Flusher {
+    db: Arc<Database>,
+    rollups: Option<Arc<RollupManager>>,
+    // Points inserted since the database was last flushed
+    buffered: AtomicUsize,
+}
+
+impl Flusher {
+    pub fn new(db: Arc<Database>, rollups: Option<Arc<RollupManager>>) -> Self {
+        Flusher {
+            db,
+            rollups,
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts a thread which flushes the database every `interval`
+    pub fn start(flusher: Arc<Flusher>, interval: Duration) {
+        thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match flusher.flush() {
+                    Ok(points) => debug!("Flushed {} points", points),
+                    Err(err) => error!("{}", err),
+                }
+            })
+            .unwrap();
+    }
+
+    /// Notes that points have been inserted into the database, but may not be in storage yet
+    pub fn inserted(&self, points: usize) {
+        self.buffered.fetch_add(points, Ordering::SeqCst);
+    }
+
+    /// Flushes the database and any rollups to storage, returning the number of points
+    /// which were buffered
+    pub fn flush(&self) -> Result<usize, String> {
+        let points = self.buffered.swap(0, Ordering::SeqCst);
+
+        if let Err(err) = self.db.flush() {
+            // The points are still buffered
+            self.buffered.fetch_add(points, Ordering::SeqCst);
+            return Err(format!("Failed to flush database: {:?}", err));
+        }
+
+        if let Some(rollups) = &self.rollups {
+            rollups.flush();
+        }
+
+        Ok(points)
+    }
+}
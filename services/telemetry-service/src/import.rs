@@ -0,0 +1,280 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Bulk import of telemetry from files
+//
+// Telemetry which didn't arrive through the live path (eg. recovered from a payload's SD card)
+// can be merged into the database with the `importFile` mutation. Files are either CSV, with
+// `timestamp,subsystem,parameter,value` rows, or CBOR, holding an array of data points in the
+// format accepted on the direct UDP port.
+//
+// Every entry is checked before anything is inserted: timestamps must be valid, the
+// subsystem/parameter pair must be in the telemetry map and values must be numbers. Entries with
+// the same timestamp, subsystem and parameter as an earlier entry are duplicates and skipped.
+// When insert tokens are configured, the importing token must also cover each entry's subsystem.
+// The SHA-256 digest of each imported file is kept in a ledger next to the database, so the same
+// file can't be imported twice. The database itself can't be searched, so points which are
+// already in it from the live path aren't detected.
+
+use crate::auth::InsertToken;
+use crate::udp::{numeric_value, DataPoint, DirectUdp};
+use chrono::{DateTime, TimeZone, Utc};
+use juniper::GraphQLEnum;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// File in the database directory which the digests of imported files are saved to
+pub const IMPORTS_FILE: &str = ".imports.json";
+// Most rejected entries described in an import's result. The rest are only counted
+const MAX_REJECTED: usize = 20;
+
+/// Format of a file of telemetry to import
+#[derive(Clone, Copy, Debug, Eq, GraphQLEnum, PartialEq)]
+pub enum ImportFormat {
+    /// `timestamp,subsystem,parameter,value` rows, with an optional header row. Timestamps are
+    /// seconds since the UNIX epoch or RFC 3339
+    Csv,
+    /// An array of data points, as accepted on the direct UDP port
+    Cbor,
+}
+
+// Entries read from a file, once they've been checked
+#[derive(Default)]
+pub struct ImportBatch {
+    pub points: Vec<DataPoint>,
+    pub duplicates: usize,
+    pub rejected: usize,
+    // Why the first few rejected entries were rejected
+    pub errors: Vec<String>,
+    // SHA-256 of the file's contents, hex-encoded
+    pub digest: String,
+}
+
+impl ImportBatch {
+    fn reject(&mut self, entry: String, err: String) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_REJECTED {
+            self.errors.push(format!("{}: {}", entry, err));
+        }
+    }
+}
+
+// Reads and checks the entries of a file, imported with the given token if there is one
+pub fn read_file(
+    path: &Path,
+    format: ImportFormat,
+    token: Option<&InsertToken>,
+) -> Result<ImportBatch, String> {
+    let raw =
+        fs::read(path).map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+    let mut batch = ImportBatch {
+        digest: Sha256::digest(&raw)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+        ..Default::default()
+    };
+
+    let entries: Vec<(String, Result<DataPoint, String>)> = match format {
+        ImportFormat::Csv => std::str::from_utf8(&raw)
+            .map_err(|err| format!("File is not valid text: {}", err))?
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .filter(|(_, line)| !line.starts_with("timestamp,"))
+            .map(|(number, line)| (format!("line {}", number), parse_row(line)))
+            .collect(),
+        ImportFormat::Cbor => serde_cbor::from_slice::<Vec<DataPoint>>(&raw)
+            .map_err(|err| format!("File is not an array of data points: {}", err))?
+            .into_iter()
+            .enumerate()
+            .map(|(index, dp)| (format!("entry {}", index), Ok(dp)))
+            .collect(),
+    };
+
+    let mut seen = HashSet::new();
+    for (entry, dp) in entries {
+        match dp
+            .and_then(check_point)
+            .and_then(|dp| check_access(dp, token))
+        {
+            Ok(dp) => {
+                if seen.insert((dp.0, dp.1.clone(), dp.2.clone())) {
+                    batch.points.push(dp);
+                } else {
+                    batch.duplicates += 1;
+                }
+            }
+            Err(err) => batch.reject(entry, err),
+        }
+    }
+
+    Ok(batch)
+}
+
+// Parses a `timestamp,subsystem,parameter,value` row
+fn parse_row(line: &str) -> Result<DataPoint, String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() != 4 {
+        return Err(format!("expected 4 fields, found {}", fields.len()));
+    }
+
+    let timestamp = parse_timestamp(fields[0])?;
+    // Whole numbers are kept as integers, as they would be if sent to the direct UDP port
+    let value = if let Ok(value) = fields[3].parse::<i32>() {
+        value.into()
+    } else if let Ok(value) = fields[3].parse::<f64>() {
+        value.into()
+    } else {
+        return Err(format!("value '{}' is not a number", fields[3]));
+    };
+
+    Ok(DataPoint(
+        timestamp,
+        fields[1].to_owned(),
+        fields[2].to_owned(),
+        value,
+    ))
+}
+
+fn parse_timestamp(field: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(secs) = field.parse::<f64>() {
+        if secs.is_finite() && secs >= 0.0 {
+            let nanos = (secs.fract() * 1e9) as u32;
+            if let Some(time) = Utc.timestamp_opt(secs.trunc() as i64, nanos).single() {
+                return Ok(time);
+            }
+        }
+    } else if let Ok(time) = DateTime::parse_from_rfc3339(field) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    Err(format!("invalid timestamp '{}'", field))
+}
+
+// Checks that a data point can be stored
+fn check_point(dp: DataPoint) -> Result<DataPoint, String> {
+    if telemetry_map::get_id((&dp.1, &dp.2)).is_none() {
+        return Err(format!("{}/{} is not in the telemetry map", dp.1, dp.2));
+    }
+    match numeric_value(&dp.3) {
+        Some(value) if value.is_finite() => Ok(dp),
+        _ => Err(format!("{}/{} value is not a finite number", dp.1, dp.2)),
+    }
+}
+
+// Checks that the token importing the file may insert telemetry for the data point's subsystem
+fn check_access(dp: DataPoint, token: Option<&InsertToken>) -> Result<DataPoint, String> {
+    match token {
+        Some(token) if !token.allows(&dp.1) => Err(format!(
+            "token '{}' may not insert telemetry for {}",
+            token.name, dp.1
+        )),
+        _ => Ok(dp),
+    }
+}
+
+// What an import did (or would have done, in a dry run)
+pub struct Imported {
+    pub points: usize,
+    pub duplicates: usize,
+    pub rejected: usize,
+    pub errors: Vec<String>,
+}
+
+/// Digests of the files which have been imported, saved to a file
+pub struct ImportLedger {
+    path: PathBuf,
+    digests: Mutex<BTreeSet<String>>,
+}
+
+impl ImportLedger {
+    /// Opens the ledger saved in the given database directory, if there is one
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(IMPORTS_FILE);
+        let digests = match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("Failed to parse import ledger: {}", err))?,
+            Err(_) => BTreeSet::new(),
+        };
+
+        Ok(ImportLedger {
+            path,
+            digests: Mutex::new(digests),
+        })
+    }
+
+    /// Checks a file and, unless this is a dry run, inserts its entries into the database.
+    /// Nothing is inserted if any entry is rejected or the file has already been imported.
+    pub fn import(
+        &self,
+        udp: &DirectUdp,
+        path: &str,
+        format: ImportFormat,
+        dry_run: bool,
+        token: Option<&InsertToken>,
+    ) -> Result<Imported, String> {
+        let batch = read_file(Path::new(path), format, token)?;
+        // Held until the digest is recorded, so the same file can't be imported twice at once
+        let mut digests = self.lock()?;
+        if digests.contains(&batch.digest) {
+            return Err(format!("{} has already been imported", path));
+        }
+
+        let imported = Imported {
+            points: batch.points.len(),
+            duplicates: batch.duplicates,
+            rejected: batch.rejected,
+            errors: batch.errors,
+        };
+        if dry_run || imported.rejected > 0 {
+            return Ok(imported);
+        }
+
+        udp.backfill(batch.points)
+            .map_err(|err| format!("Failed to insert telemetry: {:?}", err))?;
+        info!(
+            "Imported {} points from {} ({} duplicates skipped)",
+            imported.points, path, imported.duplicates
+        );
+
+        let mut updated = digests.clone();
+        updated.insert(batch.digest);
+        self.save(&updated)?;
+        *digests = updated;
+        Ok(imported)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, BTreeSet<String>>, String> {
+        self.digests
+            .lock()
+            .map_err(|_| "Import ledger mutex poisoned".to_owned())
+    }
+
+    // Write to a temporary file first, so a reset mid-write can't lose the whole ledger
+    fn save(&self, digests: &BTreeSet<String>) -> Result<(), String> {
+        let raw = serde_json::to_vec_pretty(digests).map_err(|err| err.to_string())?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, raw)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|err| format!("Failed to save import ledger: {}", err))
+    }
+}
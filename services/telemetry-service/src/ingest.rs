@@ -0,0 +1,163 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Recovering from database IO errors while ingesting telemetry
+//
+// An IO error from the database (eg. the storage medium filling up, or the database's files being
+// removed) means it can't be written to at all. Rather than stopping the direct UDP and syslog
+// loops, the database is rotated to a new file, which re-opens it. Rotations are retried a few
+// times, with a growing delay between attempts. If the database still can't be written to,
+// ingestion is marked as stalled: incoming telemetry is dropped, and another rotation is attempted
+// every `STALLED_RETRY_INTERVAL` until one works. The state is reported by the `health` query.
+
+use crate::encryption::{encrypt_rotated, DbEncryption};
+use crate::unique_db_name;
+use flat_db::Database;
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Rotations attempted after an IO error before ingestion is marked as stalled
+const RECOVERY_ATTEMPTS: u32 = 3;
+// Delay before the first retried rotation, doubled for each one after it
+const RECOVERY_BACKOFF: Duration = Duration::from_secs(1);
+// Time between the rotations attempted while ingestion is stalled
+const STALLED_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct IngestState {
+    // Set while incoming telemetry is dropped because the database can't be written to
+    stalled: bool,
+    // When a rotation was last attempted
+    last_attempt: Option<Instant>,
+    // Times the database has been rotated to recover from an IO error
+    recoveries: u32,
+    last_error: Option<String>,
+}
+
+pub struct IngestHealth {
+    db: Arc<Database>,
+    db_path: PathBuf,
+    encryption: Option<Arc<DbEncryption>>,
+    // Shared by the direct UDP and syslog threads, so only one of them recovers the database
+    state: Mutex<IngestState>,
+}
+
+impl IngestHealth {
+    pub fn new(db: Arc<Database>, db_path: PathBuf, encryption: Option<Arc<DbEncryption>>) -> Self {
+        IngestHealth {
+            db,
+            db_path,
+            encryption,
+            state: Mutex::new(IngestState::default()),
+        }
+    }
+
+    // Whether incoming telemetry is being stored
+    pub fn ingesting(&self) -> bool {
+        !self.state().stalled
+    }
+
+    pub fn recoveries(&self) -> u32 {
+        self.state().recoveries
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.state().last_error.clone()
+    }
+
+    // Whether incoming telemetry should be dropped. While ingestion is stalled, this attempts
+    // a rotation once every `STALLED_RETRY_INTERVAL`, and ingestion resumes if it works
+    pub fn stalled(&self) -> bool {
+        let mut state = self.state();
+        if !state.stalled {
+            return false;
+        }
+        if let Some(last_attempt) = state.last_attempt {
+            if last_attempt.elapsed() < STALLED_RETRY_INTERVAL {
+                return true;
+            }
+        }
+
+        state.last_attempt = Some(Instant::now());
+        match self.rotate() {
+            Ok(()) => {
+                info!("Telemetry ingestion resumed");
+                state.stalled = false;
+                state.recoveries += 1;
+                false
+            }
+            Err(err) => {
+                warn!("Telemetry ingestion still stalled: {}", err);
+                state.last_error = Some(err);
+                true
+            }
+        }
+    }
+
+    // Recovers from an IO error returned by the database. The telemetry which was being
+    // inserted is lost, but anything received afterwards goes to the rotated database.
+    // Blocks the calling thread while rotations are retried.
+    pub fn io_error(&self, err: String) {
+        let mut state = self.state();
+        state.last_error = Some(err);
+        if state.stalled {
+            return;
+        }
+
+        for attempt in 0..RECOVERY_ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(RECOVERY_BACKOFF * 2u32.pow(attempt - 1));
+            }
+            state.last_attempt = Some(Instant::now());
+            match self.rotate() {
+                Ok(()) => {
+                    state.recoveries += 1;
+                    return;
+                }
+                Err(err) => {
+                    warn!("Failed to recover telemetry database: {}", err);
+                    state.last_error = Some(err);
+                }
+            }
+        }
+
+        error!(
+            "Telemetry database couldn't be recovered after {} attempts. Dropping telemetry",
+            RECOVERY_ATTEMPTS
+        );
+        state.stalled = true;
+    }
+
+    fn rotate(&self) -> Result<(), String> {
+        let db_path = unique_db_name(&self.db_path);
+        let new = self.db.rotate(db_path).map_err(|err| err.to_string())?;
+        info!("Rotated telemetry database to {:?} after IO error", new);
+        if let Some(encryption) = &self.encryption {
+            encrypt_rotated(encryption, new.to_path_buf());
+        }
+        Ok(())
+    }
+
+    // A panic while the state was locked can't leave it inconsistent, so poisoning is ignored
+    fn state(&self) -> MutexGuard<IngestState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
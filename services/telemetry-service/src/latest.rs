@@ -0,0 +1,315 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Latest value of each telemetry parameter
+//
+// "Current values" dashboards are the most common thing asked of the service. Like the reports and
+// rollups (see `reports.rs` for why), the latest values are kept as telemetry arrives, so fetching
+// them never touches the database files. Values are only replaced by ones with a newer timestamp,
+// so telemetry arriving out of order doesn't hide a newer value. They're held in memory, so after a
+// restart a parameter has no latest value until it's received again.
+//
+// For downlink, the latest values can also be packed into a CBOR array: the first value's
+// timestamp in milliseconds since the UNIX epoch, then for each value (oldest first) the
+// milliseconds since the previous value, its telemetry map parameter ID and the value itself.
+//
+// Building the subsystem, parameter and value strings for a large response is most of the work
+// of a query, so only the fields the query selected are filled in; the rest are left empty and
+// never make it into the response.
+
+use chrono::{DateTime, Utc};
+use juniper::{GraphQLEnum, GraphQLObject};
+use serde::Serialize;
+use serde_cbor::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Order values are listed in
+#[derive(Clone, Copy, Debug, Eq, GraphQLEnum, PartialEq)]
+pub enum Order {
+    /// Oldest first
+    Asc,
+    /// Newest first
+    Desc,
+}
+
+/// Most recent value received for a telemetry parameter
+#[derive(Clone, Debug, GraphQLObject, PartialEq)]
+pub struct LatestValue {
+    /// When the value was measured, in seconds since the UNIX epoch
+    pub timestamp: f64,
+    pub subsystem: String,
+    pub parameter: String,
+    pub value: String,
+}
+
+/// Fields of `LatestValue` selected by a query. The timestamp is always filled in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fields {
+    pub subsystem: bool,
+    pub parameter: bool,
+    pub value: bool,
+}
+
+#[derive(Default)]
+pub struct LatestValues {
+    // Timestamp and value of each parameter, keyed by subsystem and parameter
+    values: Mutex<HashMap<(String, String), (f64, Value)>>,
+}
+
+impl LatestValues {
+    /// Notes a value received for a parameter, unless a newer one has already been received
+    pub fn record<T: Serialize>(
+        &self,
+        timestamp: &DateTime<Utc>,
+        subsystem: &str,
+        parameter: &str,
+        value: &T,
+    ) {
+        let timestamp = timestamp.timestamp_millis() as f64 / 1000.0;
+        let value = match serde_cbor::value::to_value(value) {
+            Ok(value) => value,
+            Err(err) => Value::Text(err.to_string()),
+        };
+        if let Ok(mut values) = self.values.lock() {
+            match values.get_mut(&(subsystem.to_owned(), parameter.to_owned())) {
+                Some(latest) if latest.0 > timestamp => {}
+                Some(latest) => *latest = (timestamp, value),
+                None => {
+                    values.insert(
+                        (subsystem.to_owned(), parameter.to_owned()),
+                        (timestamp, value),
+                    );
+                }
+            }
+        }
+    }
+
+    /// The latest values of the given subsystem's parameters (or of every subsystem's), in
+    /// timestamp order. If `parameters` are given, only their values are listed. Fields which
+    /// aren't in `fields` are left empty
+    pub fn get(
+        &self,
+        subsystem: Option<&str>,
+        parameters: Option<&[String]>,
+        order: Order,
+        fields: Fields,
+    ) -> Vec<LatestValue> {
+        let text = |selected: bool, text: &str| {
+            if selected {
+                text.to_owned()
+            } else {
+                String::new()
+            }
+        };
+        let mut latest = self.select(subsystem, parameters, |timestamp, sub, param, value| {
+            LatestValue {
+                timestamp,
+                subsystem: text(fields.subsystem, sub),
+                parameter: text(fields.parameter, param),
+                value: if fields.value {
+                    value_text(value)
+                } else {
+                    String::new()
+                },
+            }
+        });
+
+        if order == Order::Desc {
+            latest.reverse();
+        }
+        latest
+    }
+
+    pub fn packed<F>(
+        &self,
+        subsystem: Option<&str>,
+        parameters: Option<&[String]>,
+        id: F,
+    ) -> Result<Vec<u8>, String>
+    where
+        F: Fn(&str, &str) -> Option<u16>,
+    {
+        let mut packed = vec![];
+        let mut previous = None;
+        let selected = self.select(subsystem, parameters, |timestamp, sub, param, value| {
+            id(sub, param).map(|id| (timestamp, id, value.clone()))
+        });
+        for (timestamp, id, value) in selected.into_iter().flatten() {
+            let millis = (timestamp * 1000.0).round() as i128;
+            let delta = match previous {
+                Some(previous) => millis - previous,
+                None => {
+                    packed.push(Value::Integer(millis));
+                    0
+                }
+            };
+            previous = Some(millis);
+
+            packed.push(Value::Integer(delta));
+            packed.push(Value::Integer(i128::from(id)));
+            packed.push(value);
+        }
+
+        serde_cbor::to_vec(&Value::Array(packed))
+            .map_err(|err| format!("Failed to pack latest values: {}", err))
+    }
+
+    // Maps the timestamp, subsystem, parameter and value of each selected latest value, oldest
+    // first. Values with the same timestamp are listed by name, so the order is stable
+    fn select<T, F>(&self, subsystem: Option<&str>, parameters: Option<&[String]>, map: F) -> Vec<T>
+    where
+        F: Fn(f64, &str, &str, &Value) -> T,
+    {
+        let values = match self.values.lock() {
+            Ok(values) => values,
+            Err(_) => return vec![],
+        };
+
+        let mut selected: Vec<(&(String, String), &(f64, Value))> = values
+            .iter()
+            .filter(|((sub, _), _)| subsystem.map_or(true, |subsystem| subsystem == sub))
+            .filter(|((_, param), _)| parameters.map_or(true, |params| params.contains(param)))
+            .collect();
+
+        selected.sort_by(|(a_key, a), (b_key, b)| {
+            a.0.partial_cmp(&b.0)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a_key.cmp(b_key))
+        });
+        selected
+            .into_iter()
+            .map(|((sub, param), (timestamp, value))| map(*timestamp, sub, param, value))
+            .collect()
+    }
+}
+
+// Text form of a data point's value, as it would be read back from the database
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::Integer(value) => value.to_string(),
+        Value::Float(value) => value.to_string(),
+        Value::Bool(value) => value.to_string(),
+        Value::Text(value) => value.to_owned(),
+        value => format!("{:?}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn values() -> LatestValues {
+        let latest = LatestValues::default();
+        let at = |millis| Utc.timestamp_millis(millis);
+        latest.record(&at(1_577_836_800_500), "eps", "voltage", &3.3);
+        latest.record(&at(1_577_836_801_000), "eps", "current", &2);
+        latest.record(&at(1_577_836_800_000), "adcs", "mode", &"safe");
+        latest.record(&at(1_577_836_799_000), "eps", "voltage", &1.0);
+        latest
+    }
+
+    fn ids(subsystem: &str, parameter: &str) -> Option<u16> {
+        match (subsystem, parameter) {
+            ("eps", "voltage") => Some(7),
+            ("eps", "current") => Some(8),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn get_orders() {
+        let latest = values();
+        let names = |order| -> Vec<String> {
+            latest
+                .get(
+                    None,
+                    None,
+                    order,
+                    Fields {
+                        subsystem: false,
+                        parameter: true,
+                        value: true,
+                    },
+                )
+                .into_iter()
+                .map(|value| format!("{}={}", value.parameter, value.value))
+                .collect()
+        };
+        assert_eq!(
+            names(Order::Asc),
+            vec!["mode=safe", "voltage=3.3", "current=2"]
+        );
+        assert_eq!(
+            names(Order::Desc),
+            vec!["current=2", "voltage=3.3", "mode=safe"]
+        );
+    }
+
+    #[test]
+    fn get_fills_selected_fields() {
+        let fields = Fields {
+            subsystem: false,
+            parameter: false,
+            value: true,
+        };
+        assert_eq!(
+            values().get(Some("eps"), None, Order::Asc, fields),
+            vec![
+                LatestValue {
+                    timestamp: 1_577_836_800.5,
+                    subsystem: String::new(),
+                    parameter: String::new(),
+                    value: "3.3".to_owned(),
+                },
+                LatestValue {
+                    timestamp: 1_577_836_801.0,
+                    subsystem: String::new(),
+                    parameter: String::new(),
+                    value: "2".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn packed_deltas_and_ids() {
+        let packed = values().packed(Some("eps"), None, ids).unwrap();
+        let packed: Value = serde_cbor::from_slice(&packed).unwrap();
+        assert_eq!(
+            packed,
+            Value::Array(vec![
+                Value::Integer(1_577_836_800_500),
+                Value::Integer(0),
+                Value::Integer(7),
+                Value::Float(3.3),
+                Value::Integer(500),
+                Value::Integer(8),
+                Value::Integer(2),
+            ])
+        );
+    }
+
+    #[test]
+    fn packed_skips_unmapped() {
+        let packed = values().packed(Some("adcs"), None, ids).unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<Value>(&packed).unwrap(),
+            Value::Array(vec![])
+        );
+    }
+}
@@ -36,6 +36,140 @@
 //! service's IP address, and `port` specifies the port on which the service will be
 //! listening for UDP packets.
 //!
+//! Deleting telemetry can be disabled entirely (eg. for flight builds) by adding
+//! `deletes_enabled = false` to the `[telemetry-service]` section.
+//!
+//! The service can be started in read-only mode by adding `read_only = true` to the
+//! `[telemetry-service]` section. While read-only, incoming telemetry is dropped and deletes and
+//! rotations are rejected. The mode can be changed at runtime with the `setReadOnly` mutation,
+//! or by changing `read_only` in the config file and sending the service SIGHUP, and is reported
+//! by the `health` query.
+//!
+//! If the database returns an IO error while storing telemetry from the `direct_port` or
+//! `syslog_port`, the service rotates it to a new file, retrying a few times. If that fails,
+//! incoming telemetry is dropped and a rotation is retried every minute. Whether telemetry is
+//! being stored, and the last IO error, are reported by the `health` query.
+//!
+//! Standing reports are enabled by adding `report_dir = "/path/to/reports"` to the
+//! `[telemetry-service]` section. Completed reports are written to this directory, ready to be
+//! downlinked. Reports are built from the data points received on the `direct_port`.
+//!
+//! Existing daemons can log to the telemetry database by adding `syslog_port = 5514` to the
+//! `[telemetry-service]` section and sending their RFC 5424 syslog messages to that port.
+//! Each message is stored as an entry with the message's APP-NAME as the subsystem (or `syslog`
+//! if it has none), its facility keyword (eg. `daemon`, `local0`) as the parameter, and its
+//! severity (0 = emergency to 7 = debug) as the value. Like entries received on the
+//! `direct_port`, only subsystem/parameter pairs found in the telemetry map are stored.
+//!
+//! Inserts on the `direct_port` can be restricted to known clients by adding an `insert_tokens`
+//! array to the `[telemetry-service]` section:
+//!
+//! ```
+//! [[telemetry-service.insert_tokens]]
+//! name = "payload"
+//! secret = "shared secret"
+//! subsystems = ["payload"]
+//! ```
+//!
+//! Each datagram must then end with the 32-byte HMAC-SHA256 of the rest of the datagram, keyed
+//! with one of the tokens' secrets. Unsigned datagrams are dropped, as are data points for any
+//! subsystem not in the signing token's `subsystems` list (if it has one). Telemetry points in
+//! the binary message format only carry parameter IDs, so are only accepted from tokens without
+//! a `subsystems` list. Entries received on the `syslog_port` aren't authenticated.
+//!
+//! Payload teams sharing a bus can each be given a namespace with a token's `namespaces` list of
+//! subsystem prefixes (eg. `namespaces = ["cam_"]` covers `cam_thermal` and `cam_optics`). Once
+//! any tokens are configured, the mutations which change telemetry (`importFile`,
+//! `registerParameters`, `removeParameter`, `deleteRange`, `pruneFiles` and `delete`) need a
+//! `token` argument holding a token's secret, and may only change the subsystems it covers.
+//! Database files hold every subsystem's telemetry, so only tokens without `subsystems` or
+//! `namespaces` may delete them, except with `deleteRange`'s `subsystem` argument. The same goes
+//! for `setReadOnly`, which guards against deletes. Queries aren't restricted.
+//!
+//! Metadata about each telemetry parameter (units, description, data type and expected limits)
+//! can be kept in a catalog alongside the database, in the `.parameters.json` file in the
+//! database's directory. Entries are added with the `registerParameters` mutation and read back
+//! with the `parameters` query, so ground displays don't need a separate telemetry dictionary.
+//!
+//! Catalog entries also set how each parameter's values are stored. By default, values are
+//! stored as 64-bit floats. High-rate parameters can use half the storage with an `encoding` of
+//! `FLOAT32`, or `SCALED_INTEGER` along with a `scale` and `offset`, which stores
+//! `(value - offset) / scale` rounded to a 32-bit integer (clamped to the integer's range).
+//! Readers convert the stored values back with the entry returned by the `parameters` query
+//! (`value = stored * scale + offset`). Encodings apply to the data points received on the
+//! `direct_port` and `syslog_port`; standing reports and rollups are built from the values as
+//! received. Changing a parameter's encoding only affects values stored afterwards.
+//!
+//! Adding `rollups = true` to the `[telemetry-service]` section keeps 1-minute and 1-hour rollups
+//! (the minimum, mean and maximum of each parameter) in separate flat databases under the
+//! `rollups` directory beside the database, eg. `rollups/1h/max/20200101120000.db`. Like the
+//! standing reports, rollups are built from the data points received on the `direct_port` and
+//! `syslog_port`. The `queryPlan` query picks the resolution to read for a time range, along with
+//! the database files holding it, so long-range trend queries read the rollups rather than the
+//! raw telemetry.
+//!
+//! Inserted telemetry can be mirrored live to a downlink port of the communications service,
+//! so the ground gets a telemetry stream during a pass without polling queries:
+//!
+//! ```
+//! [telemetry-service.mirror]
+//! ip = "127.0.0.1"
+//! port = 14020
+//! subsystems = ["eps", "adcs"]
+//! ```
+//!
+//! `ip` defaults to `127.0.0.1`, and all subsystems are mirrored if `subsystems` isn't given.
+//! Each batch of telemetry stored from the `direct_port` or `syslog_port` is sent as one
+//! datagram, in the same formats accepted on the `direct_port`: data points as a CBOR array,
+//! and telemetry points in the binary message format as they were received. Binary telemetry
+//! points only carry parameter IDs, so they aren't mirrored when `subsystems` is given. Nothing
+//! is mirrored while the service is read-only.
+//!
+//! Telemetry recovered from elsewhere (eg. a payload's SD card) can be merged into the database
+//! with the `importFile` mutation, from a CSV file of `timestamp,subsystem,parameter,value` rows
+//! or a CBOR array of data points. Every entry is checked first, and nothing is imported if any
+//! are rejected. Repeated entries are skipped, and the digest of each imported file is kept in
+//! `.imports.json` in the database's directory so the same file can't be imported twice.
+//! Imported telemetry is stored with the catalog's encodings, but isn't recorded in standing
+//! reports or rollups, or mirrored.
+//!
+//! The database buffers inserted telemetry in memory, and flushes it to storage when the service
+//! is stopped with SIGTERM or SIGINT. Telemetry can be flushed at any time (eg. before a planned
+//! power cycle) with the `flush` mutation, and periodically by adding `flush_interval = 60` (in
+//! seconds) to the `[telemetry-service]` section.
+//!
+//! Missions which can't keep telemetry on storage in plaintext can have the database files
+//! encrypted with AES-256-GCM, with a hex-encoded 256-bit key read from a file which only its
+//! owner may access, or printed by a command (eg. one which fetches it from a hardware keystore):
+//!
+//! ```
+//! [telemetry-service.encryption]
+//! key_file = "/home/system/etc/telemetry-db.key"
+//! # key_command = ["/usr/sbin/keystore", "get", "telemetry-db"]
+//! decrypt_dir = "/tmp/telemetry-db"
+//! ```
+//!
+//! The database can only write plaintext, so each file is encrypted (to `{name}.db.enc`) once the
+//! database has moved on from it: after each rotation, and at startup for the previous run's
+//! files and rollups. The file currently being written stays in plaintext until then. The
+//! `queryPlan` query decrypts the encrypted files it lists into `decrypt_dir` (which should be
+//! RAM-backed, and defaults to `/tmp/telemetry-db`) and returns the decrypted copies, which are
+//! removed an hour later. Deletes and pruning work on encrypted files as they do on plaintext.
+//!
+//! Archives for downlink can be given well-defined boundaries with the `snapshot` mutation, which
+//! flushes the database and rotates it, so that the closed files hold exactly the telemetry
+//! inserted before the snapshot while inserts carry on into a new file. Each snapshot is given
+//! the next sequence number and recorded in `.snapshots.json` in the database's directory. The
+//! `snapshotExport` query lists the files holding the telemetry up to a snapshot, or only that
+//! added since an earlier snapshot, decrypting them like `queryPlan`. Snapshots only cover the
+//! raw database, not rollups, and files which have been deleted or pruned are no longer listed.
+//!
+//! The files listed by `queryPlan` or `snapshotExport` can be packed into a single tar archive
+//! for downlink with the `exportFiles` mutation, compressed with gzip or zstd. Files are streamed
+//! into the archive, so memory use stays bounded however large the export. Each export returns
+//! an estimate of the archive's size, based on compressing the start of each file, and a dry run
+//! returns only the estimate, so the ground can budget downlink time before the archive is made.
+//!
 //! # Starting the Service
 //!
 //! The service should be started automatically by its init script, but may also be started manually:
@@ -62,10 +196,24 @@
 //! }
 //!
 //! query ping: "pong"
+//! query health: { readOnly: Boolean!, deletesEnabled: Boolean!, ingesting: Boolean!, dbRecoveries: Int!, lastDbError: String }
+//! query reports: [{ definition: ReportDefinition!, periodStart: Float!, nextReport: Float! }]
+//! query parameters(subsystem: String): [{ subsystem: String!, parameter: String!, units: String, description: String, dataType: DataType!, min: Float, max: Float, encoding: Encoding!, scale: Float, offset: Float }]
 //! query telemetry(timestampGe: Integer, timestampLe: Integer, subsystem: String, parameter: String, parameters: [String]): Entry
 //! query routedTelemetry(timestampGe: Integer, timestampLe: Integer, subsystem: String, parameter: String, parameters: [String], output: String!, compress: Boolean = true): String!
 //!
 //! mutation insert(timestamp: Integer, subsystem: String!, parameter: String!, value: String!):{ success: Boolean!, errors: String! }
+//! mutation deleteRange(timestampGe: Float!, timestampLe: Float!, subsystem: String, dryRun: Boolean = false, token: String):{ success: Boolean!, errors: String!, dryRun: Boolean!, filesDeleted: Int!, files: [String!]! }
+//! mutation delete(files: [String!]!, token: String): [String!]!
+//! mutation pruneFiles(olderThanDays: Float!, dryRun: Boolean = false, token: String):{ success: Boolean!, errors: String!, dryRun: Boolean!, files: [String!]!, totalBytes: Float! }
+//! mutation setReadOnly(readOnly: Boolean!, token: String):{ readOnly: Boolean!, deletesEnabled: Boolean!, ingesting: Boolean!, dbRecoveries: Int!, lastDbError: String }
+//! mutation flush:{ success: Boolean!, errors: String!, pointsFlushed: Int! }
+//! mutation registerReport(name: String!, parameters: [ReportParameterInput!]!, aggregations: [Aggregation!]!, periodSecs: Int!, format: ReportFormat = CSV):{ success: Boolean!, errors: String!, file: String }
+//! mutation removeReport(name: String!):{ success: Boolean!, errors: String!, file: String }
+//! mutation generateReport(name: String!):{ success: Boolean!, errors: String!, file: String }
+//! mutation registerParameters(entries: [ParameterInfoInput!]!, token: String):{ success: Boolean!, errors: String! }
+//! mutation removeParameter(subsystem: String!, parameter: String!, token: String):{ success: Boolean!, errors: String! }
+//! mutation exportFiles(files: [String!]!, output: String!, format: ExportFormat = GZIP, dryRun: Boolean = false):{ success: Boolean!, errors: String!, dryRun: Boolean!, rawBytes: Float!, estimatedBytes: Float!, bytes: Float! }
 //! ```
 //!
 //! # Example Queries
@@ -191,32 +339,169 @@
 //!
 //! ```
 //!
-//! ## Delete all entries from the EPS subsystem occuring before timestamp 1003
+//! ## Delete all database files covering only the time between timestamps 1000 and 2000
+//!
+//! The flat database can't remove individual entries, so only whole database files (each covering
+//! the time from its creation until the next rotation) falling within the range are deleted.
+//! The database currently being written to is never deleted.
+//! ```graphql
+//! mutation {
+//!     deleteRange(timestampGe: 1000, timestampLe: 2000) {
+//!         success,
+//!         errors,
+//!         filesDeleted,
+//!         files
+//!     }
+//! }
+//! ```
+//!
+//! ## Check what the previous mutation would delete, without deleting anything
+//! ```graphql
+//! mutation {
+//!     deleteRange(timestampGe: 1000, timestampLe: 2000, dryRun: true) {
+//!         filesDeleted,
+//!         files
+//!     }
+//! }
+//! ```
+//!
+//! ## Delete the database files between timestamps 1000 and 2000 which only hold payload telemetry
+//!
+//! The times at which each subsystem's telemetry was stored are kept in `.subsystems.json` in the
+//! database's directory, so files holding any other subsystem's telemetry (or telemetry points in
+//! the binary message format, which don't name a subsystem) are left alone. Files written before
+//! the service started keeping this record are never deleted by subsystem.
+//! ```graphql
+//! mutation {
+//!     deleteRange(timestampGe: 1000, timestampLe: 2000, subsystem: "payload") {
+//!         success,
+//!         errors,
+//!         filesDeleted,
+//!         files
+//!     }
+//! }
+//! ```
+//!
+//! ## Delete all database files which haven't been written to for 30 days
+//!
+//! Check the files and the space they take up with `dryRun: true` first. Like `deleteRange`, this
+//! is rejected if deletes are disabled or the service is read-only, and the database currently
+//! being written to is never deleted.
+//! ```graphql
+//! mutation {
+//!     pruneFiles(olderThanDays: 30) {
+//!         success,
+//!         errors,
+//!         files,
+//!         totalBytes
+//!     }
+//! }
+//! ```
+//!
+//! ## Make sure all received telemetry is in storage before a power cycle
 //! ```graphql
 //! mutation {
-//!     delete(subsystem: "eps", timestampLe: 1004) {
+//!     flush {
 //!         success,
 //!         errors,
-//!         entriesDeleted
+//!         pointsFlushed
+//!     }
+//! }
+//! ```
+//!
+//! ## Produce a daily report of the minimum, maximum and mean battery voltage
+//!
+//! A new report covering each UTC day is written to the report directory at midnight.
+//! ```graphql
+//! mutation {
+//!     registerReport(
+//!         name: "daily-power",
+//!         parameters: [{ subsystem: "eps", parameter: "voltage" }],
+//!         aggregations: [MIN, MAX, MEAN],
+//!         periodSecs: 86400,
+//!         format: CSV
+//!     ) {
+//!         success,
+//!         errors
+//!     }
+//! }
+//! ```
+//!
+//! ## Describe the battery voltage parameter for ground displays
+//! ```graphql
+//! mutation {
+//!     registerParameters(entries: [{
+//!         subsystem: "eps",
+//!         parameter: "voltage",
+//!         units: "V",
+//!         description: "Battery bus voltage",
+//!         dataType: FLOAT,
+//!         min: 6.0,
+//!         max: 8.4
+//!     }]) {
+//!         success,
+//!         errors
+//!     }
+//! }
+//! ```
+//!
+//! ## Store a high-rate temperature in 0.01 degree steps, using half the storage
+//! ```graphql
+//! mutation {
+//!     registerParameters(entries: [{
+//!         subsystem: "adcs",
+//!         parameter: "temperature",
+//!         units: "degC",
+//!         encoding: SCALED_INTEGER,
+//!         scale: 0.01,
+//!         offset: 0.0
+//!     }]) {
+//!         success,
+//!         errors
 //!     }
 //! }
 //! ```
 
 extern crate juniper;
 
+mod auth;
+mod catalog;
+mod delete;
+mod encryption;
+mod export;
+mod flush;
+mod import;
+mod ingest;
+mod latest;
+mod mirror;
+mod reports;
+mod rollups;
 mod schema;
+mod snapshot;
+mod subsystems;
+mod syslog;
 mod udp;
 
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::schema::{MutationRoot, QueryRoot, Subsystem};
+use crate::auth::{InsertToken, InsertTokens};
+use crate::catalog::ParameterCatalog;
+use crate::encryption::{encrypted_path, DbEncryption, EncryptionConfig};
+use crate::flush::Flusher;
+use crate::import::ImportLedger;
+use crate::mirror::{Mirror, MirrorConfig};
+use crate::reports::ReportManager;
+use crate::rollups::RollupManager;
+use crate::schema::{MutationRoot, QueryRoot, Subsystem, SubsystemOptions};
+use crate::snapshot::SnapshotLedger;
+use crate::subsystems::SubsystemLedger;
 use chrono::Utc;
 use kubos_service::{Config, Logger, Service};
 // use kubos_telemetry_db::Database;
 use flat_db::Builder;
-use libc::{SIGINT, SIGTERM};
 use log::{error, info};
-use signal_hook::iterator::Signals;
 
 fn main() {
     Logger::init("kubos-telemetry-service").unwrap();
@@ -227,6 +512,7 @@ fn main() {
             err
         })
         .unwrap();
+    kubos_service::schema::exit_if_requested::<QueryRoot, MutationRoot>();
 
     let db_path = config
         .get("database")
@@ -243,6 +529,25 @@ fn main() {
         })
         .unwrap();
 
+    let encryption = match config.get("encryption").map(|val| {
+        val.try_into::<EncryptionConfig>()
+            .map_err(|err| err.to_string())
+            .and_then(DbEncryption::new)
+    }) {
+        Some(Ok(encryption)) => Some(encryption),
+        Some(Err(err)) => {
+            error!("Failed to set up database encryption: {}", err);
+            return;
+        }
+        None => None,
+    };
+
+    // Files left by the last run are closed, so they can be encrypted before the new
+    // database (and rollups) are opened
+    if let (Some(encryption), Some(dir)) = (&encryption, Path::new(db_path).parent()) {
+        encryption.encrypt_tree(dir);
+    }
+
     let db_path = unique_db_name(db_path);
 
     let db = match Builder::new().path(&db_path).build() {
@@ -253,7 +558,105 @@ fn main() {
         }
     };
 
-    let direct_udp = config.get("direct_port").map(|port| {
+    let deletes_enabled = config
+        .get("deletes_enabled")
+        .and_then(|val| val.as_bool())
+        .unwrap_or(true);
+
+    let read_only = config
+        .get("read_only")
+        .and_then(|val| val.as_bool())
+        .unwrap_or(false);
+    if read_only {
+        info!("Starting in read-only mode");
+    }
+
+    let reports = config
+        .get("report_dir")
+        .and_then(|val| val.as_str().map(|dir| dir.to_owned()))
+        .and_then(|dir| match ReportManager::new(Path::new(&dir)) {
+            Ok(reports) => Some(reports),
+            Err(err) => {
+                error!("Standing reports disabled: {}", err);
+                None
+            }
+        });
+
+    let rollups = if config
+        .get("rollups")
+        .and_then(|val| val.as_bool())
+        .unwrap_or(false)
+    {
+        match RollupManager::new(&db_path) {
+            Ok(rollups) => Some(Arc::new(rollups)),
+            Err(err) => {
+                error!("Rollups disabled: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let catalog = db_path
+        .parent()
+        .ok_or_else(|| "path does not have a parent".to_owned())
+        .and_then(ParameterCatalog::open)
+        .map_err(|err| error!("Parameter catalog disabled: {}", err))
+        .ok();
+
+    let imports = db_path
+        .parent()
+        .ok_or_else(|| "path does not have a parent".to_owned())
+        .and_then(ImportLedger::open)
+        .map_err(|err| error!("File imports disabled: {}", err))
+        .ok();
+
+    let snapshots = db_path
+        .parent()
+        .ok_or_else(|| "path does not have a parent".to_owned())
+        .and_then(SnapshotLedger::open)
+        .map_err(|err| error!("Snapshots disabled: {}", err))
+        .ok();
+
+    let subsystems = db_path
+        .parent()
+        .ok_or_else(|| "path does not have a parent".to_owned())
+        .and_then(SubsystemLedger::open)
+        .map_err(|err| error!("Deleting telemetry by subsystem disabled: {}", err))
+        .ok();
+
+    let tokens = match config.get("insert_tokens").map(|val| {
+        val.try_into::<Vec<InsertToken>>()
+            .map_err(|err| err.to_string())
+            .and_then(InsertTokens::new)
+    }) {
+        Some(Ok(tokens)) => Some(tokens),
+        Some(Err(err)) => {
+            error!("Failed to parse 'insert_tokens' config value: {}", err);
+            return;
+        }
+        None => None,
+    };
+
+    let mirror = match config.get("mirror").map(|val| {
+        val.try_into::<MirrorConfig>()
+            .map_err(|err| err.to_string())
+            .and_then(Mirror::new)
+    }) {
+        Some(Ok(mirror)) => {
+            info!("Mirroring telemetry to {}", mirror.dest());
+            Some(mirror)
+        }
+        Some(Err(err)) => {
+            error!("Failed to parse 'mirror' config value: {}", err);
+            return;
+        }
+        None => None,
+    };
+
+    // Address for a UDP socket on the service's IP
+    let udp_url = |port| {
         let host = config
             .hosturl()
             .ok_or_else(|| {
@@ -271,38 +674,60 @@ fn main() {
             .unwrap();
 
         format!("{}:{}", host_ip, port)
-    });
-
-    let db_c = db.clone();
-    std::thread::Builder::new()
-        .stack_size(1024)
-        .spawn(move || {
-            let db = db_c;
-            let sigs = vec![SIGINT, SIGTERM];
-
-            let mut signals = Signals::new(&sigs).unwrap();
-
-            for signal in &mut signals {
-                match signal as libc::c_int {
-                    SIGINT | SIGTERM => {
-                        db.flush().unwrap();
-                        std::process::exit(0);
-                    }
-                    s => {
-                        dbg!(s);
-                    }
-                }
+    };
+
+    let direct_udp = config.get("direct_port").map(udp_url);
+    let syslog_udp = config.get("syslog_port").map(udp_url);
+
+    let flush_interval = config
+        .get("flush_interval")
+        .and_then(|val| val.as_integer())
+        .filter(|secs| *secs > 0)
+        .map(|secs| Duration::from_secs(secs as u64));
+
+    let subsystem = Subsystem::new(
+        db,
+        &db_path,
+        SubsystemOptions {
+            direct_udp,
+            syslog_udp,
+            deletes_enabled,
+            read_only,
+            reports,
+            rollups,
+            catalog,
+            tokens,
+            mirror,
+            imports,
+            snapshots,
+            subsystems,
+            encryption,
+        },
+    );
+
+    if let Some(interval) = flush_interval {
+        Flusher::start(subsystem.flusher.clone(), interval);
+    }
+
+    Service::new(config, subsystem, QueryRoot, MutationRoot)
+        .reloadable(
+            "telemetry-service",
+            &["read_only"],
+            |subsystem: &Subsystem, config| {
+                let read_only = config.get("read_only").map_or(Ok(false), |val| {
+                    val.as_bool()
+                        .ok_or_else(|| "Failed to parse 'read_only' config value".to_owned())
+                })?;
+                subsystem.set_read_only(read_only);
+                Ok(())
+            },
+        )
+        .on_shutdown(|subsystem: &Subsystem| {
+            if let Err(err) = subsystem.flusher.flush() {
+                error!("Failed to flush telemetry: {}", err);
             }
         })
-        .unwrap();
-
-    Service::new(
-        config,
-        Subsystem::new(db, &db_path, direct_udp),
-        QueryRoot,
-        MutationRoot,
-    )
-    .start();
+        .start();
 }
 
 /// Generate a unique db name based of the current time, and if there are colisions a incrementing
@@ -316,7 +741,8 @@ pub fn unique_db_name(base: impl AsRef<Path>) -> PathBuf {
         // Set the extension to be the current time
         base.set_file_name(db_name(&timestamp, count));
 
-        match std::fs::metadata(&base) {
+        // An encrypted file still holds its name
+        match std::fs::metadata(&base).or_else(|_| std::fs::metadata(encrypted_path(&base))) {
             Ok(_) => count += 1,
             Err(_) => {
                 info!("Telemetry DB path {:?}", &base);
@@ -0,0 +1,110 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Live telemetry mirroring
+//
+// Every batch of telemetry inserted into the database can also be sent as a UDP datagram to a
+// downlink port of the communications service, giving the ground a live telemetry stream during
+// a pass without having to poll the service with queries. Datagrams are in the same formats
+// accepted on the direct UDP port: data points are sent as a CBOR array, and telemetry points
+// in the binary message format are passed on as received. Mirroring can be limited to a list of
+// subsystems, in which case binary telemetry points (which only carry parameter IDs) aren't
+// mirrored.
+
+use crate::udp::DataPoint;
+use log::debug;
+use serde::Deserialize;
+use std::net::{SocketAddr, UdpSocket};
+
+fn default_ip() -> String {
+    "127.0.0.1".to_owned()
+}
+
+// Where telemetry is mirrored to, as given in the `[telemetry-service.mirror]` config table
+#[derive(Clone, Debug, Deserialize)]
+pub struct MirrorConfig {
+    // Address of the downlink port
+    #[serde(default = "default_ip")]
+    pub ip: String,
+    pub port: u16,
+    // Subsystems whose telemetry is mirrored. All subsystems if not given
+    pub subsystems: Option<Vec<String>>,
+}
+
+pub struct Mirror {
+    socket: UdpSocket,
+    dest: SocketAddr,
+    subsystems: Option<Vec<String>>,
+}
+
+impl Mirror {
+    pub fn new(config: MirrorConfig) -> Result<Self, String> {
+        let dest = format!("{}:{}", config.ip, config.port)
+            .parse()
+            .map_err(|err| format!("Invalid mirror address: {}", err))?;
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .map_err(|err| format!("Failed to bind mirror socket: {}", err))?;
+
+        Ok(Mirror {
+            socket,
+            dest,
+            subsystems: config.subsystems,
+        })
+    }
+
+    pub fn dest(&self) -> SocketAddr {
+        self.dest
+    }
+
+    fn allows(&self, subsystem: &str) -> bool {
+        self.subsystems
+            .as_ref()
+            .map_or(true, |subsystems| subsystems.iter().any(|s| s == subsystem))
+    }
+
+    // Mirror a telemetry message in the binary format, as it was received
+    pub fn points(&self, raw: &[u8]) {
+        if self.subsystems.is_none() {
+            self.send(raw);
+        }
+    }
+
+    // Serialize the data points which should be mirrored, ready to be sent once they've been
+    // inserted. Points without an ID in the telemetry map aren't inserted, so aren't mirrored
+    pub fn datagram(&self, dps: &[DataPoint]) -> Option<Vec<u8>> {
+        let dps: Vec<&DataPoint> = dps
+            .iter()
+            .filter(|DataPoint(_, subsystem, metric, _)| {
+                self.allows(subsystem) && telemetry_map::get_id((subsystem, metric)).is_some()
+            })
+            .collect();
+        if dps.is_empty() {
+            return None;
+        }
+
+        serde_cbor::to_vec(&dps)
+            .map_err(|err| debug!("Failed to serialize mirrored telemetry: {}", err))
+            .ok()
+    }
+
+    // The downlink port may not be listening outside of passes, so failures aren't worth
+    // more than a debug message
+    pub fn send(&self, datagram: &[u8]) {
+        if let Err(err) = self.socket.send_to(datagram, self.dest) {
+            debug!("Failed to mirror telemetry to {}: {}", self.dest, err);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Standing reports
+//
+// A report definition names a set of telemetry parameters, the aggregations to calculate for
+// each of them and how often the report should be produced. The flat database can't be read
+// back, so rather than querying it the reports are accumulated as telemetry arrives. At the end
+// of each period the results are written to a file in the report directory, ready to be
+// downlinked, and accumulation starts again.
+//
+// Periods are aligned to multiples of the period since the UNIX epoch, so a daily report
+// always covers a single UTC day, no matter when it was registered.
+//
+// Definitions are saved to the report directory so they survive a restart. Accumulated values
+// are not, so the first report after a restart only covers the time since the restart.
+
+use chrono::{DateTime, TimeZone, Utc};
+use juniper::{GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// File in the report directory which the report definitions are saved to
+const DEFINITIONS_FILE: &str = ".reports.json";
+
+// How often to check whether any reports are due
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Shortest allowed report period, in seconds
+const MIN_PERIOD: i32 = 60;
+
+/// Calculations which can be included in a report
+#[derive(Clone, Copy, Debug, Deserialize, Eq, GraphQLEnum, PartialEq, Serialize)]
+pub enum Aggregation {
+    /// Number of values received
+    Count,
+    /// Smallest value
+    Min,
+    /// Largest value
+    Max,
+    /// Mean of all values
+    Mean,
+    /// Most recent value
+    Last,
+}
+
+impl Aggregation {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Aggregation::Count => "count",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Mean => "mean",
+            Aggregation::Last => "last",
+        }
+    }
+}
+
+/// File format reports are written in
+#[derive(Clone, Copy, Debug, Deserialize, Eq, GraphQLEnum, PartialEq, Serialize)]
+pub enum ReportFormat {
+    /// One row per parameter, with a header row
+    Csv,
+    /// A single JSON object
+    Json,
+}
+
+impl ReportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+        }
+    }
+}
+
+/// Telemetry parameter included in a report
+#[derive(Clone, Debug, Deserialize, Eq, GraphQLObject, Hash, PartialEq, Serialize)]
+pub struct ReportParameter {
+    pub subsystem: String,
+    pub parameter: String,
+}
+
+/// Telemetry parameter to include in a new report
+#[derive(Clone, Debug, GraphQLInputObject)]
+pub struct ReportParameterInput {
+    pub subsystem: String,
+    pub parameter: String,
+}
+
+impl From<ReportParameterInput> for ReportParameter {
+    fn from(input: ReportParameterInput) -> Self {
+        ReportParameter {
+            subsystem: input.subsystem,
+            parameter: input.parameter,
+        }
+    }
+}
+
+/// Standing report definition
+#[derive(Clone, Debug, Deserialize, GraphQLObject, PartialEq, Serialize)]
+pub struct ReportDefinition {
+    pub name: String,
+    pub parameters: Vec<ReportParameter>,
+    pub aggregations: Vec<Aggregation>,
+    /// Number of seconds covered by each report
+    pub period_secs: i32,
+    pub format: ReportFormat,
+}
+
+// Values accumulated for a single parameter
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Stats {
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    last: f64,
+    last_time: i64,
+}
+
+impl Stats {
+    pub(crate) fn add(&mut self, timestamp: i64, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        if self.count == 0 || timestamp >= self.last_time {
+            self.last = value;
+            self.last_time = timestamp;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    pub(crate) fn get(&self, aggregation: Aggregation) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(match aggregation {
+            Aggregation::Count => self.count as f64,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Mean => self.sum / self.count as f64,
+            Aggregation::Last => self.last,
+        })
+    }
+}
+
+// A report definition along with the values accumulated for the current period
+struct ActiveReport {
+    definition: ReportDefinition,
+    period_start: i64,
+    stats: HashMap<ReportParameter, Stats>,
+}
+
+impl ActiveReport {
+    fn new(definition: ReportDefinition, now: i64) -> Self {
+        let period_start = period_start(now, definition.period_secs);
+        ActiveReport {
+            definition,
+            period_start,
+            stats: HashMap::new(),
+        }
+    }
+
+    fn period_end(&self) -> i64 {
+        self.period_start + i64::from(self.definition.period_secs)
+    }
+}
+
+// Start of the period containing the given time
+pub(crate) fn period_start(now: i64, period_secs: i32) -> i64 {
+    let period = i64::from(period_secs);
+    now - now.rem_euclid(period)
+}
+
+pub(crate) fn format_time(timestamp: i64) -> DateTime<Utc> {
+    Utc.timestamp(timestamp, 0)
+}
+
+/// Accumulates telemetry for the standing reports and writes them out when they're due
+pub struct ReportManager {
+    dir: PathBuf,
+    reports: Mutex<HashMap<String, ActiveReport>>,
+}
+
+impl ReportManager {
+    /// Creates a report manager which writes reports to the given directory, loading any
+    /// previously registered definitions
+    pub fn new(dir: &Path) -> Result<Self, String> {
+        fs::create_dir_all(dir)
+            .map_err(|err| format!("Failed to create report directory: {}", err))?;
+
+        let now = Utc::now().timestamp();
+        let definitions_path = dir.join(DEFINITIONS_FILE);
+        let definitions: Vec<ReportDefinition> = match fs::read(&definitions_path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("Failed to parse report definitions: {}", err))?,
+            Err(_) => vec![],
+        };
+
+        let reports = definitions
+            .into_iter()
+            .map(|def| (def.name.clone(), ActiveReport::new(def, now)))
+            .collect();
+
+        Ok(ReportManager {
+            dir: dir.to_owned(),
+            reports: Mutex::new(reports),
+        })
+    }
+
+    /// Starts the thread which writes out reports as they become due
+    pub fn start(manager: Arc<ReportManager>) {
+        thread::Builder::new()
+            .stack_size(32 * 1024)
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                manager.write_due(Utc::now().timestamp());
+            })
+            .unwrap();
+    }
+
+    /// Adds or replaces a report definition
+    pub fn register(&self, definition: ReportDefinition) -> Result<(), String> {
+        if definition.name.is_empty()
+            || !definition
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(
+                "Report names may only contain letters, numbers, '-' and '_'".to_owned(),
+            );
+        }
+        if definition.period_secs < MIN_PERIOD {
+            return Err(format!(
+                "Report period must be at least {} seconds",
+                MIN_PERIOD
+            ));
+        }
+        if definition.parameters.is_empty() || definition.aggregations.is_empty() {
+            return Err("Reports need at least one parameter and one aggregation".to_owned());
+        }
+
+        let mut reports = self.lock()?;
+        let now = Utc::now().timestamp();
+        reports.insert(
+            definition.name.clone(),
+            ActiveReport::new(definition, now),
+        );
+        self.save(&reports)
+    }
+
+    /// Removes a report definition
+    pub fn remove(&self, name: &str) -> Result<(), String> {
+        let mut reports = self.lock()?;
+        reports
+            .remove(name)
+            .ok_or_else(|| format!("Report '{}' not found", name))?;
+        self.save(&reports)
+    }
+
+    /// Returns all report definitions along with the start of their current periods
+    pub fn list(&self) -> Result<Vec<(ReportDefinition, i64)>, String> {
+        let reports = self.lock()?;
+        let mut list: Vec<(ReportDefinition, i64)> = reports
+            .values()
+            .map(|report| (report.definition.clone(), report.period_start))
+            .collect();
+        list.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        Ok(list)
+    }
+
+    /// Immediately writes out a report covering the current period so far.
+    /// Accumulation continues as normal afterwards
+    pub fn generate(&self, name: &str) -> Result<String, String> {
+        let reports = self.lock()?;
+        let report = reports
+            .get(name)
+            .ok_or_else(|| format!("Report '{}' not found", name))?;
+        let path = self.write(report, Utc::now().timestamp())?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Adds a telemetry value to every report which includes the parameter
+    pub fn record(&self, timestamp: i64, subsystem: &str, parameter: &str, value: f64) {
+        let mut reports = match self.reports.lock() {
+            Ok(reports) => reports,
+            Err(_) => return,
+        };
+
+        for report in reports.values_mut() {
+            // Values from before the current period (eg. delayed telemetry) aren't included
+            if timestamp < report.period_start {
+                continue;
+            }
+
+            if let Some(param) = report
+                .definition
+                .parameters
+                .iter()
+                .find(|p| p.subsystem == subsystem && p.parameter == parameter)
+            {
+                report
+                    .stats
+                    .entry(param.clone())
+                    .or_default()
+                    .add(timestamp, value);
+            }
+        }
+    }
+
+    // Write out every report whose period has ended and start the next period
+    fn write_due(&self, now: i64) {
+        let mut reports = match self.reports.lock() {
+            Ok(reports) => reports,
+            Err(_) => {
+                error!("Report mutex poisoned");
+                return;
+            }
+        };
+
+        for report in reports.values_mut() {
+            let end = report.period_end();
+            if now < end {
+                continue;
+            }
+
+            match self.write(report, end) {
+                Ok(path) => info!("Wrote report {}", path.display()),
+                Err(err) => warn!("Failed to write report {}: {}", report.definition.name, err),
+            }
+
+            report.period_start = period_start(now, report.definition.period_secs);
+            report.stats.clear();
+        }
+    }
+
+    fn write(&self, report: &ActiveReport, end: i64) -> Result<PathBuf, String> {
+        let def = &report.definition;
+        let start = format_time(report.period_start);
+        let path = self.dir.join(format!(
+            "{}_{}.{}",
+            def.name,
+            start.format("%Y%m%d%H%M%S"),
+            def.format.extension()
+        ));
+
+        let contents = match def.format {
+            ReportFormat::Csv => csv_report(report),
+            ReportFormat::Json => json_report(report, end),
+        };
+
+        fs::write(&path, contents).map_err(|err| err.to_string())?;
+        Ok(path)
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<HashMap<String, ActiveReport>>, String> {
+        self.reports
+            .lock()
+            .map_err(|_| "Report mutex poisoned".to_owned())
+    }
+
+    fn save(&self, reports: &HashMap<String, ActiveReport>) -> Result<(), String> {
+        let definitions: Vec<&ReportDefinition> =
+            reports.values().map(|report| &report.definition).collect();
+        let raw = serde_json::to_vec_pretty(&definitions).map_err(|err| err.to_string())?;
+        fs::write(self.dir.join(DEFINITIONS_FILE), raw)
+            .map_err(|err| format!("Failed to save report definitions: {}", err))
+    }
+}
+
+fn csv_report(report: &ActiveReport) -> String {
+    let def = &report.definition;
+    let mut out = String::from("subsystem,parameter");
+    for aggregation in &def.aggregations {
+        out.push(',');
+        out.push_str(aggregation.name());
+    }
+    out.push('\n');
+
+    for param in &def.parameters {
+        out.push_str(&format!("{},{}", param.subsystem, param.parameter));
+        let stats = report.stats.get(param);
+        for aggregation in &def.aggregations {
+            out.push(',');
+            if let Some(value) = stats.and_then(|stats| stats.get(*aggregation)) {
+                out.push_str(&value.to_string());
+            }
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+fn json_report(report: &ActiveReport, end: i64) -> String {
+    let def = &report.definition;
+    let parameters: Vec<serde_json::Value> = def
+        .parameters
+        .iter()
+        .map(|param| {
+            let mut entry = serde_json::Map::new();
+            entry.insert("subsystem".to_owned(), param.subsystem.clone().into());
+            entry.insert("parameter".to_owned(), param.parameter.clone().into());
+            let stats = report.stats.get(param);
+            for aggregation in &def.aggregations {
+                let value = stats.and_then(|stats| stats.get(*aggregation));
+                entry.insert(aggregation.name().to_owned(), value.into());
+            }
+            serde_json::Value::Object(entry)
+        })
+        .collect();
+
+    serde_json::json!({
+        "report": def.name,
+        "start": format_time(report.period_start).to_rfc3339(),
+        "end": format_time(end).to_rfc3339(),
+        "parameters": parameters,
+    })
+    .to_string()
+}
@@ -0,0 +1,297 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Continuous aggregation
+//
+// Trend queries covering weeks or months would have to read far too much raw telemetry on the OBC,
+// so 1-minute and 1-hour rollups of every numeric parameter are maintained as well. Like the
+// standing reports, they're accumulated as telemetry arrives rather than read from the database.
+// When a bucket closes, the minimum, mean and maximum of each parameter are inserted into separate
+// flat databases in the `rollups` directory beside the main database (eg.
+// `rollups/1h/mean/20200101120000.db`), timestamped with the start of the bucket. The rollup
+// databases have the same layout as the main one, so they can be read with the same tools.
+//
+// Buckets are left open for a little while after they end, so that slightly delayed telemetry is
+// still included. Values arriving after their bucket has been written are left out of the
+// rollups (but are still stored in the main database).
+//
+// Open buckets aren't saved, so rollups only cover the time since the service started.
+
+use crate::reports::{format_time, period_start, Aggregation, Stats};
+use crate::udp::{insert_data_points, DataPoint};
+use crate::unique_db_name;
+use chrono::Utc;
+use flat_db::{Builder, Database};
+use juniper::GraphQLEnum;
+use log::{debug, error, info};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Directory beside the main database which the rollup databases are kept in
+const ROLLUP_DIR: &str = "rollups";
+
+// The aggregations kept for each bucket, each in its own database
+const AGGREGATIONS: [Aggregation; 3] = [Aggregation::Min, Aggregation::Mean, Aggregation::Max];
+
+// How long to wait after a bucket ends before writing it, in seconds
+const SETTLE_TIME: i64 = 30;
+
+// How often to check whether any buckets are due to be written
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Longest time range, in seconds, which is read from the raw telemetry
+const MAX_RAW_SPAN: f64 = 6.0 * 60.0 * 60.0;
+
+// Longest time range, in seconds, which is read from the 1-minute rollups
+const MAX_MINUTE_SPAN: f64 = 7.0 * 24.0 * 60.0 * 60.0;
+
+/// Resolution of the telemetry read for a query
+#[derive(Clone, Copy, Debug, Eq, GraphQLEnum, PartialEq)]
+pub enum Resolution {
+    /// Every value received
+    Raw,
+    /// Minimum, mean and maximum of each minute
+    Minute,
+    /// Minimum, mean and maximum of each hour
+    Hour,
+}
+
+impl Resolution {
+    /// Coarsest resolution which still gives a useful number of points over the given
+    /// number of seconds
+    pub fn for_span(span: f64) -> Resolution {
+        if span <= MAX_RAW_SPAN {
+            Resolution::Raw
+        } else if span <= MAX_MINUTE_SPAN {
+            Resolution::Minute
+        } else {
+            Resolution::Hour
+        }
+    }
+
+    // Length of the resolution's buckets, in seconds. Raw telemetry isn't bucketed
+    fn period(self) -> i32 {
+        match self {
+            Resolution::Raw => 0,
+            Resolution::Minute => 60,
+            Resolution::Hour => 60 * 60,
+        }
+    }
+
+    fn dir_name(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "1m",
+            Resolution::Hour => "1h",
+        }
+    }
+}
+
+// The rollups at a single resolution
+struct RollupTable {
+    resolution: Resolution,
+    // A database for each of `AGGREGATIONS`, in the same order
+    databases: Vec<Database>,
+    // Values accumulated for each open bucket, keyed by the bucket's start time
+    buckets: BTreeMap<i64, HashMap<(String, String), Stats>>,
+    // Start of the oldest bucket which hasn't been written yet
+    open_from: i64,
+}
+
+impl RollupTable {
+    fn open(dir: &Path, resolution: Resolution, now: i64) -> Result<Self, String> {
+        let databases = AGGREGATIONS
+            .iter()
+            .map(|aggregation| {
+                let dir = dir.join(resolution.dir_name()).join(aggregation.name());
+                fs::create_dir_all(&dir)
+                    .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+
+                // `unique_db_name` replaces the file name with the current time
+                let path = unique_db_name(dir.join("rollup.db"));
+                Builder::new()
+                    .path(&path)
+                    .build()
+                    .map_err(|err| format!("Failed to open {}: {:?}", path.display(), err))
+            })
+            .collect::<Result<Vec<Database>, String>>()?;
+
+        Ok(RollupTable {
+            resolution,
+            databases,
+            buckets: BTreeMap::new(),
+            open_from: period_start(now, resolution.period()),
+        })
+    }
+
+    fn record(&mut self, timestamp: i64, subsystem: &str, parameter: &str, value: f64) {
+        let bucket = period_start(timestamp, self.resolution.period());
+        if bucket < self.open_from {
+            return;
+        }
+
+        self.buckets
+            .entry(bucket)
+            .or_default()
+            .entry((subsystem.to_owned(), parameter.to_owned()))
+            .or_default()
+            .add(timestamp, value);
+    }
+
+    // Write out every bucket which has had time to settle
+    fn write_due(&mut self, now: i64, read_only: bool) {
+        let period = i64::from(self.resolution.period());
+        while let Some(&start) = self.buckets.keys().next() {
+            if start + period + SETTLE_TIME > now {
+                break;
+            }
+
+            let bucket = self.buckets.remove(&start).unwrap_or_default();
+            self.open_from = start + period;
+
+            if read_only {
+                debug!("Read-only mode, dropping rollup bucket");
+                continue;
+            }
+
+            if let Err(err) = self.write(start, bucket) {
+                error!(
+                    "Failed to write {} rollups: {:?}",
+                    self.resolution.dir_name(),
+                    err
+                );
+            }
+        }
+
+        // Buckets which never received anything are closed too
+        self.open_from = self
+            .open_from
+            .max(period_start(now - SETTLE_TIME, self.resolution.period()));
+    }
+
+    fn write(
+        &self,
+        start: i64,
+        bucket: HashMap<(String, String), Stats>,
+    ) -> Result<(), flat_db::DbError> {
+        let time = format_time(start);
+        for (aggregation, database) in AGGREGATIONS.iter().zip(&self.databases) {
+            let dps = bucket
+                .iter()
+                .filter_map(|((subsystem, parameter), stats)| {
+                    stats.get(*aggregation).map(|value| {
+                        DataPoint(
+                            time,
+                            subsystem.to_owned(),
+                            parameter.to_owned(),
+                            value.into(),
+                        )
+                    })
+                })
+                .collect();
+            insert_data_points(database, dps)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maintains the 1-minute and 1-hour rollups of the incoming telemetry
+pub struct RollupManager {
+    dir: PathBuf,
+    tables: Mutex<Vec<RollupTable>>,
+}
+
+impl RollupManager {
+    /// Opens new rollup databases in the `rollups` directory beside the given main database
+    pub fn new(db_path: &Path) -> Result<Self, String> {
+        let dir = db_path
+            .parent()
+            .ok_or_else(|| "path does not have a parent".to_owned())?
+            .join(ROLLUP_DIR);
+
+        let now = Utc::now().timestamp();
+        let tables = [Resolution::Minute, Resolution::Hour]
+            .iter()
+            .map(|resolution| RollupTable::open(&dir, *resolution, now))
+            .collect::<Result<Vec<RollupTable>, String>>()?;
+
+        info!("Telemetry rollups in {}", dir.display());
+        Ok(RollupManager {
+            dir,
+            tables: Mutex::new(tables),
+        })
+    }
+
+    /// Starts the thread which writes out buckets as they close.
+    /// Nothing is written while the service is in read-only mode
+    pub fn start(manager: Arc<RollupManager>, read_only: Arc<AtomicBool>) {
+        thread::Builder::new()
+            .stack_size(32 * 1024)
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                manager.write_due(Utc::now().timestamp(), read_only.load(Ordering::SeqCst));
+            })
+            .unwrap();
+    }
+
+    /// Adds a telemetry value to the rollups
+    pub fn record(&self, timestamp: i64, subsystem: &str, parameter: &str, value: f64) {
+        if let Ok(mut tables) = self.tables.lock() {
+            for table in tables.iter_mut() {
+                table.record(timestamp, subsystem, parameter, value);
+            }
+        }
+    }
+
+    /// Directories holding the rollup databases for the given resolution
+    pub fn dirs(&self, resolution: Resolution) -> Vec<PathBuf> {
+        AGGREGATIONS
+            .iter()
+            .map(|aggregation| {
+                self.dir
+                    .join(resolution.dir_name())
+                    .join(aggregation.name())
+            })
+            .collect()
+    }
+
+    /// Flushes the rollup databases to storage
+    pub fn flush(&self) {
+        if let Ok(tables) = self.tables.lock() {
+            for database in tables.iter().flat_map(|table| &table.databases) {
+                if let Err(err) = database.flush() {
+                    error!("Failed to flush rollups: {:?}", err);
+                }
+            }
+        }
+    }
+
+    fn write_due(&self, now: i64, read_only: bool) {
+        match self.tables.lock() {
+            Ok(mut tables) => {
+                for table in tables.iter_mut() {
+                    table.write_due(now, read_only);
+                }
+            }
+            Err(_) => error!("Rollup mutex poisoned"),
+        }
+    }
+}
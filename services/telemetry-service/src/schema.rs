@@ -16,41 +16,408 @@
 
 use std::{
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
 };
 
-use crate::{udp::*, unique_db_name};
+use crate::{
+    auth::{InsertToken, InsertTokens},
+    catalog::{ParameterCatalog, ParameterInfo, ParameterInfoInput},
+    delete::{files_in_range, files_overlapping, remove_files, spans_in_range, DbSpan},
+    encryption::{encrypt_rotated, DbEncryption},
+    export::{export, ExportFormat, Exported},
+    flush::Flusher,
+    import::{ImportFormat, ImportLedger},
+    ingest::IngestHealth,
+    latest::{Fields, LatestValue, LatestValues, Order},
+    mirror::Mirror,
+    reports::{Aggregation, ReportDefinition, ReportFormat, ReportManager, ReportParameterInput},
+    rollups::{Resolution, RollupManager},
+    snapshot::{Snapshot, SnapshotLedger},
+    subsystems::SubsystemLedger,
+    syslog,
+    udp::*,
+    unique_db_name,
+};
+use chrono::Utc;
 use flat_db::Database;
 use git_version::git_version;
-use juniper::{FieldError, FieldResult, GraphQLObject, Value};
+use juniper::{FieldResult, GraphQLObject, LookAheadMethods};
 use kubos_service;
+use kubos_service::discovery::{self, ServiceInfo};
+use kubos_service::errors::{field_error, ErrorCode};
+use kubos_service::RequestRecord;
+use log::info;
 
 pub type Context = kubos_service::Context<Subsystem>;
 
+const READ_ONLY_ERROR: &str = "Service is in read-only mode";
+
 #[derive(Clone)]
 pub struct Subsystem {
     pub database: Arc<Database>,
     pub db_path: PathBuf,
+    pub deletes_enabled: bool,
+    pub reports: Option<Arc<ReportManager>>,
+    pub rollups: Option<Arc<RollupManager>>,
+    pub catalog: Option<Arc<ParameterCatalog>>,
+    pub read_only: Arc<AtomicBool>,
+    pub flusher: Arc<Flusher>,
+    pub udp: DirectUdp,
+    pub imports: Option<Arc<ImportLedger>>,
+    pub snapshots: Option<Arc<SnapshotLedger>>,
+    pub ingest: Arc<IngestHealth>,
+    pub tokens: Option<Arc<InsertTokens>>,
+    pub encryption: Option<Arc<DbEncryption>>,
+    pub latest: Arc<LatestValues>,
+    pub subsystems: Option<Arc<SubsystemLedger>>,
+}
+
+/// Optional settings and components of the telemetry database service
+pub struct SubsystemOptions {
+    /// Address to receive telemetry datagrams on, if any
+    pub direct_udp: Option<String>,
+    /// Address to receive syslog datagrams on, if any
+    pub syslog_udp: Option<String>,
+    pub deletes_enabled: bool,
+    /// Whether the service starts in read-only mode
+    pub read_only: bool,
+    pub reports: Option<ReportManager>,
+    pub rollups: Option<Arc<RollupManager>>,
+    pub catalog: Option<ParameterCatalog>,
+    pub tokens: Option<InsertTokens>,
+    pub mirror: Option<Mirror>,
+    pub imports: Option<ImportLedger>,
+    pub snapshots: Option<SnapshotLedger>,
+    pub subsystems: Option<SubsystemLedger>,
+    pub encryption: Option<DbEncryption>,
 }
 
 impl Subsystem {
-    pub fn new(database: Database, db_path: &Path, direct_udp: Option<String>) -> Self {
+    pub fn new(database: Database, db_path: &Path, options: SubsystemOptions) -> Self {
+        let SubsystemOptions {
+            direct_udp,
+            syslog_udp,
+            deletes_enabled,
+            read_only,
+            reports,
+            rollups,
+            catalog,
+            tokens,
+            mirror,
+            imports,
+            snapshots,
+            subsystems,
+            encryption,
+        } = options;
+
         let db = Arc::new(database);
         let db_path = db_path.to_owned();
+        let reports = reports.map(Arc::new);
+        let catalog = catalog.map(Arc::new);
+        let read_only = Arc::new(AtomicBool::new(read_only));
+        let flusher = Arc::new(Flusher::new(db.clone(), rollups.clone()));
+        let encryption = encryption.map(Arc::new);
+        let subsystems = subsystems.map(Arc::new);
+        let ingest = Arc::new(IngestHealth::new(
+            db.clone(),
+            db_path.clone(),
+            encryption.clone(),
+        ));
+        let tokens = tokens.map(Arc::new);
+        let latest = Arc::new(LatestValues::default());
+
+        if let Some(reports) = &reports {
+            ReportManager::start(reports.clone());
+        }
+
+        if let Some(rollups) = &rollups {
+            RollupManager::start(rollups.clone(), read_only.clone());
+        }
+
+        let udp = DirectUdp::new(
+            db.clone(),
+            DirectUdpState {
+                reports: reports.clone(),
+                rollups: rollups.clone(),
+                catalog: catalog.clone(),
+                read_only: read_only.clone(),
+                tokens: tokens.clone(),
+                flusher: flusher.clone(),
+                mirror: mirror.map(Arc::new),
+                ingest: ingest.clone(),
+                latest: latest.clone(),
+                subsystems: subsystems.clone(),
+            },
+        );
 
         if let Some(udp_url) = direct_udp {
-            let udp = DirectUdp::new(db.clone());
+            let udp = udp.clone();
             thread::Builder::new()
                 .stack_size(16 * 1024)
                 .spawn(move || udp.start(udp_url.to_owned()))
                 .unwrap();
         }
 
+        if let Some(syslog_url) = syslog_udp {
+            let udp = udp.clone();
+            thread::Builder::new()
+                .stack_size(16 * 1024)
+                .spawn(move || syslog::start(udp, syslog_url))
+                .unwrap();
+        }
+
         Subsystem {
             database: db,
             db_path,
+            deletes_enabled,
+            reports,
+            rollups,
+            catalog,
+            read_only,
+            flusher,
+            udp,
+            imports: imports.map(Arc::new),
+            snapshots: snapshots.map(Arc::new),
+            ingest,
+            tokens,
+            encryption,
+            latest,
+            subsystems,
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Places the service in (or takes it out of) read-only mode
+    pub fn set_read_only(&self, read_only: bool) {
+        if self.read_only.swap(read_only, Ordering::SeqCst) != read_only {
+            info!(
+                "Read-only mode {}",
+                if read_only { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            read_only: self.read_only(),
+            deletes_enabled: self.deletes_enabled,
+            ingesting: self.ingest.ingesting(),
+            db_recoveries: self.ingest.recoveries() as i32,
+            last_db_error: self.ingest.last_error(),
+        }
+    }
+
+    fn reports(&self) -> Result<&ReportManager, String> {
+        self.reports
+            .as_ref()
+            .map(|reports| reports.as_ref())
+            .ok_or_else(|| "Reports are not configured".to_owned())
+    }
+
+    // Picks the resolution and database files to read for a query covering the given time range.
+    // Listing the files means decrypting any encrypted ones, so it's skipped unless `files` is set
+    fn query_plan(
+        &self,
+        timestamp_ge: f64,
+        timestamp_le: f64,
+        files: bool,
+    ) -> Result<QueryPlan, String> {
+        if timestamp_ge > timestamp_le {
+            return Err("timestampGe must not be after timestampLe".to_owned());
+        }
+
+        let resolution = match &self.rollups {
+            Some(_) => Resolution::for_span(timestamp_le - timestamp_ge),
+            None => Resolution::Raw,
+        };
+        if !files {
+            return Ok(QueryPlan {
+                resolution,
+                files: vec![],
+            });
+        }
+
+        let dirs = match (&self.rollups, resolution) {
+            (Some(rollups), Resolution::Minute) | (Some(rollups), Resolution::Hour) => {
+                rollups.dirs(resolution)
+            }
+            _ => vec![self
+                .db_path
+                .parent()
+                .ok_or_else(|| "path does not have a parent".to_owned())?
+                .to_owned()],
+        };
+
+        let mut files = vec![];
+        for dir in dirs {
+            files.extend(
+                files_overlapping(&dir, timestamp_ge, timestamp_le)?
+                    .into_iter()
+                    .filter_map(|path| path.to_str().map(|s| s.to_owned())),
+            );
+        }
+
+        // Ground tools are given decrypted copies of any encrypted files
+        if let Some(encryption) = &self.encryption {
+            let root = self
+                .db_path
+                .parent()
+                .ok_or_else(|| "path does not have a parent".to_owned())?;
+            files = encryption.decrypt_for_query(root, files)?;
+        }
+
+        Ok(QueryPlan { resolution, files })
+    }
+
+    // Flushes and rotates the database, recording the new file as a snapshot's boundary
+    fn snapshot(&self) -> Result<Snapshot, String> {
+        let snapshots = self.snapshots()?;
+        self.flusher.flush()?;
+
+        snapshots.take(Utc::now().timestamp() as f64, || {
+            let new = self
+                .database
+                .rotate(unique_db_name(&self.db_path))
+                .map_err(|err| format!("Failed to rotate database: {}", err))?
+                .to_path_buf();
+            if let Some(encryption) = &self.encryption {
+                encrypt_rotated(encryption, new.clone());
+            }
+            Ok(new)
+        })
+    }
+
+    // Lists the database files holding the telemetry up to a snapshot, decrypting them if needed
+    fn snapshot_export(&self, sequence: i32, since: Option<i32>) -> Result<SnapshotExport, String> {
+        let root = self
+            .db_path
+            .parent()
+            .ok_or_else(|| "path does not have a parent".to_owned())?;
+        let (snapshot, files) = self.snapshots()?.files(root, sequence, since)?;
+
+        let mut files: Vec<String> = files
+            .into_iter()
+            .filter_map(|path| path.to_str().map(|s| s.to_owned()))
+            .collect();
+        if let Some(encryption) = &self.encryption {
+            files = encryption.decrypt_for_query(root, files)?;
+        }
+
+        Ok(SnapshotExport { snapshot, files })
+    }
+
+    // Packs database files listed by a query plan or snapshot export into a compressed archive
+    fn export_files(
+        &self,
+        files: &[String],
+        output: &str,
+        format: ExportFormat,
+        dry_run: bool,
+    ) -> Result<Exported, String> {
+        let root = self
+            .db_path
+            .parent()
+            .ok_or_else(|| "path does not have a parent".to_owned())?;
+        let mut roots = vec![root];
+        if let Some(encryption) = &self.encryption {
+            roots.push(encryption.decrypt_dir());
+        }
+
+        // Query plans list the file being written, which should hold whole records
+        if !dry_run {
+            self.flusher.flush()?;
+        }
+
+        export(&roots, files, Path::new(output), format, dry_run)
+    }
+
+    fn snapshots(&self) -> Result<&SnapshotLedger, String> {
+        self.snapshots
+            .as_ref()
+            .map(|snapshots| snapshots.as_ref())
+            .ok_or_else(|| "Snapshots are not available".to_owned())
+    }
+
+    fn imports(&self) -> Result<&ImportLedger, String> {
+        self.imports
+            .as_ref()
+            .map(|imports| imports.as_ref())
+            .ok_or_else(|| "Imports are not available".to_owned())
+    }
+
+    fn catalog(&self) -> Result<&ParameterCatalog, String> {
+        self.catalog
+            .as_ref()
+            .map(|catalog| catalog.as_ref())
+            .ok_or_else(|| "Parameter catalog is not available".to_owned())
+    }
+
+    // Checks the secret passed to a mutation which changes telemetry. When insert tokens are
+    // configured one must be given, and the matching token is returned so that the subsystems
+    // being changed can be checked against it
+    fn authorize(&self, secret: Option<String>) -> Result<Option<&InsertToken>, String> {
+        let tokens = match &self.tokens {
+            Some(tokens) => tokens,
+            None => return Ok(None),
+        };
+        let secret = secret.ok_or_else(|| "A token is needed to change telemetry".to_owned())?;
+        tokens
+            .find(&secret)
+            .map(Some)
+            .ok_or_else(|| "Unknown token".to_owned())
+    }
+
+    // Like `authorize`, but for subsystem-specific changes, which the token must cover
+    fn authorize_subsystems<'a>(
+        &self,
+        secret: Option<String>,
+        mut subsystems: impl Iterator<Item = &'a str>,
+    ) -> Result<(), String> {
+        match self.authorize(secret)? {
+            Some(token) => match subsystems.find(|subsystem| !token.allows(subsystem)) {
+                Some(subsystem) => Err(format!(
+                    "Token '{}' may not change telemetry for {}",
+                    token.name, subsystem
+                )),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Like `authorize`, but for deleting database files. Each file holds every subsystem's
+    // telemetry, so tokens limited to some subsystems can't delete them
+    fn authorize_deletes(&self, secret: Option<String>) -> Result<(), String> {
+        match self.authorize(secret)? {
+            Some(token) if token.is_restricted() => Err(format!(
+                "Token '{}' is limited to some subsystems, so may not delete database files",
+                token.name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Picks out the database files which hold nothing but the given subsystem's telemetry
+    fn subsystem_files(&self, subsystem: &str, spans: Vec<DbSpan>) -> Result<Vec<PathBuf>, String> {
+        let ledger = self
+            .subsystems
+            .as_ref()
+            .ok_or_else(|| "Deleting telemetry by subsystem is not available".to_owned())?;
+
+        let mut files = vec![];
+        for span in spans {
+            if ledger.holds_only(subsystem, span.start, span.end)? {
+                files.push(span.path);
+            }
         }
+        Ok(files)
     }
 }
 
@@ -69,6 +436,58 @@ impl QueryRoot {
         Ok(String::from("pong"))
     }
 
+    // Services listed in the system configuration file,
+    // along with whether they answered a ping
+    //
+    // {
+    //     services {
+    //         name: String,
+    //         ip: String,
+    //         port: Int,
+    //         healthy: Boolean
+    //     }
+    // }
+    /// Known services and their health
+    fn services() -> FieldResult<Vec<ServiceInfo>> {
+        Ok(discovery::services("telemetry-service")?)
+    }
+
+    // Current write-protection state of the service
+    //
+    // {
+    //     health {
+    //         readOnly: Boolean,
+    //         deletesEnabled: Boolean,
+    //         ingesting: Boolean,
+    //         dbRecoveries: Int,
+    //         lastDbError: String
+    //     }
+    // }
+    /// Whether the service is accepting inserts and deletes, and storing incoming telemetry
+    fn health(context: &Context) -> FieldResult<Health> {
+        Ok(context.subsystem().health())
+    }
+
+    // The last few requests the service answered, newest first.
+    // Empty unless `recent_requests` is set in the service's config
+    //
+    // {
+    //     recentRequests {
+    //         timestamp: Float,
+    //         peer: String,
+    //         traceId: String,
+    //         request: String,
+    //         requestSize: Int,
+    //         responseSize: Int,
+    //         duration: Float,
+    //         errors: [String]
+    //     }
+    // }
+    /// Requests recently received by the service
+    fn recent_requests(context: &Context) -> FieldResult<Vec<RequestRecord>> {
+        Ok(context.recent_requests())
+    }
+
     // fn files(context: &Context) -> FieldResult<Vec<String>> {
     //     let db_path = context.subsystem().db_path.to_owned();
     //     let mut hash_cache_path = context.subsystem().db_path.to_owned();
@@ -100,6 +519,153 @@ impl QueryRoot {
     //         .collect())
     // }
 
+    /// Standing report definitions
+    fn reports(context: &Context) -> FieldResult<Vec<ReportStatus>> {
+        let reports = context.subsystem().reports()?;
+        Ok(reports
+            .list()?
+            .into_iter()
+            .map(|(definition, period_start)| ReportStatus {
+                next_report: (period_start + i64::from(definition.period_secs)) as f64,
+                period_start: period_start as f64,
+                definition,
+            })
+            .collect())
+    }
+
+    // Metadata of the telemetry parameters, optionally only those of one subsystem
+    //
+    // {
+    //     parameters(subsystem: String) {
+    //         subsystem: String,
+    //         parameter: String,
+    //         units: String,
+    //         description: String,
+    //         dataType: DataType,
+    //         min: Float,
+    //         max: Float,
+    //         encoding: Encoding,
+    //         scale: Float,
+    //         offset: Float
+    //     }
+    // }
+    /// Parameter metadata catalog
+    fn parameters(context: &Context, subsystem: Option<String>) -> FieldResult<Vec<ParameterInfo>> {
+        Ok(context.subsystem().catalog()?.list(subsystem.as_deref())?)
+    }
+
+    // Most recent value received for each of a subsystem's parameters (or every subsystem's),
+    // optionally only for the given parameters. Listed newest first, unless `order` is `ASC`.
+    // Only telemetry received since the service started is known. Only the fields the query
+    // selects are built
+    //
+    // {
+    //     latest(subsystem: String, parameters: [String], order: Order = DESC) {
+    //         timestamp: Float,
+    //         subsystem: String,
+    //         parameter: String,
+    //         value: String
+    //     }
+    // }
+    /// Latest telemetry values
+    fn latest(
+        context: &Context,
+        executor: &Executor,
+        subsystem: Option<String>,
+        parameters: Option<Vec<String>>,
+        order: Option<Order>,
+    ) -> FieldResult<Vec<LatestValue>> {
+        let selection = executor.look_ahead();
+        let fields = Fields {
+            subsystem: selection.has_child("subsystem"),
+            parameter: selection.has_child("parameter"),
+            value: selection.has_child("value"),
+        };
+        Ok(context.subsystem().latest.get(
+            subsystem.as_deref(),
+            parameters.as_deref(),
+            order.unwrap_or(Order::Desc),
+            fields,
+        ))
+    }
+
+    // The same values as `latest`, packed into a base64-encoded CBOR array for downlink:
+    // the first value's timestamp (milliseconds since the UNIX epoch), then for each value,
+    // oldest first, the milliseconds since the previous value, its telemetry map parameter ID
+    // and the value. Parameters without an ID in the telemetry map are left out
+    //
+    // {
+    //     latestPacked(subsystem: String, parameters: [String]): String
+    // }
+    /// Latest telemetry values, packed for downlink
+    fn latest_packed(
+        context: &Context,
+        subsystem: Option<String>,
+        parameters: Option<Vec<String>>,
+    ) -> FieldResult<String> {
+        let packed = context.subsystem().latest.packed(
+            subsystem.as_deref(),
+            parameters.as_deref(),
+            |subsystem, parameter| telemetry_map::get_id((subsystem, parameter)),
+        )?;
+        Ok(base64::encode(packed))
+    }
+
+    // Resolution and database files to read for a query covering a time range
+    // (seconds since the UNIX epoch). Long ranges are read from the rollups,
+    // if they're enabled. The files are only listed if the query selects them
+    //
+    // {
+    //     queryPlan(timestampGe: Float, timestampLe: Float) {
+    //         resolution: Resolution,
+    //         files: [String]
+    //     }
+    // }
+    /// Telemetry to read for a time range
+    fn query_plan(
+        context: &Context,
+        executor: &Executor,
+        timestamp_ge: f64,
+        timestamp_le: f64,
+    ) -> FieldResult<QueryPlan> {
+        let files = executor.look_ahead().has_child("files");
+        Ok(context
+            .subsystem()
+            .query_plan(timestamp_ge, timestamp_le, files)?)
+    }
+
+    // Snapshots which have been taken, oldest first
+    //
+    // {
+    //     snapshots {
+    //         sequence: Int,
+    //         timestamp: Float,
+    //         boundary: String
+    //     }
+    // }
+    /// Consistent points taken in the telemetry database
+    fn snapshots(context: &Context) -> FieldResult<Vec<Snapshot>> {
+        Ok(context.subsystem().snapshots()?.list()?)
+    }
+
+    // Database files holding exactly the telemetry inserted before a snapshot, or only that
+    // inserted between snapshot `since` and it, if given
+    //
+    // {
+    //     snapshotExport(sequence: Int!, since: Int) {
+    //         snapshot: Snapshot,
+    //         files: [String]
+    //     }
+    // }
+    /// Telemetry to export for a snapshot
+    fn snapshot_export(
+        context: &Context,
+        sequence: i32,
+        since: Option<i32>,
+    ) -> FieldResult<SnapshotExport> {
+        Ok(context.subsystem().snapshot_export(sequence, since)?)
+    }
+
     fn git() -> ServiceGitHash {
         ServiceGitHash {
             name: "telemetry-service",
@@ -108,6 +674,46 @@ impl QueryRoot {
     }
 }
 
+#[derive(GraphQLObject)]
+pub struct ReportStatus {
+    definition: ReportDefinition,
+    /// Start of the time covered by the next report
+    period_start: f64,
+    /// When the next report will be written
+    next_report: f64,
+}
+
+#[derive(GraphQLObject)]
+pub struct QueryPlan {
+    /// Resolution of the telemetry in the files
+    resolution: Resolution,
+    /// Database files holding the time range. For rollups, the files holding the minimum,
+    /// mean and maximum values are listed in that order
+    files: Vec<String>,
+}
+
+#[derive(GraphQLObject)]
+pub struct SnapshotExport {
+    snapshot: Snapshot,
+    /// Database files holding the snapshot's telemetry, oldest first
+    files: Vec<String>,
+}
+
+#[derive(GraphQLObject)]
+pub struct Health {
+    /// Inserts and deletes are being rejected
+    read_only: bool,
+    /// Deletes are allowed by the service config (when not read-only)
+    deletes_enabled: bool,
+    /// Telemetry received on the direct UDP and syslog ports is being stored. False while the
+    /// database can't be written to and couldn't be recovered, when telemetry is dropped
+    ingesting: bool,
+    /// Times the database has been rotated to recover from an IO error
+    db_recoveries: i32,
+    /// The last IO error returned by the database, if any
+    last_db_error: Option<String>,
+}
+
 #[derive(GraphQLObject)]
 pub struct ServiceGitHash {
     name: &'static str,
@@ -118,16 +724,159 @@ pub struct MutationRoot;
 
 #[juniper::object(Context = Context)]
 impl MutationRoot {
+    /// Delete all telemetry between two timestamps (seconds since the UNIX epoch).
+    /// Only whole database files which fall entirely within the range are deleted.
+    /// With `subsystem`, only the files holding nothing but that subsystem's telemetry are
+    /// deleted.
+    /// With `dryRun`, nothing is deleted and the files which would have been are returned.
+    /// When insert tokens are configured, `token` must be the secret of one which isn't limited
+    /// to some subsystems, or, with `subsystem`, of one which covers that subsystem.
+    /// eg:
+    /// graphql `mutation{deleteRange(timestampGe: 1577836800, timestampLe: 1580515200, subsystem: "eps", dryRun: true){success, errors, filesDeleted, files}}`
+    fn delete_range(
+        context: &Context,
+        timestamp_ge: f64,
+        timestamp_le: f64,
+        subsystem: Option<String>,
+        dry_run: Option<bool>,
+        token: Option<String>,
+    ) -> FieldResult<DeleteResult> {
+        let dry_run = dry_run.unwrap_or(false);
+
+        if !context.subsystem().deletes_enabled {
+            return Ok(DeleteResult::failure(dry_run, "Deletes are disabled"));
+        }
+
+        let authorized = match &subsystem {
+            Some(subsystem) => context
+                .subsystem()
+                .authorize_subsystems(token, std::iter::once(subsystem.as_str())),
+            None => context.subsystem().authorize_deletes(token),
+        };
+        if let Err(err) = authorized {
+            return Ok(DeleteResult::failure(dry_run, &err));
+        }
+
+        if context.subsystem().read_only() && !dry_run {
+            return Ok(DeleteResult::failure(dry_run, READ_ONLY_ERROR));
+        }
+
+        if timestamp_ge > timestamp_le {
+            return Ok(DeleteResult::failure(
+                dry_run,
+                "timestampGe must not be after timestampLe",
+            ));
+        }
+
+        let spans = match spans_in_range(&context.subsystem().db_path, timestamp_ge, timestamp_le) {
+            Ok(spans) => spans,
+            Err(err) => return Ok(DeleteResult::failure(dry_run, &err)),
+        };
+
+        let files = match &subsystem {
+            Some(subsystem) => match context.subsystem().subsystem_files(subsystem, spans) {
+                Ok(files) => files,
+                Err(err) => return Ok(DeleteResult::failure(dry_run, &err)),
+            },
+            None => spans.into_iter().map(|span| span.path).collect(),
+        };
+
+        let removed = remove_files(&files, dry_run);
+
+        Ok(DeleteResult {
+            success: removed.errors.is_empty(),
+            errors: removed.errors.join(", "),
+            dry_run,
+            files_deleted: removed.files.len() as i32,
+            files: removed.files,
+        })
+    }
+
+    /// Delete the database files which were last written to more than `olderThanDays` days ago.
+    /// The database currently being written to is never deleted.
+    /// With `dryRun`, nothing is deleted and the files which would have been are returned.
+    /// Takes a `token` like `deleteRange` without a `subsystem`.
+    /// eg:
+    /// graphql `mutation{pruneFiles(olderThanDays: 30, dryRun: true){success, errors, files, totalBytes}}`
+    fn prune_files(
+        context: &Context,
+        older_than_days: f64,
+        dry_run: Option<bool>,
+        token: Option<String>,
+    ) -> FieldResult<PruneResult> {
+        let dry_run = dry_run.unwrap_or(false);
+
+        if !context.subsystem().deletes_enabled {
+            return Ok(PruneResult::failure(dry_run, "Deletes are disabled"));
+        }
+
+        if let Err(err) = context.subsystem().authorize_deletes(token) {
+            return Ok(PruneResult::failure(dry_run, &err));
+        }
+
+        if context.subsystem().read_only() && !dry_run {
+            return Ok(PruneResult::failure(dry_run, READ_ONLY_ERROR));
+        }
+
+        if older_than_days < 0.0 {
+            return Ok(PruneResult::failure(
+                dry_run,
+                "olderThanDays must not be negative",
+            ));
+        }
+
+        // Files whose span of time ends before the cutoff
+        let cutoff = Utc::now().timestamp() as f64 - older_than_days * 86400.0;
+        let files =
+            match files_in_range(&context.subsystem().db_path, std::f64::NEG_INFINITY, cutoff) {
+                Ok(files) => files,
+                Err(err) => return Ok(PruneResult::failure(dry_run, &err)),
+            };
+
+        let removed = remove_files(&files, dry_run);
+        if !dry_run && !removed.files.is_empty() {
+            info!(
+                "Pruned {} files older than {} days",
+                removed.files.len(),
+                older_than_days
+            );
+        }
+
+        Ok(PruneResult {
+            success: removed.errors.is_empty(),
+            errors: removed.errors.join(", "),
+            dry_run,
+            files: removed.files,
+            total_bytes: removed.bytes as f64,
+        })
+    }
+
     /// This only allows deleting files from the DB directory.
+    /// Takes a `token` like `deleteRange` without a `subsystem`.
     /// eg:
     /// to delete "/sdcard/telemetry/123456789.db"
     /// graphql `mutation{delete(files:["123456789.db"])}`
-    fn delete(context: &Context, files: Vec<String>) -> FieldResult<Vec<String>> {
+    fn delete(
+        context: &Context,
+        files: Vec<String>,
+        token: Option<String>,
+    ) -> FieldResult<Vec<String>> {
+        if !context.subsystem().deletes_enabled {
+            return Err(field_error(ErrorCode::NotPermitted, "Deletes are disabled"));
+        }
+
+        if let Err(err) = context.subsystem().authorize_deletes(token) {
+            return Err(field_error(ErrorCode::NotPermitted, &err));
+        }
+
+        if context.subsystem().read_only() {
+            return Err(field_error(ErrorCode::NotPermitted, READ_ONLY_ERROR));
+        }
+
         let db_path = context.subsystem().db_path.to_owned();
-        let dir = db_path.parent().ok_or(FieldError::new(
-            "path does not have a parent",
-            Value::null(),
-        ))?;
+        let dir = db_path
+            .parent()
+            .ok_or_else(|| field_error(ErrorCode::Internal, "path does not have a parent"))?;
 
         Ok(files
             .iter()
@@ -141,7 +890,203 @@ impl MutationRoot {
             .collect())
     }
 
+    /// Register a standing report, replacing any existing report with the same name.
+    /// At the end of every period, the requested aggregations of each parameter's values are
+    /// written to a file in the report directory.
+    /// eg:
+    /// graphql `mutation{registerReport(name: "daily-power", parameters: [{subsystem: "eps", parameter: "voltage"}], aggregations: [MIN, MAX, MEAN], periodSecs: 86400, format: CSV){success, errors}}`
+    fn register_report(
+        context: &Context,
+        name: String,
+        parameters: Vec<ReportParameterInput>,
+        aggregations: Vec<Aggregation>,
+        period_secs: i32,
+        format: Option<ReportFormat>,
+    ) -> FieldResult<ReportResult> {
+        let definition = ReportDefinition {
+            name,
+            parameters: parameters.into_iter().map(|param| param.into()).collect(),
+            aggregations,
+            period_secs,
+            format: format.unwrap_or(ReportFormat::Csv),
+        };
+
+        Ok(ReportResult::from(
+            context
+                .subsystem()
+                .reports()
+                .and_then(|reports| reports.register(definition))
+                .map(|_| None),
+        ))
+    }
+
+    /// Remove a standing report
+    fn remove_report(context: &Context, name: String) -> FieldResult<ReportResult> {
+        Ok(ReportResult::from(
+            context
+                .subsystem()
+                .reports()
+                .and_then(|reports| reports.remove(&name))
+                .map(|_| None),
+        ))
+    }
+
+    /// Immediately write out a report covering its current period so far
+    fn generate_report(context: &Context, name: String) -> FieldResult<ReportResult> {
+        Ok(ReportResult::from(
+            context
+                .subsystem()
+                .reports()
+                .and_then(|reports| reports.generate(&name))
+                .map(Some),
+        ))
+    }
+
+    /// Add entries to the parameter catalog, replacing any existing entries for the same
+    /// subsystem/parameter pairs. Either all of the entries are registered, or none are.
+    /// When insert tokens are configured, `token` must be the secret of one which covers each
+    /// entry's subsystem.
+    /// eg:
+    /// graphql `mutation{registerParameters(entries: [{subsystem: "eps", parameter: "voltage", units: "V", dataType: FLOAT, min: 6.0, max: 8.4}]){success, errors}}`
+    fn register_parameters(
+        context: &Context,
+        entries: Vec<ParameterInfoInput>,
+        token: Option<String>,
+    ) -> FieldResult<CatalogResult> {
+        if context.subsystem().read_only() {
+            return Ok(CatalogResult::from(Err(READ_ONLY_ERROR.to_owned())));
+        }
+
+        if let Err(err) = context
+            .subsystem()
+            .authorize_subsystems(token, entries.iter().map(|entry| entry.subsystem.as_str()))
+        {
+            return Ok(CatalogResult::from(Err(err)));
+        }
+
+        Ok(CatalogResult::from(
+            context.subsystem().catalog().and_then(|catalog| {
+                catalog.register(entries.into_iter().map(|entry| entry.into()).collect())
+            }),
+        ))
+    }
+
+    /// Remove an entry from the parameter catalog. Takes a `token` like `registerParameters`
+    fn remove_parameter(
+        context: &Context,
+        subsystem: String,
+        parameter: String,
+        token: Option<String>,
+    ) -> FieldResult<CatalogResult> {
+        if context.subsystem().read_only() {
+            return Ok(CatalogResult::from(Err(READ_ONLY_ERROR.to_owned())));
+        }
+
+        if let Err(err) = context
+            .subsystem()
+            .authorize_subsystems(token, std::iter::once(subsystem.as_str()))
+        {
+            return Ok(CatalogResult::from(Err(err)));
+        }
+
+        Ok(CatalogResult::from(
+            context
+                .subsystem()
+                .catalog()
+                .and_then(|catalog| catalog.remove(&subsystem, &parameter)),
+        ))
+    }
+
+    /// Import telemetry from a CSV or CBOR file on the OBC (eg. recovered from a payload's SD
+    /// card) into the database. Every entry is checked first, and nothing is imported if any
+    /// are rejected. Repeated entries are skipped, and a file can only be imported once.
+    /// With `dryRun`, the file is only checked. When insert tokens are configured, `token` must
+    /// be the secret of one, and entries for subsystems it doesn't cover are rejected.
+    /// eg:
+    /// graphql `mutation{importFile(path: "/sdcard/payload.csv", format: CSV, dryRun: true){success, errors, pointsImported, duplicates, rejected}}`
+    fn import_file(
+        context: &Context,
+        path: String,
+        format: ImportFormat,
+        dry_run: Option<bool>,
+        token: Option<String>,
+    ) -> FieldResult<ImportResult> {
+        let dry_run = dry_run.unwrap_or(false);
+        let subsystem = context.subsystem();
+
+        if subsystem.read_only() && !dry_run {
+            return Ok(ImportResult::failure(dry_run, READ_ONLY_ERROR));
+        }
+
+        let token = match subsystem.authorize(token) {
+            Ok(token) => token,
+            Err(err) => return Ok(ImportResult::failure(dry_run, &err)),
+        };
+
+        let imported = subsystem
+            .imports()
+            .and_then(|imports| imports.import(&subsystem.udp, &path, format, dry_run, token));
+
+        Ok(match imported {
+            Ok(imported) => ImportResult {
+                success: imported.rejected == 0,
+                errors: imported.errors.join(", "),
+                dry_run,
+                points_imported: imported.points as i32,
+                duplicates: imported.duplicates as i32,
+                rejected: imported.rejected as i32,
+            },
+            Err(err) => ImportResult::failure(dry_run, &err),
+        })
+    }
+
+    /// Place the service in (or take it out of) read-only mode, for use during critical
+    /// operations or when the storage medium is degraded. While read-only, incoming telemetry
+    /// is dropped and deletes and rotations are rejected.
+    /// Takes a `token` like `delete`, since leaving read-only mode lets files be deleted again.
+    /// eg:
+    /// graphql `mutation{setReadOnly(readOnly: true){readOnly, deletesEnabled}}`
+    fn set_read_only(
+        context: &Context,
+        read_only: bool,
+        token: Option<String>,
+    ) -> FieldResult<Health> {
+        let subsystem = context.subsystem();
+        if let Err(err) = subsystem.authorize_deletes(token) {
+            return Err(field_error(ErrorCode::NotPermitted, &err));
+        }
+
+        subsystem.set_read_only(read_only);
+        Ok(subsystem.health())
+    }
+
+    /// Flush the telemetry buffered in memory (and any rollups) to storage, eg. before a planned
+    /// power cycle. Returns the number of points which were buffered.
+    /// eg:
+    /// graphql `mutation{flush{success, errors, pointsFlushed}}`
+    fn flush(context: &Context) -> FieldResult<FlushResult> {
+        Ok(match context.subsystem().flusher.flush() {
+            Ok(points) => {
+                info!("Flushed {} points", points);
+                FlushResult {
+                    success: true,
+                    errors: String::new(),
+                    points_flushed: points as i32,
+                }
+            }
+            Err(errors) => FlushResult {
+                success: false,
+                errors,
+                points_flushed: 0,
+            },
+        })
+    }
+
     fn rotate(context: &Context) -> FieldResult<RotateResult> {
+        if context.subsystem().read_only() {
+            return Err(field_error(ErrorCode::NotPermitted, READ_ONLY_ERROR));
+        }
+
         let old_path = context.subsystem().db_path.to_owned();
         let db_path: PathBuf = old_path.clone();
 
@@ -149,11 +1094,231 @@ impl MutationRoot {
         let db_path = unique_db_name(db_path);
 
         let new = context.subsystem().database.rotate(db_path)?;
+        if let Some(encryption) = &context.subsystem().encryption {
+            encrypt_rotated(encryption, new.to_path_buf());
+        }
 
         let old_path = old_path.to_str().unwrap().to_owned();
         let new = new.to_str().unwrap().to_owned();
         Ok(RotateResult { old: old_path, new })
     }
+
+    /// Mark a consistent point in the telemetry database for export. The database is flushed and
+    /// rotated, so the telemetry inserted before the snapshot is in closed files while inserts
+    /// carry on into a new one. Returns the snapshot, whose sequence number can be passed to the
+    /// `snapshotExport` query.
+    /// eg:
+    /// graphql `mutation{snapshot{success, errors, snapshot{sequence, timestamp}}}`
+    fn snapshot(context: &Context) -> FieldResult<SnapshotResult> {
+        let subsystem = context.subsystem();
+        if subsystem.read_only() {
+            return Ok(SnapshotResult::from(Err(READ_ONLY_ERROR.to_owned())));
+        }
+
+        Ok(SnapshotResult::from(subsystem.snapshot()))
+    }
+
+    /// Pack database files listed by the `queryPlan` or `snapshotExport` query into a tar
+    /// archive at `output` for downlink, compressed with gzip or zstd. Files are streamed into
+    /// the archive, so memory use stays bounded however large the export. The estimated size of
+    /// the archive is always returned. With `dryRun`, only the estimate is made.
+    /// eg:
+    /// graphql `mutation{exportFiles(files: ["/sdcard/telemetry/20200101000000.db"], output: "/sdcard/export.tar.zst", format: ZSTD, dryRun: true){success, errors, rawBytes, estimatedBytes}}`
+    fn export_files(
+        context: &Context,
+        files: Vec<String>,
+        output: String,
+        format: Option<ExportFormat>,
+        dry_run: Option<bool>,
+    ) -> FieldResult<ExportResult> {
+        let dry_run = dry_run.unwrap_or(false);
+        let format = format.unwrap_or(ExportFormat::Gzip);
+
+        let exported = context
+            .subsystem()
+            .export_files(&files, &output, format, dry_run);
+
+        Ok(match exported {
+            Ok(exported) => ExportResult {
+                success: true,
+                errors: String::new(),
+                dry_run,
+                raw_bytes: exported.raw_bytes as f64,
+                estimated_bytes: exported.estimated_bytes as f64,
+                bytes: exported.bytes as f64,
+            },
+            Err(errors) => ExportResult {
+                success: false,
+                errors,
+                dry_run,
+                raw_bytes: 0.0,
+                estimated_bytes: 0.0,
+                bytes: 0.0,
+            },
+        })
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct DeleteResult {
+    success: bool,
+    errors: String,
+    dry_run: bool,
+    files_deleted: i32,
+    files: Vec<String>,
+}
+
+impl DeleteResult {
+    fn failure(dry_run: bool, errors: &str) -> Self {
+        DeleteResult {
+            success: false,
+            errors: errors.to_owned(),
+            dry_run,
+            files_deleted: 0,
+            files: vec![],
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct PruneResult {
+    success: bool,
+    errors: String,
+    dry_run: bool,
+    /// Files which were deleted (or would have been, in a dry run)
+    files: Vec<String>,
+    /// Total size of the files, in bytes
+    total_bytes: f64,
+}
+
+impl PruneResult {
+    fn failure(dry_run: bool, errors: &str) -> Self {
+        PruneResult {
+            success: false,
+            errors: errors.to_owned(),
+            dry_run,
+            files: vec![],
+            total_bytes: 0.0,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct ImportResult {
+    success: bool,
+    errors: String,
+    dry_run: bool,
+    /// Points which were imported (or would have been, in a dry run)
+    points_imported: i32,
+    /// Entries skipped because an earlier entry had the same timestamp, subsystem and parameter
+    duplicates: i32,
+    /// Entries which failed validation. The first few are described in `errors`
+    rejected: i32,
+}
+
+impl ImportResult {
+    fn failure(dry_run: bool, errors: &str) -> Self {
+        ImportResult {
+            success: false,
+            errors: errors.to_owned(),
+            dry_run,
+            points_imported: 0,
+            duplicates: 0,
+            rejected: 0,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct ReportResult {
+    success: bool,
+    errors: String,
+    /// Report file which was written, if any
+    file: Option<String>,
+}
+
+impl From<Result<Option<String>, String>> for ReportResult {
+    fn from(result: Result<Option<String>, String>) -> Self {
+        match result {
+            Ok(file) => ReportResult {
+                success: true,
+                errors: String::new(),
+                file,
+            },
+            Err(errors) => ReportResult {
+                success: false,
+                errors,
+                file: None,
+            },
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct CatalogResult {
+    success: bool,
+    errors: String,
+}
+
+impl From<Result<(), String>> for CatalogResult {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => CatalogResult {
+                success: true,
+                errors: String::new(),
+            },
+            Err(errors) => CatalogResult {
+                success: false,
+                errors,
+            },
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct FlushResult {
+    success: bool,
+    errors: String,
+    /// Points which were buffered in memory when the database was flushed
+    points_flushed: i32,
+}
+
+#[derive(GraphQLObject)]
+pub struct SnapshotResult {
+    success: bool,
+    errors: String,
+    /// The snapshot which was taken, if any
+    snapshot: Option<Snapshot>,
+}
+
+impl From<Result<Snapshot, String>> for SnapshotResult {
+    fn from(result: Result<Snapshot, String>) -> Self {
+        match result {
+            Ok(snapshot) => SnapshotResult {
+                success: true,
+                errors: String::new(),
+                snapshot: Some(snapshot),
+            },
+            Err(errors) => SnapshotResult {
+                success: false,
+                errors,
+                snapshot: None,
+            },
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct ExportResult {
+    success: bool,
+    errors: String,
+    dry_run: bool,
+    /// Total size of the exported files, in bytes
+    raw_bytes: f64,
+    /// Estimated size of the archive, in bytes
+    estimated_bytes: f64,
+    /// Size of the archive written, in bytes. Zero in a dry run
+    bytes: f64,
 }
 
 #[derive(GraphQLObject)]
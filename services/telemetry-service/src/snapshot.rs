@@ -0,0 +1,164 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Consistent snapshots of the telemetry database for export
+//
+// Telemetry keeps arriving while earlier telemetry is being downlinked, so "everything so far" has
+// no well-defined end: the database file being written keeps growing, and a copy of it taken
+// mid-write may end in a torn record. The `snapshot` mutation fixes the end. It flushes the
+// database and rotates it, so every point inserted before the snapshot is in a closed file and
+// every point inserted afterwards goes to the new one. Each snapshot gets the next sequence
+// number, and is recorded along with the name of that new file (its boundary) in a ledger next to
+// the database.
+//
+// Database file names sort in the order the files were written, so the data up to a snapshot is
+// exactly the files before its boundary, and the data between two snapshots is the files from the
+// first one's boundary up to the second's. This holds whether or not the files have since been
+// encrypted, but files which have been deleted are no longer exported.
+
+use crate::delete::files_between;
+use juniper::GraphQLObject;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// File in the database directory which snapshots are saved to
+pub const SNAPSHOTS_FILE: &str = ".snapshots.json";
+// Most snapshots kept in the ledger. The oldest are forgotten first
+const MAX_SNAPSHOTS: usize = 1000;
+
+/// A consistent point in the telemetry database
+#[derive(Clone, Debug, Deserialize, GraphQLObject, PartialEq, Serialize)]
+pub struct Snapshot {
+    /// Sequence number of the snapshot, starting from 1
+    pub sequence: i32,
+    /// When the snapshot was taken, in seconds since the UNIX epoch
+    pub timestamp: f64,
+    /// Name of the first database file written after the snapshot
+    pub boundary: String,
+}
+
+/// Snapshots which have been taken, saved to a file
+pub struct SnapshotLedger {
+    path: PathBuf,
+    snapshots: Mutex<Vec<Snapshot>>,
+}
+
+impl SnapshotLedger {
+    /// Opens the ledger saved in the given database directory, if there is one
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(SNAPSHOTS_FILE);
+        let snapshots = match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map_err(|err| format!("Failed to parse snapshot ledger: {}", err))?,
+            Err(_) => vec![],
+        };
+
+        Ok(SnapshotLedger {
+            path,
+            snapshots: Mutex::new(snapshots),
+        })
+    }
+
+    /// Takes a snapshot. `rotate` moves the database on to a new file, returning its path.
+    /// Snapshots are taken one at a time, so their boundaries are in sequence order
+    pub fn take<F>(&self, timestamp: f64, rotate: F) -> Result<Snapshot, String>
+    where
+        F: FnOnce() -> Result<PathBuf, String>,
+    {
+        let mut snapshots = self.lock()?;
+        let boundary = rotate()?;
+        let boundary = boundary
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("Invalid database file name: {}", boundary.display()))?
+            .to_owned();
+
+        let snapshot = Snapshot {
+            sequence: snapshots.last().map_or(1, |last| last.sequence + 1),
+            timestamp,
+            boundary,
+        };
+
+        let mut updated = snapshots.clone();
+        updated.push(snapshot.clone());
+        if updated.len() > MAX_SNAPSHOTS {
+            updated.remove(0);
+        }
+        self.save(&updated)?;
+        *snapshots = updated;
+
+        info!(
+            "Took snapshot {} at {}",
+            snapshot.sequence, snapshot.boundary
+        );
+        Ok(snapshot)
+    }
+
+    /// The snapshots which have been taken, oldest first
+    pub fn list(&self) -> Result<Vec<Snapshot>, String> {
+        Ok(self.lock()?.clone())
+    }
+
+    /// The database files in `dir` holding exactly the data up to snapshot `sequence`, or only
+    /// the data added since snapshot `since`, if it's given
+    pub fn files(
+        &self,
+        dir: &Path,
+        sequence: i32,
+        since: Option<i32>,
+    ) -> Result<(Snapshot, Vec<PathBuf>), String> {
+        let snapshots = self.lock()?;
+        let find = |sequence: i32| {
+            snapshots
+                .iter()
+                .find(|snapshot| snapshot.sequence == sequence)
+                .ok_or_else(|| format!("Snapshot {} is not in the ledger", sequence))
+        };
+
+        let snapshot = find(sequence)?;
+        let start = match since {
+            Some(since) if since >= sequence => {
+                return Err(format!(
+                    "Snapshot {} is not before snapshot {}",
+                    since, sequence
+                ))
+            }
+            Some(since) => Some(dir.join(&find(since)?.boundary)),
+            None => None,
+        };
+
+        let files = files_between(dir, start.as_deref(), &dir.join(&snapshot.boundary))?;
+        Ok((snapshot.clone(), files))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Vec<Snapshot>>, String> {
+        self.snapshots
+            .lock()
+            .map_err(|_| "Snapshot ledger mutex poisoned".to_owned())
+    }
+
+    // Write to a temporary file first, so a reset mid-write can't lose the whole ledger
+    fn save(&self, snapshots: &[Snapshot]) -> Result<(), String> {
+        let raw = serde_json::to_vec_pretty(snapshots).map_err(|err| err.to_string())?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, raw)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|err| format!("Failed to save snapshot ledger: {}", err))
+    }
+}
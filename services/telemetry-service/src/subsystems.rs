@@ -0,0 +1,264 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Subsystems held by each database file
+//
+// Database files aren't read back (see `reports.rs`), so there's no way to tell which subsystems'
+// telemetry a file holds from the file itself. Instead, the times at which each subsystem's
+// telemetry was stored are kept in `.subsystems.json` in the database directory, as runs of
+// activity. Since a database file holds whatever was stored between its creation and the next
+// rotation, a file holds a subsystem's telemetry if any of that subsystem's runs overlap the file's
+// span.
+//
+// A run is saved as lasting until `RUN_EXTENSION` after its latest insert, and is only saved
+// again once an insert passes that end. A reset can therefore make a run look longer than it
+// was, but never shorter, so a file is never wrongly taken to be free of a subsystem.
+//
+// Telemetry points in the binary message format only carry parameter IDs, so they're recorded
+// under `ANY_SUBSYSTEM`, which is held alongside every subsystem. Files created before the ledger
+// was started are taken to hold every subsystem.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// Name of the ledger file, in the same directory as the database
+const LEDGER_FILE: &str = ".subsystems.json";
+
+// How long, in seconds, a run is saved as lasting past its latest insert. Inserts closer together
+// than this are recorded as a single run
+const RUN_EXTENSION: f64 = 60.0 * 60.0;
+
+// Recorded for telemetry whose subsystem isn't known
+pub const ANY_SUBSYSTEM: &str = "*";
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Runs {
+    // When the ledger was started (seconds since the UNIX epoch)
+    since: f64,
+    // Start and end times of the runs in which each subsystem's telemetry was stored, oldest first
+    runs: BTreeMap<String, Vec<(f64, f64)>>,
+}
+
+/// Times at which each subsystem's telemetry was stored, saved to a file
+pub struct SubsystemLedger {
+    path: PathBuf,
+    runs: Mutex<Runs>,
+}
+
+impl SubsystemLedger {
+    /// Opens the ledger saved in the given database directory, starting a new one if there isn't
+    /// one yet
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(LEDGER_FILE);
+        let (runs, saved) = match fs::read(&path) {
+            Ok(raw) => (
+                serde_json::from_slice(&raw)
+                    .map_err(|err| format!("Failed to parse subsystem ledger: {}", err))?,
+                true,
+            ),
+            Err(_) => (
+                Runs {
+                    since: Utc::now().timestamp() as f64,
+                    runs: BTreeMap::new(),
+                },
+                false,
+            ),
+        };
+
+        let ledger = SubsystemLedger {
+            path,
+            runs: Mutex::new(runs.clone()),
+        };
+        // A new ledger is saved straight away, so that files written from now on aren't taken
+        // to hold every subsystem after a restart
+        if !saved {
+            ledger.save(&runs)?;
+        }
+        Ok(ledger)
+    }
+
+    /// Records that telemetry for the given subsystems is being stored
+    pub fn record<'a>(&self, subsystems: impl IntoIterator<Item = &'a str>) -> Result<(), String> {
+        // File names only have whole seconds, so the database file created in the same second
+        // as this insert may not be the one it went to
+        let now = Utc::now().timestamp() as f64;
+        let start = now - 1.0;
+        let end = now + RUN_EXTENSION;
+
+        let mut runs = self.lock()?;
+
+        // Nothing needs saving while the subsystems' latest runs are still open
+        let stale: Vec<&str> = subsystems
+            .into_iter()
+            .filter(|subsystem| {
+                runs.runs
+                    .get(*subsystem)
+                    .and_then(|runs| runs.last())
+                    .map_or(true, |(_, run_end)| now > *run_end)
+            })
+            .collect();
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        let mut updated = runs.clone();
+        for subsystem in stale {
+            let list = updated.runs.entry(subsystem.to_owned()).or_default();
+            match list.last_mut() {
+                Some(run) if start - run.1 <= RUN_EXTENSION => run.1 = end,
+                _ => list.push((start, end)),
+            }
+        }
+
+        // Kept even if it can't be saved, so deletes made before a restart still see it
+        let saved = self.save(&updated);
+        *runs = updated;
+        saved
+    }
+
+    /// Whether the span of time from `start` (inclusive) to `end` (exclusive) holds telemetry
+    /// from nothing but the given subsystem
+    pub fn holds_only(&self, subsystem: &str, start: f64, end: f64) -> Result<bool, String> {
+        let runs = self.lock()?;
+        if start < runs.since {
+            return Ok(false);
+        }
+
+        Ok(runs
+            .runs
+            .iter()
+            .filter(|(name, _)| name.as_str() != subsystem)
+            .all(|(_, runs)| {
+                runs.iter()
+                    .all(|(run_start, run_end)| *run_start >= end || *run_end < start)
+            }))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Runs>, String> {
+        self.runs
+            .lock()
+            .map_err(|_| "Subsystem ledger mutex poisoned".to_owned())
+    }
+
+    // Write to a temporary file first, so a reset mid-write can't lose the whole ledger
+    fn save(&self, runs: &Runs) -> Result<(), String> {
+        let raw = serde_json::to_vec(runs).map_err(|err| err.to_string())?;
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, raw)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|err| format!("Failed to save subsystem ledger: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ledger_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "telemetry-subsystems-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn since(ledger: &SubsystemLedger) -> f64 {
+        ledger.lock().unwrap().since
+    }
+
+    #[test]
+    fn holds_only_recorded_subsystem() {
+        let dir = ledger_dir("only");
+        let ledger = SubsystemLedger::open(&dir).unwrap();
+        let start = since(&ledger);
+
+        ledger.record(vec!["eps", "eps"]).unwrap();
+
+        assert!(ledger.holds_only("eps", start, start + 10.0).unwrap());
+        assert!(!ledger.holds_only("adcs", start, start + 10.0).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn runs_extend_past_latest_insert() {
+        let dir = ledger_dir("extend");
+        let ledger = SubsystemLedger::open(&dir).unwrap();
+        let start = since(&ledger);
+
+        ledger.record(vec!["eps"]).unwrap();
+
+        // Still taken to be held until the run's saved end...
+        let later = start + RUN_EXTENSION - 10.0;
+        assert!(!ledger.holds_only("adcs", later, later + 5.0).unwrap());
+
+        // ...but not after it
+        let after = start + RUN_EXTENSION + 10.0;
+        assert!(ledger.holds_only("adcs", after, after + 5.0).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_subsystem_held_everywhere() {
+        let dir = ledger_dir("any");
+        let ledger = SubsystemLedger::open(&dir).unwrap();
+        let start = since(&ledger);
+
+        ledger.record(vec![ANY_SUBSYSTEM]).unwrap();
+
+        assert!(!ledger.holds_only("eps", start, start + 10.0).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_before_ledger_hold_everything() {
+        let dir = ledger_dir("before");
+        let ledger = SubsystemLedger::open(&dir).unwrap();
+        let start = since(&ledger);
+
+        assert!(!ledger
+            .holds_only("eps", start - 60.0, start - 30.0)
+            .unwrap());
+        assert!(ledger.holds_only("eps", start, start + 30.0).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = ledger_dir("reopen");
+        let start = {
+            let ledger = SubsystemLedger::open(&dir).unwrap();
+            ledger.record(vec!["eps"]).unwrap();
+            since(&ledger)
+        };
+
+        let ledger = SubsystemLedger::open(&dir).unwrap();
+        assert_eq!(since(&ledger), start);
+        assert!(!ledger.holds_only("adcs", start, start + 10.0).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//
+// Copyright (C) 2020 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Syslog ingest
+//
+// Accepts RFC 5424 syslog datagrams so that existing daemons can feed the telemetry database
+// just by pointing their syslog output at the service. Each message becomes a single entry:
+// the APP-NAME is the subsystem, the facility keyword (eg. "daemon", "local0") is the parameter
+// and the severity (0 = emergency ... 7 = debug) is the value. Messages without an APP-NAME are
+// recorded under the "syslog" subsystem. As with the direct UDP port, only entries with an ID in
+// the telemetry map are stored.
+
+use crate::udp::{DataPoint, DirectUdp};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use std::net::{SocketAddr, UdpSocket};
+
+// Subsystem used for messages without an APP-NAME
+const DEFAULT_SUBSYSTEM: &str = "syslog";
+
+// Facility keywords, indexed by facility code
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+// The parts of a syslog message's header which are recorded
+#[derive(Debug, PartialEq)]
+pub struct SyslogMessage {
+    pub facility: u8,
+    pub severity: u8,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub app_name: Option<String>,
+}
+
+impl SyslogMessage {
+    // Parse the header of an RFC 5424 message:
+    // <PRI>VERSION SP TIMESTAMP SP HOSTNAME SP APP-NAME SP PROCID SP MSGID SP SD [SP MSG]
+    pub fn parse(raw: &[u8]) -> Result<SyslogMessage, String> {
+        let raw = String::from_utf8_lossy(raw);
+
+        if !raw.starts_with('<') {
+            return Err("Missing PRI".to_owned());
+        }
+        let rest = &raw[1..];
+        let end = rest.find('>').ok_or_else(|| "Missing PRI".to_owned())?;
+        let pri = match &rest[..end] {
+            digits if (1..=3).contains(&digits.len()) => digits
+                .parse::<u8>()
+                .ok()
+                .filter(|pri| usize::from(*pri) < FACILITIES.len() * 8),
+            _ => None,
+        }
+        .ok_or_else(|| format!("Invalid PRI '{}'", &rest[..end]))?;
+
+        let mut fields = rest[end + 1..].splitn(5, ' ');
+
+        match fields.next() {
+            Some("1") => {}
+            version => return Err(format!("Unsupported version {:?}", version)),
+        }
+
+        let timestamp = match fields.next() {
+            Some("-") => None,
+            Some(timestamp) => Some(
+                DateTime::parse_from_rfc3339(timestamp)
+                    .map_err(|err| format!("Invalid timestamp '{}': {}", timestamp, err))?
+                    .with_timezone(&Utc),
+            ),
+            None => return Err("Missing timestamp".to_owned()),
+        };
+
+        // Hostname isn't recorded
+        fields.next().ok_or_else(|| "Missing hostname".to_owned())?;
+
+        let app_name = match fields.next() {
+            Some("-") => None,
+            Some(app_name) => Some(app_name.to_owned()),
+            None => return Err("Missing app name".to_owned()),
+        };
+
+        Ok(SyslogMessage {
+            facility: pri / 8,
+            severity: pri % 8,
+            timestamp,
+            app_name,
+        })
+    }
+
+    // Keyword for the message's facility
+    pub fn facility_name(&self) -> &'static str {
+        FACILITIES[usize::from(self.facility)]
+    }
+
+    // Telemetry entry for the message
+    pub fn data_point(&self) -> DataPoint {
+        DataPoint(
+            self.timestamp.unwrap_or_else(Utc::now),
+            self.app_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SUBSYSTEM.to_owned()),
+            self.facility_name().to_owned(),
+            i32::from(self.severity).into(),
+        )
+    }
+}
+
+// Listen for syslog datagrams and store them through the direct UDP path
+pub fn start(udp: DirectUdp, url: String) {
+    let socket = UdpSocket::bind(url.parse::<SocketAddr>().unwrap_or_else(|err| {
+        error!(
+            "Couldn't start syslog connection. Failed to parse {}: {:?}",
+            url, err
+        );
+        panic!()
+    }))
+    .unwrap_or_else(|err| {
+        error!(
+            "Couldn't start syslog connection. Failed to bind {}: {:?}",
+            url, err
+        );
+        panic!()
+    });
+
+    info!("Syslog listening on: {}", socket.local_addr().unwrap());
+
+    // RFC 5424 receivers must accept messages of at least 480 bytes and should accept 2048
+    let mut buf = vec![0; 2048];
+    loop {
+        let size = match socket.recv_from(&mut buf) {
+            Ok((size, _peer)) => size,
+            Err(err) => {
+                error!("Failed to receive a syslog message: {}", err);
+                continue;
+            }
+        };
+
+        let message = match SyslogMessage::parse(&buf[0..size]) {
+            Ok(message) => message,
+            Err(err) => {
+                debug!("Ignoring invalid syslog message: {}", err);
+                continue;
+            }
+        };
+
+        udp.store(vec![message.data_point()]);
+    }
+}
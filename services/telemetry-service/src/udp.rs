@@ -13,6 +13,15 @@
 // limitations under the License.
 //
 
+use crate::auth::InsertTokens;
+use crate::catalog::ParameterCatalog;
+use crate::flush::Flusher;
+use crate::ingest::IngestHealth;
+use crate::latest::LatestValues;
+use crate::mirror::Mirror;
+use crate::reports::ReportManager;
+use crate::rollups::RollupManager;
+use crate::subsystems::{SubsystemLedger, ANY_SUBSYSTEM};
 use chrono::{DateTime, Utc};
 pub use flat_db::DataPoint;
 use flat_db::{Database, DbError};
@@ -20,18 +29,89 @@ use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use deku::DekuContainerRead;
 use live_telemetry_protocol::{Point, PointType, Points, TelemetryMessage};
 
+#[derive(Clone)]
 pub struct DirectUdp {
     db: Arc<Database>,
+    reports: Option<Arc<ReportManager>>,
+    rollups: Option<Arc<RollupManager>>,
+    // Sets how each parameter's values are stored
+    catalog: Option<Arc<ParameterCatalog>>,
+    // Set while the service is in read-only mode
+    read_only: Arc<AtomicBool>,
+    // Tokens which datagrams received on the direct port must be signed with, if any
+    tokens: Option<Arc<InsertTokens>>,
+    flusher: Arc<Flusher>,
+    // Where inserted telemetry is mirrored to, if anywhere
+    mirror: Option<Arc<Mirror>>,
+    // Recovers the database after IO errors
+    ingest: Arc<IngestHealth>,
+    // Latest value of each parameter
+    latest: Arc<LatestValues>,
+    // When each subsystem's telemetry was stored
+    subsystems: Option<Arc<SubsystemLedger>>,
+}
+
+/// Service state the direct UDP ports share with the GraphQL side
+pub struct DirectUdpState {
+    pub reports: Option<Arc<ReportManager>>,
+    pub rollups: Option<Arc<RollupManager>>,
+    pub catalog: Option<Arc<ParameterCatalog>>,
+    pub read_only: Arc<AtomicBool>,
+    pub tokens: Option<Arc<InsertTokens>>,
+    pub flusher: Arc<Flusher>,
+    pub mirror: Option<Arc<Mirror>>,
+    pub ingest: Arc<IngestHealth>,
+    pub latest: Arc<LatestValues>,
+    pub subsystems: Option<Arc<SubsystemLedger>>,
 }
 
 impl DirectUdp {
-    pub fn new(db: Arc<Database>) -> Self {
-        DirectUdp { db }
+    pub fn new(db: Arc<Database>, state: DirectUdpState) -> Self {
+        let DirectUdpState {
+            reports,
+            rollups,
+            catalog,
+            read_only,
+            tokens,
+            flusher,
+            mirror,
+            ingest,
+            latest,
+            subsystems,
+        } = state;
+
+        DirectUdp {
+            db,
+            reports,
+            rollups,
+            catalog,
+            read_only,
+            tokens,
+            flusher,
+            mirror,
+            ingest,
+            latest,
+            subsystems,
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    // Notes the subsystems whose telemetry is about to be stored in the subsystem ledger
+    fn record_subsystems<'a>(&self, subsystems: impl IntoIterator<Item = &'a str>) {
+        if let Some(ledger) = &self.subsystems {
+            if let Err(err) = ledger.record(subsystems) {
+                error!("{}", err);
+            }
+        }
     }
 
     pub fn start(&self, url: String) {
@@ -55,23 +135,38 @@ impl DirectUdp {
         'main_loop: loop {
             // Wait for an incoming message
             let mut buf = vec![0; 4096];
-            let (size, _peer) = socket
-                .recv_from(&mut buf)
-                .map_err(|err| format!("Failed to receive a message: {}", err))
-                .unwrap();
+            let (size, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => {
+                    error!("Failed to receive a message: {}", err);
+                    continue;
+                }
+            };
 
             debug!("Received Telemetry");
 
-            let mut inp = (&buf[0..size], 0);
+            let (datagram, token) = match &self.tokens {
+                Some(tokens) => match tokens.authenticate(&buf[0..size]) {
+                    Some((token, payload)) => (payload, Some(token)),
+                    None => {
+                        warn!("Dropping unauthenticated telemetry from {}", peer);
+                        continue;
+                    }
+                },
+                None => (&buf[0..size], None),
+            };
+
+            let mut inp = (datagram, 0);
             'tm: loop {
                 if inp.0.len() == 0 {
                     continue 'main_loop;
                 }
 
-                let msg = match TelemetryMessage::from_bytes(inp) {
+                let (msg, raw) = match TelemetryMessage::from_bytes(inp) {
                     Ok((next, d)) => {
+                        let raw = &inp.0[..inp.0.len() - next.0.len()];
                         inp = next;
-                        d
+                        (d, raw)
                     }
                     Err(e) => {
                         debug!("Telemetry not in Telemetry Message Format: {:?}", e);
@@ -80,76 +175,208 @@ impl DirectUdp {
                 };
 
                 match msg {
-                    TelemetryMessage::Points(points) => match self.db.insert(points) {
-                        Ok(_) => {}
-                        Err(DbError::IOError { error }) => {
-                            error!("DB IO Error: {:?}", error);
-                            break 'main_loop;
-                        }
-                        Err(e) => {
-                            warn!("DB Insert Error: {:?}", e);
+                    // Points only carry telemetry map IDs, so can't be checked against the
+                    // token's subsystems
+                    TelemetryMessage::Points(_) if token.map_or(false, |t| t.is_restricted()) => {
+                        warn!(
+                            "Dropping telemetry points from restricted token '{}'",
+                            token.unwrap().name
+                        );
+                    }
+                    TelemetryMessage::Points(_) if self.read_only() => {
+                        debug!("Read-only mode, dropping telemetry");
+                    }
+                    TelemetryMessage::Points(_) if self.ingest.stalled() => {
+                        debug!("Ingestion stalled, dropping telemetry");
+                    }
+                    TelemetryMessage::Points(points) => {
+                        let count = points.points.len();
+                        self.record_subsystems(vec![ANY_SUBSYSTEM]);
+                        match self.db.insert(points) {
+                            Ok(_) => {
+                                self.flusher.inserted(count);
+                                if let Some(mirror) = &self.mirror {
+                                    mirror.points(raw);
+                                }
+                            }
+                            Err(DbError::IOError { error }) => {
+                                error!("DB IO Error: {:?}", error);
+                                self.ingest.io_error(format!("{:?}", error));
+                                continue 'main_loop;
+                            }
+                            Err(e) => {
+                                warn!("DB Insert Error: {:?}", e);
+                            }
                         }
-                    },
+                    }
                     m => {
                         warn!("Unknown TelemetryMessage: {:?}", m);
                     }
                 }
             }
 
-            let dps = if let Ok(val) = serde_cbor::from_slice::<DataPoint>(&buf[0..size]) {
+            let mut dps = if let Ok(val) = serde_cbor::from_slice::<DataPoint>(datagram) {
                 vec![val]
-            } else if let Ok(vec) = serde_cbor::from_slice::<Vec<DataPoint>>(&buf[0..size]) {
+            } else if let Ok(vec) = serde_cbor::from_slice::<Vec<DataPoint>>(datagram) {
                 vec
             } else {
                 error!(
                     "Couldn't deserialize JSON object or object array from {:?}",
-                    String::from_utf8_lossy(&buf[0..size].to_vec())
+                    String::from_utf8_lossy(datagram)
                 );
                 continue;
             };
 
-            let dps: Vec<(DateTime<Utc>, u16, PointType)> = dps
-                .into_iter()
-                .filter_map(|dp| {
-                    let DataPoint(timestamp, subsystem, metric, value) = dp;
-                    telemetry_map::get_id((&subsystem, &metric)).map(|id| (timestamp, id, value))
-                })
-                .filter_map(|(ts, id, value)| value.try_into().ok().map(|value| (ts, id, value)))
-                .collect();
+            if let Some(token) = token {
+                let received = dps.len();
+                dps.retain(|DataPoint(_, subsystem, _, _)| token.allows(subsystem));
+                if dps.len() < received {
+                    warn!(
+                        "Dropping {} data points for subsystems token '{}' isn't allowed to insert",
+                        received - dps.len(),
+                        token.name
+                    );
+                }
+            }
 
-            let mut time_bins: HashMap<DateTime<Utc>, HashMap<u16, PointType>> = HashMap::new();
+            self.store(dps);
+        }
+    }
 
-            for (ts, id, value) in dps {
-                let bin = time_bins.entry(ts).or_default();
-                bin.entry(id).or_insert(value);
+    // Records data points as the latest values of their parameters and in any standing reports and
+    // rollups, and inserts them into the database, in the encodings set by the parameter catalog.
+    // The latest values, reports, rollups and the mirror get the values as they were received.
+    // IO errors are handed over to the ingest health, which recovers the database.
+    // Nothing is recorded while the service is in read-only mode, or while ingestion is stalled.
+    pub fn store(&self, dps: Vec<DataPoint>) {
+        if self.read_only() {
+            debug!("Read-only mode, dropping {} data points", dps.len());
+            return;
+        }
+        if self.ingest.stalled() {
+            debug!("Ingestion stalled, dropping {} data points", dps.len());
+            return;
+        }
+
+        for DataPoint(timestamp, subsystem, metric, value) in &dps {
+            self.latest.record(timestamp, subsystem, metric, value);
+            if let Some(value) = numeric_value(value) {
+                if let Some(reports) = &self.reports {
+                    reports.record(timestamp.timestamp(), subsystem, metric, value);
+                }
+                if let Some(rollups) = &self.rollups {
+                    rollups.record(timestamp.timestamp(), subsystem, metric, value);
+                }
             }
+        }
 
-            let points_bin: Vec<Points> = time_bins
-                .drain()
-                .map(|(ts, mut bin)| {
-                    let mut points = Points::new(ts);
+        let mirrored = self
+            .mirror
+            .as_ref()
+            .and_then(|mirror| mirror.datagram(&dps));
+
+        let dps = match &self.catalog {
+            Some(catalog) => catalog.encode(dps),
+            None => dps,
+        };
+
+        self.record_subsystems(
+            dps.iter()
+                .map(|DataPoint(_, subsystem, _, _)| subsystem.as_str()),
+        );
+        let inserted = match insert_data_points(&self.db, dps) {
+            Ok(inserted) => inserted,
+            Err(err) => {
+                self.ingest.io_error(format!("{:?}", err));
+                return;
+            }
+        };
+        self.flusher.inserted(inserted);
+
+        if let (Some(mirror), Some(datagram)) = (&self.mirror, mirrored) {
+            mirror.send(&datagram);
+        }
+    }
+
+    // Inserts historical data points (eg. from an imported file) into the database, in the
+    // encodings set by the parameter catalog. They aren't live telemetry, so they aren't
+    // recorded in reports or rollups, or mirrored.
+    // Returns the number of points inserted.
+    pub fn backfill(&self, dps: Vec<DataPoint>) -> Result<usize, DbError> {
+        let dps = match &self.catalog {
+            Some(catalog) => catalog.encode(dps),
+            None => dps,
+        };
+
+        self.record_subsystems(
+            dps.iter()
+                .map(|DataPoint(_, subsystem, _, _)| subsystem.as_str()),
+        );
+        let inserted = insert_data_points(&self.db, dps)?;
+        self.flusher.inserted(inserted);
+        Ok(inserted)
+    }
+}
+
+// Inserts data points into a database, grouped by timestamp.
+// Data points without an ID in the telemetry map are dropped.
+// Returns the number of points inserted.
+// Only IO errors are returned, since they mean the database can't be written to at all.
+pub fn insert_data_points(db: &Database, dps: Vec<DataPoint>) -> Result<usize, DbError> {
+    let dps: Vec<(DateTime<Utc>, u16, PointType)> = dps
+        .into_iter()
+        .filter_map(|dp| {
+            let DataPoint(timestamp, subsystem, metric, value) = dp;
+            telemetry_map::get_id((&subsystem, &metric)).map(|id| (timestamp, id, value))
+        })
+        .filter_map(|(ts, id, value)| value.try_into().ok().map(|value| (ts, id, value)))
+        .collect();
 
-                    points.points = bin
-                        .drain()
-                        .map(|(id, value)| Point::new_with_value(id, value))
-                        .collect();
+    let mut time_bins: HashMap<DateTime<Utc>, HashMap<u16, PointType>> = HashMap::new();
 
-                    points
-                })
+    for (ts, id, value) in dps {
+        let bin = time_bins.entry(ts).or_default();
+        bin.entry(id).or_insert(value);
+    }
+
+    let points_bin: Vec<Points> = time_bins
+        .drain()
+        .map(|(ts, mut bin)| {
+            let mut points = Points::new(ts);
+
+            points.points = bin
+                .drain()
+                .map(|(id, value)| Point::new_with_value(id, value))
                 .collect();
 
-            for p in points_bin {
-                match self.db.insert(p) {
-                    Ok(_) => {}
-                    Err(DbError::IOError { error }) => {
-                        error!("DB IO Error: {:?}", error);
-                        break 'main_loop;
-                    }
-                    Err(e) => {
-                        warn!("DB Insert Error: {:?}", e);
-                    }
-                }
+            points
+        })
+        .collect();
+
+    let mut inserted = 0;
+    for p in points_bin {
+        let count = p.points.len();
+        match db.insert(p) {
+            Ok(_) => inserted += count,
+            Err(DbError::IOError { error }) => {
+                error!("DB IO Error: {:?}", error);
+                return Err(DbError::IOError { error });
+            }
+            Err(e) => {
+                warn!("DB Insert Error: {:?}", e);
             }
         }
     }
+
+    Ok(inserted)
+}
+
+// Get the numeric form of a data point's value, for use in reports and encodings
+pub fn numeric_value<T: serde::Serialize>(value: &T) -> Option<f64> {
+    match serde_cbor::value::to_value(value).ok()? {
+        serde_cbor::Value::Integer(value) => Some(value as f64),
+        serde_cbor::Value::Float(value) => Some(value),
+        serde_cbor::Value::Bool(value) => Some(if value { 1.0 } else { 0.0 }),
+        _ => None,
+    }
 }